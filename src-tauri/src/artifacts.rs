@@ -0,0 +1,335 @@
+// Copyright (c) 2024-2025 Wanyeki Technologies LLC. All rights reserved.
+// This source code is licensed under the proprietary license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Raw files kept after a simulation that set `keepRaw`, so they can be opened in the simulator's
+//! own waveform viewer
+//!
+//! Each raw file is copied out of the simulation's temp dir into the artifacts directory, named
+//! after the request id, and fetched with `get_artifact` or saved from the agent window. The
+//! directory is held to `max_artifact_bytes` from the resource budget, least recently used
+//! evicted first. Files it doesn't
+//! recognize, like copies a crash cut short, are removed at startup.
+
+use std::collections::VecDeque;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::resource_budget::{BudgetEntry, Budgeted};
+
+/// Most raw file bytes kept unless the resource budget says otherwise
+pub const MAX_ARTIFACT_BYTES: u64 = 1024 * 1024 * 1024;
+
+const RAW_EXTENSION: &str = "raw";
+
+/// Suffix of a raw file still being copied in
+const PARTIAL_SUFFIX: &str = ".raw.part";
+
+/// Platform directory artifacts are kept in
+pub fn default_dir() -> Option<PathBuf> {
+    dirs::data_local_dir().map(|dir| dir.join("kelicad-agent").join("artifacts"))
+}
+
+/// Artifact id of a request id: its letters, digits, `-` and `_`, with anything else replaced by `_`
+pub fn artifact_id(request_id: &str) -> String {
+    request_id
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' })
+        .collect()
+}
+
+/// A kept raw file
+#[derive(Debug, Clone, PartialEq)]
+pub struct Artifact {
+    pub id: String,
+    pub path: PathBuf,
+    pub size: u64,
+}
+
+impl Artifact {
+    /// Name to save the file under
+    pub fn file_name(&self) -> String {
+        format!("{}.{}", self.id, RAW_EXTENSION)
+    }
+}
+
+/// The artifacts directory and the raw files in it, least recently used first
+pub struct Artifacts {
+    /// None when no directory could be set up; raw files are then not kept
+    dir: Option<PathBuf>,
+    max_bytes: u64,
+    entries: VecDeque<Artifact>,
+}
+
+impl Default for Artifacts {
+    fn default() -> Self {
+        Self { dir: None, max_bytes: MAX_ARTIFACT_BYTES, entries: VecDeque::new() }
+    }
+}
+
+impl Artifacts {
+    /// Open the platform artifacts directory, or keep no raw files if it can't be set up
+    pub fn open_default(max_bytes: u64) -> Self {
+        let Some(dir) = default_dir() else {
+            log::warn!("No data directory; raw files will not be kept");
+            return Self::default();
+        };
+        Self::open(&dir, max_bytes).unwrap_or_else(|e| {
+            log::warn!("Failed to set up the artifacts directory {}: {}; raw files will not be kept", dir.display(), e);
+            Self::default()
+        })
+    }
+
+    /// Open `dir`, creating it if needed, keeping the raw files from earlier runs and removing
+    /// everything else
+    pub fn open(dir: &Path, max_bytes: u64) -> io::Result<Self> {
+        std::fs::create_dir_all(dir)?;
+        let mut kept = Vec::new();
+        let mut removed = 0;
+        for entry in std::fs::read_dir(dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            let metadata = entry.metadata()?;
+            let id = path.file_stem().and_then(|stem| stem.to_str()).unwrap_or_default().to_string();
+            let known = metadata.is_file()
+                && path.extension().is_some_and(|extension| extension == RAW_EXTENSION)
+                && !id.is_empty()
+                && artifact_id(&id) == id;
+            if known {
+                let used = metadata.modified().unwrap_or(std::time::SystemTime::UNIX_EPOCH);
+                kept.push((used, Artifact { id, path, size: metadata.len() }));
+                continue;
+            }
+            let result = if metadata.is_dir() { std::fs::remove_dir_all(&path) } else { std::fs::remove_file(&path) };
+            match result {
+                Ok(()) => removed += 1,
+                Err(e) => log::warn!("Failed to remove {}: {}", path.display(), e),
+            }
+        }
+        if removed > 0 {
+            log::info!("Removed {} unknown files from {}", removed, dir.display());
+        }
+
+        kept.sort_by_key(|(used, _)| *used);
+        let mut artifacts = Self {
+            dir: Some(dir.to_path_buf()),
+            max_bytes,
+            entries: kept.into_iter().map(|(_, artifact)| artifact).collect(),
+        };
+        artifacts.evict();
+        Ok(artifacts)
+    }
+
+    /// Where the simulator should copy the raw file of `request_id` before it is added
+    pub fn staging_path(&self, request_id: &str) -> Option<PathBuf> {
+        let dir = self.dir.as_ref()?;
+        Some(dir.join(format!("{}{}", artifact_id(request_id), PARTIAL_SUFFIX)))
+    }
+
+    /// Keep the raw file staged for `request_id`, replacing an earlier one of the same id
+    pub fn add(&mut self, request_id: &str) -> Result<Artifact, String> {
+        let staged = self.staging_path(request_id).ok_or("No artifacts directory is available")?;
+        let id = artifact_id(request_id);
+        let size = match std::fs::metadata(&staged) {
+            Ok(metadata) => metadata.len(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return Err("The simulation wrote no raw file".to_string());
+            }
+            Err(e) => return Err(e.to_string()),
+        };
+        if size > self.max_bytes {
+            let _ = std::fs::remove_file(&staged);
+            return Err(format!(
+                "The raw file is {} MB, more than the {} MB kept for artifacts",
+                size / (1024 * 1024),
+                self.max_bytes / (1024 * 1024)
+            ));
+        }
+
+        let path = staged.with_file_name(format!("{}.{}", id, RAW_EXTENSION));
+        std::fs::rename(&staged, &path).map_err(|e| e.to_string())?;
+        self.entries.retain(|artifact| artifact.id != id);
+        let artifact = Artifact { id, path, size };
+        self.entries.push_back(artifact.clone());
+        self.evict();
+        Ok(artifact)
+    }
+
+    /// The artifact with `id`, now the most recently used
+    pub fn get(&mut self, id: &str) -> Option<Artifact> {
+        let index = self.entries.iter().position(|artifact| artifact.id == id)?;
+        let artifact = self.entries.remove(index)?;
+        if !artifact.path.exists() {
+            log::warn!("Artifact {} was removed from {}", id, artifact.path.display());
+            return None;
+        }
+        self.entries.push_back(artifact.clone());
+        Some(artifact)
+    }
+
+    /// Keep at most `max_bytes`, deleting the least recently used files beyond that now
+    pub fn set_max_bytes(&mut self, max_bytes: u64) {
+        self.max_bytes = max_bytes;
+        self.evict();
+    }
+
+    pub fn total_bytes(&self) -> u64 {
+        self.entries.iter().map(|artifact| artifact.size).sum()
+    }
+
+    /// Delete the least recently used raw files until the rest fit in the size cap
+    fn evict(&mut self) {
+        while self.total_bytes() > self.max_bytes {
+            let Some(oldest) = self.entries.pop_front() else {
+                break;
+            };
+            remove_artifact(&oldest);
+        }
+    }
+}
+
+fn remove_artifact(artifact: &Artifact) {
+    log::info!("Evicting artifact {} ({} bytes)", artifact.id, artifact.size);
+    if let Err(e) = std::fs::remove_file(&artifact.path) {
+        log::warn!("Failed to remove {}: {}", artifact.path.display(), e);
+    }
+}
+
+/// Raw files count by size, their place in the directory's order standing for their last use
+impl Budgeted for Artifacts {
+    fn budget_entries(&self) -> Vec<BudgetEntry> {
+        self.entries
+            .iter()
+            .enumerate()
+            .map(|(i, artifact)| BudgetEntry { key: artifact.id.clone(), size: artifact.size, last_used: i as u64 })
+            .collect()
+    }
+
+    fn evict(&mut self, key: &str) {
+        if let Some(index) = self.entries.iter().position(|artifact| artifact.id == key) {
+            let artifact = self.entries.remove(index).expect("index is in range");
+            remove_artifact(&artifact);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stage(artifacts: &Artifacts, request_id: &str, size: usize) {
+        std::fs::write(artifacts.staging_path(request_id).unwrap(), vec![b'x'; size]).unwrap();
+    }
+
+    fn ids(artifacts: &Artifacts) -> Vec<&str> {
+        artifacts.entries.iter().map(|artifact| artifact.id.as_str()).collect()
+    }
+
+    #[test]
+    fn test_artifact_id() {
+        assert_eq!(artifact_id("sim-1_a"), "sim-1_a");
+        assert_eq!(artifact_id("../etc/passwd"), "___etc_passwd");
+        assert_eq!(artifact_id("a b.raw"), "a_b_raw");
+    }
+
+    #[test]
+    fn test_least_recently_used_is_evicted() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut artifacts = Artifacts::open(dir.path(), 100).unwrap();
+
+        for id in ["a", "b", "c"] {
+            stage(&artifacts, id, 40);
+            artifacts.add(id).unwrap();
+        }
+        // "a" went to make room for "c"
+        assert_eq!(ids(&artifacts), ["b", "c"]);
+        assert!(!dir.path().join("a.raw").exists());
+        assert_eq!(artifacts.get("a"), None);
+
+        // Reading "b" makes "c" the least recently used
+        let b = artifacts.get("b").unwrap();
+        assert_eq!(b.size, 40);
+        assert_eq!(b.file_name(), "b.raw");
+        stage(&artifacts, "d", 40);
+        artifacts.add("d").unwrap();
+        assert_eq!(ids(&artifacts), ["b", "d"]);
+        assert!(artifacts.total_bytes() <= 100);
+
+        // Re-adding an id replaces its file
+        stage(&artifacts, "b", 10);
+        assert_eq!(artifacts.add("b").unwrap().size, 10);
+        assert_eq!(ids(&artifacts), ["d", "b"]);
+    }
+
+    #[test]
+    fn test_lowering_the_cap_evicts() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut artifacts = Artifacts::open(dir.path(), 100).unwrap();
+        for id in ["a", "b", "c"] {
+            stage(&artifacts, id, 30);
+            artifacts.add(id).unwrap();
+        }
+        assert_eq!(artifacts.budget_entries().iter().map(|entry| entry.size).sum::<u64>(), 90);
+
+        artifacts.set_max_bytes(50);
+        assert_eq!(ids(&artifacts), ["c"]);
+        assert!(!dir.path().join("b.raw").exists());
+
+        Budgeted::evict(&mut artifacts, "c");
+        assert!(ids(&artifacts).is_empty());
+        assert!(!dir.path().join("c.raw").exists());
+    }
+
+    #[test]
+    fn test_size_cap() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut artifacts = Artifacts::open(dir.path(), 100).unwrap();
+        stage(&artifacts, "small", 60);
+        artifacts.add("small").unwrap();
+
+        // A file over the cap is refused without evicting anything
+        stage(&artifacts, "huge", 101);
+        assert!(artifacts.add("huge").unwrap_err().contains("more than"));
+        assert_eq!(ids(&artifacts), ["small"]);
+        assert!(!artifacts.staging_path("huge").unwrap().exists());
+
+        // One exactly at the cap fits once everything else is gone
+        stage(&artifacts, "full", 100);
+        artifacts.add("full").unwrap();
+        assert_eq!(ids(&artifacts), ["full"]);
+        assert_eq!(artifacts.total_bytes(), 100);
+
+        assert_eq!(artifacts.add("missing").unwrap_err(), "The simulation wrote no raw file");
+        assert!(Artifacts::default().add("x").is_err());
+    }
+
+    #[test]
+    fn test_open_removes_unknown_files() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("old.raw"), b"raw").unwrap();
+        std::fs::write(dir.path().join("interrupted.raw.part"), b"ra").unwrap();
+        std::fs::write(dir.path().join("notes.txt"), b"hi").unwrap();
+        std::fs::write(dir.path().join("bad name.raw"), b"raw").unwrap();
+        std::fs::create_dir(dir.path().join("nested")).unwrap();
+
+        let mut artifacts = Artifacts::open(dir.path(), 100).unwrap();
+        let mut left: Vec<String> = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        left.sort();
+        assert_eq!(left, ["old.raw"]);
+        assert_eq!(artifacts.get("old").unwrap().size, 3);
+    }
+
+    #[test]
+    fn test_open_evicts_to_the_cap() {
+        let dir = tempfile::tempdir().unwrap();
+        for id in ["a", "b", "c"] {
+            std::fs::write(dir.path().join(format!("{}.raw", id)), vec![b'x'; 40]).unwrap();
+        }
+        let artifacts = Artifacts::open(dir.path(), 100).unwrap();
+        assert_eq!(artifacts.entries.len(), 2);
+        assert!(artifacts.total_bytes() <= 100);
+    }
+}
@@ -0,0 +1,41 @@
+// Copyright (c) 2024-2025 Wanyeki Technologies LLC. All rights reserved.
+// This source code is licensed under the proprietary license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Launch at login (launch agent on macOS, Run registry key on Windows)
+
+use std::sync::Arc;
+use tauri::{AppHandle, Manager};
+use tauri_plugin_autostart::ManagerExt;
+
+use crate::settings;
+use crate::state::AppState;
+use crate::tray::TrayMenu;
+
+/// Whether the agent is currently registered to launch at login
+pub fn is_enabled(app: &AppHandle) -> Result<bool, String> {
+    app.autolaunch().is_enabled().map_err(|e| e.to_string())
+}
+
+/// Register or unregister the login item, then persist the preference and update the tray
+/// Returns the state reported by the OS afterwards
+pub async fn set_enabled(app: &AppHandle, enabled: bool) -> Result<bool, String> {
+    let launcher = app.autolaunch();
+    let result = if enabled { launcher.enable() } else { launcher.disable() };
+    result.map_err(|e| format!("Failed to {} launch at login: {}", if enabled { "enable" } else { "disable" }, e))?;
+    let enabled = is_enabled(app)?;
+
+    let state = app.state::<Arc<AppState>>().inner().clone();
+    let updated = {
+        let mut settings = state.settings.write().await;
+        settings.autostart = enabled;
+        settings.clone()
+    };
+    settings::save_settings(&updated)?;
+    if let Some(menu) = app.try_state::<TrayMenu>() {
+        menu.sync_settings(&updated);
+    }
+
+    log::info!("Launch at login {}", if enabled { "enabled" } else { "disabled" });
+    Ok(enabled)
+}
@@ -0,0 +1,175 @@
+// Copyright (c) 2024-2025 Wanyeki Technologies LLC. All rights reserved.
+// This source code is licensed under the proprietary license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Catalog of the libraries bundled in the agent's resources
+//!
+//! `libraries.json` in the resources directory lists them. Bundled libraries stand in for
+//! `.include`/`.lib` files LTspice's library directory doesn't have, unless disabled in settings.
+
+use std::path::Path;
+use serde::Deserialize;
+
+use crate::protocol::{BundledLibrary, BundledLibraryStatus};
+use crate::simulator;
+
+/// Manifest file in the resources directory
+pub const MANIFEST_FILE: &str = "libraries.json";
+
+/// Libraries assumed to be bundled when there is no manifest
+const FALLBACK_LIBRARIES: &[&str] = &["LTC3.lib"];
+
+/// Largest library `read_content` returns, in bytes
+const MAX_CONTENT_BYTES: u64 = 4 * 1024 * 1024;
+
+#[derive(Debug, Deserialize)]
+struct Manifest {
+    libraries: Vec<BundledLibrary>,
+}
+
+/// Load the catalog from the resources directory, falling back to the built-in list
+pub fn load() -> Vec<BundledLibrary> {
+    match simulator::get_resources_dir() {
+        Some(dir) => load_from(&dir),
+        None => fallback(),
+    }
+}
+
+/// Load `libraries.json` from `dir`; a missing or invalid manifest gives the built-in list
+pub fn load_from(dir: &Path) -> Vec<BundledLibrary> {
+    let path = dir.join(MANIFEST_FILE);
+    let Ok(text) = std::fs::read_to_string(&path) else {
+        log::info!("No bundled library manifest at {:?}, using the built-in list", path);
+        return fallback();
+    };
+    match parse_manifest(&text) {
+        Ok(libraries) => {
+            log::info!("Loaded {} bundled libraries from {:?}", libraries.len(), path);
+            libraries
+        }
+        Err(e) => {
+            log::warn!("Ignoring invalid bundled library manifest {:?}: {}", path, e);
+            fallback()
+        }
+    }
+}
+
+/// The built-in list, without descriptions or versions
+pub fn fallback() -> Vec<BundledLibrary> {
+    FALLBACK_LIBRARIES
+        .iter()
+        .map(|name| BundledLibrary { name: name.to_string(), description: None, version: None })
+        .collect()
+}
+
+/// Libraries from a manifest; entries that aren't plain file names are skipped
+fn parse_manifest(text: &str) -> Result<Vec<BundledLibrary>, serde_json::Error> {
+    let manifest: Manifest = serde_json::from_str(text)?;
+    Ok(manifest
+        .libraries
+        .into_iter()
+        .filter(|library| {
+            let plain = Path::new(&library.name).file_name().is_some_and(|name| name == library.name.as_str());
+            if !plain {
+                log::warn!("Skipping bundled library \"{}\": not a plain file name", library.name);
+            }
+            plain
+        })
+        .collect())
+}
+
+/// Names of the libraries that may be substituted, i.e. not in `disabled`
+pub fn enabled_names(libraries: &[BundledLibrary], disabled: &[String]) -> Vec<String> {
+    libraries
+        .iter()
+        .filter(|library| !disabled.contains(&library.name))
+        .map(|library| library.name.clone())
+        .collect()
+}
+
+/// The catalog with each library's enabled state, for `list_libraries`
+pub fn statuses(libraries: &[BundledLibrary], disabled: &[String]) -> Vec<BundledLibraryStatus> {
+    libraries
+        .iter()
+        .map(|library| BundledLibraryStatus { library: library.clone(), enabled: !disabled.contains(&library.name) })
+        .collect()
+}
+
+/// Text of a cataloged library, for previewing in the agent window
+pub fn read_content(libraries: &[BundledLibrary], name: &str) -> Result<String, String> {
+    let dir = simulator::get_resources_dir().ok_or("Bundled resources directory not found")?;
+    read_content_from(&dir, libraries, name)
+}
+
+fn read_content_from(dir: &Path, libraries: &[BundledLibrary], name: &str) -> Result<String, String> {
+    if !libraries.iter().any(|library| library.name == name) {
+        return Err(format!("\"{}\" is not a bundled library", name));
+    }
+    let path = dir.join(name);
+    let size = std::fs::metadata(&path).map_err(|e| format!("Could not read {}: {}", name, e))?.len();
+    if size > MAX_CONTENT_BYTES {
+        return Err(format!("{} is too large to preview ({} KB)", name, size / 1024));
+    }
+    let bytes = std::fs::read(&path).map_err(|e| format!("Could not read {}: {}", name, e))?;
+    // Vendor libraries are often Latin-1, so decode lossily rather than failing
+    Ok(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_missing_manifest_falls_back() {
+        let dir = TempDir::new().unwrap();
+        let libraries = load_from(dir.path());
+        assert_eq!(libraries.len(), 1);
+        assert_eq!(libraries[0].name, "LTC3.lib");
+        assert_eq!(libraries[0].description, None);
+
+        std::fs::write(dir.path().join(MANIFEST_FILE), "not json").unwrap();
+        assert_eq!(load_from(dir.path())[0].name, "LTC3.lib");
+    }
+
+    #[test]
+    fn test_manifest_is_loaded() {
+        let dir = TempDir::new().unwrap();
+        std::fs::write(
+            dir.path().join(MANIFEST_FILE),
+            r#"{"libraries":[{"name":"a.lib","description":"A","version":"1.2"},{"name":"../b.lib"},{"name":"c.sub"}]}"#,
+        )
+        .unwrap();
+        let libraries = load_from(dir.path());
+        let names: Vec<&str> = libraries.iter().map(|l| l.name.as_str()).collect();
+        assert_eq!(names, ["a.lib", "c.sub"]);
+        assert_eq!(libraries[0].version.as_deref(), Some("1.2"));
+    }
+
+    #[test]
+    fn test_bundled_manifest_parses() {
+        let text = include_str!("../resources/libraries.json");
+        let libraries = parse_manifest(text).unwrap();
+        assert!(libraries.iter().any(|l| l.name == "LTC3.lib"));
+    }
+
+    #[test]
+    fn test_disabled_libraries() {
+        let libraries = fallback();
+        let disabled = vec!["LTC3.lib".to_string()];
+        assert!(enabled_names(&libraries, &disabled).is_empty());
+        assert_eq!(enabled_names(&libraries, &[]), ["LTC3.lib"]);
+        assert!(!statuses(&libraries, &disabled)[0].enabled);
+    }
+
+    #[test]
+    fn test_read_content_only_for_cataloged_libraries() {
+        let dir = TempDir::new().unwrap();
+        std::fs::write(dir.path().join("LTC3.lib"), "* LTC3\n").unwrap();
+        std::fs::write(dir.path().join("other.lib"), "* other\n").unwrap();
+        let libraries = fallback();
+        assert_eq!(read_content_from(dir.path(), &libraries, "LTC3.lib").unwrap(), "* LTC3\n");
+        assert!(read_content_from(dir.path(), &libraries, "other.lib").is_err());
+        assert!(read_content_from(dir.path(), &libraries, "../LTC3.lib").is_err());
+    }
+}
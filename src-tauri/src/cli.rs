@@ -0,0 +1,115 @@
+// Copyright (c) 2024-2025 Wanyeki Technologies LLC. All rights reserved.
+// This source code is licensed under the proprietary license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Command-line flags, parsed before the Tauri app is built
+
+/// Passed by the login item so the agent starts in the tray without showing its window
+pub const MINIMIZED_FLAG: &str = "--minimized";
+
+/// Options given on the command line
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CliOptions {
+    /// Run only detection and the WebSocket server, without window or tray
+    pub headless: bool,
+    /// Start in the tray without showing the window
+    pub minimized: bool,
+    /// WebSocket port override (headless only)
+    pub port: Option<u16>,
+    /// Simulator path overrides, used instead of detection (headless only)
+    pub ltspice_path: Option<String>,
+    pub ngspice_path: Option<String>,
+    /// Exit with an error if no simulator is available (headless only)
+    pub require_simulator: bool,
+    /// Arguments that were not recognized; the GUI ignores them, headless mode rejects them
+    pub unrecognized: Vec<String>,
+}
+
+/// Parse arguments, excluding the program name
+/// Fails only if a known flag is missing its value or the value is invalid
+pub fn parse(args: impl IntoIterator<Item = String>) -> Result<CliOptions, String> {
+    let mut options = CliOptions::default();
+    let mut args = args.into_iter();
+
+    while let Some(arg) = args.next() {
+        // Accept both `--flag value` and `--flag=value`
+        let (flag, inline_value) = match arg.split_once('=') {
+            Some((flag, value)) if flag.starts_with("--") => (flag.to_string(), Some(value.to_string())),
+            _ => (arg.clone(), None),
+        };
+        let mut value = |name: &str| {
+            inline_value
+                .clone()
+                .or_else(|| args.next())
+                .ok_or_else(|| format!("{} requires a value", name))
+        };
+
+        match flag.as_str() {
+            "--headless" => options.headless = true,
+            MINIMIZED_FLAG => options.minimized = true,
+            "--require-simulator" => options.require_simulator = true,
+            "--port" => {
+                let port = value("--port")?;
+                options.port = Some(
+                    port.parse::<u16>()
+                        .ok()
+                        .filter(|p| *p != 0)
+                        .ok_or_else(|| format!("Invalid port: {}", port))?,
+                );
+            }
+            "--ltspice-path" => options.ltspice_path = Some(value("--ltspice-path")?),
+            "--ngspice-path" => options.ngspice_path = Some(value("--ngspice-path")?),
+            _ => options.unrecognized.push(arg),
+        }
+    }
+
+    Ok(options)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(list: &[&str]) -> Vec<String> {
+        list.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_no_arguments_is_gui_mode() {
+        assert_eq!(parse(args(&[])).unwrap(), CliOptions::default());
+    }
+
+    #[test]
+    fn test_headless_flags() {
+        let options = parse(args(&[
+            "--headless",
+            "--port",
+            "9400",
+            "--ngspice-path=/usr/bin/ngspice",
+            "--ltspice-path",
+            "/opt/LTspice/LTspice.exe",
+            "--require-simulator",
+        ]))
+        .unwrap();
+        assert!(options.headless);
+        assert!(options.require_simulator);
+        assert_eq!(options.port, Some(9400));
+        assert_eq!(options.ngspice_path.as_deref(), Some("/usr/bin/ngspice"));
+        assert_eq!(options.ltspice_path.as_deref(), Some("/opt/LTspice/LTspice.exe"));
+        assert!(options.unrecognized.is_empty());
+    }
+
+    #[test]
+    fn test_minimized_and_unrecognized() {
+        let options = parse(args(&["--minimized", "-psn_0_12345"])).unwrap();
+        assert!(options.minimized);
+        assert_eq!(options.unrecognized, vec!["-psn_0_12345".to_string()]);
+    }
+
+    #[test]
+    fn test_invalid_values() {
+        assert!(parse(args(&["--port"])).unwrap_err().contains("requires a value"));
+        assert!(parse(args(&["--port", "abc"])).unwrap_err().contains("Invalid port"));
+        assert!(parse(args(&["--port=0"])).unwrap_err().contains("Invalid port"));
+    }
+}
@@ -0,0 +1,240 @@
+// Copyright (c) 2024-2025 Wanyeki Technologies LLC. All rights reserved.
+// This source code is licensed under the proprietary license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Netlist compatibility rewrites between SPICE dialects
+//!
+//! Netlists exported for one engine often use constructs another rejects. Each rule rewrites or
+//! strips one such construct for a target engine and describes the change for the response.
+
+/// Dialect a netlist was written for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dialect {
+    Ltspice,
+    Ngspice,
+    Pspice,
+    /// Unknown origin: apply every rule for the target engine
+    Auto,
+}
+
+impl Dialect {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.trim().to_lowercase().as_str() {
+            "ltspice" => Some(Dialect::Ltspice),
+            "ngspice" => Some(Dialect::Ngspice),
+            "pspice" => Some(Dialect::Pspice),
+            "auto" => Some(Dialect::Auto),
+            _ => None,
+        }
+    }
+}
+
+/// Replacement for one netlist line, None to drop it, with a note describing the change
+struct Rewrite {
+    line: Option<String>,
+    note: String,
+}
+
+/// Facts about the machine that some rules depend on
+struct Context {
+    windows: bool,
+}
+
+/// One compatibility rule
+struct Rule {
+    /// Engine the rule prepares netlists for
+    target: &'static str,
+    /// Dialects whose netlists need the rule
+    dialects: &'static [Dialect],
+    rewrite: fn(&str, &Context) -> Option<Rewrite>,
+}
+
+const RULES: &[Rule] = &[
+    Rule { target: "ltspice", dialects: &[Dialect::Pspice, Dialect::Ngspice], rewrite: strip_probe },
+    Rule { target: "ltspice", dialects: &[Dialect::Pspice, Dialect::Ngspice], rewrite: bare_coupling },
+    Rule { target: "ltspice", dialects: &[Dialect::Pspice, Dialect::Ngspice], rewrite: include_backslashes },
+    Rule { target: "ngspice", dialects: &[Dialect::Ltspice], rewrite: strip_backanno },
+];
+
+/// Rewrite a netlist written in `dialect` for the `target` engine ("ltspice" or "ngspice")
+/// Returns the netlist and one note per change
+pub fn translate(netlist: &str, dialect: Dialect, target: &str) -> (String, Vec<String>) {
+    translate_with(netlist, dialect, target, &Context { windows: cfg!(windows) })
+}
+
+fn translate_with(netlist: &str, dialect: Dialect, target: &str, context: &Context) -> (String, Vec<String>) {
+    let rules: Vec<&Rule> = RULES
+        .iter()
+        .filter(|rule| rule.target == target && (dialect == Dialect::Auto || rule.dialects.contains(&dialect)))
+        .collect();
+    if rules.is_empty() {
+        return (netlist.to_string(), Vec::new());
+    }
+
+    let mut lines = Vec::new();
+    let mut notes = Vec::new();
+    'lines: for line in netlist.lines() {
+        let mut current = line.to_string();
+        if !current.trim_start().starts_with('*') {
+            for rule in &rules {
+                if let Some(rewrite) = (rule.rewrite)(&current, context) {
+                    notes.push(rewrite.note);
+                    match rewrite.line {
+                        Some(line) => current = line,
+                        None => continue 'lines,
+                    }
+                }
+            }
+        }
+        lines.push(current);
+    }
+    (lines.join("\n"), notes)
+}
+
+/// First token of a line, lowercased
+fn keyword(line: &str) -> Option<String> {
+    line.split_whitespace().next().map(str::to_lowercase)
+}
+
+/// PSpice `.probe` picks the signals to save; LTspice rejects it and saves everything anyway
+fn strip_probe(line: &str, _: &Context) -> Option<Rewrite> {
+    let keyword = keyword(line)?;
+    if keyword != ".probe" && !keyword.starts_with(".probe/") {
+        return None;
+    }
+    Some(Rewrite {
+        line: None,
+        note: format!("Removed PSpice directive: {}", line.trim()),
+    })
+}
+
+/// `K1 L1 L2 k=0.99` or `K1 L1 L2 0.99 CORE`: LTspice takes only a bare coupling value
+fn bare_coupling(line: &str, _: &Context) -> Option<Rewrite> {
+    let tokens: Vec<&str> = line.split_whitespace().collect();
+    if !tokens.first()?.to_lowercase().starts_with('k') {
+        return None;
+    }
+    let inductors = tokens[1..].iter().take_while(|t| t.to_lowercase().starts_with('l')).count();
+    if inductors < 2 {
+        return None;
+    }
+    let (value, extra) = tokens[1 + inductors..].split_first()?;
+    let bare = match value.get(..2) {
+        Some(prefix) if prefix.eq_ignore_ascii_case("k=") => &value[2..],
+        _ => value,
+    };
+    if bare == *value && extra.is_empty() {
+        return None;
+    }
+
+    let rewritten = format!("{} {}", tokens[..1 + inductors].join(" "), bare);
+    Some(Rewrite {
+        note: format!("Rewrote coupling \"{}\" as \"{}\"", line.trim(), rewritten),
+        line: Some(rewritten),
+    })
+}
+
+/// Forward-slash `.include`/`.lib` paths, which LTspice on Windows does not resolve
+fn include_backslashes(line: &str, context: &Context) -> Option<Rewrite> {
+    if !context.windows {
+        return None;
+    }
+    let keyword = keyword(line)?;
+    if !matches!(keyword.as_str(), ".include" | ".inc" | ".lib") {
+        return None;
+    }
+    let trimmed = line.trim();
+    let path = trimmed[keyword.len()..].trim();
+    if !path.contains('/') {
+        return None;
+    }
+
+    let rewritten = format!("{} {}", &trimmed[..keyword.len()], path.replace('/', "\\"));
+    Some(Rewrite {
+        note: format!("Converted path separators: {} -> {}", trimmed, rewritten),
+        line: Some(rewritten),
+    })
+}
+
+/// `.backanno` is written by LTspice's netlister for the schematic editor; ngspice doesn't know it
+fn strip_backanno(line: &str, _: &Context) -> Option<Rewrite> {
+    (keyword(line)? == ".backanno").then(|| Rewrite {
+        line: None,
+        note: "Removed LTspice directive: .backanno".to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const UNIX: Context = Context { windows: false };
+    const WINDOWS: Context = Context { windows: true };
+
+    fn rewrite(rule: fn(&str, &Context) -> Option<Rewrite>, line: &str, context: &Context) -> Option<Option<String>> {
+        rule(line, context).map(|rewrite| rewrite.line)
+    }
+
+    #[test]
+    fn test_dialect_parse() {
+        assert_eq!(Dialect::parse("PSpice"), Some(Dialect::Pspice));
+        assert_eq!(Dialect::parse("auto"), Some(Dialect::Auto));
+        assert_eq!(Dialect::parse("kicad"), None);
+    }
+
+    #[test]
+    fn test_strip_probe() {
+        assert_eq!(rewrite(strip_probe, ".PROBE V(out)", &UNIX), Some(None));
+        assert_eq!(rewrite(strip_probe, ".probe/csdf", &UNIX), Some(None));
+        assert_eq!(rewrite(strip_probe, ".probes", &UNIX), None);
+    }
+
+    #[test]
+    fn test_bare_coupling() {
+        assert_eq!(
+            rewrite(bare_coupling, "K1 L1 L2 k=0.99", &UNIX),
+            Some(Some("K1 L1 L2 0.99".to_string()))
+        );
+        assert_eq!(
+            rewrite(bare_coupling, "K2 Lp Ls1 Ls2 1 K528T500_3C8", &UNIX),
+            Some(Some("K2 Lp Ls1 Ls2 1".to_string()))
+        );
+        assert_eq!(rewrite(bare_coupling, "K1 L1 L2 0.99", &UNIX), None);
+        // Not a coupling line
+        assert_eq!(rewrite(bare_coupling, "Kx out 0 k=1", &UNIX), None);
+    }
+
+    #[test]
+    fn test_include_backslashes() {
+        assert_eq!(
+            rewrite(include_backslashes, ".include models/opamps.lib", &WINDOWS),
+            Some(Some(".include models\\opamps.lib".to_string()))
+        );
+        assert_eq!(rewrite(include_backslashes, ".lib standard.dio", &WINDOWS), None);
+        assert_eq!(rewrite(include_backslashes, ".include models/opamps.lib", &UNIX), None);
+    }
+
+    #[test]
+    fn test_strip_backanno() {
+        assert_eq!(rewrite(strip_backanno, ".backanno", &UNIX), Some(None));
+        assert_eq!(rewrite(strip_backanno, ".end", &UNIX), None);
+    }
+
+    #[test]
+    fn test_translate_selects_rules_by_dialect_and_target() {
+        let netlist = "* .probe in a comment\nL1 a 0 1u\nL2 b 0 1u\nK1 L1 L2 k=1\n.probe\n.backanno\n.end";
+
+        let (translated, notes) = translate_with(netlist, Dialect::Pspice, "ltspice", &UNIX);
+        assert_eq!(translated, "* .probe in a comment\nL1 a 0 1u\nL2 b 0 1u\nK1 L1 L2 1\n.backanno\n.end");
+        assert_eq!(notes.len(), 2);
+
+        let (translated, notes) = translate_with(netlist, Dialect::Auto, "ngspice", &UNIX);
+        assert!(!translated.contains(".backanno"));
+        assert_eq!(notes, ["Removed LTspice directive: .backanno"]);
+
+        // A netlist already in the target's dialect is left alone
+        let (translated, notes) = translate_with(netlist, Dialect::Ltspice, "ltspice", &UNIX);
+        assert_eq!(translated, netlist);
+        assert!(notes.is_empty());
+    }
+}
@@ -0,0 +1,462 @@
+// Copyright (c) 2024-2025 Wanyeki Technologies LLC. All rights reserved.
+// This source code is licensed under the proprietary license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Recently seen simulate request ids and their responses
+//!
+//! Results are delivered to whichever connections are attached to a run when it finishes, not
+//! just the one that sent it. A client that re-sends a request or asks for it with `get_result`
+//! after a dropped connection joins the run in flight or gets the stored response. Stored
+//! responses count against the resource budget's `max_result_bytes` by the size of their results.
+
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc::{self, error::TrySendError};
+
+use crate::export;
+use crate::protocol::{now_ms, SimulationResponse};
+use crate::resource_budget::{self, BudgetEntry, Budgeted};
+
+/// Number of request ids remembered
+pub const MAX_RECENT_REQUESTS: usize = 100;
+
+/// How long a finished response is kept, in ms
+pub const RESULT_RETENTION_MS: u64 = 15 * 60 * 1000;
+
+/// Responses with larger results are not kept
+const MAX_STORED_RESULTS_BYTES: usize = 32 * 1024 * 1024;
+
+/// Connections receiving a running simulation's progress and result
+#[derive(Default)]
+pub struct Listeners {
+    senders: Mutex<Vec<mpsc::Sender<String>>>,
+}
+
+impl Listeners {
+    fn attach(&self, sender: &mpsc::Sender<String>) {
+        if let Ok(mut senders) = self.senders.lock() {
+            senders.push(sender.clone());
+        }
+    }
+
+    /// Connections still open; closed ones are forgotten
+    fn open(&self) -> Vec<mpsc::Sender<String>> {
+        let Ok(mut senders) = self.senders.lock() else {
+            return Vec::new();
+        };
+        senders.retain(|sender| !sender.is_closed());
+        senders.clone()
+    }
+
+    /// Whether `sender`'s connection is attached
+    pub fn includes(&self, sender: &mpsc::Sender<String>) -> bool {
+        self.senders.lock().is_ok_and(|senders| senders.iter().any(|attached| attached.same_channel(sender)))
+    }
+
+    pub fn any_open(&self) -> bool {
+        !self.open().is_empty()
+    }
+
+    /// Listeners for a run only `sender` receives
+    pub fn new(sender: &mpsc::Sender<String>) -> Self {
+        let listeners = Self::default();
+        listeners.attach(sender);
+        listeners
+    }
+
+    /// Queue a progress update for every open connection without waiting
+    /// Returns how many connections were too far behind to take it; they get a later update instead
+    pub fn offer(&self, message: &str) -> usize {
+        self.open()
+            .iter()
+            .filter(|sender| matches!(sender.try_send(message.to_string()), Err(TrySendError::Full(_))))
+            .count()
+    }
+
+    /// Queue a message for every open connection, returning how many accepted it
+    pub async fn send(&self, message: &str) -> usize {
+        let mut delivered = 0;
+        for sender in self.open() {
+            if sender.send(message.to_string()).await.is_ok() {
+                delivered += 1;
+            }
+        }
+        delivered
+    }
+}
+
+enum Run {
+    Running(Arc<Listeners>),
+    /// The response, unless it was too large to keep or evicted to stay within the budget
+    Finished(Option<Arc<SimulationResponse>>),
+}
+
+struct RecentRequest {
+    request_id: String,
+    /// When the request was first seen, or when it finished
+    updated_at: u64,
+    run: Run,
+    /// Size of the stored response's results
+    bytes: u64,
+    /// Stamp from `resource_budget::next_use` of when the stored response was last read
+    last_used: AtomicU64,
+}
+
+impl RecentRequest {
+    fn touch(&self) {
+        self.last_used.store(resource_budget::next_use(), Ordering::Relaxed);
+    }
+}
+
+/// How to handle an incoming simulate request
+pub enum Seen {
+    /// Not seen before: run it, sending messages to these listeners
+    New(Arc<Listeners>),
+    /// Already running; the connection now receives its remaining messages
+    Attached,
+    /// Already finished; replay this response
+    Finished(Arc<SimulationResponse>),
+}
+
+/// What is kept of an earlier simulate request
+pub enum Stored {
+    Running,
+    Finished(Arc<SimulationResponse>),
+    /// Finished, but the response was too large to keep or was evicted
+    Dropped,
+}
+
+/// The last `MAX_RECENT_REQUESTS` simulate request ids, oldest first
+#[derive(Default)]
+pub struct RecentRequests {
+    entries: VecDeque<RecentRequest>,
+}
+
+impl RecentRequests {
+    /// Look up a request id, registering it as running if it is new
+    /// A finished request whose response was not kept, or has expired, runs again
+    pub fn begin(&mut self, request_id: &str, sender: &mpsc::Sender<String>) -> Seen {
+        self.begin_at(request_id, sender, now_ms())
+    }
+
+    fn begin_at(&mut self, request_id: &str, sender: &mpsc::Sender<String>, now: u64) -> Seen {
+        if let Some(seen) = self.lookup_at(request_id, sender, now) {
+            return seen;
+        }
+        self.entries.retain(|entry| entry.request_id != request_id);
+
+        if self.entries.len() >= MAX_RECENT_REQUESTS {
+            // Forget the oldest finished request; a running one is only dropped if nothing else is left
+            let index = self
+                .entries
+                .iter()
+                .position(|entry| matches!(entry.run, Run::Finished(_)))
+                .unwrap_or(0);
+            self.entries.remove(index);
+        }
+
+        let listeners = Arc::new(Listeners::new(sender));
+        self.entries.push_back(RecentRequest {
+            request_id: request_id.to_string(),
+            updated_at: now,
+            run: Run::Running(listeners.clone()),
+            bytes: 0,
+            last_used: AtomicU64::new(0),
+        });
+        Seen::New(listeners)
+    }
+
+    /// True if `request_id` is running or finished recently enough to be replayed
+    pub fn contains(&self, request_id: &str) -> bool {
+        let now = now_ms();
+        self.entries.iter().any(|entry| {
+            entry.request_id == request_id
+                && (matches!(entry.run, Run::Running(_)) || now.saturating_sub(entry.updated_at) < RESULT_RETENTION_MS)
+        })
+    }
+
+    /// Look at a request without attaching to it; None if the id is unknown or expired
+    pub fn stored(&self, request_id: &str) -> Option<Stored> {
+        self.stored_at(request_id, now_ms())
+    }
+
+    fn stored_at(&self, request_id: &str, now: u64) -> Option<Stored> {
+        let entry = self.entries.iter().find(|entry| entry.request_id == request_id)?;
+        match &entry.run {
+            Run::Running(_) => Some(Stored::Running),
+            Run::Finished(_) if now.saturating_sub(entry.updated_at) >= RESULT_RETENTION_MS => None,
+            Run::Finished(Some(response)) => {
+                entry.touch();
+                Some(Stored::Finished(response.clone()))
+            }
+            Run::Finished(None) => Some(Stored::Dropped),
+        }
+    }
+
+    /// Attach `sender` to a running request, or get its stored response
+    /// None if the id is unknown, expired or its response was too large to keep
+    pub fn lookup(&mut self, request_id: &str, sender: &mpsc::Sender<String>) -> Option<Seen> {
+        self.lookup_at(request_id, sender, now_ms())
+    }
+
+    fn lookup_at(&mut self, request_id: &str, sender: &mpsc::Sender<String>, now: u64) -> Option<Seen> {
+        self.expire(now);
+        let entry = self.entries.iter().find(|entry| entry.request_id == request_id)?;
+        match &entry.run {
+            Run::Running(listeners) => {
+                listeners.attach(sender);
+                Some(Seen::Attached)
+            }
+            Run::Finished(response) => {
+                entry.touch();
+                response.clone().map(Seen::Finished)
+            }
+        }
+    }
+
+    /// Forget finished requests past the retention time
+    fn expire(&mut self, now: u64) {
+        self.entries.retain(|entry| {
+            matches!(entry.run, Run::Running(_)) || now.saturating_sub(entry.updated_at) < RESULT_RETENTION_MS
+        });
+    }
+
+    /// Store the response of a run registered by `begin`, for later duplicates and `get_result`
+    pub fn finish(&mut self, response: &SimulationResponse) {
+        self.finish_at(response, now_ms());
+    }
+
+    fn finish_at(&mut self, response: &SimulationResponse, now: u64) {
+        let Some(entry) = self
+            .entries
+            .iter_mut()
+            .find(|entry| entry.request_id == response.request_id && matches!(entry.run, Run::Running(_)))
+        else {
+            return;
+        };
+
+        let results_bytes = response.results.as_ref().map_or(0, export::results_size_bytes);
+        let stored = (results_bytes <= MAX_STORED_RESULTS_BYTES).then(|| Arc::new(response.clone()));
+        entry.updated_at = now;
+        entry.bytes = if stored.is_some() { results_bytes as u64 } else { 0 };
+        entry.run = Run::Finished(stored);
+        entry.touch();
+    }
+}
+
+/// Stored responses count by the size of their results; evicting one leaves its id known, so a
+/// `get_result` for it reports it as dropped rather than unknown
+impl Budgeted for RecentRequests {
+    fn budget_entries(&self) -> Vec<BudgetEntry> {
+        self.entries
+            .iter()
+            .filter(|entry| matches!(entry.run, Run::Finished(Some(_))))
+            .map(|entry| BudgetEntry {
+                key: entry.request_id.clone(),
+                size: entry.bytes,
+                last_used: entry.last_used.load(Ordering::Relaxed),
+            })
+            .collect()
+    }
+
+    fn evict(&mut self, key: &str) {
+        for entry in self.entries.iter_mut().filter(|entry| entry.request_id == key) {
+            if matches!(entry.run, Run::Finished(Some(_))) {
+                entry.run = Run::Finished(None);
+                entry.bytes = 0;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::SimulationTimings;
+
+    fn response(request_id: &str) -> SimulationResponse {
+        SimulationResponse {
+            id: "resp-1".to_string(),
+            msg_type: "simulation_result".to_string(),
+            request_id: request_id.to_string(),
+            timestamp: 0,
+            success: true,
+            f32_results: false,
+            results: None,
+            error: None,
+            error_code: None,
+            execution_time: 100,
+            simulator: "ngspice".to_string(),
+            monte_carlo: None,
+            duplicate: false,
+            retried_with: None,
+            translations: Vec::new(),
+            debug_info: None,
+            results_compressed: None,
+            compression: None,
+            retry_after_ms: None,
+            warnings: Vec::new(),
+            simulator_version: None,
+            simulator_binary_sha256: None,
+            replay_of: None,
+            fallback_simulator_used: None,
+            engine_errors: Vec::new(),
+            artifact_id: None,
+            raw_file_size: None,
+            trace_catalog: None,
+            operating_point: None,
+            wave_data: None,
+            libraries_used: Vec::new(),
+            busy_info: None,
+            timings: SimulationTimings::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_attaches_then_replays() {
+        let mut recent = RecentRequests::default();
+        let (first_tx, first_rx) = mpsc::channel(4);
+        let (second_tx, mut second_rx) = mpsc::channel(4);
+
+        let Seen::New(listeners) = recent.begin_at("sim-1", &first_tx, 0) else {
+            panic!("expected a new run");
+        };
+        // The first connection drops and its retry arrives on a new one
+        drop(first_rx);
+        assert!(matches!(recent.begin_at("sim-1", &second_tx, 10), Seen::Attached));
+
+        assert_eq!(listeners.send("progress").await, 1);
+        assert_eq!(second_rx.recv().await.as_deref(), Some("progress"));
+
+        recent.finish_at(&response("sim-1"), 20);
+        let Seen::Finished(stored) = recent.begin_at("sim-1", &second_tx, 30) else {
+            panic!("expected the stored response");
+        };
+        assert_eq!(stored.execution_time, 100);
+
+        // Once expired the request runs again
+        assert!(recent.lookup_at("sim-1", &second_tx, 20 + RESULT_RETENTION_MS).is_none());
+        assert!(matches!(recent.begin_at("sim-1", &second_tx, 20 + RESULT_RETENTION_MS), Seen::New(_)));
+    }
+
+    #[tokio::test]
+    async fn test_stored_does_not_attach() {
+        let mut recent = RecentRequests::default();
+        let (tx, mut rx) = mpsc::channel(4);
+        let Seen::New(listeners) = recent.begin_at("sim-1", &tx, 0) else {
+            panic!("expected a new run");
+        };
+        assert!(matches!(recent.stored_at("sim-1", 10), Some(Stored::Running)));
+        assert!(recent.stored_at("sim-2", 10).is_none());
+        // Only the connection that started the run receives its messages
+        assert_eq!(listeners.send("result").await, 1);
+        assert_eq!(rx.recv().await.as_deref(), Some("result"));
+
+        recent.finish_at(&response("sim-1"), 20);
+        let Some(Stored::Finished(stored)) = recent.stored_at("sim-1", 30) else {
+            panic!("expected the stored response");
+        };
+        assert_eq!(stored.request_id, "sim-1");
+        assert!(recent.stored_at("sim-1", 20 + RESULT_RETENTION_MS).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_progress_is_dropped_for_a_slow_reader() {
+        let (fast_tx, mut fast_rx) = mpsc::channel(4);
+        let (slow_tx, mut slow_rx) = mpsc::channel(2);
+        let listeners = Listeners::new(&fast_tx);
+        listeners.attach(&slow_tx);
+
+        // The slow connection never reads; its queue fills and later updates skip it
+        let mut dropped = 0;
+        for percent in 0..4 {
+            dropped += listeners.offer(&format!("progress {}", percent));
+            assert_eq!(fast_rx.recv().await, Some(format!("progress {}", percent)));
+        }
+        assert_eq!(dropped, 2);
+
+        // Results still wait for room rather than being dropped
+        let send = tokio::spawn(async move { listeners.send("result").await });
+        assert_eq!(slow_rx.recv().await.as_deref(), Some("progress 0"));
+        assert_eq!(send.await.unwrap(), 2);
+        assert_eq!(slow_rx.recv().await.as_deref(), Some("progress 1"));
+        assert_eq!(slow_rx.recv().await.as_deref(), Some("result"));
+    }
+
+    #[tokio::test]
+    async fn test_lookup_attaches_to_running_request() {
+        let mut recent = RecentRequests::default();
+        let (first_tx, first_rx) = mpsc::channel(4);
+        let (second_tx, mut second_rx) = mpsc::channel(4);
+
+        let Seen::New(listeners) = recent.begin_at("sim-1", &first_tx, 0) else {
+            panic!("expected a new run");
+        };
+        drop(first_rx);
+        assert!(recent.lookup_at("unknown", &second_tx, 0).is_none());
+        assert!(matches!(recent.lookup_at("sim-1", &second_tx, 0), Some(Seen::Attached)));
+
+        // Running requests never expire
+        recent.expire(RESULT_RETENTION_MS * 2);
+        assert_eq!(listeners.send("result").await, 1);
+        assert_eq!(second_rx.recv().await.as_deref(), Some("result"));
+    }
+
+    #[test]
+    fn test_oldest_finished_request_is_forgotten() {
+        let mut recent = RecentRequests::default();
+        let (tx, _rx) = mpsc::channel(1);
+
+        recent.begin_at("running", &tx, 0);
+        for i in 1..MAX_RECENT_REQUESTS {
+            let id = format!("sim-{}", i);
+            recent.begin_at(&id, &tx, 0);
+            recent.finish_at(&response(&id), 0);
+        }
+        recent.begin_at("one-more", &tx, 0);
+
+        assert_eq!(recent.entries.len(), MAX_RECENT_REQUESTS);
+        assert!(matches!(recent.begin_at("running", &tx, 0), Seen::Attached));
+        assert!(matches!(recent.begin_at("sim-2", &tx, 0), Seen::Finished(_)));
+        assert!(matches!(recent.begin_at("sim-1", &tx, 0), Seen::New(_)));
+    }
+
+    #[test]
+    fn test_evicted_response_is_reported_dropped() {
+        let mut recent = RecentRequests::default();
+        let (tx, _rx) = mpsc::channel(1);
+        let with_results = |request_id: &str| SimulationResponse {
+            results: Some(crate::protocol::SimulationResults {
+                time: vec![0.0; 100],
+                traces: Vec::new(),
+                analysis_type: "transient".to_string(),
+                x_axis_label: None,
+                x_axis_unit: None,
+                scalar_results: None,
+                device_operating_points: Default::default(),
+                truncated: None,
+                plot_hints: None,
+                raw_header: None,
+            }),
+            ..response(request_id)
+        };
+        for id in ["a", "b"] {
+            recent.begin_at(id, &tx, 0);
+            recent.finish_at(&with_results(id), 0);
+        }
+        recent.begin_at("running", &tx, 0);
+        // Reading "a" makes "b" the least recently used
+        assert!(matches!(recent.stored_at("a", 1), Some(Stored::Finished(_))));
+
+        let entries = recent.budget_entries();
+        assert_eq!(entries.iter().map(|entry| entry.key.as_str()).collect::<Vec<_>>(), ["a", "b"]);
+        assert!(entries[0].size > 0);
+        assert!(entries[1].last_used < entries[0].last_used);
+
+        Budgeted::evict(&mut recent, "b");
+        assert!(matches!(recent.stored_at("b", 1), Some(Stored::Dropped)));
+        assert!(recent.lookup_at("b", &tx, 1).is_none());
+        assert_eq!(recent.budget_entries().len(), 1);
+    }
+}
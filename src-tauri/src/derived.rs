@@ -0,0 +1,441 @@
+// Copyright (c) 2024-2025 Wanyeki Technologies LLC. All rights reserved.
+// This source code is licensed under the proprietary license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Traces computed from the traces of an existing result, such as `V(a)-V(b)`, `-I(R1)` or
+//! `2*V(out)`, without running the simulator again
+//!
+//! Expressions combine trace references and SPICE numbers (`1k`, `2.5m`) with `+ - * /` and
+//! parentheses. `V(a,b)` stands for `V(a)-V(b)` when the result has no trace of that name.
+//! AC traces are combined as complex values, so the result keeps a magnitude and phase.
+
+use crate::netlist;
+use crate::protocol::{DerivedTrace, SimulationResults, Trace};
+
+/// Most traces computed by one `post_process` request
+pub const MAX_DERIVED_TRACES: usize = 32;
+
+/// Longest expression accepted, in bytes
+const MAX_EXPRESSION_BYTES: usize = 1024;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Expr {
+    Number(f64),
+    Trace(String),
+    Negate(Box<Expr>),
+    Binary(char, Box<Expr>, Box<Expr>),
+}
+
+/// Recursive descent parser over the expression's characters
+struct Parser<'a> {
+    chars: Vec<char>,
+    pos: usize,
+    source: &'a str,
+}
+
+impl<'a> Parser<'a> {
+    fn new(source: &'a str) -> Self {
+        Self { chars: source.chars().collect(), pos: 0, source }
+    }
+
+    fn error(&self, message: &str) -> String {
+        format!("Invalid expression '{}': {} at position {}", self.source, message, self.pos + 1)
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.chars.get(self.pos).is_some_and(|c| c.is_whitespace()) {
+            self.pos += 1;
+        }
+    }
+
+    fn peek(&mut self) -> Option<char> {
+        self.skip_whitespace();
+        self.chars.get(self.pos).copied()
+    }
+
+    fn parse(mut self) -> Result<Expr, String> {
+        if self.peek().is_none() {
+            return Err(format!("Invalid expression '{}': it is empty", self.source));
+        }
+        let expr = self.sum()?;
+        match self.peek() {
+            None => Ok(expr),
+            Some(c) => Err(self.error(&format!("unexpected '{}'", c))),
+        }
+    }
+
+    fn sum(&mut self) -> Result<Expr, String> {
+        let mut expr = self.product()?;
+        while let Some(op @ ('+' | '-')) = self.peek() {
+            self.pos += 1;
+            expr = Expr::Binary(op, Box::new(expr), Box::new(self.product()?));
+        }
+        Ok(expr)
+    }
+
+    fn product(&mut self) -> Result<Expr, String> {
+        let mut expr = self.unary()?;
+        while let Some(op @ ('*' | '/')) = self.peek() {
+            self.pos += 1;
+            expr = Expr::Binary(op, Box::new(expr), Box::new(self.unary()?));
+        }
+        Ok(expr)
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        match self.peek() {
+            Some('-') => {
+                self.pos += 1;
+                Ok(Expr::Negate(Box::new(self.unary()?)))
+            }
+            Some('+') => {
+                self.pos += 1;
+                self.unary()
+            }
+            _ => self.primary(),
+        }
+    }
+
+    fn primary(&mut self) -> Result<Expr, String> {
+        match self.peek() {
+            None => Err(self.error("expected a value")),
+            Some('(') => {
+                self.pos += 1;
+                let expr = self.sum()?;
+                if self.peek() != Some(')') {
+                    return Err(self.error("expected ')'"));
+                }
+                self.pos += 1;
+                Ok(expr)
+            }
+            Some(c) if c.is_ascii_digit() || c == '.' => self.number(),
+            Some(c) if c.is_alphabetic() || c == '_' => self.trace(),
+            Some(c) => Err(self.error(&format!("unexpected '{}'", c))),
+        }
+    }
+
+    /// A SPICE number; `e` followed by a sign is an exponent, other letters a scale suffix
+    fn number(&mut self) -> Result<Expr, String> {
+        let start = self.pos;
+        while let Some(&c) = self.chars.get(self.pos) {
+            let exponent_sign = (c == '+' || c == '-')
+                && matches!(self.chars.get(self.pos - 1), Some('e' | 'E'))
+                && self.chars[start..self.pos - 1].iter().all(|c| c.is_ascii_digit() || *c == '.');
+            if !(c.is_ascii_alphanumeric() || c == '.' || exponent_sign) {
+                break;
+            }
+            self.pos += 1;
+        }
+        let token: String = self.chars[start..self.pos].iter().collect();
+        netlist::parse_spice_number(&token).map(Expr::Number).ok_or_else(|| {
+            self.pos = start;
+            self.error(&format!("'{}' is not a number", token))
+        })
+    }
+
+    /// A trace name, with its parenthesized part: `V(out)`, `I(R1)`, `Ix(U1:OUT)`, or an alias like `Vout`
+    fn trace(&mut self) -> Result<Expr, String> {
+        let start = self.pos;
+        while self
+            .chars
+            .get(self.pos)
+            .is_some_and(|c| c.is_alphanumeric() || matches!(c, '_' | '#' | ':' | '.'))
+        {
+            self.pos += 1;
+        }
+        if self.chars.get(self.pos) == Some(&'(') {
+            let Some(close) = self.chars[self.pos..].iter().position(|c| *c == ')') else {
+                return Err(self.error("expected ')'"));
+            };
+            self.pos += close + 1;
+        }
+        let name: String = self.chars[start..self.pos].iter().filter(|c| !c.is_whitespace()).collect();
+        Ok(Expr::Trace(name))
+    }
+}
+
+/// Sample values of an evaluated expression; `complex` when any operand was an AC trace
+struct Values {
+    /// One entry per point, or a single entry for a constant
+    points: Vec<(f64, f64)>,
+    complex: bool,
+    unit: Option<String>,
+    /// Trace whose length the points came from, for error messages
+    source: Option<String>,
+}
+
+impl Values {
+    fn constant(value: f64) -> Self {
+        Self { points: vec![(value, 0.0)], complex: false, unit: None, source: None }
+    }
+
+    fn from_trace(trace: &Trace) -> Self {
+        let points = match &trace.phase {
+            Some(phase) => trace
+                .data
+                .iter()
+                .zip(phase)
+                .map(|(magnitude, degrees)| {
+                    let radians = degrees.to_radians();
+                    (magnitude * radians.cos(), magnitude * radians.sin())
+                })
+                .collect(),
+            None => trace.data.iter().map(|value| (*value, 0.0)).collect(),
+        };
+        Self {
+            points,
+            complex: trace.phase.is_some(),
+            unit: (!trace.unit.is_empty()).then(|| trace.unit.clone()),
+            source: Some(trace.name.clone()),
+        }
+    }
+
+    fn negate(mut self) -> Self {
+        for (re, im) in &mut self.points {
+            *re = -*re;
+            *im = -*im;
+        }
+        self
+    }
+
+    fn combine(self, op: char, other: Values) -> Result<Self, String> {
+        if let (Some(a), Some(b)) = (&self.source, &other.source) {
+            if self.points.len() != other.points.len() {
+                return Err(format!(
+                    "Traces {} ({} points) and {} ({} points) have different lengths",
+                    a,
+                    self.points.len(),
+                    b,
+                    other.points.len()
+                ));
+            }
+        }
+        let len = self.points.len().max(other.points.len());
+        let at = |values: &Values, i: usize| values.points[if values.points.len() == 1 { 0 } else { i }];
+        let points = (0..len)
+            .map(|i| {
+                let ((a, b), (c, d)) = (at(&self, i), at(&other, i));
+                match op {
+                    '+' => (a + c, b + d),
+                    '-' => (a - c, b - d),
+                    '*' => (a * c - b * d, a * d + b * c),
+                    _ => {
+                        let denominator = c * c + d * d;
+                        ((a * c + b * d) / denominator, (b * c - a * d) / denominator)
+                    }
+                }
+            })
+            .collect();
+
+        // Sums keep a shared unit, scaling by a number keeps the trace's; anything else has none
+        let unit = match (op, &self.unit, &other.unit) {
+            ('+' | '-', a, b) if a == b => a.clone(),
+            ('+' | '-', Some(a), None) | ('+' | '-', None, Some(a)) => Some(a.clone()),
+            ('*', a, None) | ('*', None, a) | ('/', a, None) => a.clone(),
+            _ => None,
+        };
+        let source = if self.points.len() >= other.points.len() { self.source } else { other.source };
+        Ok(Self { points, complex: self.complex || other.complex, unit, source })
+    }
+}
+
+/// Find a trace by name, or by raw name when it was aliased, ignoring case and whitespace;
+/// `V(a,b)` falls back to `V(a)-V(b)`
+fn resolve(results: &SimulationResults, name: &str) -> Result<Values, (String, &'static str)> {
+    let normalized = |name: &str| name.chars().filter(|c| !c.is_whitespace()).collect::<String>().to_lowercase();
+    let wanted = normalized(name);
+    let named = |trace: &&Trace| normalized(&trace.name) == wanted || trace.raw_name.as_deref().is_some_and(|raw| normalized(raw) == wanted);
+    if let Some(trace) = results.traces.iter().find(named) {
+        return Ok(Values::from_trace(trace));
+    }
+
+    let pair = wanted
+        .strip_prefix("v(")
+        .and_then(|rest| rest.strip_suffix(')'))
+        .and_then(|nodes| nodes.split_once(','));
+    if let Some((a, b)) = pair {
+        let positive = resolve(results, &format!("V({})", a))?;
+        if b == "0" || b == "gnd" {
+            return Ok(positive);
+        }
+        let negative = resolve(results, &format!("V({})", b))?;
+        return positive.combine('-', negative).map_err(|error| (error, "LENGTH_MISMATCH"));
+    }
+    Err((format!("Trace {} is not in the results", name), "UNKNOWN_TRACE"))
+}
+
+fn evaluate(results: &SimulationResults, expr: &Expr) -> Result<Values, (String, &'static str)> {
+    match expr {
+        Expr::Number(value) => Ok(Values::constant(*value)),
+        Expr::Trace(name) => resolve(results, name),
+        Expr::Negate(inner) => Ok(evaluate(results, inner)?.negate()),
+        Expr::Binary(op, a, b) => {
+            let (a, b) = (evaluate(results, a)?, evaluate(results, b)?);
+            a.combine(*op, b).map_err(|error| (error, "LENGTH_MISMATCH"))
+        }
+    }
+}
+
+/// Compute one trace from the traces of `results`
+/// Errors are a message and an error code: `INVALID_EXPRESSION`, `UNKNOWN_TRACE` or `LENGTH_MISMATCH`
+pub fn derive_trace(results: &SimulationResults, derived: &DerivedTrace) -> Result<Trace, (String, &'static str)> {
+    if derived.expression.len() > MAX_EXPRESSION_BYTES {
+        return Err((
+            format!("Expression is longer than {} bytes", MAX_EXPRESSION_BYTES),
+            "INVALID_EXPRESSION",
+        ));
+    }
+    let expr = Parser::new(&derived.expression).parse().map_err(|error| (error, "INVALID_EXPRESSION"))?;
+    let mut values = evaluate(results, &expr)?;
+
+    // A constant is spread over the x axis
+    if values.source.is_none() {
+        values.points = vec![values.points[0]; results.time.len()];
+    } else if !results.time.is_empty() && values.points.len() != results.time.len() {
+        return Err((
+            format!(
+                "Trace {} has {} points but the x axis has {}",
+                values.source.unwrap_or_default(),
+                values.points.len(),
+                results.time.len()
+            ),
+            "LENGTH_MISMATCH",
+        ));
+    }
+
+    let name = derived.name.clone().filter(|name| !name.trim().is_empty()).unwrap_or_else(|| derived.expression.clone());
+    let (data, phase) = if values.complex {
+        values
+            .points
+            .iter()
+            .map(|(re, im)| ((re * re + im * im).sqrt(), im.atan2(*re).to_degrees()))
+            .unzip()
+    } else {
+        (values.points.iter().map(|(re, _)| *re).collect(), Vec::new())
+    };
+    Ok(Trace { name, data, unit: values.unit.unwrap_or_default(), phase: values.complex.then_some(phase), raw_name: None })
+}
+
+/// Compute every requested trace, failing on the first that can't be
+pub fn derive_traces(results: &SimulationResults, derived: &[DerivedTrace]) -> Result<Vec<Trace>, (String, &'static str)> {
+    if derived.is_empty() {
+        return Err(("No traces requested".to_string(), "INVALID_EXPRESSION"));
+    }
+    if derived.len() > MAX_DERIVED_TRACES {
+        return Err((
+            format!("{} traces requested; at most {} can be computed at once", derived.len(), MAX_DERIVED_TRACES),
+            "INVALID_EXPRESSION",
+        ));
+    }
+    derived.iter().map(|derived| derive_trace(results, derived)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    fn trace(name: &str, data: Vec<f64>, phase: Option<Vec<f64>>) -> Trace {
+        Trace { name: name.to_string(), data, unit: "V".to_string(), phase, raw_name: None }
+    }
+
+    fn results(traces: Vec<Trace>) -> SimulationResults {
+        SimulationResults {
+            time: vec![0.0, 1.0, 2.0],
+            traces,
+            analysis_type: "transient".to_string(),
+            x_axis_label: None,
+            x_axis_unit: None,
+            scalar_results: None,
+            device_operating_points: BTreeMap::new(),
+            truncated: None,
+            plot_hints: None,
+            raw_header: None,
+        }
+    }
+
+    fn derived(expression: &str) -> DerivedTrace {
+        DerivedTrace { name: None, expression: expression.to_string() }
+    }
+
+    fn transient() -> SimulationResults {
+        results(vec![
+            trace("V(a)", vec![5.0, 4.0, 3.0], None),
+            trace("V(b)", vec![1.0, 1.5, 2.0], None),
+            Trace { name: "I(R1)".to_string(), data: vec![1e-3, 2e-3, 3e-3], unit: "A".to_string(), phase: None, raw_name: None },
+        ])
+    }
+
+    #[test]
+    fn test_differential_scaling_and_inversion() {
+        let results = transient();
+
+        let across = derive_trace(&results, &DerivedTrace { name: Some("V(R1)".to_string()), expression: "V(a)-V(b)".to_string() }).unwrap();
+        assert_eq!(across.name, "V(R1)");
+        assert_eq!(across.data, vec![4.0, 2.5, 1.0]);
+        assert_eq!(across.unit, "V");
+        assert!(across.phase.is_none());
+
+        // Node pair shorthand and case-insensitive names
+        assert_eq!(derive_trace(&results, &derived("v(A, b)")).unwrap().data, vec![4.0, 2.5, 1.0]);
+        assert_eq!(derive_trace(&results, &derived("V(a,0)")).unwrap().data, vec![5.0, 4.0, 3.0]);
+
+        let inverted = derive_trace(&results, &derived("-I(R1)")).unwrap();
+        assert_eq!(inverted.name, "-I(R1)");
+        assert_eq!(inverted.data, vec![-1e-3, -2e-3, -3e-3]);
+        assert_eq!(inverted.unit, "A");
+
+        let scaled = derive_trace(&results, &derived("2 * (V(a) - 1) / 1k")).unwrap();
+        assert_eq!(scaled.data, vec![8e-3, 6e-3, 4e-3]);
+        assert_eq!(scaled.unit, "V");
+        assert_eq!(derive_trace(&results, &derived("V(b) * 2e+1")).unwrap().data, vec![20.0, 30.0, 40.0]);
+
+        // A power has no unit the traces share; a constant fills the x axis
+        assert_eq!(derive_trace(&results, &derived("V(a)*I(R1)")).unwrap().unit, "");
+        assert_eq!(derive_trace(&results, &derived("2.5")).unwrap().data, vec![2.5; 3]);
+    }
+
+    #[test]
+    fn test_ac_traces_combine_as_complex() {
+        let mut results = results(vec![
+            trace("V(a)", vec![1.0, 2.0, 1.0], Some(vec![0.0, 90.0, 0.0])),
+            trace("V(b)", vec![1.0, 2.0, 1.0], Some(vec![90.0, 90.0, 180.0])),
+        ]);
+        results.analysis_type = "ac".to_string();
+
+        let across = derive_trace(&results, &derived("V(a)-V(b)")).unwrap();
+        let phase = across.phase.unwrap();
+        assert!((across.data[0] - 2f64.sqrt()).abs() < 1e-12);
+        assert!((phase[0] + 45.0).abs() < 1e-9);
+        assert!(across.data[1].abs() < 1e-12);
+        assert!((across.data[2] - 2.0).abs() < 1e-12);
+        assert!(phase[2].abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut results = transient();
+        results.traces.push(trace("V(short)", vec![1.0, 2.0], None));
+
+        let (error, code) = derive_trace(&results, &derived("V(a)-V(missing)")).unwrap_err();
+        assert_eq!(code, "UNKNOWN_TRACE");
+        assert_eq!(error, "Trace V(missing) is not in the results");
+
+        let (error, code) = derive_trace(&results, &derived("V(a)-V(short)")).unwrap_err();
+        assert_eq!(code, "LENGTH_MISMATCH");
+        assert_eq!(error, "Traces V(a) (3 points) and V(short) (2 points) have different lengths");
+        assert_eq!(derive_trace(&results, &derived("2*V(short)")).unwrap_err().1, "LENGTH_MISMATCH");
+
+        let (error, code) = derive_trace(&results, &derived("V(a) -")).unwrap_err();
+        assert_eq!(code, "INVALID_EXPRESSION");
+        assert_eq!(error, "Invalid expression 'V(a) -': expected a value at position 7");
+        assert_eq!(derive_trace(&results, &derived("(V(a)")).unwrap_err().1, "INVALID_EXPRESSION");
+        assert_eq!(derive_trace(&results, &derived("V(a) V(b)")).unwrap_err().1, "INVALID_EXPRESSION");
+        assert_eq!(derive_trace(&results, &derived("  ")).unwrap_err().1, "INVALID_EXPRESSION");
+
+        assert_eq!(derive_traces(&results, &[]).unwrap_err().1, "INVALID_EXPRESSION");
+        let too_many = vec![derived("V(a)"); MAX_DERIVED_TRACES + 1];
+        assert_eq!(derive_traces(&results, &too_many).unwrap_err().1, "INVALID_EXPRESSION");
+        assert_eq!(derive_traces(&results, &[derived("V(a)"), derived("-V(b)")]).unwrap().len(), 2);
+    }
+}
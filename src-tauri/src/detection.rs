@@ -0,0 +1,212 @@
+// Copyright (c) 2024-2025 Wanyeki Technologies LLC. All rights reserved.
+// This source code is licensed under the proprietary license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Simulator detection, repeated in the background so installs and uninstalls are noticed
+//! without restarting the agent
+
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::RwLock;
+
+use crate::onboarding;
+use crate::protocol::now_ms;
+use crate::simulator;
+use crate::state::{AppState, StatusEvent};
+
+/// How often detection re-runs in the background
+pub const REDETECT_INTERVAL: Duration = Duration::from_secs(180);
+
+/// Detect both simulators and publish the result; returns whether availability changed
+pub async fn redetect(state: &AppState) -> bool {
+    match tokio::task::spawn_blocking(|| (simulator::detect_ltspice(), simulator::detect_ngspice())).await {
+        Ok((ltspice, ngspice)) => {
+            let changed = apply(state, ltspice, ngspice).await;
+            // Hash now so the first response doesn't wait; unchanged files stay cached
+            for simulator in ["ltspice", "ngspice"] {
+                state.simulator_binary(simulator).await;
+            }
+            changed
+        }
+        Err(e) => {
+            log::warn!("Simulator detection failed: {}", e);
+            false
+        }
+    }
+}
+
+/// Store detected paths and the detection time, notifying listeners
+/// Returns whether LTspice or ngspice became available or unavailable
+pub async fn apply(state: &AppState, ltspice: Option<String>, ngspice: Option<String>) -> bool {
+    let first_check = state.simulators_checked_at.read().await.is_none();
+    let ltspice_changed = update_path(&state.ltspice_path, "LTspice", ltspice, first_check).await;
+    let ngspice_changed = update_path(&state.ngspice_path, "ngspice", ngspice, first_check).await;
+    *state.simulators_checked_at.write().await = Some(now_ms());
+    refresh_onboarding(state).await;
+
+    let changed = ltspice_changed || ngspice_changed;
+    state.notify_status(StatusEvent::Changed);
+    if changed {
+        state.notify_status(StatusEvent::CapabilitiesChanged);
+    }
+    changed
+}
+
+/// Re-detect a simulator whose cached executable is gone, replacing the cached path with what
+/// `detect` finds
+/// Clients are told even when it is found again, since its path and version may have changed
+/// Returns the path that went missing and the one found instead, if any
+pub async fn replace_missing(
+    state: &AppState,
+    simulator: &str,
+    detect: fn() -> Option<String>,
+) -> (Option<String>, Option<String>) {
+    let (cached, label) = match simulator {
+        "ngspice" => (&state.ngspice_path, "ngspice"),
+        _ => (&state.ltspice_path, "LTspice"),
+    };
+    let stale = cached.write().await.take();
+    log::warn!("{} is gone from {}; re-running detection", label, stale.as_deref().unwrap_or("its cached path"));
+    let found = tokio::task::spawn_blocking(detect)
+        .await
+        .ok()
+        .flatten()
+        .filter(|found| stale.as_ref() != Some(found));
+    match &found {
+        Some(found) => log::info!("{} detected at: {}", label, found),
+        None => log::warn!("No other {} installation found", label),
+    }
+    *cached.write().await = found.clone();
+    *state.simulators_checked_at.write().await = Some(now_ms());
+    refresh_onboarding(state).await;
+    state.notify_status(StatusEvent::Changed);
+    state.notify_status(StatusEvent::CapabilitiesChanged);
+    (stale, found)
+}
+
+/// Recompute the onboarding stage from the cached simulator paths, logging any change
+pub async fn refresh_onboarding(state: &AppState) {
+    let ltspice_available = state.ltspice_path.read().await.is_some();
+    let ngspice_available = state.ngspice_path.read().await.is_some();
+    let updated = onboarding::onboarding_state(ltspice_available, ngspice_available);
+    let mut current = state.onboarding.write().await;
+    if current.stage != updated.stage {
+        log::info!("Onboarding stage: {:?} -> {:?}", current.stage, updated.stage);
+    }
+    *current = updated;
+}
+
+/// Replace a cached path, logging differences (everything on the first check)
+async fn update_path(cached: &RwLock<Option<String>>, label: &str, detected: Option<String>, first_check: bool) -> bool {
+    let mut path = cached.write().await;
+    if first_check || *path != detected {
+        match &detected {
+            Some(found) => log::info!("{} detected at: {}", label, found),
+            None => log::warn!("{} not found", label),
+        }
+    }
+    let changed = path.is_some() != detected.is_some();
+    *path = detected;
+    changed
+}
+
+/// Re-run detection every `interval` until the agent shuts down
+pub async fn run_periodic(state: Arc<AppState>, interval: Duration) {
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep(interval) => {
+                if redetect(&state).await {
+                    log::info!("Simulator availability changed");
+                }
+            }
+            _ = state.wait_for_shutdown() => break,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::OnboardingStage;
+
+    #[tokio::test]
+    async fn test_apply_reports_availability_changes() {
+        let state = AppState::default();
+        let mut events = state.subscribe_status();
+
+        assert!(apply(&state, None, Some("/usr/bin/ngspice".to_string())).await);
+        assert_eq!(events.try_recv().unwrap(), StatusEvent::Changed);
+        assert_eq!(events.try_recv().unwrap(), StatusEvent::CapabilitiesChanged);
+        assert!(state.simulators_checked_at.read().await.is_some());
+
+        // A new path for a simulator that was already available is not a capability change
+        assert!(!apply(&state, None, Some("/usr/local/bin/ngspice".to_string())).await);
+        assert_eq!(events.try_recv().unwrap(), StatusEvent::Changed);
+        assert!(events.try_recv().is_err());
+        assert_eq!(state.ngspice_path.read().await.as_deref(), Some("/usr/local/bin/ngspice"));
+
+        assert!(apply(&state, None, None).await);
+        assert!(state.ngspice_path.read().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_onboarding_advances_as_simulators_are_installed() {
+        let state = AppState::default();
+        apply(&state, None, None).await;
+        assert_eq!(state.onboarding.read().await.stage, OnboardingStage::NoSimulator);
+        assert_eq!(state.onboarding.read().await.guides.len(), 2);
+
+        // The periodic pass notices a new install
+        apply(&state, None, Some("/usr/bin/ngspice".to_string())).await;
+        let onboarding = state.onboarding.read().await.clone();
+        assert_eq!(onboarding.stage, OnboardingStage::DetectedNgspiceOnly);
+        assert_eq!(onboarding.guides.len(), 1);
+        assert_eq!(onboarding.guides[0].simulator, "ltspice");
+
+        apply(&state, Some("/usr/bin/ltspice".to_string()), Some("/usr/bin/ngspice".to_string())).await;
+        assert_eq!(state.onboarding.read().await.stage, OnboardingStage::Ready);
+        assert!(state.onboarding.read().await.guides.is_empty());
+
+        apply(&state, Some("/usr/bin/ltspice".to_string()), None).await;
+        assert_eq!(state.onboarding.read().await.stage, OnboardingStage::DetectedLtspiceOnly);
+    }
+
+    #[tokio::test]
+    async fn test_missing_simulator_is_replaced() {
+        fn upgraded() -> Option<String> {
+            Some("/opt/ltspice-25/ltspice".to_string())
+        }
+        fn uninstalled() -> Option<String> {
+            None
+        }
+
+        let state = AppState::default();
+        apply(&state, Some("/opt/ltspice-24/ltspice".to_string()), None).await;
+        let mut events = state.subscribe_status();
+
+        let (stale, found) = replace_missing(&state, "ltspice", upgraded).await;
+        assert_eq!(stale.as_deref(), Some("/opt/ltspice-24/ltspice"));
+        assert_eq!(found.as_deref(), Some("/opt/ltspice-25/ltspice"));
+        assert_eq!(state.ltspice_path.read().await.as_deref(), Some("/opt/ltspice-25/ltspice"));
+        // Still available, but clients hear about the new install
+        assert_eq!(events.try_recv().unwrap(), StatusEvent::Changed);
+        assert_eq!(events.try_recv().unwrap(), StatusEvent::CapabilitiesChanged);
+
+        // Finding the path that just failed again doesn't count
+        assert_eq!(replace_missing(&state, "ltspice", upgraded).await.1, None);
+        assert!(state.ltspice_path.read().await.is_none());
+
+        apply(&state, Some("/opt/ltspice-24/ltspice".to_string()), None).await;
+        assert_eq!(replace_missing(&state, "ltspice", uninstalled).await.1, None);
+        assert!(state.ltspice_path.read().await.is_none());
+        assert_eq!(state.onboarding.read().await.stage, OnboardingStage::NoSimulator);
+    }
+
+    #[tokio::test]
+    async fn test_periodic_detection_stops_on_shutdown() {
+        let state = Arc::new(AppState::default());
+        let task = tokio::spawn(run_periodic(state.clone(), Duration::from_secs(3600)));
+        state.begin_shutdown();
+        tokio::time::timeout(Duration::from_secs(1), task).await.unwrap().unwrap();
+    }
+}
@@ -0,0 +1,269 @@
+// Copyright (c) 2024-2025 Wanyeki Technologies LLC. All rights reserved.
+// This source code is licensed under the proprietary license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! CSV export of simulation results
+
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use crate::protocol::SimulationResults;
+
+/// Approximate in-memory size of a result set, in bytes
+pub fn results_size_bytes(results: &SimulationResults) -> usize {
+    let values: usize = results
+        .traces
+        .iter()
+        .map(|trace| trace.data.len() + trace.phase.as_ref().map_or(0, Vec::len))
+        .sum();
+    (results.time.len() + values) * std::mem::size_of::<f64>()
+}
+
+/// Write results as CSV to a file
+pub fn write_csv_file(results: &SimulationResults, path: &Path) -> io::Result<()> {
+    let file = std::fs::File::create(path)?;
+    write_csv(results, file)
+}
+
+/// Render results as a CSV string, for results small enough to send in one message
+pub fn to_csv_string(results: &SimulationResults) -> String {
+    let mut buffer = Vec::new();
+    // Writing to a Vec cannot fail
+    let _ = write_csv(results, &mut buffer);
+    String::from_utf8(buffer).unwrap_or_default()
+}
+
+/// Write results as CSV: the sweep variable first, then one column per trace
+/// AC traces with phase data get an extra `<name> phase [deg]` column each
+/// Rows are written one at a time so large results never exist as a single string
+/// Scalar results (`.tf`, `.sens`) are written as `name,value` rows instead
+pub fn write_csv<W: Write>(results: &SimulationResults, writer: W) -> io::Result<()> {
+    let mut out = BufWriter::new(writer);
+
+    // `.tf`/`.sens` results have no sweep, only named values
+    if let Some(values) = &results.scalar_results {
+        write_row(&mut out, ["name", "value"].into_iter())?;
+        for (name, value) in values {
+            write_row(&mut out, [escape_field(name), value.to_string()].iter().map(String::as_str))?;
+        }
+        return out.flush();
+    }
+
+    let mut header = vec![x_axis_header(results)];
+    for trace in &results.traces {
+        header.push(column_header(&trace.name, &trace.unit));
+        if trace.phase.is_some() {
+            header.push(column_header(&format!("{} phase", trace.name), "deg"));
+        }
+    }
+    let header: Vec<String> = header.iter().map(|h| escape_field(h)).collect();
+    write_row(&mut out, header.iter().map(String::as_str))?;
+
+    let mut row = Vec::with_capacity(header.len());
+    for (i, x) in results.time.iter().enumerate() {
+        row.clear();
+        row.push(x.to_string());
+        for trace in &results.traces {
+            row.push(format_value(trace.data.get(i)));
+            if let Some(phase) = &trace.phase {
+                row.push(format_value(phase.get(i)));
+            }
+        }
+        write_row(&mut out, row.iter().map(String::as_str))?;
+    }
+
+    out.flush()
+}
+
+fn write_row<'a, W: Write>(out: &mut W, fields: impl Iterator<Item = &'a str>) -> io::Result<()> {
+    for (i, field) in fields.enumerate() {
+        if i > 0 {
+            out.write_all(b",")?;
+        }
+        out.write_all(field.as_bytes())?;
+    }
+    out.write_all(b"\r\n")
+}
+
+/// Header of the first column, based on the analysis type
+fn x_axis_header(results: &SimulationResults) -> String {
+    match results.analysis_type.as_str() {
+        "ac" | "noise" => column_header("frequency", "Hz"),
+        "transient" => column_header("time", "s"),
+        _ => column_header(
+            results.x_axis_label.as_deref().unwrap_or("sweep"),
+            results.x_axis_unit.as_deref().unwrap_or_default(),
+        ),
+    }
+}
+
+fn column_header(name: &str, unit: &str) -> String {
+    if unit.is_empty() {
+        name.to_string()
+    } else {
+        format!("{} [{}]", name, unit)
+    }
+}
+
+/// Missing samples (a trace shorter than the sweep) become empty cells
+fn format_value(value: Option<&f64>) -> String {
+    value.map(f64::to_string).unwrap_or_default()
+}
+
+/// Quote a field if it contains a separator, quote or line break
+fn escape_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+    use crate::protocol::Trace;
+
+    fn trace(name: &str, unit: &str, data: Vec<f64>, phase: Option<Vec<f64>>) -> Trace {
+        Trace {
+            name: name.to_string(),
+            data,
+            unit: unit.to_string(),
+            phase,
+            raw_name: None,
+        }
+    }
+
+    #[test]
+    fn test_transient_csv() {
+        let results = SimulationResults {
+            time: vec![0.0, 0.001],
+            traces: vec![
+                trace("V(out)", "V", vec![0.0, 2.5], None),
+                trace("I(R1)", "A", vec![0.0, 1e-3], None),
+            ],
+            analysis_type: "transient".to_string(),
+            x_axis_label: Some("time".to_string()),
+            x_axis_unit: None,
+            scalar_results: None,
+            device_operating_points: BTreeMap::new(),
+            truncated: None,
+            plot_hints: None,
+            raw_header: None,
+        };
+
+        let csv = to_csv_string(&results);
+        assert_eq!(csv, "time [s],V(out) [V],I(R1) [A]\r\n0,0,0\r\n0.001,2.5,0.001\r\n");
+    }
+
+    #[test]
+    fn test_ac_csv_with_phase_columns() {
+        let results = SimulationResults {
+            time: vec![1.0, 10.0],
+            traces: vec![trace("v(out)", "V", vec![1.0, 0.5], Some(vec![0.0, -45.0]))],
+            analysis_type: "ac".to_string(),
+            x_axis_label: Some("frequency".to_string()),
+            x_axis_unit: None,
+            scalar_results: None,
+            device_operating_points: BTreeMap::new(),
+            truncated: None,
+            plot_hints: None,
+            raw_header: None,
+        };
+
+        let csv = to_csv_string(&results);
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines[0], "frequency [Hz],v(out) [V],v(out) phase [deg]");
+        assert_eq!(lines[2], "10,0.5,-45");
+    }
+
+    #[test]
+    fn test_noise_csv_header() {
+        let results = SimulationResults {
+            time: vec![1.0, 10.0],
+            traces: vec![trace("onoise_spectrum", "V/√Hz", vec![4.0e-8, 3.0e-8], None)],
+            analysis_type: "noise".to_string(),
+            x_axis_label: Some("frequency".to_string()),
+            x_axis_unit: None,
+            scalar_results: None,
+            device_operating_points: BTreeMap::new(),
+            truncated: None,
+            plot_hints: None,
+            raw_header: None,
+        };
+
+        let csv = to_csv_string(&results);
+        assert_eq!(csv.lines().next().unwrap(), "frequency [Hz],onoise_spectrum [V/√Hz]");
+    }
+
+    #[test]
+    fn test_dc_csv_and_escaping() {
+        let results = SimulationResults {
+            time: vec![0.0, 1.0, 2.0],
+            traces: vec![trace("I(a,b)", "", vec![0.1, 0.2], None)],
+            analysis_type: "dc".to_string(),
+            x_axis_label: Some("v-sweep".to_string()),
+            x_axis_unit: None,
+            scalar_results: None,
+            device_operating_points: BTreeMap::new(),
+            truncated: None,
+            plot_hints: None,
+            raw_header: None,
+        };
+
+        let csv = to_csv_string(&results);
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines[0], "v-sweep,\"I(a,b)\"");
+        // The trace is one sample short: the cell is left empty
+        assert_eq!(lines[3], "2,");
+
+        let current_sweep = SimulationResults {
+            x_axis_label: Some("i-sweep".to_string()),
+            x_axis_unit: Some("A".to_string()),
+            ..results
+        };
+        assert!(to_csv_string(&current_sweep).starts_with("i-sweep [A],"));
+    }
+
+    #[test]
+    fn test_scalar_results_csv() {
+        let results = SimulationResults {
+            time: vec![],
+            traces: vec![],
+            analysis_type: "tf".to_string(),
+            x_axis_label: None,
+            x_axis_unit: None,
+            scalar_results: Some(
+                [("Transfer_function".to_string(), 0.5), ("output_impedance_at_V(out,ref)".to_string(), 500.0)]
+                    .into_iter()
+                    .collect(),
+            ),
+            device_operating_points: BTreeMap::new(),
+            truncated: None,
+            plot_hints: None,
+            raw_header: None,
+        };
+
+        let csv = to_csv_string(&results);
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines, vec!["name,value", "Transfer_function,0.5", "\"output_impedance_at_V(out,ref)\",500"]);
+    }
+
+    #[test]
+    fn test_results_size_bytes() {
+        let results = SimulationResults {
+            time: vec![0.0; 10],
+            traces: vec![trace("v(out)", "V", vec![0.0; 10], Some(vec![0.0; 10]))],
+            analysis_type: "ac".to_string(),
+            x_axis_label: None,
+            x_axis_unit: None,
+            scalar_results: None,
+            device_operating_points: BTreeMap::new(),
+            truncated: None,
+            plot_hints: None,
+            raw_header: None,
+        };
+        assert_eq!(results_size_bytes(&results), 30 * 8);
+    }
+}
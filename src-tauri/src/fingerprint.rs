@@ -0,0 +1,198 @@
+// Copyright (c) 2024-2025 Wanyeki Technologies LLC. All rights reserved.
+// This source code is licensed under the proprietary license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! SHA-256 and version of the simulator executables, so results can be traced to the build
+//! that produced them
+//!
+//! Hashing a large LTspice binary takes a while, so results are cached until the file's
+//! modification time or size changes.
+
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant, SystemTime};
+use regex::Regex;
+use sha2::{Digest, Sha256};
+use tokio::sync::RwLock;
+
+use crate::protocol::SimulatorBinary;
+
+/// How long `ngspice --version` may take before it is killed
+const VERSION_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// What a cached fingerprint was computed from
+#[derive(Debug, Clone, PartialEq)]
+struct FileStamp {
+    modified: Option<SystemTime>,
+    len: u64,
+}
+
+impl FileStamp {
+    fn of(path: &str) -> Option<Self> {
+        let metadata = std::fs::metadata(path).ok()?;
+        Some(Self { modified: metadata.modified().ok(), len: metadata.len() })
+    }
+}
+
+/// Fingerprints by executable path
+#[derive(Debug, Default)]
+pub struct BinaryCache {
+    entries: HashMap<String, (FileStamp, SimulatorBinary)>,
+}
+
+impl BinaryCache {
+    fn get(&self, path: &str, stamp: &FileStamp) -> Option<SimulatorBinary> {
+        self.entries
+            .get(path)
+            .filter(|(cached, _)| cached == stamp)
+            .map(|(_, binary)| binary.clone())
+    }
+}
+
+/// Fingerprint of the `simulator` executable at `path`, hashed again if the file changed
+/// None if the file can't be read
+pub async fn identify(cache: &RwLock<BinaryCache>, simulator: &str, path: &str) -> Option<SimulatorBinary> {
+    let stamp = FileStamp::of(path)?;
+    if let Some(binary) = cache.read().await.get(path, &stamp) {
+        return Some(binary);
+    }
+
+    let (simulator, owned_path) = (simulator.to_string(), path.to_string());
+    let binary = match tokio::task::spawn_blocking(move || inspect(&simulator, &owned_path)).await {
+        Ok(Ok(binary)) => binary,
+        Ok(Err(e)) => {
+            log::warn!("Could not hash simulator binary {}: {}", path, e);
+            return None;
+        }
+        Err(e) => {
+            log::warn!("Simulator binary hashing failed: {}", e);
+            return None;
+        }
+    };
+    log::info!("Simulator binary {}: sha256 {}, version {:?}", path, binary.sha256, binary.version);
+    cache.write().await.entries.insert(path.to_string(), (stamp, binary.clone()));
+    Some(binary)
+}
+
+/// Hash the file and look up its version
+fn inspect(simulator: &str, path: &str) -> std::io::Result<SimulatorBinary> {
+    let sha256 = sha256_file(Path::new(path))?;
+    let version = match simulator {
+        "ngspice" => ngspice_version(path),
+        _ => ltspice_version(Path::new(path)),
+    };
+    Ok(SimulatorBinary { version, sha256 })
+}
+
+/// Lowercase hex SHA-256 of a file, read in chunks
+fn sha256_file(path: &Path) -> std::io::Result<String> {
+    let mut file = std::fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; 1024 * 1024];
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    Ok(hasher.finalize().iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+/// Version from `ngspice --version`, e.g. "42" from "ngspice-42 : Circuit level simulation program"
+fn ngspice_version(path: &str) -> Option<String> {
+    let mut child = Command::new(path)
+        .arg("--version")
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .ok()?;
+
+    let started = Instant::now();
+    loop {
+        match child.try_wait() {
+            Ok(Some(_)) => break,
+            Ok(None) if started.elapsed() < VERSION_TIMEOUT => std::thread::sleep(Duration::from_millis(50)),
+            _ => {
+                let _ = child.kill();
+                let _ = child.wait();
+                return None;
+            }
+        }
+    }
+
+    let mut output = String::new();
+    child.stdout.take()?.read_to_string(&mut output).ok()?;
+    parse_ngspice_version(&output)
+}
+
+fn parse_ngspice_version(output: &str) -> Option<String> {
+    let pattern = Regex::new(r"(?i)ngspice-(\S+)").expect("version pattern is valid");
+    pattern.captures(output).map(|cap| cap[1].to_string())
+}
+
+/// Version from the app bundle's Info.plist on macOS; other LTspice builds don't expose one
+fn ltspice_version(path: &Path) -> Option<String> {
+    // .app/Contents/MacOS/LTspice -> .app/Contents/Info.plist
+    let plist = path.parent()?.parent()?.join("Info.plist");
+    parse_bundle_version(&std::fs::read_to_string(plist).ok()?)
+}
+
+fn parse_bundle_version(plist: &str) -> Option<String> {
+    let pattern = Regex::new(r"<key>CFBundleShortVersionString</key>\s*<string>([^<]+)</string>")
+        .expect("plist pattern is valid");
+    pattern.captures(plist).map(|cap| cap[1].trim().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_sha256_file() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("ltspice");
+        std::fs::write(&path, "abc").unwrap();
+        assert_eq!(
+            sha256_file(&path).unwrap(),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_identify_rehashes_modified_binary() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("LTspice.exe");
+        std::fs::write(&path, "abc").unwrap();
+        let path = path.to_string_lossy().to_string();
+        let cache = RwLock::new(BinaryCache::default());
+
+        let first = identify(&cache, "ltspice", &path).await.unwrap();
+        assert_eq!(first.sha256, "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+        assert_eq!(first.version, None);
+        assert_eq!(identify(&cache, "ltspice", &path).await.unwrap(), first);
+
+        // A different size changes the stamp even where mtimes are coarse
+        std::fs::write(&path, "abcd").unwrap();
+        let second = identify(&cache, "ltspice", &path).await.unwrap();
+        assert_ne!(second.sha256, first.sha256);
+        assert_eq!(cache.read().await.entries.len(), 1);
+
+        assert!(identify(&cache, "ltspice", &dir.path().join("missing").to_string_lossy()).await.is_none());
+    }
+
+    #[test]
+    fn test_parse_versions() {
+        assert_eq!(
+            parse_ngspice_version("******\n** ngspice-42 : Circuit level simulation program\n").as_deref(),
+            Some("42")
+        );
+        assert_eq!(parse_ngspice_version("usage: ngspice"), None);
+        let plist = "<dict>\n\t<key>CFBundleShortVersionString</key>\n\t<string>17.1.8</string>\n</dict>";
+        assert_eq!(parse_bundle_version(plist).as_deref(), Some("17.1.8"));
+    }
+}
@@ -0,0 +1,159 @@
+// Copyright (c) 2024-2025 Wanyeki Technologies LLC. All rights reserved.
+// This source code is licensed under the proprietary license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Headless mode (`--headless`): simulator detection and the WebSocket server without window or tray,
+//! for CI runners and servers
+//!
+//! Exit codes:
+//! - 0: stopped with Ctrl+C / SIGINT
+//! - 1: the server stopped unexpectedly or the runtime could not start
+//! - 2: invalid command-line arguments
+//! - 3: the WebSocket port could not be bound
+//! - 4: `--require-simulator` was given and neither LTspice nor ngspice was found
+
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::artifacts::Artifacts;
+use crate::catalog;
+use crate::cli::CliOptions;
+use crate::detection;
+use crate::protocol::{now_ms, WS_PORT};
+use crate::settings;
+use crate::shutdown;
+use crate::simulator;
+use crate::state::AppState;
+use crate::websocket;
+
+pub const EXIT_OK: i32 = 0;
+pub const EXIT_FAILURE: i32 = 1;
+pub const EXIT_USAGE: i32 = 2;
+pub const EXIT_PORT_UNAVAILABLE: i32 = 3;
+pub const EXIT_NO_SIMULATOR: i32 = 4;
+
+/// Run until interrupted, returning the process exit code
+pub fn run(options: CliOptions) -> i32 {
+    if !options.unrecognized.is_empty() {
+        log::error!("Unrecognized arguments: {}", options.unrecognized.join(" "));
+        return EXIT_USAGE;
+    }
+
+    match tokio::runtime::Runtime::new() {
+        Ok(runtime) => runtime.block_on(serve(options)),
+        Err(e) => {
+            log::error!("Failed to start async runtime: {}", e);
+            EXIT_FAILURE
+        }
+    }
+}
+
+async fn serve(options: CliOptions) -> i32 {
+    let mut state = AppState::new(settings::load_settings());
+    state.bundled_libraries = catalog::load();
+    *state.artifacts.get_mut() = Artifacts::open_default(state.settings.get_mut().max_artifact_bytes);
+    let state = Arc::new(state);
+
+    // Orphan cleanup is skipped: CI machines may run several agents side by side
+    let overridden = options.ltspice_path.is_some() || options.ngspice_path.is_some();
+    let ltspice = resolve_simulator_path("LTspice", options.ltspice_path, simulator::detect_ltspice);
+    let ngspice = resolve_simulator_path("ngspice", options.ngspice_path, simulator::detect_ngspice);
+    if ltspice.is_none() && ngspice.is_none() {
+        if options.require_simulator {
+            log::error!("Neither LTspice nor ngspice was found");
+            return EXIT_NO_SIMULATOR;
+        }
+        log::warn!("Neither LTspice nor ngspice was found; simulations will fail");
+    }
+    *state.ltspice_path.write().await = ltspice;
+    *state.ngspice_path.write().await = ngspice;
+    *state.simulators_checked_at.write().await = Some(now_ms());
+
+    // Paths given on the command line are kept for the whole run
+    if !overridden {
+        tokio::spawn(detection::run_periodic(state.clone(), detection::REDETECT_INTERVAL));
+    }
+
+    let port = options.port.unwrap_or(WS_PORT);
+    tokio::select! {
+        result = websocket::start_server(state.clone(), port) => match result {
+            Err(e) => {
+                log::error!("{}", websocket::describe_start_error(&*e, port).await);
+                EXIT_PORT_UNAVAILABLE
+            }
+            Ok(()) => {
+                log::error!("WebSocket server stopped unexpectedly");
+                EXIT_FAILURE
+            }
+        },
+        _ = tokio::signal::ctrl_c() => {
+            log::info!("Interrupted");
+            shutdown::shutdown(&state, shutdown::SHUTDOWN_GRACE).await;
+            EXIT_OK
+        }
+    }
+}
+
+/// Use the path given on the command line if it exists, otherwise run detection
+fn resolve_simulator_path(label: &str, path_override: Option<String>, detect: fn() -> Option<String>) -> Option<String> {
+    let path = match path_override {
+        Some(path) if Path::new(&path).exists() => Some(path),
+        Some(path) => {
+            log::error!("{} not found at {}", label, path);
+            None
+        }
+        None => detect(),
+    };
+    match &path {
+        Some(path) => log::info!("Using {} at: {}", label, path),
+        None => log::warn!("{} not found", label),
+    }
+    path
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_simulator_path_override() {
+        let dir = tempfile::tempdir().unwrap();
+        let exe = dir.path().join("ngspice");
+        std::fs::write(&exe, "").unwrap();
+        let exe = exe.to_string_lossy().to_string();
+
+        let found = resolve_simulator_path("ngspice", Some(exe.clone()), || None);
+        assert_eq!(found, Some(exe));
+
+        // A missing override is reported as not found instead of falling back to detection
+        let missing = dir.path().join("missing").to_string_lossy().to_string();
+        let detect: fn() -> Option<String> = || Some("/usr/bin/ngspice".to_string());
+        assert_eq!(resolve_simulator_path("ngspice", Some(missing), detect), None);
+    }
+
+    #[test]
+    fn test_unrecognized_arguments_rejected() {
+        let options = CliOptions {
+            headless: true,
+            unrecognized: vec!["--verbose".to_string()],
+            ..CliOptions::default()
+        };
+        assert_eq!(run(options), EXIT_USAGE);
+    }
+
+    #[tokio::test]
+    async fn test_port_in_use_exit_code() {
+        let dir = tempfile::tempdir().unwrap();
+        let exe = dir.path().join("ngspice");
+        std::fs::write(&exe, "").unwrap();
+
+        let taken = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let options = CliOptions {
+            headless: true,
+            port: Some(taken.local_addr().unwrap().port()),
+            ngspice_path: Some(exe.to_string_lossy().to_string()),
+            ..CliOptions::default()
+        };
+        assert_eq!(serve(options).await, EXIT_PORT_UNAVAILABLE);
+    }
+}
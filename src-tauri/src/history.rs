@@ -0,0 +1,287 @@
+// Copyright (c) 2024-2025 Wanyeki Technologies LLC. All rights reserved.
+// This source code is licensed under the proprietary license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Netlists of recent simulations, kept so a simulation can be run again exactly as before
+//!
+//! Only recorded with the `store_netlists` setting on. Each record holds the prepared netlist
+//! (after schematic conversion, dialect rewrites, the default analysis and current probes),
+//! gzip-compressed, and the request's options; attachments are not kept. Records are keyed by
+//! the original request id and live in memory, oldest evicted first past the limits below. The
+//! number kept is `max_history_entries` from the resource budget.
+
+use std::collections::VecDeque;
+use std::io::{Read, Write};
+
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use serde::Serialize;
+
+use crate::protocol::{now_ms, InputFormat, NetlistEncoding, SimulationRequest};
+use crate::resource_budget::{BudgetEntry, Budgeted};
+
+/// Most netlists kept unless the resource budget says otherwise
+pub const MAX_STORED_NETLISTS: usize = 20;
+
+/// Most compressed netlist bytes kept
+pub const MAX_STORED_NETLIST_BYTES: usize = 10 * 1024 * 1024;
+
+struct Record {
+    /// The original request, without its netlist or attachments
+    request: SimulationRequest,
+    /// Unix time in ms the netlist was prepared
+    recorded_at: u64,
+    /// Prepared netlist, gzip-compressed
+    netlist: Vec<u8>,
+    netlist_bytes: usize,
+}
+
+/// A stored simulation, as listed in the agent window
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct HistoryEntry {
+    pub request_id: String,
+    pub simulator: String,
+    pub recorded_at: u64,
+    /// Size of the prepared netlist, uncompressed
+    pub netlist_bytes: usize,
+    /// Request id of the simulation this one replayed
+    pub replay_of: Option<String>,
+}
+
+/// Prepared netlists of recent simulations, oldest first
+pub struct History {
+    max_entries: usize,
+    records: VecDeque<Record>,
+}
+
+impl Default for History {
+    fn default() -> Self {
+        Self::with_max_entries(MAX_STORED_NETLISTS)
+    }
+}
+
+impl History {
+    pub fn with_max_entries(max_entries: usize) -> Self {
+        Self { max_entries, records: VecDeque::new() }
+    }
+
+    /// Keep at most `max_entries` netlists, forgetting the oldest beyond that now
+    pub fn set_max_entries(&mut self, max_entries: usize) {
+        self.max_entries = max_entries;
+        self.evict_oldest();
+    }
+
+    /// Keep the netlist `request` was prepared into, replacing an earlier record of the same id
+    pub fn record(&mut self, request: &SimulationRequest, netlist: &str) {
+        self.record_at(request, netlist, now_ms());
+    }
+
+    fn record_at(&mut self, request: &SimulationRequest, netlist: &str, now: u64) {
+        let compressed = match compress(netlist) {
+            Ok(compressed) => compressed,
+            Err(e) => {
+                log::warn!("Could not compress the netlist of simulation {}: {}", request.id, e);
+                return;
+            }
+        };
+        if compressed.len() > MAX_STORED_NETLIST_BYTES {
+            log::info!("Netlist of simulation {} is too large to keep for replay", request.id);
+            return;
+        }
+
+        self.records.retain(|record| record.request.id != request.id);
+        self.records.push_back(Record {
+            request: SimulationRequest {
+                netlist: String::new(),
+                attachments: Vec::new(),
+                ..request.clone()
+            },
+            recorded_at: now,
+            netlist: compressed,
+            netlist_bytes: netlist.len(),
+        });
+        self.evict_oldest();
+    }
+
+    fn evict_oldest(&mut self) {
+        while self.records.len() > self.max_entries || self.stored_bytes() > MAX_STORED_NETLIST_BYTES {
+            self.records.pop_front();
+        }
+    }
+
+    fn stored_bytes(&self) -> usize {
+        self.records.iter().map(|record| record.netlist.len()).sum()
+    }
+
+    /// Stored simulations, newest first
+    pub fn entries(&self) -> Vec<HistoryEntry> {
+        self.records
+            .iter()
+            .rev()
+            .map(|record| HistoryEntry {
+                request_id: record.request.id.clone(),
+                simulator: record.request.simulator.clone(),
+                recorded_at: record.recorded_at,
+                netlist_bytes: record.netlist_bytes,
+                replay_of: record.request.replay_of.clone(),
+            })
+            .collect()
+    }
+
+    /// A new request running the stored netlist of `history_id` with the same options
+    /// The netlist is used as prepared, so it is not converted or rewritten again
+    pub fn replay_request(&self, history_id: &str) -> Result<SimulationRequest, String> {
+        let record = self
+            .records
+            .iter()
+            .find(|record| record.request.id == history_id)
+            .ok_or_else(|| format!("No stored netlist for simulation {}", history_id))?;
+        let netlist = decompress(&record.netlist)
+            .map_err(|e| format!("Could not read the stored netlist of simulation {}: {}", history_id, e))?;
+
+        Ok(SimulationRequest {
+            id: format!("replay-{}", uuid::Uuid::new_v4()),
+            netlist,
+            input_format: InputFormat::Netlist,
+            netlist_encoding: NetlistEncoding::Plain,
+            dialect: None,
+            default_analysis: None,
+            confirm_long_runs: false,
+            replay_of: Some(history_id.to_string()),
+            timestamp: now_ms(),
+            ..record.request.clone()
+        })
+    }
+
+    /// Forget every stored netlist
+    pub fn clear(&mut self) {
+        self.records.clear();
+    }
+}
+
+/// Each netlist counts as one entry, its place in the history standing for its last use
+impl Budgeted for History {
+    fn budget_entries(&self) -> Vec<BudgetEntry> {
+        self.records
+            .iter()
+            .enumerate()
+            .map(|(i, record)| BudgetEntry { key: record.request.id.clone(), size: 1, last_used: i as u64 })
+            .collect()
+    }
+
+    fn evict(&mut self, key: &str) {
+        self.records.retain(|record| record.request.id != key);
+    }
+}
+
+fn compress(text: &str) -> std::io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(text.as_bytes())?;
+    encoder.finish()
+}
+
+fn decompress(data: &[u8]) -> std::io::Result<String> {
+    let mut text = String::new();
+    GzDecoder::new(data).read_to_string(&mut text)?;
+    Ok(text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(id: &str) -> SimulationRequest {
+        serde_json::from_value(serde_json::json!({
+            "id": id,
+            "type": "simulate",
+            "netlist": "* original\n.tran 1m\n.end",
+            "simulator": "ngspice",
+            "dialect": "pspice",
+            "currentProbes": ["R1"],
+            "attachments": [{"name": "sub.lib", "contentBase64": "KiBzdWI="}],
+            "timestamp": 0,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_replay_uses_prepared_netlist() {
+        let mut history = History::default();
+        history.record_at(&request("sim-1"), "* prepared\nVprobe_R1 a probe_R1 0\n.tran 1m\n.end", 100);
+
+        let replay = history.replay_request("sim-1").unwrap();
+        assert!(replay.id.starts_with("replay-"));
+        assert_eq!(replay.replay_of.as_deref(), Some("sim-1"));
+        assert_eq!(replay.netlist, "* prepared\nVprobe_R1 a probe_R1 0\n.tran 1m\n.end");
+        assert_eq!(replay.simulator, "ngspice");
+        assert_eq!(replay.dialect, None);
+        assert_eq!(replay.current_probes, Some(vec!["R1".to_string()]));
+        assert!(replay.attachments.is_empty());
+
+        let entries = history.entries();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].request_id, "sim-1");
+        assert_eq!(entries[0].recorded_at, 100);
+
+        assert_eq!(history.replay_request("sim-2").unwrap_err(), "No stored netlist for simulation sim-2");
+        history.clear();
+        assert!(history.replay_request("sim-1").is_err());
+    }
+
+    #[test]
+    fn test_oldest_netlists_are_evicted() {
+        let mut history = History::default();
+        for i in 0..MAX_STORED_NETLISTS + 3 {
+            history.record_at(&request(&format!("sim-{}", i)), "* netlist\n.op\n.end", i as u64);
+        }
+        let entries = history.entries();
+        assert_eq!(entries.len(), MAX_STORED_NETLISTS);
+        assert_eq!(entries[0].request_id, format!("sim-{}", MAX_STORED_NETLISTS + 2));
+        assert_eq!(entries.last().unwrap().request_id, "sim-3");
+
+        // Recording an id again moves it to the newest
+        history.record_at(&request("sim-5"), "* again\n.op\n.end", 99);
+        assert_eq!(history.entries().len(), MAX_STORED_NETLISTS);
+        assert_eq!(history.entries()[0].request_id, "sim-5");
+    }
+
+    #[test]
+    fn test_max_entries() {
+        let mut history = History::with_max_entries(3);
+        for i in 0..5 {
+            history.record_at(&request(&format!("sim-{}", i)), "* netlist\n.op\n.end", i as u64);
+        }
+        let ids: Vec<_> = history.entries().into_iter().map(|entry| entry.request_id).collect();
+        assert_eq!(ids, ["sim-4", "sim-3", "sim-2"]);
+
+        // Lowering the cap forgets the oldest right away
+        history.set_max_entries(1);
+        assert_eq!(history.entries().len(), 1);
+        assert_eq!(history.entries()[0].request_id, "sim-4");
+
+        let entries = history.budget_entries();
+        assert_eq!(entries, [BudgetEntry { key: "sim-4".to_string(), size: 1, last_used: 0 }]);
+        Budgeted::evict(&mut history, "sim-4");
+        assert!(history.entries().is_empty());
+    }
+
+    #[test]
+    fn test_byte_limit() {
+        // Random-looking text compresses poorly, so a few of these fill the limit
+        let mut seed = 0x2545_f491_4f6c_dd1du64;
+        let large: String = (0..3_000_000)
+            .map(|_| {
+                seed ^= seed << 13;
+                seed ^= seed >> 7;
+                seed ^= seed << 17;
+                char::from(b'a' + (seed % 26) as u8)
+            })
+            .collect();
+        let mut history = History::default();
+        for i in 0..8 {
+            history.record_at(&request(&format!("sim-{}", i)), &large, i);
+        }
+        assert!(history.stored_bytes() <= MAX_STORED_NETLIST_BYTES);
+        assert!(history.entries().len() < 8);
+        assert_eq!(history.entries()[0].request_id, "sim-7");
+    }
+}
@@ -0,0 +1,269 @@
+// Copyright (c) 2024-2025 Wanyeki Technologies LLC. All rights reserved.
+// This source code is licensed under the proprietary license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Which library files a run actually used, for `librariesUsed` in the response
+//!
+//! The agent knows where it resolved each `.include` and `.lib` to. LTspice may say in its log
+//! where it loaded a library from, and when that is a different file, such as a same-named
+//! library earlier on its own search path, the response flags the mismatch. The wording of
+//! those log lines varies between LTspice versions, so they are matched loosely: a line about a
+//! library or include naming a file, and not one reporting that the file couldn't be opened.
+
+use std::path::Path;
+
+use crate::protocol::{IncludedLibrary, LibraryStrategy, LibraryUsed};
+
+/// Words in a log line about loading a library
+const LOADING_WORDS: &[&str] = &["library", "include"];
+
+/// Words in a log line saying a library could not be loaded
+const FAILURE_WORDS: &[&str] = &["could not", "couldn't", "can't", "cannot", "not found", "unable", "error", "failed"];
+
+/// Paths of the library files `log` says were loaded, in the order it names them
+pub fn loaded_from_log(log: &str) -> Vec<String> {
+    let mut loaded: Vec<String> = Vec::new();
+    for line in log.lines() {
+        let lower = line.to_lowercase();
+        if !LOADING_WORDS.iter().any(|word| lower.contains(word)) || FAILURE_WORDS.iter().any(|word| lower.contains(word)) {
+            continue;
+        }
+        if let Some(path) = named_file(line) {
+            if !loaded.contains(&path) {
+                loaded.push(path);
+            }
+        }
+    }
+    loaded
+}
+
+/// The file a log line names: a quoted path, an absolute path running to the end of the line,
+/// what follows "label: ", or its last word
+fn named_file(line: &str) -> Option<String> {
+    let line = line.trim();
+    let quoted = line.split('"').nth(1).filter(|_| line.matches('"').count() >= 2);
+    let candidate = match (quoted, absolute_path_start(line)) {
+        (Some(quoted), _) => quoted,
+        (None, Some(start)) => &line[start..],
+        (None, None) => match line.split_once(": ") {
+            Some((_, rest)) => rest,
+            None => line.split_whitespace().last()?,
+        },
+    };
+    let candidate = candidate.trim().trim_end_matches('.').trim_matches('\'');
+    let has_extension = file_name(candidate).rsplit_once('.').is_some_and(|(stem, ext)| {
+        !stem.is_empty() && !ext.is_empty() && ext.chars().all(|c| c.is_ascii_alphanumeric())
+    });
+    has_extension.then(|| candidate.to_string())
+}
+
+/// Where the first word that starts an absolute path, `C:\` or `/`, begins in `line`
+fn absolute_path_start(line: &str) -> Option<usize> {
+    line.char_indices().map(|(i, _)| i).find(|&i| {
+        let word_start = line[..i].chars().next_back().is_none_or(char::is_whitespace);
+        word_start && is_absolute(&line[i..])
+    })
+}
+
+/// Whether `path` is absolute, as a Windows path or a Unix one
+fn is_absolute(path: &str) -> bool {
+    let bytes = path.as_bytes();
+    let has_drive = bytes.len() >= 3 && bytes[0].is_ascii_alphabetic() && bytes[1] == b':' && matches!(bytes[2], b'\\' | b'/');
+    has_drive || path.starts_with(['/', '\\'])
+}
+
+/// Last component of a path written with either separator
+fn file_name(path: &str) -> &str {
+    path.rsplit(['/', '\\']).next().unwrap_or(path)
+}
+
+/// A path in a form two spellings of the same file agree on: forward slashes, no `\\?\` or
+/// Wine `Z:` prefix, lowercase as LTspice runs on case-insensitive Windows paths
+fn normalize(path: &str) -> String {
+    let mut path = path.replace('\\', "/").to_lowercase();
+    if let Some(rest) = path.strip_prefix("//?/") {
+        path = rest.to_string();
+    }
+    if let Some(rest) = path.strip_prefix("z:/") {
+        path = format!("/{}", rest);
+    }
+    path
+}
+
+/// Whether the library was loaded from where the netlist pointed the simulator; a relative path
+/// is taken to be the file the netlist names, as there is nothing to compare it with
+fn loaded_as_resolved(library: &IncludedLibrary, temp_dir: &Path, loaded: &str) -> bool {
+    if !is_absolute(loaded) {
+        return true;
+    }
+    let expected = match library.strategy {
+        LibraryStrategy::Copy => temp_dir.join(&library.name),
+        LibraryStrategy::Reference => Path::new(&library.source).to_path_buf(),
+    };
+    let loaded = normalize(loaded);
+    std::iter::once(expected.clone())
+        .chain(std::fs::canonicalize(&expected))
+        .any(|expected| normalize(&expected.to_string_lossy()) == loaded)
+}
+
+/// The libraries the agent resolved, each with the path `loaded` gives for it, followed by any
+/// others the simulator says it loaded
+pub fn report(included: &[IncludedLibrary], temp_dir: &Path, loaded: &[String]) -> Vec<LibraryUsed> {
+    let mut unmatched: Vec<&String> = loaded.iter().collect();
+    let mut used: Vec<LibraryUsed> = included
+        .iter()
+        .map(|library| {
+            let position = unmatched.iter().position(|path| file_name(path).eq_ignore_ascii_case(&library.name));
+            let loaded_path = position.map(|i| unmatched.remove(i).clone());
+            LibraryUsed {
+                name: library.name.clone(),
+                source: Some(library.source.clone()),
+                strategy: Some(library.strategy),
+                expanded_from: library.expanded_from.clone(),
+                mismatch: loaded_path.as_deref().is_some_and(|path| !loaded_as_resolved(library, temp_dir, path)),
+                loaded_path,
+            }
+        })
+        .collect();
+    used.extend(unmatched.into_iter().map(|path| LibraryUsed {
+        name: file_name(path).to_string(),
+        source: None,
+        strategy: None,
+        expanded_from: None,
+        loaded_path: Some(path.clone()),
+        mismatch: false,
+    }));
+    used
+}
+
+/// A warning for each library the simulator loaded from somewhere other than where it was resolved
+pub fn mismatch_warnings(used: &[LibraryUsed]) -> Vec<String> {
+    used.iter()
+        .filter(|library| library.mismatch)
+        .map(|library| {
+            format!(
+                "{} was resolved to {} but the simulator loaded {}",
+                library.name,
+                library.source.as_deref().unwrap_or("nothing"),
+                library.loaded_path.as_deref().unwrap_or("nothing")
+            )
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Library lines in the forms LTspice logs them, around lines that must not be taken for them
+    const LOG: &str = "Circuit: * Z:\\tmp\\kelicad-sim-1\\circuit.net\r\n\
+        \r\n\
+        Loading library: C:\\Users\\me\\AppData\\Local\\LTspice\\lib\\sub\\LTC.lib\r\n\
+        Reading include file \"Z:\\tmp\\kelicad-sim-1\\opamps.lib\"\r\n\
+        Could not open include file \"missing.lib\"\r\n\
+        WARNING: Less than two connections to node N001.\r\n\
+        Direct Newton iteration for .op point succeeded.\r\n\
+        library C:\\Program Files\\ADI\\LTspice\\lib\\cmp\\standard.dio\r\n";
+
+    fn library(name: &str, source: &str, strategy: LibraryStrategy) -> IncludedLibrary {
+        IncludedLibrary { name: name.to_string(), source: source.to_string(), strategy, expanded_from: None }
+    }
+
+    #[test]
+    fn test_loaded_from_log() {
+        assert_eq!(
+            loaded_from_log(LOG),
+            [
+                "C:\\Users\\me\\AppData\\Local\\LTspice\\lib\\sub\\LTC.lib",
+                "Z:\\tmp\\kelicad-sim-1\\opamps.lib",
+                "C:\\Program Files\\ADI\\LTspice\\lib\\cmp\\standard.dio",
+            ]
+        );
+        assert!(loaded_from_log("").is_empty());
+        assert!(loaded_from_log("Direct Newton iteration for .op point succeeded.\nTotal elapsed time: 0.01 seconds.").is_empty());
+        assert_eq!(loaded_from_log(".include opamps.lib\n.include opamps.lib"), ["opamps.lib"]);
+    }
+
+    #[test]
+    fn test_is_absolute() {
+        assert!(is_absolute("C:\\Models\\a.lib"));
+        assert!(is_absolute("z:/tmp/a.lib"));
+        assert!(is_absolute("/opt/a.lib"));
+        assert!(is_absolute("\\\\server\\share\\a.lib"));
+        assert!(!is_absolute("vendor/a.lib"));
+        assert!(!is_absolute("C:a.lib"));
+    }
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(normalize("Z:\\tmp\\Run\\opamps.lib"), "/tmp/run/opamps.lib");
+        assert_eq!(normalize("\\\\?\\C:\\Models\\LTC.lib"), "c:/models/ltc.lib");
+        assert_eq!(normalize("/opt/models/LTC.lib"), "/opt/models/ltc.lib");
+    }
+
+    #[test]
+    fn test_report_matches_the_log_to_what_was_resolved() {
+        let temp_dir = Path::new("/tmp/kelicad-sim-1");
+        let included = [
+            library("opamps.lib", "/opt/models/opamps.lib", LibraryStrategy::Copy),
+            library("LTC.lib", "/opt/models/LTC.lib", LibraryStrategy::Reference),
+            library("diodes.lib", "attachment", LibraryStrategy::Copy),
+        ];
+        let used = report(&included, temp_dir, &loaded_from_log(LOG));
+        assert_eq!(used.len(), 4);
+
+        // The copy in the temp dir, seen through Wine's Z: drive, is the one that was resolved
+        assert_eq!(used[0].loaded_path.as_deref(), Some("Z:\\tmp\\kelicad-sim-1\\opamps.lib"));
+        assert!(!used[0].mismatch);
+
+        // LTspice took its own LTC.lib over the referenced one
+        assert!(used[1].mismatch);
+        assert_eq!(used[1].source.as_deref(), Some("/opt/models/LTC.lib"));
+
+        // Nothing in the log about the attachment, which isn't a mismatch
+        assert_eq!(used[2].loaded_path, None);
+        assert!(!used[2].mismatch);
+
+        // A library only the log names is listed without a source
+        assert_eq!(used[3].name, "standard.dio");
+        assert_eq!(used[3].source, None);
+        assert!(!used[3].mismatch);
+
+        assert_eq!(
+            mismatch_warnings(&used),
+            ["LTC.lib was resolved to /opt/models/LTC.lib but the simulator loaded C:\\Users\\me\\AppData\\Local\\LTspice\\lib\\sub\\LTC.lib"]
+        );
+    }
+
+    #[test]
+    fn test_loaded_as_resolved() {
+        let temp_dir = Path::new("/tmp/run");
+        let copy = library("a.lib", "/opt/a.lib", LibraryStrategy::Copy);
+        let reference = library("a.lib", "/opt/a.lib", LibraryStrategy::Reference);
+        assert!(loaded_as_resolved(&copy, temp_dir, "a.lib"));
+        assert!(loaded_as_resolved(&copy, temp_dir, "/tmp/run/A.LIB"));
+        assert!(!loaded_as_resolved(&copy, temp_dir, "/opt/a.lib"));
+        assert!(loaded_as_resolved(&reference, temp_dir, "/opt/a.lib"));
+        assert!(loaded_as_resolved(&reference, temp_dir, "Z:\\opt\\a.lib"));
+        assert!(loaded_as_resolved(&reference, temp_dir, "vendor/a.lib"));
+        assert!(!loaded_as_resolved(&reference, temp_dir, "/tmp/run/a.lib"));
+    }
+
+    #[test]
+    fn test_report_without_a_log() {
+        let included = [library("a.lib", "attachment", LibraryStrategy::Copy)];
+        let used = report(&included, Path::new("/tmp/run"), &[]);
+        assert_eq!(
+            used,
+            [LibraryUsed {
+                name: "a.lib".to_string(),
+                source: Some("attachment".to_string()),
+                strategy: Some(LibraryStrategy::Copy),
+                expanded_from: None,
+                loaded_path: None,
+                mismatch: false,
+            }]
+        );
+        assert!(mismatch_warnings(&used).is_empty());
+    }
+}
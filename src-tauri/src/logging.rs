@@ -0,0 +1,326 @@
+// Copyright (c) 2024-2025 Wanyeki Technologies LLC. All rights reserved.
+// This source code is licensed under the proprietary license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Log file with size-based rotation, so bundled builds leave logs users can attach to bug reports
+//!
+//! The active file is `kelicad-agent.log`; older files are `kelicad-agent.1.log` (newest)
+//! through `kelicad-agent.4.log` (oldest).
+
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::{LevelFilter, Log, Metadata, Record};
+
+const LOG_FILE_STEM: &str = "kelicad-agent";
+
+/// Size at which the active file is rotated
+pub const MAX_LOG_FILE_BYTES: u64 = 5 * 1024 * 1024;
+
+/// Number of files kept, including the active one
+pub const MAX_LOG_FILES: usize = 5;
+
+/// Upper bound for `get_recent_logs`
+pub const MAX_RECENT_LINES: usize = 5000;
+
+/// Records from other crates (tao, tungstenite, ...) are never logged above this level
+const DEPENDENCY_MAX_LEVEL: LevelFilter = LevelFilter::Info;
+
+static LOGGER: OnceLock<AgentLogger> = OnceLock::new();
+
+/// Platform log directory (`~/Library/Logs/kelicad-agent` on macOS, the local data dir elsewhere)
+pub fn log_dir() -> Option<PathBuf> {
+    if cfg!(target_os = "macos") {
+        dirs::home_dir().map(|home| home.join("Library").join("Logs").join(LOG_FILE_STEM))
+    } else {
+        dirs::data_local_dir().map(|dir| dir.join(LOG_FILE_STEM).join("logs"))
+    }
+}
+
+/// Path of the n-th log file, 0 being the active one
+fn log_file_path_in(dir: &Path, index: usize) -> PathBuf {
+    match index {
+        0 => dir.join(format!("{}.log", LOG_FILE_STEM)),
+        n => dir.join(format!("{}.{}.log", LOG_FILE_STEM, n)),
+    }
+}
+
+/// Install the logger
+/// Falls back to stderr only if the log directory cannot be created
+pub fn init(level: LevelFilter, stderr: bool) {
+    let file = log_dir().and_then(|dir| match RotatingFile::open(&dir, MAX_LOG_FILE_BYTES) {
+        Ok(file) => Some(file),
+        Err(e) => {
+            eprintln!("kelicad-agent: cannot open log file in {}: {}", dir.display(), e);
+            None
+        }
+    });
+
+    let logger = LOGGER.get_or_init(|| AgentLogger {
+        file: Mutex::new(file),
+        stderr,
+    });
+    if log::set_logger(logger).is_ok() {
+        log::set_max_level(level);
+    }
+}
+
+/// Initial level from `RUST_LOG` when it is a plain level name, `info` otherwise
+pub fn level_from_env() -> LevelFilter {
+    std::env::var("RUST_LOG")
+        .ok()
+        .and_then(|value| parse_level(&value).ok())
+        .unwrap_or(LevelFilter::Info)
+}
+
+/// Parse a level name such as `debug` or `WARN`
+pub fn parse_level(level: &str) -> Result<LevelFilter, String> {
+    level
+        .trim()
+        .parse::<LevelFilter>()
+        .map_err(|_| format!("Invalid log level: {} (expected off, error, warn, info, debug or trace)", level))
+}
+
+/// Change the level at runtime
+pub fn set_level(level: LevelFilter) {
+    log::set_max_level(level);
+    log::info!("Log level set to {}", level);
+}
+
+/// Path of the file currently written to, if file logging is active
+pub fn active_log_file() -> Option<PathBuf> {
+    let logger = LOGGER.get()?;
+    let file = logger.file.lock().ok()?;
+    file.as_ref().map(|file| file.path())
+}
+
+/// Last `lines` lines across the active and rotated files, oldest first
+pub fn recent_lines(lines: usize) -> Result<Vec<String>, String> {
+    let dir = log_dir().ok_or("No log directory on this platform")?;
+    read_recent_lines(&dir, lines.min(MAX_RECENT_LINES))
+}
+
+fn read_recent_lines(dir: &Path, lines: usize) -> Result<Vec<String>, String> {
+    // Newest file first, stopping once enough lines are collected
+    let mut collected: Vec<String> = Vec::new();
+    for index in 0..MAX_LOG_FILES {
+        if collected.len() >= lines {
+            break;
+        }
+        let content = match fs::read(log_file_path_in(dir, index)) {
+            Ok(bytes) => String::from_utf8_lossy(&bytes).into_owned(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(format!("Failed to read log file: {}", e)),
+        };
+        let wanted = lines - collected.len();
+        let file_lines: Vec<&str> = content.lines().collect();
+        let start = file_lines.len().saturating_sub(wanted);
+        collected.extend(file_lines[start..].iter().rev().map(|line| line.to_string()));
+    }
+    collected.reverse();
+    Ok(collected)
+}
+
+/// Reveal the log directory in the platform file manager
+pub fn open_log_folder() -> Result<(), String> {
+    let dir = log_dir().ok_or("No log directory on this platform")?;
+    fs::create_dir_all(&dir).map_err(|e| format!("Failed to create log folder: {}", e))?;
+
+    let program = if cfg!(target_os = "macos") {
+        "open"
+    } else if cfg!(target_os = "windows") {
+        "explorer"
+    } else {
+        "xdg-open"
+    };
+    std::process::Command::new(program)
+        .arg(&dir)
+        .spawn()
+        .map(|_| ())
+        .map_err(|e| format!("Failed to open log folder: {}", e))
+}
+
+/// The active log file, renamed through the numbered backups once it reaches `max_bytes`
+struct RotatingFile {
+    dir: PathBuf,
+    file: File,
+    size: u64,
+    max_bytes: u64,
+}
+
+impl RotatingFile {
+    fn open(dir: &Path, max_bytes: u64) -> std::io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        let file = OpenOptions::new().create(true).append(true).open(log_file_path_in(dir, 0))?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            file,
+            size,
+            max_bytes,
+        })
+    }
+
+    fn path(&self) -> PathBuf {
+        log_file_path_in(&self.dir, 0)
+    }
+
+    fn write_line(&mut self, line: &str) -> std::io::Result<()> {
+        let len = line.len() as u64 + 1;
+        if self.size > 0 && self.size + len > self.max_bytes {
+            self.rotate()?;
+        }
+        writeln!(self.file, "{}", line)?;
+        self.size += len;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> std::io::Result<()> {
+        // The oldest file falls off the end; a missing file just leaves a gap
+        for index in (0..MAX_LOG_FILES - 1).rev() {
+            let from = log_file_path_in(&self.dir, index);
+            if from.exists() {
+                fs::rename(&from, log_file_path_in(&self.dir, index + 1))?;
+            }
+        }
+        self.file = OpenOptions::new().create(true).append(true).open(self.path())?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+struct AgentLogger {
+    file: Mutex<Option<RotatingFile>>,
+    stderr: bool,
+}
+
+impl Log for AgentLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        let own_crate = metadata.target().starts_with(env!("CARGO_CRATE_NAME"));
+        own_crate || metadata.level() <= DEPENDENCY_MAX_LEVEL
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let line = format_line(now_ms(), record);
+
+        if self.stderr {
+            eprintln!("{}", line);
+        }
+        if let Ok(mut file) = self.file.lock() {
+            if let Some(file) = file.as_mut() {
+                let _ = file.write_line(&line);
+            }
+        }
+    }
+
+    fn flush(&self) {
+        if let Ok(mut file) = self.file.lock() {
+            if let Some(file) = file.as_mut() {
+                let _ = file.file.flush();
+            }
+        }
+    }
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0)
+}
+
+/// `2025-03-14T09:26:53.589Z INFO  kelicad_agent::websocket: message`
+fn format_line(timestamp_ms: u64, record: &Record) -> String {
+    format!(
+        "{} {:<5} {}: {}",
+        format_timestamp(timestamp_ms),
+        record.level(),
+        record.target(),
+        record.args()
+    )
+}
+
+/// RFC 3339 UTC timestamp with milliseconds
+fn format_timestamp(timestamp_ms: u64) -> String {
+    let secs = timestamp_ms / 1000;
+    let (year, month, day) = civil_from_days((secs / 86_400) as i64);
+    let time = secs % 86_400;
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        time / 3600,
+        time % 3600 / 60,
+        time % 60,
+        timestamp_ms % 1000
+    )
+}
+
+/// Days since 1970-01-01 to (year, month, day), after Howard Hinnant's `civil_from_days`
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_timestamp() {
+        assert_eq!(format_timestamp(0), "1970-01-01T00:00:00.000Z");
+        assert_eq!(format_timestamp(1_709_251_199_999), "2024-02-29T23:59:59.999Z");
+        assert_eq!(format_timestamp(1_741_944_413_589), "2025-03-14T09:26:53.589Z");
+    }
+
+    #[test]
+    fn test_parse_level() {
+        assert_eq!(parse_level("debug"), Ok(LevelFilter::Debug));
+        assert_eq!(parse_level(" WARN "), Ok(LevelFilter::Warn));
+        assert!(parse_level("verbose").unwrap_err().contains("Invalid log level"));
+    }
+
+    #[test]
+    fn test_rotation_keeps_max_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut file = RotatingFile::open(dir.path(), 100).unwrap();
+        // Ten lines of 50 bytes: two per file, so only the last ten survive across five files
+        for i in 0..20 {
+            file.write_line(&format!("{:02}{}", i, "x".repeat(47))).unwrap();
+        }
+
+        for index in 0..MAX_LOG_FILES {
+            let len = fs::metadata(log_file_path_in(dir.path(), index)).unwrap().len();
+            assert!(len <= 100, "file {} is {} bytes", index, len);
+        }
+        assert!(!log_file_path_in(dir.path(), MAX_LOG_FILES).exists());
+
+        let lines = read_recent_lines(dir.path(), 100).unwrap();
+        assert_eq!(lines.len(), 10);
+        assert!(lines[0].starts_with("10"));
+        assert!(lines[9].starts_with("19"));
+    }
+
+    #[test]
+    fn test_recent_lines_spans_rotated_files() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(log_file_path_in(dir.path(), 1), "a\nb\nc\n").unwrap();
+        fs::write(log_file_path_in(dir.path(), 0), "d\ne\n").unwrap();
+
+        assert_eq!(read_recent_lines(dir.path(), 3).unwrap(), vec!["c", "d", "e"]);
+        assert_eq!(read_recent_lines(dir.path(), 1).unwrap(), vec!["e"]);
+        assert!(read_recent_lines(dir.path(), 0).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,134 @@
+// Copyright (c) 2024-2025 Wanyeki Technologies LLC. All rights reserved.
+// This source code is licensed under the proprietary license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Windows path forms the simulators accept
+//!
+//! Redirected profiles put the temp dir on a file share (`\\server\share\...`), where LTspice
+//! fails to write its raw file without saying so, and deep folders push paths past the 260
+//! character limit older Windows programs still have. The helpers here work on plain strings so
+//! they can be tested on any platform; callers decide whether they apply.
+
+/// Longest path, terminator included, Windows programs without long path support can open
+pub const MAX_PATH: usize = 260;
+
+/// Longest temp root left room for a simulation's temp dir and the file names in it
+pub const MAX_TEMP_ROOT_CHARS: usize = 160;
+
+const VERBATIM_PREFIX: &str = r"\\?\";
+const VERBATIM_UNC_PREFIX: &str = r"\\?\UNC\";
+
+/// Whether `path` is on a file share: `\\server\share\...`, `//server/share/...` or `\\?\UNC\...`
+pub fn is_unc(path: &str) -> bool {
+    if path.starts_with(VERBATIM_UNC_PREFIX) {
+        return true;
+    }
+    let bytes = path.as_bytes();
+    let separator = |i: usize| matches!(bytes.get(i), Some(b'\\' | b'/'));
+    // \\?\C:\ and device paths like \\.\pipe are local
+    separator(0) && separator(1) && !matches!(bytes.get(2), None | Some(b'?' | b'.' | b'\\' | b'/'))
+}
+
+/// Why simulations shouldn't make their temp dirs under `path`, if they shouldn't
+pub fn unsuitable_temp_root(path: &str) -> Option<String> {
+    if is_unc(path) {
+        Some(format!("{} is on a network share", path))
+    } else if path.chars().count() > MAX_TEMP_ROOT_CHARS {
+        Some(format!("{} is longer than {} characters", path, MAX_TEMP_ROOT_CHARS))
+    } else {
+        None
+    }
+}
+
+/// `path` with the `\\?\` prefix that lifts the length limit, when it is an absolute path too
+/// long to use without it; None when it can be used as it is
+pub fn extended_length(path: &str) -> Option<String> {
+    if path.starts_with(VERBATIM_PREFIX) || path.chars().count() < MAX_PATH {
+        return None;
+    }
+    // The prefix turns off path parsing, so only backslashes separate components
+    let path = path.replace('/', "\\");
+    if is_unc(&path) {
+        return Some(format!("{}{}", VERBATIM_UNC_PREFIX, &path[2..]));
+    }
+    let bytes = path.as_bytes();
+    let has_drive = bytes.len() > 2 && bytes[0].is_ascii_alphabetic() && bytes[1] == b':' && bytes[2] == b'\\';
+    has_drive.then(|| format!("{}{}", VERBATIM_PREFIX, path))
+}
+
+/// `path` as written into a netlist: forward slashes, which LTspice and ngspice read on every
+/// platform, and no `\\?\` prefix, which neither understands
+pub fn for_netlist(path: &str) -> String {
+    let path = if let Some(rest) = path.strip_prefix(VERBATIM_UNC_PREFIX) {
+        format!(r"\\{}", rest)
+    } else {
+        path.strip_prefix(VERBATIM_PREFIX).unwrap_or(path).to_string()
+    };
+    path.replace('\\', "/")
+}
+
+/// Whether a simulator can't open `path` from a netlist, so it has to be copied somewhere local
+pub fn unreachable_from_netlist(path: &str) -> bool {
+    is_unc(path) || for_netlist(path).chars().count() >= MAX_PATH
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_unc() {
+        assert!(is_unc(r"\\fileserver\profiles\bob\AppData\Local\Temp"));
+        assert!(is_unc("//fileserver/profiles"));
+        assert!(is_unc(r"\\?\UNC\fileserver\profiles"));
+        assert!(!is_unc(r"C:\Users\bob\AppData\Local\Temp"));
+        assert!(!is_unc(r"\\?\C:\Users\bob"));
+        assert!(!is_unc(r"\\.\pipe\kelicad"));
+        assert!(!is_unc("/tmp"));
+        assert!(!is_unc(r"\\"));
+    }
+
+    #[test]
+    fn test_unsuitable_temp_root() {
+        assert_eq!(unsuitable_temp_root(r"C:\Users\bob\AppData\Local\Temp"), None);
+        assert!(unsuitable_temp_root(r"\\fileserver\profiles\bob\AppData\Local\Temp").unwrap().contains("network share"));
+        let deep = format!(r"C:\{}", "nested\\".repeat(30));
+        assert!(unsuitable_temp_root(&deep).unwrap().contains("longer than 160 characters"));
+    }
+
+    #[test]
+    fn test_extended_length() {
+        assert_eq!(extended_length(r"C:\Temp\circuit.net"), None);
+
+        let long = format!(r"C:\{}circuit.net", "deep\\".repeat(60));
+        assert_eq!(extended_length(&long), Some(format!(r"\\?\{}", long)));
+        let mixed = format!("C:/{}circuit.net", "deep/".repeat(60));
+        assert_eq!(extended_length(&mixed), Some(format!(r"\\?\{}", long)));
+        assert_eq!(extended_length(&format!(r"\\?\{}", long)), None);
+
+        let share = format!(r"\\fileserver\profiles\{}circuit.net", "deep\\".repeat(60));
+        assert_eq!(
+            extended_length(&share),
+            Some(format!(r"\\?\UNC\fileserver\profiles\{}circuit.net", "deep\\".repeat(60)))
+        );
+
+        // Relative paths can't take the prefix
+        assert_eq!(extended_length(&"deep\\".repeat(60)), None);
+    }
+
+    #[test]
+    fn test_for_netlist() {
+        assert_eq!(for_netlist(r"C:\Users\bob\models\opamp.lib"), "C:/Users/bob/models/opamp.lib");
+        assert_eq!(for_netlist(r"\\?\C:\Users\bob\opamp.lib"), "C:/Users/bob/opamp.lib");
+        assert_eq!(for_netlist(r"\\?\UNC\fileserver\models\opamp.lib"), "//fileserver/models/opamp.lib");
+        assert_eq!(for_netlist("/home/bob/opamp.lib"), "/home/bob/opamp.lib");
+    }
+
+    #[test]
+    fn test_unreachable_from_netlist() {
+        assert!(!unreachable_from_netlist(r"C:\models\opamp.lib"));
+        assert!(!unreachable_from_netlist(r"\\?\C:\models\opamp.lib"));
+        assert!(unreachable_from_netlist(r"\\fileserver\models\opamp.lib"));
+        assert!(unreachable_from_netlist(&format!(r"C:\{}opamp.lib", "deep\\".repeat(60))));
+    }
+}
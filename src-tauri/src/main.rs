@@ -7,136 +7,753 @@
 mod websocket;
 mod simulator;
 mod protocol;
+mod settings;
+mod simulation;
+mod state;
+mod export;
+mod tray;
+mod notifications;
+mod autostart;
+mod cli;
+mod headless;
+mod shutdown;
+mod logging;
+mod metrics;
+mod detection;
+mod dedup;
+mod derived;
+mod netlist;
+mod probes;
+mod quality;
+mod self_test;
+mod compat;
+mod wire;
+mod rate_limit;
+mod catalog;
+mod fingerprint;
+mod history;
+mod status;
+mod window;
+mod validation;
+mod artifacts;
+mod precision;
+mod temp_root;
+mod onboarding;
+mod trace_store;
+mod libraries_used;
+mod long_path;
+mod path_variables;
+mod peek;
+mod plot_hints;
+mod resource_budget;
+mod shared_results;
+mod status_file;
+mod templates;
+mod wave;
+mod ngspice_session;
+mod trace_aliases;
+#[cfg(feature = "libngspice")]
+mod ngspice_shared;
 
+use std::collections::HashMap;
 use std::sync::Arc;
-use std::sync::atomic::{AtomicU32, AtomicBool};
-use serde::Serialize;
 use tauri::{
-    menu::{Menu, MenuItem},
+    menu::{CheckMenuItem, Menu, MenuItem, PredefinedMenuItem},
     tray::{MouseButton, MouseButtonState, TrayIconBuilder, TrayIconEvent},
-    Manager, RunEvent, State,
+    AppHandle, Emitter, Manager, RunEvent, State,
 };
-use tokio::sync::RwLock;
-
-pub struct AppState {
-    pub ltspice_path: RwLock<Option<String>>,
-    pub ngspice_path: RwLock<Option<String>>,
-    pub is_simulating: RwLock<bool>,
-    pub ws_connections: RwLock<u32>,
-    pub simulation_count: RwLock<u32>,
-    pub last_simulation_time: RwLock<Option<u64>>,
-    pub current_simulation_id: RwLock<Option<String>>,
-    pub cancel_requested: AtomicBool,
-    pub current_process_id: Arc<AtomicU32>,
-}
-
-impl Default for AppState {
-    fn default() -> Self {
-        Self {
-            ltspice_path: RwLock::new(None),
-            ngspice_path: RwLock::new(None),
-            is_simulating: RwLock::new(false),
-            ws_connections: RwLock::new(0),
-            simulation_count: RwLock::new(0),
-            last_simulation_time: RwLock::new(None),
-            current_simulation_id: RwLock::new(None),
-            cancel_requested: AtomicBool::new(false),
-            current_process_id: Arc::new(AtomicU32::new(0)),
+use tauri_plugin_clipboard_manager::ClipboardExt;
+use tauri_plugin_dialog::DialogExt;
+use tokio::sync::mpsc;
+
+use protocol::{EngineOptions, InputFormat, NetlistEncoding, SimulationRequest, SimulationResponse};
+use resource_budget::{BudgetUsage, ResourceBudget};
+use settings::AgentSettings;
+use state::{AppState, StatusEvent};
+
+#[tauri::command]
+async fn get_agent_status(state: State<'_, Arc<AppState>>) -> Result<status::AgentStatus, String> {
+    Ok(status::agent_status(&state).await)
+}
+
+/// Onboarding stage and install guides, for the window's install-guide screen
+#[tauri::command]
+async fn get_onboarding_state(state: State<'_, Arc<AppState>>) -> Result<protocol::OnboardingState, String> {
+    Ok(state.onboarding.read().await.clone())
+}
+
+#[tauri::command]
+async fn get_settings(state: State<'_, Arc<AppState>>) -> Result<AgentSettings, String> {
+    Ok(state.settings.read().await.clone())
+}
+
+#[tauri::command]
+async fn update_settings(
+    settings: AgentSettings,
+    app: AppHandle,
+    state: State<'_, Arc<AppState>>,
+) -> Result<AgentSettings, String> {
+    state
+        .set_max_concurrent_simulations(settings.max_concurrent_simulations)
+        .await;
+    let budget = ResourceBudget::from_settings(&settings);
+    {
+        let mut current = state.settings.write().await;
+        current.watchdog_stall_secs = settings.watchdog_stall_secs;
+        current.keepalive_interval_secs = settings.keepalive_interval_secs;
+        current.background_priority = settings.background_priority;
+        current.ngspice_threads = settings.ngspice_threads.map(|n| n.max(1));
+        current.ngspice_persistent_session = settings.ngspice_persistent_session;
+        current.min_free_disk_mb = settings.min_free_disk_mb;
+        current.notifications_enabled = settings.notifications_enabled;
+        current.notification_threshold_secs = settings.notification_threshold_secs;
+        current.max_simulation_seconds = settings.max_simulation_seconds.max(1);
+        current.long_run_warning_secs = settings.long_run_warning_secs;
+        current.rate_limit_per_minute = settings.rate_limit_per_minute;
+        current.rate_limit_localhost = settings.rate_limit_localhost;
+        current.disabled_bundled_libraries = settings.disabled_bundled_libraries;
+        current.store_netlists = settings.store_netlists;
+        current.max_result_bytes = settings.max_result_bytes;
+        current.max_artifact_bytes = settings.max_artifact_bytes;
+        current.max_history_entries = settings.max_history_entries;
+        current.auto_inject_options = settings.auto_inject_options;
+        current.start_hidden = settings.start_hidden;
+        current.allow_result_subscriptions = settings.allow_result_subscriptions;
+    }
+    state.set_resource_budget(budget).await;
+    if !settings.store_netlists {
+        state.history.write().await.clear();
+    }
+    if !settings.ngspice_persistent_session {
+        state.ngspice_session.close().await;
+    }
+
+    let effective = state.settings.read().await.clone();
+    settings::save_settings(&effective)?;
+    if let Some(menu) = app.try_state::<tray::TrayMenu>() {
+        menu.sync_settings(&effective);
+    }
+    log::info!("Settings updated: {:?}", effective);
+    Ok(effective)
+}
+
+/// Text of a bundled library, for previewing it before enabling or disabling it
+#[tauri::command]
+async fn get_bundled_library_content(name: String, state: State<'_, Arc<AppState>>) -> Result<String, String> {
+    let libraries = state.bundled_libraries.clone();
+    tokio::task::spawn_blocking(move || catalog::read_content(&libraries, &name))
+        .await
+        .map_err(|e| e.to_string())?
+}
+
+/// Change the longest a simulation may run and persist it; returns the new limit
+/// Applies to simulations started afterwards
+#[tauri::command]
+async fn set_max_simulation_time(seconds: u64, state: State<'_, Arc<AppState>>) -> Result<u64, String> {
+    if seconds == 0 {
+        return Err("Maximum simulation time must be at least 1 second".to_string());
+    }
+    let updated = {
+        let mut settings = state.settings.write().await;
+        settings.max_simulation_seconds = seconds;
+        settings.clone()
+    };
+    settings::save_settings(&updated)?;
+    log::info!("Maximum simulation time set to {}s", seconds);
+    Ok(seconds)
+}
+
+/// Waveform quality profiles by name, as a request's `waveformQuality` picks them
+#[tauri::command]
+async fn get_quality_profiles(
+    state: State<'_, Arc<AppState>>,
+) -> Result<std::collections::BTreeMap<String, quality::QualityProfile>, String> {
+    Ok(state.settings.read().await.quality_profiles.clone())
+}
+
+/// Add or replace a quality profile and persist it, or remove it when `profile` is None
+/// Returns the profiles now defined
+#[tauri::command]
+async fn set_quality_profile(
+    name: String,
+    profile: Option<quality::QualityProfile>,
+    state: State<'_, Arc<AppState>>,
+) -> Result<std::collections::BTreeMap<String, quality::QualityProfile>, String> {
+    let updated = {
+        let mut settings = state.settings.write().await;
+        match profile {
+            Some(profile) => {
+                quality::validate(&name, &profile)?;
+                settings.quality_profiles.insert(name.clone(), profile);
+            }
+            None if settings.quality_profiles.len() == 1 && settings.quality_profiles.contains_key(&name) => {
+                return Err("At least one quality profile must remain".to_string());
+            }
+            None => {
+                settings.quality_profiles.remove(&name);
+            }
+        }
+        settings.clone()
+    };
+    settings::save_settings(&updated)?;
+    log::info!("Quality profile {} updated", name);
+    Ok(updated.quality_profiles)
+}
+
+/// Re-run simulator detection now; returns whether LTspice or ngspice availability changed
+#[tauri::command]
+async fn redetect_simulators(state: State<'_, Arc<AppState>>) -> Result<bool, String> {
+    Ok(detection::redetect(&state).await)
+}
+
+/// Open the LTspice window so the dialogs a fresh install shows on first launch, which block
+/// batch runs, can be dismissed
+#[tauri::command]
+async fn initialize_ltspice(state: State<'_, Arc<AppState>>) -> Result<(), String> {
+    let path = state.ltspice_path.read().await.clone().ok_or("LTspice was not found")?;
+    simulator::launch_ltspice_gui(&path)
+}
+
+/// Simulate the built-in reference circuit on each detected simulator, for support
+#[tauri::command]
+async fn run_self_test(state: State<'_, Arc<AppState>>) -> Result<protocol::SelfTestResponse, String> {
+    Ok(self_test::run(&state, uuid::Uuid::new_v4().to_string()).await)
+}
+
+/// Clear the health counters; uptime and the lifetime simulation count are kept
+#[tauri::command]
+async fn reset_metrics(state: State<'_, Arc<AppState>>) -> Result<protocol::AgentMetrics, String> {
+    let mut metrics = state.metrics.write().await;
+    metrics.reset();
+    log::info!("Metrics reset");
+    Ok(metrics.snapshot())
+}
+
+/// Last lines of the log, oldest first, for attaching to bug reports
+#[tauri::command]
+async fn get_recent_logs(lines: u32) -> Result<Vec<String>, String> {
+    tokio::task::spawn_blocking(move || logging::recent_lines(lines as usize))
+        .await
+        .map_err(|e| e.to_string())?
+}
+
+#[tauri::command]
+fn open_log_folder() -> Result<(), String> {
+    logging::open_log_folder()
+}
+
+/// Change the log level until the agent restarts; returns the level now in effect
+#[tauri::command]
+fn set_log_level(level: String) -> Result<String, String> {
+    let level = logging::parse_level(&level)?;
+    logging::set_level(level);
+    Ok(level.to_string().to_lowercase())
+}
+
+/// Whether the agent launches at login, as registered with the OS
+#[tauri::command]
+fn get_autostart(app: AppHandle) -> Result<bool, String> {
+    autostart::is_enabled(&app)
+}
+
+/// Enable or disable launch at login; returns the resulting state
+#[tauri::command]
+async fn set_autostart(enabled: bool, app: AppHandle) -> Result<bool, String> {
+    autostart::set_enabled(&app, enabled).await
+}
+
+/// Recover from a stuck agent: drop all in-flight simulations and kill their processes
+#[tauri::command]
+async fn force_reset(state: State<'_, Arc<AppState>>) -> Result<Vec<String>, String> {
+    let cleared = state.force_reset().await;
+    for (request_id, pids) in &cleared {
+        if pids.is_empty() {
+            log::warn!("Force reset: cleared simulation {} (no process running)", request_id);
+        }
+        for pid in pids {
+            log::warn!("Force reset: killing simulator PID {} for simulation {}", pid, request_id);
+            simulator::kill_process_tree(*pid);
+        }
+    }
+    log::info!("Force reset cleared {} simulation(s)", cleared.len());
+    Ok(cleared.into_iter().map(|(request_id, _)| request_id).collect())
+}
+
+/// Run a simulation from the agent window without the web app
+/// Progress and log messages are emitted as `simulation-progress` and `simulation-log` events
+/// With `keep_raw`, the raw file can then be saved with `export_raw_file`
+#[tauri::command]
+async fn run_local_simulation(
+    netlist: String,
+    simulator: String,
+    quality: String,
+    keep_raw: Option<bool>,
+    app: tauri::AppHandle,
+    state: State<'_, Arc<AppState>>,
+) -> Result<SimulationResponse, String> {
+    let request = SimulationRequest {
+        id: format!("local-{}", uuid::Uuid::new_v4()),
+        msg_type: "simulate".to_string(),
+        netlist,
+        input_format: InputFormat::Netlist,
+        netlist_encoding: NetlistEncoding::Plain,
+        dialect: None,
+        waveform_quality: quality,
+        simulator,
+        timeout: None,
+        monte_carlo: None,
+        stream_logs: true,
+        default_analysis: None,
+        confirm_long_runs: false,
+        convergence_assist: false,
+        include_debug_info: false,
+        compress: false,
+        engine_options: EngineOptions::default(),
+        attachments: Vec::new(),
+        current_probes: None,
+        auto_retry_other_engine: false,
+        library_search_paths: Vec::new(),
+        library_strategy: None,
+        keep_raw: keep_raw.unwrap_or(false),
+        precision: None,
+        f32_results: false,
+        defer_trace_data: false,
+        save_traces: Vec::new(),
+        peek: false,
+        peek_fast: false,
+        no_auto_options: false,
+        wave_output: None,
+        trace_aliases: HashMap::new(),
+        timestamp: protocol::now_ms(),
+        replay_of: None,
+        origin: None,
+    };
+    run_local(request, app, &state).await
+}
+
+/// Run the stored netlist of an earlier simulation again, like `run_local_simulation`
+/// Needs the `store_netlists` setting to have been on when the simulation ran
+#[tauri::command]
+async fn rerun_simulation(
+    history_id: String,
+    app: tauri::AppHandle,
+    state: State<'_, Arc<AppState>>,
+) -> Result<SimulationResponse, String> {
+    let request = state.history.read().await.replay_request(&history_id)?;
+    log::info!("Replaying simulation {} as {}", history_id, request.id);
+    run_local(request, app, &state).await
+}
+
+/// Simulations whose netlists are kept for `rerun_simulation`, newest first
+#[tauri::command]
+async fn get_simulation_history(state: State<'_, Arc<AppState>>) -> Result<Vec<history::HistoryEntry>, String> {
+    Ok(state.history.read().await.entries())
+}
+
+/// Results, raw files and netlists kept now against the resource budget
+#[tauri::command]
+async fn get_resource_usage(state: State<'_, Arc<AppState>>) -> Result<BudgetUsage, String> {
+    Ok(state.resource_usage().await)
+}
+
+/// Run a simulation for the agent window, emitting its progress and log messages as events
+async fn run_local(request: SimulationRequest, app: tauri::AppHandle, state: &AppState) -> Result<SimulationResponse, String> {
+    {
+        let mut local = state.local_simulation.write().await;
+        if local.is_some() {
+            return Err("A local simulation is already running".to_string());
         }
+        *local = Some(request.id.clone());
     }
+    log::info!("Running local simulation {}", request.id);
+
+    // Re-emit the same JSON messages the WebSocket client would receive
+    let (progress_tx, mut progress_rx) = mpsc::channel::<String>(16);
+    let forwarder = tauri::async_runtime::spawn(async move {
+        while let Some(json) = progress_rx.recv().await {
+            let Ok(message) = serde_json::from_str::<serde_json::Value>(&json) else {
+                continue;
+            };
+            let event = match message["type"].as_str() {
+                Some("simulation_log") => "simulation-log",
+                _ => "simulation-progress",
+            };
+            let _ = app.emit(event, message);
+        }
+    });
+
+    let mut response = simulation::execute(&request, state, &progress_tx).await;
+    response.replay_of = request.replay_of.clone();
+    drop(progress_tx);
+    let _ = forwarder.await;
+    // The agent window is the client of a local simulation
+    simulation::report_finished(state, &response, true);
+
+    *state.local_simulation.write().await = None;
+    Ok(response)
 }
 
-#[derive(Serialize)]
-struct AgentStatus {
-    ltspice_path: Option<String>,
-    ltspice_available: bool,
-    ngspice_path: Option<String>,
-    ngspice_available: bool,
-    is_simulating: bool,
-    ws_connections: u32,
-    simulation_count: u32,
-    last_simulation_time: Option<u64>,
-    ws_port: u16,
-    version: String,
-}
-
-#[tauri::command]
-async fn get_agent_status(state: State<'_, Arc<AppState>>) -> Result<AgentStatus, String> {
-    let ltspice_path = state.ltspice_path.read().await.clone();
-    let ngspice_path = state.ngspice_path.read().await.clone();
-    let is_simulating = *state.is_simulating.read().await;
-    let ws_connections = *state.ws_connections.read().await;
-    let simulation_count = *state.simulation_count.read().await;
-    let last_simulation_time = *state.last_simulation_time.read().await;
-
-    Ok(AgentStatus {
-        ltspice_available: ltspice_path.is_some(),
-        ltspice_path,
-        ngspice_available: ngspice_path.is_some(),
-        ngspice_path,
-        is_simulating,
-        ws_connections,
-        simulation_count,
-        last_simulation_time,
-        ws_port: protocol::WS_PORT,
-        version: protocol::AGENT_VERSION.to_string(),
-    })
+/// Cancel the simulation started with `run_local_simulation`
+/// Returns false if no local simulation is running
+#[tauri::command]
+async fn cancel_local_simulation(state: State<'_, Arc<AppState>>) -> Result<bool, String> {
+    let Some(request_id) = state.local_simulation.read().await.clone() else {
+        return Ok(false);
+    };
+    Ok(simulation::cancel(&request_id, &state).await.success())
+}
+
+/// Write the last simulation results to a CSV file
+/// Shows a save dialog when no path is given; returns the written path, or None if the dialog was cancelled
+#[tauri::command]
+async fn export_results_csv(
+    path: Option<String>,
+    app: tauri::AppHandle,
+    state: State<'_, Arc<AppState>>,
+) -> Result<Option<String>, String> {
+    let Some(results) = state.last_results.write().await.get() else {
+        return Err("No simulation results to export".to_string());
+    };
+
+    let path = match path {
+        Some(path) => std::path::PathBuf::from(path),
+        None => {
+            let (tx, rx) = tokio::sync::oneshot::channel();
+            app.dialog()
+                .file()
+                .add_filter("CSV", &["csv"])
+                .set_file_name("simulation-results.csv")
+                .save_file(move |file| {
+                    let _ = tx.send(file);
+                });
+            match rx.await.ok().flatten() {
+                Some(file) => file.into_path().map_err(|e| e.to_string())?,
+                None => return Ok(None),
+            }
+        }
+    };
+
+    let target = path.clone();
+    tokio::task::spawn_blocking(move || export::write_csv_file(&results, &target))
+        .await
+        .map_err(|e| e.to_string())?
+        .map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+
+    log::info!("Exported simulation results to {}", path.display());
+    Ok(Some(path.to_string_lossy().to_string()))
+}
+
+/// Save a raw file kept by a simulation that set `keepRaw`, e.g. to open it in LTspice's waveform viewer
+/// Shows a save dialog when no path is given; returns the written path, or None if the dialog was cancelled
+#[tauri::command]
+async fn export_raw_file(
+    artifact_id: String,
+    path: Option<String>,
+    app: tauri::AppHandle,
+    state: State<'_, Arc<AppState>>,
+) -> Result<Option<String>, String> {
+    let Some(artifact) = state.artifacts.write().await.get(&artifact_id) else {
+        return Err(format!("No kept raw file with id {}", artifact_id));
+    };
+
+    let path = match path {
+        Some(path) => std::path::PathBuf::from(path),
+        None => {
+            let (tx, rx) = tokio::sync::oneshot::channel();
+            app.dialog()
+                .file()
+                .add_filter("Raw waveform", &["raw"])
+                .set_file_name(artifact.file_name())
+                .save_file(move |file| {
+                    let _ = tx.send(file);
+                });
+            match rx.await.ok().flatten() {
+                Some(file) => file.into_path().map_err(|e| e.to_string())?,
+                None => return Ok(None),
+            }
+        }
+    };
+
+    let target = path.clone();
+    tokio::task::spawn_blocking(move || std::fs::copy(&artifact.path, &target))
+        .await
+        .map_err(|e| e.to_string())?
+        .map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+
+    log::info!("Saved raw file {} to {}", artifact_id, path.display());
+    Ok(Some(path.to_string_lossy().to_string()))
+}
+
+/// Change the directory simulations make their temp directories in and persist it
+/// Shows a folder picker when no path is given; `use_system_default` goes back to the system temp dir.
+/// A folder that doesn't exist, can't be written or is short of space is refused. Returns the
+/// directory now in use, or None if the picker was cancelled
+#[tauri::command]
+async fn choose_temp_dir(
+    path: Option<String>,
+    use_system_default: Option<bool>,
+    app: tauri::AppHandle,
+    state: State<'_, Arc<AppState>>,
+) -> Result<Option<String>, String> {
+    let min_free_bytes = state.settings.read().await.min_free_disk_mb.saturating_mul(1024 * 1024);
+    let chosen = if use_system_default.unwrap_or(false) {
+        None
+    } else {
+        let path = match path {
+            Some(path) => std::path::PathBuf::from(path),
+            None => {
+                let (tx, rx) = tokio::sync::oneshot::channel();
+                app.dialog()
+                    .file()
+                    .set_directory(state.temp_root.read().await.path.clone())
+                    .pick_folder(move |folder| {
+                        let _ = tx.send(folder);
+                    });
+                match rx.await.ok().flatten() {
+                    Some(folder) => folder.into_path().map_err(|e| e.to_string())?,
+                    None => return Ok(None),
+                }
+            }
+        };
+        let checked = path.clone();
+        tokio::task::spawn_blocking(move || temp_root::check(&checked, min_free_bytes))
+            .await
+            .map_err(|e| e.to_string())??;
+        Some(path.to_string_lossy().to_string())
+    };
+
+    let updated = {
+        let mut settings = state.settings.write().await;
+        settings.temp_dir_override = chosen.clone();
+        settings.clone()
+    };
+    settings::save_settings(&updated)?;
+    let root = match chosen {
+        Some(dir) => temp_root::TempRoot { path: dir.into(), override_error: None },
+        None => temp_root::TempRoot::default(),
+    };
+    log::info!("Simulations will use temp directory {}", root.path.display());
+    let effective = root.path.to_string_lossy().to_string();
+    *state.temp_root.write().await = root;
+    Ok(Some(effective))
+}
+
+/// Bring the main window to the front
+fn show_main_window(app: &AppHandle) {
+    if let Some(window) = app.get_webview_window(window::MAIN_WINDOW) {
+        let _ = window.show();
+        let _ = window.set_focus();
+    }
+}
+
+/// Run the shutdown sequence in the background, then exit for real
+fn quit_gracefully(app: &AppHandle) {
+    let app = app.clone();
+    tauri::async_runtime::spawn(async move {
+        let state = app.state::<Arc<AppState>>().inner().clone();
+        window::save_geometry(&app).await;
+        // Only the first request exits; later ones wait for it
+        if shutdown::shutdown(&state, shutdown::SHUTDOWN_GRACE).await {
+            app.exit(0);
+        }
+    });
 }
 
 fn main() {
-    env_logger::init();
+    let options = match cli::parse(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("kelicad-agent: {}", e);
+            std::process::exit(headless::EXIT_USAGE);
+        }
+    };
 
-    let app_state = Arc::new(AppState::default());
+    // Headless runs always log to stderr for CI output; the GUI only in debug builds
+    logging::init(logging::level_from_env(), options.headless || cfg!(debug_assertions));
+    if options.headless {
+        std::process::exit(headless::run(options));
+    }
+
+    let settings = settings::load_settings();
+    let notifications_enabled = settings.notifications_enabled;
+    let autostart_enabled = settings.autostart;
+    let start_hidden = settings.start_hidden;
+    // Checked before the window is shown: login launches stay in the tray
+    let start_minimized = options.minimized || settings.start_hidden;
+    let window_geometry = settings.window_geometry;
+    let mut app_state = AppState::new(settings);
+    app_state.bundled_libraries = catalog::load();
+    *app_state.artifacts.get_mut() = artifacts::Artifacts::open_default(app_state.settings.get_mut().max_artifact_bytes);
+    app_state.status_file = status_file::default_path().filter(|path| status_file::claim(path));
+    let app_state = Arc::new(app_state);
     let ws_state = app_state.clone();
 
     tauri::Builder::default()
+        // Must be registered first: a second launch focuses this instance and exits
+        .plugin(tauri_plugin_single_instance::init(|app, _args, _cwd| {
+            log::info!("Another launch was redirected to the running agent");
+            show_main_window(app);
+        }))
         .plugin(tauri_plugin_shell::init())
+        .plugin(tauri_plugin_dialog::init())
+        .plugin(tauri_plugin_clipboard_manager::init())
+        .plugin(tauri_plugin_notification::init())
+        .plugin(tauri_plugin_autostart::init(
+            tauri_plugin_autostart::MacosLauncher::LaunchAgent,
+            Some(vec![cli::MINIMIZED_FLAG]),
+        ))
         .manage(app_state.clone())
-        .invoke_handler(tauri::generate_handler![get_agent_status])
+        .invoke_handler(tauri::generate_handler![
+            get_agent_status,
+            get_settings,
+            update_settings,
+            force_reset,
+            get_autostart,
+            set_autostart,
+            run_local_simulation,
+            cancel_local_simulation,
+            export_results_csv,
+            export_raw_file,
+            choose_temp_dir,
+            get_recent_logs,
+            open_log_folder,
+            set_log_level,
+            reset_metrics,
+            redetect_simulators,
+            initialize_ltspice,
+            get_onboarding_state,
+            run_self_test,
+            set_max_simulation_time,
+            get_bundled_library_content,
+            rerun_simulation,
+            get_simulation_history,
+            get_resource_usage,
+            get_quality_profiles,
+            set_quality_profile
+        ])
         .setup(move |app| {
+            // The window is created hidden so a login launch never flashes it
+            window::restore_geometry(app.handle(), window_geometry);
+            if start_minimized {
+                log::info!("Started minimized to the tray");
+            } else {
+                show_main_window(app.handle());
+            }
+
+            // Subscribe before detection starts so the tray sees its result
+            let status_events = app_state.subscribe_status();
+
+            tauri::async_runtime::spawn(notifications::run_notifications(
+                app.handle().clone(),
+                app_state.clone(),
+                app_state.subscribe_status(),
+            ));
+
+            // Keep the status file for local tools current until shutdown removes it
+            if let Some(path) = app_state.status_file.clone() {
+                tauri::async_runtime::spawn(status_file::run(app_state.clone(), path, app_state.subscribe_status()));
+            }
+
             // Detect simulators on startup
             let state = app_state.clone();
             tauri::async_runtime::spawn(async move {
-                // Detect LTspice
-                if let Some(path) = simulator::detect_ltspice() {
-                    let mut ltspice_path = state.ltspice_path.write().await;
-                    *ltspice_path = Some(path.clone());
-                    log::info!("LTspice detected at: {}", path);
-                } else {
-                    log::warn!("LTspice not found");
-                }
+                let ltspice = simulator::detect_ltspice();
+                let ngspice = simulator::detect_ngspice();
 
-                // Detect ngspice
-                if let Some(path) = simulator::detect_ngspice() {
-                    let mut ngspice_path = state.ngspice_path.write().await;
-                    *ngspice_path = Some(path.clone());
-                    log::info!("ngspice detected at: {}", path);
-                } else {
-                    log::warn!("ngspice not found");
-                }
+                // Clean up processes from a previous run before the paths are published,
+                // so no simulation of ours can be running yet
+                let known: Vec<String> = ltspice.iter().chain(ngspice.iter()).cloned().collect();
+                let killed = tauri::async_runtime::spawn_blocking(move || simulator::kill_orphaned_simulators(&known))
+                    .await
+                    .map(|pids| pids.len() as u32)
+                    .unwrap_or(0);
+                *state.orphaned_processes_killed.write().await = killed;
+                detection::apply(&state, ltspice, ngspice).await;
+
+                // Notice simulators installed or removed while the agent runs
+                detection::run_periodic(state, detection::REDETECT_INTERVAL).await;
             });
 
             // Start WebSocket server
             let ws_state_clone = ws_state.clone();
             tauri::async_runtime::spawn(async move {
-                if let Err(e) = websocket::start_server(ws_state_clone).await {
-                    log::error!("WebSocket server error: {}", e);
+                if let Err(e) = websocket::start_server(ws_state_clone.clone(), protocol::WS_PORT).await {
+                    let message = websocket::describe_start_error(&*e, protocol::WS_PORT).await;
+                    log::error!("WebSocket server error: {} ({})", message, e);
+                    *ws_state_clone.server_error.write().await = Some(message);
+                    ws_state_clone.notify_status(StatusEvent::Changed);
                 }
             });
 
             // Create tray menu
             let quit = MenuItem::with_id(app, "quit", "Quit KeliCAD Agent", true, None::<&str>)?;
-            let status = MenuItem::with_id(app, "status", "Status: Ready", false, None::<&str>)?;
-            let menu = Menu::with_items(app, &[&status, &quit])?;
+            let status = MenuItem::with_id(app, "status", "Starting...", false, None::<&str>)?;
+            let open_status = MenuItem::with_id(app, "open_status", "Open status window", true, None::<&str>)?;
+            let copy_url = MenuItem::with_id(app, "copy_ws_url", "Copy WebSocket URL", true, None::<&str>)?;
+            let notifications = CheckMenuItem::with_id(
+                app,
+                "toggle_notifications",
+                "Notify when simulations finish",
+                true,
+                notifications_enabled,
+                None::<&str>,
+            )?;
+            let autostart = CheckMenuItem::with_id(
+                app,
+                "toggle_autostart",
+                "Start at login",
+                true,
+                autostart_enabled,
+                None::<&str>,
+            )?;
+            let show_on_launch = CheckMenuItem::with_id(
+                app,
+                "toggle_show_on_launch",
+                "Show window on launch",
+                true,
+                !start_hidden,
+                None::<&str>,
+            )?;
+            let open_logs = MenuItem::with_id(app, "open_logs", "Open log folder", true, None::<&str>)?;
+            let separator = PredefinedMenuItem::separator(app)?;
+            let menu = Menu::with_items(
+                app,
+                &[
+                    &status,
+                    &open_status,
+                    &copy_url,
+                    &notifications,
+                    &autostart,
+                    &show_on_launch,
+                    &open_logs,
+                    &separator,
+                    &quit,
+                ],
+            )?;
+            app.manage(tray::TrayMenu { notifications, autostart, show_on_launch });
 
             // Create tray icon - use default icon if available, otherwise skip tray icon setup
             let mut tray_builder = TrayIconBuilder::new()
                 .menu(&menu)
                 .show_menu_on_left_click(true)
-                .on_menu_event(|app, event| {
-                    if event.id.as_ref() == "quit" {
-                        app.exit(0);
+                .on_menu_event(|app, event| match event.id.as_ref() {
+                    "quit" => quit_gracefully(app),
+                    "open_status" => show_main_window(app),
+                    "toggle_notifications" => tray::toggle_notifications(app),
+                    "toggle_autostart" => tray::toggle_autostart(app),
+                    "toggle_show_on_launch" => tray::toggle_show_on_launch(app),
+                    "open_logs" => {
+                        if let Err(e) = logging::open_log_folder() {
+                            log::warn!("{}", e);
+                        }
                     }
+                    "copy_ws_url" => {
+                        let url = format!("ws://127.0.0.1:{}", protocol::WS_PORT);
+                        if let Err(e) = app.clipboard().write_text(url) {
+                            log::warn!("Failed to copy WebSocket URL: {}", e);
+                        }
+                    }
+                    _ => {}
                 })
                 .on_tray_icon_event(|tray, event| {
                     if let TrayIconEvent::Click {
@@ -145,11 +762,7 @@ fn main() {
                         ..
                     } = event
                     {
-                        let app = tray.app_handle();
-                        if let Some(window) = app.get_webview_window("main") {
-                            let _ = window.show();
-                            let _ = window.set_focus();
-                        }
+                        show_main_window(tray.app_handle());
                     }
                 });
 
@@ -158,17 +771,37 @@ fn main() {
                 tray_builder = tray_builder.icon(icon.clone());
             }
 
-            let _tray = tray_builder.build(app)?;
+            let tray = tray_builder.build(app)?;
+
+            // Keep the status line and icon in sync with the agent state
+            tauri::async_runtime::spawn(tray::run_status_updates(app_state.clone(), status, tray, status_events));
 
             log::info!("KeliCAD Agent started on port 9347");
             Ok(())
         })
+        // Closing the window hides it; the agent keeps serving until quit from the tray
+        .on_window_event(|window, event| {
+            if let tauri::WindowEvent::CloseRequested { api, .. } = event {
+                if let Some(window) = window.app_handle().get_webview_window(window.label()) {
+                    api.prevent_close();
+                    window::hide_to_tray(&window);
+                }
+            }
+        })
         .build(tauri::generate_context!())
         .expect("error while building tauri application")
-        .run(|_app_handle, event| {
-            if let RunEvent::ExitRequested { api, .. } = event {
-                // Keep running in background when window is closed
+        .run(|app_handle, event| {
+            if let RunEvent::ExitRequested { code, api, .. } = event {
+                // The exit issued by quit_gracefully once cleanup is done
+                if app_handle.state::<Arc<AppState>>().is_shutdown_complete() {
+                    return;
+                }
                 api.prevent_exit();
+                // Keep running in background when window is closed; any other exit
+                // request (app menu quit, OS logout or shutdown) cleans up first
+                if code.is_some() || !app_handle.webview_windows().is_empty() {
+                    quit_gracefully(app_handle);
+                }
             }
         });
 }
@@ -0,0 +1,270 @@
+// Copyright (c) 2024-2025 Wanyeki Technologies LLC. All rights reserved.
+// This source code is licensed under the proprietary license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Simulation health counters behind `get_metrics` and the agent status
+
+use std::collections::{BTreeMap, VecDeque};
+use std::time::Instant;
+
+use crate::protocol::{now_ms, AgentMetrics, OriginMetrics, SimulatorMetrics};
+
+/// Number of recent simulations the execution time statistics are taken over
+pub const EXECUTION_TIME_WINDOW: usize = 100;
+
+/// How a simulation that held a worker slot ended
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Outcome {
+    Succeeded,
+    Failed,
+    Cancelled,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct Counts {
+    succeeded: u64,
+    failed: u64,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct OriginCounts {
+    started: u64,
+    failed: u64,
+    rate_limited: u64,
+    bytes_returned: u64,
+    last_activity: u64,
+}
+
+/// Counters since agent start or the last reset
+#[derive(Debug)]
+pub struct Metrics {
+    started_at: Instant,
+    since: u64,
+    simulators: BTreeMap<String, Counts>,
+    cancelled: u64,
+    /// Execution times of the most recent succeeded or failed simulations, oldest first
+    execution_times: VecDeque<u64>,
+    result_bytes_served: u64,
+    progress_messages_dropped: u64,
+    origins: BTreeMap<String, OriginCounts>,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self {
+            started_at: Instant::now(),
+            since: now_ms(),
+            simulators: BTreeMap::new(),
+            cancelled: 0,
+            execution_times: VecDeque::with_capacity(EXECUTION_TIME_WINDOW),
+            result_bytes_served: 0,
+            progress_messages_dropped: 0,
+            origins: BTreeMap::new(),
+        }
+    }
+}
+
+impl Metrics {
+    /// Record a simulation that ran; cancelled runs do not count towards execution times
+    pub fn record(&mut self, simulator: &str, outcome: Outcome, execution_ms: u64) {
+        let counts = self.simulators.entry(simulator.to_string()).or_default();
+        match outcome {
+            Outcome::Succeeded => counts.succeeded += 1,
+            Outcome::Failed => counts.failed += 1,
+            Outcome::Cancelled => {
+                self.cancelled += 1;
+                return;
+            }
+        }
+
+        if self.execution_times.len() == EXECUTION_TIME_WINDOW {
+            self.execution_times.pop_front();
+        }
+        self.execution_times.push_back(execution_ms);
+    }
+
+    pub fn record_bytes_served(&mut self, bytes: usize) {
+        self.result_bytes_served += bytes as u64;
+    }
+
+    pub fn record_progress_dropped(&mut self, messages: usize) {
+        self.progress_messages_dropped += messages as u64;
+    }
+
+    /// Counters of `origin`, marked active now
+    fn origin(&mut self, origin: &str) -> &mut OriginCounts {
+        let counts = self.origins.entry(origin.to_string()).or_default();
+        counts.last_activity = now_ms();
+        counts
+    }
+
+    pub fn record_origin_started(&mut self, origin: &str) {
+        self.origin(origin).started += 1;
+    }
+
+    pub fn record_origin_rate_limited(&mut self, origin: &str) {
+        self.origin(origin).rate_limited += 1;
+    }
+
+    /// Record how a simulation `origin` started ended and the size of the result sent
+    pub fn record_origin_finished(&mut self, origin: &str, success: bool, bytes: usize) {
+        let counts = self.origin(origin);
+        if !success {
+            counts.failed += 1;
+        }
+        counts.bytes_returned += bytes as u64;
+    }
+
+    /// Clear every counter; uptime keeps counting from agent start
+    pub fn reset(&mut self) {
+        *self = Self {
+            started_at: self.started_at,
+            ..Self::default()
+        };
+    }
+
+    pub fn snapshot(&self) -> AgentMetrics {
+        let (succeeded, failed) = self
+            .simulators
+            .values()
+            .fold((0, 0), |(s, f), counts| (s + counts.succeeded, f + counts.failed));
+        let finished = succeeded + failed;
+
+        let mut sorted: Vec<u64> = self.execution_times.iter().copied().collect();
+        sorted.sort_unstable();
+        let average_execution_ms = (!sorted.is_empty()).then(|| sorted.iter().sum::<u64>() / sorted.len() as u64);
+
+        AgentMetrics {
+            uptime_secs: self.started_at.elapsed().as_secs(),
+            since: self.since,
+            simulators: self
+                .simulators
+                .iter()
+                .map(|(simulator, counts)| SimulatorMetrics {
+                    simulator: simulator.clone(),
+                    succeeded: counts.succeeded,
+                    failed: counts.failed,
+                })
+                .collect(),
+            cancelled: self.cancelled,
+            failure_rate: if finished == 0 { 0.0 } else { failed as f64 / finished as f64 },
+            average_execution_ms,
+            p95_execution_ms: percentile(&sorted, 95),
+            result_bytes_served: self.result_bytes_served,
+            progress_messages_dropped: self.progress_messages_dropped,
+            origins: self
+                .origins
+                .iter()
+                .map(|(origin, counts)| OriginMetrics {
+                    origin: origin.clone(),
+                    simulations_started: counts.started,
+                    simulations_failed: counts.failed,
+                    rate_limited: counts.rate_limited,
+                    bytes_returned: counts.bytes_returned,
+                    last_activity: counts.last_activity,
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Nearest-rank percentile of sorted values
+fn percentile(sorted: &[u64], percent: usize) -> Option<u64> {
+    if sorted.is_empty() {
+        return None;
+    }
+    let rank = (sorted.len() * percent).div_ceil(100).max(1);
+    Some(sorted[rank - 1])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_counts_and_failure_rate() {
+        let mut metrics = Metrics::default();
+        metrics.record("ngspice", Outcome::Succeeded, 100);
+        metrics.record("ngspice", Outcome::Failed, 300);
+        metrics.record("LTspice", Outcome::Succeeded, 200);
+        metrics.record("LTspice", Outcome::Succeeded, 400);
+        metrics.record("LTspice", Outcome::Cancelled, 9000);
+        metrics.record_bytes_served(1024);
+        metrics.record_progress_dropped(3);
+
+        let snapshot = metrics.snapshot();
+        assert_eq!(
+            snapshot.simulators,
+            vec![
+                SimulatorMetrics { simulator: "LTspice".to_string(), succeeded: 2, failed: 0 },
+                SimulatorMetrics { simulator: "ngspice".to_string(), succeeded: 1, failed: 1 },
+            ]
+        );
+        assert_eq!(snapshot.cancelled, 1);
+        assert_eq!(snapshot.failure_rate, 0.25);
+        // The cancelled run's time is left out
+        assert_eq!(snapshot.average_execution_ms, Some(250));
+        assert_eq!(snapshot.p95_execution_ms, Some(400));
+        assert_eq!(snapshot.result_bytes_served, 1024);
+        assert_eq!(snapshot.progress_messages_dropped, 3);
+    }
+
+    #[test]
+    fn test_origin_counts() {
+        let mut metrics = Metrics::default();
+        metrics.record_origin_started("https://kelicad.com");
+        metrics.record_origin_started("https://kelicad.com");
+        metrics.record_origin_finished("https://kelicad.com", true, 2048);
+        metrics.record_origin_finished("https://kelicad.com", false, 100);
+        metrics.record_origin_rate_limited("http://localhost:3000");
+
+        let origins = metrics.snapshot().origins;
+        assert_eq!(origins.len(), 2);
+        assert_eq!(origins[0].origin, "http://localhost:3000");
+        assert_eq!(origins[0].rate_limited, 1);
+        assert_eq!(origins[0].simulations_started, 0);
+        assert_eq!(origins[1].simulations_started, 2);
+        assert_eq!(origins[1].simulations_failed, 1);
+        assert_eq!(origins[1].bytes_returned, 2148);
+        assert!(origins[1].last_activity > 0);
+
+        metrics.reset();
+        assert!(metrics.snapshot().origins.is_empty());
+    }
+
+    #[test]
+    fn test_execution_time_window() {
+        let mut metrics = Metrics::default();
+        for ms in 1..=EXECUTION_TIME_WINDOW as u64 + 20 {
+            metrics.record("ngspice", Outcome::Succeeded, ms);
+        }
+
+        // Only 21..=120 remain
+        let snapshot = metrics.snapshot();
+        assert_eq!(snapshot.average_execution_ms, Some(70));
+        assert_eq!(snapshot.p95_execution_ms, Some(115));
+        assert_eq!(snapshot.simulators[0].succeeded, 120);
+    }
+
+    #[test]
+    fn test_reset() {
+        let mut metrics = Metrics::default();
+        metrics.record("ngspice", Outcome::Failed, 100);
+        metrics.record_bytes_served(10);
+        metrics.reset();
+
+        let snapshot = metrics.snapshot();
+        assert!(snapshot.simulators.is_empty());
+        assert_eq!(snapshot.failure_rate, 0.0);
+        assert_eq!(snapshot.average_execution_ms, None);
+        assert_eq!(snapshot.p95_execution_ms, None);
+        assert_eq!(snapshot.result_bytes_served, 0);
+    }
+
+    #[test]
+    fn test_percentile() {
+        assert_eq!(percentile(&[], 95), None);
+        assert_eq!(percentile(&[7], 95), Some(7));
+        assert_eq!(percentile(&[1, 2, 3, 4], 50), Some(2));
+    }
+}
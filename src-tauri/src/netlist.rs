@@ -0,0 +1,618 @@
+// Copyright (c) 2024-2025 Wanyeki Technologies LLC. All rights reserved.
+// This source code is licensed under the proprietary license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! SPICE netlist inspection without running a simulator: analysis directives, nodes and
+//! component reference designators
+//!
+//! Comment lines, inline comments (`;`, ` $`) and `.subckt` bodies are skipped, `+` continuation
+//! lines are joined, and nothing after `.end` is read. Values are kept as written (`10m`, `100k`).
+
+use regex::Regex;
+
+use std::collections::BTreeMap;
+
+use crate::protocol::{NetlistAnalysis, NetlistInventory};
+
+/// Analysis directives recognized in a netlist (and without the dot in a `.control` section)
+pub const ANALYSIS_DIRECTIVES: &[&str] = &[".tran", ".ac", ".dc", ".op", ".noise", ".tf", ".sens", ".pz"];
+
+/// Directives naming the output variables the netlist's author wanted to see
+pub const PLOT_DIRECTIVES: &[&str] = &[".plot", ".probe"];
+
+/// Output functions of a node voltage or branch current, such as the `db` of `vdb(out)`
+const OUTPUT_FUNCTIONS: &[&str] = &["db", "m", "p", "r", "i"];
+
+/// What a netlist contains, in order of first appearance
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct NetlistSummary {
+    pub analyses: Vec<NetlistAnalysis>,
+    /// Top-level node names, including ground (`0`)
+    pub nodes: Vec<String>,
+    /// Top-level component reference designators
+    pub components: Vec<String>,
+    /// Voltage or current controlled switches (`S`/`W` elements)
+    pub switches: usize,
+    /// Shortest period of the `PULSE` sources, in seconds
+    pub shortest_pulse_period: Option<f64>,
+    /// Output variables named in `.plot` and `.probe` directives, as written (e.g. `vdb(out)`)
+    pub plotted: Vec<String>,
+}
+
+impl NetlistSummary {
+    /// Element counts by type letter, nodes and subcircuit instances
+    pub fn inventory(&self) -> NetlistInventory {
+        let mut element_counts = BTreeMap::new();
+        let mut subcircuit_instances = Vec::new();
+        for component in &self.components {
+            let Some(kind) = component.chars().next().map(|c| c.to_ascii_uppercase()) else {
+                continue;
+            };
+            *element_counts.entry(kind.to_string()).or_insert(0) += 1;
+            if kind == 'X' {
+                subcircuit_instances.push(component.clone());
+            }
+        }
+        NetlistInventory {
+            element_counts,
+            component_count: self.components.len(),
+            nodes: self.nodes.clone(),
+            node_count: self.nodes.len(),
+            subcircuit_instances,
+        }
+    }
+}
+
+/// Tuning constants for `estimate_runtime_secs`
+#[derive(Debug, Clone, Copy)]
+pub struct RuntimeModel {
+    /// Solver time per node per time point
+    pub secs_per_node_point: f64,
+    /// Time points the solver needs per period of a switching source
+    pub points_per_switching_period: f64,
+    /// Extra cost per point in switching circuits, for rejected steps around the edges
+    pub switching_cost_factor: f64,
+    /// Points when the step is left to the simulator, which caps it at a fiftieth of the span
+    pub default_points: f64,
+}
+
+/// Model used for the long-run warning
+pub const RUNTIME_MODEL: RuntimeModel = RuntimeModel {
+    secs_per_node_point: 2e-6,
+    points_per_switching_period: 200.0,
+    switching_cost_factor: 3.0,
+    default_points: 50.0,
+};
+
+/// Parse the analyses, nodes and components of a netlist
+pub fn parse(netlist: &str) -> NetlistSummary {
+    let mut summary = NetlistSummary::default();
+    let mut subckt_depth = 0usize;
+    let mut in_control = false;
+
+    for line in logical_lines(netlist) {
+        let tokens = tokenize(&line);
+        let Some(first) = tokens.first() else {
+            continue;
+        };
+        let keyword = first.to_lowercase();
+
+        match keyword.as_str() {
+            ".subckt" => subckt_depth += 1,
+            ".ends" => subckt_depth = subckt_depth.saturating_sub(1),
+            ".control" => in_control = true,
+            ".endc" => in_control = false,
+            ".end" if subckt_depth == 0 && !in_control => break,
+            _ if in_control => {
+                // ngspice commands use the directive names without the dot
+                let directive = format!(".{}", keyword);
+                if ANALYSIS_DIRECTIVES.contains(&directive.as_str()) {
+                    summary.analyses.push(parse_analysis(&directive, &tokens[1..]));
+                }
+            }
+            _ if subckt_depth > 0 => {}
+            directive if directive.starts_with('.') => {
+                if ANALYSIS_DIRECTIVES.contains(&directive) {
+                    summary.analyses.push(parse_analysis(directive, &tokens[1..]));
+                } else if PLOT_DIRECTIVES.contains(&directive) {
+                    for variable in plotted_variables(&tokens[1..]) {
+                        push_unique(&mut summary.plotted, variable);
+                    }
+                }
+            }
+            _ => {
+                push_unique(&mut summary.components, first);
+                for node in element_nodes(&tokens) {
+                    push_unique(&mut summary.nodes, node);
+                }
+                match keyword.chars().next() {
+                    Some('s' | 'w') => summary.switches += 1,
+                    Some('v' | 'i') => {
+                        if let Some(period) = pulse_period(&tokens) {
+                            let shortest = summary.shortest_pulse_period.map_or(period, |p| p.min(period));
+                            summary.shortest_pulse_period = Some(shortest);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    summary
+}
+
+/// Output variables among the arguments of a `.plot` or `.probe`
+/// Skips the analysis name (`.plot tran v(out)`), ngspice's plot limits (`(0,5)`) and LTspice's
+/// wildcards (`.probe I(*)`)
+fn plotted_variables(args: &[String]) -> impl Iterator<Item = &str> {
+    args.iter().map(String::as_str).filter(|arg| {
+        let is_variable = arg.find('(').is_some_and(|open| open > 0) && arg.ends_with(')');
+        is_variable && !arg.contains('*')
+    })
+}
+
+/// The node voltage or branch current an output variable is taken of, lowercase: `v(out)` for
+/// `vdb(out)`, `i(r1)` for `IM(R1)`; other variables are returned as they are, lowercase
+pub fn plotted_signal(variable: &str) -> String {
+    let variable = variable.to_lowercase();
+    let Some(open) = variable.find('(') else {
+        return variable;
+    };
+    match variable[..open].split_at_checked(1) {
+        Some((kind @ ("v" | "i"), modifier)) if OUTPUT_FUNCTIONS.contains(&modifier) => {
+            format!("{}{}", kind, &variable[open..])
+        }
+        _ => variable,
+    }
+}
+
+/// Whether the netlist runs at least one analysis
+pub fn has_analysis(netlist: &str) -> bool {
+    !parse(netlist).analyses.is_empty()
+}
+
+/// Rough wall-clock time of the netlist's first transient analysis, in seconds
+/// None when there is no `.tran` or its stop time cannot be read
+pub fn estimate_runtime_secs(netlist: &str) -> Option<f64> {
+    estimate_runtime_secs_with(&parse(netlist), &RUNTIME_MODEL)
+}
+
+/// Points (from the step, or the switching period when that needs more) x nodes x cost per point
+pub fn estimate_runtime_secs_with(summary: &NetlistSummary, model: &RuntimeModel) -> Option<f64> {
+    let (step, stop, start, max_step) = summary.analyses.iter().find_map(|analysis| match analysis {
+        NetlistAnalysis::Tran { step, stop, start, max_step } => Some((step, stop, start, max_step)),
+        _ => None,
+    })?;
+    let value = |v: &Option<String>| v.as_deref().and_then(parse_spice_number);
+    let span = value(stop)? - value(start).unwrap_or(0.0);
+    if span <= 0.0 {
+        return None;
+    }
+
+    // A zero or missing step leaves the choice to the simulator
+    let step_points = [value(step), value(max_step)]
+        .into_iter()
+        .flatten()
+        .filter(|v| *v > 0.0)
+        .fold(None, |smallest: Option<f64>, v| Some(smallest.map_or(v, |s| s.min(v))))
+        .map_or(model.default_points, |step| span / step);
+    let switching_points = summary
+        .shortest_pulse_period
+        .filter(|period| *period > 0.0)
+        .map_or(0.0, |period| span / period * model.points_per_switching_period);
+
+    let switching = summary.switches > 0 || summary.shortest_pulse_period.is_some();
+    let cost_factor = if switching { model.switching_cost_factor } else { 1.0 };
+    let nodes = summary.nodes.len().max(1) as f64;
+    Some(step_points.max(switching_points) * nodes * model.secs_per_node_point * cost_factor)
+}
+
+/// Period of a `PULSE(v1 v2 td tr tf pw per)` source
+fn pulse_period(tokens: &[String]) -> Option<f64> {
+    let index = tokens.iter().position(|t| t.to_lowercase().starts_with("pulse"))?;
+    let inline = tokens[index][5..].trim();
+    let args = if inline.is_empty() { tokens.get(index + 1)?.as_str() } else { inline };
+    let args = args.trim_start_matches('(').trim_end_matches(')');
+    args.split(|c: char| c.is_whitespace() || c == ',')
+        .filter(|a| !a.is_empty())
+        .nth(6)
+        .and_then(parse_spice_number)
+}
+
+/// Parse a SPICE number with an optional scale suffix, e.g. `10u`, `1.5meg`, `2ms`
+pub fn parse_spice_number(token: &str) -> Option<f64> {
+    let re = Regex::new(r"^([+-]?(?:\d+\.?\d*|\.\d+)(?:e[+-]?\d+)?)(meg|mil|[tgkmunpf])?[a-z]*$").ok()?;
+    let token = token.to_lowercase();
+    let caps = re.captures(&token)?;
+    let value: f64 = caps[1].parse().ok()?;
+    let scale = match caps.get(2).map(|m| m.as_str()) {
+        Some("t") => 1e12,
+        Some("g") => 1e9,
+        Some("meg") => 1e6,
+        Some("k") => 1e3,
+        Some("m") => 1e-3,
+        Some("mil") => 25.4e-6,
+        Some("u") => 1e-6,
+        Some("n") => 1e-9,
+        Some("p") => 1e-12,
+        Some("f") => 1e-15,
+        _ => 1.0,
+    };
+    Some(value * scale)
+}
+
+/// Lines with comments removed and `+` continuations joined onto the line they continue
+fn logical_lines(netlist: &str) -> Vec<String> {
+    let mut lines: Vec<String> = Vec::new();
+    for raw in netlist.lines() {
+        let line = strip_inline_comment(raw).trim();
+        if line.is_empty() || line.starts_with('*') {
+            continue;
+        }
+        match (line.strip_prefix('+'), lines.last_mut()) {
+            (Some(continuation), Some(previous)) => {
+                previous.push(' ');
+                previous.push_str(continuation.trim());
+            }
+            _ => lines.push(line.to_string()),
+        }
+    }
+    lines
+}
+
+/// Cut an LTspice `;` or ngspice ` $` comment from the end of a line
+fn strip_inline_comment(line: &str) -> &str {
+    let end = line
+        .char_indices()
+        .find(|&(i, c)| c == ';' || (c == '$' && (i == 0 || line[..i].ends_with(char::is_whitespace))))
+        .map_or(line.len(), |(i, _)| i);
+    &line[..end]
+}
+
+/// Split on whitespace, keeping `v(out, 0)` and `{a * b}` together
+fn tokenize(line: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut current = String::new();
+    let mut depth = 0usize;
+    for c in line.chars() {
+        match c {
+            '(' | '{' => depth += 1,
+            ')' | '}' => depth = depth.saturating_sub(1),
+            _ => {}
+        }
+        if c.is_whitespace() && depth == 0 {
+            if !current.is_empty() {
+                tokens.push(std::mem::take(&mut current));
+            }
+        } else {
+            current.push(c);
+        }
+    }
+    if !current.is_empty() {
+        tokens.push(current);
+    }
+    tokens
+}
+
+fn push_unique(list: &mut Vec<String>, value: &str) {
+    if !list.iter().any(|existing| existing.eq_ignore_ascii_case(value)) {
+        list.push(value.to_string());
+    }
+}
+
+/// Node names of an element line, by the element type's node count
+fn element_nodes(tokens: &[String]) -> Vec<&str> {
+    let Some(kind) = tokens[0].chars().next().map(|c| c.to_ascii_uppercase()) else {
+        return Vec::new();
+    };
+    let args = &tokens[1..];
+    // Parameters, expressions and POLY(...) sources end the node list
+    let is_node = |token: &&String| !token.contains('=') && !token.contains('(') && !token.starts_with('{');
+
+    let count = match kind {
+        'R' | 'C' | 'L' | 'D' | 'V' | 'I' | 'B' | 'F' | 'H' | 'W' => 2,
+        'Q' | 'J' | 'Z' => 3,
+        'M' | 'E' | 'G' | 'S' | 'T' | 'O' => 4,
+        'X' => {
+            // Nodes, then the subcircuit name, then optional parameters
+            let positional = args
+                .iter()
+                .take_while(|token| is_node(token) && !token.eq_ignore_ascii_case("params:"))
+                .count();
+            positional.saturating_sub(1)
+        }
+        _ => 0,
+    };
+    args.iter().take(count).take_while(is_node).map(String::as_str).collect()
+}
+
+/// Build the analysis for a directive from its arguments
+fn parse_analysis(directive: &str, args: &[String]) -> NetlistAnalysis {
+    let arg = |i: usize| args.get(i).cloned();
+    match directive {
+        ".tran" => {
+            // Flags such as `uic` are not timing values
+            let values: Vec<&String> = args.iter().filter(|a| !is_tran_flag(a)).collect();
+            let value = |i: usize| values.get(i).map(|v| v.to_string());
+            if values.len() == 1 {
+                // LTspice shorthand: `.tran <stop>`
+                NetlistAnalysis::Tran { step: None, stop: value(0), start: None, max_step: None }
+            } else {
+                NetlistAnalysis::Tran { step: value(0), stop: value(1), start: value(2), max_step: value(3) }
+            }
+        }
+        ".ac" => {
+            let (sweep, points, fstart, fstop) = frequency_sweep(args);
+            NetlistAnalysis::Ac { sweep, points, fstart, fstop }
+        }
+        ".dc" => {
+            let sweep = args.first().filter(|a| is_sweep_type(a)).map(|a| a.to_lowercase());
+            let offset = usize::from(sweep.is_some());
+            NetlistAnalysis::Dc {
+                sweep,
+                source: arg(offset),
+                start: arg(offset + 1),
+                stop: arg(offset + 2),
+                step: arg(offset + 3),
+            }
+        }
+        ".noise" => {
+            let (sweep, points, fstart, fstop) = frequency_sweep(args.get(2..).unwrap_or_default());
+            NetlistAnalysis::Noise { output: arg(0), source: arg(1), sweep, points, fstart, fstop }
+        }
+        ".tf" => NetlistAnalysis::Tf { output: arg(0), source: arg(1) },
+        ".sens" => NetlistAnalysis::Sens { output: arg(0) },
+        ".op" => NetlistAnalysis::Op,
+        _ => NetlistAnalysis::Other {
+            directive: directive.trim_start_matches('.').to_string(),
+            args: args.to_vec(),
+        },
+    }
+}
+
+fn is_tran_flag(arg: &str) -> bool {
+    matches!(arg.to_lowercase().as_str(), "uic" | "startup" | "steady" | "nodiscard")
+}
+
+fn is_sweep_type(arg: &str) -> bool {
+    matches!(arg.to_lowercase().as_str(), "dec" | "oct" | "lin" | "list")
+}
+
+/// `dec 10 1 100k` as (sweep, points, fstart, fstop)
+fn frequency_sweep(args: &[String]) -> (Option<String>, Option<u32>, Option<String>, Option<String>) {
+    let sweep = args.first().map(|a| a.to_lowercase());
+    if sweep.as_deref() == Some("list") {
+        return (sweep, None, args.get(1).cloned(), args.last().filter(|_| args.len() > 2).cloned());
+    }
+    let points = args.get(1).and_then(|p| p.parse().ok());
+    (sweep, points, args.get(2).cloned(), args.get(3).cloned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn s(value: &str) -> Option<String> {
+        Some(value.to_string())
+    }
+
+    #[test]
+    fn test_tran_forms() {
+        let analyses = parse("* T\n.tran 1u 10m\n.end").analyses;
+        assert_eq!(
+            analyses,
+            vec![NetlistAnalysis::Tran { step: s("1u"), stop: s("10m"), start: None, max_step: None }]
+        );
+
+        let analyses = parse("* T\n.TRAN 0 10u 0 1n UIC\n.end").analyses;
+        assert_eq!(
+            analyses,
+            vec![NetlistAnalysis::Tran { step: s("0"), stop: s("10u"), start: s("0"), max_step: s("1n") }]
+        );
+
+        // LTspice accepts the stop time alone
+        let analyses = parse("* T\n.tran 5m\n.end").analyses;
+        assert_eq!(analyses, vec![NetlistAnalysis::Tran { step: None, stop: s("5m"), start: None, max_step: None }]);
+    }
+
+    #[test]
+    fn test_ac_dc_and_noise() {
+        let summary = parse(
+            "* T\n.ac dec 10 1 100k\n.dc V1 0 5 0.1\n.dc lin temp -40 85 5\n.noise V(out, 0) V1 oct 5 10 1meg\n.end",
+        );
+        assert_eq!(
+            summary.analyses,
+            vec![
+                NetlistAnalysis::Ac { sweep: s("dec"), points: Some(10), fstart: s("1"), fstop: s("100k") },
+                NetlistAnalysis::Dc { sweep: None, source: s("V1"), start: s("0"), stop: s("5"), step: s("0.1") },
+                NetlistAnalysis::Dc { sweep: s("lin"), source: s("temp"), start: s("-40"), stop: s("85"), step: s("5") },
+                NetlistAnalysis::Noise {
+                    output: s("V(out, 0)"),
+                    source: s("V1"),
+                    sweep: s("oct"),
+                    points: Some(5),
+                    fstart: s("10"),
+                    fstop: s("1meg"),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_ac_list() {
+        let analyses = parse(".ac list 1k 10k 100k\n.end").analyses;
+        assert_eq!(
+            analyses,
+            vec![NetlistAnalysis::Ac { sweep: s("list"), points: None, fstart: s("1k"), fstop: s("100k") }]
+        );
+    }
+
+    #[test]
+    fn test_op_tf_sens_and_other() {
+        let analyses = parse(".op\n.tf V(out) Vin\n.sens V(out)\n.pz in 0 out 0 vol pz\n.end").analyses;
+        assert_eq!(analyses[0], NetlistAnalysis::Op);
+        assert_eq!(analyses[1], NetlistAnalysis::Tf { output: s("V(out)"), source: s("Vin") });
+        assert_eq!(analyses[2], NetlistAnalysis::Sens { output: s("V(out)") });
+        let NetlistAnalysis::Other { directive, args } = &analyses[3] else {
+            panic!("expected other analysis");
+        };
+        assert_eq!(directive, "pz");
+        assert_eq!(args.len(), 6);
+    }
+
+    #[test]
+    fn test_skips_comments_subcircuits_and_after_end() {
+        let netlist = "* Title\n*.tran 1m\nR1 in out 1k ; .ac dec 10 1 1k\n.subckt amp a b\n.op\nR9 a b 1\n.ends amp\n.END\n.dc V1 0 1 0.1";
+        let summary = parse(netlist);
+        assert!(summary.analyses.is_empty());
+        assert_eq!(summary.components, vec!["R1"]);
+        assert!(!has_analysis(netlist));
+    }
+
+    #[test]
+    fn test_control_section_commands() {
+        let analyses = parse("V1 in 0 1\n.control\nset noaskquit\ntran 1u 1m\nrun\n.endc\n.end").analyses;
+        assert_eq!(analyses, vec![NetlistAnalysis::Tran { step: s("1u"), stop: s("1m"), start: None, max_step: None }]);
+        assert!(has_analysis("V1 in 0 1\n.control\nop\n.endc\n.end"));
+        assert!(!has_analysis("V1 in 0 1\n.options plotwinsize=0\n.temp 27\n.end"));
+    }
+
+    #[test]
+    fn test_plotted_variables() {
+        let netlist = "* Test\n\
+.plot tran v(out) v(in) (0,5)\n\
+.plot ac vdb(out) vp(out)\n\
+.probe V(out) I(R1) I(*)\n\
+.probe\n\
+.subckt buf a b\n.plot tran v(a)\n.ends\n\
+.tran 1m\n.end\n";
+        assert_eq!(parse(netlist).plotted, ["v(out)", "v(in)", "vdb(out)", "vp(out)", "I(R1)"]);
+        assert!(parse("* Test\n.tran 1m\n.end").plotted.is_empty());
+    }
+
+    #[test]
+    fn test_plotted_signal() {
+        assert_eq!(plotted_signal("vdb(out)"), "v(out)");
+        assert_eq!(plotted_signal("VP(out)"), "v(out)");
+        assert_eq!(plotted_signal("IM(R1)"), "i(r1)");
+        assert_eq!(plotted_signal("V(out)"), "v(out)");
+        assert_eq!(plotted_signal("ix(u1:1)"), "ix(u1:1)");
+        assert_eq!(plotted_signal("vgain(out)"), "vgain(out)");
+    }
+
+    #[test]
+    fn test_continuation_lines() {
+        let analyses = parse("* T\n.ac dec\n+ 20 1\n+ 1meg\n.end").analyses;
+        assert_eq!(
+            analyses,
+            vec![NetlistAnalysis::Ac { sweep: s("dec"), points: Some(20), fstart: s("1"), fstop: s("1meg") }]
+        );
+    }
+
+    #[test]
+    fn test_nodes_and_components() {
+        let netlist = "* Amp\n\
+            V1 VCC 0 12\n\
+            Vin in 0 AC 1\n\
+            R1 in base 10k\n\
+            C1 out 0 1u Rser=0.1\n\
+            Q1 vcc base emit 2N3904\n\
+            M1 d g 0 0 NMOS L=1u W=10u\n\
+            E1 buf 0 POLY(1) out 0 0 2\n\
+            G1 cur 0 value={V(out)*1m}\n\
+            X1 out fb VCC 0 opamp params: gain=100\n\
+            K1 L1 L2 0.99\n\
+            .tran 1m\n\
+            .end";
+        let summary = parse(netlist);
+        assert_eq!(summary.components, vec!["V1", "Vin", "R1", "C1", "Q1", "M1", "E1", "G1", "X1", "K1"]);
+        assert_eq!(
+            summary.nodes,
+            vec!["VCC", "0", "in", "base", "out", "emit", "d", "g", "buf", "cur", "fb"]
+        );
+    }
+
+    #[test]
+    fn test_inventory() {
+        let netlist = "* Buffer\n\
+            V1 vcc 0 5 ; supply\n\
+            * R9 vcc out 1k is commented out\n\
+            R1 in\n\
+            + mid 10k\n\
+            R2 mid out 1k $ ngspice comment\n\
+            C1 out 0\n\
+            +   100n\n\
+            XU1 mid out vcc 0 opamp\n\
+            xbuf out\n\
+            + buffered vcc 0 buffer params: gain=2\n\
+            .subckt buffer a b p n\n\
+            R3 a b 1\n\
+            .ends\n\
+            .tran 1m\n\
+            .end\n\
+            R4 after end 1";
+        let inventory = parse(netlist).inventory();
+        assert_eq!(
+            inventory.element_counts,
+            BTreeMap::from([
+                ("C".to_string(), 1),
+                ("R".to_string(), 2),
+                ("V".to_string(), 1),
+                ("X".to_string(), 2)
+            ])
+        );
+        assert_eq!(inventory.component_count, 6);
+        assert_eq!(inventory.nodes, vec!["vcc", "0", "in", "mid", "out", "buffered"]);
+        assert_eq!(inventory.node_count, 6);
+        assert_eq!(inventory.subcircuit_instances, vec!["XU1", "xbuf"]);
+
+        assert_eq!(parse("* Empty\n.end").inventory(), NetlistInventory::default());
+    }
+
+    #[test]
+    fn test_parse_spice_number() {
+        assert_eq!(parse_spice_number("10"), Some(10.0));
+        assert_eq!(parse_spice_number("1.5meg"), Some(1.5e6));
+        assert_eq!(parse_spice_number("2ms"), Some(2e-3));
+        assert_eq!(parse_spice_number("1e-3"), Some(1e-3));
+        assert!((parse_spice_number("10u").unwrap() - 10e-6).abs() < 1e-18);
+        assert_eq!(parse_spice_number("uic"), None);
+    }
+
+    #[test]
+    fn test_pulse_period_and_switches() {
+        let summary = parse("V1 g 0 PULSE(0 5 0 1n 1n 1u 2u)\nV2 h 0 pulse (0 1 0 1n 1n 5u 10u)\nS1 a b g 0 SW\n.end");
+        assert_eq!(summary.shortest_pulse_period, Some(2e-6));
+        assert_eq!(summary.switches, 1);
+        assert_eq!(parse("V1 a 0 PULSE(0 5 0 1n 1n 1u)\n.end").shortest_pulse_period, None);
+    }
+
+    /// Expected runtime ranges; tune `RUNTIME_MODEL` against these
+    #[test]
+    fn test_estimate_runtime_table() {
+        let rc = "V1 in 0 1\nR1 in out 1k\nC1 out 0 1u\n";
+        let buck = "V1 in 0 12\nV2 g 0 PULSE(0 10 0 10n 10n 1u 2u)\nM1 in g sw sw NMOS\nD1 0 sw DS\nL1 sw out 10u\nC1 out 0 100u\nR1 out 0 5\n";
+        let cases: &[(&str, String, f64, f64)] = &[
+            ("rc, fine step", format!("{}.tran 1u 10m\n.end", rc), 0.0, 1.0),
+            ("rc, automatic step", format!("{}.tran 0 10\n.end", rc), 0.0, 0.01),
+            ("rc, tiny max step", format!("{}.tran 0 1 0 1n\n.end", rc), 1_000.0, 100_000.0),
+            ("buck, 1 ms", format!("{}.tran 0 1m\n.end", buck), 0.1, 10.0),
+            ("buck, 10 s", format!("{}.tran 0 10\n.end", buck), 3_600.0, 1e6),
+        ];
+
+        for (name, netlist, min, max) in cases {
+            let secs = estimate_runtime_secs(netlist).unwrap();
+            assert!((*min..=*max).contains(&secs), "{}: {} s not in {}..={}", name, secs, min, max);
+        }
+        assert_eq!(estimate_runtime_secs(&format!("{}.ac dec 10 1 1k\n.end", rc)), None);
+        assert_eq!(estimate_runtime_secs(&format!("{}.tran 0 0\n.end", rc)), None);
+    }
+
+    #[test]
+    fn test_strip_inline_comment() {
+        assert_eq!(strip_inline_comment("R1 a b 1k ; load"), "R1 a b 1k ");
+        assert_eq!(strip_inline_comment("R1 a b 1k $ load"), "R1 a b 1k ");
+        assert_eq!(strip_inline_comment("V1 a 0 PWL file=$HOME/x"), "V1 a 0 PWL file=$HOME/x");
+    }
+}
@@ -0,0 +1,488 @@
+// Copyright (c) 2024-2025 Wanyeki Technologies LLC. All rights reserved.
+// This source code is licensed under the proprietary license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! One long-lived ngspice in pipe mode, for the `ngspice_persistent_session` setting
+//!
+//! Starting ngspice takes 300-800 ms on Windows, most of a short run. With the setting on, one
+//! `ngspice -p` process is kept running and each run is sent to it on stdin: `cd` into the
+//! run's temp dir, `source` the netlist, the commands batch mode would find in the injected
+//! `.control` section, then freeing the circuit and its plots. ngspice prints no prompt that can
+//! be relied on in pipe mode, so every run ends by echoing a marker and output is read up to the
+//! line carrying it.
+//!
+//! A session that exited between runs is relaunched before the next one. One that dies during a
+//! run is dropped, and that run falls back to a process of its own, which reports whatever
+//! killed it. The run's time limit and cancellation kill the session like they would the run's
+//! own process. The session takes one run at a time; runs that come in meanwhile get their own
+//! process.
+
+use std::path::Path;
+use std::process::Stdio;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines};
+use tokio::process::{Child, ChildStdin, ChildStdout};
+use tokio::sync::{mpsc, Mutex};
+use tokio::time::Instant;
+
+use crate::protocol::{now_ms, LogLine};
+use crate::simulator::{self, ProcessOptions, SimulationCancelled, SimulationHung};
+
+/// How often a run is checked for cancellation and its time limit
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// How long a new session gets to answer its first commands
+const LAUNCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Set when the session starts: no paging of long output, and no question before quitting
+const SETUP_COMMANDS: &[&str] = &["set nomoremode", "set noaskquit"];
+
+/// Sent after every run, so nothing of one circuit is left for the next
+const CLEANUP_COMMANDS: &[&str] = &["destroy all", "remcirc"];
+
+/// Start of the marker echoed at the end of each run
+const MARKER_PREFIX: &str = "kelicad-session-done-";
+
+/// What ngspice printed during one run
+#[derive(Debug, Default)]
+pub struct SessionOutput {
+    pub stdout: String,
+    pub stderr: String,
+}
+
+/// The persistent ngspice, started on first use
+#[derive(Default)]
+pub struct NgspiceSession {
+    process: Mutex<Option<SessionProcess>>,
+}
+
+impl NgspiceSession {
+    /// Run the netlist at `netlist_path` with `commands` in its directory
+    /// None when the session is busy with another run, couldn't be started or died during this
+    /// one; the caller then runs ngspice in a process of its own
+    pub async fn run(
+        &self,
+        executable: &str,
+        netlist_path: &Path,
+        commands: &[String],
+        options: &ProcessOptions,
+    ) -> Result<Option<SessionOutput>, Box<dyn std::error::Error + Send + Sync>> {
+        let Ok(mut process) = self.process.try_lock() else {
+            log::info!("The ngspice session is busy; running ngspice in a process of its own");
+            return Ok(None);
+        };
+
+        if let Some(session) = process.as_mut() {
+            if !session.is_usable(executable) {
+                log::warn!("ngspice session (PID {}) has exited or ngspice moved; starting a new one", session.pid);
+                *process = None;
+            }
+        }
+        if process.is_none() {
+            match SessionProcess::launch(executable, options.background_priority).await {
+                Ok(session) => *process = Some(session),
+                Err(e) => {
+                    log::warn!("Could not start an ngspice session, running ngspice per run: {}", e);
+                    return Ok(None);
+                }
+            }
+        }
+        let Some(session) = process.as_mut() else {
+            return Ok(None);
+        };
+
+        let script = run_script(netlist_path, commands, &session.next_marker());
+        match session.run(&script, options).await {
+            Ok(output) => Ok(Some(output)),
+            Err(failure) => {
+                let pid = session.pid;
+                *process = None;
+                match failure {
+                    RunFailure::Exited if !is_cancelled(options) => {
+                        log::warn!("ngspice session (PID {}) exited during a run; running it in a process of its own", pid);
+                        Ok(None)
+                    }
+                    RunFailure::Exited | RunFailure::Cancelled => Err(Box::new(SimulationCancelled)),
+                    RunFailure::TimedOut(limit) => {
+                        let reason = format!(
+                            "ngspice exceeded the maximum simulation time of {}s and was stopped",
+                            limit.as_secs()
+                        );
+                        log::error!("Watchdog: {} (session PID {})", reason, pid);
+                        Err(Box::new(SimulationHung { reason }))
+                    }
+                }
+            }
+        }
+    }
+
+    /// Stop the session, if one is running; the next run starts a new one
+    pub async fn close(&self) {
+        if let Some(session) = self.process.lock().await.take() {
+            log::info!("Stopping the ngspice session (PID {})", session.pid);
+        }
+    }
+}
+
+/// How a run through the session ended without its marker
+enum RunFailure {
+    /// The process closed its output
+    Exited,
+    Cancelled,
+    TimedOut(Duration),
+}
+
+/// A running `ngspice -p`; killed when dropped
+struct SessionProcess {
+    executable: String,
+    pid: u32,
+    child: Child,
+    stdin: ChildStdin,
+    stdout: Lines<BufReader<ChildStdout>>,
+    /// Lines of stderr, read on their own task
+    stderr: mpsc::UnboundedReceiver<String>,
+    /// Scripts sent so far, numbering their markers
+    scripts: u64,
+}
+
+impl SessionProcess {
+    async fn launch(executable: &str, background_priority: bool) -> std::io::Result<Self> {
+        let mut command = tokio::process::Command::from(simulator::bare_simulator_command(executable, background_priority));
+        command.arg("-p").stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::piped()).kill_on_drop(true);
+        let mut child = command.spawn()?;
+        let pid = child.id().unwrap_or_default();
+        let (Some(stdin), Some(stdout), Some(stderr)) = (child.stdin.take(), child.stdout.take(), child.stderr.take()) else {
+            return Err(std::io::Error::other("ngspice session has no pipes"));
+        };
+
+        let (stderr_tx, stderr_rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let mut lines = BufReader::new(stderr).lines();
+            while let Ok(Some(line)) = lines.next_line().await {
+                if stderr_tx.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+
+        let mut session = Self {
+            executable: executable.to_string(),
+            pid,
+            child,
+            stdin,
+            stdout: BufReader::new(stdout).lines(),
+            stderr: stderr_rx,
+            scripts: 0,
+        };
+        let setup = script(SETUP_COMMANDS.iter().map(|command| command.to_string()), &session.next_marker());
+        let options = ProcessOptions { hard_timeout: Some(LAUNCH_TIMEOUT), ..ProcessOptions::default() };
+        if session.run(&setup, &options).await.is_err() {
+            return Err(std::io::Error::other("ngspice did not answer in pipe mode"));
+        }
+        log::info!("ngspice session started with PID: {}", pid);
+        Ok(session)
+    }
+
+    /// Whether the process is still running the ngspice at `executable`
+    fn is_usable(&mut self, executable: &str) -> bool {
+        self.executable == executable && matches!(self.child.try_wait(), Ok(None))
+    }
+
+    fn next_marker(&mut self) -> String {
+        self.scripts += 1;
+        format!("{}{}", MARKER_PREFIX, self.scripts)
+    }
+
+    /// Send `script` and collect the output up to its closing marker
+    async fn run(&mut self, script: &str, options: &ProcessOptions) -> Result<SessionOutput, RunFailure> {
+        // Whatever an earlier run printed late is not this run's
+        while self.stderr.try_recv().is_ok() {}
+        if let Some(holder) = &options.process_id_holder {
+            holder.store(self.pid, Ordering::SeqCst);
+        }
+        let result = self.exchange(script, options).await;
+        if let Some(holder) = &options.process_id_holder {
+            holder.store(0, Ordering::SeqCst);
+        }
+        if result.is_err() {
+            simulator::kill_process_tree(self.pid);
+        }
+        result
+    }
+
+    async fn exchange(&mut self, script: &str, options: &ProcessOptions) -> Result<SessionOutput, RunFailure> {
+        let marker = script.lines().last().and_then(|line| line.strip_prefix("echo ")).unwrap_or_default().to_string();
+        if self.stdin.write_all(script.as_bytes()).await.is_err() || self.stdin.flush().await.is_err() {
+            return Err(RunFailure::Exited);
+        }
+
+        let deadline = options.hard_timeout.map(|limit| (Instant::now() + limit, limit));
+        let mut poll = tokio::time::interval(POLL_INTERVAL);
+        let mut output = SessionOutput::default();
+        loop {
+            tokio::select! {
+                line = self.stdout.next_line() => {
+                    let Ok(Some(line)) = line else {
+                        return Err(RunFailure::Exited);
+                    };
+                    if let Some(activity) = &options.log_activity {
+                        activity.store(now_ms(), Ordering::SeqCst);
+                    }
+                    let line = without_prompts(&line);
+                    if line.trim_end().ends_with(&marker) {
+                        break;
+                    }
+                    forward(options, "stdout", line);
+                    output.stdout.push_str(line);
+                    output.stdout.push('\n');
+                }
+                Some(line) = self.stderr.recv() => {
+                    forward(options, "stderr", &line);
+                    output.stderr.push_str(&line);
+                    output.stderr.push('\n');
+                }
+                _ = poll.tick() => {
+                    if is_cancelled(options) {
+                        return Err(RunFailure::Cancelled);
+                    }
+                    if let Some((_, limit)) = deadline.filter(|(at, _)| Instant::now() >= *at) {
+                        return Err(RunFailure::TimedOut(limit));
+                    }
+                }
+            }
+        }
+        while let Ok(line) = self.stderr.try_recv() {
+            forward(options, "stderr", &line);
+            output.stderr.push_str(&line);
+            output.stderr.push('\n');
+        }
+        Ok(output)
+    }
+}
+
+fn is_cancelled(options: &ProcessOptions) -> bool {
+    options.cancelled.as_ref().is_some_and(|cancelled| cancelled())
+}
+
+/// Pass a line of output to the run's log sink, if it has one
+fn forward(options: &ProcessOptions, stream: &str, line: &str) {
+    if let Some(sink) = &options.log_sink {
+        // Carriage returns separate ngspice's in-place progress updates
+        for text in line.split('\r').filter(|text| !text.is_empty()) {
+            let _ = sink.send(LogLine { stream: stream.to_string(), text: text.to_string() });
+        }
+    }
+}
+
+/// `line` without the `ngspice 12 -> ` prompts ngspice may print before reading each command
+fn without_prompts(mut line: &str) -> &str {
+    while let Some(rest) = line.strip_prefix("ngspice ") {
+        let digits = rest.len() - rest.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+        match rest[digits..].strip_prefix(" -> ").filter(|_| digits > 0) {
+            Some(after) => line = after,
+            None => break,
+        }
+    }
+    line
+}
+
+/// Commands running the netlist at `netlist_path` with `commands`, then `marker`
+/// Forward slashes work on every platform, and unlike backslashes ngspice reads them as written
+fn run_script(netlist_path: &Path, commands: &[String], marker: &str) -> String {
+    let dir = netlist_path.parent().unwrap_or(Path::new(".")).to_string_lossy().replace('\\', "/");
+    let name = netlist_path.file_name().unwrap_or_default().to_string_lossy().into_owned();
+    let lines = [format!("cd \"{}\"", dir), format!("source {}", name)]
+        .into_iter()
+        .chain(commands.iter().cloned())
+        .chain(CLEANUP_COMMANDS.iter().map(|command| command.to_string()));
+    script(lines, marker)
+}
+
+fn script(commands: impl Iterator<Item = String>, marker: &str) -> String {
+    let mut script: String = commands.map(|command| command + "\n").collect();
+    script.push_str(&format!("echo {}\n", marker));
+    script
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    use crate::quality::QualityProfile;
+    use crate::simulator::run_ngspice_simulation;
+
+    /// Fake ngspice answering pipe-mode commands with a prompt before each line it prints, and
+    /// running batch mode too for runs that fall back. Each start is appended to `launches`
+    /// beside it. A netlist mentioning `crash` kills it during `run`, one mentioning `hang`
+    /// makes it sleep
+    #[cfg(unix)]
+    fn fake_pipe_ngspice(dir: &Path) -> String {
+        use std::os::unix::fs::PermissionsExt;
+
+        let script = format!(
+            r#"#!/bin/sh
+echo "$1" >> "{launches}"
+raw() {{
+    printf 'Title: fake\nPlotname: Transient Analysis\nFlags: real\nNo. Variables: 2\nNo. Points: 1\nVariables:\n\t0\ttime\ttime\n\t1\tv(out)\tvoltage\nValues:\n 0\t0.0\n\t1.0\n' > "$1"
+}}
+if [ "$1" = "-b" ]; then
+    raw "$(sed -n 's/^write \([^ ]*\) .*$/\1/p' "$2")"
+    exit 0
+fi
+n=0
+while IFS= read -r line; do
+    n=$((n + 1))
+    set -- $line
+    case "$1" in
+        cd) eval "cd $2" ;;
+        source) netlist="$2" ;;
+        run)
+            grep -q crash "$netlist" && exit 1
+            grep -q hang "$netlist" && sleep 30
+            echo "fake ngspice: simulation done" >&2 ;;
+        write) raw "$2" ;;
+        echo) printf 'ngspice %s -> %s\n' "$n" "$2" ;;
+    esac
+done
+"#,
+            launches = dir.join("launches").display()
+        );
+        let path = dir.join("ngspice");
+        std::fs::write(&path, script).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        path.to_string_lossy().to_string()
+    }
+
+    #[cfg(unix)]
+    fn launches(dir: &Path) -> Vec<String> {
+        std::fs::read_to_string(dir.join("launches")).unwrap_or_default().lines().map(str::to_string).collect()
+    }
+
+    /// PID of the session's ngspice, if one is running
+    #[cfg(unix)]
+    async fn session_pid(session: &NgspiceSession) -> Option<u32> {
+        session.process.lock().await.as_ref().map(|session| session.pid)
+    }
+
+    #[cfg(unix)]
+    fn session_options(session: &Arc<NgspiceSession>) -> ProcessOptions {
+        ProcessOptions {
+            ngspice_session: Some(session.clone()),
+            background_priority: false,
+            hard_timeout: Some(Duration::from_secs(10)),
+            ..ProcessOptions::default()
+        }
+    }
+
+    #[test]
+    fn test_without_prompts() {
+        assert_eq!(without_prompts("ngspice 3 -> Circuit: * test"), "Circuit: * test");
+        assert_eq!(without_prompts("ngspice 1 -> ngspice 2 -> done"), "done");
+        assert_eq!(without_prompts("ngspice -> x"), "ngspice -> x");
+        assert_eq!(without_prompts("ngspice 12 x"), "ngspice 12 x");
+        assert_eq!(without_prompts("plain"), "plain");
+    }
+
+    #[test]
+    fn test_run_script() {
+        let commands = ["run".to_string(), "write circuit.raw all".to_string()];
+        let script = run_script(Path::new("/tmp/kelicad sim/circuit.cir"), &commands, "kelicad-session-done-4");
+        assert_eq!(
+            script,
+            "cd \"/tmp/kelicad sim\"\nsource circuit.cir\nrun\nwrite circuit.raw all\ndestroy all\nremcirc\necho kelicad-session-done-4\n"
+        );
+        #[cfg(windows)]
+        {
+            let script = run_script(Path::new(r"C:\Temp\sim\circuit.cir"), &[], "m");
+            assert!(script.starts_with("cd \"C:/Temp/sim\"\nsource circuit.cir\n"), "{}", script);
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_session_is_reused_across_runs() {
+        let dir = tempfile::tempdir().unwrap();
+        let ngspice = fake_pipe_ngspice(dir.path());
+        let session = Arc::new(NgspiceSession::default());
+        let options = session_options(&session);
+
+        for _ in 0..2 {
+            let results = run_ngspice_simulation(&ngspice, "* Test\nV1 out 0 1\n.tran 1m\n.end", &[], &QualityProfile::default(), &options, None)
+                .await
+                .unwrap();
+            assert_eq!(results.traces[0].data, [1.0]);
+        }
+        assert_eq!(launches(dir.path()), ["-p"]);
+        assert!(session_pid(&session).await.is_some());
+
+        // A netlist with its own .control section runs in batch mode, leaving the session be
+        let own_control = "* Test\nV1 out 0 1\n.tran 1m\n.control\nrun\nwrite circuit.raw all\n.endc\n.end";
+        run_ngspice_simulation(&ngspice, own_control, &[], &QualityProfile::default(), &options, None).await.unwrap();
+        assert_eq!(launches(dir.path()), ["-p", "-b"]);
+
+        session.close().await;
+        assert_eq!(session_pid(&session).await, None);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_session_that_dies_is_replaced() {
+        let dir = tempfile::tempdir().unwrap();
+        let ngspice = fake_pipe_ngspice(dir.path());
+        let session = Arc::new(NgspiceSession::default());
+        let options = session_options(&session);
+        let quality = QualityProfile::default();
+
+        // The run it died during is run again in batch mode
+        let results = run_ngspice_simulation(&ngspice, "* crash\nV1 out 0 1\n.tran 1m\n.end", &[], &quality, &options, None).await.unwrap();
+        assert_eq!(results.traces[0].data, [1.0]);
+        assert_eq!(launches(dir.path()), ["-p", "-b"]);
+        assert_eq!(session_pid(&session).await, None);
+
+        run_ngspice_simulation(&ngspice, "* Test\nV1 out 0 1\n.tran 1m\n.end", &[], &quality, &options, None).await.unwrap();
+        // Killed between runs, it is relaunched for the next
+        simulator::kill_process_tree(session_pid(&session).await.unwrap());
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        run_ngspice_simulation(&ngspice, "* Test\nV1 out 0 1\n.tran 1m\n.end", &[], &quality, &options, None).await.unwrap();
+        assert_eq!(launches(dir.path()), ["-p", "-b", "-p", "-p"]);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_session_run_past_its_time_limit_is_stopped() {
+        let dir = tempfile::tempdir().unwrap();
+        let ngspice = fake_pipe_ngspice(dir.path());
+        let session = Arc::new(NgspiceSession::default());
+        let options = ProcessOptions { hard_timeout: Some(Duration::from_secs(1)), ..session_options(&session) };
+        let quality = QualityProfile::default();
+
+        let started = std::time::Instant::now();
+        let err = run_ngspice_simulation(&ngspice, "* hang\nV1 out 0 1\n.tran 1m\n.end", &[], &quality, &options, None)
+            .await
+            .unwrap_err();
+        assert!(err.is::<SimulationHung>(), "{}", err);
+        assert!(started.elapsed() < Duration::from_secs(10));
+        assert_eq!(session_pid(&session).await, None);
+
+        run_ngspice_simulation(&ngspice, "* Test\nV1 out 0 1\n.tran 1m\n.end", &[], &quality, &options, None).await.unwrap();
+        assert_eq!(launches(dir.path()), ["-p", "-p"]);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_busy_session_leaves_runs_to_their_own_process() {
+        let dir = tempfile::tempdir().unwrap();
+        let ngspice = fake_pipe_ngspice(dir.path());
+        let session = Arc::new(NgspiceSession::default());
+        let options = session_options(&session);
+
+        let _held = session.process.lock().await;
+        let netlist_path = dir.path().join("circuit.cir");
+        let output = session.run(&ngspice, &netlist_path, &[], &options).await.unwrap();
+        assert!(output.is_none());
+        assert!(launches(dir.path()).is_empty());
+    }
+}
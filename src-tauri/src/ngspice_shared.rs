@@ -0,0 +1,704 @@
+// Copyright (c) 2024-2025 Wanyeki Technologies LLC. All rights reserved.
+// This source code is licensed under the proprietary license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! In-process ngspice through its shared library (libngspice), built with the `libngspice` feature
+//!
+//! The netlist is handed over as a deck with `ngSpice_Circ` and run on ngspice's background
+//! thread; vectors arrive point by point through the SendData callback, so nothing is written
+//! to disk. The library holds one circuit at a time: when it is missing, busy or can't run a
+//! netlist, `run` returns None and the caller uses the ngspice CLI instead.
+
+use std::ffi::{c_char, c_int, c_void, CStr, CString};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use libloading::Library;
+
+use crate::quality::QualityProfile;
+use crate::protocol::{LogLine, ProgressStage, SimulationResults, Trace};
+use crate::simulator::{self, LogSink, ProcessOptions, ProgressSender, SimulationHung};
+
+/// How often a running simulation is checked for completion, cancellation and timeout
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// How long ngspice gets to stop its background thread after `bg_halt`
+const HALT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// File names of the shared library
+#[cfg(windows)]
+const LIBRARY_NAMES: &[&str] = &["ngspice.dll", "libngspice-0.dll"];
+#[cfg(target_os = "macos")]
+const LIBRARY_NAMES: &[&str] = &["libngspice.dylib", "libngspice.0.dylib"];
+#[cfg(all(unix, not(target_os = "macos")))]
+const LIBRARY_NAMES: &[&str] = &["libngspice.so", "libngspice.so.0"];
+
+/// Library directories searched after the ones beside the ngspice binary
+#[cfg(unix)]
+const STANDARD_LIB_DIRS: &[&str] = &[
+    "/usr/local/lib",
+    "/opt/homebrew/lib",
+    "/usr/lib",
+    "/usr/lib64",
+    "/usr/lib/x86_64-linux-gnu",
+    "/usr/lib/aarch64-linux-gnu",
+];
+#[cfg(not(unix))]
+const STANDARD_LIB_DIRS: &[&str] = &[];
+
+/// The loaded library; never unloaded, since ngspice keeps our callbacks
+static BACKEND: Mutex<Option<&'static Backend>> = Mutex::new(None);
+
+/// Set once ngspice asked to exit or unload; later runs go to the CLI
+static UNUSABLE: AtomicBool = AtomicBool::new(false);
+
+/// Collector of the run in progress, written to by the callbacks
+static RUN: Mutex<Option<Collector>> = Mutex::new(None);
+
+/// A netlist run in-process: the last plot's vectors and everything ngspice printed
+pub struct SharedRun {
+    /// Lines given to ngspice, joined
+    pub deck: String,
+    pub results: Option<SimulationResults>,
+    pub output: String,
+    pub library: PathBuf,
+}
+
+/// Run `netlist` in libngspice, or return None if it has to go to the CLI
+pub async fn run(
+    ngspice_path: &str,
+    netlist: &str,
+    quality: &QualityProfile,
+    options: &ProcessOptions,
+    progress: Option<&ProgressSender>,
+) -> Result<Option<SharedRun>, Box<dyn std::error::Error + Send + Sync>> {
+    if let Some(reason) = needs_cli(netlist) {
+        log::info!("Running the ngspice CLI because {}", reason);
+        return Ok(None);
+    }
+    let Some(backend) = backend(ngspice_path) else {
+        return Ok(None);
+    };
+
+    let deck = deck(netlist, quality, !options.no_auto_options);
+    let options = options.clone();
+    let progress = progress.cloned();
+    tokio::task::spawn_blocking(move || backend.simulate(&deck, &options, progress)).await?
+}
+
+/// Why a netlist must go through the CLI, if it must
+fn needs_cli(netlist: &str) -> Option<&'static str> {
+    let mut plots = false;
+    for line in netlist.lines() {
+        match line.trim().to_lowercase().split_whitespace().next().unwrap_or("") {
+            ".control" => return Some("the netlist has its own .control section"),
+            // Include paths are relative to the temp dir, which only the CLI runs in
+            ".include" | ".lib" => return Some("the netlist includes other files"),
+            ".tran" | ".ac" | ".dc" => plots = true,
+            ".op" | ".noise" | ".tf" | ".sens" | ".pz" | ".disto" => {
+                return Some("the netlist has an analysis that is read back from the CLI's output")
+            }
+            _ => {}
+        }
+    }
+    (!plots).then_some("the netlist has no .tran, .ac or .dc analysis")
+}
+
+/// Lines handed to `ngSpice_Circ`: the netlist with the quality options unless `auto_options`
+/// is off, ending in `.end`
+fn deck(netlist: &str, quality: &QualityProfile, auto_options: bool) -> Vec<String> {
+    let mut lines: Vec<String> = netlist.lines().map(|line| line.replace('\0', "")).collect();
+    let end_idx = simulator::end_line(&lines);
+    if end_idx.is_none() {
+        lines.push(".end".to_string());
+    }
+    if let Some(options) = simulator::quality_options_line(netlist, "ngspice", quality).filter(|_| auto_options) {
+        let end_idx = end_idx.unwrap_or(lines.len() - 1);
+        lines.insert(end_idx, options);
+    }
+    lines
+}
+
+/// Shared library for the ngspice at `ngspice_path`: beside the binary or in its `../lib`,
+/// then in the standard library directories
+pub fn detect_library(ngspice_path: &str) -> Option<PathBuf> {
+    let beside = Path::new(ngspice_path)
+        .parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .into_iter()
+        .flat_map(|dir| [dir.to_path_buf(), dir.join("..").join("lib")]);
+    find_library(beside.chain(STANDARD_LIB_DIRS.iter().map(PathBuf::from)))
+}
+
+fn find_library(dirs: impl IntoIterator<Item = PathBuf>) -> Option<PathBuf> {
+    dirs.into_iter()
+        .flat_map(|dir| LIBRARY_NAMES.iter().map(move |name| dir.join(name)))
+        .find(|path| path.is_file())
+}
+
+/// The loaded library, loading it on first use
+fn backend(ngspice_path: &str) -> Option<&'static Backend> {
+    if UNUSABLE.load(Ordering::SeqCst) {
+        return None;
+    }
+    let mut loaded = BACKEND.lock().ok()?;
+    if loaded.is_none() {
+        let path = detect_library(ngspice_path)?;
+        // SAFETY: the library is ngspice's, whose initialisation has no preconditions
+        match unsafe { Backend::load(&path) } {
+            Ok(backend) => {
+                log::info!("Loaded libngspice from {:?}", path);
+                *loaded = Some(Box::leak(Box::new(backend)));
+            }
+            Err(e) => {
+                log::warn!("Could not load libngspice from {:?}: {}", path, e);
+                return None;
+            }
+        }
+    }
+    *loaded
+}
+
+type SendChar = unsafe extern "C" fn(*mut c_char, c_int, *mut c_void) -> c_int;
+type SendStat = unsafe extern "C" fn(*mut c_char, c_int, *mut c_void) -> c_int;
+type ControlledExit = unsafe extern "C" fn(c_int, bool, bool, c_int, *mut c_void) -> c_int;
+type SendData = unsafe extern "C" fn(*mut VecValuesAll, c_int, c_int, *mut c_void) -> c_int;
+type SendInitData = unsafe extern "C" fn(*mut VecInfoAll, c_int, *mut c_void) -> c_int;
+type BgThreadRunning = unsafe extern "C" fn(bool, c_int, *mut c_void) -> c_int;
+type InitFn = unsafe extern "C" fn(
+    Option<SendChar>,
+    Option<SendStat>,
+    Option<ControlledExit>,
+    Option<SendData>,
+    Option<SendInitData>,
+    Option<BgThreadRunning>,
+    *mut c_void,
+) -> c_int;
+type CircFn = unsafe extern "C" fn(*mut *mut c_char) -> c_int;
+type CommandFn = unsafe extern "C" fn(*mut c_char) -> c_int;
+
+/// One vector's value at a point (`vecvalues` in sharedspice.h)
+#[repr(C)]
+struct VecValues {
+    name: *mut c_char,
+    creal: f64,
+    cimag: f64,
+    is_scale: bool,
+    is_complex: bool,
+}
+
+/// Every vector's value at a point (`vecvaluesall`)
+#[repr(C)]
+struct VecValuesAll {
+    veccount: c_int,
+    vecindex: c_int,
+    vecsa: *mut *mut VecValues,
+}
+
+/// A vector of a new plot (`vecinfo`)
+#[repr(C)]
+struct VecInfo {
+    number: c_int,
+    vecname: *mut c_char,
+    is_real: bool,
+    pdvec: *mut c_void,
+    pdvecscale: *mut c_void,
+}
+
+/// A new plot and its vectors (`vecinfoall`)
+#[repr(C)]
+struct VecInfoAll {
+    name: *mut c_char,
+    title: *mut c_char,
+    date: *mut c_char,
+    plot_type: *mut c_char,
+    veccount: c_int,
+    vecs: *mut *mut VecInfo,
+}
+
+struct Backend {
+    library: PathBuf,
+    circ: CircFn,
+    command: CommandFn,
+    /// Held for the length of a run; the library simulates one circuit at a time
+    busy: Mutex<()>,
+    _library: Library,
+}
+
+impl Backend {
+    /// Load the library and register the callbacks
+    unsafe fn load(path: &Path) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let library = Library::new(path)?;
+        let init: InitFn = *library.get(b"ngSpice_Init\0")?;
+        let circ: CircFn = *library.get(b"ngSpice_Circ\0")?;
+        let command: CommandFn = *library.get(b"ngSpice_Command\0")?;
+
+        let status = init(
+            Some(send_char),
+            Some(send_stat),
+            Some(controlled_exit),
+            Some(send_data),
+            Some(send_init_data),
+            Some(bg_thread_running),
+            std::ptr::null_mut(),
+        );
+        if status != 0 {
+            return Err(format!("ngSpice_Init returned {}", status).into());
+        }
+        Ok(Self { library: path.to_path_buf(), circ, command, busy: Mutex::new(()), _library: library })
+    }
+
+    /// Run an ngspice interpreter command such as `bg_run`
+    fn command(&self, command: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let command = CString::new(command)?;
+        // SAFETY: ngspice only reads the NUL-terminated command
+        match unsafe { (self.command)(command.as_ptr() as *mut c_char) } {
+            0 => Ok(()),
+            status => Err(format!("ngspice command '{}' failed ({})", command.to_string_lossy(), status).into()),
+        }
+    }
+
+    /// Load and run a deck, blocking until it finishes, is cancelled or times out
+    fn simulate(
+        &self,
+        deck: &[String],
+        options: &ProcessOptions,
+        progress: Option<ProgressSender>,
+    ) -> Result<Option<SharedRun>, Box<dyn std::error::Error + Send + Sync>> {
+        let Ok(_busy) = self.busy.try_lock() else {
+            log::info!("libngspice is running another simulation; using the ngspice CLI");
+            return Ok(None);
+        };
+
+        if let Ok(mut run) = RUN.lock() {
+            *run = Some(Collector::new(progress, options.log_sink.clone()));
+        }
+        let outcome = self.run_deck(deck, options);
+        // Free the circuit and its plots for the next run
+        let _ = self.command("destroy all");
+        let _ = self.command("remcirc");
+        let collector = RUN.lock().ok().and_then(|mut run| run.take()).unwrap_or_default();
+        outcome?;
+
+        Ok(Some(SharedRun {
+            deck: deck.join("\n"),
+            output: collector.output.join("\n"),
+            results: collector.into_results(),
+            library: self.library.clone(),
+        }))
+    }
+
+    fn run_deck(&self, deck: &[String], options: &ProcessOptions) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let lines = deck
+            .iter()
+            .map(|line| CString::new(line.as_str()))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut pointers: Vec<*mut c_char> = lines
+            .iter()
+            .map(|line| line.as_ptr() as *mut c_char)
+            .chain([std::ptr::null_mut()])
+            .collect();
+        // SAFETY: a NULL-terminated array of NUL-terminated lines that outlive the call
+        if unsafe { (self.circ)(pointers.as_mut_ptr()) } != 0 {
+            // The reason was printed through SendChar
+            return Ok(());
+        }
+
+        if let Some(threads) = options.ngspice_threads {
+            self.command(&format!("set num_threads={}", threads.max(1)))?;
+        }
+        with_run(|run| run.running = true);
+        self.command("bg_run")?;
+
+        let started = Instant::now();
+        while with_run(|run| run.running).unwrap_or(false) {
+            std::thread::sleep(POLL_INTERVAL);
+            if options.cancelled.as_ref().is_some_and(|cancelled| cancelled()) {
+                self.halt();
+                return Err("Simulation was cancelled".into());
+            }
+            if let Some(limit) = options.hard_timeout.filter(|limit| started.elapsed() > *limit) {
+                self.halt();
+                let reason = format!(
+                    "ngspice exceeded the maximum simulation time of {}s and was stopped",
+                    limit.as_secs()
+                );
+                log::error!("Watchdog: {}", reason);
+                return Err(Box::new(SimulationHung { reason }));
+            }
+        }
+        Ok(())
+    }
+
+    /// Stop the background thread and wait for it to finish
+    fn halt(&self) {
+        let _ = self.command("bg_halt");
+        let deadline = Instant::now() + HALT_TIMEOUT;
+        while with_run(|run| run.running).unwrap_or(false) && Instant::now() < deadline {
+            std::thread::sleep(POLL_INTERVAL);
+        }
+    }
+}
+
+/// Apply `f` to the run in progress, if there is one
+fn with_run<T>(f: impl FnOnce(&mut Collector) -> T) -> Option<T> {
+    RUN.lock().ok()?.as_mut().map(f)
+}
+
+/// Owned copy of a C string from ngspice
+unsafe fn c_string(ptr: *const c_char) -> Option<String> {
+    (!ptr.is_null()).then(|| CStr::from_ptr(ptr).to_string_lossy().into_owned())
+}
+
+unsafe extern "C" fn send_char(text: *mut c_char, _id: c_int, _user: *mut c_void) -> c_int {
+    if let Some(text) = c_string(text) {
+        with_run(|run| run.output_line(&text));
+    }
+    0
+}
+
+unsafe extern "C" fn send_stat(text: *mut c_char, _id: c_int, _user: *mut c_void) -> c_int {
+    if let Some(text) = c_string(text) {
+        with_run(|run| run.status(&text));
+    }
+    0
+}
+
+unsafe extern "C" fn controlled_exit(status: c_int, unload: bool, quit: bool, _id: c_int, _user: *mut c_void) -> c_int {
+    log::warn!("libngspice asked to exit (status {}, unload {}, quit {})", status, unload, quit);
+    if unload || quit {
+        UNUSABLE.store(true, Ordering::SeqCst);
+    }
+    with_run(|run| run.running = false);
+    0
+}
+
+unsafe extern "C" fn send_init_data(info: *mut VecInfoAll, _id: c_int, _user: *mut c_void) -> c_int {
+    let Some(info) = info.as_ref() else { return 0 };
+    let names = (0..info.veccount.max(0) as usize)
+        .map(|i| {
+            (*info.vecs.add(i))
+                .as_ref()
+                .and_then(|vec| c_string(vec.vecname))
+                .unwrap_or_default()
+        })
+        .collect();
+    // `name` is the plot's description, e.g. "Transient Analysis"; `type` is e.g. "tran1"
+    let plot_name = c_string(info.name).unwrap_or_default();
+    with_run(|run| run.init_plot(&plot_name, names));
+    0
+}
+
+unsafe extern "C" fn send_data(values: *mut VecValuesAll, count: c_int, _id: c_int, _user: *mut c_void) -> c_int {
+    let Some(values) = values.as_ref() else { return 0 };
+    let point: Vec<Value> = (0..values.veccount.min(count).max(0) as usize)
+        .filter_map(|i| (*values.vecsa.add(i)).as_ref())
+        .map(|value| Value {
+            real: value.creal,
+            imag: value.cimag,
+            is_scale: value.is_scale,
+            is_complex: value.is_complex,
+        })
+        .collect();
+    with_run(|run| run.push_point(&point));
+    0
+}
+
+unsafe extern "C" fn bg_thread_running(not_running: bool, _id: c_int, _user: *mut c_void) -> c_int {
+    with_run(|run| run.running = !not_running);
+    0
+}
+
+/// One vector's value at a point
+#[derive(Debug, Clone, Copy)]
+struct Value {
+    real: f64,
+    imag: f64,
+    is_scale: bool,
+    is_complex: bool,
+}
+
+/// What the callbacks have delivered for the run in progress
+#[derive(Default)]
+struct Collector {
+    plot_name: String,
+    names: Vec<String>,
+    /// Index of the independent variable (time, frequency or sweep)
+    scale: Option<usize>,
+    complex: bool,
+    values: Vec<Vec<f64>>,
+    /// Phase in degrees, filled for complex plots only
+    phase: Vec<Vec<f64>>,
+    output: Vec<String>,
+    /// Last whole percent reported
+    percent: Option<u32>,
+    running: bool,
+    progress: Option<ProgressSender>,
+    log_sink: Option<LogSink>,
+}
+
+impl Collector {
+    fn new(progress: Option<ProgressSender>, log_sink: Option<LogSink>) -> Self {
+        Self { progress, log_sink, ..Self::default() }
+    }
+
+    /// A line printed by ngspice, `stdout ...` or `stderr ...`
+    fn output_line(&mut self, line: &str) {
+        let (stream, text) = match line.split_once(' ') {
+            Some((stream @ ("stdout" | "stderr"), text)) => (stream, text),
+            _ => ("stdout", line),
+        };
+        if let Some(sink) = &self.log_sink {
+            let _ = sink.send(LogLine { stream: stream.to_string(), text: text.to_string() });
+        }
+        self.output.push(text.to_string());
+    }
+
+    /// A status message such as `tran: 42.5%`, reported as progress when the whole percent changes
+    fn status(&mut self, status: &str) {
+        let Some(percent) = parse_percent(status) else { return };
+        if self.percent == Some(percent as u32) {
+            return;
+        }
+        self.percent = Some(percent as u32);
+        if let Some(progress) = &self.progress {
+            // Progress is best effort; a full channel just skips this update
+            let _ = progress.try_send(ProgressStage::Running { percent: Some(percent) });
+        }
+    }
+
+    /// A new plot begins; an earlier analysis's vectors are replaced like the CLI's current plot
+    fn init_plot(&mut self, plot_name: &str, names: Vec<String>) {
+        self.plot_name = plot_name.to_string();
+        self.values = vec![Vec::new(); names.len()];
+        self.phase = vec![Vec::new(); names.len()];
+        self.names = names;
+        self.scale = None;
+        self.complex = false;
+    }
+
+    fn push_point(&mut self, point: &[Value]) {
+        if point.len() != self.names.len() {
+            return;
+        }
+        if self.scale.is_none() {
+            self.scale = Some(point.iter().position(|value| value.is_scale).unwrap_or(0));
+            self.complex = point.iter().any(|value| value.is_complex);
+        }
+        for (i, value) in point.iter().enumerate() {
+            if self.complex {
+                // Magnitude for signals; the frequency scale is real
+                let magnitude = if Some(i) == self.scale {
+                    value.real
+                } else {
+                    (value.real * value.real + value.imag * value.imag).sqrt()
+                };
+                self.values[i].push(magnitude);
+                self.phase[i].push(value.imag.atan2(value.real).to_degrees());
+            } else {
+                self.values[i].push(value.real);
+            }
+        }
+    }
+
+    /// Results in the shape the raw file parsers produce, or None if no point arrived
+    fn into_results(self) -> Option<SimulationResults> {
+        let scale = self.scale?;
+        let analysis_type = simulator::plot_analysis_type(&self.plot_name);
+        let mut values = self.values;
+        let mut phase = self.phase;
+        let time = std::mem::take(&mut values[scale]);
+        if time.is_empty() {
+            return None;
+        }
+
+        let traces = self
+            .names
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| *i != scale)
+            .map(|(i, name)| {
+                let (name, var_type) = trace_name(name);
+                Trace {
+                    name,
+                    data: std::mem::take(&mut values[i]),
+                    unit: simulator::variable_unit(var_type, analysis_type).to_string(),
+                    phase: self.complex.then(|| std::mem::take(&mut phase[i])),
+                    raw_name: None,
+                }
+            })
+            .collect();
+
+        Some(SimulationResults {
+            time,
+            traces,
+            analysis_type: analysis_type.to_string(),
+            x_axis_label: Some(self.names[scale].to_lowercase()),
+            x_axis_unit: simulator::axis_unit(scale_type(&self.names[scale]), analysis_type),
+            scalar_results: None,
+            device_operating_points: Default::default(),
+            truncated: None,
+            plot_hints: None,
+            raw_header: None,
+        })
+    }
+}
+
+/// Percent complete from a status message such as `tran: 42.5%`
+fn parse_percent(status: &str) -> Option<f32> {
+    let (before, _) = status.split_once('%')?;
+    let number = before.rsplit(|c: char| c.is_whitespace() || c == ':').next()?;
+    number.parse::<f32>().ok().filter(|p| (0.0..=100.0).contains(p))
+}
+
+/// Declared type of a scale vector, which the callbacks don't pass on, from ngspice's name for it
+fn scale_type(name: &str) -> &'static str {
+    match name.to_lowercase().as_str() {
+        "time" => "time",
+        "frequency" => "frequency",
+        "v-sweep" => "voltage",
+        "i-sweep" => "current",
+        "temp-sweep" => "temp-sweep",
+        "res-sweep" => "res-sweep",
+        _ => "notype",
+    }
+}
+
+/// A vector's name as the raw file has it, and its type: `out` is `v(out)`, `v1#branch` is `i(v1)`
+fn trace_name(name: &str) -> (String, &'static str) {
+    let lower = name.to_lowercase();
+    if let Some(device) = lower.strip_suffix("#branch") {
+        (format!("i({})", device), "current")
+    } else if lower.starts_with("i(") {
+        (lower, "current")
+    } else if lower.contains('(') {
+        (lower, "voltage")
+    } else {
+        (format!("v({})", lower), "voltage")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn value(real: f64, imag: f64, is_scale: bool, is_complex: bool) -> Value {
+        Value { real, imag, is_scale, is_complex }
+    }
+
+    #[test]
+    fn test_finds_library_in_first_directory_that_has_it() {
+        let bin = tempfile::tempdir().unwrap();
+        let lib = tempfile::tempdir().unwrap();
+        assert_eq!(find_library([bin.path().to_path_buf(), lib.path().to_path_buf()]), None);
+
+        let name = LIBRARY_NAMES[LIBRARY_NAMES.len() - 1];
+        std::fs::write(lib.path().join(name), "").unwrap();
+        assert_eq!(
+            find_library([bin.path().to_path_buf(), lib.path().to_path_buf()]),
+            Some(lib.path().join(name))
+        );
+
+        // Beside the binary wins
+        std::fs::write(bin.path().join(LIBRARY_NAMES[0]), "").unwrap();
+        let exe = bin.path().join("ngspice");
+        assert_eq!(detect_library(&exe.to_string_lossy()), Some(bin.path().join(LIBRARY_NAMES[0])));
+    }
+
+    #[test]
+    fn test_only_plotted_analyses_run_in_process() {
+        assert_eq!(needs_cli("* rc\nR1 in out 1k\n.tran 1u 1m\n.end"), None);
+        assert_eq!(needs_cli("* rc\n.AC dec 10 1 1Meg\n.end"), None);
+        assert!(needs_cli("* rc\n.tran 1m\n.control\nrun\n.endc\n.end").is_some());
+        assert!(needs_cli("* rc\n.tf v(out) V1\n.end").is_some());
+        assert!(needs_cli("* rc\n.tran 1m\n.noise v(out) V1 dec 10 1 1k\n.end").is_some());
+        assert!(needs_cli("* rc\nR1 in out 1k\n.end").is_some());
+        assert!(needs_cli("* rc\n.include opamp.sub\n.tran 1m\n.end").is_some());
+    }
+
+    #[test]
+    fn test_deck_adds_quality_options_before_end() {
+        let profiles = crate::quality::default_quality_profiles();
+        let prepared = deck("* rc\nR1 in out 1k\n.tran 1m\n.end", &profiles["fast"], true);
+        assert_eq!(prepared.last().map(String::as_str), Some(".end"));
+        assert_eq!(prepared[prepared.len() - 2], ".options reltol=1e-2 trtol=10");
+
+        assert_eq!(deck("* rc\n.tran 1m", &profiles["balanced"], true), ["* rc", ".tran 1m", ".end"]);
+        assert_eq!(deck("* rc\n.tran 1m\n.end", &profiles["fast"], false), ["* rc", ".tran 1m", ".end"]);
+    }
+
+    #[test]
+    fn test_collects_transient_vectors() {
+        let mut run = Collector::default();
+        run.init_plot("Transient Analysis", vec!["time".into(), "out".into(), "v1#branch".into()]);
+        run.push_point(&[value(0.0, 0.0, true, false), value(1.0, 0.0, false, false), value(-1e-3, 0.0, false, false)]);
+        run.push_point(&[value(1e-3, 0.0, true, false), value(0.5, 0.0, false, false), value(-5e-4, 0.0, false, false)]);
+        // A point that doesn't match the plot's vectors is ignored
+        run.push_point(&[value(2e-3, 0.0, true, false)]);
+
+        let results = run.into_results().unwrap();
+        assert_eq!(results.analysis_type, "transient");
+        assert_eq!(results.x_axis_label.as_deref(), Some("time"));
+        assert_eq!(results.x_axis_unit.as_deref(), Some("s"));
+        assert_eq!(results.time, [0.0, 1e-3]);
+        assert_eq!(results.traces[0].name, "v(out)");
+        assert_eq!(results.traces[0].unit, "V");
+        assert_eq!(results.traces[0].data, [1.0, 0.5]);
+        assert_eq!(results.traces[1].name, "i(v1)");
+        assert_eq!(results.traces[1].unit, "A");
+        assert!(results.traces[1].phase.is_none());
+    }
+
+    #[test]
+    fn test_sweep_axis_unit_follows_scale() {
+        for (scale, unit) in [("i-sweep", Some("A")), ("temp-sweep", Some("°C")), ("res-sweep", Some("Ω")), ("sweep", None)] {
+            let mut run = Collector::default();
+            run.init_plot("DC transfer characteristic", vec![scale.into(), "out".into()]);
+            run.push_point(&[value(0.0, 0.0, true, false), value(1.0, 0.0, false, false)]);
+            let results = run.into_results().unwrap();
+            assert_eq!(results.x_axis_unit.as_deref(), unit, "{}", scale);
+        }
+    }
+
+    #[test]
+    fn test_collects_ac_magnitude_and_phase() {
+        let mut run = Collector::default();
+        // An earlier plot is replaced by the next analysis's
+        run.init_plot("Operating Point", vec!["out".into()]);
+        run.init_plot("AC Analysis", vec!["frequency".into(), "out".into()]);
+        run.push_point(&[value(10.0, 0.0, true, true), value(0.0, -2.0, false, true)]);
+
+        let results = run.into_results().unwrap();
+        assert_eq!(results.analysis_type, "ac");
+        assert_eq!(results.time, [10.0]);
+        assert_eq!(results.traces[0].data, [2.0]);
+        assert_eq!(results.traces[0].phase.as_deref(), Some(&[-90.0][..]));
+
+        let mut empty = Collector::default();
+        empty.init_plot("Transient Analysis", vec!["time".into()]);
+        assert!(empty.into_results().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_status_and_output_are_forwarded() {
+        let (progress_tx, mut progress_rx) = tokio::sync::mpsc::channel(8);
+        let (log_tx, mut log_rx) = tokio::sync::mpsc::unbounded_channel();
+        let mut run = Collector::new(Some(progress_tx), Some(log_tx));
+
+        run.status("tran: 42.1%");
+        run.status("tran: 42.7%");
+        run.status("--ready--");
+        run.status("tran: 100%");
+        assert!(matches!(progress_rx.try_recv(), Ok(ProgressStage::Running { percent: Some(p) }) if p == 42.1));
+        assert!(matches!(progress_rx.try_recv(), Ok(ProgressStage::Running { percent: Some(p) }) if p == 100.0));
+        assert!(progress_rx.try_recv().is_err());
+
+        run.output_line("stderr Error: unknown model 'npn1'");
+        run.output_line("stdout Circuit: * rc");
+        let line = log_rx.try_recv().unwrap();
+        assert_eq!((line.stream.as_str(), line.text.as_str()), ("stderr", "Error: unknown model 'npn1'"));
+        assert_eq!(run.output, ["Error: unknown model 'npn1'", "Circuit: * rc"]);
+    }
+}
@@ -0,0 +1,88 @@
+// Copyright (c) 2024-2025 Wanyeki Technologies LLC. All rights reserved.
+// This source code is licensed under the proprietary license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Desktop notifications for finished simulations
+
+use std::sync::Arc;
+use tauri::{AppHandle, Manager};
+use tauri_plugin_notification::NotificationExt;
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::settings::AgentSettings;
+use crate::state::{AppState, StatusEvent};
+
+/// Whether a finished simulation deserves a notification
+/// Short runs and runs the user is evidently watching (client still connected, agent window focused) stay quiet
+pub fn should_notify(
+    settings: &AgentSettings,
+    execution_ms: u64,
+    client_connected: bool,
+    window_focused: bool,
+) -> bool {
+    settings.notifications_enabled
+        && execution_ms >= settings.notification_threshold_secs.saturating_mul(1000)
+        && !(client_connected && window_focused)
+}
+
+/// Show a notification for every qualifying finished simulation until the app exits
+pub async fn run_notifications(
+    app: AppHandle,
+    state: Arc<AppState>,
+    mut events: broadcast::Receiver<StatusEvent>,
+) {
+    loop {
+        let (success, summary, execution_ms, client_connected) = match events.recv().await {
+            Ok(StatusEvent::SimulationFinished {
+                success,
+                summary,
+                execution_ms,
+                client_connected,
+                ..
+            }) => (success, summary, execution_ms, client_connected),
+            Ok(_) => continue,
+            Err(RecvError::Lagged(skipped)) => {
+                log::warn!("Notification listener skipped {} status events", skipped);
+                continue;
+            }
+            Err(RecvError::Closed) => break,
+        };
+
+        let window_focused = app
+            .get_webview_window(crate::window::MAIN_WINDOW)
+            .and_then(|window| window.is_focused().ok())
+            .unwrap_or(false);
+        let settings = state.settings.read().await.clone();
+        if !should_notify(&settings, execution_ms, client_connected, window_focused) {
+            continue;
+        }
+
+        let title = if success { "Simulation complete" } else { "Simulation failed" };
+        if let Err(e) = app.notification().builder().title(title).body(&summary).show() {
+            log::warn!("Failed to show notification: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_should_notify() {
+        let settings = AgentSettings::default();
+        assert!(should_notify(&settings, 12_000, false, false));
+        // Below the default 10 s threshold
+        assert!(!should_notify(&settings, 9_999, false, false));
+        // Only suppressed when the client is still connected and the window is focused
+        assert!(should_notify(&settings, 12_000, true, false));
+        assert!(should_notify(&settings, 12_000, false, true));
+        assert!(!should_notify(&settings, 12_000, true, true));
+
+        let disabled = AgentSettings {
+            notifications_enabled: false,
+            ..AgentSettings::default()
+        };
+        assert!(!should_notify(&disabled, 60_000, false, false));
+    }
+}
@@ -0,0 +1,114 @@
+// Copyright (c) 2024-2025 Wanyeki Technologies LLC. All rights reserved.
+// This source code is licensed under the proprietary license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! First-run onboarding: which simulators are missing and how to install them
+//!
+//! New users often connect before installing anything. The stage is recomputed on every
+//! detection pass, so an install is picked up by the periodic re-detection and the install
+//! guide goes away on its own. All install guidance is worded here, for the agent window, the
+//! status and handshake messages, and "not found" errors alike.
+
+use crate::protocol::{InstallGuide, OnboardingStage, OnboardingState};
+
+const LTSPICE_DOWNLOAD_URL: &str =
+    "https://www.analog.com/en/design-center/design-tools-and-calculators/ltspice-simulator.html";
+
+const NGSPICE_DOWNLOAD_URL: &str = "https://ngspice.sourceforge.io/download.html";
+
+/// Onboarding state for the simulators detected, with guides for this platform
+pub fn onboarding_state(ltspice_available: bool, ngspice_available: bool) -> OnboardingState {
+    state_for_platform(ltspice_available, ngspice_available, std::env::consts::OS)
+}
+
+/// Onboarding state with guides for `os`, as named by `std::env::consts::OS`
+fn state_for_platform(ltspice_available: bool, ngspice_available: bool, os: &str) -> OnboardingState {
+    let stage = match (ltspice_available, ngspice_available) {
+        (false, false) => OnboardingStage::NoSimulator,
+        (true, false) => OnboardingStage::DetectedLtspiceOnly,
+        (false, true) => OnboardingStage::DetectedNgspiceOnly,
+        (true, true) => OnboardingStage::Ready,
+    };
+    let mut guides = Vec::new();
+    if !ltspice_available {
+        guides.push(install_guide("ltspice", os));
+    }
+    if !ngspice_available {
+        guides.push(install_guide("ngspice", os));
+    }
+    OnboardingState { stage, guides }
+}
+
+/// How to install "ltspice" or "ngspice" on `os`
+fn install_guide(simulator: &str, os: &str) -> InstallGuide {
+    let (message, url, command) = match (simulator, os) {
+        ("ngspice", "macos") => ("Install ngspice with Homebrew", NGSPICE_DOWNLOAD_URL, Some("brew install ngspice")),
+        ("ngspice", "linux") => (
+            "Install ngspice from your distribution's packages",
+            NGSPICE_DOWNLOAD_URL,
+            Some("sudo apt install ngspice"),
+        ),
+        ("ngspice", _) => ("Download ngspice and unzip it to C:\\Spice64", NGSPICE_DOWNLOAD_URL, None),
+        (_, "macos") => ("Download LTspice for macOS from Analog Devices", LTSPICE_DOWNLOAD_URL, None),
+        (_, "linux") => (
+            "LTspice has no Linux version; install it under Wine and put an `ltspice` launcher on the PATH",
+            LTSPICE_DOWNLOAD_URL,
+            None,
+        ),
+        _ => ("Download and install LTspice from Analog Devices", LTSPICE_DOWNLOAD_URL, None),
+    };
+    InstallGuide {
+        simulator: simulator.to_string(),
+        message: message.to_string(),
+        url: Some(url.to_string()),
+        command: command.map(str::to_string),
+    }
+}
+
+/// One-line install instructions for "ltspice" or "ngspice", to follow a "not found" error
+pub fn install_hint(simulator: &str) -> String {
+    let guide = install_guide(simulator, std::env::consts::OS);
+    match (&guide.command, &guide.url) {
+        (Some(command), Some(url)) => format!("{} ({}) or download it from {}", guide.message, command, url),
+        (Some(command), None) => format!("{} ({})", guide.message, command),
+        (None, Some(url)) => format!("{}: {}", guide.message, url),
+        (None, None) => guide.message,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stage_follows_detection() {
+        let none = state_for_platform(false, false, "windows");
+        assert_eq!(none.stage, OnboardingStage::NoSimulator);
+        let simulators: Vec<&str> = none.guides.iter().map(|guide| guide.simulator.as_str()).collect();
+        assert_eq!(simulators, ["ltspice", "ngspice"]);
+
+        let ltspice = state_for_platform(true, false, "windows");
+        assert_eq!(ltspice.stage, OnboardingStage::DetectedLtspiceOnly);
+        assert_eq!(ltspice.guides.len(), 1);
+        assert_eq!(ltspice.guides[0].simulator, "ngspice");
+
+        let ngspice = state_for_platform(false, true, "windows");
+        assert_eq!(ngspice.stage, OnboardingStage::DetectedNgspiceOnly);
+        assert_eq!(ngspice.guides[0].url.as_deref(), Some(LTSPICE_DOWNLOAD_URL));
+
+        assert_eq!(state_for_platform(true, true, "macos"), OnboardingState { stage: OnboardingStage::Ready, guides: vec![] });
+    }
+
+    #[test]
+    fn test_guides_are_platform_specific() {
+        assert_eq!(install_guide("ngspice", "macos").command.as_deref(), Some("brew install ngspice"));
+        assert_eq!(install_guide("ngspice", "linux").command.as_deref(), Some("sudo apt install ngspice"));
+        assert_eq!(install_guide("ngspice", "windows").command, None);
+        assert!(install_guide("ltspice", "linux").message.contains("Wine"));
+        for os in ["windows", "macos", "linux"] {
+            assert_eq!(install_guide("ltspice", os).url.as_deref(), Some(LTSPICE_DOWNLOAD_URL));
+            assert_eq!(install_guide("ngspice", os).url.as_deref(), Some(NGSPICE_DOWNLOAD_URL));
+        }
+        assert!(install_hint("ltspice").contains(LTSPICE_DOWNLOAD_URL));
+    }
+}
@@ -0,0 +1,111 @@
+// Copyright (c) 2024-2025 Wanyeki Technologies LLC. All rights reserved.
+// This source code is licensed under the proprietary license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Variables in `.include` and `.lib` paths
+//!
+//! Netlists shared across a team point at models through a variable, `.include $MODELS/nmos.lib`
+//! or `.lib %MODELS%\nmos.lib`, which neither simulator expands. They are expanded before the
+//! include is resolved, from the settings' `path_variables` first and then the agent's
+//! environment, so a team can define `MODELS` without touching the real environment.
+
+use std::collections::BTreeMap;
+
+/// `path` with its `$NAME`, `${NAME}` and `%NAME%` variables replaced by `lookup`'s values
+/// A `$` or `%` that doesn't start a variable is kept; a variable `lookup` has no value for is
+/// the error, as written in `path`
+pub fn expand(path: &str, lookup: impl Fn(&str) -> Option<String>) -> Result<String, String> {
+    let mut expanded = String::with_capacity(path.len());
+    let mut rest = path;
+    while let Some(start) = rest.find(['$', '%']) {
+        expanded.push_str(&rest[..start]);
+        let after = &rest[start..];
+        let Some((name, written)) = variable_at(after) else {
+            expanded.push_str(&after[..1]);
+            rest = &after[1..];
+            continue;
+        };
+        expanded.push_str(&lookup(name).ok_or_else(|| written.to_string())?);
+        rest = &after[written.len()..];
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+/// `expand` with the values of `variables`, or else of the environment
+pub fn expand_with(path: &str, variables: &BTreeMap<String, String>) -> Result<String, String> {
+    expand(path, |name| variables.get(name).cloned().or_else(|| std::env::var(name).ok()))
+}
+
+/// The name of the variable `text` starts with, and the whole of it as written
+fn variable_at(text: &str) -> Option<(&str, &str)> {
+    let (name, len) = if let Some(braced) = text.strip_prefix("${") {
+        let end = braced.find('}')?;
+        (&braced[..end], end + 3)
+    } else if let Some(unbraced) = text.strip_prefix('$') {
+        let end = unbraced.find(|c: char| !is_name_char(c)).unwrap_or(unbraced.len());
+        (&unbraced[..end], end + 1)
+    } else {
+        let percent = text.strip_prefix('%')?;
+        let end = percent.find('%')?;
+        (&percent[..end], end + 2)
+    };
+    valid_name(name).then(|| (name, &text[..len]))
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+/// Whether `name` can name a variable: letters, digits and underscores, not starting with a digit
+fn valid_name(name: &str) -> bool {
+    name.chars().next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_') && name.chars().all(is_name_char)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn models(name: &str) -> Option<String> {
+        (name == "MODELS").then(|| "/opt/models".to_string())
+    }
+
+    #[test]
+    fn test_expands_each_syntax() {
+        assert_eq!(expand("$MODELS/nmos.lib", models).unwrap(), "/opt/models/nmos.lib");
+        assert_eq!(expand("${MODELS}/nmos.lib", models).unwrap(), "/opt/models/nmos.lib");
+        assert_eq!(expand("%MODELS%\\nmos.lib", models).unwrap(), "/opt/models\\nmos.lib");
+        assert_eq!(expand("${MODELS}nmos.lib", models).unwrap(), "/opt/modelsnmos.lib");
+        assert_eq!(expand("$MODELS/$MODELS.lib", models).unwrap(), "/opt/models//opt/models.lib");
+    }
+
+    #[test]
+    fn test_leaves_paths_without_variables_alone() {
+        assert_eq!(expand("models/nmos.lib", models).unwrap(), "models/nmos.lib");
+        assert_eq!(expand("C:\\Models\\100%\\nmos.lib", models).unwrap(), "C:\\Models\\100%\\nmos.lib");
+        assert_eq!(expand("price$/5%off.lib", models).unwrap(), "price$/5%off.lib");
+        assert_eq!(expand("${1BAD}/a.lib", models).unwrap(), "${1BAD}/a.lib");
+        assert_eq!(expand("${MODELS/a.lib", models).unwrap(), "${MODELS/a.lib");
+    }
+
+    #[test]
+    fn test_unset_variables_are_named_as_written() {
+        assert_eq!(expand("$PDK/nmos.lib", models).unwrap_err(), "$PDK");
+        assert_eq!(expand("${PDK}/nmos.lib", models).unwrap_err(), "${PDK}");
+        assert_eq!(expand("%PDK%\\nmos.lib", models).unwrap_err(), "%PDK%");
+        assert_eq!(expand("$MODELS/$PDK/nmos.lib", models).unwrap_err(), "$PDK");
+    }
+
+    #[test]
+    fn test_settings_variables_come_before_the_environment() {
+        let variables = BTreeMap::from([("PATH".to_string(), "/team/models".to_string())]);
+        assert_eq!(expand_with("$PATH/nmos.lib", &variables).unwrap(), "/team/models/nmos.lib");
+        if let Ok(home) = std::env::var("HOME") {
+            assert_eq!(expand_with("${HOME}/nmos.lib", &variables).unwrap(), format!("{}/nmos.lib", home));
+        }
+        assert_eq!(
+            expand_with("%KELICAD_UNSET_MODELS_DIR%\\nmos.lib", &variables).unwrap_err(),
+            "%KELICAD_UNSET_MODELS_DIR%"
+        );
+    }
+}
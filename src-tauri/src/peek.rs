@@ -0,0 +1,303 @@
+// Copyright (c) 2024-2025 Wanyeki Technologies LLC. All rights reserved.
+// This source code is licensed under the proprietary license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Peek runs: which signals a netlist produces, without their data
+//!
+//! A raw file's header names every variable and the number of points written, which is all a
+//! probe picker needs, so a peek reads only that. A fast peek also has LTspice stop a `.tran`
+//! analysis after its first timestep, making the run itself cheap.
+
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::path::Path;
+
+use encoding_rs::UTF_16LE;
+
+use crate::netlist;
+use crate::protocol::{RawHeader, RawVariable, SimulationResults, Trace};
+use crate::simulator;
+
+/// Most of a raw file read looking for the end of its header
+const MAX_HEADER_BYTES: u64 = 4 * 1024 * 1024;
+
+/// Fraction of its stop time a `.tran` without a timestep runs for in a fast peek
+const FAST_PEEK_STOP_FRACTION: f64 = 1e-3;
+
+/// `.tran` modifiers left out of a fast peek; `steady` runs on until steady state is reached
+const SLOW_TRAN_MODIFIERS: &[&str] = &["steady"];
+
+/// Results naming the variables of the raw file at `path`, read from its header alone
+pub fn read_header(path: &Path) -> Result<SimulationResults, String> {
+    let mut data = Vec::new();
+    std::fs::File::open(path)
+        .and_then(|file| file.take(MAX_HEADER_BYTES).read_to_end(&mut data))
+        .map_err(|e| format!("Could not read the raw file: {}", e))?;
+    parse_header(&data)
+}
+
+/// Results with the variables the raw file header at the start of `data` declares, and empty
+/// data arrays; reads LTspice's UTF-16LE headers and ngspice's ASCII ones
+pub fn parse_header(data: &[u8]) -> Result<SimulationResults, String> {
+    let text = if data.get(1) == Some(&0) {
+        UTF_16LE.decode(data).0
+    } else {
+        String::from_utf8_lossy(data)
+    };
+
+    let mut plot_name = "";
+    let mut points = None;
+    let mut variables = Vec::new();
+    let mut in_variables = false;
+    let mut complete = false;
+    for line in text.lines() {
+        let line = line.trim();
+        if let Some(name) = line.strip_prefix("Plotname:") {
+            plot_name = name;
+        } else if let Some(count) = line.strip_prefix("No. Points:") {
+            points = count.trim().parse::<usize>().ok();
+        } else if line == "Variables:" {
+            in_variables = true;
+        } else if line == "Values:" || line == "Binary:" {
+            complete = true;
+            break;
+        } else if in_variables && !line.is_empty() {
+            // "0\ttime\ttime", tab separated by LTspice and space separated by ngspice
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            if parts.len() >= 3 {
+                variables.push(RawVariable { name: parts[1].to_string(), var_type: parts[2].to_string() });
+            }
+        }
+    }
+
+    if !complete {
+        return Err(format!("The raw file header is cut short or longer than {} MB", MAX_HEADER_BYTES / (1024 * 1024)));
+    }
+    let (Some(points), Some(scale)) = (points, variables.first()) else {
+        return Err("Could not parse raw file header".to_string());
+    };
+    if points == 0 {
+        return Err("The raw file has no data points; the simulation produced no output".to_string());
+    }
+
+    let analysis_type = simulator::plot_analysis_type(plot_name);
+    let traces = variables
+        .iter()
+        .skip(1)
+        .map(|variable| Trace {
+            name: variable.name.clone(),
+            data: Vec::new(),
+            unit: simulator::variable_unit(&variable.var_type, analysis_type).to_string(),
+            phase: None,
+            raw_name: None,
+        })
+        .collect();
+    Ok(SimulationResults {
+        time: Vec::new(),
+        traces,
+        analysis_type: analysis_type.to_string(),
+        x_axis_label: Some(scale.name.to_lowercase()),
+        x_axis_unit: simulator::axis_unit(&scale.var_type, analysis_type),
+        scalar_results: None,
+        device_operating_points: BTreeMap::new(),
+        truncated: None,
+        plot_hints: None,
+        raw_header: Some(RawHeader { points, variables }),
+    })
+}
+
+/// `results` with their data arrays emptied, as a peek returns them
+/// ngspice's shared library hands vectors over without their declared types, so those are
+/// read back from the units
+#[cfg(any(feature = "libngspice", test))]
+pub fn without_data(mut results: SimulationResults) -> SimulationResults {
+    let scale = RawVariable {
+        name: results.x_axis_label.clone().unwrap_or_else(|| "time".to_string()),
+        var_type: unit_type(results.x_axis_unit.as_deref().unwrap_or_default()).to_string(),
+    };
+    let variables = std::iter::once(scale)
+        .chain(results.traces.iter().map(|trace| RawVariable {
+            name: trace.name.clone(),
+            var_type: unit_type(&trace.unit).to_string(),
+        }))
+        .collect();
+    results.raw_header = Some(RawHeader { points: results.time.len(), variables });
+    results.time = Vec::new();
+    for trace in &mut results.traces {
+        trace.data = Vec::new();
+        trace.phase = None;
+    }
+    results
+}
+
+/// Raw file variable type of a trace `unit`
+#[cfg(any(feature = "libngspice", test))]
+fn unit_type(unit: &str) -> &'static str {
+    match unit {
+        "s" => "time",
+        "Hz" => "frequency",
+        "V" => "voltage",
+        "A" => "current",
+        "V/√Hz" => "voltage-density",
+        "A/√Hz" => "current-density",
+        _ => "notype",
+    }
+}
+
+/// `netlist` with each `.tran` cut to its first timestep, for a fast peek; None when there is
+/// no `.tran` whose times are plain numbers
+pub fn shorten_transient(netlist: &str) -> Option<String> {
+    let mut shortened = false;
+    let lines: Vec<String> = netlist
+        .lines()
+        .map(|line| match shortened_tran(line) {
+            Some(tran) => {
+                shortened = true;
+                tran
+            }
+            None => line.to_string(),
+        })
+        .collect();
+    shortened.then(|| lines.join("\n"))
+}
+
+/// `.tran <step> <stop> [start [max step]] [modifiers]` stopping at `step`, or at a small
+/// fraction of the stop time when there is no step; the start time and max step are dropped
+fn shortened_tran(line: &str) -> Option<String> {
+    let code = line.split(';').next().unwrap_or_default();
+    let mut tokens = code.split_whitespace();
+    if !tokens.next()?.eq_ignore_ascii_case(".tran") {
+        return None;
+    }
+    let args: Vec<&str> = tokens.collect();
+    let times: Vec<f64> = args.iter().map_while(|arg| netlist::parse_spice_number(arg)).collect();
+    let modifiers = &args[times.len()..];
+    // Times given as parameters, like {tstop}, can't be shortened
+    if !modifiers.iter().all(|modifier| modifier.chars().all(|c| c.is_ascii_alphabetic())) {
+        return None;
+    }
+
+    let mut tran = match times.as_slice() {
+        [] => return None,
+        [stop] => format!(".tran {:e}", stop * FAST_PEEK_STOP_FRACTION),
+        [step, ..] if *step > 0.0 => format!(".tran {} {}", args[0], args[0]),
+        [_, stop, ..] => format!(".tran {} {:e}", args[0], stop * FAST_PEEK_STOP_FRACTION),
+    };
+    for modifier in modifiers {
+        if !SLOW_TRAN_MODIFIERS.contains(&modifier.to_lowercase().as_str()) {
+            tran.push(' ');
+            tran.push_str(modifier);
+        }
+    }
+    Some(tran)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NGSPICE_HEADER: &str = "Title: * rc filter\nDate: Thu Jan  1 00:00:00  2025\nPlotname: AC Analysis\nFlags: complex\nNo. Variables: 3\nNo. Points: 41\nVariables:\n\t0\tfrequency\tfrequency grid=3\n\t1\tv(out)\tvoltage\n\t2\tv1#branch\tcurrent\nValues:\n 0\t1.0e+00,0.0e+00\n";
+
+    /// An LTspice binary raw file: UTF-16LE header, then float data
+    fn ltspice_raw(header: &str) -> Vec<u8> {
+        let mut data: Vec<u8> = header.encode_utf16().flat_map(|c| c.to_le_bytes()).collect();
+        data.extend((0..64u8).map(|i| i.wrapping_mul(37)));
+        data
+    }
+
+    #[test]
+    fn test_parse_ngspice_header() {
+        let results = parse_header(NGSPICE_HEADER.as_bytes()).unwrap();
+        assert_eq!(results.analysis_type, "ac");
+        assert_eq!(results.x_axis_label.as_deref(), Some("frequency"));
+        assert_eq!(results.x_axis_unit.as_deref(), Some("Hz"));
+        assert!(results.time.is_empty());
+        let traces: Vec<(&str, &str)> = results.traces.iter().map(|t| (t.name.as_str(), t.unit.as_str())).collect();
+        assert_eq!(traces, [("v(out)", "V"), ("v1#branch", "A")]);
+        assert!(results.traces.iter().all(|trace| trace.data.is_empty() && trace.phase.is_none()));
+
+        let header = results.raw_header.unwrap();
+        assert_eq!(header.points, 41);
+        let types: Vec<&str> = header.variables.iter().map(|v| v.var_type.as_str()).collect();
+        assert_eq!(types, ["frequency", "voltage", "current"]);
+    }
+
+    #[test]
+    fn test_parse_ltspice_header() {
+        let raw = ltspice_raw(
+            "Title: * C:\\circuits\\rc.asc\nDate: Thu Jan 01 00:00:00 2025\nPlotname: Transient Analysis\nFlags: real forward\nNo. Variables: 3\nNo. Points:         1234\nOffset:   0.0000000000000000e+000\nCommand: Linear Technology Corporation LTspice XVII\nVariables:\n\t0\ttime\ttime\n\t1\tV(out)\tvoltage\n\t2\tI(R1)\tdevice_current\nBinary:\n",
+        );
+        let results = parse_header(&raw).unwrap();
+        assert_eq!(results.analysis_type, "transient");
+        assert_eq!(results.x_axis_unit.as_deref(), Some("s"));
+        assert_eq!(results.traces.len(), 2);
+        assert_eq!(results.traces[1].unit, "A");
+        let header = results.raw_header.unwrap();
+        assert_eq!(header.points, 1234);
+        assert_eq!(header.variables[2], RawVariable { name: "I(R1)".to_string(), var_type: "device_current".to_string() });
+    }
+
+    #[test]
+    fn test_parse_header_failures() {
+        let cut = &NGSPICE_HEADER[..NGSPICE_HEADER.find("Variables:\n").unwrap()];
+        assert!(parse_header(cut.as_bytes()).unwrap_err().contains("cut short"));
+        assert_eq!(parse_header(b"Title: x\nBinary:\n").unwrap_err(), "Could not parse raw file header");
+        let empty = NGSPICE_HEADER.replace("No. Points: 41", "No. Points: 0");
+        assert!(parse_header(empty.as_bytes()).unwrap_err().contains("no data points"));
+    }
+
+    #[test]
+    fn test_read_header_of_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("circuit.raw");
+        std::fs::write(&path, NGSPICE_HEADER).unwrap();
+        assert_eq!(read_header(&path).unwrap().raw_header.unwrap().points, 41);
+        assert!(read_header(&dir.path().join("missing.raw")).is_err());
+    }
+
+    #[test]
+    fn test_without_data() {
+        let results = SimulationResults {
+            time: vec![0.0, 1e-6, 2e-6],
+            traces: vec![Trace { name: "V(out)".to_string(), data: vec![0.0, 0.5, 1.0], unit: "V".to_string(), phase: None, raw_name: None }],
+            analysis_type: "transient".to_string(),
+            x_axis_label: Some("time".to_string()),
+            x_axis_unit: Some("s".to_string()),
+            scalar_results: None,
+            device_operating_points: BTreeMap::new(),
+            truncated: None,
+            plot_hints: None,
+            raw_header: None,
+        };
+        let peeked = without_data(results);
+        assert!(peeked.time.is_empty() && peeked.traces[0].data.is_empty());
+        let header = peeked.raw_header.unwrap();
+        assert_eq!(header.points, 3);
+        let variables: Vec<(&str, &str)> = header.variables.iter().map(|v| (v.name.as_str(), v.var_type.as_str())).collect();
+        assert_eq!(variables, [("time", "time"), ("V(out)", "voltage")]);
+    }
+
+    #[test]
+    fn test_shorten_transient() {
+        let netlist = "* RC\nV1 in 0 PULSE(0 1 0 1n 1n 5u 10u)\nR1 in out 1k\n.tran 1u 10m\n.end";
+        assert_eq!(
+            shorten_transient(netlist).unwrap(),
+            "* RC\nV1 in 0 PULSE(0 1 0 1n 1n 5u 10u)\nR1 in out 1k\n.tran 1u 1u\n.end"
+        );
+
+        // Start time and max step are dropped, modifiers kept, except steady
+        assert_eq!(shortened_tran(".TRAN 10n 5m 1m 1n UIC steady ; settle").unwrap(), ".tran 10n 10n UIC");
+        // Without a timestep, a thousandth of the stop time
+        assert_eq!(shortened_tran(".tran 10m").unwrap(), ".tran 1e-5");
+        assert_eq!(shortened_tran(".tran 0 2 0 1m startup").unwrap(), ".tran 0 2e-3 startup");
+    }
+
+    #[test]
+    fn test_shorten_transient_leaves_what_it_cannot_read() {
+        assert_eq!(shortened_tran(".tran 1u {tstop}"), None);
+        assert_eq!(shortened_tran(".tran {tstep} 10m"), None);
+        assert_eq!(shortened_tran("* .tran 1u 10m"), None);
+        assert_eq!(shortened_tran(".tran"), None);
+        assert_eq!(shorten_transient("* AC\n.ac dec 10 1 1meg\n.end"), None);
+    }
+}
@@ -0,0 +1,243 @@
+// Copyright (c) 2024-2025 Wanyeki Technologies LLC. All rights reserved.
+// This source code is licensed under the proprietary license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Plot hints for a result: the x axis scale, what each trace measures and which traces to show
+//! first
+//!
+//! A transient voltage counts as digital when nearly all of its samples sit at one of two levels;
+//! the few in between are its edges. Traces the netlist asks for with `.plot` or `.probe` are
+//! matched to result traces by name, with ngspice's `vdb(out)` style output functions read as the
+//! node voltage or branch current they are taken of.
+
+use std::collections::BTreeMap;
+
+use crate::netlist;
+use crate::protocol::{AxisScale, PlotHints, SimulationResults, Trace, TraceGroup};
+
+/// How far from a level, as a fraction of the swing between the two, a sample still counts as at it
+const DIGITAL_LEVEL_TOLERANCE: f64 = 0.05;
+
+/// Fraction of samples that must sit at one of the two levels; the rest are edges
+const DIGITAL_SETTLED_FRACTION: f64 = 0.9;
+
+/// Fewest samples a trace needs before it can be called digital
+const DIGITAL_MIN_POINTS: usize = 4;
+
+/// Plot hints for `results` of running `netlist`
+pub fn compute(results: &SimulationResults, netlist: &str) -> PlotHints {
+    let trace_groups: BTreeMap<String, TraceGroup> = results
+        .traces
+        .iter()
+        .map(|trace| (trace.name.clone(), group(trace, &results.analysis_type)))
+        .collect();
+
+    let mut default_selection = Vec::new();
+    for variable in netlist::parse(netlist).plotted {
+        if let Some(trace) = find_plotted(&results.traces, &variable) {
+            if !default_selection.contains(&trace.name) {
+                default_selection.push(trace.name.clone());
+            }
+        }
+    }
+    if default_selection.is_empty() {
+        default_selection = results
+            .traces
+            .iter()
+            .filter(|trace| matches!(trace_groups[&trace.name], TraceGroup::Voltage | TraceGroup::Digital))
+            .map(|trace| trace.name.clone())
+            .collect();
+    }
+
+    PlotHints { x_axis_scale: axis_scale(&results.analysis_type), trace_groups, default_selection }
+}
+
+/// Frequency sweeps span decades, so they are read on a log axis
+fn axis_scale(analysis_type: &str) -> AxisScale {
+    match analysis_type {
+        "ac" | "noise" => AxisScale::Log,
+        _ => AxisScale::Linear,
+    }
+}
+
+/// Group of `trace` from its unit, or its name when the unit is unknown
+fn group(trace: &Trace, analysis_type: &str) -> TraceGroup {
+    let name = trace.original_name().to_lowercase();
+    let group = if trace.unit == "W" || name.starts_with("p(") {
+        TraceGroup::Power
+    } else if trace.unit.starts_with('A') || name.starts_with("i(") || name.starts_with("ix(") || name.ends_with("#branch") {
+        TraceGroup::Current
+    } else if trace.unit.starts_with('V') || name.starts_with("v(") {
+        TraceGroup::Voltage
+    } else {
+        TraceGroup::Other
+    };
+    if group == TraceGroup::Voltage && analysis_type == "transient" && is_digital(&trace.data) {
+        TraceGroup::Digital
+    } else {
+        group
+    }
+}
+
+/// Whether nearly all of `data` sits at its minimum or its maximum, and both levels are held
+fn is_digital(data: &[f64]) -> bool {
+    let finite: Vec<f64> = data.iter().copied().filter(|value| value.is_finite()).collect();
+    if finite.len() < DIGITAL_MIN_POINTS {
+        return false;
+    }
+    let low = finite.iter().copied().fold(f64::INFINITY, f64::min);
+    let high = finite.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+    let swing = high - low;
+    // A constant isn't a signal, and a swing lost in rounding noise isn't a logic level
+    if swing <= f64::EPSILON * high.abs().max(low.abs()).max(1.0) * 1e6 {
+        return false;
+    }
+
+    let tolerance = swing * DIGITAL_LEVEL_TOLERANCE;
+    let at_low = finite.iter().filter(|value| **value - low <= tolerance).count();
+    let at_high = finite.iter().filter(|value| high - **value <= tolerance).count();
+    // A ramp or sine passes through both ends too, but only briefly
+    let held = |count: usize| count >= 2 && count as f64 >= finite.len() as f64 * (1.0 - DIGITAL_SETTLED_FRACTION);
+    held(at_low) && held(at_high) && (at_low + at_high) as f64 >= finite.len() as f64 * DIGITAL_SETTLED_FRACTION
+}
+
+/// The trace a `.plot`/`.probe` variable refers to, e.g. `V(out)` for `vdb(out)`, and for
+/// `i(x)` ngspice's `x#branch` or `@x[i]` when there is no `I(x)`
+fn find_plotted<'a>(traces: &'a [Trace], variable: &str) -> Option<&'a Trace> {
+    let signal = netlist::plotted_signal(variable);
+    let mut names = vec![signal.clone()];
+    if let Some(element) = signal.strip_prefix("i(").and_then(|rest| rest.strip_suffix(')')) {
+        names.extend([format!("{}#branch", element), format!("@{}[i]", element)]);
+    }
+    names.iter().find_map(|name| traces.iter().find(|trace| trace.answers_to(name)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trace(name: &str, unit: &str, data: Vec<f64>) -> Trace {
+        Trace { name: name.to_string(), data, unit: unit.to_string(), phase: None, raw_name: None }
+    }
+
+    fn results(analysis_type: &str, traces: Vec<Trace>) -> SimulationResults {
+        let points = traces.first().map_or(0, |trace| trace.data.len());
+        SimulationResults {
+            time: (0..points).map(|i| i as f64 * 1e-6).collect(),
+            traces,
+            analysis_type: analysis_type.to_string(),
+            x_axis_label: None,
+            x_axis_unit: None,
+            scalar_results: None,
+            device_operating_points: BTreeMap::new(),
+            truncated: None,
+            plot_hints: None,
+            raw_header: None,
+        }
+    }
+
+    /// A 0-5 V clock with a sample on each edge, as a simulator samples it
+    fn clock(points: usize) -> Vec<f64> {
+        (0..points)
+            .map(|i| match i % 25 {
+                0..=11 => 0.0,
+                12 => 1.7,
+                13..=23 => 5.0,
+                _ => 3.4,
+            })
+            .collect()
+    }
+
+    /// A mixed-signal transient: a clocked comparator driving an RC filter
+    fn mixed_signal() -> SimulationResults {
+        let points = 200;
+        let sine = (0..points).map(|i| 2.5 + 2.0 * (i as f64 / 10.0).sin()).collect();
+        let ramp = (0..points).map(|i| i as f64 / points as f64 * 3.3).collect();
+        results(
+            "transient",
+            vec![
+                trace("V(clk)", "V", clock(points)),
+                trace("V(out)", "V", sine),
+                trace("V(ramp)", "V", ramp),
+                trace("V(vdd)", "V", vec![3.3; points]),
+                trace("V(q)", "V", clock(points).iter().map(|v| v / 5.0 * 3.3 + 1e-4).collect()),
+                trace("I(R1)", "A", vec![1e-3; points]),
+                trace("vdd#branch", "", vec![-2e-3; points]),
+                trace("P(U1)", "W", vec![5e-3; points]),
+                trace("temp", "", vec![27.0; points]),
+            ],
+        )
+    }
+
+    #[test]
+    fn test_groups_of_mixed_signal_traces() {
+        let hints = compute(&mixed_signal(), "* Test\n.tran 200u\n.end");
+        assert_eq!(hints.x_axis_scale, AxisScale::Linear);
+        let groups = &hints.trace_groups;
+        assert_eq!(groups["V(clk)"], TraceGroup::Digital);
+        assert_eq!(groups["V(q)"], TraceGroup::Digital);
+        assert_eq!(groups["V(out)"], TraceGroup::Voltage);
+        assert_eq!(groups["V(ramp)"], TraceGroup::Voltage);
+        assert_eq!(groups["V(vdd)"], TraceGroup::Voltage);
+        assert_eq!(groups["I(R1)"], TraceGroup::Current);
+        assert_eq!(groups["vdd#branch"], TraceGroup::Current);
+        assert_eq!(groups["P(U1)"], TraceGroup::Power);
+        assert_eq!(groups["temp"], TraceGroup::Other);
+
+        // Without .plot or .probe, the node voltages are shown first
+        assert_eq!(hints.default_selection, ["V(clk)", "V(out)", "V(ramp)", "V(vdd)", "V(q)"]);
+    }
+
+    #[test]
+    fn test_is_digital() {
+        assert!(is_digital(&clock(100)));
+        assert!(!is_digital(&[0.0, 5.0, 0.0]));
+        assert!(!is_digital(&[1.0; 100]));
+        // Two levels held only a moment each, like the ends of a triangle wave
+        let triangle: Vec<f64> = (0..100).map(|i| (i as f64 - 50.0).abs()).collect();
+        assert!(!is_digital(&triangle));
+        // A tiny ripple on a supply isn't a logic signal either
+        let ripple: Vec<f64> = (0..100).map(|i| 3.3 + if i % 2 == 0 { 1e-15 } else { 0.0 }).collect();
+        assert!(!is_digital(&ripple));
+    }
+
+    #[test]
+    fn test_digital_only_in_transient() {
+        let dc = results("dc", vec![trace("V(out)", "V", clock(100))]);
+        assert_eq!(compute(&dc, "").trace_groups["V(out)"], TraceGroup::Voltage);
+    }
+
+    #[test]
+    fn test_default_selection_from_plot_and_probe() {
+        let netlist = "* Test\n.plot tran V(OUT) i(vdd) (0,5)\n.probe I(R1) V(missing)\n.tran 200u\n.end";
+        let hints = compute(&mixed_signal(), netlist);
+        assert_eq!(hints.default_selection, ["V(out)", "vdd#branch", "I(R1)"]);
+    }
+
+    #[test]
+    fn test_ac_hints() {
+        let mut ac = results(
+            "ac",
+            vec![trace("V(out)", "V", vec![1.0, 0.7, 0.1, 0.01]), trace("I(C1)", "A", vec![1e-3, 1e-3, 1e-4, 1e-5])],
+        );
+        ac.traces[0].phase = Some(vec![0.0, -45.0, -84.0, -89.0]);
+        let hints = compute(&ac, "* Test\n.ac dec 10 1 1meg\n.plot ac vdb(out) vp(out)\n.end");
+        assert_eq!(hints.x_axis_scale, AxisScale::Log);
+        assert_eq!(hints.trace_groups["V(out)"], TraceGroup::Voltage);
+        assert_eq!(hints.default_selection, ["V(out)"]);
+
+        let noise = results("noise", vec![trace("V(onoise)", "V/√Hz", vec![1e-9; 4])]);
+        let hints = compute(&noise, "");
+        assert_eq!(hints.x_axis_scale, AxisScale::Log);
+        assert_eq!(hints.trace_groups["V(onoise)"], TraceGroup::Voltage);
+    }
+
+    #[test]
+    fn test_hints_serialization() {
+        let hints = compute(&results("ac", vec![trace("V(out)", "V", vec![1.0])]), "");
+        let json = serde_json::to_value(&hints).unwrap();
+        assert_eq!(json["xAxisScale"], "log");
+        assert_eq!(json["traceGroups"]["V(out)"], "voltage");
+        assert_eq!(json["defaultSelection"][0], "V(out)");
+    }
+}
@@ -0,0 +1,119 @@
+// Copyright (c) 2024-2025 Wanyeki Technologies LLC. All rights reserved.
+// This source code is licensed under the proprietary license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Rounding of simulation results to the precision a request asked for with `precision`
+//!
+//! A sample like `0.30000000000000004` takes three times the characters of `0.3`, and samples are
+//! most of a waveform payload. Rounding to a few significant digits lets the serializer's
+//! shortest-representation output drop the rest. Results are rounded last, just before the
+//! response is sent, so Monte Carlo statistics, stored results and exports all see full precision.
+
+use crate::protocol::SimulationResults;
+
+/// Most significant digits a request may ask for; an f64 holds no more
+pub const MAX_PRECISION: u8 = 17;
+
+/// Digits the x axis keeps beyond `precision`, so closely spaced points stay distinct and in order
+pub const AXIS_GUARD_DIGITS: u8 = 3;
+
+/// Powers of ten that are exact in an f64, so scaling by them adds no error of its own
+const POWERS_OF_TEN: [f64; 23] = [
+    1e0, 1e1, 1e2, 1e3, 1e4, 1e5, 1e6, 1e7, 1e8, 1e9, 1e10, 1e11, 1e12, 1e13, 1e14, 1e15, 1e16, 1e17, 1e18,
+    1e19, 1e20, 1e21, 1e22,
+];
+
+/// `value` rounded to `digits` significant digits
+/// Scales by an exact power of ten and rounds, falling back to formatting for magnitudes out of
+/// the table's reach; zero, infinities and NaN are returned as is
+pub fn round_significant(value: f64, digits: u8) -> f64 {
+    if value == 0.0 || !value.is_finite() || digits >= MAX_PRECISION {
+        return value;
+    }
+    let digits = digits.max(1);
+    let magnitude = value.abs().log10().floor() as i32;
+    let shift = digits as i32 - 1 - magnitude;
+    match POWERS_OF_TEN.get(shift.unsigned_abs() as usize) {
+        Some(&scale) if shift >= 0 => (value * scale).round() / scale,
+        Some(&scale) => (value / scale).round() * scale,
+        None => format!("{:.*e}", digits as usize - 1, value).parse().unwrap_or(value),
+    }
+}
+
+/// Round every trace sample of `results` to `precision` significant digits, and the x axis to
+/// `precision` plus `AXIS_GUARD_DIGITS`
+pub fn round_results(results: &mut SimulationResults, precision: u8) {
+    let axis_digits = precision.saturating_add(AXIS_GUARD_DIGITS);
+    for value in &mut results.time {
+        *value = round_significant(*value, axis_digits);
+    }
+    for trace in &mut results.traces {
+        for value in trace.data.iter_mut().chain(trace.phase.iter_mut().flatten()) {
+            *value = round_significant(*value, precision);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::Trace;
+    use std::collections::BTreeMap;
+
+    fn synthetic_results(points: usize) -> SimulationResults {
+        let time: Vec<f64> = (0..points).map(|i| i as f64 * 1e-6 / 3.0).collect();
+        let data = time.iter().map(|t| (t * 2e6 * std::f64::consts::PI).sin() * 3.3).collect();
+        let phase = time.iter().map(|t| t * 1e8 / 7.0).collect();
+        SimulationResults {
+            time,
+            traces: vec![Trace { name: "V(out)".to_string(), data, unit: "V".to_string(), phase: Some(phase), raw_name: None }],
+            analysis_type: "tran".to_string(),
+            x_axis_label: None,
+            x_axis_unit: None,
+            scalar_results: None,
+            device_operating_points: BTreeMap::new(),
+            truncated: None,
+            plot_hints: None,
+            raw_header: None,
+        }
+    }
+
+    #[test]
+    fn test_round_significant() {
+        assert_eq!(round_significant(0.1 + 0.2, 3), 0.3);
+        assert_eq!(round_significant(123456.0, 2), 120000.0);
+        assert_eq!(round_significant(-0.000123456, 3), -0.000123);
+        assert_eq!(round_significant(2.6e-12, 1), 3e-12);
+        assert_eq!(round_significant(9.996, 3), 10.0);
+        assert_eq!(round_significant(1.0 / 3.0, 0), 0.3);
+        // Out of the power table's range
+        assert_eq!(round_significant(1.23456e-300, 3), 1.23e-300);
+        assert_eq!(round_significant(6.02214076e200, 4), 6.022e200);
+        // Left alone
+        assert_eq!(round_significant(1.0 / 3.0, MAX_PRECISION), 1.0 / 3.0);
+        assert_eq!(round_significant(0.0, 3), 0.0);
+        assert!(round_significant(f64::NAN, 3).is_nan());
+        assert_eq!(round_significant(f64::NEG_INFINITY, 3), f64::NEG_INFINITY);
+    }
+
+    #[test]
+    fn test_rounded_results_are_smaller() {
+        let full = synthetic_results(2000);
+        let mut rounded = full.clone();
+        round_results(&mut rounded, 4);
+
+        let full_size = serde_json::to_string(&full).unwrap().len();
+        let rounded_size = serde_json::to_string(&rounded).unwrap().len();
+        assert!(rounded_size * 2 < full_size, "{} vs {} bytes", rounded_size, full_size);
+
+        // Samples are within half a unit of the fourth digit; phase is rounded too
+        for (rounded, full) in rounded.traces[0].data.iter().zip(&full.traces[0].data) {
+            assert!((rounded - full).abs() <= full.abs() * 5e-4, "{} vs {}", rounded, full);
+        }
+        assert_eq!(rounded.traces[0].phase.as_ref().unwrap()[1], round_significant(1e2 / 21.0, 4));
+
+        // The axis keeps its guard digits, so neighbouring points stay in order
+        assert_eq!(rounded.time[1], 3.333333e-7);
+        assert!(rounded.time.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+}
@@ -0,0 +1,260 @@
+// Copyright (c) 2024-2025 Wanyeki Technologies LLC. All rights reserved.
+// This source code is licensed under the proprietary license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Current probes: a 0 V source in series with a component, so its current can be plotted
+//!
+//! ngspice only saves currents through voltage sources. For a probe on `R5 a b 1k` the netlist
+//! gets `Vprobe_R5 a probe_R5 0` and `R5 probe_R5 b 1k`; after the run the source's current is
+//! returned as `I(R5)`, positive into the component's first terminal, and the extra node is dropped.
+
+use crate::protocol::SimulationResults;
+
+/// Name of the 0 V source inserted for `component`
+fn probe_source(component: &str) -> String {
+    format!("Vprobe_{}", component)
+}
+
+/// Current of the probe source for `component`, as saved before it is renamed `I(<component>)`
+pub fn probe_current(component: &str) -> String {
+    format!("I({})", probe_source(component.trim()))
+}
+
+/// Node between the probe source and the component
+fn probe_node(component: &str) -> String {
+    format!("probe_{}", component)
+}
+
+/// Line of the top-level element named `component`, with the line and token index of its
+/// first node, which may be on a `+` continuation line
+fn find_first_node(lines: &[&str], component: &str) -> Option<(usize, usize, usize)> {
+    let mut in_subckt = false;
+    for (index, line) in lines.iter().enumerate() {
+        let mut tokens = line.split_whitespace();
+        let Some(first) = tokens.next() else { continue };
+        let keyword = first.to_lowercase();
+        if keyword == ".subckt" {
+            in_subckt = true;
+        } else if keyword == ".ends" {
+            in_subckt = false;
+        } else if keyword == ".end" {
+            break;
+        }
+        if in_subckt || !keyword.eq_ignore_ascii_case(component) {
+            continue;
+        }
+
+        // The node is the next token, here or on a following continuation line
+        if tokens.next().is_some() {
+            return Some((index, index, 1));
+        }
+        for (offset, continuation) in lines[index + 1..].iter().enumerate() {
+            let Some(rest) = continuation.trim_start().strip_prefix('+') else {
+                break;
+            };
+            if rest.split_whitespace().next().is_some() {
+                // Counting the `+` itself, or not when it is written together with the node
+                let token = if continuation.split_whitespace().next() == Some("+") { 1 } else { 0 };
+                return Some((index, index + 1 + offset, token));
+            }
+        }
+        return None;
+    }
+    None
+}
+
+/// Replace the `token`-th whitespace-separated token of `line`, keeping the rest as written
+fn replace_token(line: &str, token: usize, replacement: &str) -> String {
+    let mut start = 0;
+    for (index, word) in line.split_whitespace().enumerate() {
+        let offset = start + line[start..].find(word).unwrap_or(0);
+        if index == token {
+            // A node written together with its `+` keeps the `+`
+            let (prefix, word) = match word.strip_prefix('+') {
+                Some(node) if token == 0 => ("+", node),
+                _ => ("", word),
+            };
+            return format!("{}{}{}{}", &line[..offset], prefix, replacement, &line[offset + prefix.len() + word.len()..]);
+        }
+        start = offset + word.len();
+    }
+    line.to_string()
+}
+
+/// Node at `token` on `line`, without a leading `+`
+fn token_at(line: &str, token: usize) -> Option<&str> {
+    let word = line.split_whitespace().nth(token)?;
+    Some(word.strip_prefix('+').unwrap_or(word))
+}
+
+/// Split the first node of each component and insert its probe source
+/// Errors, one per probe that can't be added, name the component and the reason
+pub fn insert_current_probes(netlist: &str, components: &[String]) -> Result<String, Vec<String>> {
+    let mut lines: Vec<String> = netlist.lines().map(str::to_string).collect();
+    let mut errors = Vec::new();
+    let mut insertions: Vec<(usize, String)> = Vec::new();
+    let mut seen: Vec<String> = Vec::new();
+
+    for component in components {
+        let component = component.trim();
+        if seen.iter().any(|done| done.eq_ignore_ascii_case(component)) {
+            continue;
+        }
+        seen.push(component.to_string());
+
+        if component.is_empty() || !component.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            errors.push(format!("'{}' is not a component reference", component));
+            continue;
+        }
+        if component.starts_with(['K', 'k']) {
+            errors.push(format!("{}: a coupling statement has no terminals to probe", component));
+            continue;
+        }
+        let borrowed: Vec<&str> = lines.iter().map(String::as_str).collect();
+        let Some((element, line, token)) = find_first_node(&borrowed, component) else {
+            errors.push(format!("{}: no such component in the netlist", component));
+            continue;
+        };
+        let Some(node) = token_at(&lines[line], token).map(str::to_string) else {
+            errors.push(format!("{}: no terminals found", component));
+            continue;
+        };
+
+        lines[line] = replace_token(&lines[line], token, &probe_node(component));
+        insertions.push((element, format!("{} {} {} 0", probe_source(component), node, probe_node(component))));
+    }
+
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    // From the bottom up so earlier indices stay valid
+    insertions.sort_by_key(|(index, _)| std::cmp::Reverse(*index));
+    for (index, source) in insertions {
+        lines.insert(index, source);
+    }
+    let mut rewritten = lines.join("\n");
+    if netlist.ends_with('\n') {
+        rewritten.push('\n');
+    }
+    Ok(rewritten)
+}
+
+/// Trace names without case, spaces, or the `v(...)` around a node name
+fn trace_key(name: &str) -> String {
+    let name: String = name.chars().filter(|c| !c.is_whitespace()).collect::<String>().to_lowercase();
+    match name.strip_prefix("v(").and_then(|node| node.strip_suffix(')')) {
+        Some(node) => node.to_string(),
+        None => name,
+    }
+}
+
+/// Return each probe's current as `I(<component>)` and drop its extra node
+/// LTspice's own current trace for a probed component is replaced by the probe's
+pub fn rename_probe_traces(results: &mut SimulationResults, components: &[String]) {
+    for component in components {
+        let component = component.trim();
+        let source = probe_source(component).to_lowercase();
+        let current_names = [format!("i({})", source), format!("{}#branch", source)];
+        let current = format!("I({})", component);
+        let node = probe_node(component).to_lowercase();
+
+        let Some(probe) = results.traces.iter().position(|trace| current_names.contains(&trace_key(&trace.name))) else {
+            continue;
+        };
+        results.traces[probe].name = current.clone();
+        let mut index = 0;
+        results.traces.retain(|trace| {
+            let keep = index == probe || (trace_key(&trace.name) != node && !trace.name.eq_ignore_ascii_case(&current));
+            index += 1;
+            keep
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::Trace;
+    use std::collections::BTreeMap;
+
+    fn probes(components: &[&str]) -> Vec<String> {
+        components.iter().map(|component| component.to_string()).collect()
+    }
+
+    #[test]
+    fn test_insert_current_probes() {
+        let netlist = "* RC\nV1 in 0 PULSE(0 1 0 1n 1n 1m 2m)\nR5 in out 1k ; series\nC1 out 0 1u\n.tran 5m\n.end\n";
+        let rewritten = insert_current_probes(netlist, &probes(&["r5", "C1"])).unwrap();
+        assert_eq!(
+            rewritten,
+            "* RC\nV1 in 0 PULSE(0 1 0 1n 1n 1m 2m)\nVprobe_r5 in probe_r5 0\nR5 probe_r5 out 1k ; series\n\
+             Vprobe_C1 out probe_C1 0\nC1 probe_C1 0 1u\n.tran 5m\n.end\n"
+        );
+
+        // Listed twice, or not at all
+        assert_eq!(insert_current_probes(netlist, &probes(&["R5", "R5"])).unwrap().matches("Vprobe_R5").count(), 1);
+        assert_eq!(insert_current_probes(netlist, &[]).unwrap(), netlist);
+    }
+
+    #[test]
+    fn test_probe_on_continuation_line() {
+        let netlist = "* cont\nR5\n+ a b\n+ 1k\nX1 a\n+b sub\n.subckt sub p n\nR5 p n 1\n.ends\n.end";
+        let rewritten = insert_current_probes(netlist, &probes(&["R5", "X1"])).unwrap();
+        assert_eq!(
+            rewritten,
+            "* cont\nVprobe_R5 a probe_R5 0\nR5\n+ probe_R5 b\n+ 1k\nVprobe_X1 a probe_X1 0\nX1 probe_X1\n+b sub\n\
+             .subckt sub p n\nR5 p n 1\n.ends\n.end"
+        );
+
+        let netlist = "* cont\nQ1\n+a b c 2n3904\n.end";
+        let rewritten = insert_current_probes(netlist, &probes(&["Q1"])).unwrap();
+        assert_eq!(rewritten, "* cont\nVprobe_Q1 a probe_Q1 0\nQ1\n+probe_Q1 b c 2n3904\n.end");
+    }
+
+    #[test]
+    fn test_probe_errors_are_per_component() {
+        let netlist = "* T\nR1 a 0 1k\nL1 a 0 1m\nL2 b 0 1m\nK1 L1 L2 0.9\n.subckt sub p n\nR9 p n 1\n.ends\n.end";
+        let errors = insert_current_probes(netlist, &probes(&["R1", "R9", "K1", "R1 a", "R7"])).unwrap_err();
+        assert_eq!(
+            errors,
+            vec![
+                "R9: no such component in the netlist",
+                "K1: a coupling statement has no terminals to probe",
+                "'R1 a' is not a component reference",
+                "R7: no such component in the netlist",
+            ]
+        );
+        // Nothing after .end is searched
+        assert!(insert_current_probes("* T\n.end\nR1 a 0 1", &probes(&["R1"])).is_err());
+    }
+
+    #[test]
+    fn test_rename_probe_traces() {
+        let trace = |name: &str, unit: &str| Trace { name: name.to_string(), data: vec![1.0], unit: unit.to_string(), phase: None, raw_name: None };
+        let mut results = SimulationResults {
+            time: vec![0.0],
+            traces: vec![
+                trace("v(in)", "V"),
+                trace("v(probe_r5)", "V"),
+                trace("v(out)", "V"),
+                trace("i(vprobe_r5)", "A"),
+                trace("I(R5)", "A"),
+                trace("vprobe_c1#branch", "A"),
+                trace("probe_c1", "V"),
+            ],
+            analysis_type: "transient".to_string(),
+            x_axis_label: None,
+            x_axis_unit: None,
+            scalar_results: None,
+            device_operating_points: BTreeMap::new(),
+            truncated: None,
+            plot_hints: None,
+            raw_header: None,
+        };
+
+        rename_probe_traces(&mut results, &probes(&["R5", "C1", "R7"]));
+        let names: Vec<&str> = results.traces.iter().map(|trace| trace.name.as_str()).collect();
+        assert_eq!(names, ["v(in)", "v(out)", "I(R5)", "I(C1)"]);
+        assert_eq!(results.traces[2].unit, "A");
+    }
+}
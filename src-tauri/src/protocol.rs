@@ -4,14 +4,39 @@
 
 //! WebSocket protocol types for communication with the web app
 
+use std::collections::{BTreeMap, HashMap};
+use std::io::Write;
+
+use base64::prelude::{Engine, BASE64_STANDARD};
+use flate2::{write::GzEncoder, Compression};
 use serde::{Deserialize, Serialize};
 
+use crate::resource_budget::BudgetUsage;
+
 /// Simulation trace data
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Trace {
     pub name: String,
     pub data: Vec<f64>,
     pub unit: String,
+    /// Phase in degrees, only present for complex (AC) data
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub phase: Option<Vec<f64>>,
+    /// Name the simulator gave the trace, when `traceAliases` renamed it
+    #[serde(rename = "rawName", default, skip_serializing_if = "Option::is_none")]
+    pub raw_name: Option<String>,
+}
+
+impl Trace {
+    /// Name the simulator gave the trace
+    pub fn original_name(&self) -> &str {
+        self.raw_name.as_deref().unwrap_or(&self.name)
+    }
+
+    /// Whether `name` is the trace's name or, for an aliased trace, its raw name, ignoring case
+    pub fn answers_to(&self, name: &str) -> bool {
+        self.name.eq_ignore_ascii_case(name) || self.raw_name.as_deref().is_some_and(|raw| raw.eq_ignore_ascii_case(name))
+    }
 }
 
 /// Simulation results
@@ -22,6 +47,113 @@ pub struct SimulationResults {
     pub analysis_type: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub x_axis_label: Option<String>,
+    /// Unit of the independent variable from its declared type, e.g. `A` for a current sweep
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub x_axis_unit: Option<String>,
+    /// Named values of analyses that print results instead of plotting them (`.tf`, `.sens`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scalar_results: Option<BTreeMap<String, f64>>,
+    /// Bias point of each semiconductor device, keyed by reference designator (LTspice only)
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub device_operating_points: BTreeMap<String, DeviceOperatingPoint>,
+    /// Set when the raw file was cut short and only its complete points were read
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub truncated: Option<RawTruncation>,
+    /// How the traces are best plotted, derived from the analysis and the netlist's `.plot`/`.probe`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub plot_hints: Option<PlotHints>,
+    /// Point count and declared variables of the raw file, set by `peek` runs, whose data arrays are empty
+    #[serde(rename = "rawHeader", default, skip_serializing_if = "Option::is_none")]
+    pub raw_header: Option<RawHeader>,
+}
+
+/// Suggestions for plotting a result, so clients needn't guess from trace names
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PlotHints {
+    #[serde(rename = "xAxisScale")]
+    pub x_axis_scale: AxisScale,
+    /// Group of each trace, by trace name
+    #[serde(rename = "traceGroups")]
+    pub trace_groups: BTreeMap<String, TraceGroup>,
+    /// Traces to show first: those the netlist's `.plot`/`.probe` name, else the node voltages
+    #[serde(rename = "defaultSelection")]
+    pub default_selection: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AxisScale {
+    Linear,
+    Log,
+}
+
+/// Kind of quantity a trace holds, for sharing a y axis
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TraceGroup {
+    Voltage,
+    Current,
+    Power,
+    /// A voltage that only switches between two levels, such as a logic signal
+    Digital,
+    Other,
+}
+
+/// How much of a raw file was missing, e.g. after the simulator crashed or the disk filled up mid-write
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RawTruncation {
+    /// Points the header declared
+    #[serde(rename = "expectedPoints")]
+    pub expected_points: usize,
+    /// Complete points that were read
+    #[serde(rename = "actualPoints")]
+    pub actual_points: usize,
+    /// Line of the simulator's log that probably explains it
+    #[serde(rename = "likelyCause", default, skip_serializing_if = "Option::is_none")]
+    pub likely_cause: Option<String>,
+}
+
+impl RawTruncation {
+    /// Warning for the response, e.g. "Results are incomplete: the raw file holds 120 of 500 points"
+    pub fn warning(&self) -> String {
+        let mut warning = format!(
+            "Results are incomplete: the raw file holds {} of {} points",
+            self.actual_points, self.expected_points
+        );
+        if let Some(cause) = &self.likely_cause {
+            warning.push_str("; likely cause: ");
+            warning.push_str(cause);
+        }
+        warning
+    }
+}
+
+/// What a raw file holds, read from its header without its data
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RawHeader {
+    /// Points the header declared
+    pub points: usize,
+    /// Every variable, the independent one first
+    pub variables: Vec<RawVariable>,
+}
+
+/// A variable as a raw file declares it, e.g. `V(out)` of type `voltage`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RawVariable {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub var_type: String,
+}
+
+/// Operating point LTspice reports for one device, e.g. Vgs/Vds/Id of a MOSFET
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DeviceOperatingPoint {
+    /// "bjt", "mosfet", "jfet" or "diode"
+    pub kind: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    /// Quantities as LTspice names them, such as `Vbe`, `Ic` or `Gm`
+    pub values: BTreeMap<String, f64>,
 }
 
 /// Agent capabilities
@@ -31,10 +163,36 @@ pub struct AgentCapabilities {
     pub ltspice_available: bool,
     #[serde(rename = "ngspiceAvailable")]
     pub ngspice_available: bool,
+    /// Analyses at least one detected simulator can run; superseded by `analyses`
     #[serde(rename = "supportedAnalyses")]
     pub supported_analyses: Vec<String>,
+    /// Every analysis the agent knows, with the detected simulators that can run it
+    pub analyses: Vec<AnalysisCapability>,
+    /// Accepted `inputFormat` values; `asc` needs LTspice
+    #[serde(rename = "supportedInputFormats")]
+    pub supported_input_formats: Vec<String>,
+    /// Configured ceiling on a simulation's run time, in seconds
     #[serde(rename = "maxSimulationTime")]
-    pub max_simulation_time: u32,
+    pub max_simulation_time: u64,
+    #[serde(rename = "maxBatchSize")]
+    pub max_batch_size: u32,
+    /// `engineOptions` the detected LTspice honours, e.g. "alternateSolver"
+    #[serde(rename = "engineOptions", default)]
+    pub engine_options: Vec<String>,
+    /// Simulation requests may send the netlist as `netlistEncoding: "gzip+base64"`
+    #[serde(rename = "acceptsCompressedNetlist", default)]
+    pub accepts_compressed_netlist: bool,
+}
+
+/// Which detected simulators can run an analysis
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AnalysisCapability {
+    /// e.g. "transient", as listed in `supportedAnalyses`
+    pub name: String,
+    /// Detected simulators that run it; empty if none of them can
+    pub simulators: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub notes: Option<String>,
 }
 
 /// Handshake request from web app
@@ -45,6 +203,12 @@ pub struct HandshakeRequest {
     pub msg_type: String,
     pub origin: String,
     pub version: String,
+    /// Encoding the client wants for every later message
+    #[serde(default)]
+    pub encoding: Encoding,
+    /// Receive every simulation's progress and result, not only those of this connection
+    #[serde(rename = "subscribeResults", default)]
+    pub subscribe_results: bool,
     pub timestamp: u64,
 }
 
@@ -63,24 +227,252 @@ pub struct HandshakeResponse {
     #[serde(rename = "ngspicePath", skip_serializing_if = "Option::is_none")]
     pub ngspice_path: Option<String>,
     pub capabilities: AgentCapabilities,
+    /// Left out when the handshake is rejected
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub onboarding: Option<OnboardingState>,
+    /// Encoding of every later message; the handshake response itself is always JSON
+    pub encoding: Encoding,
+    /// The connection receives every simulation's progress and result; false when it didn't ask
+    /// or `allow_result_subscriptions` is off
+    #[serde(rename = "subscribedResults")]
+    pub subscribed_results: bool,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub error: Option<String>,
 }
 
+/// Body of the plain HTTP `GET /health` on the WebSocket port, for detecting the agent without
+/// opening a WebSocket
+#[derive(Debug, Clone, Serialize)]
+pub struct HealthResponse {
+    /// Always "ok"
+    pub status: String,
+    #[serde(rename = "agentVersion")]
+    pub agent_version: String,
+    /// Every worker slot is taken
+    pub busy: bool,
+    #[serde(rename = "activeSimulations")]
+    pub active_simulations: usize,
+}
+
+/// Wire format for messages after the handshake
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    /// JSON in text frames
+    #[default]
+    Json,
+    /// MessagePack in binary frames
+    Msgpack,
+}
+
 /// Simulation request from web app
 #[derive(Debug, Clone, Deserialize)]
 pub struct SimulationRequest {
     pub id: String,
     #[serde(rename = "type")]
     pub msg_type: String,
+    /// SPICE netlist, or the schematic text when `input_format` is `asc`
     pub netlist: String,
+    #[serde(rename = "inputFormat", default)]
+    pub input_format: InputFormat,
+    /// How `netlist` is encoded; large netlists can be sent gzip-compressed
+    #[serde(rename = "netlistEncoding", default)]
+    pub netlist_encoding: NetlistEncoding,
+    /// Dialect the netlist was written in ("ltspice", "ngspice", "pspice" or "auto"); when set,
+    /// constructs the chosen simulator rejects are rewritten and listed in `translations`
+    #[serde(default)]
+    pub dialect: Option<String>,
+    /// Name of a quality profile from the settings; "fast", "balanced" and "smooth" ship by default
     #[serde(rename = "waveformQuality", default = "default_waveform_quality")]
     pub waveform_quality: String,
     /// Which simulator to use: "ltspice" or "ngspice"
     #[serde(default = "default_simulator")]
     pub simulator: String,
     pub timeout: Option<u64>,
+    /// Run the netlist repeatedly and return only `.meas` statistics instead of waveforms
+    #[serde(rename = "monteCarlo", default)]
+    pub monte_carlo: Option<MonteCarloOptions>,
+    /// Forward simulator output as `simulation_log` messages while it runs (ngspice only)
+    #[serde(rename = "streamLogs", default)]
+    pub stream_logs: bool,
+    /// Directive such as `.op` added when the netlist has no analysis of its own
+    #[serde(rename = "defaultAnalysis", default)]
+    pub default_analysis: Option<String>,
+    /// Wait for a `confirm` message before starting a run estimated to take unusually long
+    #[serde(rename = "confirmLongRuns", default)]
+    pub confirm_long_runs: bool,
+    /// Retry with fallback options when the simulator fails to converge
+    #[serde(rename = "convergenceAssist", default)]
+    pub convergence_assist: bool,
+    /// Return the prepared netlist, copied libraries, command line and temp directory
+    #[serde(rename = "includeDebugInfo", default)]
+    pub include_debug_info: bool,
+    /// Send `results` as base64 gzip JSON in the response's `resultsCompressed`
+    #[serde(default)]
+    pub compress: bool,
+    /// LTspice solver and output switches (LTspice only)
+    #[serde(rename = "engineOptions", default)]
+    pub engine_options: EngineOptions,
+    /// Files written into the simulation's temp dir for this run only, e.g. subcircuits or symbols
+    #[serde(default)]
+    pub attachments: Vec<Attachment>,
+    /// Components whose current is returned as `I(<name>)`, measured through an inserted 0 V source
+    #[serde(rename = "currentProbes", default)]
+    pub current_probes: Option<Vec<String>>,
+    /// When the simulator can't parse the netlist, translate it and run it on the other engine,
+    /// if installed
+    #[serde(rename = "autoRetryOtherEngine", default)]
+    pub auto_retry_other_engine: bool,
+    /// Directories, inside the settings' allowed library roots, LTspice looks for `.include`/`.lib` files in
+    #[serde(rename = "librarySearchPaths", default)]
+    pub library_search_paths: Vec<String>,
+    /// Copy resolved libraries into the temp dir or reference them in place; the settings' default when absent
+    #[serde(rename = "libraryStrategy", default)]
+    pub library_strategy: Option<LibraryStrategy>,
+    /// Keep the raw file after the run so it can be fetched with `get_artifact`
+    #[serde(rename = "keepRaw", default)]
+    pub keep_raw: bool,
+    /// Significant digits to round trace samples to before sending, 1 to 17; the x axis keeps a few more
+    #[serde(default)]
+    pub precision: Option<u8>,
+    /// Send floats as 32-bit in MessagePack frames; JSON text is unaffected
+    #[serde(rename = "f32Results", default)]
+    pub f32_results: bool,
+    /// Send the trace catalog with empty data arrays and keep the results for `get_trace_data`
+    #[serde(rename = "deferTraceData", default)]
+    pub defer_trace_data: bool,
+    /// Signals to save, e.g. `V(out)`, along with any the netlist's `.probe`/`.plot` name; every
+    /// node voltage and current is saved when neither names one
+    #[serde(rename = "saveTraces", default)]
+    pub save_traces: Vec<String>,
+    /// Read only the raw file's header: trace names, types, point count and analysis type, with
+    /// empty data arrays
+    #[serde(default)]
+    pub peek: bool,
+    /// Peek, and have LTspice stop a `.tran` analysis after its first timestep
+    #[serde(rename = "peekFast", default)]
+    pub peek_fast: bool,
+    /// Add no `.backanno` or quality `.options` to the netlist, only the `.save` its output needs
+    #[serde(rename = "noAutoOptions", default)]
+    pub no_auto_options: bool,
+    /// Write a node as WAV audio with LTspice's `.wave` directive, returned in `waveData` (LTspice only)
+    #[serde(rename = "waveOutput", default)]
+    pub wave_output: Option<WaveOutput>,
+    /// Display names for traces, keyed by the simulator's trace name ignoring case, e.g.
+    /// `{"V(n001)": "Output"}`; each renamed trace keeps the original in `rawName`
+    #[serde(rename = "traceAliases", default)]
+    pub trace_aliases: HashMap<String, String>,
     pub timestamp: u64,
+    /// Set on a replay of a stored simulation: the original's request id
+    /// Its netlist is already prepared and is run as is
+    #[serde(skip)]
+    pub replay_of: Option<String>,
+    /// Page origin of the connection that sent it; None for runs started in the agent window
+    #[serde(skip)]
+    pub origin: Option<String>,
+}
+
+/// A file sent along with a simulation request
+#[derive(Debug, Clone, Deserialize)]
+pub struct Attachment {
+    /// File name the netlist or schematic refers to it by
+    pub name: String,
+    #[serde(rename = "contentBase64")]
+    pub content_base64: String,
+}
+
+/// A node to write as WAV audio, for listening to an audio circuit
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct WaveOutput {
+    /// Node whose voltage is written, e.g. `out`, or a signal such as `V(out,in)`; 1 V is full scale
+    pub node: String,
+    /// Samples per second
+    #[serde(rename = "sampleRate")]
+    pub sample_rate: u32,
+    /// Seconds of audio returned, from the start of the transient
+    pub duration: f64,
+}
+
+/// Whitelisted LTspice command-line switches a request may turn on
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+pub struct EngineOptions {
+    /// Use the alternate solver (`-alt`)
+    #[serde(rename = "alternateSolver", default)]
+    pub alternate_solver: bool,
+    /// Write the .raw file as text (`-ascii`)
+    #[serde(rename = "asciiOutput", default)]
+    pub ascii_output: bool,
+}
+
+/// What the `netlist` field of a simulation request holds
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum InputFormat {
+    #[default]
+    Netlist,
+    /// LTspice schematic, converted to a netlist by LTspice before running (LTspice only)
+    Asc,
+}
+
+/// Most bytes a compressed netlist may decompress to
+pub const MAX_NETLIST_BYTES: usize = 128 * 1024 * 1024;
+
+/// How the `netlist` field of a simulation request is encoded
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum NetlistEncoding {
+    /// The text as is
+    #[default]
+    #[serde(rename = "plain")]
+    Plain,
+    /// Gzip-compressed UTF-8 text in standard base64, at most `MAX_NETLIST_BYTES` once decompressed
+    #[serde(rename = "gzip+base64")]
+    GzipBase64,
+}
+
+/// How a library an `.include` or `.lib` resolves to is made available to LTspice
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LibraryStrategy {
+    /// Copied into the simulation's temp dir and included by file name
+    #[default]
+    #[serde(rename = "copy")]
+    Copy,
+    /// Included by its absolute path where it is, so files it includes by relative path are found
+    #[serde(rename = "reference")]
+    Reference,
+}
+
+/// Monte Carlo options for a simulation request
+#[derive(Debug, Clone, Deserialize)]
+pub struct MonteCarloOptions {
+    pub runs: u32,
+    /// `.meas` names whose values are collected from every run
+    pub measurements: Vec<String>,
+    /// Seed for the first run; run N uses seed + N
+    #[serde(default)]
+    pub seed: Option<u64>,
+}
+
+/// Per-run values and summary statistics for one `.meas` result
+#[derive(Debug, Clone, Serialize)]
+pub struct MeasurementStatistics {
+    pub name: String,
+    /// One entry per run, null where the measurement failed
+    pub values: Vec<Option<f64>>,
+    pub mean: Option<f64>,
+    pub stddev: Option<f64>,
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+    #[serde(rename = "failedRuns")]
+    pub failed_runs: u32,
+}
+
+/// Aggregated Monte Carlo results
+#[derive(Debug, Clone, Serialize)]
+pub struct MonteCarloResults {
+    pub runs: u32,
+    #[serde(rename = "completedRuns")]
+    pub completed_runs: u32,
+    pub measurements: Vec<MeasurementStatistics>,
 }
 
 fn default_waveform_quality() -> String {
@@ -91,6 +483,10 @@ fn default_simulator() -> String {
     "ltspice".to_string()
 }
 
+fn is_zero(value: &u32) -> bool {
+    *value == 0
+}
+
 /// Simulation response to web app
 #[derive(Debug, Clone, Serialize)]
 pub struct SimulationResponse {
@@ -101,13 +497,486 @@ pub struct SimulationResponse {
     pub request_id: String,
     pub timestamp: u64,
     pub success: bool,
+    /// Echoes the request's `f32Results`; MessagePack framing writes every float after it as 32-bit,
+    /// so it must come before `results`
+    #[serde(rename = "f32Results", skip_serializing_if = "std::ops::Not::not")]
+    pub f32_results: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub results: Option<SimulationResults>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Machine-readable failure reason, e.g. `SIMULATION_HUNG`
+    #[serde(rename = "errorCode", skip_serializing_if = "Option::is_none")]
+    pub error_code: Option<String>,
+    #[serde(rename = "executionTime")]
+    pub execution_time: u64,
+    pub simulator: String,
+    #[serde(rename = "monteCarlo", skip_serializing_if = "Option::is_none")]
+    pub monte_carlo: Option<MonteCarloResults>,
+    /// Set on a stored response replayed for a repeated request id
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub duplicate: bool,
+    /// Convergence fallback the final run used, e.g. "uic", when `convergenceAssist` retried
+    #[serde(rename = "retriedWith", skip_serializing_if = "Option::is_none")]
+    pub retried_with: Option<String>,
+    /// Compatibility rewrites applied to the netlist for `dialect`
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub translations: Vec<String>,
+    /// What was run, when the request set `includeDebugInfo`
+    #[serde(rename = "debugInfo", skip_serializing_if = "Option::is_none")]
+    pub debug_info: Option<DebugInfo>,
+    /// `results` as base64-encoded gzip JSON, when the request set `compress`
+    #[serde(rename = "resultsCompressed", skip_serializing_if = "Option::is_none")]
+    pub results_compressed: Option<String>,
+    /// Encoding of `resultsCompressed`, "gzip"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub compression: Option<String>,
+    /// With `RATE_LIMITED`: how long until the origin may start another simulation
+    #[serde(rename = "retryAfterMs", skip_serializing_if = "Option::is_none")]
+    pub retry_after_ms: Option<u64>,
+    /// Requested engine options that were ignored, and why
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub warnings: Vec<String>,
+    /// Version of the simulator executable, when it can be read
+    #[serde(rename = "simulatorVersion", skip_serializing_if = "Option::is_none")]
+    pub simulator_version: Option<String>,
+    /// SHA-256 of the simulator executable, as lowercase hex
+    #[serde(rename = "simulatorBinarySha256", skip_serializing_if = "Option::is_none")]
+    pub simulator_binary_sha256: Option<String>,
+    /// Request id of the stored simulation this response replayed
+    #[serde(rename = "replayOf", skip_serializing_if = "Option::is_none")]
+    pub replay_of: Option<String>,
+    /// Engine the simulation was retried on after the requested one couldn't parse the netlist
+    #[serde(rename = "fallbackSimulatorUsed", skip_serializing_if = "Option::is_none")]
+    pub fallback_simulator_used: Option<String>,
+    /// With a failed retry on the other engine: each engine's error, in the order they ran
+    #[serde(rename = "engineErrors", skip_serializing_if = "Vec::is_empty")]
+    pub engine_errors: Vec<EngineError>,
+    /// With `keepRaw`: id to fetch the kept raw file by
+    #[serde(rename = "artifactId", skip_serializing_if = "Option::is_none")]
+    pub artifact_id: Option<String>,
+    /// With `keepRaw`: size of the kept raw file in bytes
+    #[serde(rename = "rawFileSize", skip_serializing_if = "Option::is_none")]
+    pub raw_file_size: Option<u64>,
+    /// With `deferTraceData`: every trace's name, unit and statistics; its data is fetched with `get_trace_data`
+    #[serde(rename = "traceCatalog", skip_serializing_if = "Option::is_none")]
+    pub trace_catalog: Option<Vec<TraceSummary>>,
+    /// With `ANALYSIS_INCOMPLETE`: the node voltages and branch currents of the operating point
+    /// the simulator did compute, for diagnosis
+    #[serde(rename = "operatingPoint", skip_serializing_if = "Option::is_none")]
+    pub operating_point: Option<BTreeMap<String, f64>>,
+    /// With `waveOutput`: the WAV file of the node, base64-encoded
+    #[serde(rename = "waveData", skip_serializing_if = "Option::is_none")]
+    pub wave_data: Option<String>,
+    /// Library files the run used: those the agent resolved, and those the simulator's log says it loaded
+    #[serde(rename = "librariesUsed", skip_serializing_if = "Vec::is_empty")]
+    pub libraries_used: Vec<LibraryUsed>,
+    /// With `AGENT_BUSY`: what the agent is busy with
+    #[serde(rename = "busyInfo", skip_serializing_if = "Option::is_none")]
+    pub busy_info: Option<BusyInfo>,
+    /// Where the time went
+    pub timings: SimulationTimings,
+}
+
+/// The run a simulation was turned away for, so the client can say what it is waiting on
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BusyInfo {
+    /// Request id of the run expected to finish first; a short hash of it if another origin sent it
+    #[serde(rename = "runningRequestId")]
+    pub running_request_id: String,
+    /// Whether `runningRequestId` is a hash rather than the id itself
+    #[serde(rename = "requestIdRedacted")]
+    pub request_id_redacted: bool,
+    #[serde(rename = "elapsedMs")]
+    pub elapsed_ms: u64,
+    /// Extrapolated from the run's progress; absent until it reports any
+    #[serde(rename = "estimatedRemainingMs", skip_serializing_if = "Option::is_none")]
+    pub estimated_remaining_ms: Option<u64>,
+    /// Simulations holding worker slots
+    #[serde(rename = "runningSimulations")]
+    pub running_simulations: usize,
+    /// Simulations waiting for the user to confirm them
+    #[serde(rename = "queueLength")]
+    pub queue_length: usize,
+}
+
+/// A trace held back by `deferTraceData`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TraceSummary {
+    pub name: String,
+    /// Name the simulator gave the trace, when `traceAliases` renamed it
+    #[serde(rename = "rawName", skip_serializing_if = "Option::is_none")]
+    pub raw_name: Option<String>,
+    pub unit: String,
+    pub points: usize,
+    /// Smallest, largest and mean finite sample; None when there are none
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+    pub mean: Option<f64>,
+    /// Whether the trace has phase data (AC analyses)
+    #[serde(rename = "hasPhase")]
+    pub has_phase: bool,
+}
+
+/// How long each step of a simulation took, in milliseconds
+/// Steps that didn't happen, like raw parsing for an in-process ngspice run, are 0
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct SimulationTimings {
+    /// Resolving and copying `.include`/`.lib` files into the temp dir
+    #[serde(rename = "includeResolutionMs")]
+    pub include_resolution_ms: u64,
+    /// Preparing the netlist and writing it out
+    #[serde(rename = "netlistWriteMs")]
+    pub netlist_write_ms: u64,
+    /// Size of the prepared netlist written for the simulator
+    #[serde(rename = "netlistBytes")]
+    pub netlist_bytes: u64,
+    /// Simulator wall time
+    #[serde(rename = "simulatorMs")]
+    pub simulator_ms: u64,
+    #[serde(rename = "rawFileBytes")]
+    pub raw_file_bytes: u64,
+    /// Size of LTspice's `.log` file, or of the output ngspice printed
+    #[serde(rename = "logFileBytes")]
+    pub log_file_bytes: u64,
+    /// Reading the results back from the raw file and log
+    #[serde(rename = "rawParseMs")]
+    pub raw_parse_ms: u64,
+    /// Reading the raw file from disk, part of `rawParseMs`
+    #[serde(rename = "rawReadMs")]
+    pub raw_read_ms: u64,
+    /// Raw file megabytes parsed per second, reading included; left out when there was no raw
+    /// file or it took under a millisecond
+    #[serde(rename = "parseMbPerSec", skip_serializing_if = "Option::is_none")]
+    pub parse_mb_per_sec: Option<f64>,
+    /// Compressing and serializing the response
+    #[serde(rename = "serializationMs")]
+    pub serialization_ms: u64,
+    #[serde(rename = "totalMs")]
+    pub total_ms: u64,
+}
+
+impl SimulationTimings {
+    /// Add the steps of an earlier run of the same request, e.g. on the other engine
+    pub fn absorb(&mut self, earlier: &SimulationTimings) {
+        self.include_resolution_ms += earlier.include_resolution_ms;
+        self.netlist_write_ms += earlier.netlist_write_ms;
+        self.simulator_ms += earlier.simulator_ms;
+        self.raw_parse_ms += earlier.raw_parse_ms;
+        self.raw_read_ms += earlier.raw_read_ms;
+        self.serialization_ms += earlier.serialization_ms;
+    }
+
+    /// Raw file megabytes parsed per second, reading included
+    pub fn parse_throughput(&self) -> Option<f64> {
+        mb_per_sec(self.raw_file_bytes, self.raw_parse_ms)
+    }
+
+    /// Raw file megabytes read from disk per second
+    pub fn raw_read_throughput(&self) -> Option<f64> {
+        mb_per_sec(self.raw_file_bytes, self.raw_read_ms)
+    }
+}
+
+/// Megabytes per second of `bytes` handled in `ms`; None when either is 0
+fn mb_per_sec(bytes: u64, ms: u64) -> Option<f64> {
+    (bytes > 0 && ms > 0).then(|| bytes as f64 / (1024.0 * 1024.0) / (ms as f64 / 1000.0))
+}
+
+impl std::fmt::Display for SimulationTimings {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "includes {} ms, netlist write {} ms, simulator {} ms, raw parse {} ms ({} KB, read {} ms), serialization {} ms, total {} ms",
+            self.include_resolution_ms,
+            self.netlist_write_ms,
+            self.simulator_ms,
+            self.raw_parse_ms,
+            self.raw_file_bytes / 1024,
+            self.raw_read_ms,
+            self.serialization_ms,
+            self.total_ms
+        )
+    }
+}
+
+/// Why one engine failed, when a simulation was tried on both
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct EngineError {
+    pub simulator: String,
+    pub error: String,
+    #[serde(rename = "errorCode", skip_serializing_if = "Option::is_none")]
+    pub error_code: Option<String>,
+}
+
+/// Fingerprint of a simulator executable
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SimulatorBinary {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    pub sha256: String,
+}
+
+/// Longest prepared netlist echoed in `DebugInfo`, in bytes
+pub const MAX_DEBUG_NETLIST_BYTES: usize = 256 * 1024;
+
+/// The netlist, libraries and command the simulator actually ran with
+#[derive(Debug, Clone, Serialize)]
+pub struct DebugInfo {
+    /// Netlist as written to the temp directory, cut at `MAX_DEBUG_NETLIST_BYTES`
+    #[serde(rename = "preparedNetlist")]
+    pub prepared_netlist: String,
+    #[serde(rename = "preparedNetlistTruncated", skip_serializing_if = "std::ops::Not::not")]
+    pub prepared_netlist_truncated: bool,
+    #[serde(rename = "includedLibraries")]
+    pub included_libraries: Vec<IncludedLibrary>,
+    #[serde(rename = "commandLine")]
+    pub command_line: String,
+    #[serde(rename = "tempDir")]
+    pub temp_dir: String,
+    /// Elements and nodes of the prepared netlist, read from all of it even when `preparedNetlist` is cut
+    #[serde(rename = "netlistSummary")]
+    pub netlist_summary: NetlistInventory,
+    /// Lines the agent added to the netlist, like `.backanno`, `.options` and `.save`
+    #[serde(rename = "injectedDirectives")]
+    pub injected_directives: Vec<String>,
+}
+
+impl DebugInfo {
+    pub fn new(prepared_netlist: &str, included_libraries: Vec<IncludedLibrary>, command_line: String, temp_dir: String) -> Self {
+        let netlist_summary = crate::netlist::parse(prepared_netlist).inventory();
+        let mut end = prepared_netlist.len().min(MAX_DEBUG_NETLIST_BYTES);
+        while !prepared_netlist.is_char_boundary(end) {
+            end -= 1;
+        }
+        Self {
+            prepared_netlist: prepared_netlist[..end].to_string(),
+            prepared_netlist_truncated: end < prepared_netlist.len(),
+            included_libraries,
+            command_line,
+            temp_dir,
+            netlist_summary,
+            injected_directives: Vec::new(),
+        }
+    }
+}
+
+/// Top-level elements and nodes of a netlist, for checking it against the schematic it came from
+/// Elements the agent added, like `currentProbes` sources, are included
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct NetlistInventory {
+    /// Element count by type letter, e.g. `"R": 4`
+    #[serde(rename = "elementCounts")]
+    pub element_counts: BTreeMap<String, usize>,
+    #[serde(rename = "componentCount")]
+    pub component_count: usize,
+    /// Node names in order of first appearance, including ground (`0`)
+    pub nodes: Vec<String>,
+    #[serde(rename = "nodeCount")]
+    pub node_count: usize,
+    /// Reference designators of the subcircuit instances (`X` elements)
+    #[serde(rename = "subcircuitInstances")]
+    pub subcircuit_instances: Vec<String>,
+}
+
+/// Library an `.include` or `.lib` was resolved to
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct IncludedLibrary {
+    /// File name of the library
+    pub name: String,
+    /// Where it was found
+    pub source: String,
+    /// Whether the netlist includes a copy in the temp dir or the file where it is
+    pub strategy: LibraryStrategy,
+    /// The include's path as written, when it had variables that were expanded
+    #[serde(rename = "expandedFrom", skip_serializing_if = "Option::is_none")]
+    pub expanded_from: Option<String>,
+}
+
+/// A library file a simulation used
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct LibraryUsed {
+    /// File name of the library
+    pub name: String,
+    /// Where the agent found it, "attachment" for an attached file; None when only the
+    /// simulator's log names it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+    /// Whether the netlist included a copy in the temp dir or the file where it is
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub strategy: Option<LibraryStrategy>,
+    /// The include's path as written, when it had variables that were expanded
+    #[serde(rename = "expandedFrom", skip_serializing_if = "Option::is_none")]
+    pub expanded_from: Option<String>,
+    /// Path the simulator's log says it loaded the library from, when the log says
+    #[serde(rename = "loadedPath", skip_serializing_if = "Option::is_none")]
+    pub loaded_path: Option<String>,
+    /// The simulator loaded a different file than the one the agent resolved
+    pub mismatch: bool,
+}
+
+impl SimulationResponse {
+    /// Move `results` into `resultsCompressed` as base64 gzip JSON
+    /// Returns the JSON and compressed sizes in bytes, or None if there were no results
+    pub fn compress_results(&mut self) -> std::io::Result<Option<(usize, usize)>> {
+        let Some(results) = &self.results else {
+            return Ok(None);
+        };
+        let json = serde_json::to_vec(results)?;
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&json)?;
+        let encoded = BASE64_STANDARD.encode(encoder.finish()?);
+
+        let sizes = (json.len(), encoded.len());
+        self.results = None;
+        self.results_compressed = Some(encoded);
+        self.compression = Some("gzip".to_string());
+        Ok(Some(sizes))
+    }
+
+    /// Serialize, counting the time it took in `timings`
+    /// The response is converted to a JSON value once, timed, and its `timings` replaced with the
+    /// final ones; the encoding of that value to text isn't counted
+    pub fn serialize_timed(&mut self) -> serde_json::Result<String> {
+        let started = std::time::Instant::now();
+        let mut value = serde_json::to_value(&*self)?;
+        let elapsed = started.elapsed().as_millis() as u64;
+        self.timings.serialization_ms += elapsed;
+        self.timings.total_ms += elapsed;
+        value["timings"] = serde_json::to_value(&self.timings)?;
+        serde_json::to_string(&value)
+    }
+
+    /// `results`, decoding `resultsCompressed` if they were compressed
+    pub fn decompressed_results(&self) -> std::io::Result<Option<SimulationResults>> {
+        let Some(encoded) = &self.results_compressed else {
+            return Ok(self.results.clone());
+        };
+        let gzip = BASE64_STANDARD
+            .decode(encoded)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        let json = flate2::read::GzDecoder::new(gzip.as_slice());
+        Ok(Some(serde_json::from_reader(json)?))
+    }
+
+    /// One-line outcome, e.g. "Simulation complete: 3 traces, 1.2 M points, 94 s"
+    pub fn summary(&self) -> String {
+        let duration = format_duration_ms(self.execution_time);
+        if !self.success {
+            let error = self.error.as_deref().and_then(|e| e.lines().next()).unwrap_or("unknown error");
+            return format!("Simulation failed after {}: {}", duration, error);
+        }
+        if let Some(values) = self.results.as_ref().and_then(|r| r.scalar_results.as_ref()) {
+            let noun = if values.len() == 1 { "value" } else { "values" };
+            return format!("Simulation complete: {} {}, {}", values.len(), noun, duration);
+        }
+        if let Some(results) = &self.results {
+            return format!(
+                "Simulation complete: {} traces, {} points, {}",
+                results.traces.len(),
+                format_count(results.time.len()),
+                duration
+            );
+        }
+        if let Some(monte_carlo) = &self.monte_carlo {
+            return format!(
+                "Monte Carlo complete: {}/{} runs, {}",
+                monte_carlo.completed_runs, monte_carlo.runs, duration
+            );
+        }
+        format!("Simulation complete: {}", duration)
+    }
+}
+
+/// Compact count: 850, 12.3 k, 1.2 M
+fn format_count(count: usize) -> String {
+    match count {
+        0..=999 => count.to_string(),
+        1_000..=999_999 => format!("{:.1} k", count as f64 / 1e3),
+        _ => format!("{:.1} M", count as f64 / 1e6),
+    }
+}
+
+fn format_duration_ms(ms: u64) -> String {
+    if ms < 10_000 {
+        format!("{:.1} s", ms as f64 / 1000.0)
+    } else {
+        format!("{} s", (ms + 500) / 1000)
+    }
+}
+
+/// A named parameter/temperature corner within a batch simulation
+#[derive(Debug, Clone, Deserialize)]
+pub struct BatchCorner {
+    pub name: String,
+    /// `.param` overrides applied on top of the base netlist (strings or numbers)
+    #[serde(default)]
+    pub params: BTreeMap<String, serde_json::Value>,
+    /// `.temp` override in degrees Celsius
+    #[serde(default)]
+    pub temperature: Option<f64>,
+}
+
+/// Batch simulation request: one base netlist run at several corners
+#[derive(Debug, Clone, Deserialize)]
+pub struct BatchSimulationRequest {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub msg_type: String,
+    pub netlist: String,
+    pub corners: Vec<BatchCorner>,
+    /// Name of a quality profile from the settings; "fast", "balanced" and "smooth" ship by default
+    #[serde(rename = "waveformQuality", default = "default_waveform_quality")]
+    pub waveform_quality: String,
+    /// Which simulator to use: "ltspice" or "ngspice"
+    #[serde(default = "default_simulator")]
+    pub simulator: String,
+    pub timeout: Option<u64>,
+    pub timestamp: u64,
+    /// Page origin of the connection that sent it
+    #[serde(skip)]
+    pub origin: Option<String>,
+}
+
+/// Result of a single corner within a batch simulation
+#[derive(Debug, Clone, Serialize)]
+pub struct CornerResult {
+    pub name: String,
+    pub success: bool,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub results: Option<SimulationResults>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub error: Option<String>,
     #[serde(rename = "executionTime")]
     pub execution_time: u64,
+}
+
+/// Batch simulation response to web app
+#[derive(Debug, Clone, Serialize)]
+pub struct BatchSimulationResponse {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub msg_type: String,
+    #[serde(rename = "requestId")]
+    pub request_id: String,
+    pub timestamp: u64,
+    /// True only if every corner succeeded
+    pub success: bool,
+    pub corners: Vec<CornerResult>,
+    /// Request-level error (busy, simulator missing, invalid batch)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(rename = "errorCode", skip_serializing_if = "Option::is_none")]
+    pub error_code: Option<String>,
+    #[serde(rename = "executionTime")]
+    pub execution_time: u64,
     pub simulator: String,
+    /// With `RATE_LIMITED`: how long until the origin may start another simulation
+    #[serde(rename = "retryAfterMs", skip_serializing_if = "Option::is_none")]
+    pub retry_after_ms: Option<u64>,
+    /// With `AGENT_BUSY`: what the agent is busy with
+    #[serde(rename = "busyInfo", skip_serializing_if = "Option::is_none")]
+    pub busy_info: Option<BusyInfo>,
 }
 
 /// Simulation progress update
@@ -119,8 +988,156 @@ pub struct SimulationProgress {
     #[serde(rename = "requestId")]
     pub request_id: String,
     pub timestamp: u64,
-    pub stage: String,
+    /// Serialized inline: `"stage": "<name>"` plus any stage fields
+    #[serde(flatten)]
+    pub stage: ProgressStage,
     pub message: String,
+    /// Name of the batch corner this update refers to
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub corner: Option<String>,
+    /// Runs or corners finished so far, for multi-run requests
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub completed: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total: Option<u32>,
+    /// Live resource usage of the simulator process
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub resources: Option<ResourceUsage>,
+}
+
+impl SimulationProgress {
+    /// Overall progress in percent: the running stage's own estimate, else runs or corners completed
+    pub fn percent(&self) -> Option<f32> {
+        match (&self.stage, self.completed, self.total) {
+            (ProgressStage::Running { percent: Some(percent) }, _, _) => Some(*percent),
+            (_, Some(completed), Some(total)) if total > 0 => Some(completed as f32 * 100.0 / total as f32),
+            _ => None,
+        }
+    }
+}
+
+/// Phase of a simulation, reported in order as each one is reached
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "stage", rename_all = "snake_case", rename_all_fields = "camelCase")]
+pub enum ProgressStage {
+    /// Temp directory created
+    Preparing { temp_dir: String },
+    /// Library includes copied into the temp directory
+    ResolvingLibraries { included_files: usize },
+    /// Simulator process spawned
+    Launching { pid: u32 },
+    /// Simulator running; percent is set when the simulator reports it
+    Running {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        percent: Option<f32>,
+    },
+    /// Reading the .raw output
+    ParsingResults { raw_file_bytes: u64 },
+    /// The requested timeout exceeds the agent's limit; the simulation runs with the limit instead
+    TimeoutLimited { requested_secs: u64, effective_secs: u64 },
+    /// The run is estimated to take unusually long; with `confirmation_required` it waits for `confirm`
+    Warning { estimated_secs: u64, confirmation_required: bool },
+    /// The previous run failed to converge; running again with a fallback
+    Retrying { fallback: String },
+    /// The simulator couldn't parse the netlist; running it on the other engine
+    SwitchingEngine { simulator: String },
+    /// Keepalive sent at a fixed interval for as long as the run lasts, whatever stage it is in
+    StillRunning {
+        elapsed_ms: u64,
+        /// Unix time in ms the simulator last wrote to its log or output, None until it has
+        #[serde(skip_serializing_if = "Option::is_none")]
+        last_log_activity: Option<u64>,
+    },
+    Complete,
+}
+
+impl ProgressStage {
+    /// Stage name as sent in the `stage` field
+    pub fn name(&self) -> &'static str {
+        match self {
+            ProgressStage::Preparing { .. } => "preparing",
+            ProgressStage::ResolvingLibraries { .. } => "resolving_libraries",
+            ProgressStage::Launching { .. } => "launching",
+            ProgressStage::Running { .. } => "running",
+            ProgressStage::ParsingResults { .. } => "parsing_results",
+            ProgressStage::TimeoutLimited { .. } => "timeout_limited",
+            ProgressStage::Warning { .. } => "warning",
+            ProgressStage::Retrying { .. } => "retrying",
+            ProgressStage::SwitchingEngine { .. } => "switching_engine",
+            ProgressStage::StillRunning { .. } => "still_running",
+            ProgressStage::Complete => "complete",
+        }
+    }
+
+    /// Human-readable description for the progress message
+    pub fn message(&self) -> String {
+        match self {
+            ProgressStage::Preparing { .. } => "Preparing simulation...".to_string(),
+            ProgressStage::ResolvingLibraries { included_files } => {
+                format!("Resolved {} library include(s)", included_files)
+            }
+            ProgressStage::Launching { pid } => format!("Simulator started (PID {})", pid),
+            ProgressStage::Running { percent: Some(percent) } => format!("Simulating... {:.0}%", percent),
+            ProgressStage::Running { percent: None } => "Simulating...".to_string(),
+            ProgressStage::ParsingResults { raw_file_bytes } => {
+                format!("Parsing results ({} KB)", raw_file_bytes / 1024)
+            }
+            ProgressStage::TimeoutLimited { requested_secs, effective_secs } => format!(
+                "Requested timeout of {}s exceeds the agent limit; the simulation will stop after {}s",
+                requested_secs, effective_secs
+            ),
+            ProgressStage::Warning { estimated_secs, confirmation_required } => format!(
+                "This simulation may take about {}{}",
+                format_duration_ms(estimated_secs.saturating_mul(1000)),
+                if *confirmation_required { "; waiting for confirmation to start" } else { "" }
+            ),
+            ProgressStage::Retrying { fallback } => {
+                format!("Simulation did not converge; retrying with {}", fallback)
+            }
+            ProgressStage::SwitchingEngine { simulator } => {
+                format!("Simulator could not parse the netlist; retrying with {}", simulator)
+            }
+            ProgressStage::StillRunning { elapsed_ms, .. } => {
+                format!("Still running after {}", format_duration_ms(*elapsed_ms))
+            }
+            ProgressStage::Complete => "Simulation complete".to_string(),
+        }
+    }
+}
+
+/// Batch of simulator output lines streamed while a simulation runs
+#[derive(Debug, Clone, Serialize)]
+pub struct SimulationLog {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub msg_type: String,
+    #[serde(rename = "requestId")]
+    pub request_id: String,
+    pub timestamp: u64,
+    pub lines: Vec<LogLine>,
+    /// Lines discarded by rate limiting since the previous message
+    #[serde(rename = "droppedLines", skip_serializing_if = "is_zero")]
+    pub dropped_lines: u32,
+}
+
+/// One line of simulator output
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LogLine {
+    /// "stdout" or "stderr"
+    pub stream: String,
+    pub text: String,
+}
+
+/// Resource usage sampled from a running simulator process
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ResourceUsage {
+    #[serde(rename = "cpuPercent")]
+    pub cpu_percent: f32,
+    #[serde(rename = "memoryBytes")]
+    pub memory_bytes: u64,
+    /// Size of the .raw output written so far
+    #[serde(rename = "rawFileBytes")]
+    pub raw_file_bytes: u64,
 }
 
 /// Ping message
@@ -140,6 +1157,320 @@ pub struct PongResponse {
     pub msg_type: String,
     pub timestamp: u64,
     pub status: String,
+    /// Longest-running simulation in flight
+    #[serde(rename = "currentRequestId", skip_serializing_if = "Option::is_none")]
+    pub current_request_id: Option<String>,
+    /// Completion of the current simulation, when its simulator or batch has reported one
+    #[serde(rename = "progressPercent", skip_serializing_if = "Option::is_none")]
+    pub progress_percent: Option<f32>,
+    /// Time since the current simulation started
+    #[serde(rename = "elapsedMs", skip_serializing_if = "Option::is_none")]
+    pub elapsed_ms: Option<u64>,
+    /// Other simulations running or waiting for confirmation
+    #[serde(rename = "queueLength", skip_serializing_if = "Option::is_none")]
+    pub queue_length: Option<u32>,
+}
+
+/// Sent to every client before the agent closes its connections on quit
+#[derive(Debug, Clone, Serialize)]
+pub struct AgentShutdown {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub msg_type: String,
+    pub timestamp: u64,
+    pub reason: String,
+}
+
+/// Simulator availability, sent unsolicited to handshaked clients when LTspice or ngspice
+/// appears or disappears, and in reply to `redetect_simulators` (with `requestId` set)
+#[derive(Debug, Clone, Serialize)]
+pub struct CapabilitiesChanged {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub msg_type: String,
+    #[serde(rename = "requestId", skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    pub timestamp: u64,
+    #[serde(rename = "ltspicePath", skip_serializing_if = "Option::is_none")]
+    pub ltspice_path: Option<String>,
+    #[serde(rename = "ngspicePath", skip_serializing_if = "Option::is_none")]
+    pub ngspice_path: Option<String>,
+    pub capabilities: AgentCapabilities,
+    pub onboarding: OnboardingState,
+    /// Unix time in ms of the last detection run
+    #[serde(rename = "checkedAt", skip_serializing_if = "Option::is_none")]
+    pub checked_at: Option<u64>,
+}
+
+/// Re-run simulator detection now instead of waiting for the periodic check
+#[derive(Debug, Clone, Deserialize)]
+pub struct RedetectSimulatorsRequest {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub msg_type: String,
+    pub timestamp: u64,
+}
+
+/// Run the built-in reference circuit on each detected simulator
+#[derive(Debug, Clone, Deserialize)]
+pub struct SelfTestRequest {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub msg_type: String,
+    pub timestamp: u64,
+}
+
+/// Outcome of a self test, one entry per detected simulator
+#[derive(Debug, Clone, Serialize)]
+pub struct SelfTestResponse {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub msg_type: String,
+    #[serde(rename = "requestId")]
+    pub request_id: String,
+    pub timestamp: u64,
+    /// Every detected simulator passed; false when none was detected
+    pub success: bool,
+    pub engines: Vec<EngineSelfTest>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// How one simulator did on the self test
+#[derive(Debug, Clone, Serialize)]
+pub struct EngineSelfTest {
+    pub simulator: String,
+    pub path: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    pub passed: bool,
+    #[serde(rename = "executionTime")]
+    pub execution_time: u64,
+    /// V(out) at t = RC, if the run got far enough to produce it
+    #[serde(rename = "measuredVout", skip_serializing_if = "Option::is_none")]
+    pub measured_vout: Option<f64>,
+    #[serde(rename = "expectedVout")]
+    pub expected_vout: f64,
+    /// The simulator's error or the failed check, verbatim for bug reports
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Fetch the outcome of an earlier simulate request, e.g. after reconnecting
+#[derive(Debug, Clone, Deserialize)]
+pub struct GetResultRequest {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub msg_type: String,
+    /// Id of the simulate request whose result is wanted
+    #[serde(rename = "requestId")]
+    pub request_id: String,
+    pub timestamp: u64,
+}
+
+/// Reply to `get_result`
+/// `status` is "complete" (with `result`), "running" (the `simulation_result` follows on this
+/// connection when it finishes) or "not_found"
+#[derive(Debug, Clone, Serialize)]
+pub struct GetResultResponse {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub msg_type: String,
+    #[serde(rename = "requestId")]
+    pub request_id: String,
+    pub timestamp: u64,
+    pub status: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result: Option<SimulationResponse>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Fetch a raw file kept by a simulate request that set `keepRaw`
+#[derive(Debug, Clone, Deserialize)]
+pub struct GetArtifactRequest {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub msg_type: String,
+    /// `artifactId` from the simulation's response
+    #[serde(rename = "artifactId")]
+    pub artifact_id: String,
+    pub timestamp: u64,
+}
+
+/// Reply to `get_artifact`
+#[derive(Debug, Clone, Serialize)]
+pub struct ArtifactResponse {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub msg_type: String,
+    #[serde(rename = "requestId")]
+    pub request_id: String,
+    pub timestamp: u64,
+    pub success: bool,
+    #[serde(rename = "artifactId")]
+    pub artifact_id: String,
+    /// Name to save the file under, e.g. `sim-1.raw`
+    #[serde(rename = "fileName", skip_serializing_if = "Option::is_none")]
+    pub file_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    /// The raw file, base64-encoded
+    #[serde(rename = "contentBase64", skip_serializing_if = "Option::is_none")]
+    pub content_base64: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// `ARTIFACT_NOT_FOUND` or `ARTIFACT_TOO_LARGE`
+    #[serde(rename = "errorCode", skip_serializing_if = "Option::is_none")]
+    pub error_code: Option<String>,
+}
+
+/// Fetch traces held back by a simulate request that set `deferTraceData`
+#[derive(Debug, Clone, Deserialize)]
+pub struct GetTraceDataRequest {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub msg_type: String,
+    /// Id of the simulate request
+    #[serde(rename = "simulationId")]
+    pub simulation_id: String,
+    /// Names from the response's `traceCatalog`
+    pub traces: Vec<String>,
+    /// Downsample to at most this many points, keeping the first and last
+    #[serde(rename = "maxPoints", default)]
+    pub max_points: Option<usize>,
+    pub timestamp: u64,
+}
+
+/// Reply to `get_trace_data`
+#[derive(Debug, Clone, Serialize)]
+pub struct TraceDataResponse {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub msg_type: String,
+    /// Id of the `get_trace_data` message
+    #[serde(rename = "requestId")]
+    pub request_id: String,
+    pub timestamp: u64,
+    pub success: bool,
+    /// Id of the simulate request the traces belong to
+    #[serde(rename = "simulationId")]
+    pub simulation_id: String,
+    /// X axis values at the returned points
+    pub time: Vec<f64>,
+    pub traces: Vec<Trace>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// `TRACE_DATA_NOT_FOUND` or `UNKNOWN_TRACE`
+    #[serde(rename = "errorCode", skip_serializing_if = "Option::is_none")]
+    pub error_code: Option<String>,
+}
+
+/// Keep a netlist with `{{name}}` placeholders for `simulate_template`
+#[derive(Debug, Clone, Deserialize)]
+pub struct RegisterTemplateRequest {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub msg_type: String,
+    /// Replaces a template of the same name
+    pub name: String,
+    pub netlist: String,
+    pub timestamp: u64,
+}
+
+/// List the templates registered by the client's origin
+#[derive(Debug, Clone, Deserialize)]
+pub struct ListTemplatesRequest {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub msg_type: String,
+    pub timestamp: u64,
+}
+
+/// Delete a registered template
+#[derive(Debug, Clone, Deserialize)]
+pub struct DeleteTemplateRequest {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub msg_type: String,
+    pub name: String,
+    pub timestamp: u64,
+}
+
+/// A simulate message that names a registered template and its placeholders' values instead of
+/// giving a netlist; its other fields are read as a simulate message's
+#[derive(Debug, Clone, Deserialize)]
+pub struct SimulateTemplateRequest {
+    pub id: String,
+    pub template: String,
+    pub values: BTreeMap<String, String>,
+}
+
+/// A registered template
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct TemplateInfo {
+    pub name: String,
+    /// Placeholder names, in order of first use
+    pub placeholders: Vec<String>,
+    pub bytes: usize,
+    /// Unix time in ms the template is dropped unless registered or run again before
+    #[serde(rename = "expiresAt")]
+    pub expires_at: u64,
+}
+
+/// Reply to `register_template`, `delete_template` and `list_templates`
+#[derive(Debug, Clone, Serialize)]
+pub struct TemplateResponse {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub msg_type: String,
+    #[serde(rename = "requestId")]
+    pub request_id: String,
+    pub timestamp: u64,
+    pub success: bool,
+    /// The registered template, or every template of the client's origin for `list_templates`
+    pub templates: Vec<TemplateInfo>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// `TEMPLATE_INVALID`, `TEMPLATE_TOO_LARGE`, `TOO_MANY_TEMPLATES` or `TEMPLATE_NOT_FOUND`
+    #[serde(rename = "errorCode", skip_serializing_if = "Option::is_none")]
+    pub error_code: Option<String>,
+}
+
+/// Run the stored netlist of an earlier simulation again
+/// The result is a `simulation_result` for this message's id, with `replayOf` set
+#[derive(Debug, Clone, Deserialize)]
+pub struct RerunSimulationRequest {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub msg_type: String,
+    /// Request id of the simulation to replay
+    #[serde(rename = "historyId")]
+    pub history_id: String,
+    pub timestamp: u64,
+}
+
+/// Go ahead with a simulation waiting on a long-run warning; `cancel` declines it
+#[derive(Debug, Clone, Deserialize)]
+pub struct ConfirmRequest {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub msg_type: String,
+    #[serde(rename = "requestId")]
+    pub request_id: String,
+    pub timestamp: u64,
+}
+
+/// Confirm response; `success` is false if the simulation was not waiting for confirmation
+#[derive(Debug, Clone, Serialize)]
+pub struct ConfirmResponse {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub msg_type: String,
+    #[serde(rename = "requestId")]
+    pub request_id: String,
+    pub timestamp: u64,
+    pub success: bool,
 }
 
 /// Cancel simulation request
@@ -148,14 +1479,254 @@ pub struct CancelRequest {
     pub id: String,
     #[serde(rename = "type")]
     pub msg_type: String,
-    #[serde(rename = "requestId")]
-    pub request_id: String,
+    #[serde(rename = "requestId")]
+    pub request_id: String,
+    pub timestamp: u64,
+}
+
+/// Cancel response
+#[derive(Debug, Clone, Serialize)]
+pub struct CancelResponse {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub msg_type: String,
+    #[serde(rename = "requestId")]
+    pub request_id: String,
+    pub timestamp: u64,
+    pub success: bool,
+    /// Why the cancel failed, e.g. "completed before cancel" when the results won the race
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+/// Inspect a netlist without running it
+#[derive(Debug, Clone, Deserialize)]
+pub struct AnalyzeNetlistRequest {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub msg_type: String,
+    pub netlist: String,
+    pub timestamp: u64,
+}
+
+/// Analyses, nodes and components found in a netlist
+#[derive(Debug, Clone, Serialize)]
+pub struct AnalyzeNetlistResponse {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub msg_type: String,
+    #[serde(rename = "requestId")]
+    pub request_id: String,
+    pub timestamp: u64,
+    pub analyses: Vec<NetlistAnalysis>,
+    pub nodes: Vec<String>,
+    pub components: Vec<String>,
+}
+
+/// Analysis directive found in a netlist, with its values as written (e.g. `"10m"`)
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case", rename_all_fields = "camelCase")]
+pub enum NetlistAnalysis {
+    Tran {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        step: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        stop: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        start: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        max_step: Option<String>,
+    },
+    Ac {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        sweep: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        points: Option<u32>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        fstart: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        fstop: Option<String>,
+    },
+    Dc {
+        /// `lin`, `oct` or `dec` when given before the source (LTspice)
+        #[serde(skip_serializing_if = "Option::is_none")]
+        sweep: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        source: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        start: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        stop: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        step: Option<String>,
+    },
+    Noise {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        output: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        source: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        sweep: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        points: Option<u32>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        fstart: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        fstop: Option<String>,
+    },
+    Tf {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        output: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        source: Option<String>,
+    },
+    Sens {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        output: Option<String>,
+    },
+    Op,
+    /// A recognized analysis without structured parameters, such as `.pz`
+    Other { directive: String, args: Vec<String> },
+}
+
+impl NetlistAnalysis {
+    /// Directive name without the dot, e.g. `tran`
+    pub fn directive(&self) -> &str {
+        match self {
+            NetlistAnalysis::Tran { .. } => "tran",
+            NetlistAnalysis::Ac { .. } => "ac",
+            NetlistAnalysis::Dc { .. } => "dc",
+            NetlistAnalysis::Noise { .. } => "noise",
+            NetlistAnalysis::Tf { .. } => "tf",
+            NetlistAnalysis::Sens { .. } => "sens",
+            NetlistAnalysis::Op => "op",
+            NetlistAnalysis::Other { directive, .. } => directive,
+        }
+    }
+}
+
+/// List libraries request
+#[derive(Debug, Clone, Deserialize)]
+pub struct ListLibrariesRequest {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub msg_type: String,
+    /// Which simulator's libraries to list: "ltspice" or "ngspice" (defaults to ltspice)
+    #[serde(default = "default_simulator")]
+    pub simulator: String,
+    pub timestamp: u64,
+}
+
+/// List libraries response
+#[derive(Debug, Clone, Serialize)]
+pub struct ListLibrariesResponse {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub msg_type: String,
+    pub timestamp: u64,
+    pub success: bool,
+    pub libraries: Vec<String>,
+    #[serde(rename = "libPath", skip_serializing_if = "Option::is_none")]
+    pub lib_path: Option<String>,
+    /// Libraries the agent bundles for LTspice, used when its library directory lacks them
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub bundled: Vec<BundledLibraryStatus>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// A library in the bundled library manifest
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BundledLibrary {
+    /// File name, as used in `.include`/`.lib`
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+}
+
+/// A bundled library and whether settings allow it to be substituted
+#[derive(Debug, Clone, Serialize)]
+pub struct BundledLibraryStatus {
+    #[serde(flatten)]
+    pub library: BundledLibrary,
+    pub enabled: bool,
+}
+
+/// Export request: the most recent simulation results as CSV
+#[derive(Debug, Clone, Deserialize)]
+pub struct ExportRequest {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub msg_type: String,
+    pub timestamp: u64,
+}
+
+/// Export response
+#[derive(Debug, Clone, Serialize)]
+pub struct ExportResponse {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub msg_type: String,
+    #[serde(rename = "requestId")]
+    pub request_id: String,
+    pub timestamp: u64,
+    pub success: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub csv: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(rename = "errorCode", skip_serializing_if = "Option::is_none")]
+    pub error_code: Option<String>,
+}
+
+/// Metrics request
+#[derive(Debug, Clone, Deserialize)]
+pub struct MetricsRequest {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub msg_type: String,
+    pub timestamp: u64,
+}
+
+/// Metrics response
+#[derive(Debug, Clone, Serialize)]
+pub struct MetricsResponse {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub msg_type: String,
+    #[serde(rename = "requestId")]
+    pub request_id: String,
+    pub timestamp: u64,
+    pub metrics: AgentMetrics,
+}
+
+/// A trace to compute from existing ones, e.g. `V(a)-V(b)`
+#[derive(Debug, Clone, Deserialize)]
+pub struct DerivedTrace {
+    /// Defaults to the expression
+    #[serde(default)]
+    pub name: Option<String>,
+    pub expression: String,
+}
+
+/// Compute traces from an earlier result without running the simulator
+#[derive(Debug, Clone, Deserialize)]
+pub struct PostProcessRequest {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub msg_type: String,
     pub timestamp: u64,
+    /// Stored result of this simulation; the most recent results when absent
+    #[serde(rename = "simulationId", default)]
+    pub simulation_id: Option<String>,
+    pub traces: Vec<DerivedTrace>,
 }
 
-/// Cancel response
+/// Post-process response: only the computed traces
 #[derive(Debug, Clone, Serialize)]
-pub struct CancelResponse {
+pub struct PostProcessResponse {
     pub id: String,
     #[serde(rename = "type")]
     pub msg_type: String,
@@ -163,33 +1734,202 @@ pub struct CancelResponse {
     pub request_id: String,
     pub timestamp: u64,
     pub success: bool,
+    #[serde(rename = "simulationId", skip_serializing_if = "Option::is_none")]
+    pub simulation_id: Option<String>,
+    #[serde(rename = "analysisType", skip_serializing_if = "Option::is_none")]
+    pub analysis_type: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub traces: Vec<Trace>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(rename = "errorCode", skip_serializing_if = "Option::is_none")]
+    pub error_code: Option<String>,
 }
 
-/// List libraries request
+/// Status request
 #[derive(Debug, Clone, Deserialize)]
-pub struct ListLibrariesRequest {
+pub struct StatusRequest {
     pub id: String,
     #[serde(rename = "type")]
     pub msg_type: String,
-    /// Which simulator's libraries to list: "ltspice" or "ngspice" (defaults to ltspice)
-    #[serde(default = "default_simulator")]
-    pub simulator: String,
     pub timestamp: u64,
 }
 
-/// List libraries response
+/// A simulation in flight, as listed in a status response
 #[derive(Debug, Clone, Serialize)]
-pub struct ListLibrariesResponse {
+pub struct ActiveSimulationInfo {
+    #[serde(rename = "requestId")]
+    pub request_id: String,
+    #[serde(rename = "elapsedMs")]
+    pub elapsed_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub resources: Option<ResourceUsage>,
+    #[serde(rename = "progressPercent", skip_serializing_if = "Option::is_none")]
+    pub progress_percent: Option<f32>,
+}
+
+/// Status response: everything the agent window shows
+#[derive(Debug, Clone, Serialize)]
+pub struct StatusResponse {
     pub id: String,
     #[serde(rename = "type")]
     pub msg_type: String,
+    #[serde(rename = "requestId")]
+    pub request_id: String,
     pub timestamp: u64,
-    pub success: bool,
-    pub libraries: Vec<String>,
-    #[serde(rename = "libPath", skip_serializing_if = "Option::is_none")]
-    pub lib_path: Option<String>,
+    #[serde(rename = "agentVersion")]
+    pub agent_version: String,
+    #[serde(rename = "wsPort")]
+    pub ws_port: u16,
+    #[serde(rename = "ltspiceAvailable")]
+    pub ltspice_available: bool,
+    #[serde(rename = "ltspicePath", skip_serializing_if = "Option::is_none")]
+    pub ltspice_path: Option<String>,
+    #[serde(rename = "ltspiceBinary", skip_serializing_if = "Option::is_none")]
+    pub ltspice_binary: Option<SimulatorBinary>,
+    #[serde(rename = "ngspiceAvailable")]
+    pub ngspice_available: bool,
+    #[serde(rename = "ngspicePath", skip_serializing_if = "Option::is_none")]
+    pub ngspice_path: Option<String>,
+    #[serde(rename = "ngspiceBinary", skip_serializing_if = "Option::is_none")]
+    pub ngspice_binary: Option<SimulatorBinary>,
+    /// Unix time in ms simulator detection last ran
+    #[serde(rename = "simulatorsCheckedAt", skip_serializing_if = "Option::is_none")]
+    pub simulators_checked_at: Option<u64>,
+    pub onboarding: OnboardingState,
+    #[serde(rename = "isSimulating")]
+    pub is_simulating: bool,
+    /// Longest-running first
+    #[serde(rename = "activeSimulations")]
+    pub active_simulations: Vec<ActiveSimulationInfo>,
+    /// Simulations held back by a long-run warning, waiting for `confirm`
+    #[serde(rename = "queueLength")]
+    pub queue_length: u32,
+    #[serde(rename = "maxConcurrentSimulations")]
+    pub max_concurrent_simulations: usize,
+    #[serde(rename = "backgroundPriority")]
+    pub background_priority: bool,
+    #[serde(rename = "ngspiceThreads", skip_serializing_if = "Option::is_none")]
+    pub ngspice_threads: Option<u32>,
+    #[serde(rename = "maxSimulationSeconds")]
+    pub max_simulation_seconds: u64,
+    #[serde(rename = "orphanedProcessesKilled")]
+    pub orphaned_processes_killed: u32,
+    #[serde(rename = "serverError", skip_serializing_if = "Option::is_none")]
+    pub server_error: Option<String>,
+    #[serde(rename = "logFile", skip_serializing_if = "Option::is_none")]
+    pub log_file: Option<String>,
+    #[serde(rename = "logLevel")]
+    pub log_level: String,
+    /// Directory simulations make their temp directories in
+    #[serde(rename = "tempRoot")]
+    pub temp_root: String,
+    /// Why the `temp_dir_override` setting isn't in use, when it was rejected
+    #[serde(rename = "tempRootError", skip_serializing_if = "Option::is_none")]
+    pub temp_root_error: Option<String>,
+    #[serde(rename = "wsConnections")]
+    pub ws_connections: u32,
+    #[serde(rename = "simulationCount")]
+    pub simulation_count: u32,
+    #[serde(rename = "lastSimulationTime", skip_serializing_if = "Option::is_none")]
+    pub last_simulation_time: Option<u64>,
+    pub metrics: AgentMetrics,
+    /// Path of the read-only status file kept for local tools, when it is written
+    #[serde(rename = "statusFile", skip_serializing_if = "Option::is_none")]
+    pub status_file: Option<String>,
+    /// Results, raw files and netlists kept now against the resource budget
+    #[serde(rename = "resourceUsage")]
+    pub resource_usage: BudgetUsage,
+}
+
+/// How far a new user has got with installing simulators
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OnboardingStage {
+    /// Neither simulator found, or detection hasn't run yet
+    #[default]
+    NoSimulator,
+    DetectedLtspiceOnly,
+    DetectedNgspiceOnly,
+    /// Both simulators found
+    Ready,
+}
+
+/// How to install a simulator that wasn't found, on the agent's platform
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct InstallGuide {
+    /// "ltspice" or "ngspice"
+    pub simulator: String,
+    pub message: String,
+    /// Download page
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub error: Option<String>,
+    pub url: Option<String>,
+    /// Shell command that installs it, such as `brew install ngspice`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub command: Option<String>,
+}
+
+/// Onboarding stage, with a guide for each simulator still missing
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct OnboardingState {
+    pub stage: OnboardingStage,
+    /// Empty once `ready`
+    pub guides: Vec<InstallGuide>,
+}
+
+/// Simulation outcomes per simulator
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SimulatorMetrics {
+    pub simulator: String,
+    pub succeeded: u64,
+    pub failed: u64,
+}
+
+/// Agent health counters, counted since `since` (agent start or the last reset)
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct AgentMetrics {
+    /// Seconds since the agent started; not affected by a reset
+    #[serde(rename = "uptimeSecs")]
+    pub uptime_secs: u64,
+    /// Unix time in ms the counters started from
+    pub since: u64,
+    pub simulators: Vec<SimulatorMetrics>,
+    pub cancelled: u64,
+    /// Failed / (succeeded + failed), 0 before any simulation finished
+    #[serde(rename = "failureRate")]
+    pub failure_rate: f64,
+    /// Over the most recent finished simulations
+    #[serde(rename = "averageExecutionMs", skip_serializing_if = "Option::is_none")]
+    pub average_execution_ms: Option<u64>,
+    #[serde(rename = "p95ExecutionMs", skip_serializing_if = "Option::is_none")]
+    pub p95_execution_ms: Option<u64>,
+    /// Size of the result and export messages sent to clients
+    #[serde(rename = "resultBytesServed")]
+    pub result_bytes_served: u64,
+    /// Progress and log messages skipped for clients that were not keeping up
+    #[serde(rename = "progressMessagesDropped")]
+    pub progress_messages_dropped: u64,
+    /// Simulate requests by page origin
+    pub origins: Vec<OriginMetrics>,
+}
+
+/// Simulate requests from one page origin
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct OriginMetrics {
+    pub origin: String,
+    #[serde(rename = "simulationsStarted")]
+    pub simulations_started: u64,
+    #[serde(rename = "simulationsFailed")]
+    pub simulations_failed: u64,
+    /// Simulate requests refused with `RATE_LIMITED`
+    #[serde(rename = "rateLimited")]
+    pub rate_limited: u64,
+    /// Size of the results sent for the origin's simulations
+    #[serde(rename = "bytesReturned")]
+    pub bytes_returned: u64,
+    /// Unix time in ms of the origin's last simulate request or result
+    #[serde(rename = "lastActivity")]
+    pub last_activity: u64,
 }
 
 /// Generic message for type detection
@@ -200,6 +1940,29 @@ pub struct GenericMessage {
     pub msg_type: String,
 }
 
+/// Sent instead of handling a message that doesn't match its type's schema
+#[derive(Debug, Clone, Serialize)]
+pub struct InvalidMessageResponse {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub msg_type: String,
+    /// Id of the rejected message, when it had one
+    #[serde(rename = "requestId", skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    pub timestamp: u64,
+    /// Type of the rejected message, when it had one
+    #[serde(rename = "messageType", skip_serializing_if = "Option::is_none")]
+    pub message_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub field: Option<String>,
+    pub error: String,
+    /// What the field should hold
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expected: Option<String>,
+    #[serde(rename = "errorCode")]
+    pub error_code: String,
+}
+
 /// Allowed origins for WebSocket connections
 pub const ALLOWED_ORIGINS: &[&str] = &[
     "https://kelicad.com",
@@ -217,6 +1980,18 @@ pub const AGENT_VERSION: &str = "1.0.0";
 /// WebSocket server port
 pub const WS_PORT: u16 = 9347;
 
+/// Maximum number of corners accepted in a single batch simulation
+pub const MAX_BATCH_SIZE: u32 = 10;
+
+/// Maximum number of runs accepted in a single Monte Carlo request
+pub const MAX_MONTE_CARLO_RUNS: u32 = 1000;
+
+/// Largest result set, in bytes of sample data, that can be exported over the WebSocket
+pub const MAX_WS_EXPORT_BYTES: usize = 8 * 1024 * 1024;
+
+/// Largest kept raw file that can be fetched over the WebSocket; bigger ones are saved from the agent window
+pub const MAX_WS_ARTIFACT_BYTES: u64 = 64 * 1024 * 1024;
+
 /// Check if origin is allowed
 pub fn is_origin_allowed(origin: &str) -> bool {
     ALLOWED_ORIGINS.contains(&origin)
@@ -275,6 +2050,16 @@ mod tests {
         assert_eq!(request.origin, "https://kelicad.com");
         assert_eq!(request.version, "1.0.0");
         assert_eq!(request.timestamp, 1704067200000);
+        assert_eq!(request.encoding, Encoding::Json);
+
+        let json = r#"{"id":"test-124","type":"handshake","origin":"https://kelicad.com","version":"1.0.0","encoding":"msgpack","timestamp":0}"#;
+        let request: HandshakeRequest = serde_json::from_str(json).unwrap();
+        assert_eq!(request.encoding, Encoding::Msgpack);
+        assert!(!request.subscribe_results);
+
+        let json = r#"{"id":"test-125","type":"handshake","origin":"https://kelicad.com","version":"1.0.0","subscribeResults":true,"timestamp":0}"#;
+        let request: HandshakeRequest = serde_json::from_str(json).unwrap();
+        assert!(request.subscribe_results);
     }
 
     #[test]
@@ -291,8 +2076,16 @@ mod tests {
                 ltspice_available: true,
                 ngspice_available: true,
                 supported_analyses: vec!["transient".to_string(), "ac".to_string()],
-                max_simulation_time: 120,
+                analyses: vec![],
+                supported_input_formats: vec!["netlist".to_string(), "asc".to_string()],
+                max_simulation_time: 300,
+                max_batch_size: 10,
+                engine_options: vec![],
+                accepts_compressed_netlist: true,
             },
+            onboarding: Some(OnboardingState { stage: OnboardingStage::Ready, guides: vec![] }),
+            encoding: Encoding::Msgpack,
+            subscribed_results: true,
             error: None,
         };
 
@@ -304,6 +2097,11 @@ mod tests {
         assert!(json.contains("\"ngspicePath\":\"/opt/homebrew/bin/ngspice\""));
         assert!(json.contains("\"ltspiceAvailable\":true"));
         assert!(json.contains("\"ngspiceAvailable\":true"));
+        assert!(json.contains("\"maxBatchSize\":10"));
+        assert!(json.contains("\"supportedInputFormats\":[\"netlist\",\"asc\"]"));
+        assert!(json.contains("\"encoding\":\"msgpack\""));
+        assert!(json.contains("\"subscribedResults\":true"));
+        assert!(json.contains("\"onboarding\":{\"stage\":\"ready\",\"guides\":[]}"));
         // Error should be skipped when None
         assert!(!json.contains("\"error\""));
     }
@@ -322,8 +2120,16 @@ mod tests {
                 ltspice_available: false,
                 ngspice_available: false,
                 supported_analyses: vec![],
-                max_simulation_time: 120,
+                analyses: vec![],
+                supported_input_formats: vec![],
+                max_simulation_time: 300,
+                max_batch_size: 10,
+                engine_options: vec![],
+                accepts_compressed_netlist: true,
             },
+            onboarding: None,
+            encoding: Encoding::Json,
+            subscribed_results: false,
             error: Some("Invalid origin".to_string()),
         };
 
@@ -333,6 +2139,7 @@ mod tests {
         // ltspicePath should be skipped when None
         assert!(!json.contains("\"ltspicePath\""));
         assert!(!json.contains("\"ngspicePath\""));
+        assert!(!json.contains("\"onboarding\""));
     }
 
     #[test]
@@ -381,6 +2188,20 @@ mod tests {
         assert_eq!(request.waveform_quality, "smooth"); // default value
     }
 
+    #[test]
+    fn test_simulation_request_input_format() {
+        let json = r#"{"id": "s", "type": "simulate", "netlist": "* Test", "timestamp": 0}"#;
+        let request: SimulationRequest = serde_json::from_str(json).unwrap();
+        assert_eq!(request.input_format, InputFormat::Netlist);
+
+        let json = r#"{"id": "s", "type": "simulate", "netlist": "Version 4", "inputFormat": "asc", "timestamp": 0}"#;
+        let request: SimulationRequest = serde_json::from_str(json).unwrap();
+        assert_eq!(request.input_format, InputFormat::Asc);
+
+        let json = r#"{"id": "s", "type": "simulate", "netlist": "", "inputFormat": "kicad", "timestamp": 0}"#;
+        assert!(serde_json::from_str::<SimulationRequest>(json).is_err());
+    }
+
     #[test]
     fn test_simulation_response_serialization() {
         let response = SimulationResponse {
@@ -389,6 +2210,7 @@ mod tests {
             request_id: "sim-123".to_string(),
             timestamp: 1704067200000,
             success: true,
+            f32_results: false,
             results: Some(SimulationResults {
                 time: vec![0.0, 0.001, 0.002],
                 traces: vec![
@@ -396,14 +2218,45 @@ mod tests {
                         name: "V(out)".to_string(),
                         data: vec![0.0, 0.5, 1.0],
                         unit: "V".to_string(),
+                        phase: None,
+                        raw_name: None,
                     },
                 ],
                 analysis_type: "transient".to_string(),
                 x_axis_label: Some("time".to_string()),
+                x_axis_unit: None,
+                scalar_results: None,
+                device_operating_points: BTreeMap::new(),
+                truncated: None,
+                plot_hints: None,
+                raw_header: None,
             }),
             error: None,
+            error_code: None,
             execution_time: 1500,
             simulator: "ltspice".to_string(),
+            monte_carlo: None,
+            duplicate: false,
+            retried_with: None,
+            translations: Vec::new(),
+            debug_info: None,
+            results_compressed: None,
+            compression: None,
+            retry_after_ms: None,
+            warnings: Vec::new(),
+            simulator_version: None,
+            simulator_binary_sha256: None,
+            replay_of: None,
+            fallback_simulator_used: None,
+            engine_errors: Vec::new(),
+            artifact_id: None,
+            raw_file_size: None,
+            trace_catalog: None,
+            operating_point: None,
+            wave_data: None,
+            libraries_used: Vec::new(),
+            busy_info: None,
+            timings: SimulationTimings::default(),
         };
 
         let json = serde_json::to_string(&response).unwrap();
@@ -422,16 +2275,108 @@ mod tests {
             request_id: "sim-456".to_string(),
             timestamp: 1704067200000,
             success: false,
+            f32_results: false,
             results: None,
             error: Some("LTspice not found".to_string()),
+            error_code: None,
             execution_time: 50,
             simulator: "ltspice".to_string(),
+            monte_carlo: None,
+            duplicate: false,
+            retried_with: None,
+            translations: Vec::new(),
+            debug_info: None,
+            results_compressed: None,
+            compression: None,
+            retry_after_ms: None,
+            warnings: Vec::new(),
+            simulator_version: None,
+            simulator_binary_sha256: None,
+            replay_of: None,
+            fallback_simulator_used: None,
+            engine_errors: Vec::new(),
+            artifact_id: None,
+            raw_file_size: None,
+            trace_catalog: None,
+            operating_point: None,
+            wave_data: None,
+            libraries_used: Vec::new(),
+            busy_info: None,
+            timings: SimulationTimings::default(),
         };
 
         let json = serde_json::to_string(&response).unwrap();
         assert!(json.contains("\"success\":false"));
         assert!(json.contains("\"error\":\"LTspice not found\""));
         assert!(!json.contains("\"results\""));
+        assert!(!json.contains("\"errorCode\""));
+        assert!(!json.contains("\"librariesUsed\""));
+        assert!(!json.contains("\"busyInfo\""));
+    }
+
+    #[test]
+    fn test_busy_info_serialization() {
+        let info = BusyInfo {
+            running_request_id: "3f2a9c01b7de".to_string(),
+            request_id_redacted: true,
+            elapsed_ms: 4200,
+            estimated_remaining_ms: Some(1800),
+            running_simulations: 1,
+            queue_length: 2,
+        };
+        let json = serde_json::to_value(&info).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "runningRequestId": "3f2a9c01b7de",
+                "requestIdRedacted": true,
+                "elapsedMs": 4200,
+                "estimatedRemainingMs": 1800,
+                "runningSimulations": 1,
+                "queueLength": 2
+            })
+        );
+        assert_eq!(serde_json::from_value::<BusyInfo>(json).unwrap(), info);
+
+        let info = BusyInfo { estimated_remaining_ms: None, ..info };
+        let json = serde_json::to_string(&info).unwrap();
+        assert!(!json.contains("estimatedRemainingMs"));
+        assert_eq!(serde_json::from_str::<BusyInfo>(&json).unwrap(), info);
+    }
+
+    #[test]
+    fn test_libraries_used_serialization() {
+        let used = LibraryUsed {
+            name: "LTC.lib".to_string(),
+            source: Some("/opt/models/LTC.lib".to_string()),
+            strategy: Some(LibraryStrategy::Reference),
+            expanded_from: Some("$MODELS/LTC.lib".to_string()),
+            loaded_path: Some("C:\\LTspice\\lib\\sub\\LTC.lib".to_string()),
+            mismatch: true,
+        };
+        let json = serde_json::to_value(&used).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "name": "LTC.lib",
+                "source": "/opt/models/LTC.lib",
+                "strategy": "reference",
+                "expandedFrom": "$MODELS/LTC.lib",
+                "loadedPath": "C:\\LTspice\\lib\\sub\\LTC.lib",
+                "mismatch": true,
+            })
+        );
+
+        // Only named in the log: no source or strategy
+        let logged = LibraryUsed {
+            name: "standard.dio".to_string(),
+            source: None,
+            strategy: None,
+            expanded_from: None,
+            loaded_path: None,
+            mismatch: false,
+        };
+        assert_eq!(serde_json::to_value(&logged).unwrap(), serde_json::json!({ "name": "standard.dio", "mismatch": false }));
     }
 
     #[test]
@@ -457,11 +2402,38 @@ mod tests {
             msg_type: "pong".to_string(),
             timestamp: 1704067200001,
             status: "ready".to_string(),
+            current_request_id: None,
+            progress_percent: None,
+            elapsed_ms: None,
+            queue_length: None,
         };
 
         let pong_json = serde_json::to_string(&pong).unwrap();
         assert!(pong_json.contains("\"type\":\"pong\""));
         assert!(pong_json.contains("\"status\":\"ready\""));
+        // Idle pongs look the same as before the progress fields were added
+        assert!(!pong_json.contains("currentRequestId"));
+        assert!(!pong_json.contains("queueLength"));
+    }
+
+    #[test]
+    fn test_pong_with_progress_serialization() {
+        let pong = PongResponse {
+            id: "pong-123".to_string(),
+            msg_type: "pong".to_string(),
+            timestamp: 1704067200001,
+            status: "busy".to_string(),
+            current_request_id: Some("sim-1".to_string()),
+            progress_percent: Some(42.5),
+            elapsed_ms: Some(1500),
+            queue_length: Some(0),
+        };
+
+        let json = serde_json::to_value(&pong).unwrap();
+        assert_eq!(json["currentRequestId"], "sim-1");
+        assert_eq!(json["progressPercent"], 42.5);
+        assert_eq!(json["elapsedMs"], 1500);
+        assert_eq!(json["queueLength"], 0);
     }
 
     #[test]
@@ -471,14 +2443,511 @@ mod tests {
             msg_type: "simulation_progress".to_string(),
             request_id: "sim-123".to_string(),
             timestamp: 1704067200000,
-            stage: "running".to_string(),
+            stage: ProgressStage::Running { percent: None },
             message: "Executing simulation...".to_string(),
+            corner: None,
+            completed: None,
+            total: None,
+            resources: None,
         };
 
         let json = serde_json::to_string(&progress).unwrap();
         assert!(json.contains("\"type\":\"simulation_progress\""));
         assert!(json.contains("\"requestId\":\"sim-123\""));
         assert!(json.contains("\"stage\":\"running\""));
+        assert!(!json.contains("\"corner\""));
+        assert!(!json.contains("\"completed\""));
+        assert!(!json.contains("\"resources\""));
+        assert!(!json.contains("\"percent\""));
+    }
+
+    #[test]
+    fn test_netlist_analysis_serialization() {
+        let tran = NetlistAnalysis::Tran {
+            step: Some("1u".to_string()),
+            stop: Some("10m".to_string()),
+            start: None,
+            max_step: Some("1n".to_string()),
+        };
+        assert_eq!(
+            serde_json::to_string(&tran).unwrap(),
+            r#"{"type":"tran","step":"1u","stop":"10m","maxStep":"1n"}"#
+        );
+
+        let ac = NetlistAnalysis::Ac {
+            sweep: Some("dec".to_string()),
+            points: Some(10),
+            fstart: Some("1".to_string()),
+            fstop: Some("100k".to_string()),
+        };
+        assert_eq!(
+            serde_json::to_string(&ac).unwrap(),
+            r#"{"type":"ac","sweep":"dec","points":10,"fstart":"1","fstop":"100k"}"#
+        );
+        assert_eq!(serde_json::to_string(&NetlistAnalysis::Op).unwrap(), r#"{"type":"op"}"#);
+    }
+
+    #[test]
+    fn test_progress_stage_serialization() {
+        let json = serde_json::to_string(&ProgressStage::Launching { pid: 1234 }).unwrap();
+        assert_eq!(json, r#"{"stage":"launching","pid":1234}"#);
+
+        let json = serde_json::to_string(&ProgressStage::ParsingResults { raw_file_bytes: 2048 }).unwrap();
+        assert_eq!(json, r#"{"stage":"parsing_results","rawFileBytes":2048}"#);
+
+        let json = serde_json::to_string(&ProgressStage::Complete).unwrap();
+        assert_eq!(json, r#"{"stage":"complete"}"#);
+
+        let still_running = ProgressStage::StillRunning { elapsed_ms: 65_000, last_log_activity: Some(1_700_000_000_000) };
+        let json = serde_json::to_string(&still_running).unwrap();
+        assert_eq!(json, r#"{"stage":"still_running","elapsedMs":65000,"lastLogActivity":1700000000000}"#);
+
+        let stage: ProgressStage = serde_json::from_str(r#"{"stage":"resolving_libraries","includedFiles":3}"#).unwrap();
+        assert_eq!(stage, ProgressStage::ResolvingLibraries { included_files: 3 });
+        assert_eq!(stage.name(), "resolving_libraries");
+
+        // name() must agree with the serde tag for every stage
+        for stage in [
+            ProgressStage::Preparing { temp_dir: String::new() },
+            ProgressStage::ResolvingLibraries { included_files: 0 },
+            ProgressStage::Launching { pid: 1 },
+            ProgressStage::Running { percent: None },
+            ProgressStage::ParsingResults { raw_file_bytes: 0 },
+            ProgressStage::TimeoutLimited { requested_secs: 0, effective_secs: 0 },
+            ProgressStage::Warning { estimated_secs: 0, confirmation_required: false },
+            ProgressStage::Retrying { fallback: "uic".to_string() },
+            ProgressStage::SwitchingEngine { simulator: "ngspice".to_string() },
+            ProgressStage::StillRunning { elapsed_ms: 0, last_log_activity: None },
+            ProgressStage::Complete,
+        ] {
+            assert_eq!(serde_json::to_value(&stage).unwrap()["stage"], stage.name());
+        }
+    }
+
+    #[test]
+    fn test_compress_results_round_trip() {
+        use base64::prelude::{Engine, BASE64_STANDARD};
+        use std::io::Read;
+
+        let results = SimulationResults {
+            time: (0..10_000).map(|i| i as f64 * 1e-6).collect(),
+            traces: vec![Trace {
+                name: "V(out)".to_string(),
+                data: (0..10_000).map(|i| (i as f64 / 100.0).sin()).collect(),
+                unit: "V".to_string(),
+                phase: None,
+                raw_name: None,
+            }],
+            analysis_type: "transient".to_string(),
+            x_axis_label: None,
+            x_axis_unit: None,
+            scalar_results: None,
+            device_operating_points: BTreeMap::new(),
+            truncated: None,
+            plot_hints: None,
+            raw_header: None,
+        };
+        let mut response = SimulationResponse {
+            id: "resp-1".to_string(),
+            msg_type: "simulation_result".to_string(),
+            request_id: "sim-1".to_string(),
+            timestamp: 0,
+            success: true,
+            f32_results: false,
+            results: Some(results.clone()),
+            error: None,
+            error_code: None,
+            execution_time: 0,
+            simulator: "ngspice".to_string(),
+            monte_carlo: None,
+            duplicate: false,
+            retried_with: None,
+            translations: Vec::new(),
+            debug_info: None,
+            results_compressed: None,
+            compression: None,
+            retry_after_ms: None,
+            warnings: Vec::new(),
+            simulator_version: None,
+            simulator_binary_sha256: None,
+            replay_of: None,
+            fallback_simulator_used: None,
+            engine_errors: Vec::new(),
+            artifact_id: None,
+            raw_file_size: None,
+            trace_catalog: None,
+            operating_point: None,
+            wave_data: None,
+            libraries_used: Vec::new(),
+            busy_info: None,
+            timings: SimulationTimings::default(),
+        };
+
+        let (json_bytes, compressed_bytes) = response.compress_results().unwrap().unwrap();
+        assert!(compressed_bytes < json_bytes);
+        let json = serde_json::to_value(&response).unwrap();
+        assert!(json.get("results").is_none());
+        assert_eq!(json["compression"], "gzip");
+
+        let gzip = BASE64_STANDARD.decode(json["resultsCompressed"].as_str().unwrap()).unwrap();
+        let mut decompressed = String::new();
+        flate2::read::GzDecoder::new(gzip.as_slice()).read_to_string(&mut decompressed).unwrap();
+        assert_eq!(decompressed.len(), json_bytes);
+        assert_eq!(decompressed, serde_json::to_string(&results).unwrap());
+
+        // JSON float parsing may be off in the last digit
+        let restored = response.decompressed_results().unwrap().unwrap();
+        assert_eq!(restored.traces.len(), results.traces.len());
+        assert_eq!(restored.time.len(), results.time.len());
+        assert!(restored.time.iter().zip(&results.time).all(|(a, b)| (a - b).abs() <= b.abs() * 1e-15));
+
+        // Nothing to compress on a failed response
+        response.results_compressed = None;
+        assert_eq!(response.compress_results().unwrap(), None);
+        assert!(response.results_compressed.is_none());
+    }
+
+    #[test]
+    fn test_serialize_timed_writes_final_timings() {
+        let mut response = SimulationResponse {
+            id: "resp-1".to_string(),
+            msg_type: "simulation_result".to_string(),
+            request_id: "sim-1".to_string(),
+            timestamp: 0,
+            success: true,
+            f32_results: false,
+            results: Some(SimulationResults {
+                time: vec![0.0; 100_000],
+                traces: vec![Trace { name: "\"timings\":".to_string(), data: vec![1.0; 100_000], unit: "V".to_string(), phase: None, raw_name: None }],
+                analysis_type: "transient".to_string(),
+                x_axis_label: None,
+                x_axis_unit: None,
+                scalar_results: None,
+                device_operating_points: BTreeMap::new(),
+                truncated: None,
+                plot_hints: None,
+                raw_header: None,
+            }),
+            error: None,
+            error_code: None,
+            execution_time: 1200,
+            simulator: "ngspice".to_string(),
+            monte_carlo: None,
+            duplicate: false,
+            retried_with: None,
+            translations: Vec::new(),
+            debug_info: None,
+            results_compressed: None,
+            compression: None,
+            retry_after_ms: None,
+            warnings: Vec::new(),
+            simulator_version: None,
+            simulator_binary_sha256: None,
+            replay_of: None,
+            fallback_simulator_used: None,
+            engine_errors: Vec::new(),
+            artifact_id: None,
+            raw_file_size: None,
+            trace_catalog: None,
+            operating_point: None,
+            wave_data: None,
+            libraries_used: Vec::new(),
+            busy_info: None,
+            timings: SimulationTimings { simulator_ms: 1000, total_ms: 1200, ..SimulationTimings::default() },
+        };
+
+        let json = response.serialize_timed().unwrap();
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(value["timings"], serde_json::to_value(&response.timings).unwrap());
+        assert_eq!(value["timings"]["totalMs"], 1200 + response.timings.serialization_ms);
+        assert_eq!(value["results"]["traces"][0]["name"], "\"timings\":");
+        assert_eq!(value.as_object().unwrap().keys().filter(|key| *key == "timings").count(), 1);
+    }
+
+    #[test]
+    fn test_timings_throughput() {
+        let timings = SimulationTimings {
+            raw_file_bytes: 50 * 1024 * 1024,
+            raw_parse_ms: 2000,
+            raw_read_ms: 500,
+            ..SimulationTimings::default()
+        };
+        assert_eq!(timings.parse_throughput(), Some(25.0));
+        assert_eq!(timings.raw_read_throughput(), Some(100.0));
+        assert_eq!(SimulationTimings { raw_read_ms: 0, ..timings.clone() }.raw_read_throughput(), None);
+        assert_eq!(SimulationTimings::default().parse_throughput(), None);
+
+        let value = serde_json::to_value(SimulationTimings { parse_mb_per_sec: Some(25.0), ..timings }).unwrap();
+        assert_eq!(value["rawReadMs"], 500);
+        assert_eq!(value["parseMbPerSec"], 25.0);
+        assert!(serde_json::to_value(SimulationTimings::default()).unwrap().get("parseMbPerSec").is_none());
+    }
+
+    #[test]
+    fn test_simulation_response_summary() {
+        let mut response = SimulationResponse {
+            id: "resp-1".to_string(),
+            msg_type: "simulation_result".to_string(),
+            request_id: "sim-1".to_string(),
+            timestamp: 0,
+            success: true,
+            f32_results: false,
+            results: Some(SimulationResults {
+                time: vec![0.0; 1_200_000],
+                traces: vec![],
+                analysis_type: "transient".to_string(),
+                x_axis_label: None,
+                x_axis_unit: None,
+                scalar_results: None,
+                device_operating_points: BTreeMap::new(),
+                truncated: None,
+                plot_hints: None,
+                raw_header: None,
+            }),
+            error: None,
+            error_code: None,
+            execution_time: 94_200,
+            simulator: "ltspice".to_string(),
+            monte_carlo: None,
+            duplicate: false,
+            retried_with: None,
+            translations: Vec::new(),
+            debug_info: None,
+            results_compressed: None,
+            compression: None,
+            retry_after_ms: None,
+            warnings: Vec::new(),
+            simulator_version: None,
+            simulator_binary_sha256: None,
+            replay_of: None,
+            fallback_simulator_used: None,
+            engine_errors: Vec::new(),
+            artifact_id: None,
+            raw_file_size: None,
+            trace_catalog: None,
+            operating_point: None,
+            wave_data: None,
+            libraries_used: Vec::new(),
+            busy_info: None,
+            timings: SimulationTimings::default(),
+        };
+        assert_eq!(response.summary(), "Simulation complete: 0 traces, 1.2 M points, 94 s");
+
+        let values = [("Transfer_function".to_string(), 0.5)].into_iter().collect();
+        response.results.as_mut().unwrap().scalar_results = Some(values);
+        assert_eq!(response.summary(), "Simulation complete: 1 value, 94 s");
+
+        response.success = false;
+        response.results = None;
+        response.execution_time = 1_500;
+        response.error = Some("Timestep too small\nat time 1e-9".to_string());
+        assert_eq!(response.summary(), "Simulation failed after 1.5 s: Timestep too small");
+    }
+
+    #[test]
+    fn test_simulation_progress_percent() {
+        let mut progress = SimulationProgress {
+            id: "prog-1".to_string(),
+            msg_type: "simulation_progress".to_string(),
+            request_id: "mc-1".to_string(),
+            timestamp: 0,
+            stage: ProgressStage::Running { percent: None },
+            message: "Completed run 25/100".to_string(),
+            corner: None,
+            completed: Some(25),
+            total: Some(100),
+            resources: None,
+        };
+        assert_eq!(progress.percent(), Some(25.0));
+
+        progress.stage = ProgressStage::Running { percent: Some(42.0) };
+        assert_eq!(progress.percent(), Some(42.0));
+
+        progress.stage = ProgressStage::Complete;
+        progress.total = Some(0);
+        assert_eq!(progress.percent(), None);
+    }
+
+    #[test]
+    fn test_simulation_progress_with_resources() {
+        let progress = SimulationProgress {
+            id: "prog-456".to_string(),
+            msg_type: "simulation_progress".to_string(),
+            request_id: "sim-456".to_string(),
+            timestamp: 1704067200000,
+            stage: ProgressStage::Running { percent: Some(42.0) },
+            message: "Simulating".to_string(),
+            corner: None,
+            completed: None,
+            total: None,
+            resources: Some(ResourceUsage {
+                cpu_percent: 97.5,
+                memory_bytes: 1048576,
+                raw_file_bytes: 4096,
+            }),
+        };
+
+        let json = serde_json::to_string(&progress).unwrap();
+        assert!(json.contains("\"stage\":\"running\""));
+        assert!(json.contains("\"percent\":42.0"));
+        assert!(json.contains("\"cpuPercent\":97.5"));
+        assert!(json.contains("\"memoryBytes\":1048576"));
+        assert!(json.contains("\"rawFileBytes\":4096"));
+    }
+
+    #[test]
+    fn test_simulation_request_with_monte_carlo() {
+        let json = r#"{
+            "id": "sim-mc",
+            "type": "simulate",
+            "netlist": "* Test",
+            "monteCarlo": {"runs": 50, "measurements": ["vout_max", "tdelay"]},
+            "timestamp": 1704067200000
+        }"#;
+
+        let request: SimulationRequest = serde_json::from_str(json).unwrap();
+        let mc = request.monte_carlo.unwrap();
+        assert_eq!(mc.runs, 50);
+        assert_eq!(mc.measurements, vec!["vout_max", "tdelay"]);
+        assert_eq!(mc.seed, None);
+    }
+
+    #[test]
+    fn test_simulation_response_with_monte_carlo() {
+        let response = SimulationResponse {
+            id: "resp-mc".to_string(),
+            msg_type: "simulation_result".to_string(),
+            request_id: "sim-mc".to_string(),
+            timestamp: 1704067200000,
+            success: true,
+            f32_results: false,
+            results: None,
+            error: None,
+            error_code: None,
+            execution_time: 5000,
+            simulator: "ltspice".to_string(),
+            monte_carlo: Some(MonteCarloResults {
+                runs: 2,
+                completed_runs: 2,
+                measurements: vec![MeasurementStatistics {
+                    name: "vout_max".to_string(),
+                    values: vec![Some(4.9), None],
+                    mean: Some(4.9),
+                    stddev: Some(0.0),
+                    min: Some(4.9),
+                    max: Some(4.9),
+                    failed_runs: 1,
+                }],
+            }),
+            duplicate: false,
+            retried_with: None,
+            translations: Vec::new(),
+            debug_info: None,
+            results_compressed: None,
+            compression: None,
+            retry_after_ms: None,
+            warnings: Vec::new(),
+            simulator_version: None,
+            simulator_binary_sha256: None,
+            replay_of: None,
+            fallback_simulator_used: None,
+            engine_errors: Vec::new(),
+            artifact_id: None,
+            raw_file_size: None,
+            trace_catalog: None,
+            operating_point: None,
+            wave_data: None,
+            libraries_used: Vec::new(),
+            busy_info: None,
+            timings: SimulationTimings::default(),
+        };
+
+        let json = serde_json::to_string(&response).unwrap();
+        assert!(json.contains("\"monteCarlo\""));
+        assert!(json.contains("\"completedRuns\":2"));
+        assert!(json.contains("\"values\":[4.9,null]"));
+        assert!(json.contains("\"failedRuns\":1"));
+        assert!(!json.contains("\"results\""));
+    }
+
+    #[test]
+    fn test_batch_simulation_request_deserialization() {
+        let json = r#"{
+            "id": "batch-123",
+            "type": "batch_simulate",
+            "netlist": "* Test\nV1 in 0 {vin}\n.tran 1m\n.end",
+            "corners": [
+                {"name": "vin_min", "params": {"vin": 4.5}, "temperature": -40},
+                {"name": "vin_max", "params": {"vin": "5.5"}},
+                {"name": "hot", "temperature": 125}
+            ],
+            "timestamp": 1704067200000
+        }"#;
+
+        let request: BatchSimulationRequest = serde_json::from_str(json).unwrap();
+        assert_eq!(request.msg_type, "batch_simulate");
+        assert_eq!(request.simulator, "ltspice"); // default value
+        assert_eq!(request.waveform_quality, "smooth"); // default value
+        assert_eq!(request.corners.len(), 3);
+        assert_eq!(request.corners[0].name, "vin_min");
+        assert_eq!(request.corners[0].temperature, Some(-40.0));
+        assert_eq!(request.corners[0].params["vin"], serde_json::json!(4.5));
+        assert_eq!(request.corners[1].params["vin"], serde_json::json!("5.5"));
+        assert!(request.corners[2].params.is_empty());
+        assert_eq!(request.corners[1].temperature, None);
+    }
+
+    #[test]
+    fn test_batch_simulation_response_serialization() {
+        let response = BatchSimulationResponse {
+            id: "resp-batch-123".to_string(),
+            msg_type: "batch_simulation_result".to_string(),
+            request_id: "batch-123".to_string(),
+            timestamp: 1704067200000,
+            success: false,
+            corners: vec![
+                CornerResult {
+                    name: "vin_min".to_string(),
+                    success: true,
+                    results: Some(SimulationResults {
+                        time: vec![0.0, 0.001],
+                        traces: vec![],
+                        analysis_type: "transient".to_string(),
+                        x_axis_label: Some("time".to_string()),
+                        x_axis_unit: None,
+                        scalar_results: None,
+                        device_operating_points: BTreeMap::new(),
+                        truncated: None,
+                        plot_hints: None,
+                        raw_header: None,
+                    }),
+                    error: None,
+                    execution_time: 800,
+                },
+                CornerResult {
+                    name: "vin_max".to_string(),
+                    success: false,
+                    results: None,
+                    error: Some("Simulation cancelled".to_string()),
+                    execution_time: 0,
+                },
+            ],
+            error: None,
+            error_code: None,
+            execution_time: 900,
+            simulator: "ltspice".to_string(),
+            retry_after_ms: None,
+            busy_info: None,
+        };
+
+        let json = serde_json::to_string(&response).unwrap();
+        assert!(json.contains("\"type\":\"batch_simulation_result\""));
+        assert!(json.contains("\"requestId\":\"batch-123\""));
+        assert!(json.contains("\"name\":\"vin_min\""));
+        assert!(json.contains("\"error\":\"Simulation cancelled\""));
+        assert!(json.contains("\"executionTime\":800"));
+        // Request-level error should be skipped when None
+        assert!(!json.contains("\"error\":null"));
     }
 
     #[test]
@@ -486,6 +2955,195 @@ mod tests {
         assert_eq!(PROTOCOL_VERSION, "1.0.0");
         assert_eq!(AGENT_VERSION, "1.0.0");
         assert_eq!(WS_PORT, 9347);
+        assert_eq!(MAX_BATCH_SIZE, 10);
+        assert_eq!(MAX_MONTE_CARLO_RUNS, 1000);
+        assert_eq!(MAX_WS_EXPORT_BYTES, 8 * 1024 * 1024);
         assert_eq!(ALLOWED_ORIGINS.len(), 4);
     }
+
+    #[test]
+    fn test_capabilities_changed_serialization() {
+        let message = CapabilitiesChanged {
+            id: "msg-1".to_string(),
+            msg_type: "capabilities_changed".to_string(),
+            request_id: None,
+            timestamp: 1000,
+            ltspice_path: None,
+            ngspice_path: Some("/usr/bin/ngspice".to_string()),
+            capabilities: AgentCapabilities {
+                ltspice_available: false,
+                ngspice_available: true,
+                supported_analyses: vec!["transient".to_string()],
+                analyses: vec![],
+                supported_input_formats: vec![],
+                max_simulation_time: 300,
+                max_batch_size: MAX_BATCH_SIZE,
+                engine_options: vec![],
+                accepts_compressed_netlist: true,
+            },
+            onboarding: OnboardingState { stage: OnboardingStage::DetectedNgspiceOnly, guides: vec![] },
+            checked_at: Some(900),
+        };
+
+        let json = serde_json::to_string(&message).unwrap();
+        assert!(json.contains("\"type\":\"capabilities_changed\""));
+        assert!(json.contains("\"ngspicePath\":\"/usr/bin/ngspice\""));
+        assert!(json.contains("\"ngspiceAvailable\":true"));
+        assert!(json.contains("\"checkedAt\":900"));
+        assert!(!json.contains("requestId"));
+        assert!(!json.contains("ltspicePath"));
+    }
+
+    #[test]
+    fn test_metrics_response_serialization() {
+        let response = MetricsResponse {
+            id: "resp-1".to_string(),
+            msg_type: "metrics".to_string(),
+            request_id: "metrics-1".to_string(),
+            timestamp: 1000,
+            metrics: AgentMetrics {
+                uptime_secs: 3600,
+                since: 500,
+                simulators: vec![SimulatorMetrics {
+                    simulator: "ngspice".to_string(),
+                    succeeded: 3,
+                    failed: 1,
+                }],
+                cancelled: 2,
+                failure_rate: 0.25,
+                average_execution_ms: Some(1200),
+                p95_execution_ms: None,
+                result_bytes_served: 4096,
+                progress_messages_dropped: 0,
+                origins: vec![],
+            },
+        };
+
+        let json = serde_json::to_string(&response).unwrap();
+        assert!(json.contains("\"type\":\"metrics\""));
+        assert!(json.contains("\"requestId\":\"metrics-1\""));
+        assert!(json.contains("\"uptimeSecs\":3600"));
+        assert!(json.contains("\"failureRate\":0.25"));
+        assert!(json.contains("\"averageExecutionMs\":1200"));
+        assert!(json.contains("\"resultBytesServed\":4096"));
+        assert!(json.contains("\"simulators\":[{\"simulator\":\"ngspice\",\"succeeded\":3,\"failed\":1}]"));
+        assert!(!json.contains("p95ExecutionMs"));
+
+        let parsed: AgentMetrics = serde_json::from_str(&serde_json::to_string(&response.metrics).unwrap()).unwrap();
+        assert_eq!(parsed, response.metrics);
+    }
+
+    #[test]
+    fn test_status_response_serialization() {
+        let response = StatusResponse {
+            id: "resp-1".to_string(),
+            msg_type: "status".to_string(),
+            request_id: "status-1".to_string(),
+            timestamp: 1000,
+            agent_version: "1.2.3".to_string(),
+            ws_port: WS_PORT,
+            ltspice_available: false,
+            ltspice_path: None,
+            ltspice_binary: None,
+            ngspice_available: true,
+            ngspice_path: Some("/usr/bin/ngspice".to_string()),
+            ngspice_binary: Some(SimulatorBinary {
+                version: Some("ngspice-42".to_string()),
+                sha256: "ab12".to_string(),
+            }),
+            simulators_checked_at: Some(900),
+            onboarding: OnboardingState {
+                stage: OnboardingStage::DetectedNgspiceOnly,
+                guides: vec![InstallGuide {
+                    simulator: "ltspice".to_string(),
+                    message: "Install LTspice".to_string(),
+                    url: Some("https://example.com".to_string()),
+                    command: None,
+                }],
+            },
+            is_simulating: true,
+            active_simulations: vec![ActiveSimulationInfo {
+                request_id: "sim-1".to_string(),
+                elapsed_ms: 250,
+                resources: None,
+                progress_percent: Some(40.0),
+            }],
+            queue_length: 1,
+            max_concurrent_simulations: 2,
+            background_priority: false,
+            ngspice_threads: None,
+            max_simulation_seconds: 300,
+            orphaned_processes_killed: 0,
+            server_error: None,
+            log_file: None,
+            log_level: "info".to_string(),
+            temp_root: "/tmp".to_string(),
+            temp_root_error: None,
+            ws_connections: 1,
+            simulation_count: 7,
+            last_simulation_time: Some(800),
+            metrics: AgentMetrics::default(),
+            status_file: Some("/home/me/.local/share/kelicad-agent/status.json".to_string()),
+            resource_usage: BudgetUsage {
+                results: crate::resource_budget::PoolUsage { used: 2048, max: 4096, entries: 2 },
+                ..BudgetUsage::default()
+            },
+        };
+
+        let json = serde_json::to_string(&response).unwrap();
+        assert!(json.contains("\"type\":\"status\""));
+        assert!(json.contains("\"requestId\":\"status-1\""));
+        assert!(json.contains("\"agentVersion\":\"1.2.3\""));
+        assert!(json.contains("\"ngspicePath\":\"/usr/bin/ngspice\""));
+        assert!(json.contains("\"ngspiceBinary\":{\"version\":\"ngspice-42\",\"sha256\":\"ab12\"}"));
+        assert!(json.contains("\"activeSimulations\":[{\"requestId\":\"sim-1\",\"elapsedMs\":250,\"progressPercent\":40.0}]"));
+        assert!(json.contains("\"queueLength\":1"));
+        assert!(json.contains("\"maxConcurrentSimulations\":2"));
+        assert!(json.contains("\"wsConnections\":1"));
+        assert!(json.contains("\"tempRoot\":\"/tmp\""));
+        assert!(json.contains("\"onboarding\":{\"stage\":\"detected_ngspice_only\",\"guides\":[{\"simulator\":\"ltspice\","));
+        assert!(json.contains("\"uptimeSecs\":0"));
+        assert!(json.contains("\"statusFile\":\"/home/me/.local/share/kelicad-agent/status.json\""));
+        assert!(json.contains("\"resourceUsage\":{\"results\":{\"used\":2048,\"max\":4096,\"entries\":2},"));
+        // Absent values are left out rather than sent as null
+        assert!(!json.contains("ltspicePath"));
+        assert!(!json.contains("serverError"));
+        assert!(!json.contains("tempRootError"));
+        assert!(!json.contains("null"));
+    }
+
+    #[test]
+    fn test_export_response_serialization() {
+        let response = ExportResponse {
+            id: "resp-1".to_string(),
+            msg_type: "export_response".to_string(),
+            request_id: "export-1".to_string(),
+            timestamp: 1000,
+            success: false,
+            csv: None,
+            error: Some("No simulation results to export".to_string()),
+            error_code: Some("NO_RESULTS".to_string()),
+        };
+
+        let json = serde_json::to_string(&response).unwrap();
+        assert!(json.contains("\"type\":\"export_response\""));
+        assert!(json.contains("\"requestId\":\"export-1\""));
+        assert!(json.contains("\"errorCode\":\"NO_RESULTS\""));
+        assert!(!json.contains("\"csv\""));
+    }
+
+    #[test]
+    fn test_debug_info_truncates_long_netlists() {
+        let info = DebugInfo::new("* Test\n.end", vec![], "ngspice -b circuit.cir".to_string(), "/tmp/x".to_string());
+        assert_eq!(info.prepared_netlist, "* Test\n.end");
+        let json = serde_json::to_value(&info).unwrap();
+        assert!(json.get("preparedNetlistTruncated").is_none());
+        assert_eq!(json["commandLine"], "ngspice -b circuit.cir");
+
+        // Cut on a character boundary
+        let netlist = format!("*{}", "é".repeat(MAX_DEBUG_NETLIST_BYTES));
+        let info = DebugInfo::new(&netlist, vec![], String::new(), String::new());
+        assert!(info.prepared_netlist_truncated);
+        assert_eq!(info.prepared_netlist.len(), MAX_DEBUG_NETLIST_BYTES - 1);
+    }
 }
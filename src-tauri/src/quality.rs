@@ -0,0 +1,143 @@
+// Copyright (c) 2024-2025 Wanyeki Technologies LLC. All rights reserved.
+// This source code is licensed under the proprietary license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Waveform quality profiles: named sets of simulator options a request picks with `waveformQuality`
+//!
+//! Profiles live in the settings file, so advanced users can change what a name means or add
+//! their own. Each one has an option map per engine, written into the netlist as `.options`;
+//! anything the netlist already sets is left as the user wrote it.
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+/// Options a profile adds for each engine, by `.options` name
+/// An empty value adds the option as a bare flag, like ngspice's `interp`
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct QualityProfile {
+    pub ltspice: BTreeMap<String, String>,
+    pub ngspice: BTreeMap<String, String>,
+}
+
+impl QualityProfile {
+    /// Options for runs whose waveforms are discarded: LTspice's cheapest output, ngspice's
+    /// default accuracy
+    pub fn measurements() -> Self {
+        profile(&[("plotwinsize", "128")], &[])
+    }
+
+    /// The option map for `simulator`
+    pub fn options(&self, simulator: &str) -> &BTreeMap<String, String> {
+        match simulator {
+            "ngspice" => &self.ngspice,
+            _ => &self.ltspice,
+        }
+    }
+
+    /// LTspice's waveform compression window, or None if the profile leaves it at LTspice's default
+    pub fn plotwinsize(&self) -> Option<u32> {
+        self.ltspice.get("plotwinsize").and_then(|value| value.trim().parse().ok())
+    }
+}
+
+fn profile(ltspice: &[(&str, &str)], ngspice: &[(&str, &str)]) -> QualityProfile {
+    let map = |options: &[(&str, &str)]| {
+        options.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect()
+    };
+    QualityProfile {
+        ltspice: map(ltspice),
+        ngspice: map(ngspice),
+    }
+}
+
+/// The profiles shipped with the agent
+///
+/// | profile  | LTspice           | ngspice                |
+/// |----------|-------------------|------------------------|
+/// | fast     | `plotwinsize=128` | `reltol=1e-2 trtol=10` |
+/// | balanced | `plotwinsize=0`   | simulator defaults     |
+/// | smooth   | `plotwinsize=0`   | `reltol=1e-4 interp`   |
+pub fn default_quality_profiles() -> BTreeMap<String, QualityProfile> {
+    BTreeMap::from([
+        ("fast".to_string(), profile(&[("plotwinsize", "128")], &[("reltol", "1e-2"), ("trtol", "10")])),
+        ("balanced".to_string(), profile(&[("plotwinsize", "0")], &[])),
+        ("smooth".to_string(), profile(&[("plotwinsize", "0")], &[("reltol", "1e-4"), ("interp", "")])),
+    ])
+}
+
+/// The profile a request's `waveformQuality` names
+pub fn resolve(profiles: &BTreeMap<String, QualityProfile>, name: &str) -> Result<QualityProfile, String> {
+    profiles.get(name).cloned().ok_or_else(|| {
+        let available: Vec<&str> = profiles.keys().map(String::as_str).collect();
+        format!(
+            "Unknown waveform quality \"{}\"; available profiles: {}",
+            name,
+            available.join(", ")
+        )
+    })
+}
+
+/// Check a profile before it is saved: names are single words, and nothing can add another
+/// line or directive to the netlist
+pub fn validate(name: &str, profile: &QualityProfile) -> Result<(), String> {
+    let is_word = |text: &str| {
+        !text.is_empty() && text.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    };
+    if !is_word(name) {
+        return Err(format!("\"{}\" is not a valid profile name; use letters, digits, - and _", name));
+    }
+    for (simulator, options) in [("LTspice", &profile.ltspice), ("ngspice", &profile.ngspice)] {
+        for (option, value) in options {
+            if !is_word(option) {
+                return Err(format!("\"{}\" is not a valid {} option name", option, simulator));
+            }
+            if value.chars().any(|c| c.is_whitespace() || c.is_control() || c == '=' || c == ';') {
+                return Err(format!("Value of {} option {} must be a single word", simulator, option));
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_lists_available_profiles() {
+        let profiles = default_quality_profiles();
+        assert_eq!(resolve(&profiles, "fast").unwrap().plotwinsize(), Some(128));
+        assert_eq!(resolve(&profiles, "smooth").unwrap().options("ngspice").get("interp").map(String::as_str), Some(""));
+        assert_eq!(
+            resolve(&profiles, "ultra").unwrap_err(),
+            "Unknown waveform quality \"ultra\"; available profiles: balanced, fast, smooth"
+        );
+    }
+
+    #[test]
+    fn test_validate() {
+        let mut custom = profile(&[("plotwinsize", "0")], &[("reltol", "1e-5"), ("interp", "")]);
+        assert!(validate("precise", &custom).is_ok());
+        assert!(validate("very precise", &custom).is_err());
+        assert!(validate("", &custom).is_err());
+
+        custom.ngspice.insert("gmin".to_string(), "1e-12\n.include evil.lib".to_string());
+        assert_eq!(validate("precise", &custom).unwrap_err(), "Value of ngspice option gmin must be a single word");
+        custom.ngspice.remove("gmin");
+        custom.ltspice.insert("max step".to_string(), "1u".to_string());
+        assert!(validate("precise", &custom).is_err());
+    }
+
+    #[test]
+    fn test_profiles_from_settings_json() {
+        let profiles: BTreeMap<String, QualityProfile> = serde_json::from_str(
+            r#"{"draft": {"ltspice": {"plotwinsize": "512"}}, "precise": {"ngspice": {"reltol": "1e-5"}}}"#,
+        )
+        .unwrap();
+        assert_eq!(profiles["draft"].plotwinsize(), Some(512));
+        assert!(profiles["draft"].ngspice.is_empty());
+        assert_eq!(profiles["precise"].plotwinsize(), None);
+    }
+}
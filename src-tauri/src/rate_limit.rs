@@ -0,0 +1,170 @@
+// Copyright (c) 2024-2025 Wanyeki Technologies LLC. All rights reserved.
+// This source code is licensed under the proprietary license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Per-origin limit on how often simulations may be started
+//!
+//! Each origin gets a token bucket holding up to `per_minute` starts that refills at
+//! `per_minute` tokens a minute, so a page can burst briefly but not sustain more.
+
+use std::collections::HashMap;
+
+use crate::protocol::now_ms;
+
+const MS_PER_MINUTE: f64 = 60_000.0;
+
+/// Starts available to one origin
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    capacity: f64,
+    tokens: f64,
+    /// Unix time in ms the tokens were last refilled
+    refilled_at: u64,
+}
+
+impl TokenBucket {
+    /// A full bucket of `per_minute` tokens
+    pub fn new(per_minute: u32, now: u64) -> Self {
+        Self {
+            capacity: per_minute as f64,
+            tokens: per_minute as f64,
+            refilled_at: now,
+        }
+    }
+
+    fn refill(&mut self, now: u64) {
+        let elapsed_ms = now.saturating_sub(self.refilled_at) as f64;
+        self.tokens = (self.tokens + elapsed_ms * self.capacity / MS_PER_MINUTE).min(self.capacity);
+        self.refilled_at = now;
+    }
+
+    /// Take a token, or return the ms until one is available
+    pub fn try_take(&mut self, now: u64) -> Result<(), u64> {
+        self.refill(now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return Ok(());
+        }
+        if self.capacity <= 0.0 {
+            return Err(u64::MAX);
+        }
+        Err(((1.0 - self.tokens) * MS_PER_MINUTE / self.capacity).ceil() as u64)
+    }
+
+    /// Change the rate, keeping no more tokens than the new capacity
+    fn set_per_minute(&mut self, per_minute: u32, now: u64) {
+        self.refill(now);
+        self.capacity = per_minute as f64;
+        self.tokens = self.tokens.min(self.capacity);
+    }
+}
+
+/// True for pages served from this machine, e.g. a development server
+pub fn is_localhost(origin: &str) -> bool {
+    let host = origin.split_once("://").map_or(origin, |(_, rest)| rest);
+    let host = host.split('/').next().unwrap_or_default();
+    let host = match host.rsplit_once(':') {
+        Some((name, port)) if !name.is_empty() && port.chars().all(|c| c.is_ascii_digit()) => name,
+        _ => host,
+    };
+    matches!(host, "localhost" | "127.0.0.1" | "[::1]")
+}
+
+/// Token buckets by origin
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    buckets: HashMap<String, TokenBucket>,
+}
+
+impl RateLimiter {
+    /// Count a simulation start for `origin`, or return the ms until it may start one
+    /// A `per_minute` of 0 disables the limit; localhost is exempt unless `limit_localhost`
+    pub fn check(&mut self, origin: &str, per_minute: u32, limit_localhost: bool) -> Result<(), u64> {
+        self.check_at(origin, per_minute, limit_localhost, now_ms())
+    }
+
+    fn check_at(&mut self, origin: &str, per_minute: u32, limit_localhost: bool, now: u64) -> Result<(), u64> {
+        if per_minute == 0 || (!limit_localhost && is_localhost(origin)) {
+            return Ok(());
+        }
+        let bucket = self
+            .buckets
+            .entry(origin.to_string())
+            .or_insert_with(|| TokenBucket::new(per_minute, now));
+        if bucket.capacity != per_minute as f64 {
+            bucket.set_per_minute(per_minute, now);
+        }
+        bucket.try_take(now)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bucket_allows_a_burst_then_refills() {
+        let mut bucket = TokenBucket::new(6, 0);
+        for _ in 0..6 {
+            assert_eq!(bucket.try_take(0), Ok(()));
+        }
+        // One token takes 10 s to come back at 6 a minute
+        assert_eq!(bucket.try_take(0), Err(10_000));
+        assert_eq!(bucket.try_take(4_000), Err(6_000));
+        assert_eq!(bucket.try_take(10_000), Ok(()));
+        assert_eq!(bucket.try_take(10_000), Err(10_000));
+
+        // Refill stops at capacity however long the origin was idle
+        for _ in 0..6 {
+            assert_eq!(bucket.try_take(3_600_000), Ok(()));
+        }
+        assert!(bucket.try_take(3_600_000).is_err());
+    }
+
+    #[test]
+    fn test_partial_refill_accumulates() {
+        let mut bucket = TokenBucket::new(60, 0);
+        for _ in 0..60 {
+            bucket.try_take(0).unwrap();
+        }
+        // 1 token a second: half a token after 500 ms, a whole one after another 500 ms
+        assert_eq!(bucket.try_take(500), Err(500));
+        assert_eq!(bucket.try_take(1_000), Ok(()));
+    }
+
+    #[test]
+    fn test_limiter_is_per_origin() {
+        let mut limiter = RateLimiter::default();
+        assert_eq!(limiter.check_at("https://kelicad.com", 1, false, 0), Ok(()));
+        assert_eq!(limiter.check_at("https://kelicad.com", 1, false, 0), Err(60_000));
+        assert_eq!(limiter.check_at("https://www.kelicad.com", 1, false, 0), Ok(()));
+
+        // A raised limit refills the existing bucket at the new rate
+        assert_eq!(limiter.check_at("https://kelicad.com", 2, false, 60_000), Ok(()));
+        assert_eq!(limiter.check_at("https://kelicad.com", 2, false, 90_000), Ok(()));
+    }
+
+    #[test]
+    fn test_localhost_exempt_unless_limited() {
+        let mut limiter = RateLimiter::default();
+        for _ in 0..10 {
+            assert_eq!(limiter.check_at("http://localhost:3000", 1, false, 0), Ok(()));
+        }
+        assert_eq!(limiter.check_at("http://127.0.0.1:3000", 1, true, 0), Ok(()));
+        assert!(limiter.check_at("http://127.0.0.1:3000", 1, true, 0).is_err());
+
+        // 0 turns the limit off everywhere
+        for _ in 0..10 {
+            assert_eq!(limiter.check_at("https://kelicad.com", 0, true, 0), Ok(()));
+        }
+    }
+
+    #[test]
+    fn test_is_localhost() {
+        assert!(is_localhost("http://localhost:3000"));
+        assert!(is_localhost("http://127.0.0.1"));
+        assert!(is_localhost("http://[::1]:8080"));
+        assert!(!is_localhost("https://kelicad.com"));
+        assert!(!is_localhost("https://localhost.example.com"));
+    }
+}
@@ -0,0 +1,231 @@
+// Copyright (c) 2024-2025 Wanyeki Technologies LLC. All rights reserved.
+// This source code is licensed under the proprietary license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! One budget for everything the agent keeps between simulations
+//!
+//! The last results kept for export, finished responses kept for duplicates and `get_result`,
+//! and deferred trace data all hold results in memory. Together they are held to
+//! `max_result_bytes`, the least recently used entry evicted first whichever store holds it.
+//! Raw files kept with `keepRaw` are held to `max_artifact_bytes` and stored netlists to
+//! `max_history_entries`. Stores take part by implementing `Budgeted`; their entries are stamped
+//! from one counter, so which entry is least recently used is well defined across stores and
+//! eviction doesn't depend on clock resolution.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use serde::Serialize;
+
+use crate::settings::AgentSettings;
+
+/// Default for `max_result_bytes`
+pub const DEFAULT_MAX_RESULT_BYTES: u64 = 512 * 1024 * 1024;
+
+/// Default for `max_artifact_bytes`
+pub const DEFAULT_MAX_ARTIFACT_BYTES: u64 = 1024 * 1024 * 1024;
+
+/// Default for `max_history_entries`
+pub const DEFAULT_MAX_HISTORY_ENTRIES: u64 = 20;
+
+static USE_COUNTER: AtomicU64 = AtomicU64::new(1);
+
+/// Stamp for an entry being stored or used; later uses get larger stamps
+pub fn next_use() -> u64 {
+    USE_COUNTER.fetch_add(1, Ordering::Relaxed)
+}
+
+/// An entry a store holds, as the budget sees it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BudgetEntry {
+    pub key: String,
+    /// Bytes, or 1 for stores capped by entry count
+    pub size: u64,
+    /// Stamp from `next_use` of its last use
+    pub last_used: u64,
+}
+
+/// A store whose entries count against the budget
+pub trait Budgeted {
+    /// Every entry that can be evicted
+    fn budget_entries(&self) -> Vec<BudgetEntry>;
+    /// Drop the entry with `key`
+    fn evict(&mut self, key: &str);
+}
+
+/// Caps on what the agent keeps, from the settings
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct ResourceBudget {
+    pub max_result_bytes: u64,
+    pub max_artifact_bytes: u64,
+    pub max_history_entries: u64,
+}
+
+impl Default for ResourceBudget {
+    fn default() -> Self {
+        Self {
+            max_result_bytes: DEFAULT_MAX_RESULT_BYTES,
+            max_artifact_bytes: DEFAULT_MAX_ARTIFACT_BYTES,
+            max_history_entries: DEFAULT_MAX_HISTORY_ENTRIES,
+        }
+    }
+}
+
+impl ResourceBudget {
+    pub fn from_settings(settings: &AgentSettings) -> Self {
+        Self {
+            max_result_bytes: settings.max_result_bytes,
+            max_artifact_bytes: settings.max_artifact_bytes,
+            max_history_entries: settings.max_history_entries,
+        }
+    }
+}
+
+/// What one kind of resource uses against its cap
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct PoolUsage {
+    /// Bytes, or entries for history
+    pub used: u64,
+    pub max: u64,
+    pub entries: usize,
+}
+
+impl PoolUsage {
+    /// Usage of the entries of `stores` against `max`
+    pub fn of(stores: &[Vec<BudgetEntry>], max: u64) -> Self {
+        Self {
+            used: stores.iter().flatten().map(|entry| entry.size).sum(),
+            max,
+            entries: stores.iter().map(Vec::len).sum(),
+        }
+    }
+}
+
+/// Current usage against the budget, for `get_resource_usage` and the agent status
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct BudgetUsage {
+    /// Results kept in memory by every store
+    pub results: PoolUsage,
+    /// Raw files kept on disk
+    pub artifacts: PoolUsage,
+    /// Stored netlists
+    pub history: PoolUsage,
+}
+
+/// Entries to evict from `stores` so the rest fit in `max`, as the store's index in `stores` and
+/// the entry's key: least recently used first, ties going to the earlier store and then the
+/// smaller key
+pub fn plan_evictions(stores: &[Vec<BudgetEntry>], max: u64) -> Vec<(usize, String)> {
+    let mut used: u64 = stores.iter().flatten().map(|entry| entry.size).sum();
+    let mut candidates: Vec<(usize, &BudgetEntry)> =
+        stores.iter().enumerate().flat_map(|(store, entries)| entries.iter().map(move |entry| (store, entry))).collect();
+    candidates.sort_by(|(a_store, a), (b_store, b)| {
+        (a.last_used, a_store, &a.key).cmp(&(b.last_used, b_store, &b.key))
+    });
+
+    let mut evictions = Vec::new();
+    for (store, entry) in candidates {
+        if used <= max {
+            break;
+        }
+        used -= entry.size;
+        evictions.push((store, entry.key.clone()));
+    }
+    evictions
+}
+
+/// Apply `plan_evictions` to `stores`, returning how many entries were evicted
+pub fn enforce(stores: &mut [&mut dyn Budgeted], max: u64) -> usize {
+    let entries: Vec<Vec<BudgetEntry>> = stores.iter().map(|store| store.budget_entries()).collect();
+    let evictions = plan_evictions(&entries, max);
+    for (store, key) in &evictions {
+        stores[*store].evict(key);
+    }
+    evictions.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(key: &str, size: u64, last_used: u64) -> BudgetEntry {
+        BudgetEntry { key: key.to_string(), size, last_used }
+    }
+
+    /// A store of synthetic entries
+    #[derive(Default)]
+    struct Fake(Vec<BudgetEntry>);
+
+    impl Budgeted for Fake {
+        fn budget_entries(&self) -> Vec<BudgetEntry> {
+            self.0.clone()
+        }
+
+        fn evict(&mut self, key: &str) {
+            self.0.retain(|entry| entry.key != key);
+        }
+    }
+
+    #[test]
+    fn test_nothing_evicted_within_budget() {
+        let stores = vec![vec![entry("a", 40, 1)], vec![entry("b", 60, 2)]];
+        assert!(plan_evictions(&stores, 100).is_empty());
+        assert!(plan_evictions(&[], 0).is_empty());
+    }
+
+    #[test]
+    fn test_least_recently_used_evicted_first_across_stores() {
+        let stores = vec![
+            vec![entry("last", 30, 5)],
+            vec![entry("r1", 20, 1), entry("r2", 20, 4)],
+            vec![entry("t1", 50, 2), entry("t2", 10, 6)],
+        ];
+        // 130 bytes held: r1 (used first) and t1 (second) go, leaving 60
+        assert_eq!(plan_evictions(&stores, 100), [(1, "r1".to_string()), (2, "t1".to_string())]);
+        assert_eq!(plan_evictions(&stores, 110), [(1, "r1".to_string())]);
+        assert_eq!(plan_evictions(&stores, 0).len(), 5);
+    }
+
+    #[test]
+    fn test_ties_are_broken_by_store_then_key() {
+        let stores = vec![vec![entry("b", 10, 1)], vec![entry("a", 10, 1)], vec![entry("d", 10, 1), entry("c", 10, 1)]];
+        let order: Vec<_> = plan_evictions(&stores, 0).into_iter().map(|(_, key)| key).collect();
+        assert_eq!(order, ["b", "a", "c", "d"]);
+        // The same entries always give the same plan
+        assert_eq!(plan_evictions(&stores, 15), plan_evictions(&stores, 15));
+    }
+
+    #[test]
+    fn test_entry_larger_than_the_budget_is_evicted() {
+        let stores = vec![vec![entry("small", 10, 2), entry("huge", 500, 3)]];
+        assert_eq!(plan_evictions(&stores, 100), [(0, "small".to_string()), (0, "huge".to_string())]);
+    }
+
+    #[test]
+    fn test_enforce_evicts_from_the_stores() {
+        let mut first = Fake(vec![entry("a", 60, 1), entry("b", 60, 3)]);
+        let mut second = Fake(vec![entry("c", 60, 2)]);
+        assert_eq!(enforce(&mut [&mut first, &mut second], 100), 2);
+        assert_eq!(first.0, [entry("b", 60, 3)]);
+        assert!(second.0.is_empty());
+        assert_eq!(enforce(&mut [&mut first, &mut second], 100), 0);
+    }
+
+    #[test]
+    fn test_pool_usage() {
+        let stores = vec![vec![entry("a", 10, 1), entry("b", 5, 2)], vec![], vec![entry("c", 1, 3)]];
+        assert_eq!(PoolUsage::of(&stores, 100), PoolUsage { used: 16, max: 100, entries: 3 });
+    }
+
+    #[test]
+    fn test_uses_are_ordered() {
+        let first = next_use();
+        assert!(next_use() > first);
+    }
+
+    #[test]
+    fn test_budget_from_settings() {
+        assert_eq!(ResourceBudget::from_settings(&AgentSettings::default()), ResourceBudget::default());
+        let settings = AgentSettings { max_history_entries: 5, ..AgentSettings::default() };
+        assert_eq!(ResourceBudget::from_settings(&settings).max_history_entries, 5);
+    }
+}
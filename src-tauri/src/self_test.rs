@@ -0,0 +1,234 @@
+// Copyright (c) 2024-2025 Wanyeki Technologies LLC. All rights reserved.
+// This source code is licensed under the proprietary license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Self test: simulate a small RC circuit on each detected simulator and check the waveform,
+//! so support can confirm an install works from detection through raw file parsing
+//!
+//! Shared by the agent window's `run_self_test` command and the WebSocket `self_test` message.
+
+use std::time::{Duration, Instant};
+
+use crate::protocol::{now_ms, EngineSelfTest, SelfTestResponse, SimulationResults};
+use crate::quality;
+use crate::simulator;
+use crate::state::AppState;
+
+/// 1 V step into a 1 kΩ / 1 µF low-pass, so RC = 1 ms
+pub const REFERENCE_NETLIST: &str = "* KeliCAD Agent self test: RC step response
+V1 in 0 PULSE(0 1 0 1n 1n 1 2)
+R1 in out 1k
+C1 out 0 1u
+.tran 10u 5m 0 10u
+.end
+";
+
+const TIME_CONSTANT: f64 = 1e-3;
+const STEP_VOLTS: f64 = 1.0;
+/// Allowed difference from the analytic V(out) at t = RC, in volts
+const TOLERANCE: f64 = 0.02;
+/// A healthy install finishes in well under a second
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+/// V(out) at t = RC for an ideal RC: Vin·(1 − 1/e) ≈ 0.632 V
+pub fn expected_vout() -> f64 {
+    STEP_VOLTS * (1.0 - (-1.0f64).exp())
+}
+
+/// V(out) at t = RC, interpolated between the nearest points
+pub fn measure_vout(results: &SimulationResults) -> Result<f64, String> {
+    let trace = results
+        .traces
+        .iter()
+        .find(|trace| trace.name.eq_ignore_ascii_case("v(out)"))
+        .ok_or_else(|| {
+            let names: Vec<&str> = results.traces.iter().map(|trace| trace.name.as_str()).collect();
+            format!("No V(out) trace in the results; got [{}]", names.join(", "))
+        })?;
+    let after = results.time.partition_point(|&t| t < TIME_CONSTANT);
+    if after == 0 || after >= results.time.len().min(trace.data.len()) {
+        return Err(format!("The results do not cover t = {} s ({} points)", TIME_CONSTANT, results.time.len()));
+    }
+    let (t0, t1) = (results.time[after - 1], results.time[after]);
+    let (v0, v1) = (trace.data[after - 1], trace.data[after]);
+    // Compressed LTspice plots can repeat a time point
+    if t1 <= t0 {
+        return Ok(v1);
+    }
+    Ok(v0 + (v1 - v0) * (TIME_CONSTANT - t0) / (t1 - t0))
+}
+
+/// Run the reference circuit on every detected simulator, one after another
+pub async fn run(state: &AppState, request_id: String) -> SelfTestResponse {
+    let mut engines = Vec::new();
+    for simulator in ["ltspice", "ngspice"] {
+        let path = match simulator {
+            "ngspice" => state.ngspice_path.read().await.clone(),
+            _ => state.ltspice_path.read().await.clone(),
+        };
+        if let Some(path) = path {
+            engines.push(run_engine(simulator, path, state).await);
+        }
+    }
+
+    let error = engines.is_empty().then(|| "Neither LTspice nor ngspice was detected".to_string());
+    SelfTestResponse {
+        id: uuid::Uuid::new_v4().to_string(),
+        msg_type: "self_test_result".to_string(),
+        request_id,
+        timestamp: now_ms(),
+        success: !engines.is_empty() && engines.iter().all(|engine| engine.passed),
+        engines,
+        error,
+    }
+}
+
+async fn run_engine(simulator: &str, path: String, state: &AppState) -> EngineSelfTest {
+    let version = state.simulator_binary(simulator).await.and_then(|binary| binary.version);
+    let options = {
+        let settings = state.settings.read().await;
+        simulator::ProcessOptions {
+            hard_timeout: Some(TIMEOUT),
+            first_run_grace: Some(simulator::LTSPICE_FIRST_RUN_GRACE),
+            background_priority: settings.background_priority,
+            ngspice_threads: settings.ngspice_threads,
+            temp_root: Some(state.temp_root.read().await.path.clone()),
+            ..simulator::ProcessOptions::default()
+        }
+    };
+    // The built-in profile, so a customized one can't fail the test
+    let quality = quality::default_quality_profiles().remove("balanced").unwrap_or_default();
+
+    let started = Instant::now();
+    let result = match simulator {
+        "ngspice" => simulator::run_ngspice_simulation(&path, REFERENCE_NETLIST, &[], &quality, &options, None).await,
+        _ => simulator::run_ltspice_simulation(&path, REFERENCE_NETLIST, &[], &quality, &options, None).await,
+    };
+    let execution_time = started.elapsed().as_millis() as u64;
+
+    let expected = expected_vout();
+    let measured = result.map_err(|e| e.to_string()).and_then(|results| measure_vout(&results));
+    let error = match &measured {
+        Ok(vout) if (vout - expected).abs() <= TOLERANCE => None,
+        Ok(vout) => Some(format!("V(out) at t = RC is {:.4} V, expected {:.4} ± {} V", vout, expected, TOLERANCE)),
+        Err(e) => Some(e.clone()),
+    };
+    match &error {
+        None => log::info!("Self test passed on {} in {} ms", simulator, execution_time),
+        Some(e) => log::warn!("Self test failed on {}: {}", simulator, e),
+    }
+
+    EngineSelfTest {
+        simulator: simulator.to_string(),
+        path,
+        version,
+        passed: error.is_none(),
+        execution_time,
+        measured_vout: measured.ok(),
+        expected_vout: expected,
+        error,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::Trace;
+    use crate::settings::AgentSettings;
+    use std::collections::BTreeMap;
+
+    fn rc_results(name: &str, gain: f64) -> SimulationResults {
+        let time: Vec<f64> = (0..=50).map(|i| i as f64 * 1e-4).collect();
+        let data = time.iter().map(|t| gain * (1.0 - (-t / TIME_CONSTANT).exp())).collect();
+        SimulationResults {
+            time,
+            traces: vec![Trace { name: name.to_string(), data, unit: "V".to_string(), phase: None, raw_name: None }],
+            analysis_type: "transient".to_string(),
+            x_axis_label: None,
+            x_axis_unit: None,
+            scalar_results: None,
+            device_operating_points: BTreeMap::new(),
+            truncated: None,
+            plot_hints: None,
+            raw_header: None,
+        }
+    }
+
+    #[test]
+    fn test_measure_vout() {
+        let vout = measure_vout(&rc_results("V(out)", 1.0)).unwrap();
+        assert!((vout - expected_vout()).abs() < 1e-9);
+        assert!((measure_vout(&rc_results("v(out)", 0.5)).unwrap() - 0.316).abs() < 1e-3);
+
+        assert_eq!(
+            measure_vout(&rc_results("V(in)", 1.0)).unwrap_err(),
+            "No V(out) trace in the results; got [V(in)]"
+        );
+        let mut short = rc_results("V(out)", 1.0);
+        short.time.truncate(5);
+        assert!(measure_vout(&short).unwrap_err().starts_with("The results do not cover"));
+    }
+
+    /// Fake ngspice that writes `v(out)` scaled by `gain`, or fails with `error` if it is set
+    #[cfg(unix)]
+    fn fake_ngspice(dir: &std::path::Path, gain: f64, error: Option<&str>) -> String {
+        use std::fmt::Write;
+        use std::os::unix::fs::PermissionsExt;
+
+        let results = rc_results("v(out)", gain);
+        let mut values = String::new();
+        for (i, (t, v)) in results.time.iter().zip(&results.traces[0].data).enumerate() {
+            let _ = write!(values, " {}\t{:e}\n\t{:e}\n", i, t, v);
+        }
+        let body = match error {
+            Some(error) => format!("echo '{}' >&2\nexit 1\n", error),
+            None => format!(
+                "cat > \"$raw\" <<RAW\nTitle: fake\nPlotname: Transient Analysis\nFlags: real\nNo. Variables: 2\nNo. Points: {}\nVariables:\n\t0\ttime\ttime\n\t1\tv(out)\tvoltage\nValues:\n{}RAW\n",
+                results.time.len(),
+                values
+            ),
+        };
+        let script = format!(
+            "#!/bin/sh\nraw=$(sed -n 's/^write \\([^ ]*\\) .*$/\\1/p' \"$2\" | tr -d \"'\")\n{}",
+            body
+        );
+        let path = dir.join(format!("ngspice-{}", gain));
+        std::fs::write(&path, script).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        path.to_string_lossy().to_string()
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_self_test_report() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = AppState::new(AgentSettings::default());
+
+        let report = run(&state, "none".to_string()).await;
+        assert!(!report.success);
+        assert!(report.engines.is_empty());
+        assert!(report.error.is_some());
+
+        *state.ngspice_path.write().await = Some(fake_ngspice(dir.path(), 1.0, None));
+        let report = run(&state, "ok".to_string()).await;
+        assert!(report.success, "{:?}", report.engines);
+        assert_eq!(report.request_id, "ok");
+        let engine = &report.engines[0];
+        assert_eq!(engine.simulator, "ngspice");
+        assert!(engine.passed);
+        assert!((engine.measured_vout.unwrap() - expected_vout()).abs() < 1e-3);
+
+        // Wrong values fail with the measurement, simulator errors with their text
+        *state.ngspice_path.write().await = Some(fake_ngspice(dir.path(), 0.5, None));
+        let report = run(&state, "wrong".to_string()).await;
+        assert!(!report.success);
+        assert!(report.engines[0].error.as_deref().unwrap().starts_with("V(out) at t = RC is 0.316"));
+
+        *state.ngspice_path.write().await =
+            Some(fake_ngspice(dir.path(), 2.0, Some("Error: unknown subckt: x1 rc")));
+        let report = run(&state, "broken".to_string()).await;
+        assert!(!report.engines[0].passed);
+        assert_eq!(report.engines[0].measured_vout, None);
+        assert!(report.engines[0].error.as_deref().unwrap().contains("unknown subckt: x1 rc"), "{:?}", report.engines[0].error);
+    }
+}
@@ -0,0 +1,311 @@
+// Copyright (c) 2024-2025 Wanyeki Technologies LLC. All rights reserved.
+// This source code is licensed under the proprietary license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Persisted agent settings
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use serde::{Deserialize, Serialize};
+
+use crate::protocol::LibraryStrategy;
+use crate::quality::{self, QualityProfile};
+use crate::resource_budget;
+
+/// User-configurable agent settings, stored as JSON in the config directory
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AgentSettings {
+    /// Number of simulations allowed to run at the same time
+    pub max_concurrent_simulations: usize,
+    /// Seconds without CPU or file activity before a simulator is treated as hung (0 disables)
+    pub watchdog_stall_secs: u64,
+    /// Seconds between `still_running` progress messages while a simulation runs (0 disables)
+    pub keepalive_interval_secs: u64,
+    /// Run simulators below normal priority so the desktop stays responsive
+    pub background_priority: bool,
+    /// Maximum threads ngspice may use, or unlimited when unset
+    pub ngspice_threads: Option<u32>,
+    /// Keep one ngspice running in pipe mode and send runs to it, instead of starting ngspice
+    /// for each run
+    pub ngspice_persistent_session: bool,
+    /// Free temp space required when a simulation's output size can't be estimated
+    pub min_free_disk_mb: u64,
+    /// Show a desktop notification when a long simulation finishes
+    pub notifications_enabled: bool,
+    /// Simulations shorter than this many seconds finish without a notification
+    pub notification_threshold_secs: u64,
+    /// Launch the agent (minimized to the tray) at login; changed through `set_autostart`
+    pub autostart: bool,
+    /// Longest a single simulation may run; longer request timeouts are clamped to this
+    pub max_simulation_seconds: u64,
+    /// Warn before simulations estimated to run longer than this many seconds (0 disables)
+    pub long_run_warning_secs: u64,
+    /// Simulations each page origin may start per minute, in short bursts (0 disables)
+    pub rate_limit_per_minute: u32,
+    /// Apply the rate limit to localhost origins too; off so development servers aren't throttled
+    pub rate_limit_localhost: bool,
+    /// Bundled libraries never substituted for a missing `.include`/`.lib`
+    pub disabled_bundled_libraries: Vec<String>,
+    /// Keep the netlists of recent simulations so they can be run again; off for privacy
+    pub store_netlists: bool,
+    /// Bytes of results kept in memory across the last results, finished responses and deferred
+    /// traces; the least recently used are dropped first
+    pub max_result_bytes: u64,
+    /// Bytes of raw output files kept on disk with `keepRaw`
+    pub max_artifact_bytes: u64,
+    /// Netlists kept when `store_netlists` is on
+    pub max_history_entries: u64,
+    /// Waveform quality profiles by name; changed through `set_quality_profile`
+    pub quality_profiles: BTreeMap<String, QualityProfile>,
+    /// Add `.backanno` and the quality profile's `.options` to netlists; off leaves them as
+    /// written apart from what output needs, like a request's `noAutoOptions`
+    pub auto_inject_options: bool,
+    /// Directories a request's `librarySearchPaths` may point into; none allowed when empty
+    pub allowed_library_roots: Vec<String>,
+    /// Whether libraries found for an `.include`/`.lib` are copied into the temp dir or referenced
+    /// where they are, unless a request sets `libraryStrategy`
+    pub library_strategy: LibraryStrategy,
+    /// Values of `$NAME`, `${NAME}` and `%NAME%` in `.include`/`.lib` paths, used before the
+    /// agent's environment so teams can define e.g. `MODELS` without setting it system-wide
+    pub path_variables: BTreeMap<String, String>,
+    /// Keep the window in the tray at launch, for users who only work in the web app
+    pub start_hidden: bool,
+    /// Where the window was when it was last closed; saved by the agent
+    pub window_geometry: Option<WindowGeometry>,
+    /// The notice that closing the window leaves the agent running has been shown
+    pub close_to_tray_notified: bool,
+    /// Directory simulations make their temp directories in instead of the system temp dir;
+    /// checked at startup and ignored if unusable. Changed through `choose_temp_dir`
+    pub temp_dir_override: Option<String>,
+    /// Accept WebSocket connections without an `Origin` header, as made by scripts and other
+    /// non-browser tools; off so only pages on an allowed origin can connect
+    pub allow_non_browser_clients: bool,
+    /// Let a connection ask in its handshake for copies of every simulation's progress and
+    /// result, whichever connection started it; off refuses such requests
+    pub allow_result_subscriptions: bool,
+}
+
+/// Position and size of the main window, in physical pixels
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct WindowGeometry {
+    pub x: i32,
+    pub y: i32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl WindowGeometry {
+    /// Whether enough of the window's title bar would be on one of `monitors` to drag it,
+    /// so a window saved on a since-disconnected display isn't restored off screen
+    pub fn is_reachable_on(&self, monitors: &[WindowGeometry]) -> bool {
+        const GRIP: i32 = 40;
+        monitors.iter().any(|monitor| {
+            let visible_left = self.x.max(monitor.x);
+            let visible_right = (self.x + self.width as i32).min(monitor.x + monitor.width as i32);
+            visible_right - visible_left >= GRIP
+                && self.y >= monitor.y
+                && self.y + GRIP <= monitor.y + monitor.height as i32
+        })
+    }
+}
+
+impl Default for AgentSettings {
+    fn default() -> Self {
+        Self {
+            // A single worker preserves the one-simulation-at-a-time behavior
+            max_concurrent_simulations: 1,
+            watchdog_stall_secs: 60,
+            keepalive_interval_secs: 5,
+            background_priority: true,
+            ngspice_threads: None,
+            ngspice_persistent_session: false,
+            min_free_disk_mb: 500,
+            notifications_enabled: true,
+            notification_threshold_secs: 10,
+            autostart: false,
+            max_simulation_seconds: 300,
+            long_run_warning_secs: 300,
+            rate_limit_per_minute: 20,
+            rate_limit_localhost: false,
+            disabled_bundled_libraries: Vec::new(),
+            store_netlists: false,
+            max_result_bytes: resource_budget::DEFAULT_MAX_RESULT_BYTES,
+            max_artifact_bytes: resource_budget::DEFAULT_MAX_ARTIFACT_BYTES,
+            max_history_entries: resource_budget::DEFAULT_MAX_HISTORY_ENTRIES,
+            quality_profiles: quality::default_quality_profiles(),
+            auto_inject_options: true,
+            allowed_library_roots: Vec::new(),
+            library_strategy: LibraryStrategy::Copy,
+            path_variables: BTreeMap::new(),
+            start_hidden: false,
+            window_geometry: None,
+            close_to_tray_notified: false,
+            temp_dir_override: None,
+            allow_non_browser_clients: false,
+            allow_result_subscriptions: true,
+        }
+    }
+}
+
+/// Location of the settings file
+pub fn settings_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("kelicad-agent").join("settings.json"))
+}
+
+/// Load settings, falling back to defaults if the file is missing or invalid
+pub fn load_settings() -> AgentSettings {
+    let Some(path) = settings_path() else {
+        return AgentSettings::default();
+    };
+
+    match std::fs::read_to_string(&path) {
+        Ok(content) => serde_json::from_str(&content).unwrap_or_else(|e| {
+            log::warn!("Ignoring invalid settings file {:?}: {}", path, e);
+            AgentSettings::default()
+        }),
+        Err(_) => AgentSettings::default(),
+    }
+}
+
+/// Write settings to the config directory
+pub fn save_settings(settings: &AgentSettings) -> Result<(), String> {
+    let path = settings_path().ok_or("Could not determine config directory")?;
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+    }
+    let json = serde_json::to_string_pretty(settings).map_err(|e| e.to_string())?;
+    std::fs::write(&path, json).map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_settings() {
+        let settings = AgentSettings::default();
+        assert_eq!(settings.max_concurrent_simulations, 1);
+        assert_eq!(settings.watchdog_stall_secs, 60);
+        assert_eq!(settings.keepalive_interval_secs, 5);
+        assert!(settings.background_priority);
+        assert_eq!(settings.ngspice_threads, None);
+        assert!(!settings.ngspice_persistent_session);
+        assert_eq!(settings.min_free_disk_mb, 500);
+        assert!(settings.notifications_enabled);
+        assert_eq!(settings.notification_threshold_secs, 10);
+        assert!(!settings.autostart);
+        assert_eq!(settings.max_simulation_seconds, 300);
+        assert_eq!(settings.long_run_warning_secs, 300);
+        assert_eq!(settings.rate_limit_per_minute, 20);
+        assert!(!settings.rate_limit_localhost);
+        assert!(settings.disabled_bundled_libraries.is_empty());
+        assert!(!settings.store_netlists);
+        assert_eq!(settings.max_result_bytes, 512 * 1024 * 1024);
+        assert_eq!(settings.max_artifact_bytes, 1024 * 1024 * 1024);
+        assert_eq!(settings.max_history_entries, 20);
+        assert_eq!(settings.quality_profiles.keys().collect::<Vec<_>>(), ["balanced", "fast", "smooth"]);
+        assert!(settings.auto_inject_options);
+        assert!(settings.allowed_library_roots.is_empty());
+        assert_eq!(settings.library_strategy, LibraryStrategy::Copy);
+        assert!(settings.path_variables.is_empty());
+        assert!(!settings.start_hidden);
+        assert_eq!(settings.window_geometry, None);
+        assert!(!settings.close_to_tray_notified);
+        assert_eq!(settings.temp_dir_override, None);
+        assert!(!settings.allow_non_browser_clients);
+        assert!(settings.allow_result_subscriptions);
+    }
+
+    #[test]
+    fn test_missing_fields_use_defaults() {
+        let settings: AgentSettings = serde_json::from_str("{}").unwrap();
+        assert_eq!(settings.max_concurrent_simulations, 1);
+        assert_eq!(settings.watchdog_stall_secs, 60);
+    }
+
+    #[test]
+    fn test_settings_round_trip() {
+        let settings = AgentSettings {
+            max_concurrent_simulations: 4,
+            watchdog_stall_secs: 0,
+            keepalive_interval_secs: 0,
+            background_priority: false,
+            ngspice_threads: Some(2),
+            ngspice_persistent_session: true,
+            min_free_disk_mb: 100,
+            notifications_enabled: false,
+            notification_threshold_secs: 30,
+            autostart: true,
+            max_simulation_seconds: 1800,
+            long_run_warning_secs: 0,
+            rate_limit_per_minute: 0,
+            rate_limit_localhost: true,
+            disabled_bundled_libraries: vec!["LTC3.lib".to_string()],
+            store_netlists: true,
+            max_result_bytes: 64 * 1024 * 1024,
+            max_artifact_bytes: 0,
+            max_history_entries: 5,
+            quality_profiles: BTreeMap::from([("draft".to_string(), QualityProfile::default())]),
+            auto_inject_options: false,
+            allowed_library_roots: vec!["/opt/models".to_string()],
+            library_strategy: LibraryStrategy::Reference,
+            path_variables: BTreeMap::from([("MODELS".to_string(), "/opt/models".to_string())]),
+            start_hidden: true,
+            window_geometry: Some(WindowGeometry { x: -1200, y: 80, width: 420, height: 560 }),
+            close_to_tray_notified: true,
+            temp_dir_override: Some("/scratch/sim".to_string()),
+            allow_non_browser_clients: true,
+            allow_result_subscriptions: false,
+        };
+        let json = serde_json::to_string(&settings).unwrap();
+        let parsed: AgentSettings = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed.max_concurrent_simulations, 4);
+        assert_eq!(parsed.watchdog_stall_secs, 0);
+        assert_eq!(parsed.keepalive_interval_secs, 0);
+        assert!(!parsed.background_priority);
+        assert_eq!(parsed.ngspice_threads, Some(2));
+        assert!(parsed.ngspice_persistent_session);
+        assert_eq!(parsed.min_free_disk_mb, 100);
+        assert!(!parsed.notifications_enabled);
+        assert_eq!(parsed.notification_threshold_secs, 30);
+        assert!(parsed.autostart);
+        assert_eq!(parsed.max_simulation_seconds, 1800);
+        assert_eq!(parsed.long_run_warning_secs, 0);
+        assert_eq!(parsed.rate_limit_per_minute, 0);
+        assert!(parsed.rate_limit_localhost);
+        assert_eq!(parsed.disabled_bundled_libraries, ["LTC3.lib"]);
+        assert!(parsed.store_netlists);
+        assert_eq!(parsed.max_result_bytes, 64 * 1024 * 1024);
+        assert_eq!(parsed.max_artifact_bytes, 0);
+        assert_eq!(parsed.max_history_entries, 5);
+        assert_eq!(parsed.quality_profiles.keys().collect::<Vec<_>>(), ["draft"]);
+        assert!(!parsed.auto_inject_options);
+        assert_eq!(parsed.allowed_library_roots, ["/opt/models"]);
+        assert_eq!(parsed.library_strategy, LibraryStrategy::Reference);
+        assert_eq!(parsed.path_variables["MODELS"], "/opt/models");
+        assert!(parsed.start_hidden);
+        assert_eq!(parsed.window_geometry, Some(WindowGeometry { x: -1200, y: 80, width: 420, height: 560 }));
+        assert!(parsed.close_to_tray_notified);
+        assert_eq!(parsed.temp_dir_override.as_deref(), Some("/scratch/sim"));
+        assert!(parsed.allow_non_browser_clients);
+        assert!(!parsed.allow_result_subscriptions);
+    }
+
+    #[test]
+    fn test_window_geometry_reachable() {
+        let primary = WindowGeometry { x: 0, y: 0, width: 1920, height: 1080 };
+        let left = WindowGeometry { x: -1280, y: 0, width: 1280, height: 1024 };
+        let window = |x, y| WindowGeometry { x, y, width: 400, height: 500 };
+
+        assert!(window(100, 100).is_reachable_on(&[primary]));
+        assert!(window(-900, 100).is_reachable_on(&[primary, left]));
+        // Saved on a display that is no longer connected
+        assert!(!window(-900, 100).is_reachable_on(&[primary]));
+        // Only a sliver left on screen, or the title bar above or below it
+        assert!(!window(1900, 100).is_reachable_on(&[primary]));
+        assert!(!window(100, -30).is_reachable_on(&[primary]));
+        assert!(!window(100, 1060).is_reachable_on(&[primary]));
+        assert!(!window(100, 100).is_reachable_on(&[]));
+    }
+}
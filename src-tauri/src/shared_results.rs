@@ -0,0 +1,143 @@
+// Copyright (c) 2024-2025 Wanyeki Technologies LLC. All rights reserved.
+// This source code is licensed under the proprietary license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Copies of every simulation's progress and result for connections that asked for them
+//!
+//! Two people debugging a circuit together from different browsers both want to see each run.
+//! A connection whose handshake sets `subscribeResults` gets a copy of every
+//! `simulation_progress` and `simulation_result` message, whichever connection started the run,
+//! with the page origin that started it in `sourceOrigin`. Connections don't subscribe unless
+//! they ask, and the `allow_result_subscriptions` setting turns the feature off. A connection
+//! attached to a run already gets its messages and isn't sent copies.
+
+use std::sync::Arc;
+
+use serde::Deserialize;
+use tokio::sync::{broadcast, mpsc};
+
+use crate::dedup::Listeners;
+
+/// Messages buffered per subscriber before the oldest are dropped
+const FEED_CAPACITY: usize = 64;
+
+/// Message types copied to subscribers
+const SHARED_TYPES: &[&str] = &["simulation_progress", "simulation_result"];
+
+/// A message copied to subscribed connections
+#[derive(Clone)]
+pub struct SharedMessage {
+    /// Connections attached to the run, which get the message anyway
+    listeners: Arc<Listeners>,
+    /// The message with `sourceOrigin` added
+    pub json: Arc<str>,
+}
+
+impl SharedMessage {
+    /// Whether the connection sending to `sender` is attached to the run and has the message already
+    pub fn reaches(&self, sender: &mpsc::Sender<String>) -> bool {
+        self.listeners.includes(sender)
+    }
+}
+
+/// Where simulations publish copies of their messages
+#[derive(Clone)]
+pub struct ResultFeed {
+    sender: broadcast::Sender<SharedMessage>,
+}
+
+impl Default for ResultFeed {
+    fn default() -> Self {
+        Self { sender: broadcast::channel(FEED_CAPACITY).0 }
+    }
+}
+
+impl ResultFeed {
+    pub fn subscribe(&self) -> broadcast::Receiver<SharedMessage> {
+        self.sender.subscribe()
+    }
+
+    /// Publisher for the messages of a run started from `origin` and sent to `listeners`
+    pub fn source(&self, origin: &str, listeners: &Arc<Listeners>) -> FeedSource {
+        FeedSource { feed: self.clone(), origin: origin.to_string(), listeners: listeners.clone() }
+    }
+}
+
+/// Publishes the messages of one run
+#[derive(Clone)]
+pub struct FeedSource {
+    feed: ResultFeed,
+    origin: String,
+    listeners: Arc<Listeners>,
+}
+
+impl FeedSource {
+    /// Copy `json` to subscribers if it is a progress or result message and anyone subscribed
+    pub fn publish(&self, json: &str) {
+        if self.feed.sender.receiver_count() == 0 || !message_type(json).is_some_and(|msg_type| SHARED_TYPES.contains(&msg_type.as_str())) {
+            return;
+        }
+        let _ = self.feed.sender.send(SharedMessage {
+            listeners: self.listeners.clone(),
+            json: with_source_origin(json, &self.origin).into(),
+        });
+    }
+}
+
+/// The `type` of a JSON message
+fn message_type(json: &str) -> Option<String> {
+    #[derive(Deserialize)]
+    struct Typed {
+        #[serde(rename = "type")]
+        msg_type: String,
+    }
+    serde_json::from_str::<Typed>(json).ok().map(|typed| typed.msg_type)
+}
+
+/// `json`, a message object, with `"sourceOrigin": origin` as its first entry
+fn with_source_origin(json: &str, origin: &str) -> String {
+    let Some(rest) = json.strip_prefix('{') else {
+        return json.to_string();
+    };
+    let origin = serde_json::to_string(origin).unwrap_or_else(|_| "\"\"".to_string());
+    let separator = if rest.trim_start().starts_with('}') { "" } else { "," };
+    format!("{{\"sourceOrigin\":{}{}{}", origin, separator, rest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_with_source_origin() {
+        assert_eq!(
+            with_source_origin("{\"type\":\"simulation_result\"}", "https://kelicad.com"),
+            "{\"sourceOrigin\":\"https://kelicad.com\",\"type\":\"simulation_result\"}"
+        );
+        assert_eq!(with_source_origin("{}", "a\"b"), "{\"sourceOrigin\":\"a\\\"b\"}");
+        assert_eq!(with_source_origin("[]", "x"), "[]");
+    }
+
+    #[tokio::test]
+    async fn test_only_progress_and_results_are_published() {
+        let feed = ResultFeed::default();
+        let (attached, _attached_rx) = mpsc::channel(1);
+        let (other, _other_rx) = mpsc::channel(1);
+        let source = feed.source("https://kelicad.com", &Arc::new(Listeners::new(&attached)));
+
+        // Nothing is built for a feed nobody reads
+        source.publish("{\"type\":\"simulation_result\"}");
+        let mut receiver = feed.subscribe();
+        source.publish("{\"type\":\"simulation_log\",\"line\":\"x\"}");
+        source.publish("{\"type\":\"simulation_progress\",\"percent\":50}");
+        source.publish("{\"type\":\"simulation_result\",\"success\":true}");
+
+        let progress = receiver.recv().await.unwrap();
+        assert_eq!(&*progress.json, "{\"sourceOrigin\":\"https://kelicad.com\",\"type\":\"simulation_progress\",\"percent\":50}");
+        assert!(progress.reaches(&attached));
+        assert!(!progress.reaches(&other));
+        let result = receiver.recv().await.unwrap();
+        assert!(result.json.contains("\"success\":true"));
+        assert!(receiver.try_recv().is_err());
+    }
+}
@@ -0,0 +1,113 @@
+// Copyright (c) 2024-2025 Wanyeki Technologies LLC. All rights reserved.
+// This source code is licensed under the proprietary license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Graceful shutdown: close client connections and stop in-flight simulations before exiting
+
+use std::time::Duration;
+
+use crate::simulation;
+use crate::simulator;
+use crate::state::AppState;
+use crate::status_file;
+
+/// How long cancelled simulations and closing connections get to finish
+pub const SHUTDOWN_GRACE: Duration = Duration::from_secs(3);
+
+const CLEANUP_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Stop accepting connections, send `agent_shutdown` and close frames to clients, cancel
+/// running simulations and wait up to `grace` for them to clean up
+/// Returns false without doing anything if a shutdown is already in progress
+pub async fn shutdown(state: &AppState, grace: Duration) -> bool {
+    // Flipping the signal stops the listener and makes every connection say goodbye
+    if !state.begin_shutdown() {
+        return false;
+    }
+    log::info!("Shutting down");
+
+    let running: Vec<String> = state.active_simulations.read().await.keys().cloned().collect();
+    for request_id in &running {
+        simulation::stop(request_id, state).await;
+    }
+
+    let deadline = tokio::time::Instant::now() + grace;
+    while tokio::time::Instant::now() < deadline {
+        let simulations = state.active_simulations.read().await.len();
+        let connections = *state.ws_connections.read().await;
+        if simulations == 0 && connections == 0 {
+            break;
+        }
+        tokio::time::sleep(CLEANUP_POLL_INTERVAL).await;
+    }
+
+    // Anything still registered did not react to cancellation in time
+    for (request_id, pids) in state.force_reset().await {
+        log::warn!("Simulation {} did not stop in time", request_id);
+        for pid in pids {
+            simulator::kill_process_tree(pid);
+        }
+    }
+
+    state.ngspice_session.close().await;
+    if let Some(path) = &state.status_file {
+        status_file::remove(path);
+    }
+    state.mark_shutdown_complete();
+    log::info!("Shutdown complete");
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use futures_util::StreamExt;
+    use tokio::net::TcpListener;
+    use tokio_tungstenite::tungstenite::{protocol::frame::coding::CloseCode, Message};
+
+    use crate::protocol::ALLOWED_ORIGINS;
+    use crate::websocket;
+
+    #[tokio::test]
+    async fn test_shutdown_closes_connections_and_server() {
+        let state = Arc::new(AppState::default());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(websocket::serve(listener, state.clone()));
+
+        let (mut client, _) = tokio_tungstenite::connect_async(websocket::browser_request(&addr.to_string(), ALLOWED_ORIGINS[0]).unwrap()).await.unwrap();
+        while *state.ws_connections.read().await == 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        assert!(shutdown(&state, Duration::from_secs(2)).await);
+        assert!(state.is_shutdown_complete());
+        assert_eq!(*state.ws_connections.read().await, 0);
+
+        let Some(Ok(Message::Text(notice))) = client.next().await else {
+            panic!("expected agent_shutdown message");
+        };
+        assert!(notice.contains("\"type\":\"agent_shutdown\""));
+        let Some(Ok(Message::Close(Some(frame)))) = client.next().await else {
+            panic!("expected close frame");
+        };
+        assert_eq!(frame.code, CloseCode::Library(4000));
+
+        // The listener has stopped, and a second shutdown is a no-op
+        tokio::time::timeout(Duration::from_secs(1), server).await.unwrap().unwrap();
+        assert!(!shutdown(&state, Duration::from_secs(2)).await);
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_removes_the_status_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(status_file::FILE_NAME);
+        let mut state = AppState::default();
+        state.status_file = Some(path.clone());
+        status_file::write(&path, &status_file::snapshot(&state).await).unwrap();
+
+        assert!(shutdown(&state, Duration::from_secs(1)).await);
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,3996 @@
+// Copyright (c) 2024-2025 Wanyeki Technologies LLC. All rights reserved.
+// This source code is licensed under the proprietary license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Simulation orchestration shared by the WebSocket server and the local agent window
+
+use std::any::Any;
+use std::borrow::Cow;
+use std::io::Read;
+use std::panic::AssertUnwindSafe;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, Ordering};
+use base64::prelude::{Engine, BASE64_STANDARD};
+use futures_util::{FutureExt, StreamExt};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+use crate::catalog;
+use crate::compat;
+use crate::dedup::Listeners;
+use crate::detection;
+use crate::libraries_used;
+use crate::metrics::Outcome;
+use crate::netlist;
+use crate::onboarding;
+use crate::peek;
+use crate::plot_hints;
+use crate::precision;
+use crate::probes;
+use crate::quality::{self, QualityProfile};
+use crate::protocol::*;
+use crate::shared_results::FeedSource;
+use crate::simulator;
+use crate::state::{ActiveSimulation, AppState, BeginError, RunState, SimulationSlot, StatusEvent};
+use crate::trace_aliases;
+use crate::trace_store;
+use crate::wave;
+
+/// How long streamed log lines are collected before being sent as one message
+const LOG_FLUSH_INTERVAL: std::time::Duration = std::time::Duration::from_millis(250);
+
+/// Maximum log lines per `simulation_log` message
+const MAX_LOG_LINES_PER_MESSAGE: usize = 200;
+
+/// How long LTspice may take to generate a netlist from a schematic
+const ASC_NETLIST_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// How long a simulation held by a long-run warning waits for `confirm`
+const CONFIRM_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(120);
+
+/// How long a cancel waits to learn whether the kill stopped the run or it had finished first
+const CANCEL_SETTLE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// Raw files smaller than this are read too quickly for their throughput to say anything
+const SLOW_READ_MIN_BYTES: u64 = 16 * 1024 * 1024;
+
+/// Raw file read throughput, in MB/s, below which the temp dir is probably on a network share or
+/// a slow disk
+const SLOW_READ_MB_PER_SEC: f64 = 20.0;
+
+/// Error code of a simulator that could not read the netlist
+const NETLIST_PARSE_ERROR: &str = "NETLIST_PARSE_ERROR";
+
+/// Error code of a run whose simulator executable had disappeared
+const SIMULATOR_MISSING: &str = "SIMULATOR_MISSING";
+
+/// Error code of a run that stopped after the operating point of a sweep it was asked for
+const ANALYSIS_INCOMPLETE: &str = "ANALYSIS_INCOMPLETE";
+
+/// Error code of an LTspice run stuck behind a dialog a fresh install shows on first launch
+const LTSPICE_NEEDS_FIRST_RUN: &str = "LTSPICE_NEEDS_FIRST_RUN";
+
+/// Error code of a run whose `waveOutput` node has no trace in the results
+const WAVE_NODE_NOT_FOUND: &str = "WAVE_NODE_NOT_FOUND";
+
+/// Error code of an include path using a variable set neither in the settings nor in the environment
+const LIBRARY_MISSING: &str = "LIBRARY_MISSING";
+
+/// Error code of a request turned away because every worker slot is taken
+const AGENT_BUSY: &str = "AGENT_BUSY";
+
+/// Error code and, when the pool is full, what it is busy with, for a request from `origin`
+/// that could not claim a worker slot
+async fn refusal_details(state: &AppState, error: &BeginError, origin: Option<&str>) -> (Option<String>, Option<BusyInfo>) {
+    match error {
+        BeginError::Busy => (Some(AGENT_BUSY.to_string()), state.busy_info(origin).await),
+        BeginError::ShuttingDown | BeginError::AlreadyRunning(_) => (None, None),
+    }
+}
+
+/// Run a single simulation request from start to finish
+/// Progress, stage and log messages are sent to `progress_tx` as JSON before the response is returned
+pub async fn execute(
+    request: &SimulationRequest,
+    state: &AppState,
+    progress_tx: &mpsc::Sender<String>,
+) -> SimulationResponse {
+    let mut response = execute_on_engine(request, state, progress_tx).await;
+    if response.error_code.as_deref() == Some(SIMULATOR_MISSING) {
+        response = retry_after_missing_simulator(request, response, state, progress_tx).await;
+    }
+    match other_engine_request(request, &response, state).await {
+        Some(retry) => retry_on_other_engine(&retry, response, state, progress_tx).await,
+        None => response,
+    }
+}
+
+/// Re-detect the simulator whose executable vanished after it was resolved, e.g. during an
+/// upgrade, and run `request` once more on whatever was found instead
+async fn retry_after_missing_simulator(
+    request: &SimulationRequest,
+    failed: SimulationResponse,
+    state: &AppState,
+    progress_tx: &mpsc::Sender<String>,
+) -> SimulationResponse {
+    let (stale, found) = detection::replace_missing(state, &failed.simulator, detector(&failed.simulator)).await;
+    let label = simulator_label(&failed.simulator);
+    let stale = stale.unwrap_or_else(|| "its previous location".to_string());
+    let Some(found) = found else {
+        return SimulationResponse { error: Some(missing_install_error(&failed.simulator, &stale)), ..failed };
+    };
+
+    log::warn!("Simulation {}: {} disappeared from {}; retrying with {}", request.id, label, stale, found);
+    let mut response = execute_on_engine(request, state, progress_tx).await;
+    response.execution_time += failed.execution_time;
+    if response.error_code.as_deref() == Some(SIMULATOR_MISSING) {
+        response.error = Some(format!(
+            "{} is no longer installed at {}, and the one found at {} could not be started either",
+            label, stale, found
+        ));
+    } else {
+        response.warnings.insert(0, format!("{} was no longer at {}; ran with the one found at {}", label, stale, found));
+    }
+    response
+}
+
+/// Error for a simulator that was installed at `stale` and can no longer be found
+fn missing_install_error(simulator: &str, stale: &str) -> String {
+    format!(
+        "{} is no longer installed at {} and no other installation was found. {}",
+        simulator_label(simulator),
+        stale,
+        onboarding::install_hint(simulator)
+    )
+}
+
+fn simulator_label(simulator: &str) -> &'static str {
+    if simulator == "ngspice" { "ngspice" } else { "LTspice" }
+}
+
+fn detector(simulator: &str) -> fn() -> Option<String> {
+    if simulator == "ngspice" { simulator::detect_ngspice } else { simulator::detect_ltspice }
+}
+
+/// The request to run on the other engine after `response`, if it failed in a way that may not
+/// fail there
+/// Cancelled and timed out runs are never retried, nor is anything when the other engine isn't installed
+async fn other_engine_request(
+    request: &SimulationRequest,
+    response: &SimulationResponse,
+    state: &AppState,
+) -> Option<SimulationRequest> {
+    // Schematics need LTspice, and a replay's netlist was already prepared for its engine
+    if !request.auto_retry_other_engine
+        || response.error_code.as_deref() != Some(NETLIST_PARSE_ERROR)
+        || request.input_format == InputFormat::Asc
+        || request.replay_of.is_some()
+    {
+        return None;
+    }
+    let other = if response.simulator == "ngspice" { "ltspice" } else { "ngspice" };
+    resolve_simulator(other, state).await.ok()?;
+
+    // Written for the engine that failed, unless the request said otherwise
+    let dialect = request.dialect.clone().unwrap_or_else(|| response.simulator.clone());
+    Some(SimulationRequest {
+        simulator: other.to_string(),
+        dialect: Some(dialect),
+        auto_retry_other_engine: false,
+        ..request.clone()
+    })
+}
+
+/// Run `retry` after the requested engine failed with `failed`
+/// If it fails as well, the response carries both engines' errors
+async fn retry_on_other_engine(
+    retry: &SimulationRequest,
+    failed: SimulationResponse,
+    state: &AppState,
+    progress_tx: &mpsc::Sender<String>,
+) -> SimulationResponse {
+    log::warn!(
+        "Simulation {}: {} could not parse the netlist; retrying with {}",
+        retry.id,
+        failed.simulator,
+        retry.simulator
+    );
+    let stage = ProgressStage::SwitchingEngine { simulator: retry.simulator.clone() };
+    send_progress(progress_tx, &stage_progress(&retry.id, stage)).await;
+
+    let mut response = execute_on_engine(retry, state, progress_tx).await;
+    response.execution_time += failed.execution_time;
+    response.timings.absorb(&failed.timings);
+    response.timings.total_ms = response.execution_time;
+    response.fallback_simulator_used = Some(response.simulator.clone());
+    let failed_error = EngineError {
+        simulator: failed.simulator,
+        error: failed.error.unwrap_or_default(),
+        error_code: failed.error_code,
+    };
+    if response.success {
+        response
+            .warnings
+            .push(format!("{} could not parse the netlist: {}", failed_error.simulator, failed_error.error));
+    } else {
+        response.engine_errors = vec![
+            failed_error,
+            EngineError {
+                simulator: response.simulator.clone(),
+                error: response.error.clone().unwrap_or_default(),
+                error_code: response.error_code.clone(),
+            },
+        ];
+    }
+    response
+}
+
+/// Run a simulation request on the engine it names
+async fn execute_on_engine(
+    request: &SimulationRequest,
+    state: &AppState,
+    progress_tx: &mpsc::Sender<String>,
+) -> SimulationResponse {
+    let start_time = std::time::Instant::now();
+    let simulator_type = request.simulator.as_str();
+
+    // Get simulator path based on requested type
+    let (simulator_path, simulator_name) = match resolve_simulator(simulator_type, state).await {
+        Ok(resolved) => resolved,
+        Err((simulator_name, error)) => {
+            return SimulationResponse {
+                id: uuid::Uuid::new_v4().to_string(),
+                msg_type: "simulation_result".to_string(),
+                request_id: request.id.clone(),
+                timestamp: now_ms(),
+                success: false,
+                f32_results: false,
+                results: None,
+                error: Some(error),
+                error_code: None,
+                execution_time: 0,
+                simulator: simulator_name.to_string(),
+                monte_carlo: None,
+                duplicate: false,
+                retried_with: None,
+                translations: Vec::new(),
+                debug_info: None,
+                results_compressed: None,
+                compression: None,
+                retry_after_ms: None,
+                warnings: Vec::new(),
+                simulator_version: None,
+                simulator_binary_sha256: None,
+                replay_of: None,
+                fallback_simulator_used: None,
+                engine_errors: Vec::new(),
+                artifact_id: None,
+                raw_file_size: None,
+                trace_catalog: None,
+                operating_point: None,
+                wave_data: None,
+                libraries_used: Vec::new(),
+                busy_info: None,
+                timings: SimulationTimings::default(),
+            };
+        }
+    };
+
+    log::info!("Running simulation with {} at: {}", simulator_name, simulator_path);
+
+    let prepared = async {
+        let quality = quality_profile(&request.waveform_quality, state).await?;
+        let search_paths = library_search_paths(request, simulator_name, state).await?;
+        check_wave_output(request, simulator_name)?;
+        let prepared = request_netlist(request, simulator_name, &simulator_path, state).await?;
+        Ok::<_, (String, &'static str)>((quality, search_paths, prepared))
+    };
+    let (quality, search_paths, (netlist, translations, attachments)) = match prepared.await {
+        Ok(prepared) => prepared,
+        Err((error, error_code)) => {
+            return SimulationResponse {
+                id: uuid::Uuid::new_v4().to_string(),
+                msg_type: "simulation_result".to_string(),
+                request_id: request.id.clone(),
+                timestamp: now_ms(),
+                success: false,
+                f32_results: false,
+                results: None,
+                error: Some(error),
+                error_code: Some(error_code.to_string()),
+                execution_time: 0,
+                simulator: simulator_name.to_string(),
+                monte_carlo: None,
+                duplicate: false,
+                retried_with: None,
+                translations: Vec::new(),
+                debug_info: None,
+                results_compressed: None,
+                compression: None,
+                retry_after_ms: None,
+                warnings: Vec::new(),
+                simulator_version: None,
+                simulator_binary_sha256: None,
+                replay_of: None,
+                fallback_simulator_used: None,
+                engine_errors: Vec::new(),
+                artifact_id: None,
+                raw_file_size: None,
+                trace_catalog: None,
+                operating_point: None,
+                wave_data: None,
+                libraries_used: Vec::new(),
+                busy_info: None,
+                timings: SimulationTimings::default(),
+            };
+        }
+    };
+    if let Err(e) = run_preflight(&netlist, &quality, 1, state).await {
+        return SimulationResponse {
+            id: uuid::Uuid::new_v4().to_string(),
+            msg_type: "simulation_result".to_string(),
+            request_id: request.id.clone(),
+            timestamp: now_ms(),
+            success: false,
+            f32_results: false,
+            results: None,
+            error: Some(e.to_string()),
+            error_code: Some(e.error_code().to_string()),
+            execution_time: 0,
+            simulator: simulator_name.to_string(),
+            monte_carlo: None,
+            duplicate: false,
+            retried_with: None,
+            translations: Vec::new(),
+            debug_info: None,
+            results_compressed: None,
+            compression: None,
+            retry_after_ms: None,
+            warnings: Vec::new(),
+            simulator_version: None,
+            simulator_binary_sha256: None,
+            replay_of: None,
+            fallback_simulator_used: None,
+            engine_errors: Vec::new(),
+            artifact_id: None,
+            raw_file_size: None,
+            trace_catalog: None,
+            operating_point: None,
+            wave_data: None,
+            libraries_used: Vec::new(),
+            busy_info: None,
+            timings: SimulationTimings::default(),
+        };
+    }
+
+    if let Err(error) = confirm_long_run(request, &netlist, 1, state, progress_tx).await {
+        return SimulationResponse {
+            id: uuid::Uuid::new_v4().to_string(),
+            msg_type: "simulation_result".to_string(),
+            request_id: request.id.clone(),
+            timestamp: now_ms(),
+            success: false,
+            f32_results: false,
+            results: None,
+            error: Some(error),
+            error_code: Some("NOT_CONFIRMED".to_string()),
+            execution_time: 0,
+            simulator: simulator_name.to_string(),
+            monte_carlo: None,
+            duplicate: false,
+            retried_with: None,
+            translations: Vec::new(),
+            debug_info: None,
+            results_compressed: None,
+            compression: None,
+            retry_after_ms: None,
+            warnings: Vec::new(),
+            simulator_version: None,
+            simulator_binary_sha256: None,
+            replay_of: None,
+            fallback_simulator_used: None,
+            engine_errors: Vec::new(),
+            artifact_id: None,
+            raw_file_size: None,
+            trace_catalog: None,
+            operating_point: None,
+            wave_data: None,
+            libraries_used: Vec::new(),
+            busy_info: None,
+            timings: SimulationTimings::default(),
+        };
+    }
+
+    // Claim a worker slot and register the simulation under its request id
+    let slot = match state.begin_simulation(&request.id, request.origin.as_deref()).await {
+        Ok(slot) => slot,
+        Err(error) => {
+            let (error_code, busy_info) = refusal_details(state, &error, request.origin.as_deref()).await;
+            return SimulationResponse {
+                id: uuid::Uuid::new_v4().to_string(),
+                msg_type: "simulation_result".to_string(),
+                request_id: request.id.clone(),
+                timestamp: now_ms(),
+                success: false,
+                f32_results: false,
+                results: None,
+                error: Some(error.to_string()),
+                error_code,
+                execution_time: 0,
+                simulator: simulator_name.to_string(),
+                monte_carlo: None,
+                duplicate: false,
+                retried_with: None,
+                translations: Vec::new(),
+                debug_info: None,
+                results_compressed: None,
+                compression: None,
+                retry_after_ms: None,
+                warnings: Vec::new(),
+                simulator_version: None,
+                simulator_binary_sha256: None,
+                replay_of: None,
+                fallback_simulator_used: None,
+                engine_errors: Vec::new(),
+                artifact_id: None,
+                raw_file_size: None,
+                trace_catalog: None,
+                operating_point: None,
+                wave_data: None,
+                libraries_used: Vec::new(),
+                busy_info,
+                timings: SimulationTimings::default(),
+            };
+        }
+    };
+
+    // Run simulation with the appropriate simulator
+    let keepalive = Keepalive::start(state, &request.id, &slot.simulation, progress_tx).await;
+    let mut process_options = process_options(state, &request.id, request.timeout, &slot, progress_tx).await;
+    let (ltspice_flags, mut warnings) = engine_flags(request, simulator_name, &simulator_path);
+    if !simulator::has_end_line(&netlist) {
+        warnings.push("The netlist has no .end line; one was added at the end".to_string());
+    }
+    process_options.ltspice_flags = ltspice_flags;
+    process_options.library_search_paths = search_paths;
+    process_options.save_signals = save_signals(request, &netlist);
+    if simulator_name == "ngspice" {
+        let unknown = simulator::unknown_ngspice_signals(&netlist, &process_options.save_signals);
+        if !unknown.is_empty() {
+            warnings.push(format!("Not saved, as the netlist has no such node or element: {}", unknown.join(", ")));
+        }
+    }
+    process_options.peek = request.peek || request.peek_fast;
+    process_options.no_auto_options |= request.no_auto_options;
+    let timings = Arc::new(std::sync::Mutex::new(SimulationTimings::default()));
+    process_options.timings = Some(timings.clone());
+    if request.keep_raw {
+        process_options.keep_raw = state.artifacts.read().await.staging_path(&request.id);
+    }
+    let wave = request.wave_output.clone().map(wave::WaveCapture::new);
+    process_options.wave = wave.clone();
+    let libraries_used = Arc::new(std::sync::Mutex::new(Vec::new()));
+    process_options.libraries_used = Some(libraries_used.clone());
+
+    // LTspice writes its log to a file, so only ngspice output can be streamed
+    let log_forwarder = (request.stream_logs && simulator_name == "ngspice").then(|| {
+        let (log_tx, log_rx) = mpsc::unbounded_channel();
+        process_options.log_sink = Some(log_tx);
+        tokio::spawn(forward_logs(request.id.clone(), log_rx, progress_tx.clone()))
+    });
+
+    // Keep what the last run was given for the response
+    let debug_info = Arc::new(std::sync::Mutex::new(None));
+    if request.include_debug_info {
+        let debug_info = debug_info.clone();
+        process_options.debug_sink = Some(Arc::new(move |info: DebugInfo| {
+            if let Ok(mut latest) = debug_info.lock() {
+                *latest = Some(info);
+            }
+        }));
+    }
+
+    let (stage_tx, stage_rx) = mpsc::channel(16);
+    let stage_forwarder = tokio::spawn(forward_stages(
+        request.id.clone(),
+        slot.simulation.clone(),
+        stage_rx,
+        progress_tx.clone(),
+    ));
+
+    let run = |netlist: String| {
+        let (simulator_path, process_options, stage_tx) = (&simulator_path, &process_options, &stage_tx);
+        let (attachments, quality) = (&attachments, &quality);
+        async move {
+            match simulator_name {
+                "ngspice" => {
+                    simulator::run_ngspice_simulation(
+                        simulator_path,
+                        &netlist,
+                        attachments,
+                        quality,
+                        process_options,
+                        Some(stage_tx),
+                    )
+                    .await
+                }
+                _ => {
+                    simulator::run_ltspice_simulation(
+                        simulator_path,
+                        &netlist,
+                        attachments,
+                        quality,
+                        process_options,
+                        Some(stage_tx),
+                    )
+                    .await
+                }
+            }
+        }
+    };
+    // A fast peek only needs LTspice to write the raw file's header, so its .tran stops early
+    let netlist = match (request.peek_fast && simulator_name == "ltspice").then(|| peek::shorten_transient(&netlist)) {
+        Some(Some(shortened)) => shortened,
+        _ => netlist.to_string(),
+    };
+    let mut result = run(netlist.clone()).await;
+
+    // Retry a convergence failure, or a sweep that stopped at the operating point, with each
+    // fallback in turn; timeouts and cancels are not retried
+    let mut retried_with = None;
+    if request.convergence_assist {
+        for fallback in simulator::CONVERGENCE_FALLBACKS {
+            let converged = !matches!(
+                &result,
+                Err(e) if e.is::<simulator::ConvergenceFailure>() || e.is::<simulator::AnalysisIncomplete>()
+            );
+            if converged || slot.simulation.is_cancelled() {
+                break;
+            }
+            let Some(fallback_netlist) = fallback.apply(&netlist) else {
+                continue;
+            };
+            log::warn!("Simulation {} did not converge; retrying with {}", request.id, fallback.name());
+            let _ = stage_tx
+                .send(ProgressStage::Retrying { fallback: fallback.name().to_string() })
+                .await;
+            result = run(fallback_netlist).await;
+            retried_with = Some(fallback.name().to_string());
+        }
+    }
+
+    // A cancel only counts if it stopped the run; results that were already in are returned
+    slot.simulation.settle(result.is_err() && slot.simulation.is_cancelled());
+
+    // Dropping the senders closes the stage and log channels; wait for the last
+    // messages so every update reaches the client before the result
+    drop(keepalive);
+    drop(stage_tx);
+    drop(process_options);
+    let _ = stage_forwarder.await;
+    if let Some(forwarder) = log_forwarder {
+        let _ = forwarder.await;
+    }
+
+    // Release the worker slot, noting whether the simulation was cancelled
+    let was_cancelled = state.end_simulation(slot).await;
+    let debug_info = debug_info.lock().ok().and_then(|mut latest| latest.take());
+
+    // If cancelled, return cancelled error
+    if was_cancelled {
+        state
+            .metrics
+            .write()
+            .await
+            .record(simulator_name, Outcome::Cancelled, start_time.elapsed().as_millis() as u64);
+        return SimulationResponse {
+            id: uuid::Uuid::new_v4().to_string(),
+            msg_type: "simulation_result".to_string(),
+            request_id: request.id.clone(),
+            timestamp: now_ms(),
+            success: false,
+            f32_results: false,
+            results: None,
+            error: Some("Simulation cancelled".to_string()),
+            error_code: None,
+            execution_time: start_time.elapsed().as_millis() as u64,
+            simulator: simulator_name.to_string(),
+            monte_carlo: None,
+            duplicate: false,
+            retried_with: None,
+            translations: Vec::new(),
+            debug_info,
+            results_compressed: None,
+            compression: None,
+            retry_after_ms: None,
+            warnings: Vec::new(),
+            simulator_version: None,
+            simulator_binary_sha256: None,
+            replay_of: None,
+            fallback_simulator_used: None,
+            engine_errors: Vec::new(),
+            artifact_id: None,
+            raw_file_size: None,
+            trace_catalog: None,
+            operating_point: None,
+            wave_data: None,
+            libraries_used: Vec::new(),
+            busy_info: None,
+            timings: SimulationTimings::default(),
+        };
+    }
+
+    let execution_time = start_time.elapsed().as_millis() as u64;
+    let outcome = if result.is_ok() { Outcome::Succeeded } else { Outcome::Failed };
+    state.metrics.write().await.record(simulator_name, outcome, execution_time);
+    let mut timings = timings.lock().map(|timings| timings.clone()).unwrap_or_default();
+    timings.total_ms = execution_time;
+    let libraries_used = libraries_used.lock().map(|mut used| std::mem::take(&mut *used)).unwrap_or_default();
+    warnings.extend(libraries_used::mismatch_warnings(&libraries_used));
+    timings.parse_mb_per_sec = timings.parse_throughput();
+    if let Some(warning) = slow_raw_read_warning(&timings) {
+        log::warn!("Simulation {}: {}", request.id, warning);
+        warnings.push(warning);
+    }
+
+    match result {
+        Ok(mut results) => {
+            if let Some(components) = &request.current_probes {
+                probes::rename_probe_traces(&mut results, components);
+            }
+            warnings.extend(trace_aliases::apply(&mut results, &request.trace_aliases));
+            results.plot_hints = Some(plot_hints::compute(&results, &netlist));
+            log::info!(
+                "Simulation completed with {}: {} traces, {} points",
+                simulator_name,
+                results.traces.len(),
+                results.time.len()
+            );
+
+            // Update simulation stats
+            {
+                let mut count = state.simulation_count.write().await;
+                *count += 1;
+                let mut last_time = state.last_simulation_time.write().await;
+                *last_time = Some(now_ms());
+            }
+            state.store_results(&results).await;
+            if let Some(truncation) = &results.truncated {
+                warnings.push(truncation.warning());
+            }
+            let mut artifact = None;
+            if request.keep_raw {
+                match state.artifacts.write().await.add(&request.id) {
+                    Ok(kept) => artifact = Some(kept),
+                    Err(e) => {
+                        log::warn!("Raw file of simulation {} not kept: {}", request.id, e);
+                        warnings.push(format!("The raw file was not kept: {}", e));
+                    }
+                }
+            }
+
+            SimulationResponse {
+                id: uuid::Uuid::new_v4().to_string(),
+                msg_type: "simulation_result".to_string(),
+                request_id: request.id.clone(),
+                timestamp: now_ms(),
+                success: true,
+                f32_results: false,
+                results: Some(results),
+                error: None,
+                error_code: None,
+                execution_time,
+                simulator: simulator_name.to_string(),
+                monte_carlo: None,
+                duplicate: false,
+                retried_with,
+                translations,
+                debug_info,
+                results_compressed: None,
+                compression: None,
+                retry_after_ms: None,
+                warnings,
+                simulator_version: None,
+                simulator_binary_sha256: None,
+                replay_of: None,
+                fallback_simulator_used: None,
+                engine_errors: Vec::new(),
+                artifact_id: artifact.as_ref().map(|artifact| artifact.id.clone()),
+                raw_file_size: artifact.map(|artifact| artifact.size),
+                trace_catalog: None,
+                operating_point: None,
+                wave_data: wave.and_then(|wave| wave.take()).map(|bytes| BASE64_STANDARD.encode(bytes)),
+                libraries_used,
+                busy_info: None,
+                timings: timings.clone(),
+            }
+        }
+        Err(e) => {
+            log::error!("Simulation failed with {}: {}", simulator_name, e);
+            let error_code = if e.is::<simulator::SimulationHung>() {
+                Some("SIMULATION_HUNG".to_string())
+            } else if e.is::<simulator::ConvergenceFailure>() {
+                Some("CONVERGENCE_FAILURE".to_string())
+            } else if e.is::<simulator::NetlistParseError>() {
+                Some(NETLIST_PARSE_ERROR.to_string())
+            } else if e.is::<simulator::SimulatorMissing>() {
+                Some(SIMULATOR_MISSING.to_string())
+            } else if e.is::<simulator::AnalysisIncomplete>() {
+                Some(ANALYSIS_INCOMPLETE.to_string())
+            } else if e.is::<simulator::LtspiceNeedsFirstRun>() {
+                Some(LTSPICE_NEEDS_FIRST_RUN.to_string())
+            } else if e.is::<wave::WaveNodeNotFound>() {
+                Some(WAVE_NODE_NOT_FOUND.to_string())
+            } else if e.is::<simulator::LibraryMissing>() {
+                Some(LIBRARY_MISSING.to_string())
+            } else {
+                None
+            };
+            let operating_point = e
+                .downcast_ref::<simulator::AnalysisIncomplete>()
+                .map(|incomplete| incomplete.operating_point.clone());
+            SimulationResponse {
+                id: uuid::Uuid::new_v4().to_string(),
+                msg_type: "simulation_result".to_string(),
+                request_id: request.id.clone(),
+                timestamp: now_ms(),
+                success: false,
+                f32_results: false,
+                results: None,
+                error: Some(e.to_string()),
+                error_code,
+                execution_time,
+                simulator: simulator_name.to_string(),
+                monte_carlo: None,
+                duplicate: false,
+                retried_with,
+                translations,
+                debug_info,
+                results_compressed: None,
+                compression: None,
+                retry_after_ms: None,
+                warnings,
+                simulator_version: None,
+                simulator_binary_sha256: None,
+                replay_of: None,
+                fallback_simulator_used: None,
+                engine_errors: Vec::new(),
+                artifact_id: None,
+                raw_file_size: None,
+                trace_catalog: None,
+                operating_point,
+                wave_data: None,
+                libraries_used,
+                busy_info: None,
+                timings,
+            }
+        }
+    }
+}
+
+/// Keep the results of `response` for `get_trace_data` and send only their catalog
+/// Results over the store's budget are sent in full, with a warning
+async fn defer_trace_data(request: &SimulationRequest, state: &AppState, response: &mut SimulationResponse) {
+    let Some(results) = response.results.as_mut() else {
+        return;
+    };
+    let full = Arc::new(results.clone());
+    if let Err(e) = state.trace_store.write().await.insert(&request.id, full) {
+        log::warn!("Trace data of simulation {} not deferred: {}", request.id, e);
+        response.warnings.push(format!("Trace data was sent in full: {}", e));
+        return;
+    }
+    state.enforce_result_budget().await;
+    response.trace_catalog = Some(trace_store::defer(results));
+}
+
+/// Publish the outcome of a simulate request, for completion notifications
+pub fn report_finished(state: &AppState, response: &SimulationResponse, client_connected: bool) {
+    state.notify_status(StatusEvent::SimulationFinished {
+        request_id: response.request_id.clone(),
+        success: response.success,
+        summary: response.summary(),
+        execution_ms: response.execution_time,
+        client_connected,
+    });
+}
+
+/// Run a simulate request registered in `state.recent_requests`, sending progress and the
+/// result to every connection attached to it, including ones that join while it runs
+/// `origin` is the page that sent the request, for per-origin metrics
+pub async fn execute_shared(
+    request: &SimulationRequest,
+    origin: &str,
+    state: &AppState,
+    listeners: Arc<Listeners>,
+) -> SimulationResponse {
+    run_shared(request, origin, state, listeners, |progress_tx| async move {
+        if request.monte_carlo.is_some() {
+            execute_monte_carlo(request, state, &progress_tx).await
+        } else {
+            execute(request, state, &progress_tx).await
+        }
+    })
+    .await
+}
+
+/// `execute_shared` with the simulation itself done by `run`, which is handed the progress sender
+async fn run_shared<'a, F, Fut>(
+    request: &'a SimulationRequest,
+    origin: &str,
+    state: &'a AppState,
+    listeners: Arc<Listeners>,
+    run: F,
+) -> SimulationResponse
+where
+    F: FnOnce(mpsc::Sender<String>) -> Fut,
+    Fut: std::future::Future<Output = SimulationResponse> + 'a,
+{
+    state.metrics.write().await.record_origin_started(origin);
+    let share = state.result_feed.source(origin, &listeners);
+    let (progress_tx, relay) = relay_progress(listeners.clone(), Some(share.clone()));
+
+    let started = std::time::Instant::now();
+    // A panic must still answer the client and free the simulation's state, or it stays
+    // "simulating" until the agent restarts
+    let mut response = match AssertUnwindSafe(run(progress_tx)).catch_unwind().await {
+        Ok(response) => response,
+        Err(panic) => {
+            let message = panic_message(panic);
+            recover_from_panic(request, state, message, started.elapsed().as_millis() as u64).await
+        }
+    };
+    finish_relay(state, &request.id, relay).await;
+    if let Some(binary) = state.simulator_binary(&response.simulator).await {
+        response.simulator_version = binary.version;
+        response.simulator_binary_sha256 = Some(binary.sha256);
+    }
+    response.replay_of = request.replay_of.clone();
+
+    report_finished(state, &response, listeners.any_open());
+    // Monte Carlo runs many simulations, so only their total is broken out
+    if request.monte_carlo.is_some() {
+        response.timings.total_ms = response.execution_time;
+    }
+    // Deferred results are kept and cataloged before rounding, so get_trace_data and the
+    // catalog's statistics see full precision like the Monte Carlo statistics and exports do
+    if request.defer_trace_data {
+        defer_trace_data(request, state, &mut response).await;
+    }
+    // Only what goes on the wire is rounded
+    if let (Some(precision), Some(results)) = (request.precision, response.results.as_mut()) {
+        precision::round_results(results, precision);
+    }
+    response.f32_results = request.f32_results;
+    if request.compress {
+        let started = std::time::Instant::now();
+        let compressed = response.compress_results();
+        let elapsed = started.elapsed().as_millis() as u64;
+        response.timings.serialization_ms += elapsed;
+        response.timings.total_ms += elapsed;
+        match compressed {
+            Ok(Some((json_bytes, compressed_bytes))) => log::info!(
+                "Compressed results of simulation {}: {} KB -> {} KB",
+                request.id,
+                json_bytes / 1024,
+                compressed_bytes / 1024
+            ),
+            Ok(None) => {}
+            Err(e) => log::warn!("Could not compress results of simulation {}: {}", request.id, e),
+        }
+    }
+
+    let json = response.serialize_timed().unwrap_or_default();
+    log::info!("Simulation {} timings: {}", request.id, response.timings);
+
+    // Later duplicates get the stored response; connections attached until now get it sent
+    state.recent_requests.write().await.finish(&response);
+    state.enforce_result_budget().await;
+    let delivered = listeners.send(&json).await;
+    share.publish(&json);
+    let mut metrics = state.metrics.write().await;
+    metrics.record_bytes_served(json.len() * delivered);
+    metrics.record_origin_finished(origin, response.success, json.len() * delivered);
+    drop(metrics);
+    response
+}
+
+/// Text a panic was raised with
+fn panic_message(panic: Box<dyn Any + Send>) -> String {
+    match panic.downcast::<String>() {
+        Ok(message) => *message,
+        Err(panic) => panic.downcast_ref::<&str>().map_or("unknown panic", |message| message).to_string(),
+    }
+}
+
+/// Clean up after a simulation task panicked and build its failure response: the active entry
+/// and any pending confirmation are dropped, leftover simulator processes killed and the run
+/// counted as failed
+async fn recover_from_panic(
+    request: &SimulationRequest,
+    state: &AppState,
+    message: String,
+    execution_time: u64,
+) -> SimulationResponse {
+    log::error!("Simulation {} panicked: {}", request.id, message);
+    for pid in state.abandon_simulation(&request.id).await {
+        log::warn!("Killing simulator PID {} left by simulation {}", pid, request.id);
+        simulator::kill_process_tree(pid);
+    }
+    state.metrics.write().await.record(&request.simulator, Outcome::Failed, execution_time);
+
+    SimulationResponse {
+        id: uuid::Uuid::new_v4().to_string(),
+        msg_type: "simulation_result".to_string(),
+        request_id: request.id.clone(),
+        timestamp: now_ms(),
+        success: false,
+        f32_results: false,
+        results: None,
+        error: Some(format!("Internal error in the agent: {}", message)),
+        error_code: Some("INTERNAL_ERROR".to_string()),
+        execution_time,
+        simulator: request.simulator.clone(),
+        monte_carlo: None,
+        duplicate: false,
+        retried_with: None,
+        translations: Vec::new(),
+        debug_info: None,
+        results_compressed: None,
+        compression: None,
+        retry_after_ms: None,
+        warnings: Vec::new(),
+        simulator_version: None,
+        simulator_binary_sha256: None,
+        replay_of: None,
+        fallback_simulator_used: None,
+        engine_errors: Vec::new(),
+        artifact_id: None,
+        raw_file_size: None,
+        trace_catalog: None,
+        operating_point: None,
+        wave_data: None,
+        libraries_used: Vec::new(),
+        busy_info: None,
+        timings: SimulationTimings { total_ms: execution_time, ..SimulationTimings::default() },
+    }
+}
+
+/// Forward a run's progress to `listeners`, and to connections subscribed to every result
+/// through `share`, until every returned sender is dropped
+/// Never waits on a slow connection: updates it has no room for are dropped, and the relay
+/// task yields how many
+pub fn relay_progress(listeners: Arc<Listeners>, share: Option<FeedSource>) -> (mpsc::Sender<String>, JoinHandle<usize>) {
+    let (progress_tx, mut progress_rx) = mpsc::channel::<String>(16);
+    let relay = tokio::spawn(async move {
+        let mut dropped = 0;
+        while let Some(message) = progress_rx.recv().await {
+            dropped += listeners.offer(&message);
+            if let Some(share) = &share {
+                share.publish(&message);
+            }
+        }
+        dropped
+    });
+    (progress_tx, relay)
+}
+
+/// Wait for a relay to deliver the last progress updates and count what it dropped
+pub async fn finish_relay(state: &AppState, request_id: &str, relay: JoinHandle<usize>) {
+    let dropped = relay.await.unwrap_or(0);
+    if dropped > 0 {
+        log::warn!("Dropped {} progress messages of simulation {} for slow clients", dropped, request_id);
+        state.metrics.write().await.record_progress_dropped(dropped);
+    }
+}
+
+/// Watchdog, priority, PID and resource tracking options for a simulation holding `slot`
+/// A requested timeout above the configured limit is clamped, with a progress warning
+async fn process_options(
+    state: &AppState,
+    request_id: &str,
+    requested_timeout_ms: Option<u64>,
+    slot: &SimulationSlot,
+    progress_tx: &mpsc::Sender<String>,
+) -> simulator::ProcessOptions {
+    let settings = state.settings.read().await.clone();
+    let stall_secs = settings.watchdog_stall_secs;
+
+    let timeout_secs = effective_timeout_secs(requested_timeout_ms, settings.max_simulation_seconds);
+    if let Some(requested_ms) = requested_timeout_ms.filter(|ms| *ms > timeout_secs.saturating_mul(1000)) {
+        let stage = ProgressStage::TimeoutLimited {
+            requested_secs: requested_ms.div_ceil(1000),
+            effective_secs: timeout_secs,
+        };
+        log::warn!("Simulation {}: {}", request_id, stage.message());
+        send_progress(progress_tx, &stage_progress(request_id, stage)).await;
+    }
+
+    simulator::ProcessOptions {
+        process_id_holder: Some(slot.simulation.process_id.clone()),
+        hard_timeout: Some(std::time::Duration::from_secs(timeout_secs + simulator::WATCHDOG_MARGIN_SECS)),
+        stall_timeout: (stall_secs > 0).then(|| std::time::Duration::from_secs(stall_secs)),
+        first_run_grace: Some(simulator::LTSPICE_FIRST_RUN_GRACE),
+        background_priority: settings.background_priority,
+        ngspice_threads: settings.ngspice_threads,
+        // Set per request from its engine options
+        ltspice_flags: Vec::new(),
+        bundled_libraries: catalog::enabled_names(&state.bundled_libraries, &settings.disabled_bundled_libraries),
+        // Set per request from its library search paths
+        library_search_paths: simulator::LibrarySearchPaths::default(),
+        // Set by single runs from the request's saveTraces
+        save_signals: Vec::new(),
+        peek: false,
+        // Requests can turn it off too, with noAutoOptions
+        no_auto_options: !settings.auto_inject_options,
+        resource_sink: Some(resource_sink(request_id, slot, progress_tx)),
+        // Set by handlers that stream output or return debug info
+        log_sink: None,
+        log_activity: Some(slot.simulation.log_activity.clone()),
+        debug_sink: None,
+        cancelled: Some(Arc::new({
+            let simulation = slot.simulation.clone();
+            move || simulation.is_cancelled()
+        })),
+        // Set by single runs, which report where their time went
+        timings: None,
+        // Set by single runs that asked for their raw file
+        keep_raw: None,
+        // Set by single runs with waveOutput
+        wave: None,
+        temp_root: Some(state.temp_root.read().await.path.clone()),
+        // Set by single runs, which report the libraries they used
+        libraries_used: None,
+        ngspice_session: settings.ngspice_persistent_session.then(|| state.ngspice_session.clone()),
+    }
+}
+
+/// Warning for a raw file read so slowly that the temp dir is likely on a network share or a
+/// slow disk, where file I/O passes for simulator time
+fn slow_raw_read_warning(timings: &SimulationTimings) -> Option<String> {
+    let throughput = timings.raw_read_throughput()?;
+    if timings.raw_file_bytes < SLOW_READ_MIN_BYTES || throughput >= SLOW_READ_MB_PER_SEC {
+        return None;
+    }
+    Some(format!(
+        "The {} MB raw file was read at {:.1} MB/s, so the temp dir may be on a network share or a slow disk. \
+         Set temp_dir_override in the settings, or pick a local folder with \"Change…\" next to Temp Directory",
+        timings.raw_file_bytes / (1024 * 1024),
+        throughput
+    ))
+}
+
+/// Seconds a simulation may run: the requested timeout (in ms) rounded up, at most `max_secs`
+/// Requests without a timeout get the full limit
+fn effective_timeout_secs(requested_ms: Option<u64>, max_secs: u64) -> u64 {
+    let max_secs = max_secs.max(1);
+    match requested_ms {
+        Some(ms) if ms > 0 => ms.div_ceil(1000).min(max_secs),
+        _ => max_secs,
+    }
+}
+
+/// Record resource samples on the active simulation and forward them as progress updates
+fn resource_sink(
+    request_id: &str,
+    slot: &SimulationSlot,
+    progress_tx: &mpsc::Sender<String>,
+) -> simulator::ResourceSink {
+    let request_id = request_id.to_string();
+    let simulation = slot.simulation.clone();
+    let progress_tx = progress_tx.clone();
+    Arc::new(move |usage: ResourceUsage| {
+        let progress = SimulationProgress {
+            message: format!(
+                "Simulating (CPU {:.0}%, {} MB)",
+                usage.cpu_percent,
+                usage.memory_bytes / (1024 * 1024)
+            ),
+            resources: Some(usage.clone()),
+            ..stage_progress(&request_id, ProgressStage::Running { percent: None })
+        };
+        if let Ok(mut latest) = simulation.resources.lock() {
+            *latest = Some(usage);
+        }
+        // Never block the sampler; a dropped sample is replaced by the next one
+        if let Ok(json) = serde_json::to_string(&progress) {
+            let _ = progress_tx.try_send(json);
+        }
+    })
+}
+
+/// Progress message for a stage, described with the stage's default message
+fn stage_progress(request_id: &str, stage: ProgressStage) -> SimulationProgress {
+    SimulationProgress {
+        id: uuid::Uuid::new_v4().to_string(),
+        msg_type: "simulation_progress".to_string(),
+        request_id: request_id.to_string(),
+        timestamp: now_ms(),
+        message: stage.message(),
+        stage,
+        corner: None,
+        completed: None,
+        total: None,
+        resources: None,
+    }
+}
+
+async fn send_progress(progress_tx: &mpsc::Sender<String>, progress: &SimulationProgress) {
+    if let Ok(json) = serde_json::to_string(progress) {
+        let _ = progress_tx.send(json).await;
+    }
+}
+
+/// Publish a multi-run progress update to status listeners such as the tray, and keep it for `ping`
+fn report_progress(state: &AppState, simulation: &ActiveSimulation, progress: &SimulationProgress) {
+    if let Some(percent) = progress.percent() {
+        simulation.set_progress(percent);
+        state.notify_status(StatusEvent::Progress {
+            request_id: progress.request_id.clone(),
+            percent,
+        });
+    }
+}
+
+/// Sends `still_running` progress messages while a simulation runs, so clients can tell a
+/// silent simulator from a dead agent; stops when dropped
+struct Keepalive(Option<JoinHandle<()>>);
+
+impl Keepalive {
+    /// Start ticking every `keepalive_interval_secs` from the settings; a no-op when that is 0
+    async fn start(
+        state: &AppState,
+        request_id: &str,
+        simulation: &Arc<ActiveSimulation>,
+        progress_tx: &mpsc::Sender<String>,
+    ) -> Self {
+        let interval_secs = state.settings.read().await.keepalive_interval_secs;
+        if interval_secs == 0 {
+            return Self(None);
+        }
+        let interval = std::time::Duration::from_secs(interval_secs);
+        let (request_id, simulation, progress_tx) = (request_id.to_string(), simulation.clone(), progress_tx.clone());
+        Self(Some(tokio::spawn(async move {
+            let mut ticker = tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+            loop {
+                ticker.tick().await;
+                if simulation.is_cancelled() {
+                    break;
+                }
+                let stage = ProgressStage::StillRunning {
+                    elapsed_ms: simulation.started_at.elapsed().as_millis() as u64,
+                    last_log_activity: simulation.last_log_activity(),
+                };
+                send_progress(&progress_tx, &stage_progress(&request_id, stage)).await;
+            }
+        })))
+    }
+}
+
+impl Drop for Keepalive {
+    fn drop(&mut self) {
+        if let Some(task) = self.0.take() {
+            task.abort();
+        }
+    }
+}
+
+/// Translate stage transitions from the simulator into `simulation_progress` messages
+async fn forward_stages(
+    request_id: String,
+    simulation: Arc<ActiveSimulation>,
+    mut stages: mpsc::Receiver<ProgressStage>,
+    progress_tx: mpsc::Sender<String>,
+) {
+    while let Some(stage) = stages.recv().await {
+        log::debug!("Simulation {} reached stage {}", request_id, stage.name());
+        if let ProgressStage::Running { percent: Some(percent) } = stage {
+            simulation.set_progress(percent);
+        }
+        send_progress(&progress_tx, &stage_progress(&request_id, stage)).await;
+    }
+}
+
+/// Batch streamed log lines into `simulation_log` messages until the channel closes
+/// At most `MAX_LOG_LINES_PER_MESSAGE` lines are sent per flush interval; the rest are counted as dropped
+async fn forward_logs(
+    request_id: String,
+    mut lines: mpsc::UnboundedReceiver<LogLine>,
+    progress_tx: mpsc::Sender<String>,
+) {
+    while let Some(first) = lines.recv().await {
+        let mut batch = vec![first];
+        let mut dropped_lines = 0u32;
+        let deadline = tokio::time::Instant::now() + LOG_FLUSH_INTERVAL;
+
+        // Collect until the flush interval ends or the process closes its output
+        while let Ok(Some(line)) = tokio::time::timeout_at(deadline, lines.recv()).await {
+            if batch.len() < MAX_LOG_LINES_PER_MESSAGE {
+                batch.push(line);
+            } else {
+                dropped_lines += 1;
+            }
+        }
+
+        let message = SimulationLog {
+            id: uuid::Uuid::new_v4().to_string(),
+            msg_type: "simulation_log".to_string(),
+            request_id: request_id.clone(),
+            timestamp: now_ms(),
+            lines: batch,
+            dropped_lines,
+        };
+        if let Ok(json) = serde_json::to_string(&message) {
+            if progress_tx.send(json).await.is_err() {
+                break;
+            }
+        }
+    }
+}
+
+/// Signals a request asks to save besides the netlist's `.probe`/`.plot` ones, with aliases read
+/// as the names they stand for; once saving is restricted, the current probes' sources are saved
+/// too so their `I(<component>)` traces survive
+fn save_signals(request: &SimulationRequest, netlist: &str) -> Vec<String> {
+    let mut signals = trace_aliases::raw_names(&request.save_traces, &request.trace_aliases);
+    let restricted = !signals.is_empty() || !netlist::parse(netlist).plotted.is_empty();
+    if let (true, Some(components)) = (restricted, &request.current_probes) {
+        signals.extend(components.iter().map(|component| probes::probe_current(component)));
+    }
+    signals
+}
+
+/// LTspice switches for a request's engine options, and a warning for each option that was ignored
+fn engine_flags(request: &SimulationRequest, simulator_name: &str, simulator_path: &str) -> (Vec<&'static str>, Vec<String>) {
+    if request.engine_options == EngineOptions::default() {
+        return (Vec::new(), Vec::new());
+    }
+    let (flags, warnings) = if simulator_name == "ltspice" {
+        simulator::ltspice_engine_flags(simulator_path, &request.engine_options)
+    } else {
+        (Vec::new(), vec![format!("engineOptions were ignored: they apply to LTspice only, not {}", simulator_name)])
+    };
+    for warning in &warnings {
+        log::warn!("Simulation {}: {}", request.id, warning);
+    }
+    (flags, warnings)
+}
+
+/// Look up the executable for the requested simulator type
+/// On failure returns the simulator name together with a user-facing error
+async fn resolve_simulator(
+    simulator_type: &str,
+    state: &AppState,
+) -> Result<(String, &'static str), (&'static str, String)> {
+    // Default to LTspice
+    let simulator = if simulator_type == "ngspice" { "ngspice" } else { "ltspice" };
+    match current_simulator_path(state, simulator).await {
+        Ok(path) => Ok((path, simulator)),
+        Err(Some(stale)) => Err((simulator, missing_install_error(simulator, &stale))),
+        Err(None) => Err((
+            simulator,
+            format!("{} not found on this system. {}", simulator_label(simulator), onboarding::install_hint(simulator)),
+        )),
+    }
+}
+
+/// Cached simulator path, re-running detection once if the executable is gone
+/// (uninstalled or moved since startup) or was never found
+/// On failure returns the path it was last installed at, if it was
+async fn current_simulator_path(state: &AppState, simulator: &str) -> Result<String, Option<String>> {
+    let cached = if simulator == "ngspice" { &state.ngspice_path } else { &state.ltspice_path };
+    let path = cached.read().await.clone();
+    match path {
+        Some(path) if std::path::Path::new(&path).exists() => return Ok(path),
+        Some(_) => {
+            let (stale, found) = detection::replace_missing(state, simulator, detector(simulator)).await;
+            return found.ok_or(stale);
+        }
+        None => {}
+    }
+
+    let detected = detector(simulator)();
+    if let Some(found) = &detected {
+        log::info!("{} detected at: {}", simulator_label(simulator), found);
+        *cached.write().await = detected.clone();
+        detection::refresh_onboarding(state).await;
+        state.notify_status(StatusEvent::Changed);
+        state.notify_status(StatusEvent::CapabilitiesChanged);
+    }
+    detected.ok_or(None)
+}
+
+/// The request's netlist as text, decompressed when it was sent as `gzip+base64`
+fn decode_netlist(request: &SimulationRequest) -> Result<Cow<'_, str>, (String, &'static str)> {
+    match request.netlist_encoding {
+        NetlistEncoding::Plain => Ok(Cow::Borrowed(request.netlist.as_str())),
+        NetlistEncoding::GzipBase64 => {
+            let netlist = decompress_netlist(&request.netlist, MAX_NETLIST_BYTES)?;
+            log::info!(
+                "Simulation {}: decompressed a {} KB netlist to {} KB",
+                request.id,
+                request.netlist.len() / 1024,
+                netlist.len() / 1024
+            );
+            Ok(Cow::Owned(netlist))
+        }
+    }
+}
+
+/// Text of a base64 gzip netlist
+/// Decompression stops just past `max_bytes`, so a small message can't expand without limit
+fn decompress_netlist(encoded: &str, max_bytes: usize) -> Result<String, (String, &'static str)> {
+    let invalid = |reason: String| (format!("Compressed netlist could not be read: {}", reason), "INVALID_NETLIST_ENCODING");
+    let gzip = BASE64_STANDARD.decode(encoded.trim()).map_err(|e| invalid(e.to_string()))?;
+    let mut bytes = Vec::new();
+    flate2::read::GzDecoder::new(gzip.as_slice())
+        .take(max_bytes as u64 + 1)
+        .read_to_end(&mut bytes)
+        .map_err(|e| invalid(e.to_string()))?;
+    if bytes.len() > max_bytes {
+        return Err((
+            format!("Netlist is larger than {} MB once decompressed", max_bytes / (1024 * 1024)),
+            "NETLIST_TOO_LARGE",
+        ));
+    }
+    String::from_utf8(bytes).map_err(|_| invalid("it is not UTF-8 text".to_string()))
+}
+
+/// Reject a `waveOutput` the engine can't write or that is out of range
+fn check_wave_output(request: &SimulationRequest, simulator_name: &str) -> Result<(), (String, &'static str)> {
+    let Some(wave_output) = &request.wave_output else {
+        return Ok(());
+    };
+    if simulator_name == "ngspice" {
+        return Err((
+            "waveOutput needs LTspice; ngspice has no .wave directive".to_string(),
+            "WAVE_OUTPUT_UNSUPPORTED",
+        ));
+    }
+    match wave::check(wave_output) {
+        Some(error) => Err((error, "INVALID_WAVE_OUTPUT")),
+        None => Ok(()),
+    }
+}
+
+/// Netlist to simulate: generated by LTspice for schematic input, rewritten for the request's
+/// dialect and with the default analysis and any current probes added, plus the dialect rewrites
+/// made and the decoded attachments; a replay's netlist is already prepared
+/// Errors, including analyses the simulator can't run, come with the response's error code
+async fn request_netlist(
+    request: &SimulationRequest,
+    simulator_name: &str,
+    simulator_path: &str,
+    state: &AppState,
+) -> Result<(String, Vec<String>, Vec<simulator::WorkspaceFile>), (String, &'static str)> {
+    let attachments =
+        simulator::decode_attachments(&request.attachments).map_err(|error| (error, "INVALID_ATTACHMENT"))?;
+    let source = decode_netlist(request)?;
+    if request.replay_of.is_some() {
+        simulator::check_analyses(&source, simulator_name).map_err(|error| (error, "UNSUPPORTED_ANALYSIS"))?;
+        record_netlist(request, &source, state).await;
+        return Ok((source.into_owned(), Vec::new(), attachments));
+    }
+    let netlist = match request.input_format {
+        InputFormat::Netlist => Cow::Borrowed(source.as_ref()),
+        InputFormat::Asc => {
+            if simulator_name == "ngspice" {
+                return Err((
+                    "Schematic (.asc) input needs LTspice; send a netlist to simulate with ngspice".to_string(),
+                    "UNSUPPORTED_INPUT_FORMAT",
+                ));
+            }
+            let missing = simulator::missing_asc_symbols(&source, &attachments);
+            if !missing.is_empty() {
+                return Err((
+                    format!("Schematic uses symbols not found in the LTspice library: {}", missing.join(", ")),
+                    "MISSING_SYMBOLS",
+                ));
+            }
+
+            let options = simulator::ProcessOptions {
+                hard_timeout: Some(ASC_NETLIST_TIMEOUT),
+                background_priority: state.settings.read().await.background_priority,
+                temp_root: Some(state.temp_root.read().await.path.clone()),
+                ..Default::default()
+            };
+            match simulator::netlist_from_asc(simulator_path, &source, &attachments, &options).await {
+                Ok(netlist) => Cow::Owned(netlist),
+                Err(e) => {
+                    log::error!("Netlist generation failed for {}: {}", request.id, e);
+                    return Err((e.to_string(), "NETLIST_GENERATION_FAILED"));
+                }
+            }
+        }
+    };
+
+    let (netlist, translations) = match request.dialect.as_deref() {
+        None => (netlist, Vec::new()),
+        Some(name) => {
+            let Some(dialect) = compat::Dialect::parse(name) else {
+                return Err((
+                    format!("Unknown netlist dialect \"{}\"; use ltspice, ngspice, pspice or auto", name),
+                    "UNSUPPORTED_DIALECT",
+                ));
+            };
+            let (translated, translations) = compat::translate(&netlist, dialect, simulator_name);
+            for translation in &translations {
+                log::info!("Simulation {}: {}", request.id, translation);
+            }
+            (Cow::Owned(translated), translations)
+        }
+    };
+    let mut netlist = simulator::apply_default_analysis(&netlist, request.default_analysis.as_deref()).into_owned();
+    simulator::check_analyses(&netlist, simulator_name).map_err(|error| (error, "UNSUPPORTED_ANALYSIS"))?;
+    if let Some(components) = &request.current_probes {
+        netlist = probes::insert_current_probes(&netlist, components).map_err(|errors| {
+            (format!("Could not add current probes: {}", errors.join("; ")), "INVALID_CURRENT_PROBE")
+        })?;
+    }
+    record_netlist(request, &netlist, state).await;
+    Ok((netlist, translations, attachments))
+}
+
+/// Keep the prepared netlist for `rerun_simulation`, if the settings allow it
+async fn record_netlist(request: &SimulationRequest, netlist: &str, state: &AppState) {
+    if state.settings.read().await.store_netlists {
+        state.history.write().await.record(request, netlist);
+    }
+}
+
+/// The waveform quality profile named `name` in the settings
+async fn quality_profile(name: &str, state: &AppState) -> Result<QualityProfile, (String, &'static str)> {
+    quality::resolve(&state.settings.read().await.quality_profiles, name)
+        .map_err(|error| (error, "UNKNOWN_QUALITY_PROFILE"))
+}
+
+/// The request's library search paths, checked against the allowed roots, whether this LTspice
+/// takes them on its command line, and how the libraries found are placed
+async fn library_search_paths(
+    request: &SimulationRequest,
+    simulator_name: &str,
+    state: &AppState,
+) -> Result<simulator::LibrarySearchPaths, (String, &'static str)> {
+    let strategy = library_strategy(request.library_strategy, state).await;
+    let variables = state.settings.read().await.path_variables.clone();
+    if request.library_search_paths.is_empty() {
+        return Ok(simulator::LibrarySearchPaths { strategy, variables, ..Default::default() });
+    }
+    if simulator_name == "ngspice" {
+        log::warn!("Simulation {}: librarySearchPaths ignored, they apply to LTspice only", request.id);
+        return Ok(simulator::LibrarySearchPaths { strategy, variables, ..Default::default() });
+    }
+    let allowed_roots = state.settings.read().await.allowed_library_roots.clone();
+    let dirs = simulator::check_library_search_paths(&request.library_search_paths, &allowed_roots)
+        .map_err(|error| (error, "LIBRARY_PATH_NOT_ALLOWED"))?;
+    let version = state.simulator_binary("ltspice").await.and_then(|binary| binary.version);
+    let on_command_line = simulator::ltspice_takes_include_dirs(version.as_deref());
+    if !on_command_line {
+        log::info!("Simulation {}: this LTspice doesn't take -I, copying libraries from the search paths", request.id);
+    }
+    Ok(simulator::LibrarySearchPaths { dirs, on_command_line, strategy, variables })
+}
+
+/// The request's library strategy, or the settings' default
+async fn library_strategy(requested: Option<LibraryStrategy>, state: &AppState) -> LibraryStrategy {
+    match requested {
+        Some(strategy) => strategy,
+        None => state.settings.read().await.library_strategy,
+    }
+}
+
+/// Disk space and temp directory checks, run before a worker slot is claimed
+async fn run_preflight(
+    netlist: &str,
+    quality: &QualityProfile,
+    runs: u64,
+    state: &AppState,
+) -> Result<(), simulator::PreflightError> {
+    let fallback_bytes = state.settings.read().await.min_free_disk_mb.saturating_mul(1024 * 1024);
+    let temp_root = state.temp_root.read().await.path.clone();
+    let result = simulator::preflight_check(netlist, quality, runs, fallback_bytes, &temp_root);
+    if let Err(e) = &result {
+        log::error!("Pre-flight check failed: {}", e);
+    }
+    result
+}
+
+/// Warn when `runs` of the netlist are estimated to exceed the long-run threshold
+/// With `confirm_long_runs` set, hold the request until it is confirmed, declined or times out
+async fn confirm_long_run(
+    request: &SimulationRequest,
+    netlist: &str,
+    runs: u64,
+    state: &AppState,
+    progress_tx: &mpsc::Sender<String>,
+) -> Result<(), String> {
+    let threshold_secs = state.settings.read().await.long_run_warning_secs;
+    if threshold_secs == 0 {
+        return Ok(());
+    }
+    let Some(estimate) = netlist::estimate_runtime_secs(netlist) else {
+        return Ok(());
+    };
+    let estimated_secs = (estimate * runs as f64).ceil() as u64;
+    if estimated_secs <= threshold_secs {
+        return Ok(());
+    }
+
+    let stage = ProgressStage::Warning {
+        estimated_secs,
+        confirmation_required: request.confirm_long_runs,
+    };
+    log::warn!("Simulation {}: {}", request.id, stage.message());
+    if !request.confirm_long_runs {
+        send_progress(progress_tx, &stage_progress(&request.id, stage)).await;
+        return Ok(());
+    }
+
+    // Register before sending the warning so an immediate confirm is not missed
+    let decision = state.await_confirmation(&request.id).await;
+    send_progress(progress_tx, &stage_progress(&request.id, stage)).await;
+    let outcome = tokio::select! {
+        decision = tokio::time::timeout(CONFIRM_TIMEOUT, decision) => match decision {
+            Ok(Ok(true)) => Ok(()),
+            Ok(_) => Err("Simulation was declined after the long-run warning".to_string()),
+            Err(_) => Err(format!(
+                "Simulation was not confirmed within {} seconds of the long-run warning",
+                CONFIRM_TIMEOUT.as_secs()
+            )),
+        },
+        _ = state.wait_for_shutdown() => Err("Agent is shutting down".to_string()),
+    };
+    if outcome.is_err() {
+        state.resolve_confirmation(&request.id, false).await;
+    }
+    outcome
+}
+
+/// Run a batch simulation request
+/// Corners run side by side when worker slots are free, each lane in its own workspace, with
+/// a progress update as each corner starts and finishes
+pub async fn execute_batch(
+    request: &BatchSimulationRequest,
+    state: &AppState,
+    progress_tx: &mpsc::Sender<String>,
+) -> BatchSimulationResponse {
+    let start_time = std::time::Instant::now();
+    let simulator_type = request.simulator.as_str();
+
+    let rejection = |simulator: &str, error: String| BatchSimulationResponse {
+        id: uuid::Uuid::new_v4().to_string(),
+        msg_type: "batch_simulation_result".to_string(),
+        request_id: request.id.clone(),
+        timestamp: now_ms(),
+        success: false,
+        corners: vec![],
+        error: Some(error),
+        error_code: None,
+        execution_time: 0,
+        simulator: simulator.to_string(),
+        retry_after_ms: None,
+        busy_info: None,
+    };
+
+    if request.corners.is_empty() {
+        return rejection(simulator_type, "Batch simulation requires at least one corner".to_string());
+    }
+    if request.corners.len() > MAX_BATCH_SIZE as usize {
+        return rejection(
+            simulator_type,
+            format!(
+                "Batch simulation has {} corners, maximum is {}",
+                request.corners.len(),
+                MAX_BATCH_SIZE
+            ),
+        );
+    }
+
+    let (simulator_path, simulator_name) = match resolve_simulator(simulator_type, state).await {
+        Ok(resolved) => resolved,
+        Err((simulator_name, error)) => return rejection(simulator_name, error),
+    };
+
+    let quality = match quality_profile(&request.waveform_quality, state).await {
+        Ok(quality) => quality,
+        Err((error, error_code)) => {
+            return BatchSimulationResponse {
+                error_code: Some(error_code.to_string()),
+                ..rejection(simulator_name, error)
+            }
+        }
+    };
+
+    // Every corner's output stays in the workspace until the batch ends
+    let runs = request.corners.len() as u64;
+    if let Err(e) = run_preflight(&request.netlist, &quality, runs, state).await {
+        return BatchSimulationResponse {
+            error_code: Some(e.error_code().to_string()),
+            ..rejection(simulator_name, e.to_string())
+        };
+    }
+
+    // Claim a worker slot for the whole batch, plus idle ones to run corners side by side
+    let slot = match state.begin_simulation(&request.id, request.origin.as_deref()).await {
+        Ok(slot) => slot,
+        Err(error) => {
+            let (error_code, busy_info) = refusal_details(state, &error, request.origin.as_deref()).await;
+            return BatchSimulationResponse { error_code, busy_info, ..rejection(simulator_name, error.to_string()) };
+        }
+    };
+    let total = request.corners.len();
+    let extra_slots = state.claim_extra_slots(total - 1);
+
+    // Resolve includes once per lane; every corner in a lane reuses the copied libraries
+    let bundled_libraries = state.substitutable_libraries().await;
+    let search_paths = simulator::LibrarySearchPaths {
+        strategy: library_strategy(None, state).await,
+        variables: state.settings.read().await.path_variables.clone(),
+        ..Default::default()
+    };
+    let temp_root = state.temp_root.read().await.path.clone();
+    let lanes = match LanePool::prepare(1 + extra_slots.len(), &slot.simulation, || {
+        simulator::SimulationWorkspace::prepare(
+            simulator_name,
+            &request.netlist,
+            &[],
+            &bundled_libraries,
+            &search_paths,
+            &temp_root,
+        )
+    }) {
+        Ok(lanes) => lanes,
+        Err(e) => {
+            state.release_extra_slots(extra_slots);
+            state.end_simulation(slot).await;
+            return rejection(simulator_name, e.to_string());
+        }
+    };
+
+    // Report workspace setup once; per-corner/per-run updates follow below
+    for stage in lanes.prepared_stages(simulator_name) {
+        send_progress(progress_tx, &stage_progress(&request.id, stage)).await;
+    }
+
+    log::info!(
+        "Running batch of {} corners, {} at a time, with {} at: {}",
+        total,
+        lanes.len(),
+        simulator_name,
+        simulator_path
+    );
+
+    let keepalive = Keepalive::start(state, &request.id, &slot.simulation, progress_tx).await;
+    let process_options = process_options(state, &request.id, request.timeout, &slot, progress_tx).await;
+    let finished = AtomicU32::new(0);
+    let run_corner = |index: usize, lane: Arc<Lane>| {
+        let corner = &request.corners[index];
+        let (simulator_path, finished, simulation, quality) = (&simulator_path, &finished, &slot.simulation, &quality);
+        let process_options = simulator::ProcessOptions {
+            process_id_holder: Some(lane.process_id.clone()),
+            ..process_options.clone()
+        };
+        async move {
+            let progress = SimulationProgress {
+                message: format!("Running corner {}/{}: {}", index + 1, total, corner.name),
+                corner: Some(corner.name.clone()),
+                completed: Some(finished.load(Ordering::SeqCst)),
+                total: Some(total as u32),
+                ..stage_progress(&request.id, ProgressStage::Running { percent: None })
+            };
+            report_progress(state, simulation, &progress);
+            send_progress(progress_tx, &progress).await;
+
+            let corner_start = std::time::Instant::now();
+            let netlist = simulator::apply_corner_overrides(lane.workspace.netlist(), corner);
+            let file_stem = format!("corner{}", index);
+            let result = match simulator_name {
+                "ngspice" => {
+                    simulator::run_ngspice_in_workspace(
+                        simulator_path,
+                        &lane.workspace,
+                        &netlist,
+                        quality,
+                        &file_stem,
+                        &process_options,
+                        None,
+                    )
+                    .await
+                }
+                _ => {
+                    simulator::run_ltspice_in_workspace(
+                        simulator_path,
+                        &lane.workspace,
+                        &netlist,
+                        quality,
+                        &file_stem,
+                        &process_options,
+                        None,
+                    )
+                    .await
+                }
+            };
+            (result, corner_start.elapsed().as_millis() as u64)
+        }
+    };
+
+    let mut corners: Vec<Option<CornerResult>> = (0..total).map(|_| None).collect();
+    let mut runs = run_in_lanes(&lanes, total, &slot.simulation, run_corner);
+    while let Some((index, run)) = runs.next().await {
+        // A killed process shows up as a failure; run_in_lanes reports it as a cancellation instead
+        let Some((result, execution_time)) = run else {
+            continue;
+        };
+        let corner = &request.corners[index];
+        let outcome = if result.is_ok() { Outcome::Succeeded } else { Outcome::Failed };
+        state.metrics.write().await.record(simulator_name, outcome, execution_time);
+        corners[index] = Some(match result {
+            Ok(results) => CornerResult {
+                name: corner.name.clone(),
+                success: true,
+                results: Some(results),
+                error: None,
+                execution_time,
+            },
+            Err(e) => {
+                log::error!("Corner {} failed with {}: {}", corner.name, simulator_name, e);
+                CornerResult {
+                    name: corner.name.clone(),
+                    success: false,
+                    results: None,
+                    error: Some(e.to_string()),
+                    execution_time,
+                }
+            }
+        });
+
+        let done = finished.fetch_add(1, Ordering::SeqCst) + 1;
+        let progress = SimulationProgress {
+            message: format!("{}/{} corners complete", done, total),
+            corner: Some(corner.name.clone()),
+            completed: Some(done),
+            total: Some(total as u32),
+            ..stage_progress(&request.id, ProgressStage::Running { percent: None })
+        };
+        report_progress(state, &slot.simulation, &progress);
+        send_progress(progress_tx, &progress).await;
+    }
+    drop(runs);
+    drop(lanes);
+    drop(keepalive);
+    state.release_extra_slots(extra_slots);
+
+    let was_cancelled = state.end_simulation(slot).await;
+    if was_cancelled {
+        let elapsed = start_time.elapsed().as_millis() as u64;
+        state.metrics.write().await.record(simulator_name, Outcome::Cancelled, elapsed);
+    }
+
+    // Every corner that did not complete is reported as cancelled
+    let corners: Vec<CornerResult> = corners
+        .into_iter()
+        .zip(&request.corners)
+        .map(|(result, corner)| {
+            result.unwrap_or_else(|| CornerResult {
+                name: corner.name.clone(),
+                success: false,
+                results: None,
+                error: Some("Simulation cancelled".to_string()),
+                execution_time: 0,
+            })
+        })
+        .collect();
+
+    let completed = corners.iter().filter(|c| c.success).count();
+    if completed > 0 {
+        let mut count = state.simulation_count.write().await;
+        *count += completed as u32;
+        let mut last_time = state.last_simulation_time.write().await;
+        *last_time = Some(now_ms());
+    }
+
+    log::info!("Batch completed with {}: {}/{} corners succeeded", simulator_name, completed, total);
+
+    BatchSimulationResponse {
+        id: uuid::Uuid::new_v4().to_string(),
+        msg_type: "batch_simulation_result".to_string(),
+        request_id: request.id.clone(),
+        timestamp: now_ms(),
+        success: completed == total,
+        corners,
+        error: if was_cancelled { Some("Simulation cancelled".to_string()) } else { None },
+        error_code: None,
+        execution_time: start_time.elapsed().as_millis() as u64,
+        simulator: simulator_name.to_string(),
+        retry_after_ms: None,
+        busy_info: None,
+    }
+}
+
+/// Handle a simulation request with Monte Carlo options
+/// Runs the netlist N times, collecting only the requested `.meas` values from each run
+pub async fn execute_monte_carlo(
+    request: &SimulationRequest,
+    state: &AppState,
+    progress_tx: &mpsc::Sender<String>,
+) -> SimulationResponse {
+    let start_time = std::time::Instant::now();
+    let simulator_type = request.simulator.as_str();
+    let options = match &request.monte_carlo {
+        Some(options) => options,
+        None => return execute(request, state, progress_tx).await,
+    };
+
+    let rejection = |simulator: &str, error: String| SimulationResponse {
+        id: uuid::Uuid::new_v4().to_string(),
+        msg_type: "simulation_result".to_string(),
+        request_id: request.id.clone(),
+        timestamp: now_ms(),
+        success: false,
+        f32_results: false,
+        results: None,
+        error: Some(error),
+        error_code: None,
+        execution_time: 0,
+        simulator: simulator.to_string(),
+        monte_carlo: None,
+        duplicate: false,
+        retried_with: None,
+        translations: Vec::new(),
+        debug_info: None,
+        results_compressed: None,
+        compression: None,
+        retry_after_ms: None,
+        warnings: Vec::new(),
+        simulator_version: None,
+        simulator_binary_sha256: None,
+        replay_of: None,
+        fallback_simulator_used: None,
+        engine_errors: Vec::new(),
+        artifact_id: None,
+        raw_file_size: None,
+        trace_catalog: None,
+        operating_point: None,
+        wave_data: None,
+        libraries_used: Vec::new(),
+        busy_info: None,
+        timings: SimulationTimings::default(),
+    };
+
+    if options.runs == 0 || options.runs > MAX_MONTE_CARLO_RUNS {
+        return rejection(
+            simulator_type,
+            format!("Monte Carlo runs must be between 1 and {}", MAX_MONTE_CARLO_RUNS),
+        );
+    }
+    if request.wave_output.is_some() {
+        return rejection(simulator_type, "waveOutput applies to single runs; leave it out of Monte Carlo requests".to_string());
+    }
+    if options.measurements.is_empty() {
+        return rejection(simulator_type, "Monte Carlo requires at least one .meas name".to_string());
+    }
+
+    let (simulator_path, simulator_name) = match resolve_simulator(simulator_type, state).await {
+        Ok(resolved) => resolved,
+        Err((simulator_name, error)) => return rejection(simulator_name, error),
+    };
+
+    // Runs only read .meas output and their files are deleted as they finish
+    let prepared = async {
+        let search_paths = library_search_paths(request, simulator_name, state).await?;
+        let prepared = request_netlist(request, simulator_name, &simulator_path, state).await?;
+        Ok::<_, (String, &'static str)>((search_paths, prepared))
+    };
+    let (search_paths, (netlist, translations, attachments)) = match prepared.await {
+        Ok(prepared) => prepared,
+        Err((error, error_code)) => {
+            return SimulationResponse {
+                error_code: Some(error_code.to_string()),
+                ..rejection(simulator_name, error)
+            };
+        }
+    };
+    if let Err(e) = run_preflight(&netlist, &QualityProfile::measurements(), 1, state).await {
+        return SimulationResponse {
+            error_code: Some(e.error_code().to_string()),
+            ..rejection(simulator_name, e.to_string())
+        };
+    }
+    if let Err(error) = confirm_long_run(request, &netlist, options.runs as u64, state, progress_tx).await {
+        return SimulationResponse {
+            error_code: Some("NOT_CONFIRMED".to_string()),
+            ..rejection(simulator_name, error)
+        };
+    }
+
+    // Claim a worker slot for the whole batch, plus idle ones to run iterations side by side
+    let slot = match state.begin_simulation(&request.id, request.origin.as_deref()).await {
+        Ok(slot) => slot,
+        Err(error) => {
+            let (error_code, busy_info) = refusal_details(state, &error, request.origin.as_deref()).await;
+            return SimulationResponse { error_code, busy_info, ..rejection(simulator_name, error.to_string()) };
+        }
+    };
+    let runs = options.runs as usize;
+    let extra_slots = state.claim_extra_slots(runs - 1);
+
+    // Resolve includes once per lane; every run in a lane reuses the copied libraries
+    let bundled_libraries = state.substitutable_libraries().await;
+    let temp_root = state.temp_root.read().await.path.clone();
+    let lanes = match LanePool::prepare(1 + extra_slots.len(), &slot.simulation, || {
+        simulator::SimulationWorkspace::prepare(
+            simulator_name,
+            &netlist,
+            &attachments,
+            &bundled_libraries,
+            &search_paths,
+            &temp_root,
+        )
+    }) {
+        Ok(lanes) => lanes,
+        Err(e) => {
+            state.release_extra_slots(extra_slots);
+            state.end_simulation(slot).await;
+            return rejection(simulator_name, e.to_string());
+        }
+    };
+
+    // Report workspace setup once; per-corner/per-run updates follow below
+    for stage in lanes.prepared_stages(simulator_name) {
+        send_progress(progress_tx, &stage_progress(&request.id, stage)).await;
+    }
+
+    log::info!(
+        "Running {} Monte Carlo iterations, {} at a time, with {} at: {}",
+        options.runs,
+        lanes.len(),
+        simulator_name,
+        simulator_path
+    );
+
+    let base_seed = options.seed.unwrap_or(1);
+    let keepalive = Keepalive::start(state, &request.id, &slot.simulation, progress_tx).await;
+    let mut process_options = process_options(state, &request.id, request.timeout, &slot, progress_tx).await;
+    let (ltspice_flags, warnings) = engine_flags(request, simulator_name, &simulator_path);
+    process_options.ltspice_flags = ltspice_flags;
+    process_options.library_search_paths = search_paths;
+    process_options.no_auto_options |= request.no_auto_options;
+
+    let run_iteration = |run: usize, lane: Arc<Lane>| {
+        let simulator_path = &simulator_path;
+        let process_options = simulator::ProcessOptions {
+            process_id_holder: Some(lane.process_id.clone()),
+            ..process_options.clone()
+        };
+        async move {
+            let netlist = simulator::apply_monte_carlo_run(lane.workspace.netlist(), run as u32 + 1, base_seed + run as u64);
+            let file_stem = format!("mc{}", run);
+            let result = simulator::run_measurements_in_workspace(
+                simulator_name,
+                simulator_path,
+                &lane.workspace,
+                &netlist,
+                &file_stem,
+                &process_options,
+            )
+            .await;
+
+            // The temp dir would otherwise grow with every run's raw file
+            for ext in ["net", "cir", "raw", "log"] {
+                let _ = std::fs::remove_file(lane.workspace.path().join(format!("{}.{}", file_stem, ext)));
+            }
+            result.map_err(|e| e.to_string())
+        }
+    };
+
+    // Outputs are kept by run number so values line up with their runs whatever order they finish in
+    let mut outputs: Vec<Option<Result<String, String>>> = (0..runs).map(|_| None).collect();
+    let mut completed_runs = 0u32;
+    let mut iterations = run_in_lanes(&lanes, runs, &slot.simulation, run_iteration);
+    while let Some((run, output)) = iterations.next().await {
+        let Some(output) = output else {
+            continue;
+        };
+        if let Err(e) = &output {
+            log::warn!("Monte Carlo run {} failed with {}: {}", run + 1, simulator_name, e);
+        }
+        outputs[run] = Some(output);
+        completed_runs += 1;
+
+        let progress = SimulationProgress {
+            message: format!("Completed run {}/{}", completed_runs, options.runs),
+            completed: Some(completed_runs),
+            total: Some(options.runs),
+            ..stage_progress(&request.id, ProgressStage::Running { percent: None })
+        };
+        report_progress(state, &slot.simulation, &progress);
+        send_progress(progress_tx, &progress).await;
+    }
+    drop(iterations);
+    drop(lanes);
+    drop(keepalive);
+    state.release_extra_slots(extra_slots);
+
+    let mut values: Vec<Vec<Option<f64>>> = vec![Vec::with_capacity(runs); options.measurements.len()];
+    let mut last_error: Option<String> = None;
+    for output in outputs.into_iter().flatten() {
+        match output {
+            Ok(output) => {
+                for (i, name) in options.measurements.iter().enumerate() {
+                    values[i].push(simulator::extract_measurement(&output, name));
+                }
+            }
+            Err(e) => {
+                for measurement_values in values.iter_mut() {
+                    measurement_values.push(None);
+                }
+                last_error = Some(e);
+            }
+        }
+    }
+
+    let was_cancelled = state.end_simulation(slot).await;
+    let execution_time = start_time.elapsed().as_millis() as u64;
+
+    let measurements: Vec<MeasurementStatistics> = options
+        .measurements
+        .iter()
+        .zip(values)
+        .map(|(name, run_values)| simulator::summarize_measurement(name, run_values))
+        .collect();
+    let any_value = measurements.iter().any(|m| m.mean.is_some());
+
+    let outcome = match (was_cancelled, any_value) {
+        (true, _) => Outcome::Cancelled,
+        (false, true) => Outcome::Succeeded,
+        (false, false) => Outcome::Failed,
+    };
+    state.metrics.write().await.record(simulator_name, outcome, execution_time);
+
+    if any_value {
+        let mut count = state.simulation_count.write().await;
+        *count += 1;
+        let mut last_time = state.last_simulation_time.write().await;
+        *last_time = Some(now_ms());
+    }
+
+    log::info!(
+        "Monte Carlo completed with {}: {}/{} runs",
+        simulator_name,
+        completed_runs,
+        options.runs
+    );
+
+    let error = if was_cancelled {
+        Some("Simulation cancelled".to_string())
+    } else if !any_value {
+        // No run produced any of the requested measurements
+        Some(last_error.unwrap_or_else(|| {
+            format!("None of the requested measurements were found: {}", options.measurements.join(", "))
+        }))
+    } else {
+        None
+    };
+
+    SimulationResponse {
+        id: uuid::Uuid::new_v4().to_string(),
+        msg_type: "simulation_result".to_string(),
+        request_id: request.id.clone(),
+        timestamp: now_ms(),
+        success: error.is_none(),
+        f32_results: false,
+        results: None,
+        error,
+        error_code: None,
+        execution_time,
+        simulator: simulator_name.to_string(),
+        monte_carlo: Some(MonteCarloResults {
+            runs: options.runs,
+            completed_runs,
+            measurements,
+        }),
+        duplicate: false,
+        retried_with: None,
+        translations,
+        debug_info: None,
+        results_compressed: None,
+        compression: None,
+        retry_after_ms: None,
+        warnings,
+        simulator_version: None,
+        simulator_binary_sha256: None,
+        replay_of: None,
+        fallback_simulator_used: None,
+        engine_errors: Vec::new(),
+        artifact_id: None,
+        raw_file_size: None,
+        trace_catalog: None,
+        operating_point: None,
+        wave_data: None,
+        libraries_used: Vec::new(),
+        busy_info: None,
+        timings: SimulationTimings::default(),
+    }
+}
+
+/// Workspace and PID holder for one of the simulator processes a batch runs side by side
+struct Lane {
+    workspace: simulator::SimulationWorkspace,
+    process_id: Arc<AtomicU32>,
+}
+
+/// Lanes not running a corner or iteration at the moment
+struct LanePool {
+    idle: std::sync::Mutex<Vec<Arc<Lane>>>,
+    len: usize,
+}
+
+impl LanePool {
+    /// Prepare `width` lanes, each with its own temp dir from `workspace`; the first uses the
+    /// simulation's own PID holder
+    fn prepare(
+        width: usize,
+        simulation: &ActiveSimulation,
+        workspace: impl Fn() -> Result<simulator::SimulationWorkspace, Box<dyn std::error::Error + Send + Sync>>,
+    ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let mut lanes = Vec::with_capacity(width);
+        for index in 0..width {
+            let workspace = workspace()?;
+            let process_id = if index == 0 { simulation.process_id.clone() } else { simulation.add_process_holder() };
+            lanes.push(Arc::new(Lane { workspace, process_id }));
+        }
+        Ok(Self { idle: std::sync::Mutex::new(lanes), len: width })
+    }
+
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Stages completed preparing the lanes, taken from the first
+    fn prepared_stages(&self, simulator_name: &str) -> Vec<ProgressStage> {
+        let idle = self.idle.lock().unwrap_or_else(|e| e.into_inner());
+        idle.first().map(|lane| lane.workspace.prepared_stages(simulator_name)).unwrap_or_default()
+    }
+
+    fn take(&self) -> Arc<Lane> {
+        let mut idle = self.idle.lock().unwrap_or_else(|e| e.into_inner());
+        idle.pop().expect("no more jobs run at once than there are lanes")
+    }
+
+    fn put(&self, lane: Arc<Lane>) {
+        self.idle.lock().unwrap_or_else(|e| e.into_inner()).push(lane);
+    }
+}
+
+/// Run jobs `0..count` with up to one per lane at a time, yielding `(index, result)` as each finishes
+/// Jobs not started before a cancel, or finishing after one, yield None
+fn run_in_lanes<'a, T, F, Fut>(
+    lanes: &'a LanePool,
+    count: usize,
+    simulation: &'a ActiveSimulation,
+    job: F,
+) -> impl futures_util::Stream<Item = (usize, Option<T>)> + 'a
+where
+    F: Fn(usize, Arc<Lane>) -> Fut + 'a,
+    Fut: std::future::Future<Output = T> + 'a,
+    T: 'a,
+{
+    futures_util::stream::iter(0..count)
+        .map(move |index| {
+            let lane = lanes.take();
+            let run = (!simulation.is_cancelled()).then(|| job(index, lane.clone()));
+            async move {
+                let result = match run {
+                    Some(run) => Some(run.await),
+                    None => None,
+                };
+                lanes.put(lane);
+                (index, result.filter(|_| !simulation.is_cancelled()))
+            }
+        })
+        .buffer_unordered(lanes.len())
+}
+
+/// What a cancel request came to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CancelOutcome {
+    /// The simulation was stopped, or declined while it waited for confirmation
+    Cancelled,
+    /// The simulator finished before the kill landed; its results are sent as usual
+    CompletedBeforeCancel,
+    /// No simulation with that id is running
+    NotRunning,
+}
+
+impl CancelOutcome {
+    pub fn success(self) -> bool {
+        self == CancelOutcome::Cancelled
+    }
+
+    /// Why a cancel did not succeed, when there is more to say than that nothing was running
+    pub fn reason(self) -> Option<&'static str> {
+        (self == CancelOutcome::CompletedBeforeCancel).then_some("completed before cancel")
+    }
+}
+
+/// Cancel an in-flight simulation by request id, killing its simulator process and waiting to
+/// learn whether that stopped it
+pub async fn cancel(request_id: &str, state: &AppState) -> CancelOutcome {
+    // A simulation held by a long-run warning has not started yet; declining it is enough
+    if state.resolve_confirmation(request_id, false).await {
+        log::info!("Declined simulation waiting for confirmation: {}", request_id);
+        return CancelOutcome::Cancelled;
+    }
+
+    let Some(simulation) = stop(request_id, state).await else {
+        log::warn!("Cancel request for {} but no such simulation is running", request_id);
+        return CancelOutcome::NotRunning;
+    };
+
+    // Only the run knows whether its simulator died of the kill or exited first
+    match simulation.wait_settled(CANCEL_SETTLE_TIMEOUT).await {
+        RunState::Finished => {
+            log::info!("Simulation {} completed before it could be cancelled", request_id);
+            CancelOutcome::CompletedBeforeCancel
+        }
+        _ => CancelOutcome::Cancelled,
+    }
+}
+
+/// Ask an in-flight simulation to stop and kill its simulator processes, without waiting for it
+/// Returns None if no simulation with that id is running
+pub async fn stop(request_id: &str, state: &AppState) -> Option<Arc<ActiveSimulation>> {
+    // Cancel targets the specific in-flight simulation, not whichever started last
+    let simulation = state.active_simulation(request_id).await?;
+    if simulation.request_cancel() != RunState::Running {
+        return Some(simulation);
+    }
+    log::info!("Cancel requested for simulation: {}", request_id);
+
+    // Try to kill the simulator processes; a parallel batch has one per running corner
+    for pid in simulation.process_ids() {
+        log::info!("Attempting to kill simulator process with PID: {}", pid);
+        if !simulator::kill_process_tree(pid) {
+            log::info!("Simulator process {} had already exited", pid);
+        }
+    }
+    Some(simulation)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use crate::settings::AgentSettings;
+    use crate::state::max_worker_count;
+
+    /// Write a fake ngspice that sleeps, then writes a one-point raw file to the path
+    /// given in the injected `.control` block
+    #[cfg(unix)]
+    fn fake_slow_ngspice(dir: &std::path::Path) -> String {
+        use std::os::unix::fs::PermissionsExt;
+
+        let script = r#"#!/bin/sh
+raw=$(sed -n 's/^write \([^ ]*\) .*$/\1/p' "$2" | tr -d "'")
+sleep 1
+echo "fake ngspice: simulation done"
+cat > "$raw" <<RAW
+Title: fake
+Plotname: Transient Analysis
+Flags: real
+No. Variables: 2
+No. Points: 1
+Variables:
+	0	time	time
+	1	v(out)	voltage
+Values:
+ 0	0.000000000000000e+00
+	1.000000000000000e+00
+RAW
+"#;
+        let path = dir.join("ngspice");
+        std::fs::write(&path, script).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        path.to_string_lossy().to_string()
+    }
+
+    /// Fake ngspice that writes its raw file and exits straight away, touching `exited` next to
+    /// itself, while a leftover child keeps its output open for another two seconds so the
+    /// agent is still waiting on the run when a cancel comes in
+    #[cfg(unix)]
+    fn fake_fast_exiting_ngspice(dir: &std::path::Path) -> String {
+        use std::os::unix::fs::PermissionsExt;
+
+        let script = format!(
+            r#"#!/bin/sh
+raw=$(sed -n 's/^write \([^ ]*\) .*$/\1/p' "$2" | tr -d "'")
+printf 'Title: fake\nPlotname: Transient Analysis\nFlags: real\nNo. Variables: 2\nNo. Points: 1\nVariables:\n\t0\ttime\ttime\n\t1\tv(out)\tvoltage\nValues:\n 0\t0.0\n\t1.0\n' > "$raw"
+sleep 2 &
+touch "{}"
+"#,
+            dir.join("exited").display()
+        );
+        let path = dir.join("ngspice");
+        std::fs::write(&path, script).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        path.to_string_lossy().to_string()
+    }
+
+    /// Fake ngspice that fails with a too small time step unless `.tran` has `uic`
+    #[cfg(unix)]
+    fn fake_nonconverging_ngspice(dir: &std::path::Path) -> String {
+        use std::os::unix::fs::PermissionsExt;
+
+        let script = r#"#!/bin/sh
+if ! grep -q '^\.tran.* uic$' "$2"; then
+    echo "doAnalyses: TRAN:  Timestep too small; time = 1e-9"
+    echo "Error: run simulation(s) aborted"
+    exit 1
+fi
+raw=$(sed -n 's/^write \([^ ]*\) .*$/\1/p' "$2" | tr -d "'")
+cat > "$raw" <<RAW
+Title: fake
+Plotname: Transient Analysis
+Flags: real
+No. Variables: 2
+No. Points: 1
+Variables:
+	0	time	time
+	1	v(out)	voltage
+Values:
+ 0	0.000000000000000e+00
+	1.000000000000000e+00
+RAW
+"#;
+        let path = dir.join("ngspice");
+        std::fs::write(&path, script).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        path.to_string_lossy().to_string()
+    }
+
+    #[cfg(unix)]
+    async fn state_with_fake_ngspice(dir: &std::path::Path, workers: usize) -> Arc<AppState> {
+        let state = Arc::new(AppState::new(AgentSettings {
+            max_concurrent_simulations: workers,
+            ..AgentSettings::default()
+        }));
+        *state.ngspice_path.write().await = Some(fake_slow_ngspice(dir));
+        state
+    }
+
+    fn simulation_request(id: &str) -> SimulationRequest {
+        SimulationRequest {
+            id: id.to_string(),
+            msg_type: "simulate".to_string(),
+            netlist: "* Test\nV1 out 0 1\n.tran 1m\n.end".to_string(),
+            input_format: InputFormat::Netlist,
+            netlist_encoding: NetlistEncoding::Plain,
+            dialect: None,
+            waveform_quality: "smooth".to_string(),
+            simulator: "ngspice".to_string(),
+            timeout: None,
+            monte_carlo: None,
+            stream_logs: false,
+            default_analysis: None,
+            confirm_long_runs: false,
+            convergence_assist: false,
+            include_debug_info: false,
+            compress: false,
+            engine_options: EngineOptions::default(),
+            attachments: Vec::new(),
+            current_probes: None,
+            auto_retry_other_engine: false,
+            library_search_paths: Vec::new(),
+            library_strategy: None,
+            keep_raw: false,
+            precision: None,
+            f32_results: false,
+            defer_trace_data: false,
+            save_traces: Vec::new(),
+            peek: false,
+            peek_fast: false,
+            no_auto_options: false,
+            wave_output: None,
+            trace_aliases: HashMap::new(),
+            timestamp: now_ms(),
+            replay_of: None,
+            origin: None,
+        }
+    }
+
+    /// Progress channel whose updates are discarded
+    fn progress_sink() -> mpsc::Sender<String> {
+        mpsc::channel(1).0
+    }
+
+    #[cfg(unix)]
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_single_worker_rejects_concurrent_simulation() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = state_with_fake_ngspice(dir.path(), 1).await;
+
+        let first = tokio::spawn({
+            let state = state.clone();
+            async move { execute(&simulation_request("a"), &state, &progress_sink()).await }
+        });
+        while state.active_simulation("a").await.is_none() {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+
+        let second = execute(&simulation_request("b"), &state, &progress_sink()).await;
+        assert!(!second.success);
+        assert_eq!(second.error.as_deref(), Some("Another simulation is already running"));
+        assert_eq!(second.error_code.as_deref(), Some(AGENT_BUSY));
+        let busy = second.busy_info.unwrap();
+        assert_eq!((busy.running_request_id.as_str(), busy.request_id_redacted), ("a", false));
+        assert_eq!(busy.running_simulations, 1);
+
+        // A page on another origin isn't told the id
+        let request = SimulationRequest { origin: Some("https://kelicad.com".to_string()), ..simulation_request("c") };
+        let busy = execute(&request, &state, &progress_sink()).await.busy_info.unwrap();
+        assert!(busy.request_id_redacted);
+        assert_ne!(busy.running_request_id, "a");
+
+        let first = first.await.unwrap();
+        assert!(first.success, "{:?}", first.error);
+        assert!(state.active_simulation_statuses().await.is_empty());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_worker_pool_runs_simulations_concurrently() {
+        if max_worker_count() < 2 {
+            return;
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let state = state_with_fake_ngspice(dir.path(), 2).await;
+
+        let (request_a, request_b) = (simulation_request("a"), simulation_request("b"));
+        let progress_tx = progress_sink();
+        let start = std::time::Instant::now();
+        let (a, b) = tokio::join!(
+            execute(&request_a, &state, &progress_tx),
+            execute(&request_b, &state, &progress_tx),
+        );
+        assert!(a.success, "{:?}", a.error);
+        assert!(b.success, "{:?}", b.error);
+        // Each fake run sleeps 1s; sequential execution would take at least 2s
+        assert!(start.elapsed() < std::time::Duration::from_millis(1900));
+        assert_eq!(*state.simulation_count.read().await, 2);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_cancel_targets_specific_simulation() {
+        if max_worker_count() < 2 {
+            return;
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let state = state_with_fake_ngspice(dir.path(), 2).await;
+
+        let a = tokio::spawn({
+            let state = state.clone();
+            async move { execute(&simulation_request("a"), &state, &progress_sink()).await }
+        });
+        let b = tokio::spawn({
+            let state = state.clone();
+            async move { execute(&simulation_request("b"), &state, &progress_sink()).await }
+        });
+
+        // Wait until b's simulator process has been spawned
+        loop {
+            if let Some(sim) = state.active_simulation("b").await {
+                if sim.process_id.load(Ordering::SeqCst) != 0 {
+                    break;
+                }
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+
+        assert_eq!(cancel("b", &state).await, CancelOutcome::Cancelled);
+
+        let a = a.await.unwrap();
+        let b = b.await.unwrap();
+        assert!(a.success, "{:?}", a.error);
+        assert!(!b.success);
+        assert_eq!(b.error.as_deref(), Some("Simulation cancelled"));
+
+        let metrics = state.metrics.read().await.snapshot();
+        assert_eq!(metrics.simulators[0].succeeded, 1);
+        assert_eq!(metrics.simulators[0].failed, 0);
+        assert_eq!(metrics.cancelled, 1);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_cancel_after_the_simulator_exited_keeps_the_results() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = state_with_fake_ngspice(dir.path(), 1).await;
+        *state.ngspice_path.write().await = Some(fake_fast_exiting_ngspice(dir.path()));
+
+        let run = tokio::spawn({
+            let state = state.clone();
+            async move { execute(&simulation_request("a"), &state, &progress_sink()).await }
+        });
+        while !dir.path().join("exited").exists() {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        // Let the script's shell exit after touching the marker
+        tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+
+        let outcome = cancel("a", &state).await;
+        assert_eq!(outcome, CancelOutcome::CompletedBeforeCancel);
+        assert!(!outcome.success());
+        assert_eq!(outcome.reason(), Some("completed before cancel"));
+
+        let response = run.await.unwrap();
+        assert!(response.success, "{:?}", response.error);
+        assert_eq!(response.results.unwrap().traces[0].data, [1.0]);
+        let metrics = state.metrics.read().await.snapshot();
+        assert_eq!(metrics.cancelled, 0);
+    }
+
+    #[tokio::test]
+    async fn test_cancel_unknown_simulation_fails() {
+        let state = AppState::default();
+        assert_eq!(cancel("x", &state).await, CancelOutcome::NotRunning);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_insufficient_disk_space_fails_fast() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = state_with_fake_ngspice(dir.path(), 1).await;
+        state.settings.write().await.min_free_disk_mb = u64::MAX;
+
+        let response = execute(&simulation_request("a"), &state, &progress_sink()).await;
+        if response.success {
+            // Free space can't be determined in this environment
+            return;
+        }
+        assert_eq!(response.error_code.as_deref(), Some("INSUFFICIENT_DISK_SPACE"));
+        assert!(response.error.unwrap().contains("MB required"));
+        assert_eq!(*state.simulation_count.read().await, 0);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_missing_analysis_directive() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = state_with_fake_ngspice(dir.path(), 1).await;
+        let request = SimulationRequest {
+            netlist: "* Test\nV1 out 0 1\n* .tran 1m\n.end".to_string(),
+            ..simulation_request("a")
+        };
+
+        let response = execute(&request, &state, &progress_sink()).await;
+        assert!(!response.success);
+        assert_eq!(response.error_code.as_deref(), Some("NO_ANALYSIS_DIRECTIVE"));
+        assert!(state.active_simulation("a").await.is_none());
+
+        let request = SimulationRequest {
+            default_analysis: Some(".op".to_string()),
+            ..request
+        };
+        let response = execute(&request, &state, &progress_sink()).await;
+        assert!(response.success, "{:?}", response.error);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_current_probe_errors_name_each_component() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = state_with_fake_ngspice(dir.path(), 1).await;
+        let request = SimulationRequest {
+            netlist: "* Test\nV1 in 0 1\nR1 in 0 1k\n.op\n.end".to_string(),
+            current_probes: Some(vec!["R2".to_string(), "R3".to_string()]),
+            ..simulation_request("a")
+        };
+
+        let response = execute(&request, &state, &progress_sink()).await;
+        assert!(!response.success);
+        assert_eq!(response.error_code.as_deref(), Some("INVALID_CURRENT_PROBE"));
+        assert_eq!(
+            response.error.as_deref(),
+            Some("Could not add current probes: R2: no such component in the netlist; R3: no such component in the netlist")
+        );
+
+        let request = SimulationRequest { current_probes: Some(vec!["R1".to_string()]), ..request };
+        let response = execute(&request, &state, &progress_sink()).await;
+        assert!(response.success, "{:?}", response.error);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_waveform_quality_names_a_profile() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = state_with_fake_ngspice(dir.path(), 1).await;
+        let request = SimulationRequest { waveform_quality: "ultra".to_string(), ..simulation_request("a") };
+
+        let response = execute(&request, &state, &progress_sink()).await;
+        assert!(!response.success);
+        assert_eq!(response.error_code.as_deref(), Some("UNKNOWN_QUALITY_PROFILE"));
+        assert_eq!(
+            response.error.as_deref(),
+            Some("Unknown waveform quality \"ultra\"; available profiles: balanced, fast, smooth")
+        );
+
+        state.settings.write().await.quality_profiles.insert("ultra".to_string(), QualityProfile::default());
+        let response = execute(&request, &state, &progress_sink()).await;
+        assert!(response.success, "{:?}", response.error);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_replay_runs_the_stored_netlist() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = state_with_fake_ngspice(dir.path(), 1).await;
+        let request = SimulationRequest {
+            netlist: "* Test\nV1 in 0 1\nR1 in 0 1k\n.end".to_string(),
+            default_analysis: Some(".op".to_string()),
+            current_probes: Some(vec!["R1".to_string()]),
+            ..simulation_request("a")
+        };
+
+        // Nothing is kept unless the settings allow it
+        assert!(execute(&request, &state, &progress_sink()).await.success);
+        assert!(state.history.read().await.replay_request("a").is_err());
+
+        state.settings.write().await.store_netlists = true;
+        assert!(execute(&request, &state, &progress_sink()).await.success);
+        let replay = state.history.read().await.replay_request("a").unwrap();
+        assert!(replay.netlist.contains("Vprobe_R1 in probe_R1 0"));
+        assert!(replay.netlist.contains(".op"));
+
+        // The prepared netlist runs as is, without a second probe or analysis added
+        let (netlist, _, _) = request_netlist(&replay, "ngspice", "ngspice", &state).await.unwrap();
+        assert_eq!(netlist, replay.netlist);
+        let response = execute(&replay, &state, &progress_sink()).await;
+        assert!(response.success, "{:?}", response.error);
+        assert_eq!(state.history.read().await.entries()[0].replay_of.as_deref(), Some("a"));
+    }
+
+    #[tokio::test]
+    async fn test_missing_simulator_is_redetected() {
+        let state = AppState::default();
+        let stale = "/nonexistent/kelicad/ngspice".to_string();
+        *state.ngspice_path.write().await = Some(stale.clone());
+
+        let resolved = resolve_simulator("ngspice", &state).await;
+        let cached = state.ngspice_path.read().await.clone();
+        assert_ne!(cached.as_deref(), Some(stale.as_str()));
+        match resolved {
+            Ok((path, name)) => {
+                assert_eq!(name, "ngspice");
+                assert_eq!(Some(path), cached);
+            }
+            Err((name, _)) => {
+                assert_eq!(name, "ngspice");
+                assert!(cached.is_none());
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_forward_logs_batches_and_rate_limits() {
+        let (log_tx, log_rx) = mpsc::unbounded_channel();
+        let (progress_tx, mut progress_rx) = mpsc::channel(16);
+        for i in 0..500 {
+            log_tx
+                .send(LogLine {
+                    stream: "stdout".to_string(),
+                    text: format!("line {}", i),
+                })
+                .unwrap();
+        }
+        drop(log_tx);
+
+        forward_logs("sim-1".to_string(), log_rx, progress_tx).await;
+
+        let mut messages = Vec::new();
+        while let Ok(json) = progress_rx.try_recv() {
+            messages.push(serde_json::from_str::<serde_json::Value>(&json).unwrap());
+        }
+        // Everything arrived within one flush interval: one capped message
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0]["type"], "simulation_log");
+        assert_eq!(messages[0]["requestId"], "sim-1");
+        assert_eq!(messages[0]["lines"].as_array().unwrap().len(), MAX_LOG_LINES_PER_MESSAGE);
+        assert_eq!(messages[0]["lines"][0]["text"], "line 0");
+        assert_eq!(messages[0]["droppedLines"], 500 - MAX_LOG_LINES_PER_MESSAGE as u64);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_stream_logs_sends_log_messages_before_result() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = state_with_fake_ngspice(dir.path(), 1).await;
+        let (progress_tx, mut progress_rx) = mpsc::channel(64);
+        let request = SimulationRequest {
+            stream_logs: true,
+            ..simulation_request("a")
+        };
+
+        let response = execute(&request, &state, &progress_tx).await;
+        assert!(response.success, "{:?}", response.error);
+
+        let mut log_lines = Vec::new();
+        while let Ok(json) = progress_rx.try_recv() {
+            let message: serde_json::Value = serde_json::from_str(&json).unwrap();
+            if message["type"] == "simulation_log" {
+                for line in message["lines"].as_array().unwrap() {
+                    log_lines.push(line["text"].as_str().unwrap().to_string());
+                }
+            }
+        }
+        assert!(log_lines.iter().any(|l| l.contains("fake ngspice")), "{:?}", log_lines);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_progress_stages_arrive_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = state_with_fake_ngspice(dir.path(), 1).await;
+        let (progress_tx, mut progress_rx) = mpsc::channel(64);
+
+        let response = execute(&simulation_request("a"), &state, &progress_tx).await;
+        assert!(response.success, "{:?}", response.error);
+
+        let mut stages = Vec::new();
+        while let Ok(json) = progress_rx.try_recv() {
+            let message: serde_json::Value = serde_json::from_str(&json).unwrap();
+            // Resource samples are periodic rather than stage transitions
+            if message["type"] == "simulation_progress" && message.get("resources").is_none() {
+                stages.push(message);
+            }
+        }
+        let names: Vec<&str> = stages.iter().map(|m| m["stage"].as_str().unwrap()).collect();
+        assert_eq!(names, ["preparing", "launching", "running", "parsing_results", "complete"]);
+        assert!(stages[0]["tempDir"].as_str().unwrap().contains("kelicad-ngspice-"));
+        assert!(stages[1]["pid"].as_u64().unwrap() > 0);
+        assert!(stages[3]["rawFileBytes"].as_u64().unwrap() > 0);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_slow_reader_does_not_stall_simulation() {
+        use crate::dedup::Seen;
+
+        let dir = tempfile::tempdir().unwrap();
+        let state = state_with_fake_ngspice(dir.path(), 1).await;
+        let request = simulation_request("a");
+
+        // The client has room for two messages and reads nothing until the run is over
+        let (tx, mut rx) = mpsc::channel(2);
+        let Seen::New(listeners) = state.recent_requests.write().await.begin(&request.id, &tx) else {
+            panic!("expected a new run");
+        };
+        let run = tokio::spawn({
+            let state = state.clone();
+            let request = request.clone();
+            async move { execute_shared(&request, ALLOWED_ORIGINS[0], &state, listeners).await }
+        });
+        while *state.simulation_count.read().await == 0 {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        assert!(state.active_simulation("a").await.is_none());
+
+        // Progress that did not fit was dropped; the result waits for room
+        let mut messages = Vec::new();
+        while let Some(json) = rx.recv().await {
+            let message = serde_json::from_str::<serde_json::Value>(&json).unwrap();
+            let done = message["type"] == "simulation_result";
+            messages.push(message);
+            if done {
+                break;
+            }
+        }
+        assert!(run.await.unwrap().success);
+        assert_eq!(messages.len(), 3);
+        assert_eq!(messages[0]["stage"], "preparing");
+        assert!(state.metrics.read().await.snapshot().progress_messages_dropped >= 3);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_retry_after_reconnect_joins_running_simulation() {
+        use crate::dedup::Seen;
+
+        let dir = tempfile::tempdir().unwrap();
+        let state = state_with_fake_ngspice(dir.path(), 1).await;
+        let request = simulation_request("a");
+
+        let (first_tx, first_rx) = mpsc::channel(64);
+        let Seen::New(listeners) = state.recent_requests.write().await.begin(&request.id, &first_tx) else {
+            panic!("expected a new run");
+        };
+        let run = tokio::spawn({
+            let state = state.clone();
+            let request = request.clone();
+            async move { execute_shared(&request, ALLOWED_ORIGINS[0], &state, listeners).await }
+        });
+        while state.active_simulation("a").await.is_none() {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+
+        // The connection drops mid-simulation and the client re-sends the request on a new one
+        drop(first_rx);
+        drop(first_tx);
+        let (second_tx, mut second_rx) = mpsc::channel(64);
+        assert!(matches!(state.recent_requests.write().await.begin(&request.id, &second_tx), Seen::Attached));
+
+        assert!(run.await.unwrap().success);
+        let mut last = None;
+        while let Ok(json) = second_rx.try_recv() {
+            last = Some(serde_json::from_str::<serde_json::Value>(&json).unwrap());
+        }
+        let result = last.unwrap();
+        assert_eq!(result["type"], "simulation_result");
+        assert_eq!(result["requestId"], "a");
+        assert!(result.get("duplicate").is_none());
+        assert_eq!(*state.simulation_count.read().await, 1);
+
+        // A retry after completion gets the stored response without running again
+        let Seen::Finished(stored) = state.recent_requests.write().await.begin(&request.id, &second_tx) else {
+            panic!("expected the stored response");
+        };
+        assert!(stored.success);
+        assert!(state.active_simulation("a").await.is_none());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_compressed_results_match_uncompressed() {
+        use base64::prelude::{Engine, BASE64_STANDARD};
+        use crate::dedup::Seen;
+        use std::io::Read;
+
+        let dir = tempfile::tempdir().unwrap();
+        let state = state_with_fake_ngspice(dir.path(), 1).await;
+
+        let mut delivered = Vec::new();
+        for (id, compress) in [("plain", false), ("gzip", true)] {
+            let request = SimulationRequest { compress, ..simulation_request(id) };
+            let (tx, mut rx) = mpsc::channel(64);
+            let Seen::New(listeners) = state.recent_requests.write().await.begin(&request.id, &tx) else {
+                panic!("expected a new run");
+            };
+            assert!(execute_shared(&request, ALLOWED_ORIGINS[0], &state, listeners).await.success);
+            let mut last = None;
+            while let Ok(json) = rx.try_recv() {
+                last = Some(serde_json::from_str::<serde_json::Value>(&json).unwrap());
+            }
+            delivered.push(last.unwrap());
+        }
+
+        let [plain, compressed] = &delivered[..] else { unreachable!() };
+        assert!(plain.get("resultsCompressed").is_none());
+        assert!(compressed.get("results").is_none());
+        assert_eq!(compressed["compression"], "gzip");
+
+        let gzip = BASE64_STANDARD.decode(compressed["resultsCompressed"].as_str().unwrap()).unwrap();
+        let mut json = String::new();
+        flate2::read::GzDecoder::new(gzip.as_slice()).read_to_string(&mut json).unwrap();
+        assert_eq!(serde_json::from_str::<serde_json::Value>(&json).unwrap(), plain["results"]);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_panic_in_simulation_is_recovered() {
+        use crate::dedup::Seen;
+
+        let state = Arc::new(AppState::new(AgentSettings { max_concurrent_simulations: 1, ..AgentSettings::default() }));
+        let request = simulation_request("a");
+        let (tx, mut rx) = mpsc::channel(64);
+        let Seen::New(listeners) = state.recent_requests.write().await.begin(&request.id, &tx) else {
+            panic!("expected a new run");
+        };
+        // Stands in for a bug hit mid-simulation, while the run holds its worker slot
+        let response = run_shared(&request, ALLOWED_ORIGINS[0], &state, listeners, |_progress_tx| async {
+            let _slot = state.begin_simulation(&request.id, None).await.unwrap();
+            panic!("deliberate panic mid-simulation");
+        })
+        .await;
+        assert!(!response.success);
+        assert_eq!(response.error_code.as_deref(), Some("INTERNAL_ERROR"));
+        assert!(response.error.unwrap().contains("deliberate panic"));
+
+        // The client got the failure, and nothing is left looking busy
+        let mut delivered = None;
+        while let Ok(json) = rx.try_recv() {
+            delivered = Some(serde_json::from_str::<serde_json::Value>(&json).unwrap());
+        }
+        assert_eq!(delivered.unwrap()["errorCode"], "INTERNAL_ERROR");
+        assert!(state.active_simulation("a").await.is_none());
+        assert!(!state.is_busy());
+        assert!(matches!(state.recent_requests.write().await.begin("a", &tx), Seen::Finished(_)));
+        assert_eq!(state.metrics.read().await.snapshot().simulators[0].failed, 1);
+
+        // The agent still runs simulations
+        let working = tempfile::tempdir().unwrap();
+        *state.ngspice_path.write().await = Some(fake_slow_ngspice(working.path()));
+        let response = execute(&simulation_request("b"), &state, &progress_sink()).await;
+        assert!(response.success, "{:?}", response.error);
+    }
+
+    #[test]
+    fn test_effective_timeout_secs() {
+        assert_eq!(effective_timeout_secs(None, 300), 300);
+        assert_eq!(effective_timeout_secs(Some(0), 300), 300);
+        assert_eq!(effective_timeout_secs(Some(60_000), 300), 60);
+        assert_eq!(effective_timeout_secs(Some(1_500), 300), 2);
+        assert_eq!(effective_timeout_secs(Some(900_000), 300), 300);
+        assert_eq!(effective_timeout_secs(None, 0), 1);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_timeout_above_limit_runs_with_warning() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = state_with_fake_ngspice(dir.path(), 1).await;
+        state.settings.write().await.max_simulation_seconds = 120;
+        let (progress_tx, mut progress_rx) = mpsc::channel(64);
+        let request = SimulationRequest {
+            timeout: Some(600_000),
+            ..simulation_request("a")
+        };
+
+        let response = execute(&request, &state, &progress_tx).await;
+        assert!(response.success, "{:?}", response.error);
+
+        let first: serde_json::Value = serde_json::from_str(&progress_rx.try_recv().unwrap()).unwrap();
+        assert_eq!(first["stage"], "timeout_limited");
+        assert_eq!(first["requestedSecs"], 600);
+        assert_eq!(first["effectiveSecs"], 120);
+        assert!(first["message"].as_str().unwrap().contains("120s"));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_long_run_waits_for_confirmation() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = state_with_fake_ngspice(dir.path(), 1).await;
+        // A 1 ns max step over one second is estimated at well over the 300 s default
+        let request = SimulationRequest {
+            netlist: "* Test\nV1 out 0 1\n.tran 0 1 0 1n\n.end".to_string(),
+            confirm_long_runs: true,
+            ..simulation_request("a")
+        };
+
+        let (progress_tx, mut progress_rx) = mpsc::channel(64);
+        let task = {
+            let (state, request) = (state.clone(), request.clone());
+            tokio::spawn(async move { execute(&request, &state, &progress_tx).await })
+        };
+        let warning: serde_json::Value = serde_json::from_str(&progress_rx.recv().await.unwrap()).unwrap();
+        assert_eq!(warning["stage"], "warning");
+        assert_eq!(warning["confirmationRequired"], true);
+        assert!(warning["estimatedSecs"].as_u64().unwrap() > 300);
+        assert!(state.active_simulation("a").await.is_none());
+
+        assert!(state.resolve_confirmation("a", true).await);
+        let response = task.await.unwrap();
+        assert!(response.success, "{:?}", response.error);
+
+        // Cancelling a held simulation declines it without running anything
+        let (progress_tx, mut progress_rx) = mpsc::channel(64);
+        let request = SimulationRequest { id: "b".to_string(), ..request };
+        let task = {
+            let state = state.clone();
+            tokio::spawn(async move { execute(&request, &state, &progress_tx).await })
+        };
+        progress_rx.recv().await.unwrap();
+        assert_eq!(cancel("b", &state).await, CancelOutcome::Cancelled);
+        let response = task.await.unwrap();
+        assert!(!response.success);
+        assert_eq!(response.error_code.as_deref(), Some("NOT_CONFIRMED"));
+        assert_eq!(*state.simulation_count.read().await, 1);
+        assert!(!state.resolve_confirmation("b", true).await);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_long_run_warning_without_confirmation() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = state_with_fake_ngspice(dir.path(), 1).await;
+        let (progress_tx, mut progress_rx) = mpsc::channel(64);
+        let request = SimulationRequest {
+            netlist: "* Test\nV1 out 0 1\n.tran 0 1 0 1n\n.end".to_string(),
+            ..simulation_request("a")
+        };
+
+        let response = execute(&request, &state, &progress_tx).await;
+        assert!(response.success, "{:?}", response.error);
+        let warning: serde_json::Value = serde_json::from_str(&progress_rx.try_recv().unwrap()).unwrap();
+        assert_eq!(warning["stage"], "warning");
+        assert_eq!(warning["confirmationRequired"], false);
+
+        // A zero threshold turns the warning off
+        state.settings.write().await.long_run_warning_secs = 0;
+        let (progress_tx, mut progress_rx) = mpsc::channel(64);
+        execute(&request, &state, &progress_tx).await;
+        let first: serde_json::Value = serde_json::from_str(&progress_rx.try_recv().unwrap()).unwrap();
+        assert_ne!(first["stage"], "warning");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_convergence_assist_retries_with_fallbacks() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = state_with_fake_ngspice(dir.path(), 1).await;
+        *state.ngspice_path.write().await = Some(fake_nonconverging_ngspice(dir.path()));
+
+        let response = execute(&simulation_request("a"), &state, &progress_sink()).await;
+        assert!(!response.success);
+        assert_eq!(response.error_code.as_deref(), Some("CONVERGENCE_FAILURE"));
+        assert!(response.retried_with.is_none());
+
+        // The stepping options don't help this circuit; uic does
+        let (progress_tx, mut progress_rx) = mpsc::channel(64);
+        let request = SimulationRequest {
+            convergence_assist: true,
+            ..simulation_request("b")
+        };
+        let response = execute(&request, &state, &progress_tx).await;
+        assert!(response.success, "{:?}", response.error);
+        assert_eq!(response.retried_with.as_deref(), Some("uic"));
+
+        let mut retries = Vec::new();
+        while let Ok(message) = progress_rx.try_recv() {
+            let progress: serde_json::Value = serde_json::from_str(&message).unwrap();
+            if progress["stage"] == "retrying" {
+                retries.push(progress["fallback"].as_str().unwrap().to_string());
+            }
+        }
+        assert_eq!(retries, ["stepping_options", "uic"]);
+    }
+
+    /// Fake ngspice that silently writes only the operating point plot for a transient
+    #[cfg(unix)]
+    fn fake_operating_point_ngspice(dir: &std::path::Path) -> String {
+        use std::os::unix::fs::PermissionsExt;
+
+        let script = r#"#!/bin/sh
+raw=$(sed -n 's/^write \([^ ]*\) .*$/\1/p' "$2" | tr -d "'")
+cat > "$raw" <<RAW
+Title: fake
+Plotname: Operating Point
+Flags: real
+No. Variables: 2
+No. Points: 1
+Variables:
+	0	v(in)	voltage
+	1	v(out)	voltage
+Values:
+ 0	1.000000000000000e+00
+	5.000000000000000e-01
+RAW
+"#;
+        let path = dir.join("ngspice");
+        std::fs::write(&path, script).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        path.to_string_lossy().to_string()
+    }
+
+    #[test]
+    fn test_save_signals_keep_current_probes() {
+        let probed = SimulationRequest { current_probes: Some(vec!["R1".to_string()]), ..simulation_request("a") };
+        // Everything is saved, the probe's current among it
+        assert!(save_signals(&probed, "* Test\nR1 in out 1k\n.tran 1m\n.end").is_empty());
+
+        let request = SimulationRequest { save_traces: vec!["V(out)".to_string()], ..probed.clone() };
+        assert_eq!(save_signals(&request, "* Test\n.tran 1m\n.end"), ["V(out)", "I(Vprobe_R1)"]);
+        assert_eq!(save_signals(&probed, "* Test\n.probe V(in)\n.tran 1m\n.end"), ["I(Vprobe_R1)"]);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_results_carry_plot_hints() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = state_with_fake_ngspice(dir.path(), 1).await;
+
+        let response = execute(&simulation_request("a"), &state, &progress_sink()).await;
+        assert!(response.success, "{:?}", response.error);
+        let hints = response.results.unwrap().plot_hints.unwrap();
+        assert_eq!(hints.x_axis_scale, AxisScale::Linear);
+        assert_eq!(hints.trace_groups["v(out)"], TraceGroup::Voltage);
+        assert_eq!(hints.default_selection, ["v(out)"]);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_missing_end_line_is_added_with_a_warning() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = state_with_fake_ngspice(dir.path(), 1).await;
+
+        let request = SimulationRequest { netlist: "* Test\nV1 out 0 1\n.tran 1m".to_string(), ..simulation_request("a") };
+        let response = execute(&request, &state, &progress_sink()).await;
+        assert!(response.success, "{:?}", response.error);
+        assert_eq!(response.warnings, ["The netlist has no .end line; one was added at the end"]);
+
+        let response = execute(&simulation_request("b"), &state, &progress_sink()).await;
+        assert!(response.warnings.is_empty());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_unknown_save_traces_run_with_a_warning() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = state_with_fake_ngspice(dir.path(), 1).await;
+
+        let request = SimulationRequest {
+            save_traces: vec!["V(out)".to_string(), "V(nope)".to_string(), "I(R9)".to_string()],
+            ..simulation_request("a")
+        };
+        let response = execute(&request, &state, &progress_sink()).await;
+        assert!(response.success, "{:?}", response.error);
+        assert_eq!(response.warnings, ["Not saved, as the netlist has no such node or element: V(nope), I(R9)"]);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_trace_aliases_rename_results() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = state_with_fake_ngspice(dir.path(), 1).await;
+
+        let request = SimulationRequest {
+            trace_aliases: HashMap::from([("V(OUT)".to_string(), "Output".to_string())]),
+            save_traces: vec!["output".to_string()],
+            ..simulation_request("a")
+        };
+        let response = execute(&request, &state, &progress_sink()).await;
+        assert!(response.success, "{:?}", response.error);
+        assert!(response.warnings.is_empty(), "{:?}", response.warnings);
+        let results = response.results.unwrap();
+        assert_eq!(results.traces[0].name, "Output");
+        assert_eq!(results.traces[0].raw_name.as_deref(), Some("v(out)"));
+        assert!(results.plot_hints.unwrap().trace_groups.contains_key("Output"));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_peek_returns_the_header_without_data() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = state_with_fake_ngspice(dir.path(), 1).await;
+
+        let request = SimulationRequest { peek: true, ..simulation_request("a") };
+        let response = execute(&request, &state, &progress_sink()).await;
+        assert!(response.success, "{:?}", response.error);
+        let results = response.results.unwrap();
+        assert_eq!(results.analysis_type, "transient");
+        assert!(results.time.is_empty());
+        assert_eq!(results.traces[0].name, "v(out)");
+        assert!(results.traces[0].data.is_empty());
+        let header = results.raw_header.unwrap();
+        assert_eq!(header.points, 1);
+        assert_eq!(header.variables.len(), 2);
+    }
+
+    /// Fake LTspice that writes a transient raw file with `v(out)`, and a log saying it loaded
+    /// `opamps.lib` from `dir` rather than the copy next to the netlist
+    #[cfg(unix)]
+    fn fake_library_ltspice(dir: &std::path::Path) -> String {
+        use std::os::unix::fs::PermissionsExt;
+
+        let raw = "Title: fake\nPlotname: Transient Analysis\nFlags: real\nNo. Variables: 2\nNo. Points: 1\n\
+                   Variables:\n\t0\ttime\ttime\n\t1\tv(out)\tvoltage\nValues:\n 0\t0.0\n\t1.0\n";
+        let raw: Vec<u8> = raw.encode_utf16().flat_map(|unit| unit.to_le_bytes()).collect();
+        std::fs::write(dir.join("circuit.raw"), raw).unwrap();
+        let script = format!(
+            r#"#!/bin/sh
+cp '{0}/circuit.raw' "${{2%.net}}.raw"
+printf 'Loading library: {0}/opamps.lib\nDirect Newton iteration for .op point succeeded.\n' > "${{2%.net}}.log"
+"#,
+            dir.display()
+        );
+        let path = dir.join("ltspice");
+        std::fs::write(&path, script).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        path.to_string_lossy().to_string()
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_libraries_used_flags_a_library_loaded_from_elsewhere() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = state_with_fake_ngspice(dir.path(), 1).await;
+        *state.ltspice_path.write().await = Some(fake_library_ltspice(dir.path()));
+        let attachments = vec![Attachment { name: "opamps.lib".to_string(), content_base64: BASE64_STANDARD.encode("* opamps") }];
+        let request = SimulationRequest {
+            netlist: "* Test\n.lib opamps.lib\nV1 out 0 1\n.tran 1m\n.end".to_string(),
+            attachments: attachments.clone(),
+            ..simulation_request("a")
+        };
+
+        // ngspice doesn't say where it read the attachment from
+        let response = execute(&request, &state, &progress_sink()).await;
+        assert!(response.success, "{:?}", response.error);
+        assert_eq!(response.libraries_used.len(), 1);
+        assert_eq!(response.libraries_used[0].source.as_deref(), Some("attachment"));
+        assert_eq!(response.libraries_used[0].loaded_path, None);
+        assert!(response.warnings.is_empty());
+
+        // The fake LTspice logs a library other than the attached copy
+        let request = SimulationRequest { simulator: "ltspice".to_string(), ..request };
+        let response = execute(&request, &state, &progress_sink()).await;
+        assert!(response.success, "{:?}", response.error);
+        let loaded = dir.path().join("opamps.lib").to_string_lossy().into_owned();
+        assert_eq!(response.libraries_used[0].loaded_path.as_deref(), Some(loaded.as_str()));
+        assert!(response.libraries_used[0].mismatch);
+        assert_eq!(
+            response.warnings,
+            [format!("opamps.lib was resolved to attachment but the simulator loaded {}", loaded)]
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_unset_include_path_variable_fails_with_library_missing() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = state_with_fake_ngspice(dir.path(), 1).await;
+        *state.ltspice_path.write().await = Some(fake_library_ltspice(dir.path()));
+        std::fs::write(dir.path().join("nmos.lib"), "* nmos").unwrap();
+        let request = SimulationRequest {
+            simulator: "ltspice".to_string(),
+            netlist: "* Test\n.include $KELICAD_TEST_MODELS/nmos.lib\nV1 out 0 1\n.tran 1m\n.end".to_string(),
+            ..simulation_request("a")
+        };
+
+        let response = execute(&request, &state, &progress_sink()).await;
+        assert!(!response.success);
+        assert_eq!(response.error_code.as_deref(), Some("LIBRARY_MISSING"));
+        assert!(response.error.unwrap().contains("$KELICAD_TEST_MODELS"));
+
+        // Defined in the settings, the variable points at the library
+        state.settings.write().await.path_variables =
+            std::collections::BTreeMap::from([("KELICAD_TEST_MODELS".to_string(), dir.path().to_string_lossy().into_owned())]);
+        let response = execute(&request, &state, &progress_sink()).await;
+        assert!(response.success, "{:?}", response.error);
+        assert_eq!(response.libraries_used[0].expanded_from.as_deref(), Some("$KELICAD_TEST_MODELS/nmos.lib"));
+    }
+
+    /// Fake LTspice that copies `tone.wav` from `dir` to where the netlist's `.wave` directive
+    /// points, and writes a transient raw file with `v(out)`
+    #[cfg(unix)]
+    fn fake_wave_ltspice(dir: &std::path::Path) -> String {
+        use std::os::unix::fs::PermissionsExt;
+
+        // LTspice writes -ascii raw files as UTF-16LE
+        let raw = "Title: fake\nPlotname: Transient Analysis\nFlags: real\nNo. Variables: 2\nNo. Points: 1\n\
+                   Variables:\n\t0\ttime\ttime\n\t1\tv(out)\tvoltage\nValues:\n 0\t0.0\n\t1.0\n";
+        let raw: Vec<u8> = raw.encode_utf16().flat_map(|unit| unit.to_le_bytes()).collect();
+        std::fs::write(dir.join("circuit.raw"), raw).unwrap();
+        let script = format!(
+            r#"#!/bin/sh
+wav=$(sed -n 's/^\.wave "\([^"]*\)" 16 1000 .*$/\1/p' "$2")
+[ -n "$wav" ] || {{ echo "no .wave directive" >&2; exit 1; }}
+cp '{0}/tone.wav' "$wav"
+cp '{0}/circuit.raw' "${{2%.net}}.raw"
+"#,
+            dir.display()
+        );
+        let path = dir.join("ltspice");
+        std::fs::write(&path, script).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        path.to_string_lossy().to_string()
+    }
+
+    /// Mono 16-bit PCM at 1 kHz holding `samples`
+    fn tone(samples: &[i16]) -> Vec<u8> {
+        let data: Vec<u8> = samples.iter().flat_map(|sample| sample.to_le_bytes()).collect();
+        let mut wav = b"RIFF".to_vec();
+        wav.extend_from_slice(&(36 + data.len() as u32).to_le_bytes());
+        wav.extend_from_slice(b"WAVEfmt \x10\0\0\0\x01\0\x01\0");
+        wav.extend_from_slice(&1000u32.to_le_bytes());
+        wav.extend_from_slice(&2000u32.to_le_bytes());
+        wav.extend_from_slice(b"\x02\0\x10\0data");
+        wav.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        wav.extend_from_slice(&data);
+        wav
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_wave_output_returns_the_wav_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = state_with_fake_ngspice(dir.path(), 1).await;
+        std::fs::write(dir.path().join("tone.wav"), tone(&[1, 2, 3, 4, 5])).unwrap();
+        *state.ltspice_path.write().await = Some(fake_wave_ltspice(dir.path()));
+        let wave_output = WaveOutput { node: "out".to_string(), sample_rate: 1000, duration: 0.003 };
+        let request = SimulationRequest {
+            simulator: "ltspice".to_string(),
+            wave_output: Some(wave_output.clone()),
+            ..simulation_request("a")
+        };
+
+        let response = execute(&request, &state, &progress_sink()).await;
+        assert!(response.success, "{:?}", response.error);
+        let wave_data = BASE64_STANDARD.decode(response.wave_data.unwrap()).unwrap();
+        assert_eq!(wave_data, tone(&[1, 2, 3]));
+
+        let request = SimulationRequest {
+            wave_output: Some(WaveOutput { node: "in".to_string(), ..wave_output }),
+            ..request
+        };
+        let response = execute(&request, &state, &progress_sink()).await;
+        assert!(!response.success);
+        assert_eq!(response.error_code.as_deref(), Some(WAVE_NODE_NOT_FOUND));
+        assert_eq!(response.error.as_deref(), Some("waveOutput names V(in), which is not in the simulation results"));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_wave_output_is_rejected_on_ngspice() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = state_with_fake_ngspice(dir.path(), 1).await;
+        let request = SimulationRequest {
+            wave_output: Some(WaveOutput { node: "out".to_string(), sample_rate: 44_100, duration: 1.0 }),
+            ..simulation_request("a")
+        };
+
+        let response = execute(&request, &state, &progress_sink()).await;
+        assert!(!response.success);
+        assert_eq!(response.error_code.as_deref(), Some("WAVE_OUTPUT_UNSUPPORTED"));
+        assert_eq!(*state.simulation_count.read().await, 0);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_operating_point_only_is_reported_incomplete() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = state_with_fake_ngspice(dir.path(), 1).await;
+        *state.ngspice_path.write().await = Some(fake_operating_point_ngspice(dir.path()));
+
+        let response = execute(&simulation_request("a"), &state, &progress_sink()).await;
+        assert!(!response.success);
+        assert_eq!(response.error_code.as_deref(), Some(ANALYSIS_INCOMPLETE));
+        assert_eq!(
+            response.error.as_deref(),
+            Some("The .tran analysis did not complete; only the operating point was computed")
+        );
+        let operating_point = response.operating_point.as_ref().unwrap();
+        assert_eq!(operating_point.get("v(in)"), Some(&1.0));
+        assert_eq!(operating_point.get("v(out)"), Some(&0.5));
+
+        let json: serde_json::Value = serde_json::from_str(&serde_json::to_string(&response).unwrap()).unwrap();
+        assert_eq!(json["operatingPoint"]["v(out)"], 0.5);
+    }
+
+    fn gzip_base64(text: &str) -> String {
+        use std::io::Write;
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(text.as_bytes()).unwrap();
+        BASE64_STANDARD.encode(encoder.finish().unwrap())
+    }
+
+    #[test]
+    fn test_decompress_netlist() {
+        // A generated netlist of a few MB, the kind that is worth compressing
+        let mut netlist = "* Generated ladder\nV1 n0 0 1\n".to_string();
+        for i in 0..60_000 {
+            netlist.push_str(&format!("R{} n{} n{} 1k\nC{} n{} 0 1p\n", i, i, i + 1, i, i + 1));
+        }
+        netlist.push_str(".tran 1n 1u\n.end\n");
+        assert!(netlist.len() > 2 * 1024 * 1024);
+
+        let encoded = gzip_base64(&netlist);
+        assert!(encoded.len() * 5 < netlist.len(), "{} vs {} bytes", encoded.len(), netlist.len());
+        assert_eq!(decompress_netlist(&encoded, MAX_NETLIST_BYTES).unwrap(), netlist);
+
+        let (error, code) = decompress_netlist(&encoded, 1024 * 1024).unwrap_err();
+        assert_eq!(code, "NETLIST_TOO_LARGE");
+        assert!(error.contains("larger than 1 MB"), "{}", error);
+
+        for invalid in ["not base64!".to_string(), BASE64_STANDARD.encode("not gzip"), gzip_base64("")[..20].to_string()] {
+            assert_eq!(decompress_netlist(&invalid, MAX_NETLIST_BYTES).unwrap_err().1, "INVALID_NETLIST_ENCODING");
+        }
+        let latin1 = {
+            use std::io::Write;
+            let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(b"* R\xe9sistance\n").unwrap();
+            BASE64_STANDARD.encode(encoder.finish().unwrap())
+        };
+        assert!(decompress_netlist(&latin1, MAX_NETLIST_BYTES).unwrap_err().0.contains("not UTF-8"));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_compressed_netlist_runs() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = state_with_fake_ngspice(dir.path(), 1).await;
+        let plain = simulation_request("a");
+        let request = SimulationRequest {
+            netlist: gzip_base64(&plain.netlist),
+            netlist_encoding: NetlistEncoding::GzipBase64,
+            ..simulation_request("b")
+        };
+        let response = execute(&request, &state, &progress_sink()).await;
+        assert!(response.success, "{:?}", response.error);
+
+        let request = SimulationRequest { netlist: plain.netlist, ..request };
+        let response = execute(&request, &state, &progress_sink()).await;
+        assert!(!response.success);
+        assert_eq!(response.error_code.as_deref(), Some("INVALID_NETLIST_ENCODING"));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_asc_input_requires_ltspice() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = state_with_fake_ngspice(dir.path(), 1).await;
+        let request = SimulationRequest {
+            netlist: "Version 4\nSHEET 1 880 680\n".to_string(),
+            input_format: InputFormat::Asc,
+            ..simulation_request("a")
+        };
+
+        let response = execute(&request, &state, &progress_sink()).await;
+        assert!(!response.success);
+        assert_eq!(response.error_code.as_deref(), Some("UNSUPPORTED_INPUT_FORMAT"));
+        assert!(response.error.unwrap().contains("needs LTspice"));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_dialect_translations_are_reported() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = state_with_fake_ngspice(dir.path(), 1).await;
+        let request = SimulationRequest {
+            netlist: "* Test\nV1 out 0 1\n.tran 1m\n.backanno\n.end".to_string(),
+            dialect: Some("ltspice".to_string()),
+            ..simulation_request("a")
+        };
+
+        let response = execute(&request, &state, &progress_sink()).await;
+        assert!(response.success, "{:?}", response.error);
+        assert_eq!(response.translations, ["Removed LTspice directive: .backanno"]);
+
+        let request = SimulationRequest {
+            dialect: Some("kicad".to_string()),
+            ..request
+        };
+        let response = execute(&request, &state, &progress_sink()).await;
+        assert_eq!(response.error_code.as_deref(), Some("UNSUPPORTED_DIALECT"));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_debug_info_shows_what_ran() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = state_with_fake_ngspice(dir.path(), 1).await;
+
+        let response = execute(&simulation_request("a"), &state, &progress_sink()).await;
+        assert!(response.debug_info.is_none());
+
+        let request = SimulationRequest {
+            include_debug_info: true,
+            compress: false,
+            ..simulation_request("b")
+        };
+        let response = execute(&request, &state, &progress_sink()).await;
+        assert!(response.success, "{:?}", response.error);
+        let debug_info = response.debug_info.unwrap();
+        assert!(debug_info.prepared_netlist.contains(".control"));
+        assert!(!debug_info.prepared_netlist_truncated);
+        assert!(debug_info.command_line.contains(" -b "));
+        assert!(debug_info.command_line.ends_with("circuit.cir"));
+        assert!(debug_info.command_line.contains(&debug_info.temp_dir));
+        assert_eq!(debug_info.netlist_summary.nodes, vec!["out", "0"]);
+        let json = serde_json::to_value(&debug_info).unwrap();
+        assert_eq!(json["netlistSummary"]["elementCounts"], serde_json::json!({"V": 1}));
+        assert_eq!(json["netlistSummary"]["componentCount"], 1);
+        assert_eq!(json["netlistSummary"]["subcircuitInstances"], serde_json::json!([]));
+        assert_eq!(json["injectedDirectives"][0], ".options interp reltol=1e-4");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_no_auto_options_leaves_out_the_quality_options() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = state_with_fake_ngspice(dir.path(), 1).await;
+
+        let request = SimulationRequest { include_debug_info: true, no_auto_options: true, ..simulation_request("a") };
+        let response = execute(&request, &state, &progress_sink()).await;
+        assert!(response.success, "{:?}", response.error);
+        let injected = response.debug_info.unwrap().injected_directives;
+        assert!(!injected.iter().any(|line| line.starts_with(".options")), "{:?}", injected);
+        assert!(injected.iter().any(|line| line.starts_with("write ")));
+
+        // The setting turns it off for every request
+        state.settings.write().await.auto_inject_options = false;
+        let request = SimulationRequest { include_debug_info: true, ..simulation_request("b") };
+        let response = execute(&request, &state, &progress_sink()).await;
+        assert!(!response.debug_info.unwrap().prepared_netlist.contains(".options"));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_engine_options_ignored_by_ngspice_are_warned() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = state_with_fake_ngspice(dir.path(), 1).await;
+
+        let response = execute(&simulation_request("a"), &state, &progress_sink()).await;
+        assert!(response.warnings.is_empty());
+
+        let request = SimulationRequest {
+            engine_options: EngineOptions { alternate_solver: true, ascii_output: false },
+            ..simulation_request("b")
+        };
+        let response = execute(&request, &state, &progress_sink()).await;
+        assert!(response.success, "{:?}", response.error);
+        assert_eq!(response.warnings, ["engineOptions were ignored: they apply to LTspice only, not ngspice"]);
+    }
+
+    #[test]
+    fn test_engine_flags_for_ltspice() {
+        let request = SimulationRequest {
+            engine_options: EngineOptions { alternate_solver: true, ascii_output: true },
+            ..simulation_request("a")
+        };
+        let (flags, warnings) = engine_flags(&request, "ltspice", r"C:\Program Files\ADI\LTspice\LTspice.exe");
+        assert_eq!(flags, ["-alt", "-ascii"]);
+        assert!(warnings.is_empty());
+
+        let (flags, warnings) = engine_flags(&request, "ltspice", "/Applications/LTspice.app/Contents/MacOS/LTspice");
+        assert_eq!(flags, ["-ascii"]);
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].starts_with("alternateSolver was ignored"));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_unsupported_analysis_fails_fast() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = state_with_fake_ngspice(dir.path(), 1).await;
+        let fake = state.ngspice_path.read().await.clone();
+        *state.ltspice_path.write().await = fake;
+        let request = SimulationRequest {
+            netlist: "* Test\nV1 out 0 1\nR1 out 0 1k\n.sens V(out)\n.end".to_string(),
+            simulator: "ltspice".to_string(),
+            ..simulation_request("a")
+        };
+
+        let response = execute(&request, &state, &progress_sink()).await;
+        assert!(!response.success);
+        assert_eq!(response.error_code.as_deref(), Some("UNSUPPORTED_ANALYSIS"));
+        assert_eq!(response.error.as_deref(), Some(".sens analysis is not supported by LTspice; run it with ngspice"));
+        assert_eq!(*state.simulation_count.read().await, 0);
+    }
+
+    /// Fake simulator that writes `message` where each engine reports errors, then fails
+    #[cfg(unix)]
+    fn fake_unparsable(dir: &std::path::Path, name: &str, message: &str) -> String {
+        use std::os::unix::fs::PermissionsExt;
+
+        let script = format!("#!/bin/sh\necho '{0}'\necho '{0}' > \"${{2%.net}}.log\"\nexit 1\n", message);
+        let path = dir.join(name);
+        std::fs::write(&path, script).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        path.to_string_lossy().to_string()
+    }
+
+    #[cfg(unix)]
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_parse_failure_retries_on_other_engine() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = state_with_fake_ngspice(dir.path(), 1).await;
+        *state.ltspice_path.write().await = Some(fake_unparsable(dir.path(), "ltspice", "Unknown function: table"));
+        let request = SimulationRequest { simulator: "ltspice".to_string(), ..simulation_request("a") };
+
+        let response = execute(&request, &state, &progress_sink()).await;
+        assert!(!response.success);
+        assert_eq!(response.error_code.as_deref(), Some(NETLIST_PARSE_ERROR));
+        assert_eq!(response.fallback_simulator_used, None);
+
+        let (progress_tx, mut progress_rx) = mpsc::channel(64);
+        let request = SimulationRequest { auto_retry_other_engine: true, ..request };
+        let response = execute(&request, &state, &progress_tx).await;
+        assert!(response.success, "{:?}", response.error);
+        assert_eq!(response.simulator, "ngspice");
+        assert_eq!(response.fallback_simulator_used.as_deref(), Some("ngspice"));
+        assert!(response.warnings[0].starts_with("ltspice could not parse the netlist: LTspice failed"));
+        assert!(response.engine_errors.is_empty());
+        drop(progress_tx);
+        let mut stages = Vec::new();
+        while let Some(message) = progress_rx.recv().await {
+            let progress: serde_json::Value = serde_json::from_str(&message).unwrap();
+            stages.push(progress["stage"].as_str().unwrap_or_default().to_string());
+        }
+        assert!(stages.contains(&"switching_engine".to_string()));
+
+        // Both engines fail: each one's error is returned
+        *state.ngspice_path.write().await = Some(fake_unparsable(dir.path(), "ngspice-bad", "Error on line 2 : unknown parameter"));
+        let response = execute(&request, &state, &progress_sink()).await;
+        assert!(!response.success);
+        assert_eq!(response.fallback_simulator_used.as_deref(), Some("ngspice"));
+        let engines: Vec<&str> = response.engine_errors.iter().map(|e| e.simulator.as_str()).collect();
+        assert_eq!(engines, ["ltspice", "ngspice"]);
+        assert!(response.engine_errors[1].error.contains("unknown parameter"));
+        assert_eq!(response.engine_errors[1].error_code.as_deref(), Some(NETLIST_PARSE_ERROR));
+    }
+
+    #[test]
+    fn test_slow_raw_read_warning() {
+        let read = |raw_file_bytes: u64, raw_read_ms: u64| SimulationTimings {
+            raw_file_bytes,
+            raw_read_ms,
+            ..SimulationTimings::default()
+        };
+        let warning = slow_raw_read_warning(&read(64 * 1024 * 1024, 8000)).unwrap();
+        assert!(warning.starts_with("The 64 MB raw file was read at 8.0 MB/s"), "{}", warning);
+        assert!(warning.contains("temp_dir_override"));
+
+        assert_eq!(slow_raw_read_warning(&read(64 * 1024 * 1024, 500)), None);
+        // Small files are read too quickly to judge
+        assert_eq!(slow_raw_read_warning(&read(1024 * 1024, 1000)), None);
+        assert_eq!(slow_raw_read_warning(&read(64 * 1024 * 1024, 0)), None);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_timings_add_up_to_the_total() {
+        use crate::dedup::Seen;
+
+        let dir = tempfile::tempdir().unwrap();
+        let state = state_with_fake_ngspice(dir.path(), 1).await;
+        let request = simulation_request("timed");
+        let (tx, mut rx) = mpsc::channel(64);
+        let Seen::New(listeners) = state.recent_requests.write().await.begin(&request.id, &tx) else {
+            panic!("expected a new run");
+        };
+        let response = execute_shared(&request, ALLOWED_ORIGINS[0], &state, listeners).await;
+        assert!(response.success, "{:?}", response.error);
+
+        let timings = &response.timings;
+        assert!(timings.simulator_ms >= 1000, "{}", timings);
+        assert!(timings.raw_file_bytes > 0);
+        assert!(timings.netlist_bytes > 0);
+        assert!(timings.log_file_bytes > 0);
+        assert!(timings.raw_read_ms <= timings.raw_parse_ms, "{}", timings);
+        let steps = timings.include_resolution_ms
+            + timings.netlist_write_ms
+            + timings.simulator_ms
+            + timings.raw_parse_ms
+            + timings.serialization_ms;
+        assert!(steps <= timings.total_ms, "{}", timings);
+        assert!(timings.total_ms - steps < 250, "{}", timings);
+
+        // The delivered response carries the same timings, serialization included
+        let mut last = None;
+        while let Ok(json) = rx.try_recv() {
+            last = Some(serde_json::from_str::<serde_json::Value>(&json).unwrap());
+        }
+        let delivered = &last.unwrap()["timings"];
+        for field in [
+            "includeResolutionMs",
+            "netlistWriteMs",
+            "simulatorMs",
+            "rawFileBytes",
+            "rawParseMs",
+            "serializationMs",
+            "totalMs",
+        ] {
+            assert!(delivered[field].is_u64(), "{} missing from {}", field, delivered);
+        }
+        assert_eq!(delivered["totalMs"], timings.total_ms);
+        assert_eq!(delivered["serializationMs"], timings.serialization_ms);
+    }
+
+    /// Fake ngspice that sleeps for the corner's `delay` parameter, then reports it as v(out)
+    #[cfg(unix)]
+    fn fake_delay_ngspice(dir: &std::path::Path) -> String {
+        use std::os::unix::fs::PermissionsExt;
+
+        let script = r#"#!/bin/sh
+raw=$(sed -n 's/^write \([^ ]*\) .*$/\1/p' "$2" | tr -d "'")
+delay=$(sed -n 's/^\.param delay=\([0-9.]*\)$/\1/p' "$2")
+sleep "$delay"
+cat > "$raw" <<RAW
+Title: fake
+Plotname: Transient Analysis
+Flags: real
+No. Variables: 2
+No. Points: 1
+Variables:
+	0	time	time
+	1	v(out)	voltage
+Values:
+ 0	0.000000000000000e+00
+	$delay
+RAW
+"#;
+        let path = dir.join("ngspice");
+        std::fs::write(&path, script).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        path.to_string_lossy().to_string()
+    }
+
+    /// Drain `still_running` messages, returning their elapsed times
+    fn still_running_ticks(progress_rx: &mut mpsc::Receiver<String>) -> Vec<u64> {
+        let mut ticks = Vec::new();
+        while let Ok(message) = progress_rx.try_recv() {
+            let progress: serde_json::Value = serde_json::from_str(&message).unwrap();
+            if progress["stage"] == "still_running" {
+                ticks.push(progress["elapsedMs"].as_u64().unwrap());
+            }
+        }
+        ticks
+    }
+
+    #[cfg(unix)]
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_keepalive_ticks_until_the_run_ends() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = Arc::new(AppState::new(AgentSettings { keepalive_interval_secs: 1, ..AgentSettings::default() }));
+        *state.ngspice_path.write().await = Some(fake_delay_ngspice(dir.path()));
+        let request = |id: &str, delay: &str| SimulationRequest {
+            netlist: format!("* Test\n.param delay={}\nV1 out 0 1\n.tran 1m\n.end", delay),
+            ..simulation_request(id)
+        };
+
+        // The simulator prints nothing for 2.5 s, yet the client hears from the agent every second
+        let (progress_tx, mut progress_rx) = mpsc::channel(64);
+        let response = execute(&request("a", "2.5"), &state, &progress_tx).await;
+        assert!(response.success, "{:?}", response.error);
+        let ticks = still_running_ticks(&mut progress_rx);
+        assert!((2..=3).contains(&ticks.len()), "{:?}", ticks);
+        assert!(ticks[0] >= 1000 && ticks.windows(2).all(|pair| pair[1] > pair[0]), "{:?}", ticks);
+
+        tokio::time::sleep(std::time::Duration::from_millis(1500)).await;
+        assert!(still_running_ticks(&mut progress_rx).is_empty());
+
+        // A cancelled run stops ticking right away
+        let (progress_tx, mut progress_rx) = mpsc::channel(64);
+        let run = tokio::spawn({
+            let (state, request) = (state.clone(), request("b", "10"));
+            async move { execute(&request, &state, &progress_tx).await }
+        });
+        tokio::time::sleep(std::time::Duration::from_millis(1300)).await;
+        assert_eq!(cancel("b", &state).await, CancelOutcome::Cancelled);
+        let response = run.await.unwrap();
+        assert!(!response.success);
+        assert_eq!(still_running_ticks(&mut progress_rx).len(), 1);
+        tokio::time::sleep(std::time::Duration::from_millis(1500)).await;
+        assert!(still_running_ticks(&mut progress_rx).is_empty());
+    }
+
+    fn delay_batch(id: &str, delays: &[&str]) -> BatchSimulationRequest {
+        BatchSimulationRequest {
+            id: id.to_string(),
+            msg_type: "batch_simulate".to_string(),
+            netlist: "* Test\nV1 out 0 1\n.tran 1m\n.end".to_string(),
+            corners: delays
+                .iter()
+                .map(|delay| BatchCorner {
+                    name: format!("delay {}", delay),
+                    params: [("delay".to_string(), serde_json::json!(delay))].into_iter().collect(),
+                    temperature: None,
+                })
+                .collect(),
+            waveform_quality: "smooth".to_string(),
+            simulator: "ngspice".to_string(),
+            timeout: None,
+            timestamp: 0,
+            origin: None,
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_batch_runs_corners_in_parallel_in_order() {
+        if max_worker_count() < 3 {
+            return;
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let state = Arc::new(AppState::new(AgentSettings { max_concurrent_simulations: 3, ..AgentSettings::default() }));
+        *state.ngspice_path.write().await = Some(fake_delay_ngspice(dir.path()));
+
+        // Later corners finish first; sequentially this would take 2.4 s
+        let start = std::time::Instant::now();
+        let (progress_tx, mut progress_rx) = mpsc::channel(64);
+        let response = execute_batch(&delay_batch("batch", &["1.2", "0.8", "0.4"]), &state, &progress_tx).await;
+        assert!(start.elapsed() < std::time::Duration::from_millis(2200), "took {:?}", start.elapsed());
+
+        assert!(response.success, "{:?}", response.corners);
+        let values: Vec<f64> = response
+            .corners
+            .iter()
+            .map(|corner| corner.results.as_ref().unwrap().traces[0].data[0])
+            .collect();
+        assert_eq!(values, [1.2, 0.8, 0.4]);
+        assert_eq!(response.corners[0].name, "delay 1.2");
+
+        drop(progress_tx);
+        let mut messages = Vec::new();
+        while let Some(json) = progress_rx.recv().await {
+            messages.push(serde_json::from_str::<serde_json::Value>(&json).unwrap()["message"].to_string());
+        }
+        assert!(messages.iter().any(|m| m.contains("3/3 corners complete")), "{:?}", messages);
+
+        // Borrowed worker slots are returned
+        assert!(!state.is_busy());
+        assert!(state.begin_simulation("next", None).await.is_ok());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_cancel_kills_every_parallel_corner() {
+        if max_worker_count() < 2 {
+            return;
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let state = Arc::new(AppState::new(AgentSettings { max_concurrent_simulations: 2, ..AgentSettings::default() }));
+        *state.ngspice_path.write().await = Some(fake_delay_ngspice(dir.path()));
+
+        let batch = tokio::spawn({
+            let state = state.clone();
+            async move { execute_batch(&delay_batch("batch", &["30", "30", "30"]), &state, &progress_sink()).await }
+        });
+
+        // Wait until both lanes have spawned their simulator
+        loop {
+            if let Some(sim) = state.active_simulation("batch").await {
+                if sim.process_ids().len() == 2 {
+                    break;
+                }
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        // Give the fake's shell time to start its sleep, so killing the tree reaches it
+        tokio::time::sleep(std::time::Duration::from_millis(300)).await;
+
+        let start = std::time::Instant::now();
+        assert_eq!(cancel("batch", &state).await, CancelOutcome::Cancelled);
+        let response = batch.await.unwrap();
+        assert!(start.elapsed() < std::time::Duration::from_secs(10));
+        assert!(!response.success);
+        assert_eq!(response.error.as_deref(), Some("Simulation cancelled"));
+        assert_eq!(response.corners.len(), 3);
+        assert!(response.corners.iter().all(|c| c.error.as_deref() == Some("Simulation cancelled")));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_keep_raw_stores_an_artifact() {
+        use crate::artifacts::{Artifacts, MAX_ARTIFACT_BYTES};
+
+        let dir = tempfile::tempdir().unwrap();
+        let state = state_with_fake_ngspice(dir.path(), 1).await;
+        let artifacts_dir = dir.path().join("artifacts");
+        *state.artifacts.write().await = Artifacts::open(&artifacts_dir, MAX_ARTIFACT_BYTES).unwrap();
+
+        let response = execute(&simulation_request("plain"), &state, &progress_sink()).await;
+        assert!(response.success, "{:?}", response.error);
+        assert_eq!(response.artifact_id, None);
+        assert_eq!(response.raw_file_size, None);
+
+        let request = SimulationRequest { keep_raw: true, ..simulation_request("kept/1") };
+        let response = execute(&request, &state, &progress_sink()).await;
+        assert!(response.success, "{:?}", response.error);
+        assert_eq!(response.artifact_id.as_deref(), Some("kept_1"));
+        let artifact = state.artifacts.write().await.get("kept_1").unwrap();
+        assert_eq!(Some(artifact.size), response.raw_file_size);
+        assert_eq!(artifact.path, artifacts_dir.join("kept_1.raw"));
+        assert!(std::fs::read_to_string(&artifact.path).unwrap().starts_with("Title: fake"));
+
+        // Without an artifacts directory the run still succeeds, with a warning
+        *state.artifacts.write().await = Artifacts::default();
+        let response = execute(&SimulationRequest { keep_raw: true, ..simulation_request("lost") }, &state, &progress_sink()).await;
+        assert!(response.success);
+        assert_eq!(response.artifact_id, None);
+        assert!(response.warnings.iter().any(|w| w.starts_with("The raw file was not kept")), "{:?}", response.warnings);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_vanished_simulator_is_redetected() {
+        use std::os::unix::fs::PermissionsExt;
+
+        // Detection runs for real, and an installed ngspice would be found and used instead
+        if simulator::detect_ngspice().is_some() {
+            return;
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let state = Arc::new(AppState::new(AgentSettings::default()));
+        let mut events = state.subscribe_status();
+
+        // Deleted after detection cached it
+        let deleted = fake_slow_ngspice(dir.path());
+        std::fs::remove_file(&deleted).unwrap();
+        *state.ngspice_path.write().await = Some(deleted.clone());
+        let response = execute(&simulation_request("a"), &state, &progress_sink()).await;
+        let error = response.error.unwrap();
+        assert!(error.contains(&format!("ngspice is no longer installed at {}", deleted)), "{}", error);
+        assert!(state.ngspice_path.read().await.is_none());
+        let mut heard = Vec::new();
+        while let Ok(event) = events.try_recv() {
+            heard.push(event);
+        }
+        assert!(heard.contains(&StatusEvent::CapabilitiesChanged));
+
+        // Still there, but failing to start as though it weren't: caught when the process is spawned
+        let broken = dir.path().join("ngspice");
+        std::fs::write(&broken, "#!/nonexistent/interpreter\n").unwrap();
+        std::fs::set_permissions(&broken, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let broken = broken.to_string_lossy().to_string();
+        *state.ngspice_path.write().await = Some(broken.clone());
+        let response = execute(&simulation_request("b"), &state, &progress_sink()).await;
+        assert_eq!(response.error_code.as_deref(), Some(SIMULATOR_MISSING));
+        let error = response.error.unwrap();
+        assert!(error.contains(&format!("ngspice is no longer installed at {}", broken)), "{}", error);
+        assert!(state.ngspice_path.read().await.is_none());
+        assert!(!state.is_busy());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_deferred_trace_data_is_kept_for_fetching() {
+        use crate::dedup::Seen;
+
+        let dir = tempfile::tempdir().unwrap();
+        let state = state_with_fake_ngspice(dir.path(), 1).await;
+
+        let request = SimulationRequest { defer_trace_data: true, ..simulation_request("a") };
+        let (tx, _rx) = mpsc::channel(64);
+        let Seen::New(listeners) = state.recent_requests.write().await.begin(&request.id, &tx) else {
+            panic!("expected a new run");
+        };
+        let response = execute_shared(&request, ALLOWED_ORIGINS[0], &state, listeners).await;
+        assert!(response.success, "{:?}", response.error);
+        let results = response.results.as_ref().unwrap();
+        assert!(results.time.is_empty());
+        assert!(results.traces.iter().all(|trace| trace.data.is_empty()));
+
+        let catalog = response.trace_catalog.unwrap();
+        assert_eq!(catalog.len(), results.traces.len());
+        let kept = state.trace_store.write().await.get("a").unwrap();
+        assert_eq!(catalog[0].points, kept.traces[0].data.len());
+        assert!(catalog[0].points > 0);
+
+        // A cancel after the run leaves the results in place
+        assert_eq!(cancel("a", &state).await, CancelOutcome::NotRunning);
+        assert!(state.trace_store.write().await.get("a").is_some());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_deferred_trace_data_is_kept_at_full_precision() {
+        use crate::dedup::Seen;
+        use std::os::unix::fs::PermissionsExt;
+
+        // Fake ngspice whose samples have more digits than the request keeps
+        let dir = tempfile::tempdir().unwrap();
+        let script = r#"#!/bin/sh
+raw=$(sed -n 's/^write \([^ ]*\) .*$/\1/p' "$2" | tr -d "'")
+printf 'Title: fake\nPlotname: Transient Analysis\nFlags: real\nNo. Variables: 2\nNo. Points: 2\nVariables:\n\t0\ttime\ttime\n\t1\tv(out)\tvoltage\nValues:\n 0\t0.0\n\t1.23456789\n 1\t1.0e-3\n\t2.98765432\n' > "$raw"
+"#;
+        let ngspice = dir.path().join("ngspice");
+        std::fs::write(&ngspice, script).unwrap();
+        std::fs::set_permissions(&ngspice, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let state = Arc::new(AppState::new(AgentSettings { max_concurrent_simulations: 1, ..AgentSettings::default() }));
+        *state.ngspice_path.write().await = Some(ngspice.to_string_lossy().to_string());
+
+        let request = SimulationRequest { defer_trace_data: true, precision: Some(2), ..simulation_request("a") };
+        let (tx, _rx) = mpsc::channel(64);
+        let Seen::New(listeners) = state.recent_requests.write().await.begin(&request.id, &tx) else {
+            panic!("expected a new run");
+        };
+        let response = execute_shared(&request, ALLOWED_ORIGINS[0], &state, listeners).await;
+        assert!(response.success, "{:?}", response.error);
+
+        let catalog = response.trace_catalog.unwrap();
+        assert_eq!(catalog[0].min, Some(1.23456789));
+        assert_eq!(catalog[0].max, Some(2.98765432));
+        assert_eq!(catalog[0].mean, Some((1.23456789 + 2.98765432) / 2.0));
+        let kept = state.trace_store.write().await.get("a").unwrap();
+        assert_eq!(kept.traces[0].data, [1.23456789, 2.98765432]);
+
+        // A result that isn't deferred is still rounded for sending
+        let request = SimulationRequest { precision: Some(2), ..simulation_request("b") };
+        let Seen::New(listeners) = state.recent_requests.write().await.begin(&request.id, &tx) else {
+            panic!("expected a new run");
+        };
+        let response = execute_shared(&request, ALLOWED_ORIGINS[0], &state, listeners).await;
+        assert_eq!(response.results.unwrap().traces[0].data, [1.2, 3.0]);
+    }
+}
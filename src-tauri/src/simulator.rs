@@ -4,17 +4,37 @@
 
 //! SPICE simulation execution and result parsing (LTspice and ngspice)
 
-use std::path::PathBuf;
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
 use std::process::Command;
 use encoding_rs::UTF_16LE;
 use regex::Regex;
-use tempfile::Builder;
+use tempfile::{Builder, TempDir};
 use std::io::{BufRead, BufReader};
-
-use crate::protocol::{SimulationResults, Trace};
-
-/// Standard libraries bundled with the agent (fallback)
-const STANDARD_LIBRARIES: &[&str] = &["LTC3.lib"];
+use base64::prelude::{Engine, BASE64_STANDARD};
+
+use crate::libraries_used;
+use crate::long_path;
+use crate::ngspice_session::{NgspiceSession, SessionOutput};
+use crate::path_variables;
+use crate::netlist;
+use crate::peek;
+use crate::quality::QualityProfile;
+use crate::protocol::{
+    AnalysisCapability, Attachment, BatchCorner, DebugInfo, DeviceOperatingPoint, EngineOptions, IncludedLibrary, LibraryStrategy, LibraryUsed, LogLine, MeasurementStatistics, NetlistAnalysis, ProgressStage, ResourceUsage,
+    RawTruncation, now_ms, SimulationResults, SimulationTimings, Trace,
+};
+use crate::wave::{self, WaveCapture};
+
+/// Most attachments one request may carry
+const MAX_ATTACHMENTS: usize = 32;
+
+/// Largest decoded attachment, in bytes
+const MAX_ATTACHMENT_BYTES: usize = 8 * 1024 * 1024;
+
+/// Largest decoded size of all of a request's attachments together, in bytes
+const MAX_ATTACHMENTS_TOTAL_BYTES: usize = 32 * 1024 * 1024;
 
 /// Known ngspice installation paths on Windows
 #[cfg(windows)]
@@ -358,7 +378,7 @@ fn expand_glob_path(pattern: &str) -> Option<PathBuf> {
 }
 
 /// Get the path to bundled resources
-fn get_resources_dir() -> Option<PathBuf> {
+pub fn get_resources_dir() -> Option<PathBuf> {
     // When running in development, resources are in src-tauri/resources
     // When bundled, they're in the app bundle's Resources directory
 
@@ -409,13 +429,17 @@ fn get_resources_dir() -> Option<PathBuf> {
 }
 
 /// Process .include and .lib directives in the netlist
-/// Resolves library files from LTspice's library directory or bundled resources
+/// Resolves library files from the request's search paths, LTspice's library directory or,
+/// for `bundled_libraries`, bundled resources
 fn process_includes(
     netlist: &str,
     temp_dir: &std::path::Path,
-) -> Result<(String, Vec<String>), Box<dyn std::error::Error + Send + Sync>> {
+    attachments: &[WorkspaceFile],
+    bundled_libraries: &[String],
+    search_paths: &LibrarySearchPaths,
+) -> Result<(String, Vec<IncludedLibrary>), Box<dyn std::error::Error + Send + Sync>> {
     let mut processed_netlist = netlist.to_string();
-    let mut copied_files: Vec<String> = Vec::new();
+    let mut copied_files: Vec<IncludedLibrary> = Vec::new();
 
     // Match .include or .lib directives
     let include_pattern = Regex::new(r#"(?im)^\s*\.(?:include|lib)\s+(.+?)\s*$"#)?;
@@ -425,7 +449,16 @@ fn process_includes(
 
     for cap in include_pattern.captures_iter(netlist) {
         let full_match = cap.get(0).unwrap().as_str();
-        let path_str = cap.get(1).unwrap().as_str().trim_matches(|c| c == '"' || c == '\'');
+        let written = cap.get(1).unwrap().as_str().trim_matches(|c| c == '"' || c == '\'');
+
+        // Neither simulator expands variables, so the include is rewritten with them expanded
+        let expanded = path_variables::expand_with(written, &search_paths.variables)
+            .map_err(|variable| LibraryMissing { include: written.to_string(), variable })?;
+        let path_str = expanded.as_str();
+        let expanded_from = (path_str != written).then(|| written.to_string());
+        if expanded_from.is_some() {
+            log::info!("Include path {} expanded to {}", written, path_str);
+        }
 
         // Extract filename from path
         let file_name = std::path::Path::new(path_str)
@@ -433,47 +466,122 @@ fn process_includes(
             .and_then(|n| n.to_str())
             .unwrap_or(path_str);
 
+        // Attachments are already in the temp dir and win over the library directories
+        if let Some(file) = attachments.iter().find(|file| file.name == path_str) {
+            if !copied_files.iter().any(|lib| lib.name == file.name) {
+                copied_files.push(IncludedLibrary {
+                    name: file.name.clone(),
+                    source: "attachment".to_string(),
+                    strategy: LibraryStrategy::Copy,
+                    expanded_from: expanded_from.clone(),
+                });
+            }
+            if expanded_from.is_some() {
+                processed_netlist = processed_netlist.replace(full_match, &format!(".include {}", file.name));
+            }
+            continue;
+        }
+
         // Check if the path is absolute and exists
         let path_as_is = PathBuf::from(path_str);
         if path_as_is.is_absolute() && path_as_is.exists() {
+            if cfg!(windows) && long_path::unreachable_from_netlist(path_str) {
+                // Simulators can't open files on a share or past MAX_PATH, so use a local copy
+                let dest_path = temp_dir.join(file_name);
+                if std::fs::copy(&path_as_is, &dest_path).is_ok() {
+                    copied_files.push(IncludedLibrary {
+                        name: file_name.to_string(),
+                        source: path_str.to_string(),
+                        strategy: LibraryStrategy::Copy,
+                        expanded_from,
+                    });
+                    processed_netlist = processed_netlist.replace(full_match, &format!(".include {}", file_name));
+                    log::info!("Copied library out of reach of the simulator: {:?} -> {:?}", path_as_is, dest_path);
+                    continue;
+                }
+            }
+            if cfg!(windows) && path_str.starts_with(r"\\?\") {
+                let local = long_path::for_netlist(path_str);
+                processed_netlist = processed_netlist.replace(full_match, &format!(".include \"{}\"", local));
+            } else if expanded_from.is_some() {
+                processed_netlist = processed_netlist.replace(full_match, &format!(".include \"{}\"", path_str));
+            }
             log::info!("Using absolute library path: {:?}", path_as_is);
-            continue; // Keep as-is, LTspice will find it
+            copied_files.push(IncludedLibrary {
+                name: file_name.to_string(),
+                source: path_str.to_string(),
+                strategy: LibraryStrategy::Reference,
+                expanded_from,
+            });
+            continue; // LTspice will find it
+        }
+
+        // The request's own search paths come before LTspice's library directory
+        if let Some((found_path, found_as)) = search_paths.find(path_str, file_name) {
+            let source = found_path.to_string_lossy().into_owned();
+            if search_paths.on_command_line {
+                // LTspice looks in the -I directories itself, by the name the file was found under
+                if found_as != written {
+                    processed_netlist = processed_netlist.replace(full_match, &format!(".include {}", found_as));
+                }
+                log::info!("Library {} found on a search path passed to LTspice: {:?}", file_name, found_path);
+                copied_files.push(IncludedLibrary {
+                    name: file_name.to_string(),
+                    source,
+                    strategy: LibraryStrategy::Reference,
+                    expanded_from,
+                });
+                continue;
+            }
+            if let Ok((include, strategy)) = place_library(&found_path, file_name, temp_dir, search_paths.strategy) {
+                copied_files.push(IncludedLibrary { name: file_name.to_string(), source, strategy, expanded_from });
+                processed_netlist = processed_netlist.replace(full_match, &format!(".include {}", include));
+                log::info!("Library from search path {:?} included as {}", found_path, include);
+                continue;
+            }
         }
 
         // Try to find the library in LTspice's lib directory (search recursively)
         if let Some(ref lib_dir) = ltspice_lib_dir {
             if let Some(found_path) = find_library_file(lib_dir, file_name) {
-                // Copy the library to temp dir to ensure LTspice can access it
-                let dest_path = temp_dir.join(file_name);
-                if std::fs::copy(&found_path, &dest_path).is_ok() {
-                    copied_files.push(file_name.to_string());
+                if let Ok((include, strategy)) = place_library(&found_path, file_name, temp_dir, search_paths.strategy) {
+                    copied_files.push(IncludedLibrary {
+                        name: file_name.to_string(),
+                        source: found_path.to_string_lossy().into_owned(),
+                        strategy,
+                        expanded_from,
+                    });
                     processed_netlist = processed_netlist.replace(
                         full_match,
-                        &format!(".include {}", file_name),
+                        &format!(".include {}", include),
                     );
-                    log::info!("Copied LTspice library: {:?} -> {:?}", found_path, dest_path);
+                    log::info!("LTspice library {:?} included as {}", found_path, include);
                     continue;
                 }
             }
         }
 
         // Fallback: check if this is a standard library we bundle
-        if STANDARD_LIBRARIES.contains(&file_name) {
+        if bundled_libraries.iter().any(|name| name == file_name) {
             if let Some(ref res_dir) = resources_dir {
                 let src_path = res_dir.join(file_name);
-                let dest_path = temp_dir.join(file_name);
 
                 if src_path.exists() {
-                    std::fs::copy(&src_path, &dest_path)?;
-                    copied_files.push(file_name.to_string());
-
-                    // Update the netlist to use the local copy
+                    let (include, strategy) = place_library(&src_path, file_name, temp_dir, search_paths.strategy)?;
+                    copied_files.push(IncludedLibrary {
+                        name: file_name.to_string(),
+                        source: src_path.to_string_lossy().into_owned(),
+                        strategy,
+                        expanded_from,
+                    });
+
+                    // Update the netlist to use the local copy or the bundled file
                     processed_netlist = processed_netlist.replace(
                         full_match,
-                        &format!(".include {}", file_name),
+                        &format!(".include {}", include),
                     );
 
-                    log::info!("Copied bundled library: {} -> {:?}", file_name, dest_path);
+                    log::info!("Bundled library {} included as {}", file_name, include);
                     continue;
                 }
             }
@@ -485,6 +593,162 @@ fn process_includes(
     Ok((processed_netlist, copied_files))
 }
 
+/// Make the library at `found_path` available to the netlist: its quoted absolute path with
+/// `Reference`, unless LTspice couldn't open it there, otherwise a copy named `file_name` in `temp_dir`
+/// Returns what the `.include` should name and the strategy used
+fn place_library(
+    found_path: &Path,
+    file_name: &str,
+    temp_dir: &Path,
+    strategy: LibraryStrategy,
+) -> std::io::Result<(String, LibraryStrategy)> {
+    if strategy == LibraryStrategy::Reference {
+        let absolute = std::path::absolute(found_path)?;
+        let absolute = absolute.to_string_lossy();
+        if referenceable_in_netlist(&absolute) {
+            return Ok((format!("\"{}\"", absolute), LibraryStrategy::Reference));
+        }
+        log::info!("Copying {:?}: LTspice can't include it by that path", found_path);
+    }
+    std::fs::copy(found_path, temp_dir.join(file_name))?;
+    Ok((file_name.to_string(), LibraryStrategy::Copy))
+}
+
+/// Whether LTspice can open `path` written into a netlist in quotes: it reads netlists in the
+/// system code page, so the path must be ASCII, and it can't hold a quote or control character
+fn referenceable_in_netlist(path: &str) -> bool {
+    path.is_ascii()
+        && !path.chars().any(|c| c == '"' || c.is_control())
+        && !(cfg!(windows) && long_path::unreachable_from_netlist(path))
+}
+
+/// An include path as seen from the temp dir: separators normalised to `/`, `.` and `..` dropped
+/// Returns None for absolute paths, which are used as they are
+fn temp_dir_relative(path_str: &str) -> Option<String> {
+    let has_drive = path_str.as_bytes().get(1) == Some(&b':');
+    if has_drive || path_str.starts_with(['/', '\\']) || Path::new(path_str).is_absolute() {
+        return None;
+    }
+    let parts: Vec<&str> = path_str
+        .split(['/', '\\'])
+        .filter(|part| !part.is_empty() && *part != "." && *part != "..")
+        .collect();
+    Some(parts.join("/"))
+}
+
+/// Rewrite relative .include/.lib paths so they resolve inside the simulator's temp dir
+/// The simulator runs there, so this gives the same result on every platform
+fn pin_relative_includes(netlist: &str) -> String {
+    rewrite_includes(netlist, |path_str| temp_dir_relative(path_str).filter(|pinned| !pinned.is_empty()))
+}
+
+/// Point .include/.lib directives at attachments of the same file name, wherever they were
+fn use_attachments(netlist: &str, attachments: &[WorkspaceFile]) -> String {
+    if attachments.is_empty() {
+        return netlist.to_string();
+    }
+    rewrite_includes(netlist, |path_str| {
+        let file_name = path_str.rsplit(['/', '\\']).next().unwrap_or(path_str);
+        attachments
+            .iter()
+            .find(|file| file.name.eq_ignore_ascii_case(file_name))
+            .map(|file| file.name.clone())
+    })
+}
+
+/// Replace the path of each .include/.lib directive for which `rewrite` returns a new one
+fn rewrite_includes(netlist: &str, rewrite: impl Fn(&str) -> Option<String>) -> String {
+    let include_pattern = Regex::new(r#"(?im)^(\s*\.(?:include|lib)\s+)("[^"]*"|'[^']*'|\S+)(.*)$"#)
+        .expect("include pattern is valid");
+    include_pattern
+        .replace_all(netlist, |cap: &regex::Captures| {
+            let path_str = cap[2].trim_matches(|c| c == '"' || c == '\'');
+            match rewrite(path_str) {
+                Some(path) if path != path_str => {
+                    let path = if path.contains(' ') { format!("\"{}\"", path) } else { path };
+                    format!("{}{}{}", &cap[1], path, &cap[3])
+                }
+                _ => cap[0].to_string(),
+            }
+        })
+        .into_owned()
+}
+
+/// A decoded attachment, ready to be written into a simulation's temp dir
+#[derive(Debug, Clone, PartialEq)]
+pub struct WorkspaceFile {
+    pub name: String,
+    pub content: Vec<u8>,
+}
+
+/// Decode a request's attachments, checking their count, sizes and names
+pub fn decode_attachments(attachments: &[Attachment]) -> Result<Vec<WorkspaceFile>, String> {
+    if attachments.len() > MAX_ATTACHMENTS {
+        return Err(format!("Too many attachments: {} (at most {})", attachments.len(), MAX_ATTACHMENTS));
+    }
+
+    let mut files: Vec<WorkspaceFile> = Vec::with_capacity(attachments.len());
+    let mut total = 0usize;
+    for attachment in attachments {
+        let name = sanitize_attachment_name(&attachment.name)
+            .ok_or_else(|| format!("Attachment name \"{}\" is not a usable file name", attachment.name))?;
+        if files.iter().any(|file| file.name.eq_ignore_ascii_case(&name)) {
+            return Err(format!("Attachment \"{}\" is given more than once", name));
+        }
+
+        // Check the encoded size first so an oversized attachment is never decoded
+        if attachment.content_base64.len() / 4 * 3 > MAX_ATTACHMENT_BYTES + 3 {
+            return Err(format!("Attachment \"{}\" is larger than {} MB", name, MAX_ATTACHMENT_BYTES / (1024 * 1024)));
+        }
+        let content = BASE64_STANDARD
+            .decode(attachment.content_base64.trim())
+            .map_err(|e| format!("Attachment \"{}\" is not valid base64: {}", name, e))?;
+        if content.len() > MAX_ATTACHMENT_BYTES {
+            return Err(format!("Attachment \"{}\" is larger than {} MB", name, MAX_ATTACHMENT_BYTES / (1024 * 1024)));
+        }
+        total += content.len();
+        if total > MAX_ATTACHMENTS_TOTAL_BYTES {
+            return Err(format!("Attachments are larger than {} MB in total", MAX_ATTACHMENTS_TOTAL_BYTES / (1024 * 1024)));
+        }
+
+        files.push(WorkspaceFile { name, content });
+    }
+    Ok(files)
+}
+
+/// A plain file name for an attachment: its last path component with anything unusual replaced
+/// None if nothing usable is left or it could clash with a file the agent writes itself
+fn sanitize_attachment_name(name: &str) -> Option<String> {
+    let base = name.rsplit(['/', '\\']).next().unwrap_or(name);
+    let cleaned: String = base
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-' | ' ') { c } else { '_' })
+        .collect();
+    let cleaned = cleaned.trim_start_matches(['.', ' ']).trim_end_matches(['.', ' ']);
+    if cleaned.is_empty() || cleaned.len() > 255 {
+        return None;
+    }
+    (!is_agent_file_name(cleaned)).then(|| cleaned.to_string())
+}
+
+/// Whether the agent writes files named like this: circuit.*, corner<n>.* and mc<n>.*
+fn is_agent_file_name(name: &str) -> bool {
+    let stem = name.split('.').next().unwrap_or(name).to_ascii_lowercase();
+    let numbered = |prefix: &str| {
+        stem.strip_prefix(prefix)
+            .is_some_and(|n| !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()))
+    };
+    stem == "circuit" || numbered("corner") || numbered("mc")
+}
+
+/// Write attachments into `dir`
+fn write_workspace_files(dir: &Path, files: &[WorkspaceFile]) -> std::io::Result<()> {
+    for file in files {
+        std::fs::write(dir.join(&file.name), &file.content)?;
+    }
+    Ok(())
+}
+
 /// Recursively search for a library file in a directory
 fn find_library_file(dir: &PathBuf, file_name: &str) -> Option<PathBuf> {
     find_library_file_recursive(dir, file_name, 0, 4)
@@ -678,351 +942,2513 @@ fn collect_library_files(dir: &PathBuf, libraries: &mut Vec<String>, depth: usiz
     }
 }
 
+use std::io::Read;
 use std::sync::Arc;
-use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use sysinfo::{Disks, Pid, ProcessRefreshKind, ProcessesToUpdate, System, UpdateKind};
 
-/// Run an LTspice simulation
-/// The process_id_holder will be updated with the PID when the process starts
-pub async fn run_ltspice_simulation(
-    ltspice_path: &str,
-    netlist: &str,
-    waveform_quality: &str,
-    process_id_holder: Option<Arc<AtomicU32>>,
-) -> Result<SimulationResults, Box<dyn std::error::Error + Send + Sync>> {
-    // Create temp directory with kelicad prefix
-    let temp_dir = Builder::new().prefix("kelicad-sim-").tempdir()?;
-    log::info!("Created temp directory: {:?}", temp_dir.path());
-    let netlist_path = temp_dir.path().join("circuit.net");
-    let raw_path = temp_dir.path().join("circuit.raw");
-    let log_path = temp_dir.path().join("circuit.log");
+/// Temp directory prefix for LTspice workspaces
+pub const LTSPICE_TEMP_PREFIX: &str = "kelicad-sim-";
 
-    // Process includes - copy standard libraries to temp dir and update paths
-    let (processed_netlist, _copied_files) = process_includes(netlist, temp_dir.path())?;
+/// Temp directory prefix for ngspice workspaces
+pub const NGSPICE_TEMP_PREFIX: &str = "kelicad-ngspice-";
 
-    // Prepare netlist with required directives
-    let prepared_netlist = prepare_netlist(&processed_netlist, waveform_quality);
-    std::fs::write(&netlist_path, &prepared_netlist)?;
+/// Extra time allowed beyond the advertised maximum simulation time before the watchdog kills a run
+pub const WATCHDOG_MARGIN_SECS: u64 = 30;
 
-    log::info!("Running LTspice simulation...");
+/// How often the watchdog samples a running simulator
+const WATCHDOG_POLL_INTERVAL: Duration = Duration::from_secs(1);
 
-    // Run LTspice in batch mode using spawn() so we can get the PID
-    let output = tokio::task::spawn_blocking({
-        let ltspice_path = ltspice_path.to_string();
-        let netlist_path = netlist_path.clone();
-        move || {
-            let child = Command::new(&ltspice_path)
-                .arg("-b")
-                .arg(&netlist_path)
-                .stdout(std::process::Stdio::piped())
-                .stderr(std::process::Stdio::piped())
-                .spawn()?;
+/// CPU time a process must accumulate between samples to count as active
+const WATCHDOG_MIN_CPU_MS: u64 = 50;
 
-            let pid = child.id();
-            log::info!("LTspice process started with PID: {}", pid);
+/// How long a fresh LTspice batch run may sit idle without a log file before it's taken to be
+/// waiting on a first-run dialog
+pub const LTSPICE_FIRST_RUN_GRACE: Duration = Duration::from_secs(10);
 
-            // Store the PID in the holder if provided
-            if let Some(holder) = process_id_holder {
-                holder.store(pid, Ordering::SeqCst);
-            }
+/// `nice` increment used for background-priority simulator processes
+#[cfg(unix)]
+const BACKGROUND_NICE_LEVEL: &str = "10";
 
-            // Wait for the process to complete
-            let output = child.wait_with_output()?;
-            Ok::<_, std::io::Error>(output)
-        }
-    })
-    .await??;
+/// Windows process creation flag for below-normal scheduling priority
+#[cfg(windows)]
+const BELOW_NORMAL_PRIORITY_CLASS: u32 = 0x0000_4000;
 
-    if !output.status.success() {
-        // Try to read log file for error details
-        let log_content = std::fs::read_to_string(&log_path).unwrap_or_default();
-        let stderr = String::from_utf8_lossy(&output.stderr);
-        return Err(format!(
-            "LTspice failed: {}\n{}",
-            stderr,
-            log_content
-        )
-        .into());
-    }
+/// Variables passed through to simulator processes; everything else but PATH is dropped
+/// HOME and the Windows profile folders hold LTspice's preferences, WINEPREFIX its install under Wine
+const SIMULATOR_ENV_VARS: &[&str] = &[
+    "HOME", "USER", "LANG", "LC_ALL", "TMPDIR", "TEMP", "TMP", "DISPLAY", "WINEPREFIX",
+    "SPICE_LIB_DIR", "SPICE_SCRIPTS", "SystemRoot", "windir", "USERPROFILE", "APPDATA", "LOCALAPPDATA",
+];
 
-    // Check if raw file exists
-    if !raw_path.exists() {
-        return Err("No .raw file generated - simulation may have failed".into());
-    }
+/// System directories kept on a simulator's PATH, after the simulator's own directory
+#[cfg(unix)]
+const SIMULATOR_SYSTEM_PATH: &[&str] = &["/usr/local/bin", "/opt/homebrew/bin", "/usr/bin", "/bin"];
 
-    // Parse the raw file
-    log::info!("Parsing raw file: {:?}", raw_path);
-    let results = parse_raw_file(&raw_path)?;
+/// How often resource usage of a running simulator is reported
+const RESOURCE_SAMPLE_INTERVAL: Duration = Duration::from_secs(2);
 
-    Ok(results)
-}
+/// Receives resource usage samples while a simulator runs
+pub type ResourceSink = Arc<dyn Fn(ResourceUsage) + Send + Sync>;
 
-/// Run an ngspice simulation
-/// The process_id_holder will be updated with the PID when the process starts
-pub async fn run_ngspice_simulation(
-    ngspice_path: &str,
-    netlist: &str,
-    _waveform_quality: &str,
-    process_id_holder: Option<Arc<AtomicU32>>,
-) -> Result<SimulationResults, Box<dyn std::error::Error + Send + Sync>> {
-    // Create temp directory with kelicad prefix
-    let temp_dir = Builder::new().prefix("kelicad-ngspice-").tempdir()?;
-    log::info!("Created temp directory for ngspice: {:?}", temp_dir.path());
-    let netlist_path = temp_dir.path().join("circuit.cir");
-    let raw_path = temp_dir.path().join("circuit.raw");
-
-    // Prepare netlist with .control section for raw output
-    let prepared_netlist = prepare_ngspice_netlist(netlist, &raw_path);
-    std::fs::write(&netlist_path, &prepared_netlist)?;
+/// Receives what each simulator run was given, just before it starts
+pub type DebugSink = Arc<dyn Fn(DebugInfo) + Send + Sync>;
 
-    log::info!("Running ngspice simulation...");
+/// Whether the simulation has been cancelled, for runs with no process to kill
+pub type CancelCheck = Arc<dyn Fn() -> bool + Send + Sync>;
 
-    // Run ngspice in batch mode
-    let output = tokio::task::spawn_blocking({
-        let ngspice_path = ngspice_path.to_string();
-        let netlist_path = netlist_path.clone();
-        move || {
-            let child = Command::new(&ngspice_path)
-                .arg("-b")  // batch mode
-                .arg(&netlist_path)
-                .stdout(std::process::Stdio::piped())
-                .stderr(std::process::Stdio::piped())
-                .spawn()?;
+/// Receives stage transitions while a simulation runs
+pub type ProgressSender = tokio::sync::mpsc::Sender<ProgressStage>;
 
-            let pid = child.id();
-            log::info!("ngspice process started with PID: {}", pid);
+/// Receives simulator output line by line while it runs
+pub type LogSink = tokio::sync::mpsc::UnboundedSender<LogLine>;
 
-            // Store the PID in the holder if provided
-            if let Some(holder) = process_id_holder {
-                holder.store(pid, Ordering::SeqCst);
-            }
+/// First LTspice release that takes library search directories as `-I<dir>`
+const LTSPICE_INCLUDE_DIRS_VERSION: (u32, u32) = (24, 1);
 
-            // Wait for the process to complete
-            let output = child.wait_with_output()?;
-            Ok::<_, std::io::Error>(output)
-        }
-    })
-    .await??;
+/// Most library search paths one request may give
+pub const MAX_LIBRARY_SEARCH_PATHS: usize = 16;
 
-    // ngspice returns non-zero for various reasons, check stderr for actual errors
-    let stderr = String::from_utf8_lossy(&output.stderr);
-    let stdout = String::from_utf8_lossy(&output.stdout);
+/// Directories a request's `.include`/`.lib` files are looked up in, before LTspice's library
+/// directory, and how the libraries found are made available
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LibrarySearchPaths {
+    pub dirs: Vec<PathBuf>,
+    /// Pass the directories to LTspice as `-I<dir>`; otherwise the libraries found in them are
+    /// placed by `strategy`
+    pub on_command_line: bool,
+    pub strategy: LibraryStrategy,
+    /// Values of variables in include paths, used before the environment's
+    pub variables: BTreeMap<String, String>,
+}
 
-    // Log output for debugging
-    if !stdout.is_empty() {
-        log::info!("ngspice stdout: {}", stdout);
-    }
-    if !stderr.is_empty() {
-        log::warn!("ngspice stderr: {}", stderr);
+impl LibrarySearchPaths {
+    /// The include's file in the first search path holding it, by its path or else its file name,
+    /// with the name it was found by
+    fn find<'a>(&self, path_str: &'a str, file_name: &'a str) -> Option<(PathBuf, &'a str)> {
+        self.dirs
+            .iter()
+            .flat_map(|dir| [path_str, file_name].map(|name| (dir.join(name), name)))
+            .find(|(path, _)| path.is_file())
     }
+}
 
-    // Check for fatal errors in output
-    let combined_output = format!("{}\n{}", stdout, stderr);
-    if let Some(error_msg) = extract_ngspice_error(&combined_output) {
-        return Err(error_msg.into());
+/// Whether an LTspice version takes `-I<dir>`; unknown versions get the libraries copied instead
+pub fn ltspice_takes_include_dirs(version: Option<&str>) -> bool {
+    let Some(version) = version else {
+        return false;
+    };
+    let mut parts = version.trim().split('.').map(|part| part.parse::<u32>().ok());
+    match (parts.next().flatten(), parts.next().unwrap_or(Some(0))) {
+        (Some(major), Some(minor)) => (major, minor) >= LTSPICE_INCLUDE_DIRS_VERSION,
+        _ => false,
     }
+}
 
-    // Check if raw file exists
-    if !raw_path.exists() {
-        return Err(format!(
-            "No .raw file generated - simulation may have failed.\nStdout: {}\nStderr: {}",
-            stdout, stderr
-        ).into());
+/// Resolve a request's library search paths, each of which must be an existing directory
+/// inside one of `allowed_roots`
+pub fn check_library_search_paths(paths: &[String], allowed_roots: &[String]) -> Result<Vec<PathBuf>, String> {
+    if paths.is_empty() {
+        return Ok(Vec::new());
+    }
+    if allowed_roots.is_empty() {
+        return Err(
+            "Library search paths are not allowed; add a directory to allowed_library_roots in the settings file"
+                .to_string(),
+        );
+    }
+    if paths.len() > MAX_LIBRARY_SEARCH_PATHS {
+        return Err(format!("{} library search paths given, maximum is {}", paths.len(), MAX_LIBRARY_SEARCH_PATHS));
     }
 
-    // Parse the raw file (ngspice uses ASCII format by default)
-    log::info!("Parsing ngspice raw file: {:?}", raw_path);
-    let results = parse_ngspice_raw_file(&raw_path)?;
-
-    Ok(results)
+    let roots: Vec<PathBuf> = allowed_roots.iter().filter_map(|root| std::fs::canonicalize(root).ok()).collect();
+    paths
+        .iter()
+        .map(|path| {
+            // Canonical, so `..` and symlinks can't lead out of a root
+            let dir = std::fs::canonicalize(path)
+                .ok()
+                .filter(|dir| dir.is_dir())
+                .ok_or_else(|| format!("Library search path {} is not a directory", path))?;
+            if !roots.iter().any(|root| dir.starts_with(root)) {
+                return Err(format!("Library search path {} is outside the allowed library roots", path));
+            }
+            Ok(dir)
+        })
+        .collect()
 }
 
-/// Extract meaningful error message from ngspice output
-/// Returns Some(error_message) if errors found, None otherwise
-fn extract_ngspice_error(output: &str) -> Option<String> {
-    let lines: Vec<&str> = output.lines().collect();
-    let mut error_lines: Vec<String> = Vec::new();
-    let mut found_error = false;
-
-    for (i, line) in lines.iter().enumerate() {
-        let lower = line.to_lowercase();
-
-        // Skip notes and warnings, and "0 error" lines
-        if lower.contains("note:") || lower.contains("warning:") || lower.contains("0 error") {
-            continue;
-        }
-
-        // Capture error lines and context
-        if lower.contains("error") {
-            found_error = true;
-
-            // Include previous line if it has line number info
-            if i > 0 {
-                let prev = lines[i - 1];
-                if prev.to_lowercase().contains("line") && prev.contains(char::is_numeric) {
-                    if !error_lines.contains(&prev.trim().to_string()) {
-                        error_lines.push(prev.trim().to_string());
-                    }
-                }
-            }
+/// Controls for a spawned simulator process
+#[derive(Clone, Default)]
+pub struct ProcessOptions {
+    /// Updated with the PID when the process starts
+    pub process_id_holder: Option<Arc<AtomicU32>>,
+    /// Kill the process once it has run for this long
+    pub hard_timeout: Option<Duration>,
+    /// Kill the process if it shows no CPU or file activity for this long
+    pub stall_timeout: Option<Duration>,
+    /// Give up on an LTspice batch run that has neither written its log nor used CPU for this
+    /// long, since it is most likely showing a first-run dialog
+    pub first_run_grace: Option<Duration>,
+    /// Run the process below normal priority so the desktop stays responsive
+    pub background_priority: bool,
+    /// Cap on ngspice's OpenMP threads, set in the injected `.control` block
+    pub ngspice_threads: Option<u32>,
+    /// LTspice switches from the request's engine options, e.g. `-alt`
+    pub ltspice_flags: Vec<&'static str>,
+    /// Bundled libraries LTspice runs may substitute for includes its library directory lacks
+    pub bundled_libraries: Vec<String>,
+    /// Directories the request asked LTspice to look for libraries in
+    pub library_search_paths: LibrarySearchPaths,
+    /// Signals the request asked to save besides those the netlist's `.probe`/`.plot` name
+    pub save_signals: Vec<String>,
+    /// Read only the raw file's header, returning results with empty data arrays
+    pub peek: bool,
+    /// Leave out `.backanno` and the quality profile's `.options`, adding only what output needs
+    pub no_auto_options: bool,
+    /// Called every couple of seconds with the process's resource usage
+    pub resource_sink: Option<ResourceSink>,
+    /// Forward stdout/stderr lines as they are printed
+    pub log_sink: Option<LogSink>,
+    /// Set to the Unix time in ms whenever the simulator writes to its work dir or streamed output
+    pub log_activity: Option<Arc<AtomicU64>>,
+    /// Called with the prepared netlist and command line of each run
+    pub debug_sink: Option<DebugSink>,
+    /// Polled by in-process ngspice runs, which stop when it returns true; a simulator process
+    /// that dies of a kill while it returns true ends the run with `SimulationCancelled`
+    pub cancelled: Option<CancelCheck>,
+    /// Filled in with how long each step of the run took
+    pub timings: Option<Arc<std::sync::Mutex<SimulationTimings>>>,
+    /// Copy the raw file here once it has been parsed, so it outlives the temp dir
+    pub keep_raw: Option<PathBuf>,
+    /// Have LTspice write a node as WAV audio into the temp dir, read back once the raw file is parsed
+    pub wave: Option<WaveCapture>,
+    /// Directory to create the run's temp dir in; the system temp dir when None
+    pub temp_root: Option<PathBuf>,
+    /// Filled in with the library files the run used, as resolved and as the simulator reported them
+    pub libraries_used: Option<Arc<std::sync::Mutex<Vec<LibraryUsed>>>>,
+    /// Persistent ngspice to send runs to instead of starting a process for each
+    pub ngspice_session: Option<Arc<NgspiceSession>>,
+}
 
-            error_lines.push(line.trim().to_string());
+impl ProcessOptions {
+    /// Directory the run's temp dir is created in
+    pub fn temp_root(&self) -> PathBuf {
+        self.temp_root.clone().unwrap_or_else(std::env::temp_dir)
+    }
 
-            // Include next line if it's a continuation (indented or has more detail)
-            if i + 1 < lines.len() {
-                let next = lines[i + 1];
-                if next.starts_with(' ') || next.starts_with('\t') {
-                    error_lines.push(next.trim().to_string());
-                }
+    /// Update the run's timings, if they are being collected
+    fn record_timings(&self, update: impl FnOnce(&mut SimulationTimings)) {
+        if let Some(timings) = &self.timings {
+            if let Ok(mut timings) = timings.lock() {
+                update(&mut timings);
             }
         }
-        // Also capture "Error on line X" or "line X:" context
-        else if lower.contains("error on line") || (lower.contains("line") && lower.contains(':') && line.contains(char::is_numeric)) {
-            error_lines.push(line.trim().to_string());
-            found_error = true;
-        }
     }
 
-    if !error_lines.is_empty() {
-        // Deduplicate while preserving order
-        let mut seen = std::collections::HashSet::new();
-        let unique_lines: Vec<String> = error_lines
-            .into_iter()
-            .filter(|line| seen.insert(line.clone()))
-            .collect();
-
-        let mut error_message = unique_lines.join("\n");
-
-        // Add KeliCAD recommendations for common mistakes
-        if let Some(recommendations) = get_kelicad_recommendations(output) {
-            error_message.push_str("\n\n");
-            error_message.push_str(&recommendations);
+    /// Record the libraries the run used, comparing what was resolved with what `log` says was loaded
+    fn record_libraries_used(&self, workspace: &SimulationWorkspace, log: &str) {
+        if let Some(libraries_used) = &self.libraries_used {
+            if let Ok(mut libraries_used) = libraries_used.lock() {
+                let loaded = libraries_used::loaded_from_log(log);
+                *libraries_used = libraries_used::report(workspace.included_libraries(), workspace.path(), &loaded);
+            }
         }
+    }
 
-        Some(error_message)
-    } else if found_error {
-        Some("ngspice simulation failed".to_string())
-    } else {
-        None
+    /// Add the time since `started` to one step of the run's timings
+    fn record_elapsed(&self, started: Instant, step: fn(&mut SimulationTimings) -> &mut u64) {
+        let elapsed = started.elapsed().as_millis() as u64;
+        self.record_timings(|timings| *step(timings) += elapsed);
     }
 }
 
-/// Get KeliCAD-specific recommendations for common SPICE mistakes
-fn get_kelicad_recommendations(output: &str) -> Option<String> {
-    let lower = output.to_lowercase();
-    let mut recommendations: Vec<&str> = Vec::new();
+/// Error returned when the watchdog had to kill a hung simulator
+#[derive(Debug)]
+pub struct SimulationHung {
+    pub reason: String,
+}
 
-    // PWL on resistor (resistors don't support PWL)
-    if (lower.contains("pwl") && lower.contains("resistor")) ||
-       lower.contains("unknown parameter") && lower.contains("pwl") {
-        recommendations.extend_from_slice(&[
-            "💡 KeliCAD Tip: Resistors cannot use PWL (Piece-Wise Linear). PWL is only for voltage/current sources.",
-            "Alternatives for variable resistance:",
-            "  • Use a fixed resistor: Rload node1 node2 10k",
-            "  • Use a behavioral current source: Gload node1 node2 cur='V(node1)/R_value'",
-            "  • Use ngspice behavioral resistor: Rload node1 node2 r='table(time, 0, 10, 1, 20)'"
-        ]);
+impl std::fmt::Display for SimulationHung {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.reason)
     }
+}
 
-    // Floating node
-    if lower.contains("floating") || lower.contains("no dc path to ground") {
-        recommendations.extend_from_slice(&[
-            "💡 KeliCAD Tip: Floating node detected - every node needs a DC path to ground.",
-            "Solutions:",
-            "  • Add a high-value resistor to ground: Rbias floating_node 0 1G",
-            "  • Check that all components are properly connected"
-        ]);
-    }
+impl std::error::Error for SimulationHung {}
 
-    // Voltage source loop
-    if lower.contains("voltage source loop") || lower.contains("voltage loop") {
-        recommendations.extend_from_slice(&[
-            "💡 KeliCAD Tip: Voltage sources in parallel create an invalid circuit.",
-            "Solutions:",
-            "  • Add a small series resistance between voltage sources",
-            "  • Remove one of the parallel voltage sources"
-        ]);
-    }
+/// Error returned when LTspice sat idle without writing its log, as it does while a first-run
+/// dialog (a model update or the preferences) waits for someone to click through it
+#[derive(Debug)]
+pub struct LtspiceNeedsFirstRun {
+    pub idle_secs: u64,
+}
 
-    // Inductor loop / current source cutset
-    if lower.contains("inductor loop") || lower.contains("current source cutset") {
-        recommendations.extend_from_slice(&[
-            "💡 KeliCAD Tip: Current sources in series or inductors in loops need damping.",
-            "Solutions:",
-            "  • Add a parallel resistor across inductors",
-            "  • Ensure current sources have parallel paths"
-        ]);
+impl std::fmt::Display for LtspiceNeedsFirstRun {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "LTspice sat idle for {}s without starting the simulation, probably behind a first-run dialog. \
+             Open LTspice once from its shortcut, close any update or preferences dialogs, then run the simulation again",
+            self.idle_secs
+        )
     }
+}
 
-    // Missing .end
-    if lower.contains("missing .end") || lower.contains("no .end") {
-        recommendations.push("💡 KeliCAD Tip: Every netlist must end with .end directive.");
-    }
+impl std::error::Error for LtspiceNeedsFirstRun {}
 
-    // Unknown device/model
-    if lower.contains("unknown device") || lower.contains("unknown model") || lower.contains("model not found") {
-        recommendations.extend_from_slice(&[
-            "💡 KeliCAD Tip: Make sure to include the model library with .include or .lib directive.",
-            "Example: .include standard.lib"
-        ]);
-    }
+/// Error returned when the simulator executable couldn't be started because it is gone, e.g.
+/// after an upgrade installed it somewhere else
+#[derive(Debug)]
+pub struct SimulatorMissing {
+    pub path: String,
+}
 
-    if !recommendations.is_empty() {
-        Some(recommendations.join("\n"))
-    } else {
-        None
+impl std::fmt::Display for SimulatorMissing {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} could not be started because it no longer exists", self.path)
     }
 }
 
-/// Prepare netlist for ngspice with .control section
-fn prepare_ngspice_netlist(netlist: &str, raw_path: &PathBuf) -> String {
-    let mut lines: Vec<String> = netlist.lines().map(|s| s.to_string()).collect();
+impl std::error::Error for SimulatorMissing {}
 
-    // Find the .end line
-    let end_idx = lines.iter().position(|l| l.trim().to_lowercase() == ".end");
+/// Error returned when an include path uses a variable that is set neither in the settings'
+/// `path_variables` nor in the environment
+#[derive(Debug)]
+pub struct LibraryMissing {
+    /// The include's path as written in the netlist
+    pub include: String,
+    /// The unset variable, as written in the path
+    pub variable: String,
+}
 
-    // Check if there's already a .control section
-    let has_control = netlist.to_lowercase().contains(".control");
+impl std::fmt::Display for LibraryMissing {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Library {} could not be found: {} is not set in the environment or in path_variables",
+            self.include, self.variable
+        )
+    }
+}
+
+impl std::error::Error for LibraryMissing {}
+
+/// Error returned when a cancel killed the simulator process before it finished
+#[derive(Debug)]
+pub struct SimulationCancelled;
+
+impl std::fmt::Display for SimulationCancelled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Simulation was cancelled")
+    }
+}
+
+impl std::error::Error for SimulationCancelled {}
+
+/// Error returned when the netlist asked for a sweep but the raw file holds only the operating
+/// point, as when LTspice solves the bias point and then fails the transient
+#[derive(Debug)]
+pub struct AnalysisIncomplete {
+    pub message: String,
+    /// Each variable of the operating point plot
+    pub operating_point: BTreeMap<String, f64>,
+}
+
+impl std::fmt::Display for AnalysisIncomplete {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for AnalysisIncomplete {}
+
+/// Exit status `nice` reports when it can't find the command it was given
+#[cfg(unix)]
+const NICE_NOT_FOUND_STATUS: i32 = 127;
+
+/// Error returned when the simulator gave up because the circuit did not converge
+#[derive(Debug)]
+pub struct ConvergenceFailure {
+    pub message: String,
+}
+
+impl std::fmt::Display for ConvergenceFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for ConvergenceFailure {}
+
+/// Bytes of a raw file read to find its plot name, enough for any header's first lines
+const RAW_PLOT_NAME_SCAN_BYTES: u64 = 4096;
+
+/// Bytes of an LTspice raw file first decoded looking for the end of its header, doubled until
+/// the header fits
+const RAW_HEADER_WINDOW: usize = 64 * 1024;
+
+/// Lowercase phrases LTspice and ngspice print when the operating point or a time step fails
+const CONVERGENCE_FAILURE_PHRASES: &[&str] = &[
+    "time step too small",
+    "timestep too small",
+    "singular matrix",
+    "gmin stepping failed",
+    "source stepping failed",
+    "iteration limit reached",
+    "no convergence",
+];
+
+/// Error returned when the simulator could not read the netlist
+#[derive(Debug)]
+pub struct NetlistParseError {
+    pub message: String,
+}
+
+impl std::fmt::Display for NetlistParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for NetlistParseError {}
+
+/// Lowercase phrases LTspice and ngspice print when a netlist line uses syntax they don't accept
+const NETLIST_PARSE_PHRASES: &[&str] = &[
+    "syntax error",
+    "parse error",
+    "error on line",
+    "unknown parameter",
+    "unknown function",
+    "unknown dot command",
+    "unknown device type",
+    "unrecognized",
+    "expression err",
+    "unimplemented",
+];
+
+/// Lowercase phrases in a simulator's log that explain a raw file cut short
+const TRUNCATION_CAUSE_PHRASES: &[&str] = &[
+    "no space",
+    "disk full",
+    "disk is full",
+    "out of memory",
+    "insufficient memory",
+    "cannot allocate",
+    "write error",
+    "failed to write",
+    "aborted",
+    "interrupted",
+    "fatal",
+    "timestep too small",
+];
+
+/// Options added by the stepping fallback
+pub const STEPPING_OPTIONS: &str = ".options gminsteps=200 srcsteps=100 cshunt=1e-15";
+
+/// Free space always required on the temp filesystem, even for tiny simulations
+const MIN_FREE_DISK_BYTES: u64 = 50 * 1024 * 1024;
+
+/// Reason a simulation was refused before the simulator was launched
+#[derive(Debug)]
+pub enum PreflightError {
+    NoAnalysisDirective,
+    InsufficientDiskSpace { path: PathBuf, free_bytes: u64, required_bytes: u64 },
+    TempDirNotWritable { path: PathBuf, reason: String },
+}
+
+impl PreflightError {
+    /// Machine-readable code reported to the client
+    pub fn error_code(&self) -> &'static str {
+        match self {
+            PreflightError::NoAnalysisDirective => "NO_ANALYSIS_DIRECTIVE",
+            PreflightError::InsufficientDiskSpace { .. } => "INSUFFICIENT_DISK_SPACE",
+            PreflightError::TempDirNotWritable { .. } => "TEMP_DIR_NOT_WRITABLE",
+        }
+    }
+}
+
+impl std::fmt::Display for PreflightError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PreflightError::NoAnalysisDirective => write!(
+                f,
+                "Netlist has no analysis directive; add one of {}",
+                netlist::ANALYSIS_DIRECTIVES.join(", ")
+            ),
+            PreflightError::InsufficientDiskSpace { path, free_bytes, required_bytes } => write!(
+                f,
+                "Not enough free disk space for simulation in {}: {} MB free, {} MB required",
+                path.display(),
+                free_bytes / (1024 * 1024),
+                required_bytes.div_ceil(1024 * 1024)
+            ),
+            PreflightError::TempDirNotWritable { path, reason } => {
+                write!(f, "Temp directory {} is not writable: {}", path.display(), reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for PreflightError {}
+
+/// Check the netlist runs an analysis, and `temp_root` is writable and has room for `runs`
+/// result files
+/// `fallback_required_bytes` is used when the output size cannot be estimated
+pub fn preflight_check(
+    netlist: &str,
+    quality: &QualityProfile,
+    runs: u64,
+    fallback_required_bytes: u64,
+    temp_root: &Path,
+) -> Result<(), PreflightError> {
+    // Without an analysis LTspice stalls before failing with a missing raw file
+    if !netlist::has_analysis(netlist) {
+        return Err(PreflightError::NoAnalysisDirective);
+    }
+
+    Builder::new()
+        .prefix("kelicad-check-")
+        .tempfile_in(temp_root)
+        .map_err(|e| PreflightError::TempDirNotWritable {
+            path: temp_root.to_path_buf(),
+            reason: e.to_string(),
+        })?;
+
+    let required_bytes = match estimate_raw_file_bytes(netlist, quality) {
+        Some(estimate) => estimate.saturating_mul(runs.max(1)).max(MIN_FREE_DISK_BYTES),
+        None => fallback_required_bytes,
+    };
+
+    match available_disk_space(temp_root) {
+        Some(free_bytes) if free_bytes < required_bytes => Err(PreflightError::InsufficientDiskSpace {
+            path: temp_root.to_path_buf(),
+            free_bytes,
+            required_bytes,
+        }),
+        Some(_) => Ok(()),
+        None => {
+            log::warn!("Could not determine free space for {:?}; skipping disk check", temp_root);
+            Ok(())
+        }
+    }
+}
+
+/// An analysis the agent can run and which simulators support it
+struct AnalysisSupport {
+    /// Capability name sent to clients
+    name: &'static str,
+    /// Directive without the dot
+    directive: &'static str,
+    ltspice: bool,
+    ngspice: bool,
+    notes: Option<&'static str>,
+}
+
+const ANALYSIS_SUPPORT: &[AnalysisSupport] = &[
+    AnalysisSupport { name: "transient", directive: "tran", ltspice: true, ngspice: true, notes: None },
+    AnalysisSupport { name: "ac", directive: "ac", ltspice: true, ngspice: true, notes: None },
+    AnalysisSupport { name: "dc", directive: "dc", ltspice: true, ngspice: true, notes: None },
+    AnalysisSupport { name: "noise", directive: "noise", ltspice: true, ngspice: true, notes: None },
+    AnalysisSupport { name: "op", directive: "op", ltspice: true, ngspice: true, notes: None },
+    AnalysisSupport {
+        name: "tf",
+        directive: "tf",
+        ltspice: true,
+        ngspice: true,
+        notes: Some("Returns named values instead of traces"),
+    },
+    AnalysisSupport {
+        name: "sens",
+        directive: "sens",
+        ltspice: false,
+        ngspice: true,
+        notes: Some("Returns named values instead of traces"),
+    },
+    AnalysisSupport { name: "pz", directive: "pz", ltspice: false, ngspice: true, notes: None },
+];
+
+/// Every known analysis with the detected simulators that can run it
+pub fn capabilities_for(ltspice: bool, ngspice: bool) -> Vec<AnalysisCapability> {
+    ANALYSIS_SUPPORT
+        .iter()
+        .map(|support| {
+            let mut simulators = Vec::new();
+            if ltspice && support.ltspice {
+                simulators.push("ltspice".to_string());
+            }
+            if ngspice && support.ngspice {
+                simulators.push("ngspice".to_string());
+            }
+            // Say what to install when no detected simulator can run it
+            let notes = match (simulators.is_empty(), support.ltspice, support.ngspice) {
+                (true, false, true) => Some("Requires ngspice, which was not found".to_string()),
+                (true, true, false) => Some("Requires LTspice, which was not found".to_string()),
+                (true, true, true) => Some("Requires LTspice or ngspice; neither was found".to_string()),
+                _ => support.notes.map(str::to_string),
+            };
+            AnalysisCapability { name: support.name.to_string(), simulators, notes }
+        })
+        .collect()
+}
+
+/// Reject a netlist asking for an analysis `simulator` can't run, naming one that can
+pub fn check_analyses(netlist: &str, simulator: &str) -> Result<(), String> {
+    for analysis in netlist::parse(netlist).analyses {
+        let directive = analysis.directive().to_lowercase();
+        let Some(support) = ANALYSIS_SUPPORT.iter().find(|s| s.directive == directive) else {
+            continue;
+        };
+        let supported = match simulator {
+            "ngspice" => support.ngspice,
+            _ => support.ltspice,
+        };
+        if !supported {
+            let (name, other) = match simulator {
+                "ngspice" => ("ngspice", "LTspice"),
+                _ => ("LTspice", "ngspice"),
+            };
+            return Err(format!(".{} analysis is not supported by {}; run it with {}", directive, name, other));
+        }
+    }
+    Ok(())
+}
+
+/// Add `default_analysis` before `.end` if the netlist has no analysis of its own
+pub fn apply_default_analysis<'a>(netlist: &'a str, default_analysis: Option<&str>) -> Cow<'a, str> {
+    let Some(directive) = default_analysis.map(str::trim).filter(|d| !d.is_empty()) else {
+        return Cow::Borrowed(netlist);
+    };
+    if netlist::has_analysis(netlist) {
+        return Cow::Borrowed(netlist);
+    }
+
+    log::info!("Netlist has no analysis directive; adding {}", directive);
+    let mut lines: Vec<&str> = netlist.lines().collect();
+    match end_line(&lines) {
+        Some(end_idx) => lines.insert(end_idx, directive),
+        None => lines.push(directive),
+    }
+    Cow::Owned(lines.join("\n"))
+}
+
+/// Free bytes on the filesystem holding `path`, if it can be determined
+pub(crate) fn available_disk_space(path: &Path) -> Option<u64> {
+    let path = canonical_path(path);
+    let disks = Disks::new_with_refreshed_list();
+    disks
+        .list()
+        .iter()
+        .filter(|disk| path.starts_with(disk.mount_point()))
+        .max_by_key(|disk| disk.mount_point().as_os_str().len())
+        .map(|disk| disk.available_space())
+}
+
+/// Rough upper bound on the .raw file a transient analysis will write
+/// Returns None if the size can't be predicted: no `.tran` time step, or LTspice
+/// waveform compression (non-zero plotwinsize) makes the output size data dependent
+pub fn estimate_raw_file_bytes(netlist: &str, quality: &QualityProfile) -> Option<u64> {
+    let lower = netlist.to_lowercase();
+
+    let plotwinsize = lower
+        .lines()
+        .find_map(|line| line.trim().strip_prefix(".options plotwinsize="))
+        .and_then(|value| value.trim().parse::<u32>().ok())
+        .or(quality.plotwinsize())
+        // LTspice compresses waveforms by default
+        .unwrap_or(300);
+    if plotwinsize != 0 {
+        return None;
+    }
+
+    // .tran <tstep> <tstop> [<tstart> [<dtmax>]]; the single-argument LTspice form gives no step
+    let tran: Vec<f64> = lower
+        .lines()
+        .find_map(|line| line.trim().strip_prefix(".tran "))?
+        .split_whitespace()
+        .map_while(netlist::parse_spice_number)
+        .collect();
+    let (tstep, tstop) = (*tran.first()?, *tran.get(1)?);
+    // The smaller of tstep and dtmax bounds the point spacing; a zero tstep means "auto"
+    let step = [Some(tstep), tran.get(3).copied()]
+        .into_iter()
+        .flatten()
+        .filter(|v| *v > 0.0)
+        .fold(f64::INFINITY, f64::min);
+    if !step.is_finite() || tstop <= 0.0 {
+        return None;
+    }
+    let points = (tstop / step).min(1e10);
+
+    // Each element contributes roughly one node voltage and one device current
+    let elements = lower
+        .lines()
+        .map(str::trim)
+        .filter(|line| line.chars().next().is_some_and(|c| c.is_ascii_alphabetic()))
+        .count();
+    let vectors = 1 + 2 * elements as u64;
+
+    Some((points as u64).saturating_mul(vectors).saturating_mul(8))
+}
+
+/// A new temp directory in `temp_root`, its name starting with `prefix`
+/// The error names `temp_root`, so a locked-down or full override is easy to spot
+fn create_temp_dir(prefix: &str, temp_root: &Path) -> Result<TempDir, String> {
+    Builder::new()
+        .prefix(prefix)
+        .tempdir_in(temp_root)
+        .map_err(|e| format!("Could not create a temp directory in {}: {}", temp_root.display(), e))
+}
+
+/// Temp directory with a netlist's libraries already resolved
+/// Batch simulations reuse one workspace so includes are copied only once
+pub struct SimulationWorkspace {
+    temp_dir: TempDir,
+    netlist: String,
+    included_libraries: Vec<IncludedLibrary>,
+}
+
+impl SimulationWorkspace {
+    /// Create the temp directory in `temp_root`, write the attachments into it and resolve includes
+    /// for the given simulator
+    /// `bundled_libraries` may be substituted for includes LTspice's library directory doesn't have
+    pub fn prepare(
+        simulator: &str,
+        netlist: &str,
+        attachments: &[WorkspaceFile],
+        bundled_libraries: &[String],
+        search_paths: &LibrarySearchPaths,
+        temp_root: &Path,
+    ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let netlist = pin_relative_includes(&use_attachments(netlist, attachments));
+        match simulator {
+            "ngspice" => {
+                let temp_dir = create_temp_dir(NGSPICE_TEMP_PREFIX, temp_root)?;
+                log::info!("Created temp directory for ngspice: {:?}", temp_dir.path());
+                write_workspace_files(temp_dir.path(), attachments)?;
+                // ngspice resolves .include/.lib itself
+                let included_libraries = attachments
+                    .iter()
+                    .map(|file| IncludedLibrary {
+                        name: file.name.clone(),
+                        source: "attachment".to_string(),
+                        strategy: LibraryStrategy::Copy,
+                        expanded_from: None,
+                    })
+                    .collect();
+                Ok(Self { temp_dir, netlist, included_libraries })
+            }
+            _ => {
+                // Create temp directory with kelicad prefix
+                let temp_dir = create_temp_dir(LTSPICE_TEMP_PREFIX, temp_root)?;
+                log::info!("Created temp directory: {:?}", temp_dir.path());
+                write_workspace_files(temp_dir.path(), attachments)?;
+
+                // Process includes - copy standard libraries to temp dir and update paths
+                let (processed_netlist, included_libraries) =
+                    process_includes(&netlist, temp_dir.path(), attachments, bundled_libraries, search_paths)?;
+                Ok(Self { temp_dir, netlist: processed_netlist, included_libraries })
+            }
+        }
+    }
+
+    /// Path of the temp directory
+    pub fn path(&self) -> &Path {
+        self.temp_dir.path()
+    }
+
+    /// Netlist with include paths rewritten to the local copies
+    pub fn netlist(&self) -> &str {
+        &self.netlist
+    }
+
+    /// Libraries copied into the temp directory
+    pub fn included_libraries(&self) -> &[IncludedLibrary] {
+        &self.included_libraries
+    }
+
+    /// Stages completed by `prepare` for the given simulator
+    pub fn prepared_stages(&self, simulator: &str) -> Vec<ProgressStage> {
+        let mut stages = vec![ProgressStage::Preparing {
+            temp_dir: self.path().to_string_lossy().into_owned(),
+        }];
+        if simulator != "ngspice" {
+            stages.push(ProgressStage::ResolvingLibraries { included_files: self.included_libraries.len() });
+        }
+        stages
+    }
+}
+
+/// Report a stage transition, if anyone is listening
+async fn report_stage(progress: Option<&ProgressSender>, stage: ProgressStage) {
+    if let Some(tx) = progress {
+        let _ = tx.send(stage).await;
+    }
+}
+
+/// Report the stages of preparing a workspace
+async fn report_workspace_prepared(progress: Option<&ProgressSender>, workspace: &SimulationWorkspace, simulator: &str) {
+    for stage in workspace.prepared_stages(simulator) {
+        report_stage(progress, stage).await;
+    }
+}
+
+/// Apply a batch corner's `.param` and `.temp` overrides to a netlist
+/// Existing `.param` assignments are rewritten in place, new ones are inserted before `.end`
+pub fn apply_corner_overrides(netlist: &str, corner: &BatchCorner) -> String {
+    let mut lines: Vec<String> = netlist.lines().map(|s| s.to_string()).collect();
+    let mut pending: Vec<(String, String)> = Vec::new();
+
+    for (name, value) in &corner.params {
+        let value = match value {
+            serde_json::Value::String(s) => s.clone(),
+            other => other.to_string(),
+        };
+
+        let assignment = Regex::new(&format!(r"(?i)(^|[\s,])({})\s*=\s*(\{{[^}}]*\}}|[^\s,]+)", regex::escape(name)))
+            .expect("escaped parameter name is a valid regex");
+        let mut replaced = false;
+        for line in lines.iter_mut() {
+            if !line.trim_start().to_lowercase().starts_with(".param") {
+                continue;
+            }
+            if assignment.is_match(line) {
+                *line = assignment
+                    .replace_all(line, |caps: &regex::Captures| format!("{}{}={}", &caps[1], &caps[2], value))
+                    .to_string();
+                replaced = true;
+            }
+        }
+
+        if !replaced {
+            pending.push((name.clone(), value));
+        }
+    }
+
+    let mut directives: Vec<String> = pending
+        .into_iter()
+        .map(|(name, value)| format!(".param {}={}", name, value))
+        .collect();
+
+    if let Some(temperature) = corner.temperature {
+        // Drop any existing .temp so the corner's value is the only one
+        lines.retain(|l| {
+            let lower = l.trim_start().to_lowercase();
+            !(lower == ".temp" || lower.starts_with(".temp ") || lower.starts_with(".temp\t"))
+        });
+        directives.push(format!(".temp {}", temperature));
+    }
+
+    let end_idx = end_line(&lines);
+    match end_idx {
+        Some(idx) => {
+            for (i, directive) in directives.into_iter().enumerate() {
+                lines.insert(idx + i, directive);
+            }
+        }
+        None => lines.extend(directives),
+    }
+
+    lines.join("\n")
+}
+
+/// Run an LTspice simulation
+pub async fn run_ltspice_simulation(
+    ltspice_path: &str,
+    netlist: &str,
+    attachments: &[WorkspaceFile],
+    quality: &QualityProfile,
+    process_options: &ProcessOptions,
+    progress: Option<&ProgressSender>,
+) -> Result<SimulationResults, Box<dyn std::error::Error + Send + Sync>> {
+    let started = Instant::now();
+    let workspace = SimulationWorkspace::prepare(
+        "ltspice",
+        netlist,
+        attachments,
+        &process_options.bundled_libraries,
+        &process_options.library_search_paths,
+        &process_options.temp_root(),
+    )?;
+    process_options.record_elapsed(started, |t| &mut t.include_resolution_ms);
+    report_workspace_prepared(progress, &workspace, "ltspice").await;
+    run_ltspice_in_workspace(
+        ltspice_path,
+        &workspace,
+        workspace.netlist(),
+        quality,
+        "circuit",
+        process_options,
+        progress,
+    )
+    .await
+}
+
+/// Run LTspice on a netlist inside an already prepared workspace
+/// `file_stem` names the .net/.raw/.log files so several runs can share one directory
+pub async fn run_ltspice_in_workspace(
+    ltspice_path: &str,
+    workspace: &SimulationWorkspace,
+    netlist: &str,
+    quality: &QualityProfile,
+    file_stem: &str,
+    process_options: &ProcessOptions,
+    progress: Option<&ProgressSender>,
+) -> Result<SimulationResults, Box<dyn std::error::Error + Send + Sync>> {
+    let netlist_path = workspace.path().join(format!("{}.net", file_stem));
+    let raw_path = workspace.path().join(format!("{}.raw", file_stem));
+    let log_path = workspace.path().join(format!("{}.log", file_stem));
+
+    // Prepare netlist with required directives
+    let started = Instant::now();
+    let wave_path = workspace.path().join(format!("{}.wav", file_stem));
+    let mut saved = saved_signals(netlist, &process_options.save_signals);
+    if let (Some(signals), Some(capture)) = (&mut saved, &process_options.wave) {
+        let signal = wave::signal(&capture.output.node);
+        if !signals.iter().any(|saved| saved.eq_ignore_ascii_case(&signal)) {
+            signals.push(signal);
+        }
+    }
+    let mut prepared_netlist = prepare_netlist(netlist, quality, !process_options.no_auto_options, saved.as_deref());
+    if let Some(capture) = &process_options.wave {
+        let mut lines: Vec<String> = prepared_netlist.lines().map(str::to_string).collect();
+        insert_before_end(&mut lines, wave::directive(&wave_path, &capture.output));
+        prepared_netlist = lines.join("\n");
+    }
+    std::fs::write(&netlist_path, &prepared_netlist)?;
+    process_options.record_elapsed(started, |t| &mut t.netlist_write_ms);
+    process_options.record_timings(|t| t.netlist_bytes = prepared_netlist.len() as u64);
+    let args = ltspice_batch_args(process_options);
+    let flags: Vec<&str> = args.iter().map(String::as_str).collect();
+    report_debug_info(process_options, workspace, netlist, &prepared_netlist, ltspice_path, &flags, &netlist_path);
+
+    log::info!("Running LTspice simulation...");
+
+    // Run LTspice in batch mode using spawn() so we can get the PID
+    let started = Instant::now();
+    let output = run_simulator_process(ltspice_path, &flags, &netlist_path, "LTspice", process_options, progress).await;
+    process_options.record_elapsed(started, |t| &mut t.simulator_ms);
+    let log_file_bytes = std::fs::metadata(&log_path).map(|m| m.len()).unwrap_or(0);
+    process_options.record_timings(|t| t.log_file_bytes = log_file_bytes);
+    if process_options.libraries_used.is_some() {
+        let log_content = std::fs::read(&log_path).map(|bytes| decode_log_text(&bytes)).unwrap_or_default();
+        process_options.record_libraries_used(workspace, &log_content);
+    }
+    let output = output?;
+    let parse_started = Instant::now();
+
+    if !output.status.success() {
+        // Try to read log file for error details
+        let log_content = std::fs::read(&log_path).map(|bytes| decode_log_text(&bytes)).unwrap_or_default();
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(run_failure(format!("LTspice failed: {}\n{}", stderr, log_content), &log_content));
+    }
+
+    // .tf results are written to the log; there is no plot to parse
+    if let Some(analysis) = scalar_analysis(netlist) {
+        let log_content = std::fs::read(&log_path).map(|bytes| decode_log_text(&bytes)).unwrap_or_default();
+        let results = scalar_results(analysis, parse_ltspice_transfer_function(&log_content))?;
+        process_options.record_elapsed(parse_started, |t| &mut t.raw_parse_ms);
+        report_stage(progress, ProgressStage::Complete).await;
+        return Ok(results);
+    }
+
+    // Check if raw file exists; LTspice can exit cleanly after failing to converge
+    if !raw_path.exists() {
+        let log_content = std::fs::read(&log_path).map(|bytes| decode_log_text(&bytes)).unwrap_or_default();
+        let message = match convergence_failure_line(&log_content) {
+            Some(line) => format!("LTspice failed: {}", line),
+            None => "No .raw file generated - simulation may have failed".to_string(),
+        };
+        return Err(run_failure(message, &log_content));
+    }
+
+    // Parse the raw file
+    log::info!("Parsing raw file: {:?}", raw_path);
+    report_parsing_started(progress, &raw_path, process_options).await;
+    let log_content = std::fs::read(&log_path).map(|bytes| decode_log_text(&bytes)).unwrap_or_default();
+    let parsed = if process_options.peek { peek::read_header(&raw_path).map_err(Into::into) } else { parse_raw_file(&raw_path, process_options) };
+    let mut results = with_noise_input_unit(explain_raw_file(parsed, &log_content)?, netlist);
+    check_requested_analysis(&results, netlist, &raw_path, &log_content)?;
+
+    // Bias points enabled by .backanno are only in the log
+    results.device_operating_points = parse_ltspice_operating_points(&log_content);
+    if let Some(capture) = &process_options.wave {
+        capture.collect(&wave_path, &results)?;
+    }
+    process_options.record_elapsed(parse_started, |t| &mut t.raw_parse_ms);
+    keep_raw_file(&raw_path, process_options);
+    report_stage(progress, ProgressStage::Complete).await;
+
+    Ok(results)
+}
+
+/// Run an ngspice simulation
+pub async fn run_ngspice_simulation(
+    ngspice_path: &str,
+    netlist: &str,
+    attachments: &[WorkspaceFile],
+    quality: &QualityProfile,
+    process_options: &ProcessOptions,
+    progress: Option<&ProgressSender>,
+) -> Result<SimulationResults, Box<dyn std::error::Error + Send + Sync>> {
+    let started = Instant::now();
+    let workspace = SimulationWorkspace::prepare(
+        "ngspice",
+        netlist,
+        attachments,
+        &[],
+        &LibrarySearchPaths::default(),
+        &process_options.temp_root(),
+    )?;
+    process_options.record_elapsed(started, |t| &mut t.include_resolution_ms);
+    report_workspace_prepared(progress, &workspace, "ngspice").await;
+    run_ngspice_in_workspace(
+        ngspice_path,
+        &workspace,
+        workspace.netlist(),
+        quality,
+        "circuit",
+        process_options,
+        progress,
+    )
+    .await
+}
+
+/// Run ngspice on a netlist inside an already prepared workspace
+/// `file_stem` names the .cir/.raw files so several runs can share one directory
+pub async fn run_ngspice_in_workspace(
+    ngspice_path: &str,
+    workspace: &SimulationWorkspace,
+    netlist: &str,
+    quality: &QualityProfile,
+    file_stem: &str,
+    process_options: &ProcessOptions,
+    progress: Option<&ProgressSender>,
+) -> Result<SimulationResults, Box<dyn std::error::Error + Send + Sync>> {
+    // ngspice doesn't log where it read includes from, so only what was resolved is known
+    process_options.record_libraries_used(workspace, "");
+
+    // In-process runs hand results straight over, so there is no netlist file or raw file
+    #[cfg(feature = "libngspice")]
+    {
+        let started = Instant::now();
+        if let Some(run) =
+            crate::ngspice_shared::run(ngspice_path, netlist, quality, process_options, progress).await?
+        {
+            process_options.record_elapsed(started, |t| &mut t.simulator_ms);
+            if let Some(sink) = &process_options.debug_sink {
+                let mut info = DebugInfo::new(
+                    &run.deck,
+                    workspace.included_libraries().to_vec(),
+                    format!("libngspice {}", run.library.display()),
+                    workspace.path().to_string_lossy().into_owned(),
+                );
+                info.injected_directives = injected_directives(netlist, &run.deck);
+                sink(info);
+            }
+            check_ngspice_output(&run.output)?;
+            let mut results = run.results.ok_or("libngspice produced no data - simulation may have failed")?;
+            if process_options.peek {
+                results = peek::without_data(results);
+            }
+            report_stage(progress, ProgressStage::Complete).await;
+            return Ok(with_noise_input_unit(results, netlist));
+        }
+    }
+
+    let netlist_path = workspace.path().join(format!("{}.cir", file_stem));
+    let raw_path = workspace.path().join(format!("{}.raw", file_stem));
+    let saved = saved_signals(netlist, &process_options.save_signals);
+
+    let session_output = match &process_options.ngspice_session {
+        Some(session) => {
+            let deck = ngspice_deck(
+                netlist,
+                quality,
+                !process_options.no_auto_options,
+                &raw_path,
+                process_options.ngspice_threads,
+                saved.as_deref(),
+            );
+            run_ngspice_in_session(session, ngspice_path, workspace, netlist, deck, &netlist_path, process_options, progress).await?
+        }
+        None => None,
+    };
+
+    let (stdout, stderr) = match session_output {
+        Some(SessionOutput { stdout, stderr }) => (stdout, stderr),
+        None => {
+            // Prepare netlist with .control section for raw output
+            let started = Instant::now();
+            let prepared_netlist =
+                prepare_ngspice_netlist(
+                    netlist,
+                    quality,
+                    !process_options.no_auto_options,
+                    &raw_path,
+                    process_options.ngspice_threads,
+                    saved.as_deref(),
+                );
+            std::fs::write(&netlist_path, &prepared_netlist)?;
+            process_options.record_elapsed(started, |t| &mut t.netlist_write_ms);
+            process_options.record_timings(|t| t.netlist_bytes = prepared_netlist.len() as u64);
+            report_debug_info(process_options, workspace, netlist, &prepared_netlist, ngspice_path, &["-b"], &netlist_path);
+
+            log::info!("Running ngspice simulation...");
+
+            // Run ngspice in batch mode
+            let started = Instant::now();
+            let output = run_batch_process(ngspice_path, &netlist_path, "ngspice", process_options, progress).await;
+            process_options.record_elapsed(started, |t| &mut t.simulator_ms);
+            let output = output?;
+            (String::from_utf8_lossy(&output.stdout).into_owned(), String::from_utf8_lossy(&output.stderr).into_owned())
+        }
+    };
+    let parse_started = Instant::now();
+
+    // ngspice returns non-zero for various reasons, check stderr for actual errors
+    // Log output for debugging
+    if !stdout.is_empty() {
+        log::info!("ngspice stdout: {}", stdout);
+    }
+    if !stderr.is_empty() {
+        log::warn!("ngspice stderr: {}", stderr);
+    }
+
+    let combined_output = format!("{}\n{}", stdout, stderr);
+    process_options.record_timings(|t| t.log_file_bytes = (stdout.len() + stderr.len()) as u64);
+    check_ngspice_output(&combined_output)?;
+
+    // .tf and .sens values are printed to stdout instead of written as a plot
+    if let Some(analysis) = scalar_analysis(netlist) {
+        let results = scalar_results(analysis, parse_ngspice_scalars(&stdout))?;
+        process_options.record_elapsed(parse_started, |t| &mut t.raw_parse_ms);
+        report_stage(progress, ProgressStage::Complete).await;
+        return Ok(results);
+    }
+
+    // Check if raw file exists
+    if !raw_path.exists() {
+        return Err(run_failure(
+            format!(
+                "No .raw file generated - simulation may have failed.\nStdout: {}\nStderr: {}",
+                stdout, stderr
+            ),
+            &combined_output,
+        ));
+    }
+
+    // Parse the raw file (ngspice uses ASCII format by default)
+    log::info!("Parsing ngspice raw file: {:?}", raw_path);
+    report_parsing_started(progress, &raw_path, process_options).await;
+    let parsed =
+        if process_options.peek { peek::read_header(&raw_path).map_err(Into::into) } else { parse_ngspice_raw_file(&raw_path, process_options) };
+    let results = with_noise_input_unit(explain_raw_file(parsed, &combined_output)?, netlist);
+    check_requested_analysis(&results, netlist, &raw_path, &combined_output)?;
+    process_options.record_elapsed(parse_started, |t| &mut t.raw_parse_ms);
+    keep_raw_file(&raw_path, process_options);
+    report_stage(progress, ProgressStage::Complete).await;
+
+    Ok(results)
+}
+
+/// Run a prepared deck through the persistent ngspice session
+/// None when it has a `.control` section of its own, which runs only as written, or the session
+/// couldn't take the run; it then runs in batch mode
+#[allow(clippy::too_many_arguments)]
+async fn run_ngspice_in_session(
+    session: &NgspiceSession,
+    ngspice_path: &str,
+    workspace: &SimulationWorkspace,
+    netlist: &str,
+    deck: NgspiceDeck,
+    netlist_path: &Path,
+    process_options: &ProcessOptions,
+    progress: Option<&ProgressSender>,
+) -> Result<Option<SessionOutput>, Box<dyn std::error::Error + Send + Sync>> {
+    let Some(commands) = deck.commands else {
+        return Ok(None);
+    };
+    let started = Instant::now();
+    let prepared_netlist = deck.lines.join("\n");
+    std::fs::write(netlist_path, &prepared_netlist)?;
+    let write_ms = started.elapsed().as_millis() as u64;
+
+    log::info!("Running ngspice simulation in the session...");
+    let started = Instant::now();
+    let Some(output) = session.run(ngspice_path, netlist_path, &commands, process_options).await? else {
+        return Ok(None);
+    };
+    process_options.record_elapsed(started, |t| &mut t.simulator_ms);
+    process_options.record_timings(|t| {
+        t.netlist_write_ms += write_ms;
+        t.netlist_bytes = prepared_netlist.len() as u64;
+    });
+    if let Some(sink) = &process_options.debug_sink {
+        let mut info = DebugInfo::new(
+            &prepared_netlist,
+            workspace.included_libraries().to_vec(),
+            format!("{} -p (session): {}", ngspice_path, commands.join("; ")),
+            workspace.path().to_string_lossy().into_owned(),
+        );
+        info.injected_directives = injected_directives(netlist, &prepared_netlist);
+        sink(info);
+    }
+    report_stage(progress, ProgressStage::Running { percent: None }).await;
+    Ok(Some(output))
+}
+
+/// Fail on errors ngspice printed, even when it went on to produce a plot
+fn check_ngspice_output(output: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    if let Some(error_msg) = extract_ngspice_error(output) {
+        return Err(run_failure(error_msg, output));
+    }
+
+    // An aborted transient can still leave a partial plot behind
+    if let Some(line) = output.lines().find(|l| l.to_lowercase().contains("timestep too small")) {
+        return Err(Box::new(ConvergenceFailure { message: line.trim().to_string() }));
+    }
+    Ok(())
+}
+
+/// Pass what is about to run to the debug sink, if there is one
+fn report_debug_info(
+    options: &ProcessOptions,
+    workspace: &SimulationWorkspace,
+    netlist: &str,
+    prepared_netlist: &str,
+    executable: &str,
+    flags: &[&str],
+    netlist_path: &Path,
+) {
+    if let Some(sink) = &options.debug_sink {
+        let command = simulator_command(executable, flags, netlist_path, options.background_priority);
+        let mut info = DebugInfo::new(
+            prepared_netlist,
+            workspace.included_libraries().to_vec(),
+            command_line(&command),
+            workspace.path().to_string_lossy().into_owned(),
+        );
+        info.injected_directives = injected_directives(netlist, prepared_netlist);
+        sink(info);
+    }
+}
+
+/// Lines of `prepared` that aren't in `netlist`, i.e. what was added to it; lines commented
+/// out along the way are left out
+fn injected_directives(netlist: &str, prepared: &str) -> Vec<String> {
+    let mut original: Vec<&str> = netlist.lines().map(str::trim).collect();
+    let mut injected = Vec::new();
+    for line in prepared.lines().map(str::trim) {
+        if let Some(i) = original.iter().position(|existing| *existing == line) {
+            original.swap_remove(i);
+        } else if !line.is_empty() && !line.starts_with('*') {
+            injected.push(line.to_string());
+        }
+    }
+    injected
+}
+
+/// A command as it would be typed in a shell, quoting arguments that contain spaces
+fn command_line(command: &Command) -> String {
+    std::iter::once(command.get_program())
+        .chain(command.get_args())
+        .map(|part| {
+            let part = part.to_string_lossy();
+            if part.contains(char::is_whitespace) {
+                format!("\"{}\"", part)
+            } else {
+                part.into_owned()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Read a whole raw file, counting the time in the run's timings
+/// Slow reads here rather than slow parsing point at a temp dir on a network share or slow disk
+fn read_raw_file(path: &Path, options: &ProcessOptions) -> std::io::Result<Vec<u8>> {
+    let started = Instant::now();
+    let data = std::fs::read(path);
+    options.record_elapsed(started, |t| &mut t.raw_read_ms);
+    data
+}
+
+/// Report that the raw file is being parsed, with its size
+async fn report_parsing_started(progress: Option<&ProgressSender>, raw_path: &Path, options: &ProcessOptions) {
+    let raw_file_bytes = std::fs::metadata(raw_path).map(|m| m.len()).unwrap_or(0);
+    options.record_timings(|timings| timings.raw_file_bytes = raw_file_bytes);
+    report_stage(progress, ProgressStage::ParsingResults { raw_file_bytes }).await;
+}
+
+/// Copy the raw file to where the options ask for it to be kept, if anywhere
+/// A failed copy doesn't fail the run; the artifact is just missing
+fn keep_raw_file(raw_path: &Path, options: &ProcessOptions) {
+    if let Some(target) = &options.keep_raw {
+        if let Err(e) = std::fs::copy(raw_path, target) {
+            log::warn!("Failed to keep raw file {}: {}", raw_path.display(), e);
+        }
+    }
+}
+
+/// Run a simulator in batch mode (`-b <netlist>`) and wait for it to exit
+/// The process is watched for the hard and stall timeouts in `options`
+async fn run_batch_process(
+    executable: &str,
+    netlist_path: &Path,
+    label: &'static str,
+    options: &ProcessOptions,
+    progress: Option<&ProgressSender>,
+) -> Result<std::process::Output, Box<dyn std::error::Error + Send + Sync>> {
+    run_simulator_process(executable, &["-b"], netlist_path, label, options, progress).await
+}
+
+/// LTspice's batch-mode arguments: the engine option switches, the library search directories
+/// when this LTspice takes them, then `-b`
+fn ltspice_batch_args(options: &ProcessOptions) -> Vec<String> {
+    let search_paths = &options.library_search_paths;
+    let include_dirs = search_paths
+        .dirs
+        .iter()
+        .filter(|_| search_paths.on_command_line)
+        .map(|dir| format!("-I{}", dir.to_string_lossy()));
+    options
+        .ltspice_flags
+        .iter()
+        .map(|flag| flag.to_string())
+        .chain(include_dirs)
+        .chain(["-b".to_string()])
+        .collect()
+}
+
+/// Run a simulator with `flags` on a file, e.g. `-netlist <schematic>`, and wait for it to exit
+async fn run_simulator_process(
+    executable: &str,
+    flags: &[&str],
+    netlist_path: &Path,
+    label: &'static str,
+    options: &ProcessOptions,
+    progress: Option<&ProgressSender>,
+) -> Result<std::process::Output, Box<dyn std::error::Error + Send + Sync>> {
+    let spawned = simulator_command(executable, flags, netlist_path, options.background_priority)
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .spawn();
+    let child = match spawned {
+        Ok(child) => child,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return Err(Box::new(SimulatorMissing { path: executable.to_string() }));
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    let pid = child.id();
+    log::info!("{} process started with PID: {}", label, pid);
+
+    // Store the PID in the holder if provided
+    if let Some(holder) = &options.process_id_holder {
+        holder.store(pid, Ordering::SeqCst);
+    }
+    // A cancel that came in before the PID was stored had nothing to kill yet
+    let cancelled = || options.cancelled.as_ref().is_some_and(|cancelled| cancelled());
+    if cancelled() {
+        kill_process_tree(pid);
+    }
+    report_stage(progress, ProgressStage::Launching { pid }).await;
+    report_stage(progress, ProgressStage::Running { percent: None }).await;
+
+    // Wait for the process to complete on a blocking thread
+    let (log_sink, log_activity) = (options.log_sink.clone(), options.log_activity.clone());
+    let wait = tokio::task::spawn_blocking(move || match log_sink {
+        Some(sink) => wait_with_streamed_output(child, sink, log_activity),
+        None => child.wait_with_output(),
+    });
+
+    // Only LTspice's batch mode writes a log as soon as it starts simulating
+    let first_run_grace = options.first_run_grace.filter(|_| label == "LTspice" && flags.contains(&"-b"));
+    let output = if options.hard_timeout.is_none()
+        && options.stall_timeout.is_none()
+        && options.resource_sink.is_none()
+        && first_run_grace.is_none()
+    {
+        wait.await??
+    } else {
+        watch_process(wait, pid, netlist_path, label, first_run_grace, options).await?
+    };
+
+    // Whatever the simulator left behind when killed is not a result
+    if cancelled() && killed(&output.status) {
+        return Err(Box::new(SimulationCancelled));
+    }
+
+    // Below normal priority the simulator is started through nice, which spawns fine either way
+    #[cfg(unix)]
+    if options.background_priority && output.status.code() == Some(NICE_NOT_FOUND_STATUS) {
+        return Err(Box::new(SimulatorMissing { path: executable.to_string() }));
+    }
+    Ok(output)
+}
+
+/// Like `wait_with_output`, but forwards each output line to `sink` as it is printed
+/// The full text is still collected so errors can be extracted afterwards
+fn wait_with_streamed_output(
+    mut child: std::process::Child,
+    sink: LogSink,
+    activity: Option<Arc<AtomicU64>>,
+) -> std::io::Result<std::process::Output> {
+    let stdout = child
+        .stdout
+        .take()
+        .map(|pipe| spawn_line_reader(pipe, "stdout", sink.clone(), activity.clone()));
+    let stderr = child
+        .stderr
+        .take()
+        .map(|pipe| spawn_line_reader(pipe, "stderr", sink, activity));
+
+    let status = child.wait()?;
+    let collect = |reader: Option<std::thread::JoinHandle<Vec<u8>>>| {
+        reader.and_then(|handle| handle.join().ok()).unwrap_or_default()
+    };
+
+    Ok(std::process::Output {
+        status,
+        stdout: collect(stdout),
+        stderr: collect(stderr),
+    })
+}
+
+/// Read a pipe on its own thread, sending every line to `sink` and returning all bytes read
+/// Carriage returns also end a line so ngspice's in-place progress updates come through
+/// Each read is noted in `activity`, if given
+fn spawn_line_reader<R: Read + Send + 'static>(
+    mut pipe: R,
+    stream: &'static str,
+    sink: LogSink,
+    activity: Option<Arc<AtomicU64>>,
+) -> std::thread::JoinHandle<Vec<u8>> {
+    std::thread::spawn(move || {
+        let mut collected = Vec::new();
+        let mut line = Vec::new();
+        let mut buf = [0u8; 4096];
+        let emit = |line: &[u8]| {
+            if !line.is_empty() {
+                let _ = sink.send(LogLine {
+                    stream: stream.to_string(),
+                    text: String::from_utf8_lossy(line).into_owned(),
+                });
+            }
+        };
+
+        loop {
+            let n = match pipe.read(&mut buf) {
+                Ok(0) | Err(_) => break,
+                Ok(n) => n,
+            };
+            if let Some(activity) = &activity {
+                activity.store(now_ms(), Ordering::SeqCst);
+            }
+            collected.extend_from_slice(&buf[..n]);
+            for &byte in &buf[..n] {
+                if byte == b'\n' || byte == b'\r' {
+                    emit(&line);
+                    line.clear();
+                } else {
+                    line.push(byte);
+                }
+            }
+        }
+        emit(&line);
+        collected
+    })
+}
+
+/// Build the command running a simulator with `flags` on a file, e.g. `-b <netlist>` for batch mode
+/// The simulator runs in the netlist's directory with a minimal environment, so relative
+/// paths resolve inside the temp dir. With `background_priority` it starts below normal priority
+fn simulator_command(executable: &str, flags: &[&str], netlist_path: &Path, background_priority: bool) -> Command {
+    let mut command = bare_simulator_command(executable, background_priority);
+    if let Some(dir) = netlist_path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        command.current_dir(dir);
+    }
+    command.args(flags);
+    // Past MAX_PATH the simulator can only open the netlist through the long-path prefix
+    match long_path::extended_length(&netlist_path.to_string_lossy()).filter(|_| cfg!(windows)) {
+        Some(extended) => command.arg(extended),
+        None => command.arg(netlist_path),
+    };
+    command
+}
+
+/// Build the command starting a simulator with no arguments, in the minimal environment and at
+/// the priority `simulator_command` gives it
+pub(crate) fn bare_simulator_command(executable: &str, background_priority: bool) -> Command {
+    #[cfg(unix)]
+    let mut command = if background_priority {
+        // nice execs the simulator, so the PID we track is still the simulator's
+        let mut command = Command::new("nice");
+        command.args(["-n", BACKGROUND_NICE_LEVEL]).arg(executable);
+        command
+    } else {
+        Command::new(executable)
+    };
+
+    #[cfg(not(unix))]
+    let mut command = Command::new(executable);
+
+    #[cfg(windows)]
+    {
+        use std::os::windows::process::CommandExt;
+        command.creation_flags(priority_creation_flags(background_priority));
+    }
+
+    command.env_clear().envs(simulator_environment(executable));
+    command
+}
+
+/// Environment for a simulator: the preserved variables plus a PATH of the simulator's
+/// directory and the system directories
+fn simulator_environment(executable: &str) -> Vec<(String, std::ffi::OsString)> {
+    let mut env: Vec<(String, std::ffi::OsString)> = SIMULATOR_ENV_VARS
+        .iter()
+        .filter_map(|name| std::env::var_os(name).map(|value| (name.to_string(), value)))
+        .collect();
+
+    let mut path: Vec<PathBuf> = Path::new(executable)
+        .parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .map(Path::to_path_buf)
+        .into_iter()
+        .collect();
+    #[cfg(unix)]
+    path.extend(SIMULATOR_SYSTEM_PATH.iter().map(PathBuf::from));
+    #[cfg(windows)]
+    {
+        if let Some(root) = std::env::var_os("SystemRoot").map(PathBuf::from) {
+            path.extend([root.join("System32"), root]);
+        }
+    }
+    if let Ok(joined) = std::env::join_paths(path) {
+        env.push(("PATH".to_string(), joined));
+    }
+    env
+}
+
+/// Whether an LTspice build has the alternate solver; native macOS builds (an .app bundle) lack `-alt`
+fn ltspice_has_alternate_solver(ltspice_path: &str) -> bool {
+    !ltspice_path.replace('\\', "/").contains(".app/")
+}
+
+/// Engine options the LTspice at `ltspice_path` honours, as listed in capabilities
+pub fn ltspice_engine_options(ltspice_path: &str) -> Vec<String> {
+    let mut options = Vec::new();
+    if ltspice_has_alternate_solver(ltspice_path) {
+        options.push("alternateSolver".to_string());
+    }
+    options.push("asciiOutput".to_string());
+    options
+}
+
+/// LTspice switches for the requested engine options, with a warning for each one it can't honour
+pub fn ltspice_engine_flags(ltspice_path: &str, options: &EngineOptions) -> (Vec<&'static str>, Vec<String>) {
+    let mut flags = Vec::new();
+    let mut warnings = Vec::new();
+    if options.alternate_solver {
+        if ltspice_has_alternate_solver(ltspice_path) {
+            flags.push("-alt");
+        } else {
+            warnings.push("alternateSolver was ignored: this LTspice build has no alternate solver".to_string());
+        }
+    }
+    if options.ascii_output {
+        flags.push("-ascii");
+    }
+    (flags, warnings)
+}
+
+/// Process creation flags for the requested priority
+#[cfg(windows)]
+fn priority_creation_flags(background_priority: bool) -> u32 {
+    if background_priority {
+        BELOW_NORMAL_PRIORITY_CLASS
+    } else {
+        0
+    }
+}
+
+/// Wait for a simulator while watching for hangs and reporting resource usage
+/// Kills the process tree and returns `SimulationHung` if it runs past the hard timeout
+/// or shows neither CPU time nor output file growth for the stall timeout; returns
+/// `LtspiceNeedsFirstRun` if it goes idle for `first_run_grace` without writing anything
+async fn watch_process(
+    mut wait: tokio::task::JoinHandle<std::io::Result<std::process::Output>>,
+    pid: u32,
+    netlist_path: &Path,
+    label: &'static str,
+    first_run_grace: Option<Duration>,
+    options: &ProcessOptions,
+) -> Result<std::process::Output, Box<dyn std::error::Error + Send + Sync>> {
+    let started = Instant::now();
+    let mut monitor = ProcessMonitor::new(pid, netlist_path);
+    let log_path = netlist_path.with_extension("log");
+    let mut ever_wrote = false;
+    let mut last_activity = Instant::now();
+    let mut last_report: Option<Instant> = None;
+    let mut ticker = tokio::time::interval(WATCHDOG_POLL_INTERVAL);
+    ticker.tick().await; // first tick completes immediately
+
+    loop {
+        tokio::select! {
+            // Exit wins over sampling, so nothing is reported for a finished process
+            biased;
+            output = &mut wait => return Ok(output??),
+            _ = ticker.tick() => {
+                let sample = monitor.sample_process();
+                let wrote = monitor.files_grew();
+                ever_wrote |= wrote;
+                if let Some(activity) = options.log_activity.as_ref().filter(|_| wrote) {
+                    activity.store(now_ms(), Ordering::SeqCst);
+                }
+
+                if let (Some(sink), Some(sample)) = (&options.resource_sink, &sample) {
+                    if last_report.is_none_or(|at| at.elapsed() >= RESOURCE_SAMPLE_INTERVAL) {
+                        sink(monitor.resource_usage(sample));
+                        last_report = Some(Instant::now());
+                    }
+                }
+
+                let hung = if let Some(limit) = options.hard_timeout.filter(|limit| started.elapsed() > *limit) {
+                    Some(format!(
+                        "{} exceeded the maximum simulation time of {}s and was stopped",
+                        label,
+                        limit.as_secs()
+                    ))
+                } else if monitor.used_cpu(sample.as_ref()) || wrote {
+                    last_activity = Instant::now();
+                    None
+                } else {
+                    options.stall_timeout.filter(|stall| last_activity.elapsed() > *stall).map(|stall| {
+                        format!(
+                            "{} showed no CPU or file activity for {}s and was stopped - it may be waiting on a dialog",
+                            label,
+                            stall.as_secs()
+                        )
+                    })
+                };
+
+                // Idle without ever writing a file or the log: nothing was simulated, so a dialog is in the way
+                let blocked = hung.is_none()
+                    && first_run_grace.is_some_and(|grace| last_activity.elapsed() >= grace)
+                    && !ever_wrote
+                    && !log_path.exists();
+                if blocked {
+                    let idle_secs = last_activity.elapsed().as_secs();
+                    log::error!("Watchdog: {} idle for {}s without a log, likely a first-run dialog (PID {})", label, idle_secs, pid);
+                    kill_process_tree(pid);
+                    let _ = tokio::time::timeout(Duration::from_secs(5), wait).await;
+                    return Err(Box::new(LtspiceNeedsFirstRun { idle_secs }));
+                }
+
+                if let Some(reason) = hung {
+                    log::error!("Watchdog: {} (PID {})", reason, pid);
+                    kill_process_tree(pid);
+                    // Reap the child, but never block forever on a process we failed to kill
+                    let _ = tokio::time::timeout(Duration::from_secs(5), wait).await;
+                    return Err(Box::new(SimulationHung { reason }));
+                }
+            }
+        }
+    }
+}
+
+/// One reading of a simulator process
+struct ProcessSample {
+    cpu_time_ms: u64,
+    cpu_percent: f32,
+    memory_bytes: u64,
+}
+
+/// Samples a simulator process and the files it writes
+struct ProcessMonitor {
+    system: System,
+    pid: Pid,
+    /// Start time seen on the first sample, used to detect PID reuse
+    start_time: Option<u64>,
+    work_dir: Option<PathBuf>,
+    raw_path: PathBuf,
+    last_cpu_ms: u64,
+    last_dir_size: u64,
+}
+
+impl ProcessMonitor {
+    fn new(pid: u32, netlist_path: &Path) -> Self {
+        let mut monitor = Self {
+            system: System::new(),
+            pid: Pid::from_u32(pid),
+            start_time: None,
+            work_dir: netlist_path.parent().map(Path::to_path_buf),
+            // Both simulators write `<stem>.raw` next to the netlist
+            raw_path: netlist_path.with_extension("raw"),
+            last_cpu_ms: 0,
+            last_dir_size: 0,
+        };
+        monitor.last_cpu_ms = monitor.sample_process().map(|s| s.cpu_time_ms).unwrap_or(0);
+        monitor.last_dir_size = monitor.dir_size();
+        monitor
+    }
+
+    /// Refresh the process, or None if it is gone or its PID now belongs to another process
+    fn sample_process(&mut self) -> Option<ProcessSample> {
+        self.system.refresh_processes_specifics(
+            ProcessesToUpdate::Some(&[self.pid]),
+            true,
+            ProcessRefreshKind::nothing().with_cpu().with_memory(),
+        );
+        let process = self.system.process(self.pid)?;
+
+        match self.start_time {
+            Some(start_time) if start_time != process.start_time() => {
+                log::warn!("PID {} was reused by another process; stopping sampling", self.pid);
+                return None;
+            }
+            Some(_) => {}
+            None => self.start_time = Some(process.start_time()),
+        }
+
+        Some(ProcessSample {
+            cpu_time_ms: process.accumulated_cpu_time(),
+            cpu_percent: process.cpu_usage(),
+            memory_bytes: process.memory(),
+        })
+    }
+
+    /// True if the process used CPU since the last sample
+    fn used_cpu(&mut self, sample: Option<&ProcessSample>) -> bool {
+        let cpu_ms = sample.map(|s| s.cpu_time_ms).unwrap_or(0);
+        let used = cpu_ms.saturating_sub(self.last_cpu_ms) >= WATCHDOG_MIN_CPU_MS;
+        self.last_cpu_ms = cpu_ms;
+        used
+    }
+
+    /// True if the files in the work dir, the log and raw file among them, grew since the last check
+    fn files_grew(&mut self) -> bool {
+        let dir_size = self.dir_size();
+        let grew = dir_size > self.last_dir_size;
+        self.last_dir_size = dir_size;
+        grew
+    }
+
+    fn resource_usage(&self, sample: &ProcessSample) -> ResourceUsage {
+        ResourceUsage {
+            cpu_percent: sample.cpu_percent,
+            memory_bytes: sample.memory_bytes,
+            raw_file_bytes: std::fs::metadata(&self.raw_path).map(|m| m.len()).unwrap_or(0),
+        }
+    }
+
+    fn dir_size(&self) -> u64 {
+        let Some(dir) = &self.work_dir else {
+            return 0;
+        };
+        std::fs::read_dir(dir)
+            .map(|entries| {
+                entries
+                    .flatten()
+                    .filter_map(|e| e.metadata().ok())
+                    .filter(|m| m.is_file())
+                    .map(|m| m.len())
+                    .sum()
+            })
+            .unwrap_or(0)
+    }
+}
+
+/// Open LTspice's window, not waiting for it to close, so the dialogs a fresh install shows
+/// on first launch can be clicked through before batch runs
+pub fn launch_ltspice_gui(ltspice_path: &str) -> Result<(), String> {
+    Command::new(ltspice_path)
+        .spawn()
+        .map(|_| log::info!("Opened LTspice for its first-run setup"))
+        .map_err(|e| format!("Failed to open LTspice: {}", e))
+}
+
+/// Whether a process died of a kill rather than exiting: a signal on Unix, taskkill's exit
+/// code 1 on Windows
+fn killed(status: &std::process::ExitStatus) -> bool {
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        status.signal().is_some()
+    }
+
+    #[cfg(windows)]
+    {
+        status.code() == Some(1)
+    }
+}
+
+/// Kill a process by PID, returning whether it was still there to receive the signal
+pub fn kill_process(pid: u32) -> bool {
+    #[cfg(unix)]
+    {
+        // Send SIGTERM first, then SIGKILL
+        let signalled = Command::new("kill")
+            .arg("-15")  // SIGTERM
+            .arg(pid.to_string())
+            .output()
+            .is_ok_and(|output| output.status.success());
+
+        // Give it a moment, then force kill
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        let _ = Command::new("kill")
+            .arg("-9")  // SIGKILL
+            .arg(pid.to_string())
+            .output();
+
+        log::info!("Sent kill signals to process {}", pid);
+        signalled
+    }
+
+    #[cfg(windows)]
+    {
+        let signalled = Command::new("taskkill")
+            .args(["/F", "/PID", &pid.to_string()])
+            .output()
+            .is_ok_and(|output| output.status.success());
+
+        log::info!("Sent taskkill to process {}", pid);
+        signalled
+    }
+}
+
+/// Kill simulator processes left behind by a previous agent run
+/// Only processes running one of `simulator_paths` on a netlist in one of our temp
+/// directories are touched, so an interactively opened LTspice is never killed
+/// Returns the PIDs that were killed
+pub fn kill_orphaned_simulators(simulator_paths: &[String]) -> Vec<u32> {
+    let executables: Vec<PathBuf> = simulator_paths.iter().map(|p| canonical_path(Path::new(p))).collect();
+    if executables.is_empty() {
+        return Vec::new();
+    }
+
+    let mut system = System::new();
+    system.refresh_processes_specifics(
+        ProcessesToUpdate::All,
+        true,
+        ProcessRefreshKind::nothing()
+            .with_exe(UpdateKind::Always)
+            .with_cmd(UpdateKind::Always),
+    );
+
+    let own_pid = std::process::id();
+    let mut killed = Vec::new();
+    for (pid, process) in system.processes() {
+        let pid = pid.as_u32();
+        if pid == own_pid || !is_orphaned_simulator(process.exe(), process.cmd(), &executables) {
+            continue;
+        }
+        log::warn!(
+            "Killing leftover simulator process {} ({:?}) from a previous agent run",
+            pid,
+            process.exe().unwrap_or_else(|| Path::new("?"))
+        );
+        kill_process_tree(pid);
+        killed.push(pid);
+    }
+
+    if !killed.is_empty() {
+        log::info!("Killed {} leftover simulator process(es)", killed.len());
+    }
+    killed
+}
+
+/// True if a process runs one of our simulators on a file in an agent temp directory
+fn is_orphaned_simulator(exe: Option<&Path>, cmd: &[std::ffi::OsString], executables: &[PathBuf]) -> bool {
+    let Some(exe) = exe else {
+        return false;
+    };
+    let exe = canonical_path(exe);
+    if !executables.contains(&exe) {
+        return false;
+    }
+
+    // The temp path match is what keeps user-launched sessions safe
+    cmd.iter().skip(1).any(|arg| {
+        let arg = arg.to_string_lossy();
+        arg.contains(LTSPICE_TEMP_PREFIX) || arg.contains(NGSPICE_TEMP_PREFIX)
+    })
+}
+
+/// Resolve symlinks so /usr/bin/ngspice and its target compare equal
+fn canonical_path(path: &Path) -> PathBuf {
+    std::fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+/// Kill a process and every process it spawned, returning whether the process itself was
+/// still there to receive the signal
+pub fn kill_process_tree(pid: u32) -> bool {
+    #[cfg(unix)]
+    {
+        // Find the descendants before they are re-parented, but kill the process first: a
+        // shell whose child died first could otherwise carry on and exit as if it had finished
+        let descendants = descendant_pids(pid);
+        let signalled = kill_process(pid);
+        for child in descendants.into_iter().rev() {
+            kill_process(child);
+        }
+        signalled
+    }
+
+    #[cfg(windows)]
+    {
+        let signalled = Command::new("taskkill")
+            .args(["/F", "/T", "/PID", &pid.to_string()])
+            .output()
+            .is_ok_and(|output| output.status.success());
+
+        log::info!("Sent taskkill /T to process tree {}", pid);
+        signalled
+    }
+}
+
+/// PIDs of all descendants of a process, parents before children
+#[cfg(unix)]
+fn descendant_pids(pid: u32) -> Vec<u32> {
+    let mut system = System::new();
+    system.refresh_processes_specifics(ProcessesToUpdate::All, true, ProcessRefreshKind::nothing());
+
+    let mut descendants = Vec::new();
+    let mut frontier = vec![Pid::from_u32(pid)];
+    while let Some(parent) = frontier.pop() {
+        for (child_pid, process) in system.processes() {
+            if process.parent() == Some(parent) {
+                descendants.push(child_pid.as_u32());
+                frontier.push(*child_pid);
+            }
+        }
+    }
+    descendants
+}
+
+/// Run a simulation and return only the text containing its `.meas` results
+/// (the LTspice .log file or ngspice stdout); the raw file is not parsed
+pub async fn run_measurements_in_workspace(
+    simulator_name: &str,
+    executable: &str,
+    workspace: &SimulationWorkspace,
+    netlist: &str,
+    file_stem: &str,
+    process_options: &ProcessOptions,
+) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+    match simulator_name {
+        "ngspice" => {
+            let netlist_path = workspace.path().join(format!("{}.cir", file_stem));
+            let raw_path = workspace.path().join(format!("{}.raw", file_stem));
+            let prepared =
+                prepare_ngspice_netlist(
+                netlist,
+                &QualityProfile::measurements(),
+                !process_options.no_auto_options,
+                &raw_path,
+                process_options.ngspice_threads,
+                None,
+            );
+            std::fs::write(&netlist_path, prepared)?;
+
+            let output = run_batch_process(executable, &netlist_path, "ngspice", process_options, None).await?;
+            let stdout = String::from_utf8_lossy(&output.stdout).to_string();
+            let stderr = String::from_utf8_lossy(&output.stderr);
+
+            if let Some(error_msg) = extract_ngspice_error(&format!("{}\n{}", stdout, stderr)) {
+                return Err(error_msg.into());
+            }
+            Ok(stdout)
+        }
+        _ => {
+            let netlist_path = workspace.path().join(format!("{}.net", file_stem));
+            let log_path = workspace.path().join(format!("{}.log", file_stem));
+            let prepared = prepare_netlist(netlist, &QualityProfile::measurements(), !process_options.no_auto_options, None);
+            std::fs::write(&netlist_path, prepared)?;
+
+            let args = ltspice_batch_args(process_options);
+            let flags: Vec<&str> = args.iter().map(String::as_str).collect();
+            let output = run_simulator_process(executable, &flags, &netlist_path, "LTspice", process_options, None).await?;
+            let log_content = std::fs::read(&log_path).map(|bytes| decode_log_text(&bytes)).unwrap_or_default();
+
+            if !output.status.success() {
+                let stderr = String::from_utf8_lossy(&output.stderr);
+                return Err(format!("LTspice failed: {}\n{}", stderr, log_content).into());
+            }
+            Ok(log_content)
+        }
+    }
+}
+
+/// Decode simulator log text, which LTspice writes as UTF-16LE on some versions
+fn decode_log_text(bytes: &[u8]) -> String {
+    let looks_utf16 = bytes.starts_with(&[0xFF, 0xFE])
+        || (bytes.len() >= 2 && bytes[1] == 0 && bytes[0] != 0);
+    if looks_utf16 {
+        let (text, _, _) = UTF_16LE.decode(bytes);
+        text.into_owned()
+    } else {
+        String::from_utf8_lossy(bytes).to_string()
+    }
+}
+
+/// Extract the value of a named `.meas` result from LTspice log or ngspice output
+/// Handles `name: expr=value FROM ...` (LTspice) and `name = value at= ...` (ngspice);
+/// AC results such as `(-3.01dB,-45°)` yield the first component
+pub fn extract_measurement(output: &str, name: &str) -> Option<f64> {
+    for line in output.lines() {
+        let trimmed = line.trim();
+        // Compared on the line itself, as lowercasing may change its length in bytes
+        if !trimmed.get(..name.len()).is_some_and(|prefix| prefix.eq_ignore_ascii_case(name)) {
+            continue;
+        }
+
+        // Make sure we matched the whole name, not a prefix of a longer one
+        let rest = &trimmed[name.len()..];
+        if !rest.starts_with(|c: char| c == ':' || c == '=' || c.is_whitespace()) {
+            continue;
+        }
+
+        for segment in rest.split('=').skip(1) {
+            let token = segment.split_whitespace().next().unwrap_or("");
+            let token = token.trim_start_matches('(');
+            let token = token.split(',').next().unwrap_or("");
+            let token = token.trim_end_matches(')').trim_end_matches("dB");
+            if let Ok(value) = token.parse::<f64>() {
+                return Some(value);
+            }
+        }
+    }
+
+    None
+}
+
+/// Aggregate one measurement's per-run values into summary statistics
+/// Runs where the measurement failed are kept as None and excluded from the statistics
+pub fn summarize_measurement(name: &str, values: Vec<Option<f64>>) -> MeasurementStatistics {
+    let present: Vec<f64> = values.iter().flatten().copied().collect();
+    let failed_runs = (values.len() - present.len()) as u32;
+
+    let (mean, stddev, min, max) = if present.is_empty() {
+        (None, None, None, None)
+    } else {
+        let n = present.len() as f64;
+        let mean = present.iter().sum::<f64>() / n;
+        // Sample standard deviation; a single run has no spread
+        let stddev = if present.len() > 1 {
+            (present.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / (n - 1.0)).sqrt()
+        } else {
+            0.0
+        };
+        let min = present.iter().cloned().fold(f64::INFINITY, f64::min);
+        let max = present.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+        (Some(mean), Some(stddev), Some(min), Some(max))
+    };
+
+    MeasurementStatistics {
+        name: name.to_string(),
+        values,
+        mean,
+        stddev,
+        min,
+        max,
+        failed_runs,
+    }
+}
+
+/// Prepare one Monte Carlo iteration: expose the run number as the `mc_run` parameter
+/// and seed the random generator so `mc()`/`gauss()` draw different values each run
+pub fn apply_monte_carlo_run(netlist: &str, run: u32, seed: u64) -> String {
+    let mut lines: Vec<String> = netlist.lines().map(|s| s.to_string()).collect();
+    let directives = [format!(".param mc_run={}", run), format!(".options seed={}", seed)];
+
+    match end_line(&lines) {
+        Some(idx) => {
+            for (i, directive) in directives.into_iter().enumerate() {
+                lines.insert(idx + i, directive);
+            }
+        }
+        None => lines.extend(directives),
+    }
+
+    lines.join("\n")
+}
+
+/// Generate a netlist from an LTspice `.asc` schematic with `-netlist`
+/// The schematic is written as circuit.asc in its own temp directory, beside the attachments
+/// so LTspice finds attached symbols there, and circuit.net is read back
+pub async fn netlist_from_asc(
+    ltspice_path: &str,
+    schematic: &str,
+    attachments: &[WorkspaceFile],
+    process_options: &ProcessOptions,
+) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+    let temp_dir = create_temp_dir(LTSPICE_TEMP_PREFIX, &process_options.temp_root())?;
+    let asc_path = temp_dir.path().join("circuit.asc");
+    let net_path = temp_dir.path().join("circuit.net");
+    write_workspace_files(temp_dir.path(), attachments)?;
+    std::fs::write(&asc_path, schematic)?;
+
+    log::info!("Generating netlist from schematic in {:?}", temp_dir.path());
+    let output = run_simulator_process(ltspice_path, &["-netlist"], &asc_path, "LTspice", process_options, None).await?;
+
+    match std::fs::read(&net_path) {
+        Ok(bytes) => Ok(decode_log_text(&bytes)),
+        Err(_) => Err(format!(
+            "LTspice did not generate a netlist from the schematic: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )
+        .into()),
+    }
+}
+
+/// Symbols a schematic places that are neither attached as `<name>.asy` nor in the LTspice library
+/// Empty when the library directory can't be found, leaving LTspice to report them
+pub fn missing_asc_symbols(schematic: &str, attachments: &[WorkspaceFile]) -> Vec<String> {
+    match detect_ltspice_lib_dir() {
+        Some(lib_dir) => missing_symbols(schematic, &lib_dir.join("sym"))
+            .into_iter()
+            .filter(|name| !symbol_attached(name, attachments))
+            .collect(),
+        None => Vec::new(),
+    }
+}
+
+/// Whether `<name>.asy` is among the attachments; only the last part of the symbol name counts
+fn symbol_attached(name: &str, attachments: &[WorkspaceFile]) -> bool {
+    let file_name = format!("{}.asy", name.rsplit(['\\', '/']).next().unwrap_or(name));
+    attachments.iter().any(|file| file.name.eq_ignore_ascii_case(&file_name))
+}
+
+/// Names from `SYMBOL <name> x y R0` lines with no `<name>.asy` under `sym_dir`
+fn missing_symbols(schematic: &str, sym_dir: &Path) -> Vec<String> {
+    let mut missing: Vec<String> = Vec::new();
+    for line in schematic.lines() {
+        let mut tokens = line.split_whitespace();
+        if tokens.next() != Some("SYMBOL") {
+            continue;
+        }
+        let Some(name) = tokens.next() else {
+            continue;
+        };
+        // Subdirectories are written with backslashes, e.g. Opamps\UniversalOpamp2
+        let relative: PathBuf = name.split(['\\', '/']).collect();
+        let path = sym_dir.join(format!("{}.asy", relative.display()));
+        if !path.exists() && !missing.iter().any(|m| m == name) {
+            missing.push(name.to_string());
+        }
+    }
+    missing
+}
+
+/// Netlist change retried after a convergence failure
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConvergenceFallback {
+    /// Adds `STEPPING_OPTIONS`: more gmin and source steps and a small capacitance on every node
+    SteppingOptions,
+    /// Appends `uic` to `.tran`, starting from initial conditions instead of a DC operating point
+    Uic,
+}
+
+/// Fallbacks in the order they are retried, one run each
+pub const CONVERGENCE_FALLBACKS: [ConvergenceFallback; 2] =
+    [ConvergenceFallback::SteppingOptions, ConvergenceFallback::Uic];
+
+impl ConvergenceFallback {
+    /// Name reported in `retriedWith`
+    pub fn name(self) -> &'static str {
+        match self {
+            ConvergenceFallback::SteppingOptions => "stepping_options",
+            ConvergenceFallback::Uic => "uic",
+        }
+    }
+
+    /// The netlist with this fallback applied, None if it would not change anything
+    pub fn apply(self, netlist: &str) -> Option<String> {
+        let mut lines: Vec<String> = netlist.lines().map(|s| s.to_string()).collect();
+        match self {
+            ConvergenceFallback::SteppingOptions => {
+                match end_line(&lines) {
+                    Some(idx) => lines.insert(idx, STEPPING_OPTIONS.to_string()),
+                    None => lines.push(STEPPING_OPTIONS.to_string()),
+                }
+            }
+            ConvergenceFallback::Uic => {
+                let mut changed = false;
+                for line in lines.iter_mut() {
+                    let lower = line.to_lowercase();
+                    let mut tokens = lower.split_whitespace();
+                    if tokens.next() == Some(".tran") && !tokens.any(|t| t == "uic") {
+                        line.push_str(" uic");
+                        changed = true;
+                    }
+                }
+                if !changed {
+                    return None;
+                }
+            }
+        }
+        Some(lines.join("\n"))
+    }
+}
+
+/// First line of simulator output reporting a convergence failure
+fn convergence_failure_line(output: &str) -> Option<&str> {
+    output
+        .lines()
+        .find(|line| {
+            let lower = line.to_lowercase();
+            CONVERGENCE_FAILURE_PHRASES.iter().any(|phrase| lower.contains(phrase))
+        })
+        .map(str::trim)
+}
+
+/// Error for a failed run: a `ConvergenceFailure` or `NetlistParseError` when the simulator
+/// output reports one
+fn run_failure(message: String, output: &str) -> Box<dyn std::error::Error + Send + Sync> {
+    let parse_failed = output.lines().any(|line| {
+        let lower = line.to_lowercase();
+        NETLIST_PARSE_PHRASES.iter().any(|phrase| lower.contains(phrase))
+    });
+    if convergence_failure_line(output).is_some() {
+        Box::new(ConvergenceFailure { message })
+    } else if parse_failed {
+        Box::new(NetlistParseError { message })
+    } else {
+        message.into()
+    }
+}
 
-    if !has_control {
-        // Add .control section before .end to write raw file
-        // ngspice on Unix doesn't like quoted paths - use the path directly
-        // For paths with spaces, we use single quotes (ngspice handles these better)
-        let raw_path_str = raw_path.to_string_lossy().replace('\\', "/");
+/// The first line of a simulator's log that probably explains why its raw file was cut short
+fn truncation_cause(log: &str) -> Option<String> {
+    log.lines()
+        .map(str::trim)
+        .find(|line| {
+            let lower = line.to_lowercase();
+            TRUNCATION_CAUSE_PHRASES.iter().any(|phrase| lower.contains(phrase))
+        })
+        .map(str::to_string)
+}
 
-        // Use single quotes only if the path contains spaces, otherwise no quotes
-        let write_cmd = if raw_path_str.contains(' ') {
-            format!("write '{}' all", raw_path_str)
-        } else {
-            format!("write {} all", raw_path_str)
-        };
+/// Add the likely cause from the simulator's log to a raw file problem: the error if nothing
+/// could be read, the truncation warning otherwise
+fn explain_raw_file(
+    parsed: Result<SimulationResults, Box<dyn std::error::Error + Send + Sync>>,
+    log: &str,
+) -> Result<SimulationResults, Box<dyn std::error::Error + Send + Sync>> {
+    match parsed {
+        Ok(mut results) => {
+            if let Some(truncation) = &mut results.truncated {
+                truncation.likely_cause = truncation_cause(log);
+            }
+            Ok(results)
+        }
+        Err(e) => match truncation_cause(log) {
+            Some(cause) => Err(format!("{}; likely cause: {}", e, cause).into()),
+            None => Err(e),
+        },
+    }
+}
 
-        let control_section = vec![
-            ".control".to_string(),
-            "run".to_string(),
-            write_cmd,
-            "quit".to_string(),
-            ".endc".to_string(),
-        ];
+/// Fail with `AnalysisIncomplete` when the netlist asked for a sweep but the raw file at
+/// `raw_path` holds only the operating point
+/// The message carries the convergence error from the simulator's `log`, if it printed one
+fn check_requested_analysis(
+    results: &SimulationResults,
+    netlist: &str,
+    raw_path: &Path,
+    log: &str,
+) -> Result<(), AnalysisIncomplete> {
+    let operating_point_only = raw_plot_name(raw_path).is_some_and(|plot| plot.to_lowercase().contains("operating point"));
+    if !operating_point_only {
+        return Ok(());
+    }
+    let Some(requested) = netlist::parse(netlist).analyses.into_iter().find(|analysis| {
+        matches!(
+            analysis,
+            NetlistAnalysis::Tran { .. } | NetlistAnalysis::Ac { .. } | NetlistAnalysis::Dc { .. } | NetlistAnalysis::Noise { .. }
+        )
+    }) else {
+        return Ok(());
+    };
+
+    let mut message = format!("The .{} analysis did not complete; only the operating point was computed", requested.directive());
+    if let Some(line) = convergence_failure_line(log) {
+        message = format!("{}: {}", message, line);
+    }
+    // An operating point plot has no independent variable, so its first variable was read as the x axis
+    let first = results.x_axis_label.clone().zip(results.time.first().copied());
+    let operating_point = first
+        .into_iter()
+        .chain(results.traces.iter().filter_map(|trace| Some((trace.name.clone(), *trace.data.first()?))))
+        .collect();
+    Err(AnalysisIncomplete { message, operating_point })
+}
+
+/// The `Plotname:` of a raw file, from its UTF-16LE (LTspice binary) or ASCII header
+fn raw_plot_name(path: &Path) -> Option<String> {
+    let mut header = Vec::new();
+    std::fs::File::open(path).ok()?.take(RAW_PLOT_NAME_SCAN_BYTES).read_to_end(&mut header).ok()?;
+    let text = if header.get(1) == Some(&0) {
+        UTF_16LE.decode(&header).0.into_owned()
+    } else {
+        String::from_utf8_lossy(&header).into_owned()
+    };
+    text.lines()
+        .find_map(|line| line.trim().strip_prefix("Plotname:"))
+        .map(|name| name.trim().to_string())
+}
+
+/// Truncation record when fewer complete points were read than the header declared
+fn raw_truncation(expected_points: usize, actual_points: usize) -> Option<RawTruncation> {
+    if actual_points >= expected_points {
+        return None;
+    }
+    log::warn!("Raw file truncated: {} of {} points are complete", actual_points, expected_points);
+    Some(RawTruncation { expected_points, actual_points, likely_cause: None })
+}
+
+/// Extract meaningful error message from ngspice output
+/// Returns Some(error_message) if errors found, None otherwise
+fn extract_ngspice_error(output: &str) -> Option<String> {
+    let lines: Vec<&str> = output.lines().collect();
+    let mut error_lines: Vec<String> = Vec::new();
+    let mut found_error = false;
+
+    for (i, line) in lines.iter().enumerate() {
+        let lower = line.to_lowercase();
+
+        // Skip notes and warnings, and "0 error" lines
+        if lower.contains("note:") || lower.contains("warning:") || lower.contains("0 error") {
+            continue;
+        }
+
+        // Capture error lines and context
+        if lower.contains("error") {
+            found_error = true;
 
-        if let Some(idx) = end_idx {
-            for (i, line) in control_section.into_iter().enumerate() {
-                lines.insert(idx + i, line);
+            // Include previous line if it has line number info
+            if i > 0 {
+                let prev = lines[i - 1];
+                if prev.to_lowercase().contains("line") && prev.contains(char::is_numeric) {
+                    if !error_lines.contains(&prev.trim().to_string()) {
+                        error_lines.push(prev.trim().to_string());
+                    }
+                }
             }
-        } else {
-            // No .end found, append control section and .end
-            lines.extend(control_section);
-            lines.push(".end".to_string());
+
+            error_lines.push(line.trim().to_string());
+
+            // Include next line if it's a continuation (indented or has more detail)
+            if i + 1 < lines.len() {
+                let next = lines[i + 1];
+                if next.starts_with(' ') || next.starts_with('\t') {
+                    error_lines.push(next.trim().to_string());
+                }
+            }
+        }
+        // Also capture "Error on line X" or "line X:" context
+        else if lower.contains("error on line") || (lower.contains("line") && lower.contains(':') && line.contains(char::is_numeric)) {
+            error_lines.push(line.trim().to_string());
+            found_error = true;
+        }
+    }
+
+    if !error_lines.is_empty() {
+        // Deduplicate while preserving order
+        let mut seen = std::collections::HashSet::new();
+        let unique_lines: Vec<String> = error_lines
+            .into_iter()
+            .filter(|line| seen.insert(line.clone()))
+            .collect();
+
+        let mut error_message = unique_lines.join("\n");
+
+        // Add KeliCAD recommendations for common mistakes
+        if let Some(recommendations) = get_kelicad_recommendations(output) {
+            error_message.push_str("\n\n");
+            error_message.push_str(&recommendations);
         }
+
+        Some(error_message)
+    } else if found_error {
+        Some("ngspice simulation failed".to_string())
+    } else {
+        None
+    }
+}
+
+/// Get KeliCAD-specific recommendations for common SPICE mistakes
+fn get_kelicad_recommendations(output: &str) -> Option<String> {
+    let lower = output.to_lowercase();
+    let mut recommendations: Vec<&str> = Vec::new();
+
+    // PWL on resistor (resistors don't support PWL)
+    if (lower.contains("pwl") && lower.contains("resistor")) ||
+       lower.contains("unknown parameter") && lower.contains("pwl") {
+        recommendations.extend_from_slice(&[
+            "💡 KeliCAD Tip: Resistors cannot use PWL (Piece-Wise Linear). PWL is only for voltage/current sources.",
+            "Alternatives for variable resistance:",
+            "  • Use a fixed resistor: Rload node1 node2 10k",
+            "  • Use a behavioral current source: Gload node1 node2 cur='V(node1)/R_value'",
+            "  • Use ngspice behavioral resistor: Rload node1 node2 r='table(time, 0, 10, 1, 20)'"
+        ]);
+    }
+
+    // Floating node
+    if lower.contains("floating") || lower.contains("no dc path to ground") {
+        recommendations.extend_from_slice(&[
+            "💡 KeliCAD Tip: Floating node detected - every node needs a DC path to ground.",
+            "Solutions:",
+            "  • Add a high-value resistor to ground: Rbias floating_node 0 1G",
+            "  • Check that all components are properly connected"
+        ]);
+    }
+
+    // Voltage source loop
+    if lower.contains("voltage source loop") || lower.contains("voltage loop") {
+        recommendations.extend_from_slice(&[
+            "💡 KeliCAD Tip: Voltage sources in parallel create an invalid circuit.",
+            "Solutions:",
+            "  • Add a small series resistance between voltage sources",
+            "  • Remove one of the parallel voltage sources"
+        ]);
+    }
+
+    // Inductor loop / current source cutset
+    if lower.contains("inductor loop") || lower.contains("current source cutset") {
+        recommendations.extend_from_slice(&[
+            "💡 KeliCAD Tip: Current sources in series or inductors in loops need damping.",
+            "Solutions:",
+            "  • Add a parallel resistor across inductors",
+            "  • Ensure current sources have parallel paths"
+        ]);
+    }
+
+    // Missing .end
+    if lower.contains("missing .end") || lower.contains("no .end") {
+        recommendations.push("💡 KeliCAD Tip: Every netlist must end with .end directive.");
+    }
+
+    // Unknown device/model
+    if lower.contains("unknown device") || lower.contains("unknown model") || lower.contains("model not found") {
+        recommendations.extend_from_slice(&[
+            "💡 KeliCAD Tip: Make sure to include the model library with .include or .lib directive.",
+            "Example: .include standard.lib"
+        ]);
+    }
+
+    if !recommendations.is_empty() {
+        Some(recommendations.join("\n"))
+    } else {
+        None
     }
+}
 
+/// Prepare netlist for ngspice with .control section
+/// `threads` caps ngspice's thread count via `set num_threads` when the section is injected;
+/// with `save`, only those signals are kept, through a `save` command in it; without
+/// `auto_options`, the quality options are left out
+fn prepare_ngspice_netlist(
+    netlist: &str,
+    quality: &QualityProfile,
+    auto_options: bool,
+    raw_path: &PathBuf,
+    threads: Option<u32>,
+    save: Option<&[String]>,
+) -> String {
+    let NgspiceDeck { mut lines, commands } = ngspice_deck(netlist, quality, auto_options, raw_path, threads, save);
+    if let Some(commands) = commands {
+        // Add .control section before .end to write raw file
+        let control_section = std::iter::once(".control".to_string())
+            .chain(commands)
+            .chain(["quit".to_string(), ".endc".to_string()]);
+        for line in control_section {
+            insert_before_end(&mut lines, line);
+        }
+    }
     lines.join("\n")
 }
 
+/// A netlist prepared for ngspice, apart from how it is run
+pub(crate) struct NgspiceDeck {
+    pub lines: Vec<String>,
+    /// Commands that run it and write its output, or None if it has a `.control` section of its own
+    pub commands: Option<Vec<String>>,
+}
+
+/// The netlist and commands `prepare_ngspice_netlist` puts together; an ngspice session sends
+/// the commands itself instead
+pub(crate) fn ngspice_deck(
+    netlist: &str,
+    quality: &QualityProfile,
+    auto_options: bool,
+    raw_path: &Path,
+    threads: Option<u32>,
+    save: Option<&[String]>,
+) -> NgspiceDeck {
+    let mut lines = without_unsupported_plot_directives(netlist, NGSPICE_UNSUPPORTED_PLOT_DIRECTIVES);
+    // Signals the netlist has no node or element for are left out; `write` fails on any one of them
+    let save = save.map(|signals| ngspice_vectors(netlist, signals).vectors).filter(|vectors| !vectors.is_empty());
+    if end_line(&lines).is_none() {
+        lines.push(".end".to_string());
+    }
+
+    if let Some(options) = quality_options_line(netlist, "ngspice", quality).filter(|_| auto_options) {
+        insert_before_end(&mut lines, options);
+    }
+
+    // Check if there's already a .control section
+    if netlist.to_lowercase().contains(".control") {
+        // The netlist's own .control section runs it; a .save line still applies
+        if let Some(save) = &save {
+            insert_before_end(&mut lines, format!(".save {}", save.join(" ")));
+        }
+        return NgspiceDeck { lines, commands: None };
+    }
+
+    // ngspice runs in the raw file's directory, so the bare file name needs no quoting
+    // however many spaces the temp dir path has
+    let raw_name = raw_path.file_name().unwrap_or(raw_path.as_os_str());
+    let noise = lines.iter().any(|l| l.trim().to_lowercase().starts_with(".noise"));
+    // The noise1 plot holds spectra rather than the saved vectors, so it is written whole
+    let written = save.as_ref().filter(|_| !noise).map_or("all".to_string(), |save| save.join(" "));
+    let write_cmd = format!("write {} {}", raw_name.to_string_lossy(), written);
+
+    let mut commands = Vec::new();
+    if let Some(threads) = threads {
+        commands.push(format!("set num_threads={}", threads.max(1)));
+    }
+    if let Some(save) = &save {
+        commands.push(format!("save {}", save.join(" ")));
+    }
+    commands.push("run".to_string());
+    if scalar_analysis(netlist).is_some() {
+        // Values are read back from stdout; there is no plot worth writing
+        commands.push("print all".to_string());
+    } else {
+        // A noise run leaves the integrated totals (noise2) as the current plot;
+        // the spectral densities are in noise1
+        if noise {
+            commands.push("setplot noise1".to_string());
+        }
+        commands.push(write_cmd);
+    }
+    NgspiceDeck { lines, commands: Some(commands) }
+}
+
 /// Parse ngspice raw file format (supports both ASCII and binary, including complex numbers for AC analysis)
-fn parse_ngspice_raw_file(path: &PathBuf) -> Result<SimulationResults, Box<dyn std::error::Error + Send + Sync>> {
-    // Read the entire file as bytes first
-    let data = std::fs::read(path)?;
+fn parse_ngspice_raw_file(path: &PathBuf, options: &ProcessOptions) -> Result<SimulationResults, Box<dyn std::error::Error + Send + Sync>> {
+    parse_ngspice_raw_data(&read_raw_file(path, options)?)
+}
 
+/// Parse the contents of an ngspice-style raw file; also reads LTspice's `-ascii` output once decoded
+fn parse_ngspice_raw_data(data: &[u8]) -> Result<SimulationResults, Box<dyn std::error::Error + Send + Sync>> {
     // Find where the header ends and data begins
     // Header is ASCII, so we can safely convert it
     let mut num_vars = 0;
@@ -1030,6 +3456,7 @@ fn parse_ngspice_raw_file(path: &PathBuf) -> Result<SimulationResults, Box<dyn s
     let mut variables: Vec<(String, String)> = Vec::new();
     let mut analysis_type = "transient".to_string();
     let mut x_axis_label = "time".to_string();
+    let mut x_axis_type = "time".to_string();
     let mut is_binary = false;
     let mut is_complex = false; // AC analysis uses complex numbers
     let mut data_start_offset = 0;
@@ -1050,19 +3477,8 @@ fn parse_ngspice_raw_file(path: &PathBuf) -> Result<SimulationResults, Box<dyn s
                 let line = line.trim();
 
                 if line.starts_with("Plotname:") {
-                    let plotname = line.split(':').nth(1).map(|s| s.trim().to_lowercase()).unwrap_or_default();
-                    // Check for specific analysis types - order matters to avoid false matches
-                    // "DC transfer characteristic" contains "ac" in "characteristic", so check DC first
-                    if plotname.contains("dc") || plotname.contains("operating point") {
-                        analysis_type = "dc".to_string();
-                    } else if plotname.contains("ac analysis") || plotname.starts_with("ac ") {
-                        analysis_type = "ac".to_string();
-                    } else if plotname.contains("transient") {
-                        analysis_type = "transient".to_string();
-                    } else {
-                        // Default to transient for unknown analysis types
-                        analysis_type = "transient".to_string();
-                    }
+                    let plotname = line.split(':').nth(1).unwrap_or_default();
+                    analysis_type = plot_analysis_type(plotname).to_string();
                 } else if line.starts_with("Flags:") {
                     // Check for complex flag (used in AC analysis)
                     let flags = line.to_lowercase();
@@ -1095,6 +3511,7 @@ fn parse_ngspice_raw_file(path: &PathBuf) -> Result<SimulationResults, Box<dyn s
                         // First variable (index 0) is the independent variable
                         if var_index == 0 {
                             x_axis_label = name.to_lowercase();
+                            x_axis_type = var_type.clone();
                         }
                         variables.push((name, var_type));
                         var_index += 1;
@@ -1114,6 +3531,8 @@ fn parse_ngspice_raw_file(path: &PathBuf) -> Result<SimulationResults, Box<dyn s
     }
 
     let mut all_data: Vec<Vec<f64>> = vec![Vec::with_capacity(num_points); num_vars];
+    // Phase in degrees of each complex variable, empty for real data
+    let mut all_phase: Vec<Vec<f64>> = vec![Vec::new(); num_vars];
 
     if is_binary {
         // Parse binary data - ngspice uses float64 for all values
@@ -1125,12 +3544,8 @@ fn parse_ngspice_raw_file(path: &PathBuf) -> Result<SimulationResults, Box<dyn s
         log::info!("Parsing binary data: {} bytes, expecting {} points x {} vars x {} values x 8 bytes = {} bytes",
                    binary_data.len(), num_points, num_vars, values_per_var, num_points * bytes_per_point);
 
-        for point in 0..num_points {
+        for point in 0..num_points.min(binary_data.len() / bytes_per_point) {
             let point_offset = point * bytes_per_point;
-            if point_offset + bytes_per_point > binary_data.len() {
-                log::warn!("Binary data truncated at point {}", point);
-                break;
-            }
 
             for var in 0..num_vars {
                 if is_complex {
@@ -1146,6 +3561,7 @@ fn parse_ngspice_raw_file(path: &PathBuf) -> Result<SimulationResults, Box<dyn s
                             (real * real + imag * imag).sqrt() // Magnitude for voltages/currents
                         };
                         all_data[var].push(value);
+                        all_phase[var].push(imag.atan2(real).to_degrees());
                     }
                 } else {
                     let offset = point_offset + var * 8;
@@ -1223,6 +3639,7 @@ fn parse_ngspice_raw_file(path: &PathBuf) -> Result<SimulationResults, Box<dyn s
 
                             if current_var_index < all_data.len() {
                                 all_data[current_var_index].push(value);
+                                all_phase[current_var_index].push(imag.atan2(real).to_degrees());
                             }
                             current_var_index += 1;
                         }
@@ -1242,10 +3659,19 @@ fn parse_ngspice_raw_file(path: &PathBuf) -> Result<SimulationResults, Box<dyn s
         }
     }
 
+    // Only points with a value for every variable are complete
+    let complete_points = all_data.iter().map(Vec::len).min().unwrap_or(0);
+    for values in all_data.iter_mut().chain(all_phase.iter_mut().filter(|phase| !phase.is_empty())) {
+        values.truncate(complete_points);
+    }
+
     log::info!("Parsed ngspice raw: num_vars={}, num_points={}, actual_points={}, is_complex={}",
-               num_vars, num_points, all_data.get(0).map(|v| v.len()).unwrap_or(0), is_complex);
+               num_vars, num_points, complete_points, is_complex);
 
-    if all_data.is_empty() || all_data[0].is_empty() {
+    if complete_points == 0 {
+        if num_points > 0 {
+            return Err(format!("The raw file is cut short: {} points declared but none are complete", num_points).into());
+        }
         return Err("Could not parse ngspice raw file - no data found".into());
     }
 
@@ -1257,74 +3683,231 @@ fn parse_ngspice_raw_file(path: &PathBuf) -> Result<SimulationResults, Box<dyn s
         .enumerate()
         .skip(1) // Skip time/frequency variable
         .map(|(i, (name, var_type))| {
-            let unit = match var_type.as_str() {
-                "voltage" => "V",
-                "current" => "A",
-                "time" => "s",
-                "frequency" => "Hz",
-                _ => "",
-            };
             Trace {
                 name: name.clone(),
                 data: all_data.get(i).cloned().unwrap_or_default(),
-                unit: unit.to_string(),
+                unit: variable_unit(var_type, &analysis_type).to_string(),
+                phase: is_complex.then(|| all_phase.get(i).cloned().unwrap_or_default()),
+                raw_name: None,
             }
         })
         .collect();
 
+    let x_axis_unit = axis_unit(&x_axis_type, &analysis_type);
     Ok(SimulationResults {
         time,
         traces,
         analysis_type,
         x_axis_label: Some(x_axis_label),
+        x_axis_unit,
+        scalar_results: None,
+        device_operating_points: BTreeMap::new(),
+        truncated: raw_truncation(num_points, complete_points),
+        plot_hints: None,
+        raw_header: None,
     })
 }
 
+/// Analysis type of an ngspice plot name such as "Transient Analysis"
+pub(crate) fn plot_analysis_type(plotname: &str) -> &'static str {
+    let plotname = plotname.trim().to_lowercase();
+    // Check for specific analysis types - order matters to avoid false matches
+    // "DC transfer characteristic" contains "ac" in "characteristic", so check DC first
+    if plotname.contains("noise spectral density") {
+        "noise"
+    } else if plotname.contains("dc") || plotname.contains("operating point") {
+        "dc"
+    } else if plotname.contains("ac analysis") || plotname.starts_with("ac ") {
+        "ac"
+    } else {
+        // Transient, and the default for unknown analysis types
+        "transient"
+    }
+}
+
 /// Prepare netlist with required directives for proper output
-fn prepare_netlist(netlist: &str, waveform_quality: &str) -> String {
-    let mut lines: Vec<String> = netlist.lines().map(|s| s.to_string()).collect();
+/// With `save`, only those signals are kept instead of `.save all`
+/// Without `auto_options`, `.backanno` and the quality options are left out
+fn prepare_netlist(netlist: &str, quality: &QualityProfile, auto_options: bool, save: Option<&[String]>) -> String {
+    let mut lines = without_unsupported_plot_directives(netlist, LTSPICE_UNSUPPORTED_PLOT_DIRECTIVES);
+    // Netlists pieced together from fragments can lack the terminator LTspice requires
+    if end_line(&lines).is_none() {
+        lines.push(".end".to_string());
+    }
 
     // Add .backanno if not present
-    if !netlist.to_lowercase().contains(".backanno") {
-        // Find the .end line and insert before it
-        if let Some(end_idx) = lines.iter().position(|l| l.trim().to_lowercase() == ".end") {
-            lines.insert(end_idx, ".backanno".to_string());
+    if auto_options && !netlist.to_lowercase().contains(".backanno") {
+        insert_before_end(&mut lines, ".backanno".to_string());
+    }
+
+    // Add the signals to save, or .save all if there is no .save directive
+    let save_line = match save {
+        Some(signals) => Some(format!(".save {}", signals.join(" "))),
+        None => (!netlist.to_lowercase().contains(".save")).then(|| ".save all".to_string()),
+    };
+    if let Some(save_line) = save_line {
+        insert_before_end(&mut lines, save_line);
+    }
+
+    if let Some(options) = quality_options_line(netlist, "LTspice", quality).filter(|_| auto_options) {
+        insert_before_end(&mut lines, options);
+    }
+
+    lines.join("\n")
+}
+
+/// Index of the `.end` line that closes the netlist, which may carry trailing whitespace or a
+/// comment; blank lines after it don't hide it
+pub(crate) fn end_line<S: AsRef<str>>(lines: &[S]) -> Option<usize> {
+    lines.iter().position(|line| {
+        let line = line.as_ref().trim();
+        line.split([' ', '\t', ';']).next().is_some_and(|keyword| keyword.eq_ignore_ascii_case(".end"))
+    })
+}
+
+/// Insert `line` just before the `.end` line, or at the end when there is none
+fn insert_before_end(lines: &mut Vec<String>, line: String) {
+    match end_line(lines) {
+        Some(idx) => lines.insert(idx, line),
+        None => lines.push(line),
+    }
+}
+
+/// Whether `netlist` has the `.end` line SPICE requires; one is added to it when it doesn't
+pub fn has_end_line(netlist: &str) -> bool {
+    end_line(&netlist.lines().collect::<Vec<_>>()).is_some()
+}
+
+/// Signals to save in place of everything: those the netlist's `.probe`/`.plot` directives name,
+/// as the voltage or current they are taken of, and `requested`; None when neither names any
+fn saved_signals(netlist: &str, requested: &[String]) -> Option<Vec<String>> {
+    let mut signals: Vec<String> = Vec::new();
+    let plotted = netlist::parse(netlist).plotted;
+    for signal in plotted.iter().map(|variable| netlist::plotted_signal(variable)).chain(requested.iter().map(|s| s.trim().to_string())) {
+        if !signal.is_empty() && !signals.iter().any(|saved| saved.eq_ignore_ascii_case(&signal)) {
+            signals.push(signal);
         }
     }
+    (!signals.is_empty()).then_some(signals)
+}
 
-    // Add .save all if no .save directive
-    if !netlist.to_lowercase().contains(".save") {
-        if let Some(end_idx) = lines.iter().position(|l| l.trim().to_lowercase() == ".end") {
-            lines.insert(end_idx, ".save all".to_string());
+/// `.probe`/`.plot` directives LTspice rejects; the signals they name are saved with `.save`
+const LTSPICE_UNSUPPORTED_PLOT_DIRECTIVES: &[&str] = &[".probe", ".plot"];
+
+/// `.probe` directives ngspice rejects or reads differently; its `.plot` prints a text plot
+const NGSPICE_UNSUPPORTED_PLOT_DIRECTIVES: &[&str] = &[".probe"];
+
+/// Lines of `netlist` with the `directives` commented out, so line numbers in errors still match
+fn without_unsupported_plot_directives(netlist: &str, directives: &[&str]) -> Vec<String> {
+    netlist
+        .lines()
+        .map(|line| {
+            let keyword = line.split_whitespace().next().unwrap_or("").to_lowercase();
+            if directives.contains(&keyword.as_str()) {
+                format!("* {}", line.trim_start())
+            } else {
+                line.to_string()
+            }
+        })
+        .collect()
+}
+
+/// The vectors an ngspice run saves and writes for some signals, and the signals the netlist has
+/// no node or element for
+#[derive(Debug, Default, PartialEq)]
+pub struct NgspiceVectors {
+    pub vectors: Vec<String>,
+    pub unknown: Vec<String>,
+}
+
+/// Where `signals` are in an ngspice run of `netlist`
+fn ngspice_vectors(netlist: &str, signals: &[String]) -> NgspiceVectors {
+    let summary = netlist::parse(netlist);
+    let mut result = NgspiceVectors::default();
+    for signal in signals {
+        match ngspice_signal_vectors(signal, &summary) {
+            Some(vectors) => {
+                for vector in vectors {
+                    if !result.vectors.contains(&vector) {
+                        result.vectors.push(vector);
+                    }
+                }
+            }
+            None => result.unknown.push(signal.clone()),
         }
     }
+    result
+}
 
-    // Add plotwinsize option based on quality
-    let plotwinsize = match waveform_quality {
-        "fast" => 128,
-        "balanced" => 0,
-        "smooth" => 0,
-        _ => 0,
-    };
+/// Signals a request saves that an ngspice run of `netlist` leaves out, as the netlist has no
+/// node or element for them
+pub fn unknown_ngspice_signals(netlist: &str, requested: &[String]) -> Vec<String> {
+    saved_signals(netlist, requested).map_or_else(Vec::new, |signals| ngspice_vectors(netlist, &signals).unknown)
+}
 
-    if !netlist.to_lowercase().contains(".options plotwinsize") {
-        if let Some(end_idx) = lines.iter().position(|l| l.trim().to_lowercase() == ".end") {
-            lines.insert(end_idx, format!(".options plotwinsize={}", plotwinsize));
+/// ngspice's names for a signal, lowercase: `v(out)` for each node of a voltage, `v1#branch` for
+/// the current of a source or inductor, `@r1[i]` for other elements' currents. Nodes inside a
+/// subcircuit (`v(x1.mid)`) are kept when the instance exists. None when nothing matches
+fn ngspice_signal_vectors(signal: &str, summary: &netlist::NetlistSummary) -> Option<Vec<String>> {
+    let lower = signal.trim().to_lowercase();
+    let has = |names: &[String], name: &str| names.iter().any(|n| n.eq_ignore_ascii_case(name));
+    let is_node = |node: &str| {
+        has(&summary.nodes, node) || node.split_once('.').is_some_and(|(instance, _)| has(&summary.components, instance))
+    };
+    let Some((kind, args)) = lower.strip_suffix(')').and_then(|rest| rest.split_once('(')) else {
+        // A bare name in a .plot/.probe directive is a node
+        return is_node(&lower).then(|| vec![format!("v({})", lower)]);
+    };
+    match kind {
+        // Ground is always zero and is no vector of its own
+        "v" => args
+            .split(',')
+            .map(str::trim)
+            .filter(|node| *node != "0")
+            .map(|node| is_node(node).then(|| format!("v({})", node)))
+            .collect(),
+        "i" if has(&summary.components, args.trim()) => {
+            let element = args.trim();
+            Some(vec![match element.chars().next() {
+                Some('v' | 'l' | 'e' | 'h') => format!("{}#branch", element),
+                _ => format!("@{}[i]", element),
+            }])
         }
+        _ => None,
     }
+}
 
-    lines.join("\n")
+/// The `.options` line for a quality profile, leaving out anything the netlist already sets
+pub(crate) fn quality_options_line(netlist: &str, simulator: &str, quality: &QualityProfile) -> Option<String> {
+    let options: Vec<String> = quality
+        .options(simulator)
+        .iter()
+        .filter(|(name, _)| !netlist_sets_option(netlist, &name.to_lowercase()))
+        .map(|(name, value)| match value.as_str() {
+            "" => name.clone(),
+            value => format!("{}={}", name, value),
+        })
+        .collect();
+
+    (!options.is_empty()).then(|| format!(".options {}", options.join(" ")))
+}
+
+/// Whether an `.option(s)`/`.opt` line, or an `option` command in a .control section, sets `name`
+fn netlist_sets_option(netlist: &str, name: &str) -> bool {
+    netlist.lines().any(|line| {
+        let lower = line.trim().to_lowercase();
+        let mut tokens = lower.split(|c: char| c.is_whitespace() || c == '=');
+        matches!(tokens.next(), Some(".opt" | ".option" | ".options" | "option" | "options"))
+            && tokens.any(|token| token == name)
+    })
 }
 
 /// Parse an LTspice .raw file (binary format)
-fn parse_raw_file(path: &PathBuf) -> Result<SimulationResults, Box<dyn std::error::Error + Send + Sync>> {
-    let data = std::fs::read(path)?;
+fn parse_raw_file(path: &PathBuf, options: &ProcessOptions) -> Result<SimulationResults, Box<dyn std::error::Error + Send + Sync>> {
+    let data = read_raw_file(path, options)?;
 
     // LTspice raw files have a UTF-16LE header followed by binary data
-    // Find the "Binary:" marker
-
-    // First, decode as UTF-16LE to find header info
-    let (header_text, _, _) = UTF_16LE.decode(&data);
+    let (header_text, _) = utf16_raw_header(&data);
 
     // Parse header to get variable names and count
     let mut num_vars = 0;
@@ -1332,6 +3915,7 @@ fn parse_raw_file(path: &PathBuf) -> Result<SimulationResults, Box<dyn std::erro
     let mut variables: Vec<(String, String)> = Vec::new();
     let mut in_variables = false;
     let mut is_double = false; // float32 by default, float64 if "double" in Flags
+    let mut is_fastaccess = false; // column-major, written by "Convert to Fast Access"
 
     for line in header_text.lines() {
         let line = line.trim();
@@ -1346,9 +3930,14 @@ fn parse_raw_file(path: &PathBuf) -> Result<SimulationResults, Box<dyn std::erro
             }
         } else if line.starts_with("Flags:") {
             // Check if double precision: "Flags: real double forward" vs "Flags: real forward"
-            is_double = line.to_lowercase().contains("double");
+            let flags = line.to_lowercase();
+            is_double = flags.contains("double");
+            is_fastaccess = flags.contains("fastaccess");
         } else if line == "Variables:" {
             in_variables = true;
+        } else if line == "Values:" {
+            // Written with -ascii: the whole file is UTF-16LE text laid out like ngspice's ASCII output
+            return parse_ngspice_raw_data(UTF_16LE.decode(&data).0.as_bytes());
         } else if line == "Binary:" {
             break;
         } else if in_variables && !line.is_empty() {
@@ -1364,9 +3953,12 @@ fn parse_raw_file(path: &PathBuf) -> Result<SimulationResults, Box<dyn std::erro
 
     log::info!("Parsed header: num_vars={}, num_points={}, variables={:?}", num_vars, num_points, variables);
 
-    if num_vars == 0 || num_points == 0 {
+    if num_vars == 0 {
         return Err("Could not parse raw file header".into());
     }
+    if num_points == 0 {
+        return Err("The raw file has no data points; the simulation produced no output".into());
+    }
 
     if variables.len() != num_vars {
         log::warn!("Variable count mismatch: header says {} but parsed {}", num_vars, variables.len());
@@ -1382,49 +3974,47 @@ fn parse_raw_file(path: &PathBuf) -> Result<SimulationResults, Box<dyn std::erro
     // LTspice "real double" format: all variables are float64
     let binary_data = &data[binary_start..];
 
-    // Calculate expected size: time (8 bytes) + other vars (4 bytes each) per point
-    // Unless is_double, then all are 8 bytes
-    let bytes_per_point = if is_double {
-        num_vars * 8
-    } else {
-        8 + (num_vars - 1) * 4  // time is always float64, others are float32
-    };
+    // Time is always float64, other variables float32 unless is_double
+    let width = |var: usize| if is_double || var == 0 { 8 } else { 4 };
+    // Where a variable starts within a point record
+    let var_offset = |var: usize| (0..var).map(width).sum::<usize>();
+    let bytes_per_point = var_offset(num_vars);
     let expected_size = num_points * bytes_per_point;
 
-    log::info!("Binary data: {} bytes, expecting {} bytes ({} points x {} bytes/point, is_double={})",
-        binary_data.len(), expected_size, num_points, bytes_per_point, is_double);
+    log::info!("Binary data: {} bytes, expecting {} bytes ({} points x {} bytes/point, is_double={}, is_fastaccess={})",
+        binary_data.len(), expected_size, num_points, bytes_per_point, is_double, is_fastaccess);
 
-    if binary_data.len() < expected_size {
+    // A simulator that stopped mid-write leaves fewer points than the header declares; keep the
+    // complete ones. Fast access files are converted after the run, so a short one is just broken
+    let complete_points = num_points.min(binary_data.len() / bytes_per_point);
+    if complete_points == 0 || (is_fastaccess && complete_points < num_points) {
         return Err(format!(
-            "Binary data too short: expected {} bytes, got {}",
-            expected_size,
-            binary_data.len()
+            "The raw file is cut short: {} points declared but only {} of {} data bytes are present",
+            num_points,
+            binary_data.len(),
+            expected_size
         )
         .into());
     }
 
     // Parse the binary data using direct offset reads (matching TypeScript implementation)
+    // Normally one record per point; fast access files store each variable's points together
     let mut all_data: Vec<Vec<f64>> = vec![Vec::with_capacity(num_points); num_vars];
 
-    for point in 0..num_points {
-        let point_offset = point * bytes_per_point;
-
-        // Read time (always float64, 8 bytes)
-        let time_value = read_f64_le(binary_data, point_offset)?;
-        all_data[0].push(time_value);
-
-        // Read other variables
-        for var in 1..num_vars {
-            let value = if is_double {
-                // All float64
-                let offset = point_offset + var * 8;
+    for (var, values) in all_data.iter_mut().enumerate() {
+        let (width, start) = (width(var), var_offset(var));
+        for point in 0..complete_points {
+            let offset = if is_fastaccess {
+                start * num_points + point * width
+            } else {
+                point * bytes_per_point + start
+            };
+            let value = if width == 8 {
                 read_f64_le(binary_data, offset)?
             } else {
-                // Other variables are float32
-                let offset = point_offset + 8 + (var - 1) * 4;
                 read_f32_le(binary_data, offset)? as f64
             };
-            all_data[var].push(value);
+            values.push(value);
         }
     }
 
@@ -1444,50 +4034,266 @@ fn parse_raw_file(path: &PathBuf) -> Result<SimulationResults, Box<dyn std::erro
         }
     }
 
+    // Determine analysis type from directives in header
+    let header_lower = header_text.to_lowercase();
+    let analysis_type = if header_lower.contains("noise spectral density") {
+        "noise"
+    } else if header_lower.contains("transient analysis") {
+        "transient"
+    } else if header_lower.contains("ac analysis") {
+        "ac"
+    } else if header_lower.contains("dc") {
+        "dc"
+    } else {
+        "transient"
+    };
+
     let traces: Vec<Trace> = variables
         .iter()
         .enumerate()
         .skip(1) // Skip time variable (index 0)
         .map(|(i, (name, var_type))| {
             // i is the original index (1, 2, 3, ...) so it matches all_data indices
-            let unit = match var_type.as_str() {
-                "voltage" => "V",
-                "current" => "A",
-                "time" => "s",
-                _ => "",
-            };
             Trace {
                 name: name.clone(),
                 data: all_data.get(i).cloned().unwrap_or_default(),
-                unit: unit.to_string(),
+                unit: variable_unit(var_type, analysis_type).to_string(),
+                phase: None,
+                raw_name: None,
             }
         })
         .collect();
 
-    // Determine analysis type from directives in header
-    let analysis_type = if header_text.to_lowercase().contains("transient analysis") {
-        "transient"
-    } else if header_text.to_lowercase().contains("ac analysis") {
-        "ac"
-    } else if header_text.to_lowercase().contains("dc") {
-        "dc"
-    } else {
-        "transient"
-    };
-
     // Get the x-axis label from the first variable name
     let x_axis_label = variables.first()
         .map(|(name, _)| name.to_lowercase())
         .unwrap_or_else(|| "time".to_string());
+    let x_axis_unit = variables.first().and_then(|(_, var_type)| axis_unit(var_type, analysis_type));
 
     Ok(SimulationResults {
         time,
         traces,
         analysis_type: analysis_type.to_string(),
         x_axis_label: Some(x_axis_label),
+        x_axis_unit,
+        scalar_results: None,
+        device_operating_points: BTreeMap::new(),
+        truncated: raw_truncation(num_points, complete_points),
+        plot_hints: None,
+        raw_header: None,
+    })
+}
+
+/// `tf` or `sens` when that is the netlist's only analysis; these print named values
+/// instead of producing a plot, so no raw file is expected
+fn scalar_analysis(netlist: &str) -> Option<&'static str> {
+    let mut analysis = None;
+    for line in netlist.lines() {
+        let line = line.trim().to_lowercase();
+        match line.split_whitespace().next().unwrap_or("") {
+            ".tran" | ".ac" | ".dc" | ".noise" | ".op" => return None,
+            ".tf" => analysis = analysis.or(Some("tf")),
+            ".sens" => analysis = analysis.or(Some("sens")),
+            _ => {}
+        }
+    }
+    analysis
+}
+
+/// Results of a `.tf`/`.sens` run: no plot, only the named values
+fn scalar_results(
+    analysis: &str,
+    values: BTreeMap<String, f64>,
+) -> Result<SimulationResults, Box<dyn std::error::Error + Send + Sync>> {
+    if values.is_empty() {
+        return Err(format!("No .{} results found in the simulator output", analysis).into());
+    }
+    Ok(SimulationResults {
+        time: vec![],
+        traces: vec![],
+        analysis_type: analysis.to_string(),
+        x_axis_label: None,
+        x_axis_unit: None,
+        scalar_results: Some(values),
+        device_operating_points: BTreeMap::new(),
+        truncated: None,
+        plot_hints: None,
+        raw_header: None,
     })
 }
 
+/// Values under `--- Transfer Function ---` in an LTspice log, such as
+/// `Transfer_function: 0.5 transfer` or `vin#Input_impedance: 2000 impedance` (tab separated)
+fn parse_ltspice_transfer_function(log: &str) -> BTreeMap<String, f64> {
+    let mut values = BTreeMap::new();
+    let mut lines = log.lines().map(str::trim).skip_while(|line| !line.eq_ignore_ascii_case("--- Transfer Function ---"));
+    lines.next();
+
+    for line in lines {
+        if line.is_empty() {
+            if values.is_empty() {
+                continue;
+            }
+            break;
+        }
+        let Some((name, rest)) = line.split_once(':') else { break };
+        let Some(value) = rest.split_whitespace().next().and_then(|v| v.parse::<f64>().ok()) else { break };
+        values.insert(name.trim().to_string(), value);
+    }
+    values
+}
+
+/// Devices under `Semiconductor Device Operating Points:` in an LTspice log, keyed by upper-cased name
+/// Each `--- Bipolar Transistors ---` style section lists devices in columns, one `Key: value...` row
+/// per quantity; an absent or unreadable block gives an empty map
+fn parse_ltspice_operating_points(log: &str) -> BTreeMap<String, DeviceOperatingPoint> {
+    let mut points = BTreeMap::new();
+    let mut lines = log
+        .lines()
+        .map(str::trim)
+        .skip_while(|line| !line.starts_with("Semiconductor Device Operating Points"));
+    lines.next();
+
+    // Kind and device names of the section being read
+    let mut section: Option<(String, Vec<String>)> = None;
+    for line in lines {
+        if let Some(title) = line.strip_prefix("---").and_then(|rest| rest.strip_suffix("---")) {
+            section = Some((device_kind(title.trim()), Vec::new()));
+            continue;
+        }
+        let Some((kind, names)) = section.as_mut() else {
+            if line.is_empty() {
+                continue;
+            }
+            break;
+        };
+        // A blank line ends a section; anything else that isn't a row ends the block
+        let Some((key, row)) = line.split_once(':').filter(|_| !line.is_empty()) else {
+            section = None;
+            continue;
+        };
+        let columns: Vec<&str> = row.split_whitespace().collect();
+        let key = key.trim();
+        if key == "Name" {
+            *names = columns.iter().map(|name| name.to_uppercase()).collect();
+            for name in names.iter() {
+                points.insert(
+                    name.clone(),
+                    DeviceOperatingPoint { kind: kind.clone(), model: None, values: BTreeMap::new() },
+                );
+            }
+            continue;
+        }
+        if columns.len() != names.len() {
+            break;
+        }
+        for (name, column) in names.iter().zip(columns) {
+            let Some(point) = points.get_mut(name) else { continue };
+            if key == "Model" {
+                point.model = Some(column.to_string());
+            } else if let Ok(value) = column.parse::<f64>() {
+                point.values.insert(key.to_string(), value);
+            }
+        }
+    }
+    points
+}
+
+/// Device kind from an operating point section title such as "MOSFET Transistors"
+fn device_kind(title: &str) -> String {
+    let lower = title.to_lowercase();
+    if lower.starts_with("bipolar") {
+        "bjt".to_string()
+    } else if lower.starts_with("mosfet") || lower.starts_with("vdmos") {
+        "mosfet".to_string()
+    } else if lower.starts_with("jfet") {
+        "jfet".to_string()
+    } else if lower.starts_with("diode") {
+        "diode".to_string()
+    } else {
+        lower
+    }
+}
+
+/// `name = value` lines printed by ngspice's `print all` for single-value vectors
+fn parse_ngspice_scalars(output: &str) -> BTreeMap<String, f64> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let (name, value) = line.split_once('=')?;
+            let name = name.trim();
+            // Lines like "Doing analysis at TEMP = 27.000000" are not vectors
+            if name.is_empty() || name.contains(char::is_whitespace) {
+                return None;
+            }
+            Some((name.to_string(), value.trim().parse::<f64>().ok()?))
+        })
+        .collect()
+}
+
+/// Unit of a raw file variable from its declared type, as LTspice or ngspice write it
+/// Noise analyses store spectral densities: node outputs are V/√Hz, current outputs A/√Hz
+/// Types without a unit, like a `.step`/`.dc` parameter sweep (`sweep`, `notype`), give ""
+pub(crate) fn variable_unit(var_type: &str, analysis_type: &str) -> &'static str {
+    match (analysis_type, var_type.to_lowercase().as_str()) {
+        (_, "time") => "s",
+        (_, "frequency") => "Hz",
+        ("noise", "voltage" | "voltage-density" | "onoise-spectrum" | "inoise-spectrum") => "V/√Hz",
+        ("noise", "current" | "current-density") => "A/√Hz",
+        (_, "voltage-density") => "V/√Hz",
+        (_, "current-density") => "A/√Hz",
+        (_, "sqr-voltage-density") => "V²/Hz",
+        (_, "sqr-current-density") => "A²/Hz",
+        (_, "sqr-voltage") => "V²",
+        (_, "sqr-current") => "A²",
+        (_, "voltage") => "V",
+        (_, "current" | "device_current" | "subckt_current") => "A",
+        (_, "resistance" | "res-sweep" | "impedance") => "Ω",
+        (_, "admittance" | "conductance") => "S",
+        (_, "temperature" | "temp-sweep") => "°C",
+        (_, "power") => "W",
+        (_, "capacitance") => "F",
+        (_, "charge") => "C",
+        (_, "phase") => "°",
+        (_, "decibel") => "dB",
+        _ => "",
+    }
+}
+
+/// Unit of the independent variable, None when its type has none
+pub(crate) fn axis_unit(var_type: &str, analysis_type: &str) -> Option<String> {
+    Some(variable_unit(var_type, analysis_type)).filter(|unit| !unit.is_empty()).map(str::to_string)
+}
+
+/// Input-referred noise is in A/√Hz when the `.noise` input is a current source; raw files
+/// declare it as a voltage either way, so the source is looked up in the netlist
+fn with_noise_input_unit(mut results: SimulationResults, netlist: &str) -> SimulationResults {
+    if results.analysis_type != "noise" || !noise_input_is_current_source(netlist) {
+        return results;
+    }
+    for trace in &mut results.traces {
+        let name = trace.name.to_lowercase();
+        if name.contains("inoise") {
+            trace.unit = "A/√Hz".to_string();
+        }
+    }
+    results
+}
+
+/// Whether the source named in `.noise V(out[,ref]) <source> ...` is a current source
+fn noise_input_is_current_source(netlist: &str) -> bool {
+    netlist
+        .lines()
+        .map(|line| line.trim().to_lowercase())
+        .find(|line| line.starts_with(".noise"))
+        .and_then(|line| {
+            // The output may contain spaces, as in `v(out, ref)`; the source follows its `)`
+            let after_output = &line[line.find(')')? + 1..];
+            after_output.split_whitespace().next().map(|source| source.starts_with('i'))
+        })
+        .unwrap_or(false)
+}
+
 /// Read a little-endian f64 from a byte slice at the given offset
 fn read_f64_le(data: &[u8], offset: usize) -> Result<f64, Box<dyn std::error::Error + Send + Sync>> {
     if offset + 8 > data.len() {
@@ -1513,6 +4319,30 @@ fn find_subsequence(haystack: &[u8], needle: &[u8]) -> Option<usize> {
         .position(|window| window == needle)
 }
 
+/// The UTF-16LE header at the start of an LTspice raw file, up to its `Binary:` or `Values:`
+/// line, or all of `data` if it has neither, and how many bytes the last decode covered
+/// Decodes a window that doubles until it holds that line, so the size of what is decoded
+/// follows the header's, not the file's
+fn utf16_raw_header(data: &[u8]) -> (String, usize) {
+    let mut window = RAW_HEADER_WINDOW;
+    loop {
+        // Whole code units only; a surrogate pair cut in two just decodes as a replacement character
+        let end = window.min(data.len()) & !1;
+        let (text, _, _) = UTF_16LE.decode(&data[..end]);
+        let mut header_len = 0;
+        for line in text.split_inclusive('\n') {
+            header_len += line.len();
+            if matches!(line.trim(), "Binary:" | "Values:") {
+                return (text[..header_len].to_string(), end);
+            }
+        }
+        if end + 1 >= data.len() {
+            return (text.into_owned(), end);
+        }
+        window *= 2;
+    }
+}
+
 /// Find the binary data marker in LTspice raw file
 /// Tries multiple formats: UTF-16LE with \n, UTF-16LE with \r\n, UTF-8
 fn find_binary_marker(data: &[u8]) -> Option<usize> {
@@ -1556,24 +4386,56 @@ fn find_binary_marker(data: &[u8]) -> Option<usize> {
 mod tests {
     use super::*;
 
+    fn quality(name: &str) -> QualityProfile {
+        crate::quality::default_quality_profiles().remove(name).unwrap()
+    }
+
     #[test]
     fn test_prepare_netlist_adds_backanno() {
         let netlist = "* Test\nV1 in 0 1\nR1 in out 1k\n.tran 1m\n.end";
-        let prepared = prepare_netlist(netlist, "balanced");
+        let prepared = prepare_netlist(netlist, &quality("balanced"), true, None);
         assert!(prepared.contains(".backanno"));
     }
 
     #[test]
     fn test_prepare_netlist_adds_save_all() {
         let netlist = "* Test\nV1 in 0 1\n.tran 1m\n.end";
-        let prepared = prepare_netlist(netlist, "balanced");
+        let prepared = prepare_netlist(netlist, &quality("balanced"), true, None);
         assert!(prepared.contains(".save all"));
     }
 
+    #[test]
+    fn test_prepare_netlist_without_auto_options_adds_only_save() {
+        let netlist = "* Test\nV1 in 0 PULSE(0 1 0 1n 1n 5u 10u)\nR1 in out 1k\n.tran 1m\n.meas tran vmax MAX V(out)\n.end";
+        for profile in ["fast", "balanced", "smooth"] {
+            let prepared = prepare_netlist(netlist, &quality(profile), false, None);
+            assert_eq!(prepared, netlist.replace(".end", ".save all\n.end"));
+        }
+        let saved = vec!["V(out)".to_string()];
+        let prepared = prepare_netlist(netlist, &quality("fast"), false, Some(&saved));
+        assert_eq!(prepared, netlist.replace(".end", ".save V(out)\n.end"));
+
+        let ngspice = prepare_ngspice_netlist(netlist, &quality("smooth"), false, &PathBuf::from("/tmp/test.raw"), None, None);
+        assert!(!ngspice.contains(".options"));
+        assert!(ngspice.contains("write test.raw all"));
+    }
+
+    #[test]
+    fn test_injected_directives() {
+        let netlist = "* Test\nV1 in 0 1\n.probe V(in)\n.tran 1m\n.end";
+        let ltspice = prepare_netlist(netlist, &quality("fast"), true, Some(&["V(in)".to_string()]));
+        assert_eq!(injected_directives(netlist, &ltspice), [".backanno", ".save V(in)", ".options plotwinsize=128"]);
+        let bare = prepare_netlist(netlist, &quality("fast"), false, Some(&["V(in)".to_string()]));
+        assert_eq!(injected_directives(netlist, &bare), [".save V(in)"]);
+
+        let ngspice = prepare_ngspice_netlist(netlist, &quality("balanced"), true, &PathBuf::from("/tmp/test.raw"), None, None);
+        assert_eq!(injected_directives(netlist, &ngspice), [".control", "run", "write test.raw all", "quit", ".endc"]);
+    }
+
     #[test]
     fn test_prepare_netlist_does_not_duplicate_backanno() {
         let netlist = "* Test\nV1 in 0 1\n.backanno\n.tran 1m\n.end";
-        let prepared = prepare_netlist(netlist, "balanced");
+        let prepared = prepare_netlist(netlist, &quality("balanced"), true, None);
         // Should only have one .backanno
         let count = prepared.matches(".backanno").count();
         assert_eq!(count, 1);
@@ -1582,36 +4444,116 @@ mod tests {
     #[test]
     fn test_prepare_netlist_does_not_duplicate_save() {
         let netlist = "* Test\nV1 in 0 1\n.save V(out)\n.tran 1m\n.end";
-        let prepared = prepare_netlist(netlist, "balanced");
+        let prepared = prepare_netlist(netlist, &quality("balanced"), true, None);
         // Should not add .save all if .save already exists
         assert!(!prepared.contains(".save all"));
     }
 
+    #[test]
+    fn test_probe_and_plot_directives_become_saves() {
+        let netlist = "* Test\nV1 in 0 1\nR1 in out 1k\n.probe V(out) I(R1)\n.plot tran vdb(out) v(in)\n.tran 1m\n.end";
+        let requested = ["V(IN)".to_string(), "I(V1)".to_string()];
+        let saved = saved_signals(netlist, &requested).unwrap();
+        assert_eq!(saved, ["v(out)", "i(r1)", "v(in)", "I(V1)"]);
+        assert_eq!(saved_signals("* Test\n.tran 1m\n.end", &[]), None);
+        assert_eq!(saved_signals("* Test\n.tran 1m\n.end", &requested).unwrap(), requested);
+
+        // LTspice rejects both directives, so they are commented out on the line they were on
+        let ltspice = prepare_netlist(netlist, &quality("balanced"), true, Some(&saved));
+        let lines: Vec<&str> = ltspice.lines().collect();
+        assert_eq!(lines[3], "* .probe V(out) I(R1)");
+        assert_eq!(lines[4], "* .plot tran vdb(out) v(in)");
+        assert!(lines.contains(&".save v(out) i(r1) v(in) I(V1)"));
+        assert!(!ltspice.contains(".save all"));
+
+        // ngspice keeps .plot, and saves through the injected .control section
+        let raw_path = PathBuf::from("/tmp/test.raw");
+        let ngspice = prepare_ngspice_netlist(netlist, &quality("balanced"), true, &raw_path, None, Some(&saved));
+        let lines: Vec<&str> = ngspice.lines().collect();
+        assert_eq!(lines[3], "* .probe V(out) I(R1)");
+        assert_eq!(lines[4], ".plot tran vdb(out) v(in)");
+        let save = lines.iter().position(|l| *l == "save v(out) @r1[i] v(in) v1#branch").unwrap();
+        assert_eq!(lines[save + 1], "run");
+        assert_eq!(lines[save + 2], "write test.raw v(out) @r1[i] v(in) v1#branch");
+        assert!(!ngspice.contains(".save"));
+
+        // A netlist running its own .control section gets a .save line instead
+        let own_control = "* Test\nV1 in 0 1\n.probe V(in)\n.control\nrun\n.endc\n.end";
+        let ngspice = prepare_ngspice_netlist(own_control, &quality("balanced"), true, &raw_path, None, Some(&["v(in)".to_string()]));
+        assert!(ngspice.contains(".save v(in)\n.end"), "{}", ngspice);
+        assert!(ngspice.contains("* .probe V(in)"));
+    }
+
+    #[test]
+    fn test_ngspice_vectors() {
+        let netlist = "* Test\nV1 in 0 1\nR1 in out 1k\nL1 out mid 1u\nX1 mid 0 amp\nVprobe_R5 out 0 0\n.tran 1m\n.end";
+        let signals = |names: &[&str]| names.iter().map(|name| name.to_string()).collect::<Vec<_>>();
+        let vectors = ngspice_vectors(
+            netlist,
+            &signals(&["V(Out)", "I(R1)", "I(Vprobe_R5)", "I(L1)", "V(in,0)", "V(out, mid)", "V(X1.inner)", "mid", "V(OUT)"]),
+        );
+        assert_eq!(vectors.vectors, ["v(out)", "@r1[i]", "vprobe_r5#branch", "l1#branch", "v(in)", "v(mid)", "v(x1.inner)"]);
+        assert!(vectors.unknown.is_empty());
+
+        let vectors = ngspice_vectors(netlist, &signals(&["V(nope)", "I(R9)", "I(X1:1)", "V(x2.inner)", "V(out)", "vdb(out)"]));
+        assert_eq!(vectors.vectors, ["v(out)"]);
+        assert_eq!(vectors.unknown, ["V(nope)", "I(R9)", "I(X1:1)", "V(x2.inner)", "vdb(out)"]);
+    }
+
+    #[test]
+    fn test_unknown_signals_are_left_out_of_the_control_block() {
+        let netlist = "* Test\nV1 in 0 1\nR1 in out 1k\n.probe V(missing)\n.tran 1m\n.end";
+        let requested = ["V(out)".to_string()];
+        assert_eq!(unknown_ngspice_signals(netlist, &requested), ["v(missing)"]);
+        assert_eq!(unknown_ngspice_signals(netlist, &[]), ["v(missing)"]);
+        assert!(unknown_ngspice_signals("* Test\n.tran 1m\n.end", &[]).is_empty());
+
+        let raw_path = PathBuf::from("/tmp/test.raw");
+        let saved = saved_signals(netlist, &requested).unwrap();
+        let ngspice = prepare_ngspice_netlist(netlist, &quality("balanced"), false, &raw_path, None, Some(&saved));
+        assert_eq!(injected_directives(netlist, &ngspice), [".control", "save v(out)", "run", "write test.raw v(out)", "quit", ".endc"]);
+
+        // With nothing left to save, everything is written
+        let saved = saved_signals(netlist, &[]).unwrap();
+        let ngspice = prepare_ngspice_netlist(netlist, &quality("balanced"), false, &raw_path, None, Some(&saved));
+        assert_eq!(injected_directives(netlist, &ngspice), [".control", "run", "write test.raw all", "quit", ".endc"]);
+    }
+
+    #[test]
+    fn test_noise_runs_write_the_whole_noise_plot() {
+        let netlist = "* Test\nV1 in 0 ac 1\nR1 in out 1k\n.noise v(out) V1 dec 10 1 1meg\n.end";
+        let ngspice = prepare_ngspice_netlist(netlist, &quality("balanced"), false, &PathBuf::from("/tmp/test.raw"), None, Some(&["V(out)".to_string()]));
+        assert_eq!(
+            injected_directives(netlist, &ngspice),
+            [".control", "save v(out)", "run", "setplot noise1", "write test.raw all", "quit", ".endc"]
+        );
+    }
+
     #[test]
     fn test_prepare_netlist_plotwinsize_fast() {
         let netlist = "* Test\nV1 in 0 1\n.tran 1m\n.end";
-        let prepared = prepare_netlist(netlist, "fast");
+        let prepared = prepare_netlist(netlist, &quality("fast"), true, None);
         assert!(prepared.contains(".options plotwinsize=128"));
     }
 
     #[test]
     fn test_prepare_netlist_plotwinsize_balanced() {
         let netlist = "* Test\nV1 in 0 1\n.tran 1m\n.end";
-        let prepared = prepare_netlist(netlist, "balanced");
+        let prepared = prepare_netlist(netlist, &quality("balanced"), true, None);
         assert!(prepared.contains(".options plotwinsize=0"));
     }
 
     #[test]
     fn test_prepare_netlist_plotwinsize_smooth() {
         let netlist = "* Test\nV1 in 0 1\n.tran 1m\n.end";
-        let prepared = prepare_netlist(netlist, "smooth");
+        let prepared = prepare_netlist(netlist, &quality("smooth"), true, None);
         assert!(prepared.contains(".options plotwinsize=0"));
     }
 
     #[test]
     fn test_prepare_netlist_preserves_content() {
         let netlist = "* My Circuit\nV1 in 0 DC 5\nR1 in out 1k\nC1 out 0 1u\n.tran 10m\n.end";
-        let prepared = prepare_netlist(netlist, "balanced");
+        let prepared = prepare_netlist(netlist, &quality("balanced"), true, None);
         assert!(prepared.contains("* My Circuit"));
         assert!(prepared.contains("V1 in 0 DC 5"));
         assert!(prepared.contains("R1 in out 1k"));
@@ -1623,7 +4565,7 @@ mod tests {
     #[test]
     fn test_prepare_netlist_inserts_before_end() {
         let netlist = "* Test\nV1 in 0 1\n.tran 1m\n.end";
-        let prepared = prepare_netlist(netlist, "balanced");
+        let prepared = prepare_netlist(netlist, &quality("balanced"), true, None);
         let lines: Vec<&str> = prepared.lines().collect();
 
         // Find positions
@@ -1640,11 +4582,48 @@ mod tests {
         assert!(save_pos.unwrap() < end_pos.unwrap());
     }
 
+    #[test]
+    fn test_prepare_netlist_adds_missing_end() {
+        let netlist = "* Fragment\nV1 in 0 1\nR1 in out 1k\n.tran 1m";
+        let prepared = prepare_netlist(netlist, &quality("fast"), true, None);
+        assert_eq!(
+            prepared,
+            "* Fragment\nV1 in 0 1\nR1 in out 1k\n.tran 1m\n.backanno\n.save all\n.options plotwinsize=128\n.end"
+        );
+        assert!(!has_end_line(netlist));
+        assert!(has_end_line(&prepared));
+
+        let ngspice = prepare_ngspice_netlist(netlist, &quality("fast"), true, &PathBuf::from("/tmp/test.raw"), None, None);
+        let lines: Vec<&str> = ngspice.lines().collect();
+        assert_eq!(lines.last(), Some(&".end"));
+        assert!(lines.iter().position(|l| *l == ".options reltol=1e-2 trtol=10") < lines.iter().position(|l| *l == ".control"));
+    }
+
+    #[test]
+    fn test_prepare_netlist_finds_end_before_trailing_blank_lines() {
+        let netlist = "* Test\nV1 in 0 1\n.tran 1m\n.end   \r\n\n  \n";
+        let prepared = prepare_netlist(netlist, &quality("balanced"), true, None);
+        let lines: Vec<&str> = prepared.lines().collect();
+        assert_eq!(lines[3..], [".backanno", ".save all", ".options plotwinsize=0", ".end   ", "", "  "]);
+        assert!(has_end_line(netlist));
+    }
+
+    #[test]
+    fn test_prepare_netlist_finds_end_with_comment() {
+        let netlist = "* Test\nV1 in 0 1\n.tran 1m\n.END ; generated by the web app";
+        let prepared = prepare_netlist(netlist, &quality("balanced"), true, None);
+        assert!(prepared.ends_with(".save all\n.options plotwinsize=0\n.END ; generated by the web app"));
+        assert_eq!(prepared.matches(".end").count() + prepared.matches(".END").count(), 1);
+
+        assert_eq!(end_line(&["* x", ".ends", ".endc", ".end;done"]), Some(3));
+        assert_eq!(end_line(&["* x", ".ends opamp", ".endc"]), None);
+    }
+
     #[test]
     fn test_prepare_netlist_case_insensitive() {
         // Test with uppercase .END
         let netlist = "* Test\nV1 in 0 1\n.tran 1m\n.END";
-        let prepared = prepare_netlist(netlist, "balanced");
+        let prepared = prepare_netlist(netlist, &quality("balanced"), true, None);
         assert!(prepared.contains(".backanno"));
         assert!(prepared.contains(".save all"));
     }
@@ -1735,7 +4714,7 @@ R3 ref 0 100k
 .model DSCHOTTKY D(Is=1e-8 Rs=10 N=1.05)
 .end"#;
 
-        let prepared = prepare_netlist(netlist, "smooth");
+        let prepared = prepare_netlist(netlist, &quality("smooth"), true, None);
 
         // Verify original content preserved
         assert!(prepared.contains("* WiFi Wakeup Receiver"));
@@ -1754,7 +4733,7 @@ R3 ref 0 100k
     fn test_prepare_ngspice_netlist_adds_control_section() {
         let netlist = "* Test\nVin in 0 AC 1\nR1 in out 1k\nC1 out 0 100n\n.ac dec 10 1 100k\n.end";
         let raw_path = PathBuf::from("/tmp/test.raw");
-        let prepared = prepare_ngspice_netlist(netlist, &raw_path);
+        let prepared = prepare_ngspice_netlist(netlist, &quality("balanced"), true, &raw_path, None, None);
 
         assert!(prepared.contains(".control"));
         assert!(prepared.contains("run"));
@@ -1762,11 +4741,86 @@ R3 ref 0 100k
         assert!(prepared.contains(".endc"));
     }
 
+    #[test]
+    fn test_quality_options_per_level() {
+        let netlist = "* Test\nR1 in 0 1k\n.tran 1m\n.end";
+        assert_eq!(quality_options_line(netlist, "LTspice", &quality("fast")).as_deref(), Some(".options plotwinsize=128"));
+        assert_eq!(quality_options_line(netlist, "LTspice", &quality("balanced")).as_deref(), Some(".options plotwinsize=0"));
+        assert_eq!(quality_options_line(netlist, "LTspice", &quality("smooth")).as_deref(), Some(".options plotwinsize=0"));
+        assert_eq!(quality_options_line(netlist, "ngspice", &quality("fast")).as_deref(), Some(".options reltol=1e-2 trtol=10"));
+        assert_eq!(quality_options_line(netlist, "ngspice", &quality("balanced")), None);
+        assert_eq!(quality_options_line(netlist, "ngspice", &quality("smooth")).as_deref(), Some(".options interp reltol=1e-4"));
+    }
+
+    #[test]
+    fn test_quality_options_keep_user_settings() {
+        let netlist = "* Test\nR1 in 0 1k\n.OPTION RELTOL = 1e-3\n.tran 1m\n.end";
+        assert_eq!(quality_options_line(netlist, "ngspice", &quality("fast")).as_deref(), Some(".options trtol=10"));
+
+        let netlist = "* Test\n.opt plotwinsize=300\n.end";
+        assert_eq!(quality_options_line(netlist, "LTspice", &quality("fast")), None);
+
+        let netlist = "* Test\n.control\noption interp reltol=1e-5\nrun\n.endc\n.end";
+        assert_eq!(quality_options_line(netlist, "ngspice", &quality("smooth")), None);
+    }
+
+    #[test]
+    fn test_custom_quality_profile_in_both_engines() {
+        let profile: QualityProfile = serde_json::from_value(serde_json::json!({
+            "ltspice": {"plotwinsize": "0", "numdgt": "7", "reltol": "1e-4"},
+            "ngspice": {"reltol": "1e-5", "numdgt": "7", "interp": ""},
+        }))
+        .unwrap();
+        let netlist = "* Test\nR1 in 0 1k\n.options Reltol=1e-3\n.tran 1m\n.end";
+
+        let ltspice = prepare_netlist(netlist, &profile, true, None);
+        assert!(ltspice.contains(".options numdgt=7 plotwinsize=0\n.end"));
+        let ngspice = prepare_ngspice_netlist(netlist, &profile, true, &PathBuf::from("/tmp/test.raw"), None, None);
+        assert!(ngspice.contains(".options interp numdgt=7\n"));
+        // The netlist's own reltol wins in both
+        assert!(!ltspice.contains("reltol=1e-4") && !ngspice.contains("reltol=1e-5"));
+
+        // Without plotwinsize LTspice compresses, so the output size can't be estimated
+        assert_eq!(estimate_raw_file_bytes("R1 a 0 1k\n.tran 1u 10m\n.end", &QualityProfile::default()), None);
+        assert!(estimate_raw_file_bytes("R1 a 0 1k\n.tran 1u 10m\n.end", &profile).is_some());
+    }
+
+    #[test]
+    fn test_prepare_ngspice_netlist_quality_options() {
+        let netlist = "* Test\nR1 in 0 1k\n.tran 1m\n.end";
+        let raw_path = PathBuf::from("/tmp/test.raw");
+
+        let fast = prepare_ngspice_netlist(netlist, &quality("fast"), true, &raw_path, None, None);
+        let lines: Vec<&str> = fast.lines().collect();
+        let options_idx = lines.iter().position(|l| *l == ".options reltol=1e-2 trtol=10").unwrap();
+        let end_idx = lines.iter().position(|l| *l == ".end").unwrap();
+        assert!(options_idx < end_idx);
+
+        let balanced = prepare_ngspice_netlist(netlist, &quality("balanced"), true, &raw_path, None, None);
+        assert!(!balanced.contains(".options"));
+    }
+
+    #[test]
+    fn test_prepare_ngspice_netlist_thread_cap() {
+        let netlist = "* Test\nR1 in 0 1k\n.op\n.end";
+        let raw_path = PathBuf::from("/tmp/test.raw");
+        let prepared = prepare_ngspice_netlist(netlist, &quality("balanced"), true, &raw_path, Some(2), None);
+        let lines: Vec<&str> = prepared.lines().collect();
+
+        // The cap must be set before the analysis runs
+        let set_idx = lines.iter().position(|l| *l == "set num_threads=2").unwrap();
+        let run_idx = lines.iter().position(|l| *l == "run").unwrap();
+        assert!(set_idx < run_idx);
+
+        let uncapped = prepare_ngspice_netlist(netlist, &quality("balanced"), true, &raw_path, None, None);
+        assert!(!uncapped.contains("num_threads"));
+    }
+
     #[test]
     fn test_prepare_ngspice_netlist_preserves_existing_control() {
         let netlist = "* Test\nVin in 0 AC 1\n.control\nrun\n.endc\n.end";
         let raw_path = PathBuf::from("/tmp/test.raw");
-        let prepared = prepare_ngspice_netlist(netlist, &raw_path);
+        let prepared = prepare_ngspice_netlist(netlist, &quality("balanced"), true, &raw_path, None, None);
 
         // Should not add another .control section
         let control_count = prepared.matches(".control").count();
@@ -1804,7 +4858,7 @@ Values:
         let raw_path = temp_dir.path().join("test.raw");
         std::fs::write(&raw_path, raw_content).unwrap();
 
-        let results = parse_ngspice_raw_file(&raw_path).unwrap();
+        let results = parse_ngspice_raw_file(&raw_path, &ProcessOptions::default()).unwrap();
 
         assert_eq!(results.analysis_type, "transient");
         assert_eq!(results.x_axis_label, Some("time".to_string()));
@@ -1830,6 +4884,37 @@ Values:
         assert!((v_out.data[2] - 0.8).abs() < 1e-10);
     }
 
+    #[test]
+    fn test_parse_ngspice_raw_file_truncated() {
+        // Declares 4 points; the third is missing v(out) and the fourth never got written
+        let raw_content = "Title: * test circuit\nPlotname: Transient Analysis\nFlags: real\nNo. Variables: 3\nNo. Points: 4\nVariables:\n\t0\ttime\ttime\n\t1\tv(in)\tvoltage\n\t2\tv(out)\tvoltage\nValues:\n 0\t0.0\n\t1.0\n\t0.0\n\n 1\t1.0e-03\n\t1.0\n\t0.5\n\n 2\t2.0e-03\n\t1.0\n";
+        let results = parse_ngspice_raw_data(raw_content.as_bytes()).unwrap();
+        assert_eq!(results.time, vec![0.0, 1.0e-3]);
+        assert!(results.traces.iter().all(|trace| trace.data.len() == 2));
+        assert_eq!(
+            results.truncated,
+            Some(RawTruncation { expected_points: 4, actual_points: 2, likely_cause: None })
+        );
+
+        // Binary: two whole points of three, plus half of the third
+        let header = "Title: * test circuit\nPlotname: Transient Analysis\nFlags: real\nNo. Variables: 2\nNo. Points: 3\nVariables:\n\t0\ttime\ttime\n\t1\tv(out)\tvoltage\nBinary:\n";
+        let mut data = header.as_bytes().to_vec();
+        for value in [0.0f64, 0.0, 1.0e-3, 0.5, 2.0e-3] {
+            data.extend_from_slice(&value.to_le_bytes());
+        }
+        let results = parse_ngspice_raw_data(&data).unwrap();
+        assert_eq!(results.traces[0].data, vec![0.0, 0.5]);
+        assert_eq!(results.truncated.as_ref().map(|t| (t.expected_points, t.actual_points)), Some((3, 2)));
+
+        // Nothing complete to keep
+        let err = parse_ngspice_raw_data(&data[..header.len() + 8]).unwrap_err();
+        assert_eq!(err.to_string(), "The raw file is cut short: 3 points declared but none are complete");
+
+        // Complete files are not marked
+        data.extend_from_slice(&0.8f64.to_le_bytes());
+        assert_eq!(parse_ngspice_raw_data(&data).unwrap().truncated, None);
+    }
+
     #[test]
     fn test_parse_ngspice_raw_file_ac_complex() {
         // Create a mock ngspice ASCII raw file for AC analysis with complex values
@@ -1862,7 +4947,7 @@ Values:
         let raw_path = temp_dir.path().join("test_ac.raw");
         std::fs::write(&raw_path, raw_content).unwrap();
 
-        let results = parse_ngspice_raw_file(&raw_path).unwrap();
+        let results = parse_ngspice_raw_file(&raw_path, &ProcessOptions::default()).unwrap();
 
         assert_eq!(results.analysis_type, "ac");
         assert_eq!(results.x_axis_label, Some("frequency".to_string()));
@@ -1893,6 +4978,12 @@ Values:
 
         // At 100 Hz: magnitude of (0.0995, -0.995) = sqrt(0.0099 + 0.990) ≈ 1
         assert!((v_out.data[2] - 1.0).abs() < 1e-3);
+
+        // Phase in degrees: 0, -45, about -84.3
+        let phase = v_out.phase.as_ref().unwrap();
+        assert!(phase[0].abs() < 1e-10);
+        assert!((phase[1] + 45.0).abs() < 1e-6);
+        assert!((phase[2] + 84.29).abs() < 1e-2);
     }
 
     #[test]
@@ -1911,35 +5002,597 @@ Values:
  0	0.000000000000000e+00
 	0.000000000000000e+00
 
- 1	2.500000000000000e+00
-	2.500000000000000e+00
+ 1	2.500000000000000e+00
+	2.500000000000000e+00
+
+ 2	5.000000000000000e+00
+	5.000000000000000e+00
+"#;
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let raw_path = temp_dir.path().join("test_dc.raw");
+        std::fs::write(&raw_path, raw_content).unwrap();
+
+        let results = parse_ngspice_raw_file(&raw_path, &ProcessOptions::default()).unwrap();
+
+        assert_eq!(results.analysis_type, "dc");
+        assert_eq!(results.x_axis_label, Some("v-sweep".to_string()));
+        assert_eq!(results.x_axis_unit.as_deref(), Some("V"));
+        assert_eq!(results.time.len(), 3);
+        assert_eq!(results.traces.len(), 1);
+
+        // Check sweep values
+        assert!((results.time[0] - 0.0).abs() < 1e-10);
+        assert!((results.time[1] - 2.5).abs() < 1e-10);
+        assert!((results.time[2] - 5.0).abs() < 1e-10);
+
+        // Check v(out) trace
+        let v_out = &results.traces[0];
+        assert_eq!(v_out.name, "v(out)");
+        assert!((v_out.data[0] - 0.0).abs() < 1e-10);
+        assert!((v_out.data[1] - 2.5).abs() < 1e-10);
+        assert!((v_out.data[2] - 5.0).abs() < 1e-10);
+    }
+
+    #[test]
+    fn test_parse_ngspice_raw_file_noise() {
+        // Spectral densities from `.noise v(out) vin dec 1 1 100`
+        let raw_content = r#"Title: * noise test circuit
+Date: Sat Feb  7 12:00:00  2026
+Plotname: Noise Spectral Density Curves
+Flags: real
+No. Variables: 3
+No. Points: 3
+Variables:
+	0	frequency	frequency grid=3
+	1	inoise_spectrum	voltage
+	2	onoise_spectrum	voltage
+Values:
+ 0	1.000000000000000e+00
+	4.070000000000000e-09
+	4.070000000000000e-08
+
+ 1	1.000000000000000e+01
+	4.070000000000000e-09
+	4.050000000000000e-08
+
+ 2	1.000000000000000e+02
+	4.070000000000000e-09
+	2.880000000000000e-08
+"#;
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let raw_path = temp_dir.path().join("test_noise.raw");
+        std::fs::write(&raw_path, raw_content).unwrap();
+
+        let results = parse_ngspice_raw_file(&raw_path, &ProcessOptions::default()).unwrap();
+
+        assert_eq!(results.analysis_type, "noise");
+        assert_eq!(results.x_axis_label, Some("frequency".to_string()));
+        assert_eq!(results.time, vec![1.0, 10.0, 100.0]);
+        assert_eq!(results.traces.len(), 2);
+        assert_eq!(results.traces[0].name, "inoise_spectrum");
+        assert_eq!(results.traces[0].unit, "V/√Hz");
+        assert_eq!(results.traces[1].unit, "V/√Hz");
+        assert!((results.traces[1].data[2] - 2.88e-8).abs() < 1e-15);
+        assert!(results.traces[1].phase.is_none());
+
+        // Driven from a current source, input-referred noise is a current density
+        let results = with_noise_input_unit(results, "* test\nI1 0 in AC 1\n.noise v(out, 0) I1 dec 1 1 100\n.end");
+        assert_eq!(results.traces[0].unit, "A/√Hz");
+        assert_eq!(results.traces[1].unit, "V/√Hz");
+    }
+
+    /// LTspice .raw file: UTF-16LE header, then float64 frequency and float32 values per point
+    fn write_ltspice_raw(path: &Path, header: &str, points: &[(f64, Vec<f32>)]) {
+        let mut data: Vec<u8> = header.encode_utf16().flat_map(|c| c.to_le_bytes()).collect();
+        for (x, values) in points {
+            data.extend_from_slice(&x.to_le_bytes());
+            for value in values {
+                data.extend_from_slice(&value.to_le_bytes());
+            }
+        }
+        std::fs::write(path, data).unwrap();
+    }
+
+    #[test]
+    fn test_parse_ltspice_raw_file_noise() {
+        let header = "Title: * noise.asc\n\
+Date: Sat Feb  7 12:00:00 2026\n\
+Plotname: Noise Spectral Density - (V/Hz\u{bd} or A/Hz\u{bd})\n\
+Flags: real forward log\n\
+No. Variables: 4\n\
+No. Points: 2\n\
+Offset:   0.0000000000000000e+000\n\
+Command: Linear Technology Corporation LTspice XVII\n\
+Variables:\n\
+\t0\tfrequency\tfrequency\n\
+\t1\tV(onoise)\tvoltage\n\
+\t2\tV(r1)\tvoltage\n\
+\t3\tV(inoise)\tvoltage\n\
+Binary:\n";
+        let temp_dir = tempfile::tempdir().unwrap();
+        let raw_path = temp_dir.path().join("noise.raw");
+        write_ltspice_raw(
+            &raw_path,
+            header,
+            &[(10.0, vec![4.0e-8, 4.0e-8, 4.0e-9]), (1000.0, vec![3.0e-8, 3.0e-8, 4.0e-9])],
+        );
+
+        let results = parse_raw_file(&raw_path, &ProcessOptions::default()).unwrap();
+
+        assert_eq!(results.analysis_type, "noise");
+        assert_eq!(results.x_axis_label, Some("frequency".to_string()));
+        assert_eq!(results.time, vec![10.0, 1000.0]);
+        let names: Vec<&str> = results.traces.iter().map(|t| t.name.as_str()).collect();
+        assert_eq!(names, vec!["V(onoise)", "V(r1)", "V(inoise)"]);
+        assert!(results.traces.iter().all(|t| t.unit == "V/√Hz"));
+        assert!((results.traces[0].data[1] - 3.0e-8).abs() < 1e-14);
+
+        // A voltage source input keeps the declared unit
+        let results = with_noise_input_unit(results, "V1 in 0 AC 1\n.noise V(out) V1 oct 10 10 1k\n.end");
+        assert_eq!(results.traces[2].unit, "V/√Hz");
+    }
+
+    #[test]
+    fn test_raw_header_decode_is_bounded_by_the_header() {
+        let header = "Title: * big.asc\nPlotname: Transient Analysis\nFlags: real forward\nNo. Variables: 2\n\
+No. Points: 500000\nVariables:\n\t0\ttime\ttime\n\t1\tV(out)\tvoltage\nBinary:\n";
+        let temp_dir = tempfile::tempdir().unwrap();
+        let raw_path = temp_dir.path().join("big.raw");
+        let points: Vec<(f64, Vec<f32>)> = (0..500_000).map(|i| (i as f64, vec![i as f32])).collect();
+        write_ltspice_raw(&raw_path, header, &points);
+        let data = std::fs::read(&raw_path).unwrap();
+        assert!(data.len() > 50 * RAW_HEADER_WINDOW);
+
+        // Only the header is decoded, however much data follows it
+        let (decoded, decoded_bytes) = utf16_raw_header(&data);
+        assert_eq!(decoded, header);
+        assert!(decoded_bytes <= RAW_HEADER_WINDOW, "{} bytes decoded", decoded_bytes);
+        let results = parse_raw_file(&raw_path, &ProcessOptions::default()).unwrap();
+        assert_eq!(results.time.len(), 500_000);
+        assert_eq!(results.traces[0].data[499_999], 499_999.0);
+
+        // A header longer than the first window is still read whole
+        let variables: String = (1..4000).map(|i| format!("\t{}\tV(n{})\tvoltage\n", i, i)).collect();
+        let long = format!("Title: * wide.asc\nNo. Variables: 4000\nVariables:\n\t0\ttime\ttime\n{}Binary:\n", variables);
+        assert!(long.len() * 2 > 2 * RAW_HEADER_WINDOW);
+        let mut data: Vec<u8> = long.encode_utf16().flat_map(|c| c.to_le_bytes()).collect();
+        data.extend(std::iter::repeat_n(0u8, 8 * RAW_HEADER_WINDOW));
+        let (decoded, decoded_bytes) = utf16_raw_header(&data);
+        assert_eq!(decoded, long);
+        assert!(decoded_bytes <= 4 * RAW_HEADER_WINDOW, "{} bytes decoded", decoded_bytes);
+
+        // Without a marker everything is decoded, as before
+        let data: Vec<u8> = "Title: x\nNo. Variables: 1\n".encode_utf16().flat_map(|c| c.to_le_bytes()).collect();
+        assert_eq!(utf16_raw_header(&data), ("Title: x\nNo. Variables: 1\n".to_string(), data.len()));
+    }
+
+    #[test]
+    fn test_parse_ltspice_raw_file_sweep_units() {
+        // .dc I1 0 10m 5m: the x axis is the source current
+        let header = "Title: * diode.asc\n\
+Plotname: DC transfer characteristic\n\
+Flags: real forward\n\
+No. Variables: 3\n\
+No. Points: 3\n\
+Variables:\n\
+\t0\ti1\tcurrent\n\
+\t1\tV(a)\tvoltage\n\
+\t2\tI(D1)\tdevice_current\n\
+Binary:\n";
+        let temp_dir = tempfile::tempdir().unwrap();
+        let raw_path = temp_dir.path().join("current_sweep.raw");
+        write_ltspice_raw(
+            &raw_path,
+            header,
+            &[(0.0, vec![0.0, 0.0]), (5e-3, vec![0.68, 5e-3]), (10e-3, vec![0.7, 10e-3])],
+        );
+        let results = parse_raw_file(&raw_path, &ProcessOptions::default()).unwrap();
+        assert_eq!(results.analysis_type, "dc");
+        assert_eq!(results.x_axis_label.as_deref(), Some("i1"));
+        assert_eq!(results.x_axis_unit.as_deref(), Some("A"));
+        assert_eq!(results.time, vec![0.0, 5e-3, 10e-3]);
+        let units: Vec<&str> = results.traces.iter().map(|t| t.unit.as_str()).collect();
+        assert_eq!(units, ["V", "A"]);
+
+        // .dc temp -40 125 82.5
+        let header = "Title: * diode.asc\n\
+Plotname: DC transfer characteristic\n\
+Flags: real forward\n\
+No. Variables: 2\n\
+No. Points: 3\n\
+Variables:\n\
+\t0\ttemp\ttemperature\n\
+\t1\tV(a)\tvoltage\n\
+Binary:\n";
+        let raw_path = temp_dir.path().join("temperature_sweep.raw");
+        write_ltspice_raw(&raw_path, header, &[(-40.0, vec![0.8]), (42.5, vec![0.65]), (125.0, vec![0.5])]);
+        let results = parse_raw_file(&raw_path, &ProcessOptions::default()).unwrap();
+        assert_eq!(results.x_axis_label.as_deref(), Some("temp"));
+        assert_eq!(results.x_axis_unit.as_deref(), Some("°C"));
+        assert_eq!(results.time, vec![-40.0, 42.5, 125.0]);
+    }
+
+    #[test]
+    fn test_parse_ngspice_raw_file_sweep_units() {
+        let raw = |scale: &str, scale_type: &str| {
+            format!(
+                "Title: * diode\n\
+Plotname: DC transfer characteristic\n\
+Flags: real\n\
+No. Variables: 3\n\
+No. Points: 2\n\
+Variables:\n\
+\t0\t{}\t{}\n\
+\t1\tv(a)\tvoltage\n\
+\t2\tv1#branch\tcurrent\n\
+Values:\n\
+ 0\t0.000000000000000e+00\n\
+\t0.000000000000000e+00\n\
+\t0.000000000000000e+00\n\
+\n\
+ 1\t1.000000000000000e-02\n\
+\t7.000000000000000e-01\n\
+\t-1.000000000000000e-02\n",
+                scale, scale_type
+            )
+        };
+        let cases = [
+            ("i-sweep", "current", Some("A")),
+            ("temp-sweep", "temp-sweep", Some("°C")),
+            ("res-sweep", "res-sweep", Some("Ω")),
+            ("sweep", "notype", None),
+        ];
+        for (scale, scale_type, unit) in cases {
+            let results = parse_ngspice_raw_data(raw(scale, scale_type).as_bytes()).unwrap();
+            assert_eq!(results.analysis_type, "dc");
+            assert_eq!(results.x_axis_label.as_deref(), Some(scale));
+            assert_eq!(results.x_axis_unit.as_deref(), unit, "{}", scale);
+            assert_eq!(results.time, vec![0.0, 1e-2]);
+            let units: Vec<&str> = results.traces.iter().map(|t| t.unit.as_str()).collect();
+            assert_eq!(units, ["V", "A"]);
+        }
+    }
+
+    #[test]
+    fn test_variable_unit() {
+        assert_eq!(variable_unit("device_current", "transient"), "A");
+        assert_eq!(variable_unit("Resistance", "dc"), "Ω");
+        assert_eq!(variable_unit("temperature", "dc"), "°C");
+        assert_eq!(variable_unit("power", "transient"), "W");
+        assert_eq!(variable_unit("voltage", "noise"), "V/√Hz");
+        assert_eq!(variable_unit("sweep", "dc"), "");
+        assert_eq!(axis_unit("sweep", "dc"), None);
+        assert_eq!(axis_unit("frequency", "noise").as_deref(), Some("Hz"));
+    }
+
+    #[test]
+    fn test_parse_ltspice_fastaccess_raw_file() {
+        let header = |flags: &str| {
+            format!(
+                "Title: * rc.asc\n\
+Plotname: Transient Analysis\n\
+Flags: {}\n\
+No. Variables: 3\n\
+No. Points: 3\n\
+Variables:\n\
+\t0\ttime\ttime\n\
+\t1\tV(in)\tvoltage\n\
+\t2\tI(R1)\tdevice_current\n\
+Binary:\n",
+                flags
+            )
+        };
+        let points: [(f64, [f32; 2]); 3] = [(0.0, [0.0, 0.0]), (1e-3, [1.0, 3.7e-4]), (2e-3, [1.0, 1.35e-4])];
+        let temp_dir = tempfile::tempdir().unwrap();
+
+        let normal_path = temp_dir.path().join("normal.raw");
+        let normal: Vec<(f64, Vec<f32>)> = points.iter().map(|(t, values)| (*t, values.to_vec())).collect();
+        write_ltspice_raw(&normal_path, &header("real forward"), &normal);
+
+        // Column-major: every time point, then every V(in), then every I(R1)
+        let fastaccess_path = temp_dir.path().join("fastaccess.raw");
+        let mut data: Vec<u8> = header("real forward fastaccess").encode_utf16().flat_map(|c| c.to_le_bytes()).collect();
+        for (t, _) in &points {
+            data.extend_from_slice(&t.to_le_bytes());
+        }
+        for var in 0..2 {
+            for (_, values) in &points {
+                data.extend_from_slice(&values[var].to_le_bytes());
+            }
+        }
+        std::fs::write(&fastaccess_path, data).unwrap();
+
+        let columns = |results: &SimulationResults| -> Vec<(String, Vec<f64>)> {
+            results.traces.iter().map(|trace| (trace.name.clone(), trace.data.clone())).collect()
+        };
+        let normal = parse_raw_file(&normal_path, &ProcessOptions::default()).unwrap();
+        let fastaccess = parse_raw_file(&fastaccess_path, &ProcessOptions::default()).unwrap();
+        assert_eq!(fastaccess.time, normal.time);
+        assert_eq!(columns(&fastaccess), columns(&normal));
+        assert_eq!(normal.traces[1].data[1], 3.7e-4f32 as f64);
+
+        // Double precision shares the same layout switch
+        let double_path = temp_dir.path().join("double.raw");
+        let mut data: Vec<u8> = header("real double forward fastaccess")
+            .encode_utf16()
+            .flat_map(|c| c.to_le_bytes())
+            .collect();
+        for (t, _) in &points {
+            data.extend_from_slice(&t.to_le_bytes());
+        }
+        for var in 0..2 {
+            for (_, values) in &points {
+                data.extend_from_slice(&(values[var] as f64).to_le_bytes());
+            }
+        }
+        std::fs::write(&double_path, data).unwrap();
+        let double = parse_raw_file(&double_path, &ProcessOptions::default()).unwrap();
+        assert_eq!(double.time, normal.time);
+        assert_eq!(columns(&double), columns(&normal));
+    }
+
+    #[test]
+    fn test_parse_ltspice_raw_file_truncated() {
+        let header = "Title: * rc.asc\n\
+Plotname: Transient Analysis\n\
+Flags: real forward\n\
+No. Variables: 2\n\
+No. Points: 5\n\
+Variables:\n\
+\t0\ttime\ttime\n\
+\t1\tV(out)\tvoltage\n\
+Binary:\n";
+        let temp_dir = tempfile::tempdir().unwrap();
+        let raw_path = temp_dir.path().join("rc.raw");
+        write_ltspice_raw(&raw_path, header, &[(0.0, vec![0.0]), (1e-3, vec![0.632]), (2e-3, vec![])]);
+
+        let log = "Circuit: * rc.asc\nError: No space left on device\n";
+        let results = explain_raw_file(parse_raw_file(&raw_path, &ProcessOptions::default()), log).unwrap();
+        assert_eq!(results.time, vec![0.0, 1e-3]);
+        assert_eq!(results.traces[0].data.len(), 2);
+        let truncation = results.truncated.unwrap();
+        assert_eq!((truncation.expected_points, truncation.actual_points), (5, 2));
+        assert_eq!(
+            truncation.warning(),
+            "Results are incomplete: the raw file holds 2 of 5 points; likely cause: Error: No space left on device"
+        );
+
+        // Less than one point: fail, with the cause when the log has one
+        write_ltspice_raw(&raw_path, header, &[(0.0, vec![])]);
+        let err = explain_raw_file(parse_raw_file(&raw_path, &ProcessOptions::default()), log).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "The raw file is cut short: 5 points declared but only 8 of 60 data bytes are present; \
+             likely cause: Error: No space left on device"
+        );
+        let err = explain_raw_file(parse_raw_file(&raw_path, &ProcessOptions::default()), "Circuit: * rc.asc\n").unwrap_err();
+        assert!(!err.to_string().contains("likely cause"));
+
+        // Zero points declared
+        write_ltspice_raw(&raw_path, &header.replace("No. Points: 5", "No. Points: 0"), &[]);
+        assert_eq!(
+            parse_raw_file(&raw_path, &ProcessOptions::default()).unwrap_err().to_string(),
+            "The raw file has no data points; the simulation produced no output"
+        );
+    }
+
+    #[test]
+    fn test_truncation_cause() {
+        assert_eq!(truncation_cause("Circuit: x\n  Fatal error: out of memory\n"), Some("Fatal error: out of memory".to_string()));
+        assert_eq!(truncation_cause("Circuit: x\nTotal elapsed time: 0.1 seconds.\n"), None);
+    }
+
+    #[test]
+    fn test_parse_ltspice_ascii_raw_file() {
+        // Written by `LTspice -ascii -b`: UTF-16LE text throughout
+        let text = "Title: * rc.asc\n\
+Plotname: Transient Analysis\n\
+Flags: real forward\n\
+No. Variables: 2\n\
+No. Points: 2\n\
+Variables:\n\
+\t0\ttime\ttime\n\
+\t1\tV(out)\tvoltage\n\
+Values:\n\
+0\t0.000000000000000e+000\n\
+\t1.000000000000000e+000\n\
+1\t1.000000000000000e-003\n\
+\t6.321205588285577e-001\n";
+        let temp_dir = tempfile::tempdir().unwrap();
+        let raw_path = temp_dir.path().join("rc.raw");
+        std::fs::write(&raw_path, text.encode_utf16().flat_map(|c| c.to_le_bytes()).collect::<Vec<u8>>()).unwrap();
+
+        let results = parse_raw_file(&raw_path, &ProcessOptions::default()).unwrap();
+        assert_eq!(results.analysis_type, "transient");
+        assert_eq!(results.time, vec![0.0, 1e-3]);
+        assert_eq!(results.traces[0].name, "V(out)");
+        assert_eq!(results.traces[0].data, vec![1.0, 0.6321205588285577]);
+    }
+
+    #[test]
+    fn test_ltspice_engine_flags() {
+        let both = EngineOptions { alternate_solver: true, ascii_output: true };
+        let (flags, warnings) = ltspice_engine_flags("/opt/ltspice/LTspice.exe", &both);
+        assert_eq!(flags, ["-alt", "-ascii"]);
+        assert!(warnings.is_empty());
+        assert_eq!(ltspice_engine_flags("/opt/ltspice/LTspice.exe", &EngineOptions::default()), (vec![], vec![]));
+
+        let (flags, warnings) = ltspice_engine_flags("/Applications/LTspice.app/Contents/MacOS/LTspice", &both);
+        assert_eq!(flags, ["-ascii"]);
+        assert_eq!(warnings, ["alternateSolver was ignored: this LTspice build has no alternate solver"]);
+
+        // Switches go ahead of -b and the netlist
+        let options = ProcessOptions { ltspice_flags: flags, ..ProcessOptions::default() };
+        let args = ltspice_batch_args(&options);
+        let flags: Vec<&str> = args.iter().map(String::as_str).collect();
+        let command = simulator_command("LTspice", &flags, Path::new("/tmp/circuit.net"), false);
+        assert_eq!(command_line(&command), "LTspice -ascii -b /tmp/circuit.net");
+    }
+
+    #[test]
+    fn test_scalar_analysis_detection() {
+        assert_eq!(scalar_analysis("V1 in 0 1\n.tf V(out) V1\n.end"), Some("tf"));
+        assert_eq!(scalar_analysis("V1 in 0 1\n.SENS V(out)\n.end"), Some("sens"));
+        // A plotting analysis alongside keeps the raw file path
+        assert_eq!(scalar_analysis(".tf V(out) V1\n.tran 1m\n.end"), None);
+        assert_eq!(scalar_analysis(".tran 1m\n.end"), None);
+    }
 
- 2	5.000000000000000e+00
-	5.000000000000000e+00
-"#;
+    #[test]
+    fn test_parse_ltspice_transfer_function() {
+        let log = "Circuit: * tf.asc\n\
+\n\
+Direct Newton iteration for .op point succeeded.\n\
+\n\
+--- Transfer Function ---\n\
+\n\
+Transfer_function:\t0.5\ttransfer\n\
+vin#Input_impedance:\t2000\timpedance\n\
+output_impedance_at_V(out):\t500\timpedance\n\
+\n\
+Total elapsed time: 0.004 seconds.\n";
+
+        let values = parse_ltspice_transfer_function(log);
+        assert_eq!(values.len(), 3);
+        assert_eq!(values["Transfer_function"], 0.5);
+        assert_eq!(values["vin#Input_impedance"], 2000.0);
+        assert_eq!(values["output_impedance_at_V(out)"], 500.0);
+
+        assert!(parse_ltspice_transfer_function("Total elapsed time: 0.004 seconds.").is_empty());
+    }
 
-        let temp_dir = tempfile::tempdir().unwrap();
-        let raw_path = temp_dir.path().join("test_dc.raw");
-        std::fs::write(&raw_path, raw_content).unwrap();
+    #[test]
+    fn test_parse_ltspice_operating_points() {
+        let log = "Circuit: * amp.asc\n\
+\n\
+Direct Newton iteration for .op point succeeded.\n\
+\n\
+Semiconductor Device Operating Points:\n\
+\n\
+                        --- Bipolar Transistors ---\n\
+Name:       q1          q2\n\
+Model:    2n3904      2n3906\n\
+Ib:       4.21e-06   -3.98e-06\n\
+Ic:       8.73e-04   -8.11e-04\n\
+Vbe:      6.71e-01   -6.88e-01\n\
+Vce:      4.12e+00   -3.05e+00\n\
+BetaDC:   2.07e+02    2.04e+02\n\
+\n\
+                        --- MOSFET Transistors ---\n\
+Name:       m1\n\
+Model:    bss123\n\
+Id:       1.52e-03\n\
+Vgs:      2.10e+00\n\
+Vds:      5.00e+00\n\
+Vth:      1.80e+00\n\
+\n\
+\n\
+Date: Thu Oct 15 10:21:08 2026\n\
+Total elapsed time: 0.021 seconds.\n";
+
+        let points = parse_ltspice_operating_points(log);
+        assert_eq!(points.keys().collect::<Vec<_>>(), ["M1", "Q1", "Q2"]);
+
+        let q2 = &points["Q2"];
+        assert_eq!(q2.kind, "bjt");
+        assert_eq!(q2.model.as_deref(), Some("2n3906"));
+        assert_eq!(q2.values["Ic"], -8.11e-04);
+        assert_eq!(q2.values["Vbe"], -6.88e-01);
+        assert_eq!(q2.values.len(), 5);
+
+        let m1 = &points["M1"];
+        assert_eq!(m1.kind, "mosfet");
+        assert_eq!(m1.model.as_deref(), Some("bss123"));
+        assert_eq!(m1.values["Id"], 1.52e-03);
+        assert_eq!(m1.values["Vgs"], 2.10);
+        assert_eq!(m1.values["Vds"], 5.0);
+        // Nothing after the block leaks into the last section
+        assert!(!m1.values.contains_key("Date"));
+        assert!(!m1.values.contains_key("Total elapsed time"));
+    }
 
-        let results = parse_ngspice_raw_file(&raw_path).unwrap();
+    #[test]
+    fn test_parse_ltspice_operating_points_absent() {
+        let log = "Circuit: * rc.asc\n\
+\n\
+Direct Newton iteration for .op point succeeded.\n\
+\n\
+Date: Thu Oct 15 10:21:08 2026\n\
+Total elapsed time: 0.004 seconds.\n";
+        assert!(parse_ltspice_operating_points(log).is_empty());
+        assert!(parse_ltspice_operating_points("").is_empty());
+    }
 
-        assert_eq!(results.analysis_type, "dc");
-        assert_eq!(results.x_axis_label, Some("v-sweep".to_string()));
-        assert_eq!(results.time.len(), 3);
-        assert_eq!(results.traces.len(), 1);
+    #[test]
+    fn test_parse_ngspice_scalars() {
+        let stdout = "Circuit: * sens test\n\
+Doing analysis at TEMP = 27.000000 and TNOM = 27.000000\n\
+\n\
+No. of Data Rows : 1\n\
+r1 = -2.500000e-04\n\
+r2 = 2.500000e-04\n\
+v1 = 5.000000e-01\n";
+
+        let values = parse_ngspice_scalars(stdout);
+        assert_eq!(values.len(), 3);
+        assert_eq!(values["r1"], -2.5e-4);
+        assert_eq!(values["v1"], 0.5);
+
+        let results = scalar_results("sens", values).unwrap();
+        assert_eq!(results.analysis_type, "sens");
+        assert!(results.traces.is_empty());
+        assert_eq!(results.scalar_results.unwrap().len(), 3);
+
+        let err = scalar_results("tf", BTreeMap::new()).unwrap_err();
+        assert_eq!(err.to_string(), "No .tf results found in the simulator output");
+    }
 
-        // Check sweep values
-        assert!((results.time[0] - 0.0).abs() < 1e-10);
-        assert!((results.time[1] - 2.5).abs() < 1e-10);
-        assert!((results.time[2] - 5.0).abs() < 1e-10);
+    #[test]
+    fn test_prepare_ngspice_netlist_scalar_analysis_prints_values() {
+        let netlist = "* Test\nV1 in 0 1\nR1 in out 1k\nR2 out 0 1k\n.tf v(out) V1\n.end";
+        let prepared = prepare_ngspice_netlist(netlist, &quality("balanced"), true, &PathBuf::from("/tmp/test.raw"), None, None);
+        assert!(prepared.contains("print all"));
+        assert!(!prepared.contains("write "));
+    }
 
-        // Check v(out) trace
-        let v_out = &results.traces[0];
-        assert_eq!(v_out.name, "v(out)");
-        assert!((v_out.data[0] - 0.0).abs() < 1e-10);
-        assert!((v_out.data[1] - 2.5).abs() < 1e-10);
-        assert!((v_out.data[2] - 5.0).abs() < 1e-10);
+    #[test]
+    fn test_prepare_ngspice_netlist_noise_writes_spectrum() {
+        let netlist = "* Test\nV1 in 0 AC 1\nR1 in out 1k\n.noise v(out) V1 dec 10 1 1Meg\n.end";
+        let prepared = prepare_ngspice_netlist(netlist, &quality("balanced"), true, &PathBuf::from("/tmp/test.raw"), None, None);
+        let lines: Vec<&str> = prepared.lines().collect();
+        let setplot_idx = lines.iter().position(|l| *l == "setplot noise1").unwrap();
+        let write_idx = lines.iter().position(|l| l.starts_with("write ")).unwrap();
+        assert!(setplot_idx < write_idx);
+
+        let transient = prepare_ngspice_netlist("* Test\n.tran 1m\n.end", &quality("balanced"), true, &PathBuf::from("/tmp/test.raw"), None, None);
+        assert!(!transient.contains("setplot"));
+    }
+
+    #[test]
+    fn test_prepare_ngspice_netlist_writes_bare_raw_name() {
+        let raw_path = PathBuf::from("/Users/Jane Doe/tmp/kelicad sim/corner0.raw");
+        let prepared = prepare_ngspice_netlist("* Test\n.tran 1m\n.end", &quality("balanced"), true, &raw_path, None, None);
+        assert!(prepared.lines().any(|l| l == "write corner0.raw all"), "{}", prepared);
+    }
+
+    #[test]
+    fn test_relative_includes_pinned_to_temp_dir() {
+        let netlist = "* Test\n.include ./models/opamp.lib\n.lib ..\\shared\\parts.lib tt\n\
+                       .include \"my models/diode.mod\"\n.include /opt/models/abs.lib\n\
+                       .lib C:\\models\\win.lib\n.include plain.lib\n.end";
+        let pinned = pin_relative_includes(netlist);
+        let lines: Vec<&str> = pinned.lines().collect();
+        assert_eq!(lines[1], ".include models/opamp.lib");
+        assert_eq!(lines[2], ".lib shared/parts.lib tt");
+        assert_eq!(lines[3], ".include \"my models/diode.mod\"");
+        assert_eq!(lines[4], ".include /opt/models/abs.lib");
+        assert_eq!(lines[5], ".lib C:\\models\\win.lib");
+        assert_eq!(lines[6], ".include plain.lib");
+
+        assert_eq!(temp_dir_relative("../../etc/passwd").as_deref(), Some("etc/passwd"));
+        assert_eq!(temp_dir_relative("\\\\server\\share\\x.lib"), None);
     }
 
     #[test]
@@ -2027,4 +5680,923 @@ Error: Unknown device
         let msg = error.unwrap();
         assert!(msg.contains("Error: Unknown device"));
     }
+
+    fn corner(params: &[(&str, serde_json::Value)], temperature: Option<f64>) -> BatchCorner {
+        BatchCorner {
+            name: "test".to_string(),
+            params: params.iter().map(|(k, v)| (k.to_string(), v.clone())).collect(),
+            temperature,
+        }
+    }
+
+    #[test]
+    fn test_apply_corner_overrides_rewrites_existing_param() {
+        let netlist = "* Test\n.param vin=5 rload=1k\nV1 in 0 {vin}\n.tran 1m\n.end";
+        let overridden = apply_corner_overrides(netlist, &corner(&[("vin", serde_json::json!(4.5))], None));
+        assert!(overridden.contains(".param vin=4.5 rload=1k"));
+        // Nothing new inserted when the param already existed
+        assert_eq!(overridden.matches(".param").count(), 1);
+    }
+
+    #[test]
+    fn test_apply_corner_overrides_inserts_new_param_before_end() {
+        let netlist = "* Test\nV1 in 0 {vin}\n.tran 1m\n.end";
+        let overridden = apply_corner_overrides(netlist, &corner(&[("vin", serde_json::json!("5.5"))], None));
+        let lines: Vec<&str> = overridden.lines().collect();
+        let param_pos = lines.iter().position(|l| *l == ".param vin=5.5").unwrap();
+        let end_pos = lines.iter().position(|l| *l == ".end").unwrap();
+        assert!(param_pos < end_pos);
+    }
+
+    #[test]
+    fn test_apply_corner_overrides_replaces_temperature() {
+        let netlist = "* Test\n.temp 27\nV1 in 0 1\n.tran 1m\n.end";
+        let overridden = apply_corner_overrides(netlist, &corner(&[], Some(-40.0)));
+        assert!(overridden.contains(".temp -40"));
+        assert!(!overridden.contains(".temp 27"));
+        assert_eq!(overridden.matches(".temp").count(), 1);
+    }
+
+    #[test]
+    fn test_apply_corner_overrides_does_not_touch_similar_names() {
+        let netlist = "* Test\n.param vin=5 vin2=3\n.end";
+        let overridden = apply_corner_overrides(netlist, &corner(&[("vin", serde_json::json!(6))], None));
+        assert!(overridden.contains(".param vin=6 vin2=3"));
+    }
+
+    #[test]
+    fn test_simulation_workspace_keeps_ngspice_netlist() {
+        let netlist = "* Test\nV1 in 0 1\n.end";
+        let temp_root = TempDir::new().unwrap();
+        let workspace =
+            SimulationWorkspace::prepare("ngspice", netlist, &[], &[], &LibrarySearchPaths::default(), temp_root.path())
+                .unwrap();
+        assert!(workspace.path().exists());
+        assert!(workspace.path().starts_with(temp_root.path()));
+        assert_eq!(workspace.netlist(), netlist);
+
+        // The error names the directory it couldn't use
+        let missing = temp_root.path().join("missing");
+        let Err(err) = SimulationWorkspace::prepare("ltspice", netlist, &[], &[], &LibrarySearchPaths::default(), &missing)
+        else {
+            panic!("expected the temp dir to fail");
+        };
+        assert!(err.to_string().starts_with(&format!("Could not create a temp directory in {}", missing.display())));
+    }
+
+    fn attachment(name: &str, content: &[u8]) -> Attachment {
+        Attachment { name: name.to_string(), content_base64: BASE64_STANDARD.encode(content) }
+    }
+
+    #[test]
+    fn test_decode_attachments_sanitizes_names() {
+        let files = decode_attachments(&[
+            attachment("../../lib/opamp.sub", b".subckt opamp"),
+            attachment("C:\\sym\\my part?.asy", b"Version 4"),
+        ])
+        .unwrap();
+        assert_eq!(files[0], WorkspaceFile { name: "opamp.sub".to_string(), content: b".subckt opamp".to_vec() });
+        assert_eq!(files[1].name, "my part_.asy");
+    }
+
+    #[test]
+    fn test_decode_attachments_rejects_bad_input() {
+        assert!(decode_attachments(&[attachment("..", b"x")]).is_err());
+        assert!(decode_attachments(&[attachment("circuit.net", b"x")]).is_err());
+        assert!(decode_attachments(&[attachment("mc3.raw", b"x")]).is_err());
+        assert!(decode_attachments(&[attachment("a.lib", b"x"), attachment("A.LIB", b"y")]).is_err());
+        let invalid = Attachment { name: "a.lib".to_string(), content_base64: "not base64!".to_string() };
+        assert!(decode_attachments(&[invalid]).is_err());
+        let huge = attachment("big.lib", &vec![0u8; MAX_ATTACHMENT_BYTES + 1]);
+        assert!(decode_attachments(&[huge]).unwrap_err().contains("larger than"));
+        assert!(decode_attachments(&[attachment("corner.lib", b"x"), attachment("mcu.sub", b"x")]).is_ok());
+    }
+
+    #[test]
+    fn test_simulation_workspace_writes_attachments() {
+        let files = decode_attachments(&[attachment("opamp.sub", b".subckt opamp a b\n.ends")]).unwrap();
+        let netlist = "* Test\n.include /home/user/models/opamp.sub\n.lib other.lib\n.end";
+        let workspace = SimulationWorkspace::prepare("ngspice", netlist, &files, &[], &LibrarySearchPaths::default(), &std::env::temp_dir())
+                .unwrap();
+        assert_eq!(std::fs::read(workspace.path().join("opamp.sub")).unwrap(), b".subckt opamp a b\n.ends");
+        assert_eq!(workspace.netlist(), "* Test\n.include opamp.sub\n.lib other.lib\n.end");
+        assert_eq!(workspace.included_libraries()[0].source, "attachment");
+    }
+
+    #[test]
+    fn test_absolute_includes_are_listed_as_referenced() {
+        let models = TempDir::new().unwrap();
+        let library = models.path().join("opamps.lib");
+        std::fs::write(&library, "* opamps").unwrap();
+        let netlist = format!("* Test\n.include {}\n.end", library.display());
+        let workspace =
+            SimulationWorkspace::prepare("ltspice", &netlist, &[], &[], &LibrarySearchPaths::default(), &std::env::temp_dir())
+                .unwrap();
+        assert_eq!(
+            workspace.included_libraries(),
+            [IncludedLibrary {
+                name: "opamps.lib".to_string(),
+                source: library.to_string_lossy().into_owned(),
+                strategy: LibraryStrategy::Reference,
+                expanded_from: None,
+            }]
+        );
+
+        // The file the netlist points at is the one loaded
+        let used = libraries_used::report(workspace.included_libraries(), workspace.path(), &[library.to_string_lossy().into_owned()]);
+        assert!(!used[0].mismatch);
+    }
+
+    #[test]
+    fn test_include_path_variables_are_expanded() {
+        let models = TempDir::new().unwrap();
+        std::fs::write(models.path().join("nmos.lib"), "* nmos").unwrap();
+        std::fs::write(models.path().join("pmos.lib"), "* pmos").unwrap();
+        let search_paths = LibrarySearchPaths {
+            variables: BTreeMap::from([("MODELS".to_string(), models.path().to_string_lossy().into_owned())]),
+            ..Default::default()
+        };
+        let netlist = "* Test\n.include $MODELS/nmos.lib\n.lib ${MODELS}/pmos.lib\n.include %MODELS%/nmos.lib\n.end";
+        let workspace = SimulationWorkspace::prepare("ltspice", netlist, &[], &[], &search_paths, &std::env::temp_dir()).unwrap();
+        let nmos = models.path().join("nmos.lib").to_string_lossy().into_owned();
+        let pmos = models.path().join("pmos.lib").to_string_lossy().into_owned();
+        assert_eq!(
+            workspace.netlist(),
+            format!("* Test\n.include \"{0}\"\n.include \"{1}\"\n.include \"{0}\"\n.end", nmos, pmos)
+        );
+        let expansions: Vec<_> =
+            workspace.included_libraries().iter().map(|lib| (lib.source.as_str(), lib.expanded_from.as_deref())).collect();
+        assert_eq!(
+            expansions,
+            [
+                (nmos.as_str(), Some("$MODELS/nmos.lib")),
+                (pmos.as_str(), Some("${MODELS}/pmos.lib")),
+                (nmos.as_str(), Some("%MODELS%/nmos.lib")),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_unset_include_path_variable_is_a_missing_library() {
+        let netlist = "* Test\n.include %KELICAD_UNSET_PDK%\\nmos.lib\n.end";
+        let error = SimulationWorkspace::prepare("ltspice", netlist, &[], &[], &LibrarySearchPaths::default(), &std::env::temp_dir())
+            .err()
+            .unwrap();
+        let missing = error.downcast_ref::<LibraryMissing>().unwrap();
+        assert_eq!(missing.variable, "%KELICAD_UNSET_PDK%");
+        assert!(error.to_string().contains("%KELICAD_UNSET_PDK% is not set"), "{}", error);
+    }
+
+    #[test]
+    fn test_simulation_workspace_prefers_attachments_for_ltspice() {
+        let files = decode_attachments(&[attachment("LTC3.lib", b"* attached")]).unwrap();
+        let workspace = SimulationWorkspace::prepare(
+            "ltspice",
+            "* Test\n.lib LTC3.lib\n.end",
+            &files,
+            &["LTC3.lib".to_string()],
+            &LibrarySearchPaths::default(),
+            &std::env::temp_dir(),
+        )
+        .unwrap();
+        assert_eq!(std::fs::read(workspace.path().join("LTC3.lib")).unwrap(), b"* attached");
+        assert_eq!(workspace.included_libraries().len(), 1);
+        assert_eq!(workspace.included_libraries()[0].name, "LTC3.lib");
+        assert_eq!(workspace.included_libraries()[0].source, "attachment");
+    }
+
+    #[test]
+    fn test_library_search_paths_copied_or_passed_to_ltspice() {
+        let models = TempDir::new().unwrap();
+        std::fs::create_dir(models.path().join("vendor")).unwrap();
+        std::fs::write(models.path().join("vendor").join("opamps.lib"), "* opamps").unwrap();
+        std::fs::write(models.path().join("diodes.lib"), "* diodes").unwrap();
+        let netlist = "* Test\n.lib vendor/opamps.lib\n.include models/diodes.lib\n.end";
+        let mut search_paths = LibrarySearchPaths { dirs: vec![models.path().to_path_buf()], ..Default::default() };
+
+        // Older LTspice: the libraries are copied into the temp dir
+        let workspace = SimulationWorkspace::prepare("ltspice", netlist, &[], &[], &search_paths, &std::env::temp_dir()).unwrap();
+        assert_eq!(std::fs::read_to_string(workspace.path().join("opamps.lib")).unwrap(), "* opamps");
+        assert_eq!(std::fs::read_to_string(workspace.path().join("diodes.lib")).unwrap(), "* diodes");
+        assert_eq!(workspace.netlist(), "* Test\n.include opamps.lib\n.include diodes.lib\n.end");
+        assert_eq!(workspace.included_libraries().len(), 2);
+
+        // Newer LTspice finds them through -I, so nothing is copied
+        search_paths.on_command_line = true;
+        let workspace = SimulationWorkspace::prepare("ltspice", netlist, &[], &[], &search_paths, &std::env::temp_dir()).unwrap();
+        assert!(!workspace.path().join("opamps.lib").exists());
+        assert_eq!(workspace.netlist(), "* Test\n.lib vendor/opamps.lib\n.include diodes.lib\n.end");
+        assert_eq!(
+            workspace.included_libraries()[0].source,
+            models.path().join("vendor/opamps.lib").to_string_lossy()
+        );
+    }
+
+    #[test]
+    fn test_library_strategy_copy_or_reference() {
+        let root = TempDir::new().unwrap();
+        let spaced = root.path().join("vendor models");
+        let unicode = root.path().join("modèles");
+        for dir in [&spaced, &unicode] {
+            std::fs::create_dir(dir).unwrap();
+        }
+        std::fs::write(spaced.join("opamps.lib"), "* opamps").unwrap();
+        std::fs::write(unicode.join("diodes.lib"), "* diodes").unwrap();
+        let netlist = "* Test\n.lib opamps.lib\n.include diodes.lib\n.end";
+        let mut search_paths = LibrarySearchPaths { dirs: vec![spaced.clone(), unicode.clone()], ..Default::default() };
+
+        // Copy: both libraries land in the temp dir, whatever their directories are called
+        let workspace = SimulationWorkspace::prepare("ltspice", netlist, &[], &[], &search_paths, &std::env::temp_dir()).unwrap();
+        assert_eq!(workspace.netlist(), "* Test\n.include opamps.lib\n.include diodes.lib\n.end");
+        assert_eq!(std::fs::read_to_string(workspace.path().join("diodes.lib")).unwrap(), "* diodes");
+        assert!(workspace.included_libraries().iter().all(|lib| lib.strategy == LibraryStrategy::Copy));
+
+        // Reference: the path with spaces is included in place, quoted; the one LTspice couldn't
+        // read back is still copied
+        search_paths.strategy = LibraryStrategy::Reference;
+        let workspace = SimulationWorkspace::prepare("ltspice", netlist, &[], &[], &search_paths, &std::env::temp_dir()).unwrap();
+        let referenced = spaced.join("opamps.lib").to_string_lossy().into_owned();
+        assert_eq!(workspace.netlist(), format!("* Test\n.include \"{}\"\n.include diodes.lib\n.end", referenced));
+        assert!(!workspace.path().join("opamps.lib").exists());
+        assert!(workspace.path().join("diodes.lib").exists());
+        let strategies: Vec<_> = workspace.included_libraries().iter().map(|lib| (lib.name.as_str(), lib.strategy)).collect();
+        assert_eq!(strategies, [("opamps.lib", LibraryStrategy::Reference), ("diodes.lib", LibraryStrategy::Copy)]);
+    }
+
+    #[test]
+    fn test_referenceable_in_netlist() {
+        assert!(referenceable_in_netlist("/opt/vendor models/opamps.lib"));
+        assert!(!referenceable_in_netlist("/opt/modèles/diodes.lib"));
+        assert!(!referenceable_in_netlist("/opt/\"quoted\"/diodes.lib"));
+        assert!(!referenceable_in_netlist("/opt/line\nbreak/diodes.lib"));
+    }
+
+    #[test]
+    fn test_ltspice_batch_args_by_version() {
+        assert!(!ltspice_takes_include_dirs(None));
+        assert!(!ltspice_takes_include_dirs(Some("17.1.8")));
+        assert!(!ltspice_takes_include_dirs(Some("24.0.12")));
+        assert!(ltspice_takes_include_dirs(Some("24.1")));
+        assert!(ltspice_takes_include_dirs(Some("25")));
+        assert!(!ltspice_takes_include_dirs(Some("unknown")));
+
+        let dirs = vec![PathBuf::from("/models/vendor"), PathBuf::from("/models/mine")];
+        let mut options = ProcessOptions {
+            ltspice_flags: vec!["-alt"],
+            library_search_paths: LibrarySearchPaths { dirs, on_command_line: true, ..Default::default() },
+            ..ProcessOptions::default()
+        };
+        assert_eq!(ltspice_batch_args(&options), ["-alt", "-I/models/vendor", "-I/models/mine", "-b"]);
+
+        options.library_search_paths.on_command_line = false;
+        assert_eq!(ltspice_batch_args(&options), ["-alt", "-b"]);
+    }
+
+    #[test]
+    fn test_check_library_search_paths() {
+        let root = TempDir::new().unwrap();
+        let inside = root.path().join("vendor");
+        std::fs::create_dir(&inside).unwrap();
+        let outside = TempDir::new().unwrap();
+        let roots = vec![root.path().to_string_lossy().into_owned()];
+        let path = |path: &Path| path.to_string_lossy().into_owned();
+
+        assert_eq!(check_library_search_paths(&[], &[]).unwrap(), Vec::<PathBuf>::new());
+        assert_eq!(
+            check_library_search_paths(&[path(&inside)], &roots).unwrap(),
+            [std::fs::canonicalize(&inside).unwrap()]
+        );
+        assert!(check_library_search_paths(&[path(&inside)], &[]).unwrap_err().contains("allowed_library_roots"));
+        assert_eq!(
+            check_library_search_paths(&[path(outside.path())], &roots).unwrap_err(),
+            format!("Library search path {} is outside the allowed library roots", path(outside.path()))
+        );
+        // No way out through `..`
+        let escape = inside.join("..").join("..").join(outside.path().file_name().unwrap());
+        assert!(check_library_search_paths(&[path(&escape)], &roots).is_err());
+        assert!(check_library_search_paths(&[path(&inside.join("missing"))], &roots).unwrap_err().ends_with("is not a directory"));
+    }
+
+    #[test]
+    fn test_extract_measurement_ltspice_log() {
+        let log = "Circuit: * test\n\nvout_max: MAX(v(out))=4.98765 FROM 0 TO 0.001\ntdelay=1.2e-06\n";
+        assert_eq!(extract_measurement(log, "vout_max"), Some(4.98765));
+        assert_eq!(extract_measurement(log, "tdelay"), Some(1.2e-06));
+        assert_eq!(extract_measurement(log, "missing"), None);
+    }
+
+    #[test]
+    fn test_extract_measurement_ngspice_stdout() {
+        let stdout = "vout_max            =  4.990000e+00 at=  1.000000e-03\nvout_max_late       =  3.0e+00\n";
+        assert_eq!(extract_measurement(stdout, "vout_max"), Some(4.99));
+        assert_eq!(extract_measurement(stdout, "VOUT_MAX_LATE"), Some(3.0));
+    }
+
+    #[test]
+    fn test_extract_measurement_ac_and_failed() {
+        let log = "gain: v(out)/v(in)=(-3.01dB,-45\u{b0}) at 1000\nbw: FAIL'ed\n";
+        assert_eq!(extract_measurement(log, "gain"), Some(-3.01));
+        assert_eq!(extract_measurement(log, "bw"), None);
+    }
+
+    #[test]
+    fn test_extract_measurement_non_ascii_names() {
+        // U+212A KELVIN SIGN lowercases to a one-byte `k`, and `İ` to two characters
+        let log = "\u{212a}gain=2.5\nİx: MAX(v(out))=1.5\nvout=3\n";
+        assert_eq!(extract_measurement(log, "kgain"), None);
+        assert_eq!(extract_measurement(log, "\u{212a}gain"), Some(2.5));
+        assert_eq!(extract_measurement(log, "i"), None);
+        assert_eq!(extract_measurement(log, "İx"), Some(1.5));
+        assert_eq!(extract_measurement(log, "vo\u{fc}t"), None);
+    }
+
+    #[test]
+    fn test_summarize_measurement_statistics() {
+        let summary = summarize_measurement("vout", vec![Some(1.0), None, Some(3.0), Some(2.0)]);
+        assert_eq!(summary.failed_runs, 1);
+        assert_eq!(summary.values.len(), 4);
+        assert!((summary.mean.unwrap() - 2.0).abs() < 1e-12);
+        assert!((summary.stddev.unwrap() - 1.0).abs() < 1e-12);
+        assert_eq!(summary.min, Some(1.0));
+        assert_eq!(summary.max, Some(3.0));
+    }
+
+    #[test]
+    fn test_summarize_measurement_all_failed() {
+        let summary = summarize_measurement("vout", vec![None, None]);
+        assert_eq!(summary.failed_runs, 2);
+        assert!(summary.mean.is_none());
+        assert!(summary.stddev.is_none());
+    }
+
+    #[test]
+    fn test_apply_monte_carlo_run_inserts_before_end() {
+        let netlist = "* Test\nR1 in out {mc(1k,0.05)}\n.end";
+        let prepared = apply_monte_carlo_run(netlist, 3, 44);
+        let lines: Vec<&str> = prepared.lines().collect();
+        assert_eq!(lines[2], ".param mc_run=3");
+        assert_eq!(lines[3], ".options seed=44");
+        assert_eq!(lines[4], ".end");
+    }
+
+    #[test]
+    fn test_capabilities_for_each_engine_combination() {
+        let simulators = |capabilities: &[AnalysisCapability], name: &str| {
+            capabilities.iter().find(|c| c.name == name).unwrap().simulators.clone()
+        };
+
+        let both = capabilities_for(true, true);
+        assert_eq!(both.len(), ANALYSIS_SUPPORT.len());
+        assert_eq!(simulators(&both, "transient"), ["ltspice", "ngspice"]);
+        assert_eq!(simulators(&both, "sens"), ["ngspice"]);
+        let tf = both.iter().find(|c| c.name == "tf").unwrap();
+        assert_eq!(tf.notes.as_deref(), Some("Returns named values instead of traces"));
+
+        let ltspice = capabilities_for(true, false);
+        assert_eq!(simulators(&ltspice, "noise"), ["ltspice"]);
+        let sens = ltspice.iter().find(|c| c.name == "sens").unwrap();
+        assert!(sens.simulators.is_empty());
+        assert_eq!(sens.notes.as_deref(), Some("Requires ngspice, which was not found"));
+
+        let ngspice = capabilities_for(false, true);
+        assert_eq!(simulators(&ngspice, "pz"), ["ngspice"]);
+        assert_eq!(simulators(&ngspice, "ac"), ["ngspice"]);
+
+        let none = capabilities_for(false, false);
+        assert!(none.iter().all(|c| c.simulators.is_empty()));
+        assert_eq!(
+            none.iter().find(|c| c.name == "transient").unwrap().notes.as_deref(),
+            Some("Requires LTspice or ngspice; neither was found")
+        );
+    }
+
+    #[test]
+    fn test_check_analyses() {
+        let netlist = "* Test\nV1 in 0 1\n.tran 1m\n.sens V(in)\n.end";
+        assert!(check_analyses(netlist, "ngspice").is_ok());
+        assert_eq!(
+            check_analyses(netlist, "ltspice").unwrap_err(),
+            ".sens analysis is not supported by LTspice; run it with ngspice"
+        );
+        assert!(check_analyses("* Test\n.PZ in 0 out 0 vol pz\n.end", "ltspice").is_err());
+        assert!(check_analyses("* Test\n.op\n.end", "ltspice").is_ok());
+    }
+
+    #[test]
+    fn test_command_line_quotes_spaces() {
+        let command = simulator_command("/opt/LT spice/ltspice", &["-b"], Path::new("/tmp/circuit.net"), false);
+        assert_eq!(command_line(&command), "\"/opt/LT spice/ltspice\" -b /tmp/circuit.net");
+    }
+
+    #[test]
+    fn test_convergence_fallbacks() {
+        let netlist = "* Test\nV1 in 0 1\n.tran 0 1m\n.end";
+        let stepping = ConvergenceFallback::SteppingOptions.apply(netlist).unwrap();
+        assert_eq!(stepping.lines().nth(3), Some(STEPPING_OPTIONS));
+        assert!(stepping.ends_with(".end"));
+
+        let uic = ConvergenceFallback::Uic.apply(netlist).unwrap();
+        assert!(uic.contains(".tran 0 1m uic\n"));
+        // Nothing to change without a .tran, or when it already has uic
+        assert!(ConvergenceFallback::Uic.apply("* Test\n.op\n.end").is_none());
+        assert!(ConvergenceFallback::Uic.apply(&uic).is_none());
+    }
+
+    #[test]
+    fn test_convergence_failure_is_classified() {
+        let output = "Warning: gmin stepping failed\ndoAnalyses: TRAN:  Timestep too small; time = 1e-9\n";
+        assert_eq!(convergence_failure_line(output), Some("Warning: gmin stepping failed"));
+        assert!(run_failure("failed".to_string(), output).is::<ConvergenceFailure>());
+
+        let output = "Error: unknown model 'npn1'";
+        assert!(convergence_failure_line(output).is_none());
+        assert!(!run_failure("failed".to_string(), output).is::<ConvergenceFailure>());
+    }
+
+    #[test]
+    fn test_operating_point_only_is_incomplete() {
+        let header = "Title: * rc.asc\n\
+Date: Sat Feb  7 12:00:00 2026\n\
+Plotname: Operating Point\n\
+Flags: real\n\
+No. Variables: 3\n\
+No. Points: 1\n\
+Offset:   0.0000000000000000e+000\n\
+Command: Linear Technology Corporation LTspice XVII\n\
+Variables:\n\
+\t0\tV(in)\tvoltage\n\
+\t1\tV(out)\tvoltage\n\
+\t2\tI(R1)\tdevice_current\n\
+Binary:\n";
+        let temp_dir = tempfile::tempdir().unwrap();
+        let raw_path = temp_dir.path().join("rc.raw");
+        write_ltspice_raw(&raw_path, header, &[(5.0, vec![2.5, 2.5e-3])]);
+        assert_eq!(raw_plot_name(&raw_path).as_deref(), Some("Operating Point"));
+        let results = parse_raw_file(&raw_path, &ProcessOptions::default()).unwrap();
+        let log = "Circuit: * rc.asc\n\nTime step too small; time = 1.2e-6, timestep = 1.25e-19: trouble with q1\n";
+
+        let err = check_requested_analysis(&results, "* rc\n.tran 1m\n.end", &raw_path, log).unwrap_err();
+        assert!(err.message.starts_with("The .tran analysis did not complete"), "{}", err.message);
+        assert!(err.message.contains("trouble with q1"), "{}", err.message);
+        assert_eq!(err.operating_point.get("v(in)"), Some(&5.0));
+        assert_eq!(err.operating_point.get("V(out)"), Some(&2.5));
+        assert_eq!(err.operating_point.get("I(R1)"), Some(&(2.5e-3f32 as f64)));
+
+        // An operating point is all a .op netlist asks for
+        assert!(check_requested_analysis(&results, "* rc\n.op\n.end", &raw_path, log).is_ok());
+
+        let ascii_path = temp_dir.path().join("tran.raw");
+        std::fs::write(&ascii_path, "Title: fake\nPlotname: Transient Analysis\nFlags: real\n").unwrap();
+        assert!(check_requested_analysis(&results, "* rc\n.tran 1m\n.end", &ascii_path, log).is_ok());
+    }
+
+    #[test]
+    fn test_netlist_parse_error_is_classified() {
+        let ltspice_log = "Circuit: * rc\n\nUnknown function \"if2\" in expression\nB1 out 0 V=if2(v(in)>1,1,0)\n";
+        assert!(run_failure("LTspice failed".to_string(), ltspice_log).is::<NetlistParseError>());
+        let ngspice_output = "Error on line 3 :\n  b1 out 0 v=table(v(in), 0, 0, 1, 1)\n";
+        assert!(run_failure("failed".to_string(), ngspice_output).is::<NetlistParseError>());
+
+        // Convergence wins, and other failures stay unclassified
+        let output = "Error on line 3\ntimestep too small";
+        assert!(run_failure("failed".to_string(), output).is::<ConvergenceFailure>());
+        let output = "Error: unknown model 'npn1'";
+        assert!(!run_failure("failed".to_string(), output).is::<NetlistParseError>());
+    }
+
+    /// Write an executable shell script standing in for a simulator
+    #[cfg(unix)]
+    fn fake_simulator(dir: &Path, body: &str) -> (String, PathBuf) {
+        use std::os::unix::fs::PermissionsExt;
+        let script = dir.join("fake-sim.sh");
+        std::fs::write(&script, format!("#!/bin/sh\n{}\n", body)).unwrap();
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let netlist = dir.join("circuit.net");
+        std::fs::write(&netlist, "* test\n.end\n").unwrap();
+        (script.to_string_lossy().to_string(), netlist)
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_netlist_from_asc() {
+        let dir = TempDir::new().unwrap();
+        // Stands in for `LTspice -netlist circuit.asc`, writing circuit.net beside it
+        let (exe, _) = fake_simulator(
+            dir.path(),
+            r#"[ "$1" = "-netlist" ] || exit 1
+grep -q '^SYMBOL res' "$2" || { echo "bad schematic" >&2; exit 1; }
+printf '* circuit.asc\nR1 in 0 1k\n.op\n.end\n' > "${2%.asc}.net""#,
+        );
+        let options = ProcessOptions::default();
+
+        let netlist = netlist_from_asc(&exe, "Version 4\nSYMBOL res 96 64 R0\n", &[], &options).await.unwrap();
+        assert!(netlist.contains("R1 in 0 1k"));
+
+        let err = netlist_from_asc(&exe, "Version 4\n", &[], &options).await.unwrap_err();
+        assert!(err.to_string().contains("bad schematic"), "{}", err);
+    }
+
+    #[test]
+    fn test_missing_symbols() {
+        let dir = TempDir::new().unwrap();
+        std::fs::create_dir_all(dir.path().join("Opamps")).unwrap();
+        std::fs::write(dir.path().join("res.asy"), "").unwrap();
+        std::fs::write(dir.path().join("Opamps").join("opamp2.asy"), "").unwrap();
+
+        let schematic = "Version 4\nSHEET 1 880 680\nSYMBOL res 96 64 R0\nSYMATTR InstName R1\n\
+                         SYMBOL Opamps\\opamp2 200 64 R0\nSYMBOL my_part 300 64 R0\nSYMBOL my_part 400 64 R0\n";
+        assert_eq!(missing_symbols(schematic, dir.path()), ["my_part"]);
+
+        let attached = [WorkspaceFile { name: "My_Part.asy".to_string(), content: Vec::new() }];
+        assert!(symbol_attached("my_part", &attached));
+        assert!(symbol_attached("Custom\\my_part", &attached));
+        assert!(!symbol_attached("res", &attached));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_watchdog_kills_stalled_process() {
+        let dir = TempDir::new().unwrap();
+        let (exe, netlist) = fake_simulator(dir.path(), "sleep 30");
+        let options = ProcessOptions {
+            stall_timeout: Some(Duration::from_secs(2)),
+            ..ProcessOptions::default()
+        };
+
+        let start = Instant::now();
+        let err = run_batch_process(&exe, &netlist, "LTspice", &options, None).await.unwrap_err();
+        assert!(err.downcast_ref::<SimulationHung>().is_some());
+        assert!(err.to_string().contains("no CPU or file activity"));
+        assert!(start.elapsed() < Duration::from_secs(15));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_watchdog_detects_ltspice_first_run_dialog() {
+        let dir = TempDir::new().unwrap();
+        let (exe, netlist) = fake_simulator(dir.path(), "sleep 30");
+        let options = ProcessOptions {
+            first_run_grace: Some(Duration::from_secs(2)),
+            stall_timeout: Some(Duration::from_secs(60)),
+            ..ProcessOptions::default()
+        };
+
+        let start = Instant::now();
+        let err = run_batch_process(&exe, &netlist, "LTspice", &options, None).await.unwrap_err();
+        let first_run = err.downcast_ref::<LtspiceNeedsFirstRun>().expect("first-run error");
+        assert!(first_run.idle_secs >= 2);
+        assert!(err.to_string().contains("Open LTspice once"));
+        assert!(start.elapsed() < Duration::from_secs(15));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_watchdog_first_run_check_skips_runs_that_write_a_log() {
+        let dir = TempDir::new().unwrap();
+        // A run that started simulating, then stalled, is hung rather than blocked by a dialog
+        let (exe, netlist) = fake_simulator(dir.path(), "echo started > circuit.log\nsleep 30");
+        let options = ProcessOptions {
+            first_run_grace: Some(Duration::from_secs(1)),
+            stall_timeout: Some(Duration::from_secs(3)),
+            ..ProcessOptions::default()
+        };
+        let err = run_batch_process(&exe, &netlist, "LTspice", &options, None).await.unwrap_err();
+        assert!(err.downcast_ref::<SimulationHung>().is_some(), "{}", err);
+
+        // ngspice has no first-run dialog
+        let (exe, netlist) = fake_simulator(dir.path(), "sleep 30");
+        let err = run_batch_process(&exe, &netlist, "ngspice", &options, None).await.unwrap_err();
+        assert!(err.downcast_ref::<SimulationHung>().is_some(), "{}", err);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_watchdog_enforces_hard_timeout() {
+        let dir = TempDir::new().unwrap();
+        // Busy process never stalls, so only the hard ceiling can stop it
+        let (exe, netlist) = fake_simulator(dir.path(), "while :; do :; done");
+        let options = ProcessOptions {
+            hard_timeout: Some(Duration::from_secs(1)),
+            stall_timeout: Some(Duration::from_secs(60)),
+            ..ProcessOptions::default()
+        };
+
+        let err = run_batch_process(&exe, &netlist, "LTspice", &options, None).await.unwrap_err();
+        assert!(err.downcast_ref::<SimulationHung>().is_some());
+        assert!(err.to_string().contains("maximum simulation time"));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_watchdog_allows_normal_exit() {
+        let dir = TempDir::new().unwrap();
+        let (exe, netlist) = fake_simulator(dir.path(), "echo done");
+        let holder = Arc::new(AtomicU32::new(0));
+        let options = ProcessOptions {
+            process_id_holder: Some(holder.clone()),
+            hard_timeout: Some(Duration::from_secs(30)),
+            stall_timeout: Some(Duration::from_secs(30)),
+            ..ProcessOptions::default()
+        };
+
+        let output = run_batch_process(&exe, &netlist, "LTspice", &options, None).await.unwrap();
+        assert_eq!(String::from_utf8_lossy(&output.stdout).trim(), "done");
+        assert_ne!(holder.load(Ordering::SeqCst), 0);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_deleted_simulator_is_reported_missing() {
+        let dir = TempDir::new().unwrap();
+        let (exe, netlist) = fake_simulator(dir.path(), "echo done");
+        std::fs::remove_file(&exe).unwrap();
+
+        for background_priority in [false, true] {
+            let options = ProcessOptions { background_priority, ..ProcessOptions::default() };
+            let err = run_batch_process(&exe, &netlist, "ngspice", &options, None).await.unwrap_err();
+            let missing = err.downcast_ref::<SimulatorMissing>().expect("a missing simulator error");
+            assert_eq!(missing.path, exe);
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_simulator_runs_in_temp_dir_with_clean_environment() {
+        let dir = Builder::new().prefix("kelicad sim ").tempdir().unwrap();
+        let (exe, netlist) = fake_simulator(dir.path(), "pwd > cwd.txt; env > env.txt");
+        run_batch_process(&exe, &netlist, "ngspice", &ProcessOptions::default(), None).await.unwrap();
+
+        let cwd = std::fs::read_to_string(dir.path().join("cwd.txt")).unwrap();
+        assert_eq!(Path::new(cwd.trim()).canonicalize().unwrap(), dir.path().canonicalize().unwrap());
+
+        let env = std::fs::read_to_string(dir.path().join("env.txt")).unwrap();
+        for name in env.lines().filter_map(|line| line.split_once('=')).map(|(name, _)| name) {
+            assert!(
+                name == "PATH" || SIMULATOR_ENV_VARS.contains(&name) || ["PWD", "OLDPWD", "SHLVL", "_"].contains(&name),
+                "unexpected variable {}",
+                name
+            );
+        }
+        let path = env.lines().find_map(|line| line.strip_prefix("PATH=")).unwrap();
+        assert!(path.starts_with(&*dir.path().to_string_lossy()), "{}", path);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_ngspice_raw_file_lands_in_temp_dir_with_spaces() {
+        let dir = Builder::new().prefix("kelicad sim ").tempdir().unwrap();
+        // Stands in for ngspice, writing whatever the control section's `write` names
+        let (exe, _) = fake_simulator(
+            dir.path(),
+            r#"raw=$(sed -n 's/^write \([^ ]*\) .*$/\1/p' "$2")
+printf 'raw data' > "$raw""#,
+        );
+        let netlist_path = dir.path().join("corner0.cir");
+        let raw_path = dir.path().join("corner0.raw");
+        std::fs::write(&netlist_path, prepare_ngspice_netlist("* Test\n.tran 1m\n.end", &quality("balanced"), true, &raw_path, None, None))
+            .unwrap();
+
+        run_batch_process(&exe, &netlist_path, "ngspice", &ProcessOptions::default(), None).await.unwrap();
+        assert_eq!(std::fs::read_to_string(&raw_path).unwrap(), "raw data");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_simulator_command_background_priority_uses_nice() {
+        let command = simulator_command("/usr/bin/ngspice", &["-b"], Path::new("/tmp/circuit.cir"), true);
+        let args: Vec<String> = command.get_args().map(|a| a.to_string_lossy().to_string()).collect();
+        assert_eq!(command.get_program(), "nice");
+        assert_eq!(args, vec!["-n", "10", "/usr/bin/ngspice", "-b", "/tmp/circuit.cir"]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_simulator_command_normal_priority() {
+        let command = simulator_command("/usr/bin/ngspice", &["-b"], Path::new("/tmp/circuit.cir"), false);
+        let args: Vec<String> = command.get_args().map(|a| a.to_string_lossy().to_string()).collect();
+        assert_eq!(command.get_program(), "/usr/bin/ngspice");
+        assert_eq!(args, vec!["-b", "/tmp/circuit.cir"]);
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn test_simulator_command_priority_flags() {
+        assert_eq!(priority_creation_flags(true), BELOW_NORMAL_PRIORITY_CLASS);
+        assert_eq!(priority_creation_flags(false), 0);
+
+        // Priority goes through creation flags, so the simulator is spawned directly
+        let command = simulator_command("ngspice.exe", &["-b"], Path::new("circuit.cir"), true);
+        assert_eq!(command.get_program(), "ngspice.exe");
+    }
+
+    #[test]
+    fn test_orphan_match_requires_temp_path() {
+        let exe = PathBuf::from("/opt/ltspice/LTspice");
+        let executables = vec![exe.clone()];
+        let args = |list: &[&str]| list.iter().map(std::ffi::OsString::from).collect::<Vec<_>>();
+
+        assert!(is_orphaned_simulator(
+            Some(&exe),
+            &args(&["LTspice", "-b", "/tmp/kelicad-sim-abc123/circuit.net"]),
+            &executables
+        ));
+        assert!(is_orphaned_simulator(
+            Some(&exe),
+            &args(&["LTspice", "-b", "/tmp/kelicad-ngspice-abc123/circuit.cir"]),
+            &executables
+        ));
+        // A user's own LTspice session must never match
+        assert!(!is_orphaned_simulator(
+            Some(&exe),
+            &args(&["LTspice", "/home/user/amp.asc"]),
+            &executables
+        ));
+        assert!(!is_orphaned_simulator(Some(&exe), &args(&["LTspice"]), &executables));
+        // Other programs touching our temp dirs are left alone
+        assert!(!is_orphaned_simulator(
+            Some(Path::new("/usr/bin/vim")),
+            &args(&["vim", "/tmp/kelicad-sim-abc123/circuit.net"]),
+            &executables
+        ));
+        assert!(!is_orphaned_simulator(None, &args(&["LTspice", "/tmp/kelicad-sim-x/a.net"]), &executables));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_kill_outcome_and_status() {
+        let mut child = Command::new("sleep").arg("30").spawn().unwrap();
+        assert!(kill_process_tree(child.id()));
+        assert!(killed(&child.wait().unwrap()));
+
+        let status = Command::new("true").status().unwrap();
+        assert!(!killed(&status));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_kill_orphaned_simulators() {
+        let dir = TempDir::new().unwrap();
+        // A copy of sh stands in for the simulator executable
+        let fake_exe = dir.path().join("fake-ngspice");
+        std::fs::copy("/bin/sh", &fake_exe).unwrap();
+        let netlist = dir.path().join("kelicad-ngspice-test").join("circuit.cir");
+        let spawn = |arg: &Path| {
+            Command::new(&fake_exe)
+                .args(["-c", "sleep 30; true"])
+                .arg(arg)
+                .spawn()
+                .unwrap()
+        };
+        let mut orphan = spawn(&netlist);
+        let mut interactive = spawn(&dir.path().join("user-circuit.cir"));
+        // Give the processes time to show up in the process table
+        std::thread::sleep(std::time::Duration::from_millis(200));
+
+        let killed = kill_orphaned_simulators(&[fake_exe.to_string_lossy().to_string()]);
+        assert_eq!(killed, vec![orphan.id()]);
+        assert!(orphan.wait().is_ok());
+        assert!(interactive.try_wait().unwrap().is_none());
+
+        interactive.kill().unwrap();
+        let _ = interactive.wait();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_resource_sampler_reports_busy_process() {
+        let dir = TempDir::new().unwrap();
+        let (exe, netlist) = fake_simulator(dir.path(), "echo partial > \"${2%.net}.raw\"\nend=$(($(date +%s) + 5))\nwhile [ $(date +%s) -lt $end ]; do :; done");
+        let samples: Arc<std::sync::Mutex<Vec<ResourceUsage>>> = Arc::default();
+        let collected = samples.clone();
+        let options = ProcessOptions {
+            resource_sink: Some(Arc::new(move |usage| collected.lock().unwrap().push(usage))),
+            ..ProcessOptions::default()
+        };
+
+        run_batch_process(&exe, &netlist, "LTspice", &options, None).await.unwrap();
+
+        let samples = samples.lock().unwrap();
+        assert!(!samples.is_empty());
+        assert!(samples.iter().any(|s| s.cpu_percent > 0.0));
+        assert!(samples.iter().all(|s| s.memory_bytes > 0));
+        assert!(samples.iter().any(|s| s.raw_file_bytes > 0));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_process_monitor_detects_exit() {
+        let mut child = Command::new("sleep").arg("30").spawn().unwrap();
+        let mut monitor = ProcessMonitor::new(child.id(), Path::new("/tmp/none.net"));
+        assert!(monitor.sample_process().is_some());
+        assert!(monitor.start_time.is_some());
+
+        child.kill().unwrap();
+        child.wait().unwrap();
+        assert!(monitor.sample_process().is_none());
+
+        // A different start time means the PID now belongs to another process
+        let mut child = Command::new("sleep").arg("30").spawn().unwrap();
+        let mut monitor = ProcessMonitor::new(child.id(), Path::new("/tmp/none.net"));
+        monitor.start_time = monitor.start_time.map(|t| t.wrapping_sub(1000));
+        assert!(monitor.sample_process().is_none());
+        child.kill().unwrap();
+        let _ = child.wait();
+    }
+
+    #[test]
+    fn test_estimate_raw_file_bytes() {
+        let netlist = "* RC\nV1 in 0 1\nR1 in out 1k\nC1 out 0 1u\n.tran 1u 10m\n.end";
+        // 10000 points x (1 + 2 * 3 elements) vectors x 8 bytes
+        assert_eq!(estimate_raw_file_bytes(netlist, &quality("smooth")), Some(10_000 * 7 * 8));
+
+        // dtmax tighter than tstep increases the point count
+        let netlist = "* RC\nR1 in out 1k\n.tran 1u 10m 0 100n\n.end";
+        assert_eq!(estimate_raw_file_bytes(netlist, &quality("smooth")), Some(100_000 * 3 * 8));
+
+        // Zero tstep falls back to dtmax
+        let netlist = "* RC\nR1 in out 1k\n.tran 0 10m 0 1u\n.end";
+        assert_eq!(estimate_raw_file_bytes(netlist, &quality("smooth")), Some(10_000 * 3 * 8));
+    }
+
+    #[test]
+    fn test_estimate_raw_file_bytes_unknown() {
+        // LTspice single-argument form has no time step
+        assert_eq!(estimate_raw_file_bytes("R1 a 0 1k\n.tran 10m\n.end", &quality("smooth")), None);
+        // Compressed waveforms have data-dependent size
+        assert_eq!(estimate_raw_file_bytes("R1 a 0 1k\n.tran 1u 10m\n.end", &quality("fast")), None);
+        assert_eq!(
+            estimate_raw_file_bytes("R1 a 0 1k\n.tran 1u 10m\n.options plotwinsize=300\n.end", &quality("smooth")),
+            None
+        );
+        assert_eq!(estimate_raw_file_bytes("R1 a 0 1k\n.ac dec 10 1 1meg\n.end", &quality("smooth")), None);
+    }
+
+    #[test]
+    fn test_apply_default_analysis() {
+        let netlist = "* RC\nR1 in out 1k\n.end";
+        assert_eq!(apply_default_analysis(netlist, Some(".op")), "* RC\nR1 in out 1k\n.op\n.end");
+        assert_eq!(apply_default_analysis(netlist, None), netlist);
+        assert_eq!(apply_default_analysis("R1 in 0 1k", Some(" .op ")), "R1 in 0 1k\n.op");
+
+        // An existing analysis is left alone
+        let netlist = "* RC\nR1 in out 1k\n.TRAN 1m\n.end";
+        assert_eq!(apply_default_analysis(netlist, Some(".op")), netlist);
+    }
+
+    #[test]
+    fn test_preflight_check_requires_analysis() {
+        let err = preflight_check("* RC\nR1 in out 1k\n* .tran 1m\n.end", &quality("smooth"), 1, 1, &std::env::temp_dir()).unwrap_err();
+        assert_eq!(err.error_code(), "NO_ANALYSIS_DIRECTIVE");
+        assert!(err.to_string().contains(".tran, .ac, .dc, .op"));
+    }
+
+    #[test]
+    fn test_preflight_check() {
+        let netlist = "* RC\nR1 in out 1k\n.tran 1m\n.end";
+        let temp_root = std::env::temp_dir();
+        assert!(preflight_check(netlist, &quality("smooth"), 1, 1, &temp_root).is_ok());
+
+        if available_disk_space(&std::env::temp_dir()).is_none() {
+            return;
+        }
+        let err = preflight_check(netlist, &quality("smooth"), 1, u64::MAX, &temp_root).unwrap_err();
+        assert_eq!(err.error_code(), "INSUFFICIENT_DISK_SPACE");
+        assert!(err.to_string().contains("MB free"));
+        assert!(err.to_string().contains(&temp_root.display().to_string()));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_streamed_output_forwards_lines() {
+        let dir = TempDir::new().unwrap();
+        let (exe, netlist) = fake_simulator(
+            dir.path(),
+            "i=0\nwhile [ $i -lt 300 ]; do echo \"line $i\"; i=$((i+1)); done\nprintf 'progress 50%%\\rprogress 100%%\\n'\necho 'Error: singular matrix' >&2",
+        );
+        let (sink, mut lines) = tokio::sync::mpsc::unbounded_channel();
+        let options = ProcessOptions {
+            log_sink: Some(sink),
+            ..ProcessOptions::default()
+        };
+
+        let output = run_batch_process(&exe, &netlist, "ngspice", &options, None).await.unwrap();
+        drop(options);
+
+        let mut received = Vec::new();
+        while let Some(line) = lines.recv().await {
+            received.push(line);
+        }
+        let stdout: Vec<&str> = received.iter().filter(|l| l.stream == "stdout").map(|l| l.text.as_str()).collect();
+        let stderr: Vec<&str> = received.iter().filter(|l| l.stream == "stderr").map(|l| l.text.as_str()).collect();
+        assert_eq!(stdout.len(), 302);
+        assert_eq!(stdout[0], "line 0");
+        assert_eq!(stdout[299], "line 299");
+        assert_eq!(&stdout[300..], ["progress 50%", "progress 100%"]);
+        assert_eq!(stderr, ["Error: singular matrix"]);
+
+        // Full output is still available for post-mortem error extraction
+        let full = String::from_utf8_lossy(&output.stdout);
+        assert!(full.contains("line 150\n"));
+        assert!(String::from_utf8_lossy(&output.stderr).contains("singular matrix"));
+    }
 }
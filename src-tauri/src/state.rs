@@ -0,0 +1,985 @@
+// Copyright (c) 2024-2025 Wanyeki Technologies LLC. All rights reserved.
+// This source code is licensed under the proprietary license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Shared agent state and the simulation worker pool
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::time::Instant;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use tokio::sync::{broadcast, oneshot, watch, OwnedSemaphorePermit, RwLock, Semaphore};
+
+use crate::artifacts::Artifacts;
+use crate::catalog;
+use crate::dedup::RecentRequests;
+use crate::export;
+use crate::fingerprint::{self, BinaryCache};
+use crate::history::History;
+use crate::metrics::Metrics;
+use crate::ngspice_session::NgspiceSession;
+use crate::onboarding;
+use crate::protocol::{BundledLibrary, BusyInfo, OnboardingState, ResourceUsage, SimulationResults, SimulatorBinary};
+use crate::rate_limit::RateLimiter;
+use crate::resource_budget::{self, BudgetEntry, BudgetUsage, Budgeted, PoolUsage, ResourceBudget};
+use crate::settings::AgentSettings;
+use crate::shared_results::ResultFeed;
+use crate::temp_root::TempRoot;
+use crate::templates::TemplateStore;
+use crate::trace_store::TraceStore;
+
+/// Results larger than this are not kept for export
+const MAX_STORED_RESULTS_BYTES: usize = 256 * 1024 * 1024;
+
+/// Buffered status events per listener before older ones are dropped
+const STATUS_EVENT_CAPACITY: usize = 64;
+
+/// Hex digits of the hash that stands in for another origin's request id
+const REDACTED_ID_LEN: usize = 12;
+
+/// Results of the most recent successful simulation, kept for export
+#[derive(Default)]
+pub struct LastResults {
+    results: Option<Arc<SimulationResults>>,
+    bytes: u64,
+    last_used: u64,
+}
+
+impl LastResults {
+    /// The kept results, marking them most recently used
+    pub fn get(&mut self) -> Option<Arc<SimulationResults>> {
+        if self.results.is_some() {
+            self.last_used = resource_budget::next_use();
+        }
+        self.results.clone()
+    }
+
+    fn set(&mut self, results: Option<Arc<SimulationResults>>, bytes: u64) {
+        self.bytes = if results.is_some() { bytes } else { 0 };
+        self.results = results;
+        self.last_used = resource_budget::next_use();
+    }
+}
+
+impl Budgeted for LastResults {
+    fn budget_entries(&self) -> Vec<BudgetEntry> {
+        self.results
+            .iter()
+            .map(|_| BudgetEntry { key: "last".to_string(), size: self.bytes, last_used: self.last_used })
+            .collect()
+    }
+
+    fn evict(&mut self, _key: &str) {
+        self.set(None, 0);
+    }
+}
+
+/// Agent state change, published to the tray and other status listeners
+#[derive(Debug, Clone, PartialEq)]
+pub enum StatusEvent {
+    /// Connections, simulator availability or the set of running simulations changed
+    Changed,
+    /// LTspice or ngspice became available or unavailable; published after `Changed`
+    CapabilitiesChanged,
+    /// A running simulation reported overall progress
+    Progress { request_id: String, percent: f32 },
+    /// A simulate request finished; `client_connected` is false if its client went away meanwhile
+    SimulationFinished {
+        request_id: String,
+        success: bool,
+        summary: String,
+        execution_ms: u64,
+        client_connected: bool,
+    },
+}
+
+/// Where a simulation is relative to a cancel request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RunState {
+    Running,
+    /// A cancel was requested and the simulator processes were sent kill signals
+    Cancelling,
+    /// The cancel stopped the run
+    Cancelled,
+    /// The run ended on its own, possibly after a cancel that came too late to stop it
+    Finished,
+}
+
+/// A simulation currently holding a worker slot
+pub struct ActiveSimulation {
+    pub request_id: String,
+    /// Page origin of the connection that started it; None for the agent window
+    pub origin: Option<String>,
+    pub started_at: Instant,
+    pub cancel_requested: AtomicBool,
+    /// PID of the simulator process, 0 until it has been spawned
+    pub process_id: Arc<AtomicU32>,
+    /// PIDs of further simulator processes when batch corners or runs execute in parallel
+    parallel_process_ids: std::sync::Mutex<Vec<Arc<AtomicU32>>>,
+    /// Latest resource usage sample of the simulator process
+    pub resources: std::sync::Mutex<Option<ResourceUsage>>,
+    /// Latest reported completion, from the simulator or finished runs of a batch
+    progress_percent: std::sync::Mutex<Option<f32>>,
+    /// Unix time in ms a simulator of this request last wrote to its log or output, 0 until one has
+    pub log_activity: Arc<AtomicU64>,
+    run_state: watch::Sender<RunState>,
+}
+
+impl ActiveSimulation {
+    pub fn is_cancelled(&self) -> bool {
+        self.cancel_requested.load(Ordering::SeqCst)
+    }
+
+    /// Ask the run to stop, returning its state beforehand; a run that already finished keeps
+    /// its results and is not flagged
+    pub fn request_cancel(&self) -> RunState {
+        let mut previous = RunState::Running;
+        self.run_state.send_if_modified(|state| {
+            previous = *state;
+            if *state != RunState::Running {
+                return false;
+            }
+            *state = RunState::Cancelling;
+            self.cancel_requested.store(true, Ordering::SeqCst);
+            true
+        });
+        previous
+    }
+
+    /// Record how the run ended, once: `stopped` when the cancel is what ended it
+    /// Returns the state the run settled in
+    pub fn settle(&self, stopped: bool) -> RunState {
+        self.run_state.send_if_modified(|state| {
+            if matches!(*state, RunState::Cancelled | RunState::Finished) {
+                return false;
+            }
+            *state = if stopped { RunState::Cancelled } else { RunState::Finished };
+            true
+        });
+        *self.run_state.borrow()
+    }
+
+    /// Wait up to `timeout` for the run to settle, returning the state it is in by then
+    pub async fn wait_settled(&self, timeout: std::time::Duration) -> RunState {
+        let mut state = self.run_state.subscribe();
+        let settled = state.wait_for(|state| matches!(state, RunState::Cancelled | RunState::Finished));
+        let _ = tokio::time::timeout(timeout, settled).await;
+        *self.run_state.borrow()
+    }
+
+    pub fn latest_resources(&self) -> Option<ResourceUsage> {
+        self.resources.lock().ok().and_then(|usage| usage.clone())
+    }
+
+    pub fn set_progress(&self, percent: f32) {
+        if let Ok(mut progress) = self.progress_percent.lock() {
+            *progress = Some(percent);
+        }
+    }
+
+    pub fn latest_progress(&self) -> Option<f32> {
+        self.progress_percent.lock().ok().and_then(|progress| *progress)
+    }
+
+    pub fn last_log_activity(&self) -> Option<u64> {
+        Some(self.log_activity.load(Ordering::SeqCst)).filter(|at| *at > 0)
+    }
+
+    /// A PID holder for another simulator process running alongside the first
+    pub fn add_process_holder(&self) -> Arc<AtomicU32> {
+        let holder = Arc::new(AtomicU32::new(0));
+        if let Ok(mut holders) = self.parallel_process_ids.lock() {
+            holders.push(holder.clone());
+        }
+        holder
+    }
+
+    /// PIDs of every simulator process started for this simulation, leaving out unspawned ones
+    pub fn process_ids(&self) -> Vec<u32> {
+        let mut pids = vec![self.process_id.load(Ordering::SeqCst)];
+        if let Ok(holders) = self.parallel_process_ids.lock() {
+            pids.extend(holders.iter().map(|holder| holder.load(Ordering::SeqCst)));
+        }
+        pids.retain(|pid| *pid != 0);
+        pids
+    }
+}
+
+/// Status entry for an in-flight simulation
+#[derive(Debug, Clone, Serialize)]
+pub struct ActiveSimulationStatus {
+    pub request_id: String,
+    pub elapsed_ms: u64,
+    pub resources: Option<ResourceUsage>,
+    pub progress_percent: Option<f32>,
+}
+
+/// Why a simulation could not claim a worker slot
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BeginError {
+    ShuttingDown,
+    /// A simulation with the same request id is running
+    AlreadyRunning(String),
+    /// Every worker slot is taken
+    Busy,
+}
+
+impl std::fmt::Display for BeginError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::ShuttingDown => write!(f, "The agent is shutting down"),
+            Self::AlreadyRunning(request_id) => write!(f, "Simulation {} is already running", request_id),
+            Self::Busy => write!(f, "Another simulation is already running"),
+        }
+    }
+}
+
+impl std::error::Error for BeginError {}
+
+/// Worker slot held for the lifetime of one simulation
+pub struct SimulationSlot {
+    permit: OwnedSemaphorePermit,
+    pub simulation: Arc<ActiveSimulation>,
+}
+
+pub struct AppState {
+    pub ltspice_path: RwLock<Option<String>>,
+    pub ngspice_path: RwLock<Option<String>>,
+    /// Unix time in ms simulator detection last ran, None until the first run
+    pub simulators_checked_at: RwLock<Option<u64>>,
+    /// First-run onboarding stage, recomputed by every detection pass
+    pub onboarding: RwLock<OnboardingState>,
+    pub ws_connections: RwLock<u32>,
+    pub simulation_count: RwLock<u32>,
+    pub last_simulation_time: RwLock<Option<u64>>,
+    /// Per-simulator outcomes and execution times, resettable from the agent window
+    pub metrics: RwLock<Metrics>,
+    /// Why the WebSocket server is not running, if it failed to start
+    pub server_error: RwLock<Option<String>>,
+    /// Leftover simulator processes killed at startup
+    pub orphaned_processes_killed: RwLock<u32>,
+    /// SHA-256 and version of each simulator executable, kept until the file changes
+    pub simulator_binaries: RwLock<BinaryCache>,
+    pub settings: RwLock<AgentSettings>,
+    /// Caps on results, raw files and netlists kept between simulations, from the settings
+    pub resource_budget: RwLock<ResourceBudget>,
+    /// Libraries bundled in the resources directory, loaded once at startup
+    pub bundled_libraries: Vec<BundledLibrary>,
+    /// Status file kept for local tools, set at startup; None when it isn't written
+    pub status_file: Option<PathBuf>,
+    /// Worker pool: one permit per simulation allowed to run concurrently
+    simulation_slots: Arc<Semaphore>,
+    /// Permits still to be retired after the pool was shrunk while they were in use
+    pending_slot_reduction: AtomicUsize,
+    pub active_simulations: RwLock<HashMap<String, Arc<ActiveSimulation>>>,
+    /// Request id of the simulation started from the agent window, if one is running
+    pub local_simulation: RwLock<Option<String>>,
+    /// Results of the most recent successful simulation, kept for export
+    pub last_results: RwLock<LastResults>,
+    /// Recent simulate requests and their responses, for client retries and `get_result`
+    pub recent_requests: RwLock<RecentRequests>,
+    /// Prepared netlists kept for replay when `store_netlists` is on
+    pub history: RwLock<History>,
+    /// Raw files kept for `get_artifact`; none until the artifacts directory is opened at startup
+    pub artifacts: RwLock<Artifacts>,
+    /// Results held back by `deferTraceData`, for `get_trace_data`
+    pub trace_store: RwLock<TraceStore>,
+    /// Netlist templates registered for `simulate_template`, per origin
+    pub templates: RwLock<TemplateStore>,
+    /// Where simulations make their temp directories, from `temp_dir_override` when it is usable
+    pub temp_root: RwLock<TempRoot>,
+    /// Simulation starts allowed per page origin
+    pub rate_limiter: RwLock<RateLimiter>,
+    /// Simulations held back by a long-run warning, waiting for `confirm` or `cancel`
+    pending_confirmations: RwLock<HashMap<String, oneshot::Sender<bool>>>,
+    status_events: broadcast::Sender<StatusEvent>,
+    /// Copies of simulation progress and results for connections subscribed to all of them
+    pub result_feed: ResultFeed,
+    /// ngspice kept running for `ngspice_persistent_session`
+    pub ngspice_session: Arc<NgspiceSession>,
+    /// Set once a shutdown starts; the server and connections watch it to close down
+    shutdown_signal: watch::Sender<bool>,
+    /// Set once the shutdown sequence has finished and the process may exit
+    shutdown_complete: AtomicBool,
+}
+
+impl Default for AppState {
+    fn default() -> Self {
+        Self::new(AgentSettings::default())
+    }
+}
+
+impl AppState {
+    pub fn new(mut settings: AgentSettings) -> Self {
+        settings.max_concurrent_simulations = clamp_worker_count(settings.max_concurrent_simulations);
+        let temp_root = TempRoot::resolve(
+            settings.temp_dir_override.as_deref(),
+            settings.min_free_disk_mb.saturating_mul(1024 * 1024),
+        );
+        Self {
+            ltspice_path: RwLock::new(None),
+            ngspice_path: RwLock::new(None),
+            simulators_checked_at: RwLock::new(None),
+            onboarding: RwLock::new(onboarding::onboarding_state(false, false)),
+            ws_connections: RwLock::new(0),
+            simulation_count: RwLock::new(0),
+            last_simulation_time: RwLock::new(None),
+            metrics: RwLock::new(Metrics::default()),
+            server_error: RwLock::new(None),
+            orphaned_processes_killed: RwLock::new(0),
+            simulator_binaries: RwLock::new(BinaryCache::default()),
+            simulation_slots: Arc::new(Semaphore::new(settings.max_concurrent_simulations)),
+            pending_slot_reduction: AtomicUsize::new(0),
+            resource_budget: RwLock::new(ResourceBudget::from_settings(&settings)),
+            history: RwLock::new(History::with_max_entries(settings.max_history_entries as usize)),
+            settings: RwLock::new(settings),
+            bundled_libraries: catalog::fallback(),
+            status_file: None,
+            active_simulations: RwLock::new(HashMap::new()),
+            local_simulation: RwLock::new(None),
+            last_results: RwLock::new(LastResults::default()),
+            recent_requests: RwLock::new(RecentRequests::default()),
+            artifacts: RwLock::new(Artifacts::default()),
+            trace_store: RwLock::new(TraceStore::default()),
+            templates: RwLock::new(TemplateStore::default()),
+            temp_root: RwLock::new(temp_root),
+            rate_limiter: RwLock::new(RateLimiter::default()),
+            pending_confirmations: RwLock::new(HashMap::new()),
+            status_events: broadcast::channel(STATUS_EVENT_CAPACITY).0,
+            result_feed: ResultFeed::default(),
+            ngspice_session: Arc::new(NgspiceSession::default()),
+            shutdown_signal: watch::channel(false).0,
+            shutdown_complete: AtomicBool::new(false),
+        }
+    }
+
+    /// Fingerprint of the detected "ltspice" or "ngspice" executable, None if it isn't available
+    pub async fn simulator_binary(&self, simulator: &str) -> Option<SimulatorBinary> {
+        let path = match simulator {
+            "ngspice" => self.ngspice_path.read().await.clone(),
+            _ => self.ltspice_path.read().await.clone(),
+        }?;
+        fingerprint::identify(&self.simulator_binaries, simulator, &path).await
+    }
+
+    /// Bundled libraries that may stand in for a missing include, per the catalog and settings
+    pub async fn substitutable_libraries(&self) -> Vec<String> {
+        let settings = self.settings.read().await;
+        catalog::enabled_names(&self.bundled_libraries, &settings.disabled_bundled_libraries)
+    }
+
+    /// Hold a simulation until it is confirmed or declined; the receiver gets the decision
+    pub async fn await_confirmation(&self, request_id: &str) -> oneshot::Receiver<bool> {
+        let (tx, rx) = oneshot::channel();
+        self.pending_confirmations.write().await.insert(request_id.to_string(), tx);
+        rx
+    }
+
+    /// Confirm or decline a held simulation; false if it was not waiting
+    pub async fn resolve_confirmation(&self, request_id: &str, proceed: bool) -> bool {
+        match self.pending_confirmations.write().await.remove(request_id) {
+            Some(tx) => tx.send(proceed).is_ok(),
+            None => false,
+        }
+    }
+
+    /// Number of simulations held by a long-run warning
+    pub async fn pending_confirmation_count(&self) -> usize {
+        self.pending_confirmations.read().await.len()
+    }
+
+    /// Receive status events published after this call
+    pub fn subscribe_status(&self) -> broadcast::Receiver<StatusEvent> {
+        self.status_events.subscribe()
+    }
+
+    /// Publish a status event; ignored when nobody is listening
+    pub fn notify_status(&self, event: StatusEvent) {
+        let _ = self.status_events.send(event);
+    }
+
+    /// Start shutting down; returns false if a shutdown was already in progress
+    pub fn begin_shutdown(&self) -> bool {
+        !self.shutdown_signal.send_replace(true)
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        *self.shutdown_signal.borrow()
+    }
+
+    /// Resolve once a shutdown starts, immediately if one already has
+    pub async fn wait_for_shutdown(&self) {
+        let mut shutdown = self.shutdown_signal.subscribe();
+        let _ = shutdown.wait_for(|shutting_down| *shutting_down).await;
+    }
+
+    pub fn mark_shutdown_complete(&self) {
+        self.shutdown_complete.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_shutdown_complete(&self) -> bool {
+        self.shutdown_complete.load(Ordering::SeqCst)
+    }
+
+    /// True when every worker slot is taken and new simulations would be rejected
+    pub fn is_busy(&self) -> bool {
+        self.simulation_slots.available_permits() == 0
+    }
+
+    /// Claim a worker slot and register the simulation under its request id, sent by a page on
+    /// `origin` or, with None, from the agent window
+    /// Fails if the pool is full or a simulation with the same id is already running
+    pub async fn begin_simulation(&self, request_id: &str, origin: Option<&str>) -> Result<SimulationSlot, BeginError> {
+        if self.is_shutting_down() {
+            return Err(BeginError::ShuttingDown);
+        }
+        let mut active = self.active_simulations.write().await;
+        if active.contains_key(request_id) {
+            return Err(BeginError::AlreadyRunning(request_id.to_string()));
+        }
+
+        let permit = self.simulation_slots.clone().try_acquire_owned().map_err(|_| BeginError::Busy)?;
+
+        let simulation = Arc::new(ActiveSimulation {
+            request_id: request_id.to_string(),
+            origin: origin.map(str::to_string),
+            started_at: Instant::now(),
+            cancel_requested: AtomicBool::new(false),
+            process_id: Arc::new(AtomicU32::new(0)),
+            parallel_process_ids: std::sync::Mutex::new(Vec::new()),
+            resources: std::sync::Mutex::new(None),
+            progress_percent: std::sync::Mutex::new(None),
+            log_activity: Arc::new(AtomicU64::new(0)),
+            run_state: watch::Sender::new(RunState::Running),
+        });
+        active.insert(request_id.to_string(), simulation.clone());
+        self.notify_status(StatusEvent::Changed);
+
+        Ok(SimulationSlot { permit, simulation })
+    }
+
+    /// Release a worker slot, returning whether the simulation was cancelled
+    /// A run not settled by its caller counts as cancelled if a cancel was requested
+    pub async fn end_simulation(&self, slot: SimulationSlot) -> bool {
+        let SimulationSlot { permit, simulation } = slot;
+        let stopped = simulation.settle(simulation.is_cancelled()) == RunState::Cancelled;
+        {
+            // A force reset may already have dropped this entry, and a new run may reuse the id
+            let mut active = self.active_simulations.write().await;
+            if active
+                .get(&simulation.request_id)
+                .is_some_and(|current| Arc::ptr_eq(current, &simulation))
+            {
+                active.remove(&simulation.request_id);
+            }
+        }
+        self.notify_status(StatusEvent::Changed);
+        self.release_permit(permit);
+
+        stopped
+    }
+
+    /// Borrow up to `count` idle worker slots, for a batch that runs several simulator processes at once
+    pub fn claim_extra_slots(&self, count: usize) -> Vec<OwnedSemaphorePermit> {
+        std::iter::from_fn(|| self.simulation_slots.clone().try_acquire_owned().ok())
+            .take(count)
+            .collect()
+    }
+
+    /// Return slots borrowed with `claim_extra_slots`
+    pub fn release_extra_slots(&self, permits: Vec<OwnedSemaphorePermit>) {
+        for permit in permits {
+            self.release_permit(permit);
+        }
+    }
+
+    /// Retire the permit instead of returning it if the pool shrank meanwhile
+    fn release_permit(&self, permit: OwnedSemaphorePermit) {
+        let retire = self
+            .pending_slot_reduction
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+            .is_ok();
+        if retire {
+            permit.forget();
+        } else {
+            drop(permit);
+        }
+    }
+
+    /// Look up an in-flight simulation by request id
+    pub async fn active_simulation(&self, request_id: &str) -> Option<Arc<ActiveSimulation>> {
+        self.active_simulations.read().await.get(request_id).cloned()
+    }
+
+    /// Snapshot of in-flight simulations, oldest first
+    pub async fn active_simulation_statuses(&self) -> Vec<ActiveSimulationStatus> {
+        let active = self.active_simulations.read().await;
+        let mut statuses: Vec<(Instant, ActiveSimulationStatus)> = active
+            .values()
+            .map(|sim| {
+                (
+                    sim.started_at,
+                    ActiveSimulationStatus {
+                        request_id: sim.request_id.clone(),
+                        elapsed_ms: sim.started_at.elapsed().as_millis() as u64,
+                        resources: sim.latest_resources(),
+                        progress_percent: sim.latest_progress(),
+                    },
+                )
+            })
+            .collect();
+        statuses.sort_by_key(|(started_at, _)| *started_at);
+        statuses.into_iter().map(|(_, status)| status).collect()
+    }
+
+    /// What the agent is busy with, for a simulation from `origin` turned away with `BeginError::Busy`
+    /// Describes the run expected to finish first, or the longest running if none has reported
+    /// progress; its request id is hashed unless it came from `origin` too
+    pub async fn busy_info(&self, origin: Option<&str>) -> Option<BusyInfo> {
+        let running = {
+            let active = self.active_simulations.read().await;
+            let running = active
+                .values()
+                .map(|sim| (sim.clone(), estimated_remaining_ms(sim)))
+                .min_by_key(|(sim, remaining)| (remaining.is_none(), *remaining, sim.started_at))?;
+            (running, active.len())
+        };
+        let ((simulation, estimated_remaining_ms), running_simulations) = running;
+
+        let redacted = simulation.origin.as_deref() != origin;
+        Some(BusyInfo {
+            running_request_id: if redacted {
+                redact_request_id(&simulation.request_id)
+            } else {
+                simulation.request_id.clone()
+            },
+            request_id_redacted: redacted,
+            elapsed_ms: simulation.started_at.elapsed().as_millis() as u64,
+            estimated_remaining_ms,
+            running_simulations,
+            queue_length: self.pending_confirmation_count().await,
+        })
+    }
+
+    /// Forget every in-flight simulation and free their worker slots immediately
+    /// Returns the request ids and simulator PIDs (none if not yet spawned) that were dropped
+    pub async fn force_reset(&self) -> Vec<(String, Vec<u32>)> {
+        let mut active = self.active_simulations.write().await;
+        let cleared: Vec<(String, Vec<u32>)> = active
+            .drain()
+            .map(|(request_id, simulation)| {
+                simulation.cancel_requested.store(true, Ordering::SeqCst);
+                (request_id, simulation.process_ids())
+            })
+            .collect();
+
+        // Hand out replacement permits now; the stuck tasks retire theirs if they ever finish
+        if !cleared.is_empty() {
+            self.pending_slot_reduction.fetch_add(cleared.len(), Ordering::SeqCst);
+            self.simulation_slots.add_permits(cleared.len());
+            self.notify_status(StatusEvent::Changed);
+        }
+
+        cleared
+    }
+
+    /// Forget a simulation whose task panicked, returning the PIDs of simulator processes it left
+    /// running; its worker slot was already given back as the panic unwound
+    pub async fn abandon_simulation(&self, request_id: &str) -> Vec<u32> {
+        self.pending_confirmations.write().await.remove(request_id);
+        let Some(simulation) = self.active_simulations.write().await.remove(request_id) else {
+            return Vec::new();
+        };
+        simulation.cancel_requested.store(true, Ordering::SeqCst);
+        self.notify_status(StatusEvent::Changed);
+        simulation.process_ids()
+    }
+
+    /// Keep results for export, unless they exceed the size cap
+    /// Oversized results still replace the previous ones so an export never returns stale data
+    pub async fn store_results(&self, results: &SimulationResults) {
+        let size = export::results_size_bytes(results);
+        let stored = if size <= MAX_STORED_RESULTS_BYTES {
+            Some(Arc::new(results.clone()))
+        } else {
+            log::warn!("Not keeping {} MB of results for export", size / (1024 * 1024));
+            None
+        };
+        self.last_results.write().await.set(stored, size as u64);
+        self.enforce_result_budget().await;
+    }
+
+    /// Evict the least recently used results, whichever store keeps them, until what is kept fits
+    /// in `max_result_bytes`
+    /// Locks the stores in a fixed order: last results, recent requests, then the trace store
+    pub async fn enforce_result_budget(&self) {
+        let max = self.resource_budget.read().await.max_result_bytes;
+        let mut last_results = self.last_results.write().await;
+        let mut recent_requests = self.recent_requests.write().await;
+        let mut trace_store = self.trace_store.write().await;
+        let evicted = resource_budget::enforce(&mut [&mut *last_results, &mut *recent_requests, &mut *trace_store], max);
+        if evicted > 0 {
+            log::info!("Evicted {} kept results to stay within the {} MB result budget", evicted, max / (1024 * 1024));
+        }
+    }
+
+    /// Apply new caps, evicting whatever no longer fits
+    pub async fn set_resource_budget(&self, budget: ResourceBudget) {
+        *self.resource_budget.write().await = budget;
+        self.artifacts.write().await.set_max_bytes(budget.max_artifact_bytes);
+        self.history.write().await.set_max_entries(budget.max_history_entries as usize);
+        self.enforce_result_budget().await;
+    }
+
+    /// What is kept now against the budget
+    pub async fn resource_usage(&self) -> BudgetUsage {
+        let budget = *self.resource_budget.read().await;
+        let results = [
+            self.last_results.read().await.budget_entries(),
+            self.recent_requests.read().await.budget_entries(),
+            self.trace_store.read().await.budget_entries(),
+        ];
+        BudgetUsage {
+            results: PoolUsage::of(&results, budget.max_result_bytes),
+            artifacts: PoolUsage::of(&[self.artifacts.read().await.budget_entries()], budget.max_artifact_bytes),
+            history: PoolUsage::of(&[self.history.read().await.budget_entries()], budget.max_history_entries),
+        }
+    }
+
+    /// Resize the worker pool, clamped to 1..=available CPUs
+    /// Returns the effective size; running simulations are never interrupted
+    pub async fn set_max_concurrent_simulations(&self, requested: usize) -> usize {
+        let new_size = clamp_worker_count(requested);
+        let mut settings = self.settings.write().await;
+        let old_size = settings.max_concurrent_simulations;
+
+        if new_size > old_size {
+            let mut grow = new_size - old_size;
+            // Cancel out any reduction that has not been applied yet
+            while grow > 0
+                && self
+                    .pending_slot_reduction
+                    .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+                    .is_ok()
+            {
+                grow -= 1;
+            }
+            self.simulation_slots.add_permits(grow);
+        } else if new_size < old_size {
+            let shrink = old_size - new_size;
+            let forgotten = self.simulation_slots.forget_permits(shrink);
+            self.pending_slot_reduction.fetch_add(shrink - forgotten, Ordering::SeqCst);
+        }
+
+        settings.max_concurrent_simulations = new_size;
+        new_size
+    }
+}
+
+/// Upper bound for the worker pool: the number of available CPUs
+pub fn max_worker_count() -> usize {
+    std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1)
+}
+
+fn clamp_worker_count(requested: usize) -> usize {
+    requested.clamp(1, max_worker_count())
+}
+
+/// Time left for `simulation` if it keeps its pace so far, once it has reported some progress
+fn estimated_remaining_ms(simulation: &ActiveSimulation) -> Option<u64> {
+    let percent = simulation.latest_progress().filter(|percent| *percent > 0.0)?.min(100.0) as f64;
+    let elapsed = simulation.started_at.elapsed().as_millis() as f64;
+    Some((elapsed * (100.0 - percent) / percent).round() as u64)
+}
+
+/// Short hash standing in for a request id another origin sent, stable for the same id
+fn redact_request_id(request_id: &str) -> String {
+    let digest = Sha256::digest(request_id.as_bytes());
+    let hex: String = digest.iter().map(|byte| format!("{:02x}", byte)).collect();
+    hex[..REDACTED_ID_LEN].to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    fn state_with_workers(n: usize) -> AppState {
+        AppState::new(AgentSettings {
+            max_concurrent_simulations: n,
+            ..AgentSettings::default()
+        })
+    }
+
+    #[tokio::test]
+    async fn test_default_pool_allows_single_simulation() {
+        let state = AppState::default();
+        let slot = state.begin_simulation("a", None).await.unwrap();
+        assert!(state.is_busy());
+        assert!(state.begin_simulation("b", None).await.is_err());
+
+        assert!(!state.end_simulation(slot).await);
+        assert!(!state.is_busy());
+        assert!(state.active_simulation_statuses().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_request_id_rejected() {
+        let state = state_with_workers(max_worker_count());
+        let _slot = state.begin_simulation("a", None).await.unwrap();
+        let err = state.begin_simulation("a", None).await.err().unwrap();
+        assert_eq!(err, BeginError::AlreadyRunning("a".to_string()));
+        assert!(err.to_string().contains("already running"));
+    }
+
+    #[tokio::test]
+    async fn test_busy_info_describes_the_running_simulation() {
+        let state = AppState::default();
+        assert_eq!(state.busy_info(None).await, None);
+        let slot = state.begin_simulation("first", Some("https://kelicad.com")).await.unwrap();
+        assert_eq!(state.begin_simulation("second", None).await.err(), Some(BeginError::Busy));
+
+        let info = state.busy_info(Some("https://kelicad.com")).await.unwrap();
+        assert_eq!(info.running_request_id, "first");
+        assert!(!info.request_id_redacted);
+        assert_eq!(info.estimated_remaining_ms, None);
+        assert_eq!((info.running_simulations, info.queue_length), (1, 0));
+
+        // Progress gives an estimate, and another origin only sees a hash of the id
+        slot.simulation.set_progress(50.0);
+        let info = state.busy_info(Some("https://other.example")).await.unwrap();
+        assert_eq!(info.running_request_id, redact_request_id("first"));
+        assert_eq!(info.running_request_id.len(), REDACTED_ID_LEN);
+        assert!(info.request_id_redacted);
+        assert!(info.estimated_remaining_ms.is_some());
+        assert!(state.busy_info(None).await.unwrap().request_id_redacted);
+
+        state.end_simulation(slot).await;
+        assert_eq!(state.busy_info(None).await, None);
+    }
+
+    #[tokio::test]
+    async fn test_cancel_flag_reported_on_end() {
+        let state = AppState::default();
+        let slot = state.begin_simulation("a", None).await.unwrap();
+        state
+            .active_simulation("a")
+            .await
+            .unwrap()
+            .cancel_requested
+            .store(true, Ordering::SeqCst);
+        assert!(state.end_simulation(slot).await);
+        assert!(state.active_simulation("a").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_shrinking_pool_retires_busy_permits() {
+        if max_worker_count() < 2 {
+            return;
+        }
+        let state = state_with_workers(2);
+        let a = state.begin_simulation("a", None).await.unwrap();
+        let b = state.begin_simulation("b", None).await.unwrap();
+
+        // Shrink while both slots are busy: nothing can be forgotten yet
+        assert_eq!(state.set_max_concurrent_simulations(1).await, 1);
+        state.end_simulation(a).await;
+        // The released permit was retired, so the pool is still full
+        assert!(state.is_busy());
+
+        state.end_simulation(b).await;
+        assert!(!state.is_busy());
+        let _c = state.begin_simulation("c", None).await.unwrap();
+        assert!(state.begin_simulation("d", None).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_pool_size_clamped() {
+        let state = AppState::default();
+        assert_eq!(state.set_max_concurrent_simulations(0).await, 1);
+        assert_eq!(state.set_max_concurrent_simulations(10_000).await, max_worker_count());
+    }
+
+    #[tokio::test]
+    async fn test_active_simulation_statuses() {
+        let state = state_with_workers(max_worker_count());
+        let _slot = state.begin_simulation("a", None).await.unwrap();
+        let statuses = state.active_simulation_statuses().await;
+        assert_eq!(statuses.len(), 1);
+        assert_eq!(statuses[0].request_id, "a");
+    }
+
+    #[tokio::test]
+    async fn test_force_reset_frees_slots() {
+        let state = AppState::default();
+        let stuck = state.begin_simulation("a", None).await.unwrap();
+        stuck.simulation.process_id.store(4242, Ordering::SeqCst);
+
+        let cleared = state.force_reset().await;
+        assert_eq!(cleared, vec![("a".to_string(), vec![4242])]);
+        assert!(stuck.simulation.is_cancelled());
+        assert!(!state.is_busy());
+
+        // A new simulation can run, even under the same id
+        let fresh = state.begin_simulation("a", None).await.unwrap();
+        assert!(state.begin_simulation("b", None).await.is_err());
+
+        // The stuck task finishing late retires its permit and leaves the new entry alone
+        assert!(state.end_simulation(stuck).await);
+        assert!(state.active_simulation("a").await.is_some());
+        assert!(state.is_busy());
+
+        state.end_simulation(fresh).await;
+        assert!(!state.is_busy());
+    }
+
+    #[tokio::test]
+    async fn test_cancel_after_the_run_finished_is_not_recorded() {
+        let state = AppState::default();
+        let slot = state.begin_simulation("a", None).await.unwrap();
+        let simulation = slot.simulation.clone();
+        assert_eq!(simulation.settle(false), RunState::Finished);
+
+        assert_eq!(simulation.request_cancel(), RunState::Finished);
+        assert!(!simulation.is_cancelled());
+        assert_eq!(simulation.wait_settled(std::time::Duration::ZERO).await, RunState::Finished);
+        assert!(!state.end_simulation(slot).await);
+    }
+
+    #[tokio::test]
+    async fn test_cancel_settles_once() {
+        let state = AppState::default();
+        let slot = state.begin_simulation("a", None).await.unwrap();
+        let simulation = slot.simulation.clone();
+        assert_eq!(simulation.request_cancel(), RunState::Running);
+        assert_eq!(simulation.request_cancel(), RunState::Cancelling);
+        assert!(simulation.is_cancelled());
+        assert_eq!(simulation.wait_settled(std::time::Duration::ZERO).await, RunState::Cancelling);
+
+        // The process exited on its own before the kill landed
+        let waiter = tokio::spawn({
+            let simulation = simulation.clone();
+            async move { simulation.wait_settled(std::time::Duration::from_secs(5)).await }
+        });
+        assert_eq!(simulation.settle(false), RunState::Finished);
+        assert_eq!(simulation.settle(true), RunState::Finished);
+        assert_eq!(waiter.await.unwrap(), RunState::Finished);
+        assert!(!state.end_simulation(slot).await);
+
+        // Unsettled runs count as cancelled once one was requested
+        let slot = state.begin_simulation("b", None).await.unwrap();
+        slot.simulation.request_cancel();
+        assert!(state.end_simulation(slot).await);
+    }
+
+    #[tokio::test]
+    async fn test_abandon_simulation_clears_its_entry() {
+        let state = AppState::default();
+        let slot = state.begin_simulation("a", None).await.unwrap();
+        slot.simulation.process_id.store(4242, Ordering::SeqCst);
+        let simulation = slot.simulation.clone();
+        // As when a panic unwinds through the task holding the slot
+        drop(slot);
+
+        assert_eq!(state.abandon_simulation("a").await, vec![4242]);
+        assert!(simulation.is_cancelled());
+        assert!(state.active_simulation("a").await.is_none());
+        assert!(!state.is_busy());
+        assert!(state.abandon_simulation("a").await.is_empty());
+        assert!(state.begin_simulation("a", None).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_status_events_on_begin_and_end() {
+        let state = AppState::default();
+        let mut events = state.subscribe_status();
+
+        let slot = state.begin_simulation("a", None).await.unwrap();
+        assert_eq!(events.try_recv().unwrap(), StatusEvent::Changed);
+        state.end_simulation(slot).await;
+        assert_eq!(events.try_recv().unwrap(), StatusEvent::Changed);
+        assert!(events.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_no_simulations_after_shutdown_begins() {
+        let state = AppState::default();
+        assert!(state.begin_shutdown());
+        assert!(!state.begin_shutdown());
+        assert!(state.is_shutting_down());
+        // Already signalled: waiting returns at once
+        tokio::time::timeout(std::time::Duration::from_secs(1), state.wait_for_shutdown())
+            .await
+            .unwrap();
+        let err = state.begin_simulation("a", None).await.err().unwrap();
+        assert_eq!(err, BeginError::ShuttingDown);
+        assert!(err.to_string().contains("shutting down"));
+    }
+
+    #[tokio::test]
+    async fn test_store_results_respects_size_cap() {
+        let state = AppState::default();
+        let small = SimulationResults {
+            time: vec![0.0, 1.0],
+            traces: vec![],
+            analysis_type: "transient".to_string(),
+            x_axis_label: None,
+            x_axis_unit: None,
+            scalar_results: None,
+            device_operating_points: BTreeMap::new(),
+            truncated: None,
+            plot_hints: None,
+            raw_header: None,
+        };
+        state.store_results(&small).await;
+        assert_eq!(state.last_results.write().await.get().unwrap().time.len(), 2);
+
+        let huge = SimulationResults {
+            time: vec![0.0; MAX_STORED_RESULTS_BYTES / 8 + 1],
+            ..small
+        };
+        state.store_results(&huge).await;
+        assert!(state.last_results.write().await.get().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_result_budget_evicts_least_recently_used_across_stores() {
+        let results = |points: usize| SimulationResults {
+            time: vec![0.0; points],
+            traces: vec![],
+            analysis_type: "transient".to_string(),
+            x_axis_label: None,
+            x_axis_unit: None,
+            scalar_results: None,
+            device_operating_points: BTreeMap::new(),
+            truncated: None,
+            plot_hints: None,
+            raw_header: None,
+        };
+        let state = AppState::default();
+        state.trace_store.write().await.insert("deferred", Arc::new(results(1000))).unwrap();
+        state.store_results(&results(1000)).await;
+        let usage = state.resource_usage().await;
+        assert_eq!(usage.results.entries, 2);
+        let kept = usage.results.used;
+
+        // Room for only one of the two: the deferred results were used least recently
+        state.set_resource_budget(ResourceBudget { max_result_bytes: kept - 1, ..ResourceBudget::default() }).await;
+        assert!(state.trace_store.write().await.get("deferred").is_none());
+        assert!(state.last_results.write().await.get().is_some());
+
+        // Storing the deferred results again leaves the last results least recently used
+        state.trace_store.write().await.insert("deferred", Arc::new(results(1000))).unwrap();
+        state.enforce_result_budget().await;
+        assert!(state.last_results.write().await.get().is_none());
+        assert!(state.trace_store.write().await.get("deferred").is_some());
+
+        let usage = state.resource_usage().await;
+        assert_eq!(usage.results.max, kept - 1);
+        assert_eq!(usage.results.entries, 1);
+        assert_eq!(usage.history, PoolUsage { used: 0, max: 20, entries: 0 });
+    }
+}
@@ -0,0 +1,231 @@
+// Copyright (c) 2024-2025 Wanyeki Technologies LLC. All rights reserved.
+// This source code is licensed under the proprietary license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Agent status, shared by the agent window's `get_agent_status` command and the
+//! WebSocket `get_status` message so the two report the same thing
+
+use serde::Serialize;
+
+use crate::logging;
+use crate::protocol::{self, ActiveSimulationInfo, AgentMetrics, OnboardingState, SimulatorBinary, StatusResponse};
+use crate::resource_budget::BudgetUsage;
+use crate::state::{ActiveSimulationStatus, AppState};
+
+/// Status as shown in the agent window
+#[derive(Debug, Clone, Serialize)]
+pub struct AgentStatus {
+    pub ltspice_path: Option<String>,
+    pub ltspice_available: bool,
+    pub ngspice_path: Option<String>,
+    pub ngspice_available: bool,
+    /// Unix time in ms simulator detection last ran
+    pub simulators_checked_at: Option<u64>,
+    /// Drives the install guide shown until both simulators are found
+    pub onboarding: OnboardingState,
+    pub is_simulating: bool,
+    pub active_simulations: Vec<ActiveSimulationStatus>,
+    /// Simulations held back by a long-run warning, waiting for `confirm`
+    pub queue_length: u32,
+    pub max_concurrent_simulations: usize,
+    pub background_priority: bool,
+    pub ngspice_threads: Option<u32>,
+    /// Longest a simulation may run, in seconds, as advertised in the handshake
+    pub max_simulation_seconds: u64,
+    pub orphaned_processes_killed: u32,
+    pub server_error: Option<String>,
+    pub log_file: Option<String>,
+    pub log_level: String,
+    /// Directory simulations make their temp directories in
+    pub temp_root: String,
+    /// Why the `temp_dir_override` setting isn't in use, when it was rejected
+    pub temp_root_error: Option<String>,
+    pub ws_connections: u32,
+    pub simulation_count: u32,
+    pub last_simulation_time: Option<u64>,
+    pub metrics: AgentMetrics,
+    pub ws_port: u16,
+    pub version: String,
+    pub ltspice_binary: Option<SimulatorBinary>,
+    pub ngspice_binary: Option<SimulatorBinary>,
+    /// Read-only status file for local tools: `%LOCALAPPDATA%\kelicad-agent\status.json` on
+    /// Windows, `~/Library/Application Support/kelicad-agent/status.json` on macOS and
+    /// `~/.local/share/kelicad-agent/status.json` (under `$XDG_DATA_HOME` if set) on Linux;
+    /// None when it isn't written
+    pub status_file: Option<String>,
+    /// Results, raw files and netlists kept now against the resource budget
+    pub resource_usage: BudgetUsage,
+}
+
+/// Snapshot of the agent's current status
+pub async fn agent_status(state: &AppState) -> AgentStatus {
+    let ltspice_path = state.ltspice_path.read().await.clone();
+    let ngspice_path = state.ngspice_path.read().await.clone();
+    let simulators_checked_at = *state.simulators_checked_at.read().await;
+    let onboarding = state.onboarding.read().await.clone();
+    let active_simulations = state.active_simulation_statuses().await;
+    let queue_length = state.pending_confirmation_count().await as u32;
+    let settings = state.settings.read().await.clone();
+    let orphaned_processes_killed = *state.orphaned_processes_killed.read().await;
+    let server_error = state.server_error.read().await.clone();
+    let temp_root = state.temp_root.read().await.clone();
+    let ws_connections = *state.ws_connections.read().await;
+    let simulation_count = *state.simulation_count.read().await;
+    let last_simulation_time = *state.last_simulation_time.read().await;
+    let metrics = state.metrics.read().await.snapshot();
+    let ltspice_binary = state.simulator_binary("ltspice").await;
+    let ngspice_binary = state.simulator_binary("ngspice").await;
+    let resource_usage = state.resource_usage().await;
+
+    AgentStatus {
+        ltspice_available: ltspice_path.is_some(),
+        ltspice_path,
+        ngspice_available: ngspice_path.is_some(),
+        ngspice_path,
+        simulators_checked_at,
+        onboarding,
+        is_simulating: !active_simulations.is_empty(),
+        active_simulations,
+        queue_length,
+        max_concurrent_simulations: settings.max_concurrent_simulations,
+        background_priority: settings.background_priority,
+        ngspice_threads: settings.ngspice_threads,
+        max_simulation_seconds: settings.max_simulation_seconds,
+        orphaned_processes_killed,
+        server_error,
+        log_file: logging::active_log_file().map(|path| path.to_string_lossy().to_string()),
+        log_level: log::max_level().to_string().to_lowercase(),
+        temp_root: temp_root.path.to_string_lossy().to_string(),
+        temp_root_error: temp_root.override_error,
+        ws_connections,
+        simulation_count,
+        last_simulation_time,
+        metrics,
+        ws_port: protocol::WS_PORT,
+        version: protocol::AGENT_VERSION.to_string(),
+        ltspice_binary,
+        ngspice_binary,
+        status_file: state.status_file.as_ref().map(|path| path.to_string_lossy().into_owned()),
+        resource_usage,
+    }
+}
+
+/// Reply to a `get_status` message
+pub async fn status_response(state: &AppState, request_id: String) -> StatusResponse {
+    // Destructured in full so a field added to the window's status can't be left out here
+    let AgentStatus {
+        ltspice_path,
+        ltspice_available,
+        ngspice_path,
+        ngspice_available,
+        simulators_checked_at,
+        onboarding,
+        is_simulating,
+        active_simulations,
+        queue_length,
+        max_concurrent_simulations,
+        background_priority,
+        ngspice_threads,
+        max_simulation_seconds,
+        orphaned_processes_killed,
+        server_error,
+        log_file,
+        log_level,
+        temp_root,
+        temp_root_error,
+        ws_connections,
+        simulation_count,
+        last_simulation_time,
+        metrics,
+        ws_port,
+        version,
+        ltspice_binary,
+        ngspice_binary,
+        status_file,
+        resource_usage,
+    } = agent_status(state).await;
+
+    StatusResponse {
+        id: uuid::Uuid::new_v4().to_string(),
+        msg_type: "status".to_string(),
+        request_id,
+        timestamp: protocol::now_ms(),
+        agent_version: version,
+        ws_port,
+        ltspice_available,
+        ltspice_path,
+        ltspice_binary,
+        ngspice_available,
+        ngspice_path,
+        ngspice_binary,
+        simulators_checked_at,
+        onboarding,
+        is_simulating,
+        active_simulations: active_simulations
+            .into_iter()
+            .map(|sim| ActiveSimulationInfo {
+                request_id: sim.request_id,
+                elapsed_ms: sim.elapsed_ms,
+                resources: sim.resources,
+                progress_percent: sim.progress_percent,
+            })
+            .collect(),
+        queue_length,
+        max_concurrent_simulations,
+        background_priority,
+        ngspice_threads,
+        max_simulation_seconds,
+        orphaned_processes_killed,
+        server_error,
+        log_file,
+        log_level,
+        temp_root,
+        temp_root_error,
+        ws_connections,
+        simulation_count,
+        last_simulation_time,
+        metrics,
+        status_file,
+        resource_usage,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::AgentSettings;
+
+    #[tokio::test]
+    async fn test_status_response_matches_agent_status() {
+        let state = AppState::new(AgentSettings::default());
+        *state.ngspice_path.write().await = Some("/usr/bin/ngspice".to_string());
+        *state.ws_connections.write().await = 3;
+        let slot = state.begin_simulation("sim-1", None).await.unwrap();
+        let _held = state.await_confirmation("sim-2").await;
+
+        let status = agent_status(&state).await;
+        assert!(status.is_simulating);
+        assert_eq!(status.queue_length, 1);
+        assert!(!status.ltspice_available);
+        assert!(status.ngspice_available);
+
+        let response = status_response(&state, "status-1".to_string()).await;
+        assert_eq!(response.msg_type, "status");
+        assert_eq!(response.request_id, "status-1");
+        assert_eq!(response.agent_version, protocol::AGENT_VERSION);
+        assert_eq!(response.ngspice_path.as_deref(), Some("/usr/bin/ngspice"));
+        assert_eq!(response.ws_connections, 3);
+        assert_eq!(response.max_concurrent_simulations, status.max_concurrent_simulations);
+        assert_eq!(response.queue_length, 1);
+        assert_eq!(response.active_simulations.len(), 1);
+        assert_eq!(response.active_simulations[0].request_id, "sim-1");
+        assert_eq!(response.temp_root, std::env::temp_dir().to_string_lossy());
+        assert_eq!(response.temp_root_error, None);
+        assert_eq!(response.onboarding, status.onboarding);
+
+        state.end_simulation(slot).await;
+        let response = status_response(&state, "status-2".to_string()).await;
+        assert!(!response.is_simulating);
+        assert!(response.active_simulations.is_empty());
+    }
+}
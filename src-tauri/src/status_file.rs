@@ -0,0 +1,257 @@
+// Copyright (c) 2024-2025 Wanyeki Technologies LLC. All rights reserved.
+// This source code is licensed under the proprietary license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! A small read-only JSON file telling local tools that don't speak WebSocket whether the agent
+//! is running and on which port
+//!
+//! It is rewritten whenever the agent's status changes and removed on a clean shutdown. A file
+//! left behind by an agent that crashed is recognised at startup by its PID no longer running,
+//! and replaced. Each write goes to a temp file renamed over the old one, so a reader never sees
+//! half a file.
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+use sysinfo::{Pid, ProcessRefreshKind, ProcessesToUpdate, System};
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::protocol;
+use crate::state::{AppState, StatusEvent};
+
+/// Name of the status file in the agent's local data directory
+pub const FILE_NAME: &str = "status.json";
+
+/// Contents of the status file
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StatusFile {
+    /// Port the WebSocket server listens on
+    pub port: u16,
+    /// PID of the agent process
+    pub pid: u32,
+    pub version: String,
+    /// A simulation is running
+    pub busy: bool,
+    /// Unix time in ms the file was last written
+    #[serde(rename = "updatedAt")]
+    pub updated_at: u64,
+    /// Why the WebSocket server isn't listening, when it couldn't start
+    #[serde(rename = "serverError", default, skip_serializing_if = "Option::is_none")]
+    pub server_error: Option<String>,
+}
+
+/// Where the status file is kept: `%LOCALAPPDATA%\kelicad-agent\status.json` on Windows,
+/// `~/Library/Application Support/kelicad-agent/status.json` on macOS and
+/// `$XDG_DATA_HOME/kelicad-agent/status.json` (`~/.local/share` by default) on Linux
+pub fn default_path() -> Option<PathBuf> {
+    dirs::data_local_dir().map(|dir| dir.join("kelicad-agent").join(FILE_NAME))
+}
+
+/// The status file at `path`, None if it is missing or unreadable
+pub fn read(path: &Path) -> Option<StatusFile> {
+    let content = std::fs::read(path).ok()?;
+    serde_json::from_slice(&content).ok()
+}
+
+/// Replace the status file at `path` with `status`, through a temp file next to it
+pub fn write(path: &Path, status: &StatusFile) -> std::io::Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let staged = path.with_extension(format!("json.{}.tmp", std::process::id()));
+    std::fs::write(&staged, serde_json::to_vec_pretty(status)?)?;
+    std::fs::rename(&staged, path).inspect_err(|_| {
+        let _ = std::fs::remove_file(&staged);
+    })
+}
+
+/// Whether this agent may keep the status file at `path`: true unless it was written by another
+/// agent that is still running, in which case that file is left alone
+pub fn claim(path: &Path) -> bool {
+    claim_with(path, std::process::id(), process_alive)
+}
+
+fn claim_with(path: &Path, own_pid: u32, alive: impl Fn(u32) -> bool) -> bool {
+    match read(path) {
+        Some(existing) if existing.pid != own_pid && alive(existing.pid) => {
+            log::warn!("Status file {:?} belongs to running process {}; not writing it", path, existing.pid);
+            false
+        }
+        Some(existing) => {
+            log::info!("Replacing the status file left by process {}", existing.pid);
+            true
+        }
+        None => true,
+    }
+}
+
+/// Whether a process with `pid` is running
+fn process_alive(pid: u32) -> bool {
+    let pid = Pid::from_u32(pid);
+    let mut system = System::new();
+    system.refresh_processes_specifics(ProcessesToUpdate::Some(&[pid]), true, ProcessRefreshKind::nothing());
+    system.process(pid).is_some()
+}
+
+/// Remove the status file at `path`, unless another agent has since written it
+pub fn remove(path: &Path) {
+    if read(path).is_some_and(|status| status.pid != std::process::id()) {
+        return;
+    }
+    match std::fs::remove_file(path) {
+        Ok(()) => log::info!("Removed status file {:?}", path),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => log::warn!("Failed to remove status file {:?}: {}", path, e),
+    }
+}
+
+/// The status file's contents for the agent as it is now
+pub async fn snapshot(state: &AppState) -> StatusFile {
+    StatusFile {
+        port: protocol::WS_PORT,
+        pid: std::process::id(),
+        version: protocol::AGENT_VERSION.to_string(),
+        busy: !state.active_simulations.read().await.is_empty(),
+        updated_at: protocol::now_ms(),
+        server_error: state.server_error.read().await.clone(),
+    }
+}
+
+/// Write the status file at `path` now and again on every status change, until shutdown
+pub async fn run(state: Arc<AppState>, path: PathBuf, mut events: broadcast::Receiver<StatusEvent>) {
+    loop {
+        let status = snapshot(&state).await;
+        if state.is_shutting_down() {
+            return;
+        }
+        if let Err(e) = write(&path, &status) {
+            log::warn!("Failed to write status file {:?}: {}", path, e);
+        }
+
+        // Progress updates don't change anything in the file
+        loop {
+            let event = tokio::select! {
+                event = events.recv() => event,
+                _ = state.wait_for_shutdown() => return,
+            };
+            match event {
+                Ok(StatusEvent::Changed | StatusEvent::CapabilitiesChanged) | Err(RecvError::Lagged(_)) => break,
+                Ok(_) => continue,
+                Err(RecvError::Closed) => return,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tempfile::TempDir;
+
+    fn status(pid: u32) -> StatusFile {
+        StatusFile {
+            port: protocol::WS_PORT,
+            pid,
+            version: "1.0.0".to_string(),
+            busy: false,
+            updated_at: 1_700_000_000_000,
+            server_error: None,
+        }
+    }
+
+    #[test]
+    fn test_write_and_read_back() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("kelicad-agent").join(FILE_NAME);
+        write(&path, &status(42)).unwrap();
+        assert_eq!(read(&path), Some(status(42)));
+
+        let busy = StatusFile { busy: true, ..status(42) };
+        write(&path, &busy).unwrap();
+        assert_eq!(read(&path), Some(busy));
+
+        // Only the file itself is left; the temp file was renamed over it
+        let names: Vec<_> = std::fs::read_dir(path.parent().unwrap()).unwrap().map(|entry| entry.unwrap().file_name()).collect();
+        assert_eq!(names, [FILE_NAME]);
+    }
+
+    #[test]
+    fn test_serialized_fields() {
+        let json = serde_json::to_value(status(42)).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "port": protocol::WS_PORT,
+                "pid": 42,
+                "version": "1.0.0",
+                "busy": false,
+                "updatedAt": 1_700_000_000_000u64,
+            })
+        );
+    }
+
+    #[test]
+    fn test_claim_replaces_stale_files_only() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join(FILE_NAME);
+        assert!(claim_with(&path, 7, |_| true));
+
+        write(&path, &status(42)).unwrap();
+        assert!(claim_with(&path, 7, |_| false));
+        assert!(!claim_with(&path, 7, |pid| pid == 42));
+        assert!(claim_with(&path, 42, |_| true));
+
+        std::fs::write(&path, "not json").unwrap();
+        assert!(claim_with(&path, 7, |_| true));
+    }
+
+    #[test]
+    fn test_process_alive() {
+        assert!(process_alive(std::process::id()));
+    }
+
+    #[test]
+    fn test_remove_leaves_another_agents_file() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join(FILE_NAME);
+        write(&path, &status(std::process::id().wrapping_add(1))).unwrap();
+        remove(&path);
+        assert!(path.exists());
+
+        write(&path, &status(std::process::id())).unwrap();
+        remove(&path);
+        assert!(!path.exists());
+        remove(&path);
+    }
+
+    #[tokio::test]
+    async fn test_run_follows_status_changes_until_shutdown() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join(FILE_NAME);
+        let state = Arc::new(AppState::default());
+        let task = tokio::spawn(run(state.clone(), path.clone(), state.subscribe_status()));
+
+        let wait_for = |busy: bool| {
+            let path = path.clone();
+            async move {
+                while read(&path).is_none_or(|status| status.busy != busy) {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), wait_for(false)).await.unwrap();
+        let written = read(&path).unwrap();
+        assert_eq!(written.pid, std::process::id());
+        assert_eq!(written.version, protocol::AGENT_VERSION);
+
+        let slot = state.begin_simulation("sim-1", None).await.unwrap();
+        tokio::time::timeout(Duration::from_secs(5), wait_for(true)).await.unwrap();
+        state.end_simulation(slot).await;
+        tokio::time::timeout(Duration::from_secs(5), wait_for(false)).await.unwrap();
+
+        state.begin_shutdown();
+        tokio::time::timeout(Duration::from_secs(5), task).await.unwrap().unwrap();
+    }
+}
@@ -0,0 +1,194 @@
+// Copyright (c) 2024-2025 Wanyeki Technologies LLC. All rights reserved.
+// This source code is licensed under the proprietary license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Where simulations create their temp directories
+//!
+//! The system temp dir by default. Locked-down machines sometimes point it at a small RAM disk
+//! or a folder the user can't write, so the `temp_dir_override` setting can name another. An
+//! override is checked before it is used; one that doesn't exist, can't be written or is short
+//! of space is ignored in favour of the system temp dir, with the reason shown in the status.
+//!
+//! On Windows a system temp dir on a network share, as redirected profiles have, or too deep for
+//! the simulators' path limit is swapped for a local folder under the public profile.
+
+use std::path::{Path, PathBuf};
+
+use tempfile::Builder;
+
+use crate::long_path;
+use crate::simulator;
+
+/// Folder under the public profile used when the Windows temp dir can't be
+const LOCAL_FALLBACK_NAME: &str = "kelicad-temp";
+
+/// The directory simulations make their temp directories in
+#[derive(Debug, Clone, PartialEq)]
+pub struct TempRoot {
+    pub path: PathBuf,
+    /// Why the configured override isn't in use, when there is one and it was rejected
+    pub override_error: Option<String>,
+}
+
+impl Default for TempRoot {
+    fn default() -> Self {
+        Self { path: system_temp_dir(), override_error: None }
+    }
+}
+
+impl TempRoot {
+    /// The override when it passes `check`, otherwise the system temp dir
+    pub fn resolve(override_dir: Option<&str>, min_free_bytes: u64) -> Self {
+        let Some(dir) = override_dir.map(str::trim).filter(|dir| !dir.is_empty()) else {
+            return Self::default();
+        };
+        match check(Path::new(dir), min_free_bytes) {
+            Ok(()) => {
+                log::info!("Simulations will use temp directory {}", dir);
+                Self { path: PathBuf::from(dir), override_error: None }
+            }
+            Err(e) => {
+                log::warn!("Ignoring temp_dir_override: {}; using the system temp directory", e);
+                Self { override_error: Some(e), ..Self::default() }
+            }
+        }
+    }
+}
+
+/// The system temp dir, or on Windows the local fallback when it can't be used
+fn system_temp_dir() -> PathBuf {
+    local_temp_dir(std::env::temp_dir(), cfg!(windows).then(local_fallback_dir))
+}
+
+/// `C:\Users\Public\kelicad-temp`, or wherever the public profile is
+fn local_fallback_dir() -> PathBuf {
+    let public = std::env::var_os("PUBLIC").map(PathBuf::from).unwrap_or_else(|| PathBuf::from(r"C:\Users\Public"));
+    public.join(LOCAL_FALLBACK_NAME)
+}
+
+/// `system`, unless it is on a share or too long and `fallback` can be set up instead
+fn local_temp_dir(system: PathBuf, fallback: Option<PathBuf>) -> PathBuf {
+    let Some(fallback) = fallback else {
+        return system;
+    };
+    let Some(reason) = long_path::unsuitable_temp_root(&system.to_string_lossy()) else {
+        return system;
+    };
+    let usable = std::fs::create_dir_all(&fallback).map_err(|e| e.to_string()).and_then(|()| check(&fallback, 0));
+    match usable {
+        Ok(()) => {
+            log::warn!("System temp directory {}; simulations will use {}", reason, fallback.display());
+            fallback
+        }
+        Err(e) => {
+            log::warn!("System temp directory {}, and {} can't be used instead: {}", reason, fallback.display(), e);
+            system
+        }
+    }
+}
+
+/// Check `dir` can hold simulation temp directories: it exists, a file can be created in it,
+/// and it has at least `min_free_bytes` free
+/// On Windows it must also be local and short enough for the simulators
+pub fn check(dir: &Path, min_free_bytes: u64) -> Result<(), String> {
+    if !dir.is_absolute() {
+        return Err(format!("Temp directory {} is not an absolute path", dir.display()));
+    }
+    if cfg!(windows) {
+        if let Some(reason) = long_path::unsuitable_temp_root(&dir.to_string_lossy()) {
+            return Err(format!("Temp directory {}", reason));
+        }
+    }
+    if !dir.is_dir() {
+        return Err(format!("Temp directory {} does not exist", dir.display()));
+    }
+    Builder::new()
+        .prefix("kelicad-check-")
+        .tempfile_in(dir)
+        .map_err(|e| format!("Temp directory {} is not writable: {}", dir.display(), e))?;
+    match simulator::available_disk_space(dir) {
+        Some(free_bytes) if free_bytes < min_free_bytes => Err(format!(
+            "Temp directory {} has {} MB free, {} MB required",
+            dir.display(),
+            free_bytes / (1024 * 1024),
+            min_free_bytes.div_ceil(1024 * 1024)
+        )),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_valid_override_is_used() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().to_string_lossy().to_string();
+        let root = TempRoot::resolve(Some(&path), 0);
+        assert_eq!(root, TempRoot { path: dir.path().to_path_buf(), override_error: None });
+    }
+
+    #[test]
+    fn test_invalid_override_falls_back_to_the_system_default() {
+        let dir = tempfile::tempdir().unwrap();
+        let system = std::env::temp_dir();
+
+        let missing = dir.path().join("missing").to_string_lossy().to_string();
+        let root = TempRoot::resolve(Some(&missing), 0);
+        assert_eq!(root.path, system);
+        assert!(root.override_error.unwrap().ends_with("does not exist"));
+
+        let root = TempRoot::resolve(Some("relative/tmp"), 0);
+        assert_eq!(root.path, system);
+        assert!(root.override_error.unwrap().contains("not an absolute path"));
+
+        // No disk has this much room
+        if simulator::available_disk_space(dir.path()).is_some() {
+            let root = TempRoot::resolve(Some(&dir.path().to_string_lossy()), u64::MAX);
+            assert_eq!(root.path, system);
+            assert!(root.override_error.unwrap().contains("MB required"));
+        }
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let locked = dir.path().join("locked");
+            std::fs::create_dir(&locked).unwrap();
+            std::fs::set_permissions(&locked, std::fs::Permissions::from_mode(0o500)).unwrap();
+            // Root can write anywhere, so there is nothing to check when the tests run as root
+            if Builder::new().tempfile_in(&locked).is_err() {
+                let root = TempRoot::resolve(Some(&locked.to_string_lossy()), 0);
+                assert_eq!(root.path, system);
+                assert!(root.override_error.unwrap().contains("is not writable"));
+            }
+        }
+    }
+
+    #[test]
+    fn test_share_or_deep_system_temp_dir_falls_back_to_a_local_one() {
+        let dir = tempfile::tempdir().unwrap();
+        let fallback = dir.path().join(LOCAL_FALLBACK_NAME);
+        let share = PathBuf::from(r"\\fileserver\profiles\bob\AppData\Local\Temp");
+        assert_eq!(local_temp_dir(share.clone(), Some(fallback.clone())), fallback);
+        assert!(fallback.is_dir());
+
+        let deep = dir.path().join("nested".repeat(30));
+        assert_eq!(local_temp_dir(deep, Some(fallback.clone())), fallback);
+
+        // A local, short temp dir is kept, as is any temp dir when there's no fallback
+        assert_eq!(local_temp_dir(dir.path().to_path_buf(), Some(fallback.clone())), dir.path());
+        assert_eq!(local_temp_dir(share.clone(), None), share);
+
+        // So is an unusable one when the fallback can't be created either
+        let blocked = dir.path().join("file");
+        std::fs::write(&blocked, "").unwrap();
+        assert_eq!(local_temp_dir(share.clone(), Some(blocked.join(LOCAL_FALLBACK_NAME))), share);
+    }
+
+    #[test]
+    fn test_no_override_uses_the_system_default() {
+        assert_eq!(TempRoot::resolve(None, u64::MAX), TempRoot::default());
+        assert_eq!(TempRoot::resolve(Some("  "), u64::MAX), TempRoot::default());
+    }
+}
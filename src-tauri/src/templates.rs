@@ -0,0 +1,431 @@
+// Copyright (c) 2024-2025 Wanyeki Technologies LLC. All rights reserved.
+// This source code is licensed under the proprietary license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Netlist templates for `simulate_template`: a netlist registered once with `{{name}}`
+//! placeholders, then run many times with different values
+//!
+//! Templates are kept per origin, so a page can neither run nor list another's, and expire an
+//! hour after they were last registered or run. Values are single tokens such as `4.7k` or
+//! `2N3904`: they can't start a line, end one or open a comment, so a substitution can't add a
+//! directive or element the template doesn't have.
+
+use std::collections::BTreeMap;
+
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::protocol::{now_ms, SimulationRequest, TemplateInfo};
+
+/// Most templates an origin can have registered at once
+pub const MAX_TEMPLATES_PER_ORIGIN: usize = 32;
+/// Largest template netlist
+pub const MAX_TEMPLATE_BYTES: usize = 4 * 1024 * 1024;
+/// How long a template is kept after it was last registered or run, in ms
+pub const TEMPLATE_TTL_MS: u64 = 60 * 60 * 1000;
+/// Longest template name and placeholder value
+pub const MAX_TOKEN_CHARS: usize = 64;
+
+/// Why a template couldn't be registered, found or filled in
+#[derive(Debug, Clone, PartialEq)]
+pub struct TemplateError {
+    pub code: &'static str,
+    pub message: String,
+}
+
+impl TemplateError {
+    fn new(code: &'static str, message: String) -> Self {
+        Self { code, message }
+    }
+}
+
+impl std::fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for TemplateError {}
+
+/// Why `name` can't name a template, if it can't
+pub fn check_name(name: &str) -> Option<String> {
+    let valid = !name.is_empty()
+        && name.chars().count() <= MAX_TOKEN_CHARS
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'));
+    (!valid).then(|| format!("Template name \"{}\" is not 1 to {} letters, digits, _, - or .", name, MAX_TOKEN_CHARS))
+}
+
+/// Why `value` can't fill in placeholder `name`, if it can't
+/// A value is one token of letters, digits, `_`, `.`, `+` and `-` that starts with a letter,
+/// digit or minus sign; a leading `.` or `+` would make a directive or continuation line of a
+/// placeholder at the start of a line
+pub fn check_value(name: &str, value: &str) -> Option<String> {
+    let token = |c: char| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '+' | '-');
+    let valid = value.chars().count() <= MAX_TOKEN_CHARS
+        && value.chars().next().is_some_and(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-'))
+        && value.chars().all(token);
+    (!valid).then(|| {
+        format!(
+            "Value {:?} for {} is not a single token of up to {} letters, digits, _, ., + or -",
+            value, name, MAX_TOKEN_CHARS
+        )
+    })
+}
+
+/// Whether `name` can name a placeholder
+fn is_placeholder_name(name: &str) -> bool {
+    name.chars().next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// A template's text up to its first placeholder, then each placeholder's name and the text
+/// after it
+type Parts<'a> = (&'a str, Vec<(&'a str, &'a str)>);
+
+/// Split a template's text at its placeholders
+fn split(netlist: &str) -> Result<Parts<'_>, TemplateError> {
+    let mut parts = netlist.split("{{");
+    let head = parts.next().unwrap_or("");
+    let mut placeholders = Vec::new();
+    for (index, part) in parts.enumerate() {
+        let name = part.split_once("}}").map(|(name, _)| name).filter(|name| is_placeholder_name(name));
+        let Some(name) = name else {
+            let line = netlist.split("{{").take(index + 1).map(|text| text.matches('\n').count()).sum::<usize>() + 1;
+            return Err(TemplateError::new(
+                "TEMPLATE_INVALID",
+                format!("Line {}: {{{{ does not open a {{{{name}}}} placeholder", line),
+            ));
+        };
+        placeholders.push((name, &part[name.len() + 2..]));
+    }
+    Ok((head, placeholders))
+}
+
+/// Names of the placeholders in `netlist`, in order of first use
+pub fn placeholders(netlist: &str) -> Result<Vec<String>, TemplateError> {
+    let (_, parts) = split(netlist)?;
+    let mut names: Vec<String> = Vec::new();
+    for (name, _) in parts {
+        if !names.iter().any(|known| known == name) {
+            names.push(name.to_string());
+        }
+    }
+    Ok(names)
+}
+
+/// `netlist` with each `{{name}}` replaced by its value
+/// Every placeholder needs a value, every value a placeholder, and each value must pass `check_value`
+pub fn substitute(netlist: &str, values: &BTreeMap<String, String>) -> Result<String, TemplateError> {
+    let invalid = |message: String| TemplateError::new("TEMPLATE_VALUES_INVALID", message);
+    if let Some(message) = values.iter().find_map(|(name, value)| check_value(name, value)) {
+        return Err(invalid(message));
+    }
+    let names = placeholders(netlist)?;
+    let unbound: Vec<&str> = names.iter().filter(|name| !values.contains_key(*name)).map(String::as_str).collect();
+    if !unbound.is_empty() {
+        return Err(invalid(format!("No value for {}", unbound.join(", "))));
+    }
+    let unused: Vec<&str> = values.keys().filter(|name| !names.contains(name)).map(String::as_str).collect();
+    if !unused.is_empty() {
+        return Err(invalid(format!("The template has no placeholder {}", unused.join(", "))));
+    }
+
+    let (head, parts) = split(netlist)?;
+    let mut filled = head.to_string();
+    for (name, rest) in parts {
+        filled.push_str(&values[name]);
+        filled.push_str(rest);
+    }
+    Ok(filled)
+}
+
+/// The simulate request a `simulate_template` message stands for, running `netlist`
+pub fn simulate_request(message: &Value, netlist: String) -> Result<SimulationRequest, serde_json::Error> {
+    let mut message = message.clone();
+    if let Some(fields) = message.as_object_mut() {
+        fields.remove("template");
+        fields.remove("values");
+        fields.insert("type".to_string(), Value::from("simulate"));
+        fields.insert("netlist".to_string(), Value::from(netlist));
+    }
+    SimulationRequest::deserialize(&message)
+}
+
+struct Entry {
+    origin: String,
+    name: String,
+    netlist: String,
+    placeholders: Vec<String>,
+    used_at: u64,
+}
+
+impl Entry {
+    fn info(&self) -> TemplateInfo {
+        TemplateInfo {
+            name: self.name.clone(),
+            placeholders: self.placeholders.clone(),
+            bytes: self.netlist.len(),
+            expires_at: self.used_at + TEMPLATE_TTL_MS,
+        }
+    }
+}
+
+/// Registered templates of every origin
+#[derive(Default)]
+pub struct TemplateStore {
+    entries: Vec<Entry>,
+}
+
+impl TemplateStore {
+    /// Register `netlist` as `name` for `origin`, replacing a template of the same name
+    pub fn register(&mut self, origin: &str, name: &str, netlist: &str) -> Result<TemplateInfo, TemplateError> {
+        self.register_at(origin, name, netlist, now_ms())
+    }
+
+    fn register_at(&mut self, origin: &str, name: &str, netlist: &str, now: u64) -> Result<TemplateInfo, TemplateError> {
+        self.expire(now);
+        if let Some(message) = check_name(name) {
+            return Err(TemplateError::new("TEMPLATE_INVALID", message));
+        }
+        if netlist.len() > MAX_TEMPLATE_BYTES {
+            return Err(TemplateError::new(
+                "TEMPLATE_TOO_LARGE",
+                format!(
+                    "The template is {} KB, more than the {} MB a template may be",
+                    netlist.len() / 1024,
+                    MAX_TEMPLATE_BYTES / (1024 * 1024)
+                ),
+            ));
+        }
+        let placeholders = placeholders(netlist)?;
+        self.delete(origin, name);
+        if self.entries.iter().filter(|entry| entry.origin == origin).count() >= MAX_TEMPLATES_PER_ORIGIN {
+            return Err(TemplateError::new(
+                "TOO_MANY_TEMPLATES",
+                format!("{} templates are already registered; delete one first", MAX_TEMPLATES_PER_ORIGIN),
+            ));
+        }
+        let entry = Entry {
+            origin: origin.to_string(),
+            name: name.to_string(),
+            netlist: netlist.to_string(),
+            placeholders,
+            used_at: now,
+        };
+        let info = entry.info();
+        self.entries.push(entry);
+        Ok(info)
+    }
+
+    /// The netlist of `origin`'s template `name` with `values` filled in, keeping the template
+    /// for another TTL
+    pub fn fill(&mut self, origin: &str, name: &str, values: &BTreeMap<String, String>) -> Result<String, TemplateError> {
+        self.fill_at(origin, name, values, now_ms())
+    }
+
+    fn fill_at(&mut self, origin: &str, name: &str, values: &BTreeMap<String, String>, now: u64) -> Result<String, TemplateError> {
+        self.expire(now);
+        let entry = self
+            .entries
+            .iter_mut()
+            .find(|entry| entry.origin == origin && entry.name == name)
+            .ok_or_else(|| TemplateError::new("TEMPLATE_NOT_FOUND", format!("No template named {} is registered", name)))?;
+        entry.used_at = now;
+        substitute(&entry.netlist, values)
+    }
+
+    /// `origin`'s templates, in the order they were registered
+    pub fn list(&mut self, origin: &str) -> Vec<TemplateInfo> {
+        self.list_at(origin, now_ms())
+    }
+
+    fn list_at(&mut self, origin: &str, now: u64) -> Vec<TemplateInfo> {
+        self.expire(now);
+        self.entries.iter().filter(|entry| entry.origin == origin).map(Entry::info).collect()
+    }
+
+    /// Delete `origin`'s template `name`, returning whether there was one
+    pub fn delete(&mut self, origin: &str, name: &str) -> bool {
+        let count = self.entries.len();
+        self.entries.retain(|entry| !(entry.origin == origin && entry.name == name));
+        self.entries.len() < count
+    }
+
+    fn expire(&mut self, now: u64) {
+        self.entries.retain(|entry| now.saturating_sub(entry.used_at) < TEMPLATE_TTL_MS);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const TEMPLATE: &str = "* RC\nV1 in 0 {{VIN}}\nR1 in out {{R1}}\nC1 out 0 1u\n.tran {{T}}\n.end";
+
+    fn values(pairs: &[(&str, &str)]) -> BTreeMap<String, String> {
+        pairs.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect()
+    }
+
+    #[test]
+    fn test_placeholders() {
+        assert_eq!(placeholders(TEMPLATE).unwrap(), ["VIN", "R1", "T"]);
+        assert_eq!(placeholders("R1 a b {{r}}\nR2 b c {{r}}").unwrap(), ["r"]);
+        assert!(placeholders("* no placeholders\n.op\n.end").unwrap().is_empty());
+        // Single braces are LTspice expressions, left alone
+        assert!(placeholders("R1 a b {2*rval}\n.param rval=1k").unwrap().is_empty());
+
+        for malformed in ["R1 a b {{r", "R1 a b {{}}", "R1 a b {{r 1}}", "R1 a b {{1r}}", "R1 a b {{r-1}}", "R1 a b {{{r}}}"] {
+            let err = placeholders(malformed).unwrap_err();
+            assert_eq!(err.code, "TEMPLATE_INVALID", "{}", malformed);
+        }
+        assert_eq!(placeholders("* ok {{a}}\n\nR1 a b {{r").unwrap_err().message, "Line 3: {{ does not open a {{name}} placeholder");
+    }
+
+    #[test]
+    fn test_substitute() {
+        let filled = substitute(TEMPLATE, &values(&[("VIN", "5"), ("R1", "4.7k"), ("T", "1e-3")])).unwrap();
+        assert_eq!(filled, "* RC\nV1 in 0 5\nR1 in out 4.7k\nC1 out 0 1u\n.tran 1e-3\n.end");
+        let repeated = substitute("R1 a b {{r}}\nR2 b c {{r}}{{r}}", &values(&[("r", "-2")])).unwrap();
+        assert_eq!(repeated, "R1 a b -2\nR2 b c -2-2");
+        assert_eq!(substitute(".op\n.end", &BTreeMap::new()).unwrap(), ".op\n.end");
+    }
+
+    #[test]
+    fn test_every_placeholder_needs_exactly_one_value() {
+        let err = substitute(TEMPLATE, &values(&[("VIN", "5")])).unwrap_err();
+        assert_eq!(err.code, "TEMPLATE_VALUES_INVALID");
+        assert_eq!(err.message, "No value for R1, T");
+
+        let err = substitute(TEMPLATE, &values(&[("VIN", "5"), ("R1", "1k"), ("T", "1m"), ("C1", "1u")])).unwrap_err();
+        assert_eq!(err.message, "The template has no placeholder C1");
+        // Names are case sensitive
+        let err = substitute(TEMPLATE, &values(&[("vin", "5"), ("R1", "1k"), ("T", "1m")])).unwrap_err();
+        assert_eq!(err.message, "No value for VIN");
+    }
+
+    #[test]
+    fn test_values_cannot_inject_lines_or_directives() {
+        for value in ["1k", "4.7u", "-5", "1e+3", "2N3904", "_x", "10Meg", "0.5"] {
+            assert_eq!(check_value("v", value), None, "{}", value);
+        }
+        let attempts = [
+            "",
+            "1k\n.include /etc/passwd",
+            "1k\r.end",
+            "1k .include evil.lib",
+            "1k\t.end",
+            ".end",
+            ".include",
+            "+ R9 a b 1",
+            "*",
+            "1k;comment",
+            "1k$ ngspice comment",
+            "{1k*2}",
+            "{{R1}}",
+            "V(out)",
+            "\"quoted\"",
+            "1k\u{0}",
+            "1k\u{2028}.end",
+            "ｋ",
+            &"9".repeat(MAX_TOKEN_CHARS + 1),
+        ];
+        for value in attempts {
+            assert!(check_value("R1", value).is_some(), "{:?}", value);
+            let template = "* t\n{{R1}}\nR1 a 0 {{R1}}\n.op\n.end";
+            let err = substitute(template, &values(&[("R1", value)])).unwrap_err();
+            assert_eq!(err.code, "TEMPLATE_VALUES_INVALID", "{:?}", value);
+        }
+        assert_eq!(
+            check_value("R1", "1k .end").unwrap(),
+            "Value \"1k .end\" for R1 is not a single token of up to 64 letters, digits, _, ., + or -"
+        );
+    }
+
+    #[test]
+    fn test_check_name() {
+        assert_eq!(check_name("rc-filter_v1.2"), None);
+        for name in ["", "rc filter", "../rc", "rc\n", "rc/1", &"n".repeat(MAX_TOKEN_CHARS + 1)] {
+            assert!(check_name(name).is_some(), "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn test_simulate_request() {
+        let message = json!({
+            "id": "t-1",
+            "type": "simulate_template",
+            "timestamp": 0,
+            "template": "rc",
+            "values": {"R1": "1k"},
+            "simulator": "ngspice",
+            "timeout": 5000,
+        });
+        let request = simulate_request(&message, "* filled\n.op\n.end".to_string()).unwrap();
+        assert_eq!(request.id, "t-1");
+        assert_eq!(request.msg_type, "simulate");
+        assert_eq!(request.netlist, "* filled\n.op\n.end");
+        assert_eq!(request.simulator, "ngspice");
+        assert_eq!(request.timeout, Some(5000));
+    }
+
+    #[test]
+    fn test_templates_are_kept_per_origin() {
+        let mut store = TemplateStore::default();
+        let info = store.register_at("https://a.example", "rc", TEMPLATE, 1_000).unwrap();
+        assert_eq!(info.placeholders, ["VIN", "R1", "T"]);
+        assert_eq!(info.bytes, TEMPLATE.len());
+        assert_eq!(info.expires_at, 1_000 + TEMPLATE_TTL_MS);
+
+        let bound = values(&[("VIN", "1"), ("R1", "1k"), ("T", "1m")]);
+        assert!(store.fill_at("https://a.example", "rc", &bound, 2_000).is_ok());
+        let err = store.fill_at("https://b.example", "rc", &bound, 2_000).unwrap_err();
+        assert_eq!(err.code, "TEMPLATE_NOT_FOUND");
+        assert!(store.list_at("https://b.example", 2_000).is_empty());
+        assert!(!store.delete("https://b.example", "rc"));
+
+        // Registering the same name again replaces the template
+        store.register_at("https://a.example", "rc", "R1 a 0 {{R}}\n.op\n.end", 3_000).unwrap();
+        let listed = store.list_at("https://a.example", 3_000);
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].placeholders, ["R"]);
+
+        assert!(store.delete("https://a.example", "rc"));
+        assert!(store.list_at("https://a.example", 3_000).is_empty());
+    }
+
+    #[test]
+    fn test_templates_expire_unless_used() {
+        let mut store = TemplateStore::default();
+        store.register_at("o", "a", ".op\n.end", 0).unwrap();
+        store.register_at("o", "b", ".op\n.end", 0).unwrap();
+        // Running a template keeps it for another TTL
+        store.fill_at("o", "b", &BTreeMap::new(), TEMPLATE_TTL_MS - 1).unwrap();
+
+        let listed = store.list_at("o", TEMPLATE_TTL_MS);
+        assert_eq!(listed.iter().map(|info| info.name.as_str()).collect::<Vec<_>>(), ["b"]);
+        assert_eq!(listed[0].expires_at, 2 * TEMPLATE_TTL_MS - 1);
+        let err = store.fill_at("o", "a", &BTreeMap::new(), TEMPLATE_TTL_MS).unwrap_err();
+        assert_eq!(err.code, "TEMPLATE_NOT_FOUND");
+        assert!(store.list_at("o", 2 * TEMPLATE_TTL_MS).is_empty());
+    }
+
+    #[test]
+    fn test_registration_is_bounded() {
+        let mut store = TemplateStore::default();
+        for index in 0..MAX_TEMPLATES_PER_ORIGIN {
+            store.register_at("o", &format!("t{}", index), ".op\n.end", 0).unwrap();
+        }
+        let err = store.register_at("o", "one-more", ".op\n.end", 0).unwrap_err();
+        assert_eq!(err.code, "TOO_MANY_TEMPLATES");
+        // Replacing one still works, and other origins have their own allowance
+        assert!(store.register_at("o", "t0", ".tran 1m\n.end", 0).is_ok());
+        assert!(store.register_at("p", "t0", ".op\n.end", 0).is_ok());
+
+        let huge = format!("* big\n{}", "R1 a b 1k\n".repeat(MAX_TEMPLATE_BYTES / 10));
+        assert_eq!(store.register_at("p", "huge", &huge, 0).unwrap_err().code, "TEMPLATE_TOO_LARGE");
+        assert_eq!(store.register_at("p", "bad name", ".op", 0).unwrap_err().code, "TEMPLATE_INVALID");
+        assert_eq!(store.register_at("p", "broken", "R1 a b {{r", 0).unwrap_err().code, "TEMPLATE_INVALID");
+        assert_eq!(store.list_at("p", 0).len(), 1);
+    }
+}
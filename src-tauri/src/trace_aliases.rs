@@ -0,0 +1,211 @@
+// Copyright (c) 2024-2025 Wanyeki Technologies LLC. All rights reserved.
+// This source code is licensed under the proprietary license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Display names for traces, from a simulate request's `traceAliases`
+//!
+//! Aliases are keyed by the simulator's trace name, ignoring case, so `{"v(n001)": "Output"}`
+//! renames LTspice's `V(n001)`. They are applied after parsing: a renamed trace carries the alias
+//! in `name` and the simulator's name in `rawName`, and `get_trace_data`, derived expressions and
+//! the trace catalog accept either. An alias that would make two traces answer to the same name
+//! is not applied and the trace keeps its own name, with a warning.
+
+use std::collections::{HashMap, HashSet};
+
+use crate::protocol::SimulationResults;
+
+/// Lookup key for a trace name or alias
+fn key(name: &str) -> String {
+    name.trim().to_lowercase()
+}
+
+/// Aliases by lowercased raw name; of keys that differ only in case, the first in sorted order
+/// is used, with a warning
+fn lookup(aliases: &HashMap<String, String>, warnings: &mut Vec<String>) -> HashMap<String, String> {
+    let mut entries: Vec<(&String, &String)> = aliases.iter().filter(|(_, alias)| !alias.trim().is_empty()).collect();
+    entries.sort();
+    let mut lookup: HashMap<String, String> = HashMap::new();
+    for (raw, alias) in entries {
+        let alias = alias.trim();
+        match lookup.get(&key(raw)) {
+            Some(used) if used != alias => warnings.push(format!(
+                "traceAliases names {} more than once, ignoring case; it is shown as {}, not {}",
+                raw.trim(),
+                used,
+                alias
+            )),
+            Some(_) => {}
+            None => {
+                lookup.insert(key(raw), alias.to_string());
+            }
+        }
+    }
+    lookup
+}
+
+/// Rename the traces of `results` that `aliases` names, returning a warning for each alias that
+/// wasn't applied
+pub fn apply(results: &mut SimulationResults, aliases: &HashMap<String, String>) -> Vec<String> {
+    let mut warnings = Vec::new();
+    if aliases.is_empty() {
+        return warnings;
+    }
+    let lookup = lookup(aliases, &mut warnings);
+    let raw_names: Vec<String> = results.traces.iter().map(|trace| key(&trace.name)).collect();
+    let mut taken: HashSet<String> = HashSet::new();
+
+    for (index, trace) in results.traces.iter_mut().enumerate() {
+        let Some(alias) = lookup.get(&raw_names[index]) else {
+            continue;
+        };
+        if *alias == trace.name {
+            continue;
+        }
+        let alias_key = key(alias);
+        let shadows_raw = raw_names.iter().enumerate().any(|(other, raw)| other != index && *raw == alias_key);
+        if shadows_raw || !taken.insert(alias_key) {
+            warnings.push(format!(
+                "Trace {} kept its name: its alias {} is already the name of another trace",
+                trace.name, alias
+            ));
+            continue;
+        }
+        trace.raw_name = Some(std::mem::replace(&mut trace.name, alias.clone()));
+    }
+
+    let missing: Vec<&str> = lookup
+        .keys()
+        .filter(|raw| !raw_names.contains(raw))
+        .map(String::as_str)
+        .collect();
+    if !missing.is_empty() {
+        let mut missing = missing;
+        missing.sort_unstable();
+        warnings.push(format!("traceAliases names traces not in the results: {}", missing.join(", ")));
+    }
+    warnings
+}
+
+/// `names` with each alias in `aliases` replaced by the raw name it stands for, so signals to
+/// save can be given by their display names
+pub fn raw_names(names: &[String], aliases: &HashMap<String, String>) -> Vec<String> {
+    names
+        .iter()
+        .map(|name| {
+            aliases
+                .iter()
+                .filter(|(_, alias)| key(alias) == key(name))
+                .map(|(raw, _)| raw.trim())
+                .min()
+                .unwrap_or(name)
+                .to_string()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{DerivedTrace, Trace};
+    use crate::{derived, plot_hints, trace_store};
+
+    fn results(names: &[&str]) -> SimulationResults {
+        serde_json::from_value(serde_json::json!({
+            "time": [0.0, 1.0],
+            "traces": names
+                .iter()
+                .enumerate()
+                .map(|(i, name)| serde_json::json!({ "name": name, "data": [i as f64, i as f64 + 1.0], "unit": "V" }))
+                .collect::<Vec<_>>(),
+            "analysis_type": "tran",
+        }))
+        .unwrap()
+    }
+
+    fn aliases(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs.iter().map(|(raw, alias)| (raw.to_string(), alias.to_string())).collect()
+    }
+
+    fn names(results: &SimulationResults) -> Vec<(&str, Option<&str>)> {
+        results.traces.iter().map(|trace| (trace.name.as_str(), trace.raw_name.as_deref())).collect()
+    }
+
+    #[test]
+    fn test_aliases_match_raw_names_ignoring_case() {
+        let mut results = results(&["V(n001)", "V(OUT)", "I(R1)"]);
+        let warnings = apply(&mut results, &aliases(&[("v(N001)", "Input"), ("V(out)", " Output ")]));
+        assert!(warnings.is_empty(), "{:?}", warnings);
+        assert_eq!(names(&results), [("Input", Some("V(n001)")), ("Output", Some("V(OUT)")), ("I(R1)", None)]);
+
+        // An alias that only changes case still renames the trace
+        let mut results = self::results(&["V(out)"]);
+        assert!(apply(&mut results, &aliases(&[("V(OUT)", "v(OUT)")])).is_empty());
+        assert_eq!(names(&results), [("v(OUT)", Some("V(out)"))]);
+        assert!(apply(&mut results, &HashMap::new()).is_empty());
+    }
+
+    #[test]
+    fn test_keys_differing_only_in_case_use_the_first() {
+        let mut results = results(&["V(out)"]);
+        let warnings = apply(&mut results, &aliases(&[("v(out)", "Second"), ("V(OUT)", "First"), ("V(Out)", "First")]));
+        assert_eq!(names(&results), [("First", Some("V(out)"))]);
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].contains("shown as First, not Second"), "{}", warnings[0]);
+    }
+
+    #[test]
+    fn test_colliding_aliases_keep_the_raw_name() {
+        let mut results = results(&["V(a)", "V(b)", "V(c)"]);
+        let warnings = apply(
+            &mut results,
+            &aliases(&[("V(a)", "Out"), ("V(b)", "out"), ("V(c)", "v(A)"), ("V(missing)", "Gone")]),
+        );
+        // V(b)'s alias differs from V(a)'s only in case and V(c)'s is V(a)'s raw name
+        assert_eq!(names(&results), [("Out", Some("V(a)")), ("V(b)", None), ("V(c)", None)]);
+        assert_eq!(warnings.len(), 3, "{:?}", warnings);
+        assert!(warnings[0].starts_with("Trace V(b) kept its name"));
+        assert!(warnings[1].starts_with("Trace V(c) kept its name"));
+        assert_eq!(warnings[2], "traceAliases names traces not in the results: v(missing)");
+
+        // Two traces may swap names only if neither shadows the other's raw name
+        let mut results = self::results(&["V(a)", "V(b)"]);
+        let warnings = apply(&mut results, &aliases(&[("V(a)", "V(b)"), ("V(b)", "V(a)")]));
+        assert_eq!(names(&results), [("V(a)", None), ("V(b)", None)]);
+        assert_eq!(warnings.len(), 2);
+    }
+
+    #[test]
+    fn test_aliased_traces_answer_to_both_names() {
+        let mut results = results(&["V(n001)", "V(n002)"]);
+        apply(&mut results, &aliases(&[("V(N001)", "Vin"), ("v(n002)", "Vout")]));
+
+        let (_, selected) = trace_store::select(&results, &["vin".to_string(), "V(N002)".to_string()], None).unwrap();
+        assert_eq!(selected.iter().map(|trace| trace.name.as_str()).collect::<Vec<_>>(), ["Vin", "Vout"]);
+
+        let derived = |expression: &str| DerivedTrace { name: None, expression: expression.to_string() };
+        let gain = derived::derive_trace(&results, &derived("Vout - V(n001)")).unwrap();
+        assert_eq!(gain.data, [1.0, 1.0]);
+        assert_eq!(derived::derive_trace(&results, &derived("VOUT/2")).unwrap().data, [0.5, 1.0]);
+
+        let summary = trace_store::summarize(&results.traces[1]);
+        assert_eq!((summary.name.as_str(), summary.raw_name.as_deref()), ("Vout", Some("V(n002)")));
+        assert_eq!(summary.max, Some(2.0));
+
+        // Plot hints are keyed by the alias but still group by the simulator's name
+        let hints = plot_hints::compute(&results, "V1 n001 0 1\n.plot V(n002)\n");
+        assert_eq!(hints.default_selection, ["Vout"]);
+        assert!(hints.trace_groups.contains_key("Vin"));
+
+        let json = serde_json::to_value(&results.traces[0]).unwrap();
+        assert_eq!(json["rawName"], "V(n001)");
+        let plain = Trace { name: "V(x)".to_string(), data: Vec::new(), unit: "V".to_string(), phase: None, raw_name: None };
+        assert!(serde_json::to_value(&plain).unwrap().get("rawName").is_none());
+    }
+
+    #[test]
+    fn test_raw_names_resolve_aliases() {
+        let aliases = aliases(&[("V(n001)", "Vin")]);
+        let names = raw_names(&["VIN".to_string(), "V(out)".to_string()], &aliases);
+        assert_eq!(names, ["V(n001)", "V(out)"]);
+    }
+}
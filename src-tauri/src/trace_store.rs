@@ -0,0 +1,338 @@
+// Copyright (c) 2024-2025 Wanyeki Technologies LLC. All rights reserved.
+// This source code is licensed under the proprietary license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Results of simulations that set `deferTraceData`, kept so their traces can be fetched with
+//! `get_trace_data`
+//!
+//! Entries are keyed by the simulate request's id and kept for as long as finished responses are
+//! kept for duplicates, whether or not the client is still connected; a `cancel` after the run
+//! doesn't remove them either. The store is held to a memory budget, least recently used evicted
+//! first, and also counts against the resource budget's `max_result_bytes`.
+
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+use crate::dedup::RESULT_RETENTION_MS;
+use crate::protocol::{now_ms, SimulationResults, Trace, TraceSummary};
+use crate::resource_budget::{self, BudgetEntry, Budgeted};
+
+/// Most sample bytes kept across all deferred results
+pub const MAX_TRACE_STORE_BYTES: usize = 256 * 1024 * 1024;
+
+struct Entry {
+    request_id: String,
+    results: Arc<SimulationResults>,
+    bytes: usize,
+    stored_at: u64,
+    last_used: u64,
+}
+
+/// Deferred results, least recently used first
+pub struct TraceStore {
+    max_bytes: usize,
+    used_bytes: usize,
+    entries: VecDeque<Entry>,
+}
+
+impl Default for TraceStore {
+    fn default() -> Self {
+        Self::with_budget(MAX_TRACE_STORE_BYTES)
+    }
+}
+
+impl TraceStore {
+    pub fn with_budget(max_bytes: usize) -> Self {
+        Self { max_bytes, used_bytes: 0, entries: VecDeque::new() }
+    }
+
+    /// Keep the results of `request_id`, replacing earlier ones of the same id
+    /// Fails when they alone are over the budget
+    pub fn insert(&mut self, request_id: &str, results: Arc<SimulationResults>) -> Result<(), String> {
+        self.insert_at(request_id, results, now_ms())
+    }
+
+    fn insert_at(&mut self, request_id: &str, results: Arc<SimulationResults>, now: u64) -> Result<(), String> {
+        self.expire(now);
+        let bytes = results_bytes(&results);
+        if bytes > self.max_bytes {
+            return Err(format!(
+                "The results take {} MB, more than the {} MB kept for deferred trace data",
+                bytes / (1024 * 1024),
+                self.max_bytes / (1024 * 1024)
+            ));
+        }
+        self.remove(request_id);
+        while self.used_bytes + bytes > self.max_bytes {
+            let Some(evicted) = self.entries.pop_front() else { break };
+            log::info!("Evicted deferred trace data of simulation {} to stay within budget", evicted.request_id);
+            self.used_bytes -= evicted.bytes;
+        }
+        self.used_bytes += bytes;
+        self.entries.push_back(Entry {
+            request_id: request_id.to_string(),
+            results,
+            bytes,
+            stored_at: now,
+            last_used: resource_budget::next_use(),
+        });
+        Ok(())
+    }
+
+    /// Results of `request_id`, marking them most recently used
+    pub fn get(&mut self, request_id: &str) -> Option<Arc<SimulationResults>> {
+        self.get_at(request_id, now_ms())
+    }
+
+    fn get_at(&mut self, request_id: &str, now: u64) -> Option<Arc<SimulationResults>> {
+        self.expire(now);
+        let index = self.entries.iter().position(|entry| entry.request_id == request_id)?;
+        let mut entry = self.entries.remove(index)?;
+        entry.last_used = resource_budget::next_use();
+        let results = entry.results.clone();
+        self.entries.push_back(entry);
+        Some(results)
+    }
+
+    fn remove(&mut self, request_id: &str) {
+        if let Some(index) = self.entries.iter().position(|entry| entry.request_id == request_id) {
+            let entry = self.entries.remove(index).expect("index is in range");
+            self.used_bytes -= entry.bytes;
+        }
+    }
+
+    fn expire(&mut self, now: u64) {
+        let used_bytes = &mut self.used_bytes;
+        self.entries.retain(|entry| {
+            let keep = now.saturating_sub(entry.stored_at) < RESULT_RETENTION_MS;
+            if !keep {
+                *used_bytes -= entry.bytes;
+            }
+            keep
+        });
+    }
+}
+
+impl Budgeted for TraceStore {
+    fn budget_entries(&self) -> Vec<BudgetEntry> {
+        self.entries
+            .iter()
+            .map(|entry| BudgetEntry { key: entry.request_id.clone(), size: entry.bytes as u64, last_used: entry.last_used })
+            .collect()
+    }
+
+    fn evict(&mut self, key: &str) {
+        self.remove(key);
+    }
+}
+
+/// Approximate memory the samples of `results` take
+fn results_bytes(results: &SimulationResults) -> usize {
+    let samples = results.time.len()
+        + results
+            .traces
+            .iter()
+            .map(|trace| trace.data.len() + trace.phase.as_ref().map_or(0, Vec::len))
+            .sum::<usize>();
+    samples * std::mem::size_of::<f64>()
+}
+
+/// Catalog entry of `trace`
+pub fn summarize(trace: &Trace) -> TraceSummary {
+    let mut finite = trace.data.iter().copied().filter(|value| value.is_finite()).peekable();
+    let (min, max, mean) = if finite.peek().is_none() {
+        (None, None, None)
+    } else {
+        let (mut min, mut max, mut sum, mut count) = (f64::INFINITY, f64::NEG_INFINITY, 0.0, 0usize);
+        for value in finite {
+            min = min.min(value);
+            max = max.max(value);
+            sum += value;
+            count += 1;
+        }
+        (Some(min), Some(max), Some(sum / count as f64))
+    };
+    TraceSummary {
+        name: trace.name.clone(),
+        raw_name: trace.raw_name.clone(),
+        unit: trace.unit.clone(),
+        points: trace.data.len(),
+        min,
+        max,
+        mean,
+        has_phase: trace.phase.is_some(),
+    }
+}
+
+/// Keep the catalog of `results` and empty its x axis and data arrays
+pub fn defer(results: &mut SimulationResults) -> Vec<TraceSummary> {
+    results.time = Vec::new();
+    results
+        .traces
+        .iter_mut()
+        .map(|trace| {
+            let summary = summarize(trace);
+            trace.data = Vec::new();
+            trace.phase = None;
+            summary
+        })
+        .collect()
+}
+
+/// X axis values and the traces picked from a result
+pub type Selection = (Vec<f64>, Vec<Trace>);
+
+/// The x axis and the traces named in `names` from `results`, downsampled to at most
+/// `max_points` evenly spaced points, the first and last included
+pub fn select(
+    results: &SimulationResults,
+    names: &[String],
+    max_points: Option<usize>,
+) -> Result<Selection, (String, &'static str)> {
+    let mut traces = Vec::with_capacity(names.len());
+    for name in names {
+        let trace = results
+            .traces
+            .iter()
+            .find(|trace| trace.answers_to(name))
+            .ok_or_else(|| (format!("No trace named {}", name), "UNKNOWN_TRACE"))?;
+        traces.push(trace.clone());
+    }
+
+    let points = results.time.len();
+    let indices = match max_points {
+        Some(max_points) if points > max_points => sample_indices(points, max_points),
+        _ => return Ok((results.time.clone(), traces)),
+    };
+    let pick = |values: &[f64]| indices.iter().filter_map(|&i| values.get(i).copied()).collect::<Vec<_>>();
+    for trace in &mut traces {
+        trace.data = pick(&trace.data);
+        trace.phase = trace.phase.as_deref().map(pick);
+    }
+    Ok((pick(&results.time), traces))
+}
+
+/// `count` evenly spaced indices into `len` points, the first and last included
+fn sample_indices(len: usize, count: usize) -> Vec<usize> {
+    match count {
+        0 => Vec::new(),
+        1 => vec![len - 1],
+        _ => (0..count).map(|i| i * (len - 1) / (count - 1)).collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    fn results(points: usize) -> SimulationResults {
+        let time: Vec<f64> = (0..points).map(|i| i as f64).collect();
+        SimulationResults {
+            traces: vec![
+                Trace { name: "V(out)".to_string(), data: time.iter().map(|t| t * 2.0).collect(), unit: "V".to_string(), phase: None, raw_name: None },
+                Trace {
+                    name: "I(R1)".to_string(),
+                    data: time.iter().map(|t| -t).collect(),
+                    unit: "A".to_string(),
+                    phase: Some(vec![0.0; points]),
+                    raw_name: None,
+                },
+            ],
+            time,
+            analysis_type: "transient".to_string(),
+            x_axis_label: None,
+            x_axis_unit: None,
+            scalar_results: None,
+            device_operating_points: BTreeMap::new(),
+            truncated: None,
+            plot_hints: None,
+            raw_header: None,
+        }
+    }
+
+    #[test]
+    fn test_summarize_skips_non_finite_samples() {
+        let trace = Trace { name: "V(a)".to_string(), data: vec![1.0, f64::NAN, 3.0, f64::INFINITY], unit: "V".to_string(), phase: None, raw_name: None };
+        let summary = summarize(&trace);
+        assert_eq!(summary.points, 4);
+        assert_eq!((summary.min, summary.max, summary.mean), (Some(1.0), Some(3.0), Some(2.0)));
+        assert!(!summary.has_phase);
+
+        let empty = Trace { name: "V(b)".to_string(), data: vec![f64::NAN], unit: "V".to_string(), phase: None, raw_name: None };
+        assert_eq!(summarize(&empty).mean, None);
+    }
+
+    #[test]
+    fn test_defer_empties_data_and_keeps_catalog() {
+        let mut deferred = results(10);
+        let catalog = defer(&mut deferred);
+        assert!(deferred.time.is_empty());
+        assert!(deferred.traces.iter().all(|trace| trace.data.is_empty() && trace.phase.is_none()));
+        assert_eq!(catalog.len(), 2);
+        assert_eq!(catalog[1].name, "I(R1)");
+        assert_eq!(catalog[1].points, 10);
+        assert_eq!(catalog[1].min, Some(-9.0));
+        assert!(catalog[1].has_phase);
+    }
+
+    #[test]
+    fn test_select_and_downsample() {
+        let full = results(101);
+        let (time, traces) = select(&full, &["v(out)".to_string()], None).unwrap();
+        assert_eq!(time.len(), 101);
+        assert_eq!(traces.len(), 1);
+        assert_eq!(traces[0].name, "V(out)");
+
+        let (time, traces) = select(&full, &["I(R1)".to_string()], Some(5)).unwrap();
+        assert_eq!(time, vec![0.0, 25.0, 50.0, 75.0, 100.0]);
+        assert_eq!(traces[0].data, vec![0.0, -25.0, -50.0, -75.0, -100.0]);
+        assert_eq!(traces[0].phase.as_ref().unwrap().len(), 5);
+
+        let err = select(&full, &["V(missing)".to_string()], None).unwrap_err();
+        assert_eq!(err.1, "UNKNOWN_TRACE");
+    }
+
+    #[test]
+    fn test_store_expires_and_evicts_least_recently_used() {
+        // Each entry: 3 arrays of 10 points, plus 10 phase points
+        let entry_bytes = results_bytes(&results(10));
+        let mut store = TraceStore::with_budget(entry_bytes * 2);
+        store.insert_at("a", Arc::new(results(10)), 0).unwrap();
+        store.insert_at("b", Arc::new(results(10)), 1).unwrap();
+        assert!(store.get_at("a", 2).is_some());
+        store.insert_at("c", Arc::new(results(10)), 3).unwrap();
+        assert!(store.get_at("b", 4).is_none());
+        assert!(store.get_at("a", 4).is_some());
+        assert_eq!(store.used_bytes, entry_bytes * 2);
+
+        assert!(store.get_at("a", RESULT_RETENTION_MS).is_none());
+        assert!(store.get_at("c", RESULT_RETENTION_MS).is_some());
+        assert!(store.get_at("c", 3 + RESULT_RETENTION_MS).is_none());
+        assert_eq!(store.used_bytes, 0);
+    }
+
+    #[test]
+    fn test_budget_entries_follow_use() {
+        let entry_bytes = results_bytes(&results(10)) as u64;
+        let mut store = TraceStore::default();
+        store.insert_at("a", Arc::new(results(10)), 0).unwrap();
+        store.insert_at("b", Arc::new(results(10)), 1).unwrap();
+        store.get_at("a", 2).unwrap();
+        let entries = store.budget_entries();
+        assert_eq!(entries.iter().map(|entry| entry.key.as_str()).collect::<Vec<_>>(), ["b", "a"]);
+        assert!(entries.iter().all(|entry| entry.size == entry_bytes));
+        assert!(entries[0].last_used < entries[1].last_used);
+
+        Budgeted::evict(&mut store, "b");
+        assert!(store.get_at("b", 3).is_none());
+        assert_eq!(store.used_bytes as u64, entry_bytes);
+    }
+
+    #[test]
+    fn test_store_rejects_results_over_budget() {
+        let mut store = TraceStore::with_budget(64);
+        assert!(store.insert("big", Arc::new(results(100))).is_err());
+        assert_eq!(store.used_bytes, 0);
+    }
+}
@@ -0,0 +1,272 @@
+// Copyright (c) 2024-2025 Wanyeki Technologies LLC. All rights reserved.
+// This source code is licensed under the proprietary license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Tray status line, icon and settings toggles, kept in sync with the agent state
+
+use std::sync::Arc;
+use tauri::{
+    image::Image,
+    menu::{CheckMenuItem, MenuItem},
+    tray::TrayIcon,
+    AppHandle, Manager, Wry,
+};
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::autostart;
+use crate::settings::{self, AgentSettings};
+use crate::state::{AppState, StatusEvent};
+
+/// Badge color drawn on the tray icon while simulating (amber)
+const BADGE_COLOR: [u8; 4] = [0xF5, 0x9E, 0x0B, 0xFF];
+
+/// Tray menu items that mirror settings, managed as app state after setup
+pub struct TrayMenu {
+    pub notifications: CheckMenuItem<Wry>,
+    pub autostart: CheckMenuItem<Wry>,
+    pub show_on_launch: CheckMenuItem<Wry>,
+}
+
+impl TrayMenu {
+    /// Update the check marks after settings changed elsewhere
+    pub fn sync_settings(&self, settings: &AgentSettings) {
+        let _ = self.notifications.set_checked(settings.notifications_enabled);
+        let _ = self.autostart.set_checked(settings.autostart);
+        let _ = self.show_on_launch.set_checked(!settings.start_hidden);
+    }
+}
+
+/// Flip the notifications setting from the tray menu and persist it
+pub fn toggle_notifications(app: &AppHandle) {
+    let app = app.clone();
+    tauri::async_runtime::spawn(async move {
+        let state = app.state::<Arc<AppState>>().inner().clone();
+        let updated = {
+            let mut settings = state.settings.write().await;
+            settings.notifications_enabled = !settings.notifications_enabled;
+            settings.clone()
+        };
+        if let Err(e) = settings::save_settings(&updated) {
+            log::warn!("Failed to save settings: {}", e);
+        }
+        // The menu toggles its own check mark on click; make sure it matches the setting
+        if let Some(menu) = app.try_state::<TrayMenu>() {
+            menu.sync_settings(&updated);
+        }
+        log::info!("Notifications {}", if updated.notifications_enabled { "enabled" } else { "disabled" });
+    });
+}
+
+/// Flip whether the window is shown at launch from the tray menu and persist it
+pub fn toggle_show_on_launch(app: &AppHandle) {
+    let app = app.clone();
+    tauri::async_runtime::spawn(async move {
+        let state = app.state::<Arc<AppState>>().inner().clone();
+        let updated = {
+            let mut settings = state.settings.write().await;
+            settings.start_hidden = !settings.start_hidden;
+            settings.clone()
+        };
+        if let Err(e) = settings::save_settings(&updated) {
+            log::warn!("Failed to save settings: {}", e);
+        }
+        if let Some(menu) = app.try_state::<TrayMenu>() {
+            menu.sync_settings(&updated);
+        }
+        log::info!("Window {} at launch", if updated.start_hidden { "hidden" } else { "shown" });
+    });
+}
+
+/// Flip launch at login from the tray menu
+pub fn toggle_autostart(app: &AppHandle) {
+    let app = app.clone();
+    tauri::async_runtime::spawn(async move {
+        let state = app.state::<Arc<AppState>>().inner().clone();
+        let enable = !state.settings.read().await.autostart;
+        if let Err(e) = autostart::set_enabled(&app, enable).await {
+            log::warn!("{}", e);
+            // Put the check mark back to the unchanged setting
+            if let Some(menu) = app.try_state::<TrayMenu>() {
+                menu.sync_settings(&*state.settings.read().await);
+            }
+        }
+    });
+}
+
+/// Agent state shown in the tray
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TraySummary {
+    pub active_simulations: usize,
+    /// Progress of the most recently reporting simulation, if it is still running
+    pub percent: Option<f32>,
+    pub connections: u32,
+    pub ltspice_found: bool,
+    /// Why the WebSocket server is not running
+    pub server_error: Option<String>,
+}
+
+impl TraySummary {
+    async fn collect(state: &AppState, percent: Option<f32>) -> Self {
+        Self {
+            active_simulations: state.active_simulations.read().await.len(),
+            percent,
+            connections: *state.ws_connections.read().await,
+            ltspice_found: state.ltspice_path.read().await.is_some(),
+            server_error: state.server_error.read().await.clone(),
+        }
+    }
+
+    pub fn is_busy(&self) -> bool {
+        self.active_simulations > 0
+    }
+}
+
+/// Text of the disabled status menu item, most important condition first
+pub fn status_text(summary: &TraySummary) -> String {
+    if let Some(error) = &summary.server_error {
+        return error.clone();
+    }
+
+    match (summary.active_simulations, summary.percent) {
+        (0, _) => {}
+        (1, Some(percent)) => return format!("Simulating... {:.0}%", percent),
+        (1, None) => return "Simulating...".to_string(),
+        (n, _) => return format!("Running {} simulations...", n),
+    }
+
+    if !summary.ltspice_found {
+        return "LTspice not found".to_string();
+    }
+
+    match summary.connections {
+        0 => "Ready".to_string(),
+        1 => "1 client connected".to_string(),
+        n => format!("{} clients connected", n),
+    }
+}
+
+/// Update the tray status item and icon on every status event until the app exits
+pub async fn run_status_updates(
+    state: Arc<AppState>,
+    status_item: MenuItem<Wry>,
+    tray: TrayIcon<Wry>,
+    mut events: broadcast::Receiver<StatusEvent>,
+) {
+    let idle_icon = tray.app_handle().default_window_icon().map(|icon| icon.clone().to_owned());
+    let busy_icon = idle_icon.as_ref().map(badged_icon);
+
+    let mut progress: Option<(String, f32)> = None;
+    let mut shown_text = String::new();
+    let mut shown_busy = false;
+
+    loop {
+        match events.recv().await {
+            Ok(StatusEvent::Progress { request_id, percent }) => progress = Some((request_id, percent)),
+            // Ending the simulation or detecting simulators already published a change
+            Ok(StatusEvent::SimulationFinished { .. }) | Ok(StatusEvent::CapabilitiesChanged) => continue,
+            // A lagged receiver only missed intermediate states; the refresh below catches up
+            Ok(StatusEvent::Changed) | Err(RecvError::Lagged(_)) => {}
+            Err(RecvError::Closed) => break,
+        }
+
+        if let Some((request_id, _)) = &progress {
+            if state.active_simulation(request_id).await.is_none() {
+                progress = None;
+            }
+        }
+
+        let summary = TraySummary::collect(&state, progress.as_ref().map(|(_, percent)| *percent)).await;
+        let text = status_text(&summary);
+        if text != shown_text {
+            if let Err(e) = status_item.set_text(&text) {
+                log::warn!("Failed to update tray status: {}", e);
+            }
+            shown_text = text;
+        }
+
+        if summary.is_busy() != shown_busy {
+            shown_busy = summary.is_busy();
+            let icon = if shown_busy { &busy_icon } else { &idle_icon };
+            if let Some(icon) = icon {
+                if let Err(e) = tray.set_icon(Some(icon.clone())) {
+                    log::warn!("Failed to update tray icon: {}", e);
+                }
+            }
+        }
+    }
+}
+
+/// Copy of the icon with a badge in the bottom-right corner
+fn badged_icon(icon: &Image<'static>) -> Image<'static> {
+    let mut rgba = icon.rgba().to_vec();
+    draw_badge(&mut rgba, icon.width(), icon.height());
+    Image::new_owned(rgba, icon.width(), icon.height())
+}
+
+/// Fill a circle a quarter of the icon size across in the bottom-right corner
+fn draw_badge(rgba: &mut [u8], width: u32, height: u32) {
+    let radius = (width.min(height) / 4) as i64;
+    let (cx, cy) = (width as i64 - radius - 1, height as i64 - radius - 1);
+    for y in (cy - radius).max(0)..=cy + radius {
+        for x in (cx - radius).max(0)..=cx + radius {
+            if (x - cx).pow(2) + (y - cy).pow(2) > radius * radius {
+                continue;
+            }
+            let offset = ((y * width as i64 + x) * 4) as usize;
+            if let Some(pixel) = rgba.get_mut(offset..offset + 4) {
+                pixel.copy_from_slice(&BADGE_COLOR);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn summary() -> TraySummary {
+        TraySummary {
+            ltspice_found: true,
+            ..TraySummary::default()
+        }
+    }
+
+    #[test]
+    fn test_status_text_idle() {
+        assert_eq!(status_text(&summary()), "Ready");
+        assert_eq!(status_text(&TraySummary { connections: 1, ..summary() }), "1 client connected");
+        assert_eq!(status_text(&TraySummary { connections: 2, ..summary() }), "2 clients connected");
+        assert_eq!(
+            status_text(&TraySummary { connections: 2, ltspice_found: false, ..summary() }),
+            "LTspice not found"
+        );
+        let server_error = "Another KeliCAD agent is already running on port 9347".to_string();
+        assert_eq!(
+            status_text(&TraySummary { server_error: Some(server_error.clone()), ..summary() }),
+            server_error
+        );
+    }
+
+    #[test]
+    fn test_status_text_simulating() {
+        let simulating = TraySummary { active_simulations: 1, connections: 1, ..summary() };
+        assert_eq!(status_text(&simulating), "Simulating...");
+        assert_eq!(status_text(&TraySummary { percent: Some(42.4), ..simulating.clone() }), "Simulating... 42%");
+        assert_eq!(
+            status_text(&TraySummary { active_simulations: 3, ..simulating }),
+            "Running 3 simulations..."
+        );
+    }
+
+    #[test]
+    fn test_draw_badge() {
+        let (width, height) = (16u32, 16u32);
+        let mut rgba = vec![0u8; (width * height * 4) as usize];
+        draw_badge(&mut rgba, width, height);
+
+        let pixel = |x: u32, y: u32| &rgba[((y * width + x) * 4) as usize..][..4];
+        // Badge center is colored, the opposite corner is untouched
+        assert_eq!(pixel(11, 11), BADGE_COLOR);
+        assert_eq!(pixel(0, 0), [0, 0, 0, 0]);
+    }
+}
@@ -0,0 +1,665 @@
+// Copyright (c) 2024-2025 Wanyeki Technologies LLC. All rights reserved.
+// This source code is licensed under the proprietary license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Checks incoming WebSocket messages against the schema of their `type` before they are handled
+//!
+//! Clients get an `error` message naming the field and what it should hold, instead of a serde
+//! error or a dropped connection. Unknown fields are rejected so typos like `waveformQualtiy`
+//! don't silently fall back to defaults.
+
+use std::sync::OnceLock;
+
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::precision::MAX_PRECISION;
+use crate::protocol::*;
+use crate::settings::AgentSettings;
+use crate::templates;
+use crate::wave;
+use Kind::*;
+use Presence::*;
+
+/// Shortest `timeout` a request may ask for, in ms
+pub const MIN_TIMEOUT_MS: u64 = 1000;
+/// Longest `timeout` a request may ask for, in ms; the agent's own limit still applies
+pub const MAX_TIMEOUT_MS: u64 = 24 * 60 * 60 * 1000;
+
+/// JSON type a field must have
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    Text,
+    Integer,
+    Bool,
+    Array,
+    Object,
+}
+
+impl Kind {
+    fn matches(self, value: &Value) -> bool {
+        match self {
+            Kind::Text => value.is_string(),
+            Kind::Integer => value.is_u64(),
+            Kind::Bool => value.is_boolean(),
+            Kind::Array => value.is_array(),
+            Kind::Object => value.is_object(),
+        }
+    }
+
+    fn expected(self) -> &'static str {
+        match self {
+            Kind::Text => "a string",
+            Kind::Integer => "a non-negative integer",
+            Kind::Bool => "true or false",
+            Kind::Array => "an array",
+            Kind::Object => "an object",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Presence {
+    Required,
+    /// May be left out, but not null
+    Defaulted,
+    /// May be left out or null
+    Nullable,
+}
+
+struct Field {
+    name: &'static str,
+    kind: Kind,
+    presence: Presence,
+}
+
+const fn field(name: &'static str, kind: Kind, presence: Presence) -> Field {
+    Field { name, kind, presence }
+}
+
+/// Fields every message carries
+const COMMON_FIELDS: &[Field] = &[
+    field("id", Text, Required),
+    field("type", Text, Required),
+    field("timestamp", Integer, Required),
+];
+
+const HANDSHAKE_FIELDS: &[Field] = &[
+    field("origin", Text, Required),
+    field("version", Text, Required),
+    field("encoding", Text, Defaulted),
+    field("subscribeResults", Bool, Defaulted),
+];
+
+const SIMULATE_FIELDS: &[Field] = &[
+    field("netlist", Text, Required),
+    field("inputFormat", Text, Defaulted),
+    field("netlistEncoding", Text, Defaulted),
+    field("dialect", Text, Nullable),
+    field("waveformQuality", Text, Defaulted),
+    field("simulator", Text, Defaulted),
+    field("timeout", Integer, Nullable),
+    field("monteCarlo", Object, Nullable),
+    field("streamLogs", Bool, Defaulted),
+    field("defaultAnalysis", Text, Nullable),
+    field("confirmLongRuns", Bool, Defaulted),
+    field("convergenceAssist", Bool, Defaulted),
+    field("includeDebugInfo", Bool, Defaulted),
+    field("compress", Bool, Defaulted),
+    field("engineOptions", Object, Defaulted),
+    field("attachments", Array, Defaulted),
+    field("currentProbes", Array, Nullable),
+    field("autoRetryOtherEngine", Bool, Defaulted),
+    field("librarySearchPaths", Array, Defaulted),
+    field("libraryStrategy", Text, Nullable),
+    field("keepRaw", Bool, Defaulted),
+    field("precision", Integer, Nullable),
+    field("f32Results", Bool, Defaulted),
+    field("deferTraceData", Bool, Defaulted),
+    field("saveTraces", Array, Defaulted),
+    field("peek", Bool, Defaulted),
+    field("peekFast", Bool, Defaulted),
+    field("noAutoOptions", Bool, Defaulted),
+    field("waveOutput", Object, Nullable),
+    field("traceAliases", Object, Defaulted),
+];
+
+const BATCH_SIMULATE_FIELDS: &[Field] = &[
+    field("netlist", Text, Required),
+    field("corners", Array, Required),
+    field("waveformQuality", Text, Defaulted),
+    field("simulator", Text, Defaulted),
+    field("timeout", Integer, Nullable),
+];
+
+const RERUN_FIELDS: &[Field] = &[field("historyId", Text, Required)];
+
+const REQUEST_ID_FIELDS: &[Field] = &[field("requestId", Text, Required)];
+
+const GET_ARTIFACT_FIELDS: &[Field] = &[field("artifactId", Text, Required)];
+
+const NETLIST_FIELDS: &[Field] = &[field("netlist", Text, Required)];
+
+const LIST_LIBRARIES_FIELDS: &[Field] = &[field("simulator", Text, Defaulted)];
+
+const POST_PROCESS_FIELDS: &[Field] = &[
+    field("simulationId", Text, Nullable),
+    field("traces", Array, Required),
+];
+
+const GET_TRACE_DATA_FIELDS: &[Field] = &[
+    field("simulationId", Text, Required),
+    field("traces", Array, Required),
+    field("maxPoints", Integer, Nullable),
+];
+
+const REGISTER_TEMPLATE_FIELDS: &[Field] = &[
+    field("name", Text, Required),
+    field("netlist", Text, Required),
+];
+
+const TEMPLATE_NAME_FIELDS: &[Field] = &[field("name", Text, Required)];
+
+/// A simulate message's fields, with a template and its values in place of the netlist; a
+/// filled-in template is plain text, so `netlistEncoding` doesn't apply
+fn simulate_template_fields() -> &'static [Field] {
+    static FIELDS: OnceLock<Vec<Field>> = OnceLock::new();
+    FIELDS.get_or_init(|| {
+        [field("template", Text, Required), field("values", Object, Required)]
+            .into_iter()
+            .chain(
+                SIMULATE_FIELDS
+                    .iter()
+                    .filter(|simulate| !matches!(simulate.name, "netlist" | "netlistEncoding"))
+                    .map(|simulate| field(simulate.name, simulate.kind, simulate.presence)),
+            )
+            .collect()
+    })
+}
+
+/// Fields a message type takes besides the common ones, or None for types the agent doesn't handle
+fn message_fields(msg_type: &str) -> Option<&'static [Field]> {
+    Some(match msg_type {
+        "handshake" => HANDSHAKE_FIELDS,
+        "simulate" => SIMULATE_FIELDS,
+        "batch_simulate" => BATCH_SIMULATE_FIELDS,
+        "rerun_simulation" => RERUN_FIELDS,
+        "confirm" | "cancel" | "get_result" => REQUEST_ID_FIELDS,
+        "get_artifact" => GET_ARTIFACT_FIELDS,
+        "analyze_netlist" => NETLIST_FIELDS,
+        "list_libraries" => LIST_LIBRARIES_FIELDS,
+        "post_process" => POST_PROCESS_FIELDS,
+        "get_trace_data" => GET_TRACE_DATA_FIELDS,
+        "simulate_template" => simulate_template_fields(),
+        "register_template" => REGISTER_TEMPLATE_FIELDS,
+        "delete_template" => TEMPLATE_NAME_FIELDS,
+        "ping" | "export" | "get_metrics" | "get_status" | "redetect_simulators" | "self_test" | "list_templates" => &[],
+        _ => return None,
+    })
+}
+
+/// Why a message was rejected
+#[derive(Debug, Clone, PartialEq)]
+pub struct InvalidMessage {
+    /// The offending field, when the problem is with one field
+    pub field: Option<String>,
+    pub error: String,
+    /// What the field should hold
+    pub expected: Option<String>,
+}
+
+impl InvalidMessage {
+    fn field(name: &str, error: String, expected: impl Into<String>) -> Self {
+        Self {
+            field: Some(name.to_string()),
+            error,
+            expected: Some(expected.into()),
+        }
+    }
+
+    fn message(error: String) -> Self {
+        Self { field: None, error, expected: None }
+    }
+
+    /// The `error` message sent back for the message with `request_id`
+    pub fn response(self, request_id: Option<String>, message_type: Option<String>) -> InvalidMessageResponse {
+        InvalidMessageResponse {
+            id: uuid::Uuid::new_v4().to_string(),
+            msg_type: "error".to_string(),
+            request_id,
+            timestamp: now_ms(),
+            message_type,
+            field: self.field,
+            error: self.error,
+            expected: self.expected,
+            error_code: "INVALID_MESSAGE".to_string(),
+        }
+    }
+}
+
+/// Parse a message's text, which must be a JSON object with a string `type`
+pub fn parse_message(text: &str) -> Result<(Value, String), InvalidMessage> {
+    let value: Value = serde_json::from_str(text)
+        .map_err(|e| InvalidMessage::message(format!("Message is not valid JSON: {}", e)))?;
+    if !value.is_object() {
+        return Err(InvalidMessage::message("Message must be a JSON object".to_string()));
+    }
+    let msg_type = value
+        .get("type")
+        .and_then(Value::as_str)
+        .map(str::to_string)
+        .ok_or_else(|| InvalidMessage::field("type", "Message has no type".to_string(), "a string such as \"simulate\""))?;
+    Ok((value, msg_type))
+}
+
+/// Check a message of type `msg_type` before it is handled; types the agent doesn't handle pass
+pub fn check(msg_type: &str, message: &Value, settings: &AgentSettings) -> Result<(), InvalidMessage> {
+    let Some(fields) = message_fields(msg_type) else {
+        return Ok(());
+    };
+    check_fields(msg_type, message, fields)?;
+
+    match msg_type {
+        "handshake" => deserialize::<HandshakeRequest>(msg_type, message)?,
+        "simulate" => deserialize::<SimulationRequest>(msg_type, message)?,
+        "batch_simulate" => deserialize::<BatchSimulationRequest>(msg_type, message)?,
+        "rerun_simulation" => deserialize::<RerunSimulationRequest>(msg_type, message)?,
+        "confirm" => deserialize::<ConfirmRequest>(msg_type, message)?,
+        "cancel" => deserialize::<CancelRequest>(msg_type, message)?,
+        "get_result" => deserialize::<GetResultRequest>(msg_type, message)?,
+        "get_artifact" => deserialize::<GetArtifactRequest>(msg_type, message)?,
+        "analyze_netlist" => deserialize::<AnalyzeNetlistRequest>(msg_type, message)?,
+        "list_libraries" => deserialize::<ListLibrariesRequest>(msg_type, message)?,
+        "post_process" => deserialize::<PostProcessRequest>(msg_type, message)?,
+        "get_trace_data" => deserialize::<GetTraceDataRequest>(msg_type, message)?,
+        "simulate_template" => {
+            deserialize::<SimulateTemplateRequest>(msg_type, message)?;
+            templates::simulate_request(message, String::new())
+                .map_err(|e| InvalidMessage::message(format!("Invalid {} message: {}", msg_type, e)))?;
+        }
+        "register_template" => deserialize::<RegisterTemplateRequest>(msg_type, message)?,
+        "delete_template" => deserialize::<DeleteTemplateRequest>(msg_type, message)?,
+        "list_templates" => deserialize::<ListTemplatesRequest>(msg_type, message)?,
+        _ => {}
+    }
+
+    if message["id"].as_str().is_some_and(|id| id.trim().is_empty()) {
+        return Err(InvalidMessage::field("id", "id must not be empty".to_string(), "a unique request id"));
+    }
+    if let Some(timeout) = message.get("timeout").and_then(Value::as_u64) {
+        if !(MIN_TIMEOUT_MS..=MAX_TIMEOUT_MS).contains(&timeout) {
+            return Err(InvalidMessage::field(
+                "timeout",
+                format!("timeout {} ms is out of range", timeout),
+                format!(
+                    "milliseconds between {} and {}, or leave it out for the agent's limit",
+                    MIN_TIMEOUT_MS, MAX_TIMEOUT_MS
+                ),
+            ));
+        }
+    }
+    if let Some(error) = message.get("name").and_then(Value::as_str).filter(|_| msg_type == "register_template").and_then(templates::check_name) {
+        return Err(InvalidMessage::field(
+            "name",
+            error,
+            format!("1 to {} letters, digits, _, - or .", templates::MAX_TOKEN_CHARS),
+        ));
+    }
+    if let Some(values) = message.get("values").and_then(Value::as_object).filter(|_| msg_type == "simulate_template") {
+        let invalid = values.iter().find_map(|(name, value)| templates::check_value(name, value.as_str().unwrap_or_default()));
+        if let Some(error) = invalid {
+            return Err(InvalidMessage::field(
+                "values",
+                error,
+                "placeholder values such as 4.7k or 2N3904, without spaces, line breaks or a leading . or +",
+            ));
+        }
+    }
+    if let Some(precision) = message.get("precision").and_then(Value::as_u64) {
+        if !(1..=MAX_PRECISION as u64).contains(&precision) {
+            return Err(InvalidMessage::field(
+                "precision",
+                format!("precision {} is out of range", precision),
+                format!("significant digits between 1 and {}, or leave it out for full precision", MAX_PRECISION),
+            ));
+        }
+    }
+    if let Some(wave) = message.get("waveOutput").filter(|wave| !wave.is_null()) {
+        let wave = WaveOutput::deserialize(wave).map_err(|e| InvalidMessage::message(format!("Invalid waveOutput: {}", e)))?;
+        if let Some(error) = wave::check(&wave) {
+            return Err(InvalidMessage::field(
+                "waveOutput",
+                error,
+                format!(
+                    "a node, a sampleRate of {} to {} Hz and a duration in seconds of at most {} MB of audio",
+                    wave::MIN_SAMPLE_RATE,
+                    wave::MAX_SAMPLE_RATE,
+                    wave::MAX_WAVE_BYTES / (1024 * 1024)
+                ),
+            ));
+        }
+    }
+    if let Some(quality) = message.get("waveformQuality").and_then(Value::as_str) {
+        if !settings.quality_profiles.contains_key(quality) {
+            let names: Vec<&str> = settings.quality_profiles.keys().map(|name| name.as_str()).collect();
+            return Err(InvalidMessage::field(
+                "waveformQuality",
+                format!("waveformQuality \"{}\" is not a known profile", quality),
+                format!("one of {}", names.join(", ")),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Presence, JSON type and unknown-field checks against the schema
+fn check_fields(msg_type: &str, message: &Value, fields: &[Field]) -> Result<(), InvalidMessage> {
+    let object = message.as_object().ok_or_else(|| InvalidMessage::message("Message must be a JSON object".to_string()))?;
+    let schema = || COMMON_FIELDS.iter().chain(fields);
+
+    for field in schema() {
+        match object.get(field.name) {
+            None | Some(Value::Null) if field.presence == Required => {
+                return Err(InvalidMessage::field(
+                    field.name,
+                    format!("{} message is missing {}", msg_type, field.name),
+                    field.kind.expected(),
+                ));
+            }
+            Some(Value::Null) if field.presence == Defaulted => {
+                return Err(InvalidMessage::field(
+                    field.name,
+                    format!("{} must not be null; leave it out for the default", field.name),
+                    field.kind.expected(),
+                ));
+            }
+            Some(value) if !value.is_null() && !field.kind.matches(value) => {
+                return Err(InvalidMessage::field(
+                    field.name,
+                    format!("{} has the wrong type", field.name),
+                    field.kind.expected(),
+                ));
+            }
+            _ => {}
+        }
+    }
+
+    if let Some(unknown) = object.keys().find(|key| !schema().any(|field| field.name == key.as_str())) {
+        let accepted: Vec<&str> = schema().map(|field| field.name).collect();
+        return Err(InvalidMessage::field(
+            unknown,
+            format!("Unknown field {} in {} message", unknown, msg_type),
+            format!("one of {}", accepted.join(", ")),
+        ));
+    }
+    Ok(())
+}
+
+/// Deserialize without copying, reporting what the schema checks can't see, like a bad enum
+/// value or a malformed nested object
+fn deserialize<T: DeserializeOwned>(msg_type: &str, message: &Value) -> Result<(), InvalidMessage> {
+    T::deserialize(message)
+        .map(|_| ())
+        .map_err(|e| InvalidMessage::message(format!("Invalid {} message: {}", msg_type, e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn check_default(message: &Value) -> Result<(), InvalidMessage> {
+        check(message["type"].as_str().unwrap(), message, &AgentSettings::default())
+    }
+
+    /// One message of each type with every field it accepts; documents the schema
+    fn full_messages() -> Vec<Value> {
+        let common = |msg_type: &str| json!({"id": "m-1", "type": msg_type, "timestamp": 1});
+        let with = |msg_type: &str, fields: Value| {
+            let mut message = common(msg_type);
+            message.as_object_mut().unwrap().extend(fields.as_object().unwrap().clone());
+            message
+        };
+        vec![
+            with("handshake", json!({"origin": "https://kelicad.com", "version": "1.0.0", "encoding": "json"})),
+            with(
+                "simulate",
+                json!({
+                    "netlist": "V1 a 0 1\n.op\n.end",
+                    "inputFormat": "netlist",
+                    "netlistEncoding": "plain",
+                    "dialect": "ltspice",
+                    "waveformQuality": "fast",
+                    "simulator": "ngspice",
+                    "timeout": 60000,
+                    "monteCarlo": {"runs": 10, "measurements": ["vmax"], "seed": 1},
+                    "streamLogs": true,
+                    "defaultAnalysis": ".op",
+                    "confirmLongRuns": true,
+                    "convergenceAssist": true,
+                    "includeDebugInfo": true,
+                    "compress": true,
+                    "engineOptions": {"alternateSolver": true, "asciiOutput": false},
+                    "attachments": [{"name": "m.lib", "contentBase64": ""}],
+                    "currentProbes": ["R1"],
+                    "autoRetryOtherEngine": true,
+                    "librarySearchPaths": ["/opt/models"],
+                    "libraryStrategy": "reference",
+                    "keepRaw": true,
+                    "precision": 6,
+                    "f32Results": true,
+                    "deferTraceData": true,
+                    "saveTraces": ["V(out)"],
+                    "peek": false,
+                    "peekFast": false,
+                    "noAutoOptions": false,
+                    "waveOutput": {"node": "out", "sampleRate": 44100, "duration": 1.0},
+                    "traceAliases": {"V(n001)": "Input"},
+                }),
+            ),
+            with(
+                "batch_simulate",
+                json!({
+                    "netlist": ".op\n.end",
+                    "corners": [{"name": "hot", "params": {"r": "1k"}, "temperature": 85.0}],
+                    "waveformQuality": "smooth",
+                    "simulator": "ltspice",
+                    "timeout": 60000,
+                }),
+            ),
+            with("rerun_simulation", json!({"historyId": "h-1"})),
+            with("confirm", json!({"requestId": "sim-1"})),
+            with("cancel", json!({"requestId": "sim-1"})),
+            with("get_result", json!({"requestId": "sim-1"})),
+            with("get_artifact", json!({"artifactId": "sim-1"})),
+            with("analyze_netlist", json!({"netlist": ".op\n.end"})),
+            with("list_libraries", json!({"simulator": "ltspice"})),
+            with("post_process", json!({"simulationId": "sim-1", "traces": [{"name": "d", "expression": "V(a)-V(b)"}]})),
+            with("get_trace_data", json!({"simulationId": "sim-1", "traces": ["V(out)"], "maxPoints": 500})),
+            with("register_template", json!({"name": "rc", "netlist": "R1 in out {{R1}}\n.op\n.end"})),
+            with("delete_template", json!({"name": "rc"})),
+            with(
+                "simulate_template",
+                json!({
+                    "template": "rc",
+                    "values": {"R1": "4.7k"},
+                    "inputFormat": "netlist",
+                    "dialect": "ltspice",
+                    "waveformQuality": "fast",
+                    "simulator": "ngspice",
+                    "timeout": 60000,
+                    "monteCarlo": {"runs": 10, "measurements": ["vmax"], "seed": 1},
+                    "streamLogs": true,
+                    "defaultAnalysis": ".op",
+                    "confirmLongRuns": true,
+                    "convergenceAssist": true,
+                    "includeDebugInfo": true,
+                    "compress": true,
+                    "engineOptions": {"alternateSolver": true, "asciiOutput": false},
+                    "attachments": [{"name": "m.lib", "contentBase64": ""}],
+                    "currentProbes": ["R1"],
+                    "autoRetryOtherEngine": true,
+                    "librarySearchPaths": ["/opt/models"],
+                    "libraryStrategy": "reference",
+                    "keepRaw": true,
+                    "precision": 6,
+                    "f32Results": true,
+                    "deferTraceData": true,
+                    "saveTraces": ["V(out)"],
+                    "peek": false,
+                    "peekFast": false,
+                    "noAutoOptions": false,
+                    "waveOutput": {"node": "out", "sampleRate": 44100, "duration": 1.0},
+                    "traceAliases": {"V(n001)": "Input"},
+                }),
+            ),
+            common("ping"),
+            common("export"),
+            common("get_metrics"),
+            common("get_status"),
+            common("redetect_simulators"),
+            common("self_test"),
+            common("list_templates"),
+        ]
+    }
+
+    #[test]
+    fn test_full_messages_are_accepted() {
+        for message in full_messages() {
+            assert_eq!(check_default(&message), Ok(()), "{}", message);
+        }
+    }
+
+    #[test]
+    fn test_minimal_messages_are_accepted() {
+        // Only the required fields
+        let simulate = json!({"id": "s", "type": "simulate", "netlist": ".op", "timestamp": 0});
+        assert_eq!(check_default(&simulate), Ok(()));
+        let nulls = json!({"id": "s", "type": "simulate", "netlist": ".op", "timestamp": 0, "timeout": null, "monteCarlo": null});
+        assert_eq!(check_default(&nulls), Ok(()));
+        // Types the agent doesn't handle are left to the dispatcher
+        assert_eq!(check_default(&json!({"type": "future_message"})), Ok(()));
+    }
+
+    #[test]
+    fn test_every_field_is_checked() {
+        for message in full_messages() {
+            let msg_type = message["type"].as_str().unwrap().to_string();
+            for (name, value) in message.as_object().unwrap() {
+                let mut wrong = message.clone();
+                wrong[name.as_str()] = if value.is_boolean() { json!("yes") } else { json!(true) };
+                let err = check(&msg_type, &wrong, &AgentSettings::default()).unwrap_err();
+                assert_eq!(err.field.as_deref(), Some(name.as_str()), "{} in {}", name, msg_type);
+            }
+        }
+    }
+
+    #[test]
+    fn test_errors_name_the_field() {
+        let simulate = json!({"id": "s", "type": "simulate", "timestamp": 0});
+        assert_eq!(
+            check_default(&simulate).unwrap_err(),
+            InvalidMessage {
+                field: Some("netlist".to_string()),
+                error: "simulate message is missing netlist".to_string(),
+                expected: Some("a string".to_string()),
+            }
+        );
+
+        let typo = json!({"id": "s", "type": "simulate", "netlist": ".op", "timestamp": 0, "waveformQualtiy": "fast"});
+        let err = check_default(&typo).unwrap_err();
+        assert_eq!(err.field.as_deref(), Some("waveformQualtiy"));
+        assert_eq!(err.error, "Unknown field waveformQualtiy in simulate message");
+        assert!(err.expected.unwrap().contains("waveformQuality"));
+
+        let null = json!({"id": "s", "type": "simulate", "netlist": ".op", "timestamp": 0, "compress": null});
+        assert_eq!(check_default(&null).unwrap_err().field.as_deref(), Some("compress"));
+
+        let negative = json!({"id": "s", "type": "simulate", "netlist": ".op", "timestamp": 0, "timeout": -5});
+        let err = check_default(&negative).unwrap_err();
+        assert_eq!(err.field.as_deref(), Some("timeout"));
+        assert_eq!(err.expected.as_deref(), Some("a non-negative integer"));
+
+        // Nested problems are reported from the typed request
+        let format = json!({"id": "s", "type": "simulate", "netlist": ".op", "timestamp": 0, "inputFormat": "spice3"});
+        let err = check_default(&format).unwrap_err();
+        assert_eq!(err.field, None);
+        assert!(err.error.starts_with("Invalid simulate message: unknown variant `spice3`"), "{}", err.error);
+    }
+
+    #[test]
+    fn test_values_are_checked() {
+        let simulate = |fields: Value| {
+            let mut message = json!({"id": "s", "type": "simulate", "netlist": ".op", "timestamp": 0});
+            message.as_object_mut().unwrap().extend(fields.as_object().unwrap().clone());
+            check_default(&message)
+        };
+        assert_eq!(simulate(json!({"id": " "})).unwrap_err().field.as_deref(), Some("id"));
+        assert_eq!(simulate(json!({"timeout": 999})).unwrap_err().field.as_deref(), Some("timeout"));
+        assert_eq!(simulate(json!({"timeout": MAX_TIMEOUT_MS + 1})).unwrap_err().field.as_deref(), Some("timeout"));
+        assert_eq!(simulate(json!({"timeout": MIN_TIMEOUT_MS})), Ok(()));
+        assert_eq!(simulate(json!({"precision": 0})).unwrap_err().field.as_deref(), Some("precision"));
+        assert_eq!(simulate(json!({"precision": MAX_PRECISION + 1})).unwrap_err().field.as_deref(), Some("precision"));
+        assert_eq!(simulate(json!({"precision": MAX_PRECISION})), Ok(()));
+        let wave = |sample_rate: u32, duration: f64| json!({"waveOutput": {"node": "out", "sampleRate": sample_rate, "duration": duration}});
+        assert_eq!(simulate(wave(44_100, 1.0)), Ok(()));
+        assert_eq!(simulate(wave(100, 1.0)).unwrap_err().field.as_deref(), Some("waveOutput"));
+        assert_eq!(simulate(wave(44_100, 3600.0)).unwrap_err().field.as_deref(), Some("waveOutput"));
+        assert_eq!(simulate(json!({"waveOutput": null})), Ok(()));
+
+        let err = simulate(json!({"waveformQuality": "ultra"})).unwrap_err();
+        assert_eq!(err.error, "waveformQuality \"ultra\" is not a known profile");
+        assert_eq!(err.expected.as_deref(), Some("one of balanced, fast, smooth"));
+    }
+
+    #[test]
+    fn test_template_messages_are_checked() {
+        let message = |msg_type: &str, fields: Value| {
+            let mut message = json!({"id": "t", "type": msg_type, "timestamp": 0});
+            message.as_object_mut().unwrap().extend(fields.as_object().unwrap().clone());
+            check_default(&message)
+        };
+        let simulate = |values: Value| message("simulate_template", json!({"template": "rc", "values": values}));
+        assert_eq!(simulate(json!({})), Ok(()));
+        assert_eq!(simulate(json!({"R1": "1k", "VIN": "-5"})), Ok(()));
+        let err = simulate(json!({"R1": "1k\n.include evil.lib"})).unwrap_err();
+        assert_eq!(err.field.as_deref(), Some("values"));
+        assert!(err.error.starts_with("Value \"1k\\n.include evil.lib\" for R1"), "{}", err.error);
+        assert_eq!(simulate(json!({"R1": ".end"})).unwrap_err().field.as_deref(), Some("values"));
+        assert!(simulate(json!({"R1": 1000})).unwrap_err().error.starts_with("Invalid simulate_template message"));
+
+        // The netlist comes from the template
+        let err = message("simulate_template", json!({"template": "rc", "values": {}, "netlist": ".op"})).unwrap_err();
+        assert_eq!(err.field.as_deref(), Some("netlist"));
+        let err = message("simulate_template", json!({"template": "rc", "values": {}, "netlistEncoding": "gzip"})).unwrap_err();
+        assert_eq!(err.field.as_deref(), Some("netlistEncoding"));
+        let err = message("simulate_template", json!({"template": "rc", "values": {}, "timeout": 5})).unwrap_err();
+        assert_eq!(err.field.as_deref(), Some("timeout"));
+
+        let err = message("register_template", json!({"name": "rc filter", "netlist": ".op"})).unwrap_err();
+        assert_eq!(err.field.as_deref(), Some("name"));
+        assert_eq!(message("register_template", json!({"name": "rc"})).unwrap_err().field.as_deref(), Some("netlist"));
+    }
+
+    #[test]
+    fn test_parse_message() {
+        let (_, msg_type) = parse_message(r#"{"id": "p", "type": "ping", "timestamp": 0}"#).unwrap();
+        assert_eq!(msg_type, "ping");
+        assert!(parse_message("{not json").unwrap_err().error.starts_with("Message is not valid JSON"));
+        assert_eq!(parse_message("[1, 2]").unwrap_err().error, "Message must be a JSON object");
+        assert_eq!(parse_message(r#"{"id": "p"}"#).unwrap_err().field.as_deref(), Some("type"));
+    }
+
+    #[test]
+    fn test_response() {
+        let response = InvalidMessage::field("timeout", "timeout 5 ms is out of range".to_string(), "milliseconds")
+            .response(Some("sim-1".to_string()), Some("simulate".to_string()));
+        let json = serde_json::to_value(&response).unwrap();
+        assert_eq!(json["type"], "error");
+        assert_eq!(json["requestId"], "sim-1");
+        assert_eq!(json["messageType"], "simulate");
+        assert_eq!(json["field"], "timeout");
+        assert_eq!(json["errorCode"], "INVALID_MESSAGE");
+    }
+}
@@ -0,0 +1,294 @@
+// Copyright (c) 2024-2025 Wanyeki Technologies LLC. All rights reserved.
+// This source code is licensed under the proprietary license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! WAV audio of a node, written by LTspice's `.wave` directive, for listening to audio circuits
+//!
+//! The directive points into the simulation's temp dir, so the file is removed along with it.
+//! It is read back once the raw file has been parsed, cut to the requested duration and sent
+//! base64-encoded in the response's `waveData`. ngspice has no equivalent directive.
+
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use crate::protocol::{SimulationResults, WaveOutput};
+
+/// Lowest sample rate a request may ask for, in Hz
+pub const MIN_SAMPLE_RATE: u32 = 1_000;
+/// Highest sample rate a request may ask for, in Hz
+pub const MAX_SAMPLE_RATE: u32 = 192_000;
+/// Most WAV bytes returned with a simulation
+pub const MAX_WAVE_BYTES: u64 = 32 * 1024 * 1024;
+
+/// Bits per sample LTspice is asked to write
+const BITS_PER_SAMPLE: u32 = 16;
+
+/// Size of the RIFF, `fmt ` and `data` headers of a plain PCM file
+const HEADER_BYTES: u64 = 44;
+
+/// Why `waveOutput` can't be used as given; None when it can
+pub fn check(wave: &WaveOutput) -> Option<String> {
+    let node = wave.node.trim();
+    if node.is_empty() {
+        return Some("waveOutput.node must name a node".to_string());
+    }
+    if node.chars().any(|c| c.is_whitespace() || c.is_control() || c == '"' || c == ';') {
+        return Some(format!("waveOutput.node \"{}\" is not a node name", node));
+    }
+    if !(MIN_SAMPLE_RATE..=MAX_SAMPLE_RATE).contains(&wave.sample_rate) {
+        return Some(format!(
+            "waveOutput.sampleRate {} Hz is out of range; use {} to {} Hz",
+            wave.sample_rate, MIN_SAMPLE_RATE, MAX_SAMPLE_RATE
+        ));
+    }
+    if !wave.duration.is_finite() || wave.duration <= 0.0 {
+        return Some(format!("waveOutput.duration {} must be a positive number of seconds", wave.duration));
+    }
+    let bytes = HEADER_BYTES as f64 + wave.duration * wave.sample_rate as f64 * (BITS_PER_SAMPLE / 8) as f64;
+    if bytes > MAX_WAVE_BYTES as f64 {
+        return Some(format!(
+            "waveOutput of {} s at {} Hz would be {} MB, more than the {} MB returned",
+            wave.duration,
+            wave.sample_rate,
+            (bytes / (1024.0 * 1024.0)).ceil(),
+            MAX_WAVE_BYTES / (1024 * 1024)
+        ));
+    }
+    None
+}
+
+/// Signal written for `node`: its voltage, or the node as is when it already names a signal like `V(a,b)`
+pub fn signal(node: &str) -> String {
+    let node = node.trim();
+    if node.contains('(') {
+        node.to_string()
+    } else {
+        format!("V({})", node)
+    }
+}
+
+/// `.wave` directive writing `wave`'s node to `path`
+pub fn directive(path: &Path, wave: &WaveOutput) -> String {
+    format!(
+        ".wave \"{}\" {} {} {}",
+        path.display(),
+        BITS_PER_SAMPLE,
+        wave.sample_rate,
+        signal(&wave.node)
+    )
+}
+
+/// Whether `results` has a trace of `node`'s signal
+pub fn has_signal(results: &SimulationResults, node: &str) -> bool {
+    let signal = signal(node);
+    results.traces.iter().any(|trace| trace.name.eq_ignore_ascii_case(&signal))
+}
+
+/// The first `duration` seconds of the WAV file in `data`, rewritten with plain headers
+pub fn trim(data: &[u8], duration: f64) -> Result<Vec<u8>, String> {
+    if data.len() < 12 || &data[0..4] != b"RIFF" || &data[8..12] != b"WAVE" {
+        return Err("LTspice wrote a WAV file without a RIFF header".to_string());
+    }
+    let mut format = None;
+    let mut samples = None;
+    let mut offset = 12;
+    while offset + 8 <= data.len() {
+        let id = &data[offset..offset + 4];
+        let size = u32::from_le_bytes(data[offset + 4..offset + 8].try_into().unwrap()) as usize;
+        let body = &data[offset + 8..(offset + 8).saturating_add(size).min(data.len())];
+        match id {
+            b"fmt " => format = Some(body),
+            // A file cut short keeps the size it was meant to have
+            b"data" => samples = Some(body),
+            _ => {}
+        }
+        offset = (offset + 8).saturating_add(size).saturating_add(size % 2);
+    }
+    let format = format.filter(|format| format.len() >= 16).ok_or("LTspice wrote a WAV file without a format")?;
+    let samples = samples.ok_or("LTspice wrote a WAV file without samples")?;
+
+    let sample_rate = u32::from_le_bytes(format[4..8].try_into().unwrap());
+    let block_align = u16::from_le_bytes(format[12..14].try_into().unwrap()).max(1) as usize;
+    let frames = (duration * sample_rate as f64).floor() as usize;
+    let kept = &samples[..(frames * block_align).min(samples.len() / block_align * block_align)];
+
+    let mut wav = Vec::with_capacity(HEADER_BYTES as usize + kept.len());
+    wav.extend_from_slice(b"RIFF");
+    wav.extend_from_slice(&(4 + 8 + format.len() as u32 + (format.len() % 2) as u32 + 8 + kept.len() as u32).to_le_bytes());
+    wav.extend_from_slice(b"WAVE");
+    wav.extend_from_slice(b"fmt ");
+    wav.extend_from_slice(&(format.len() as u32).to_le_bytes());
+    wav.extend_from_slice(format);
+    if format.len() % 2 == 1 {
+        wav.push(0);
+    }
+    wav.extend_from_slice(b"data");
+    wav.extend_from_slice(&(kept.len() as u32).to_le_bytes());
+    wav.extend_from_slice(kept);
+    Ok(wav)
+}
+
+/// Error returned when the node to write as WAV audio has no trace in the results
+#[derive(Debug)]
+pub struct WaveNodeNotFound {
+    pub signal: String,
+}
+
+impl std::fmt::Display for WaveNodeNotFound {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "waveOutput names {}, which is not in the simulation results", self.signal)
+    }
+}
+
+impl std::error::Error for WaveNodeNotFound {}
+
+/// A run's `waveOutput` and, once it is done, the WAV bytes it wrote
+#[derive(Debug, Clone)]
+pub struct WaveCapture {
+    pub output: WaveOutput,
+    pub data: Arc<Mutex<Option<Vec<u8>>>>,
+}
+
+impl WaveCapture {
+    pub fn new(output: WaveOutput) -> Self {
+        Self { output, data: Arc::new(Mutex::new(None)) }
+    }
+
+    /// Read the WAV file LTspice wrote to `path` for `results`, keeping the requested duration
+    pub fn collect(&self, path: &Path, results: &SimulationResults) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        if !has_signal(results, &self.output.node) {
+            return Err(Box::new(WaveNodeNotFound { signal: signal(&self.output.node) }));
+        }
+        let data = std::fs::read(path).map_err(|e| format!("LTspice wrote no WAV file of {}: {}", signal(&self.output.node), e))?;
+        let wav = trim(&data, self.output.duration)?;
+        if let Ok(mut kept) = self.data.lock() {
+            *kept = Some(wav);
+        }
+        Ok(())
+    }
+
+    /// The WAV bytes collected, if any
+    pub fn take(&self) -> Option<Vec<u8>> {
+        self.data.lock().ok().and_then(|mut kept| kept.take())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    use crate::protocol::Trace;
+
+    fn wave(node: &str, sample_rate: u32, duration: f64) -> WaveOutput {
+        WaveOutput { node: node.to_string(), sample_rate, duration }
+    }
+
+    fn results_with(name: &str) -> SimulationResults {
+        SimulationResults {
+            time: Vec::new(),
+            traces: vec![Trace { name: name.to_string(), data: Vec::new(), unit: "V".to_string(), phase: None, raw_name: None }],
+            analysis_type: "transient".to_string(),
+            x_axis_label: None,
+            x_axis_unit: None,
+            scalar_results: None,
+            device_operating_points: BTreeMap::new(),
+            truncated: None,
+            plot_hints: None,
+            raw_header: None,
+        }
+    }
+
+    /// Mono 16-bit PCM at `sample_rate` holding `samples`
+    fn wav_file(sample_rate: u32, samples: &[i16]) -> Vec<u8> {
+        let mut format = Vec::new();
+        format.extend_from_slice(&1u16.to_le_bytes());
+        format.extend_from_slice(&1u16.to_le_bytes());
+        format.extend_from_slice(&sample_rate.to_le_bytes());
+        format.extend_from_slice(&(sample_rate * 2).to_le_bytes());
+        format.extend_from_slice(&2u16.to_le_bytes());
+        format.extend_from_slice(&16u16.to_le_bytes());
+        let data: Vec<u8> = samples.iter().flat_map(|sample| sample.to_le_bytes()).collect();
+
+        let mut wav = b"RIFF".to_vec();
+        wav.extend_from_slice(&(36 + data.len() as u32).to_le_bytes());
+        wav.extend_from_slice(b"WAVE");
+        wav.extend_from_slice(b"fmt ");
+        wav.extend_from_slice(&16u32.to_le_bytes());
+        wav.extend_from_slice(&format);
+        wav.extend_from_slice(b"data");
+        wav.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        wav.extend_from_slice(&data);
+        wav
+    }
+
+    #[test]
+    fn test_check() {
+        assert_eq!(check(&wave("out", 44_100, 2.0)), None);
+        assert!(check(&wave(" ", 44_100, 2.0)).unwrap().contains("must name a node"));
+        assert!(check(&wave("out\" 16 8000 V(x)", 44_100, 2.0)).unwrap().contains("not a node name"));
+        assert!(check(&wave("out", 500, 2.0)).unwrap().contains("out of range"));
+        assert!(check(&wave("out", 384_000, 2.0)).unwrap().contains("out of range"));
+        assert!(check(&wave("out", 44_100, 0.0)).unwrap().contains("positive"));
+        assert!(check(&wave("out", 44_100, f64::NAN)).unwrap().contains("positive"));
+        assert!(check(&wave("out", 192_000, 600.0)).unwrap().contains("more than the 32 MB"));
+    }
+
+    #[test]
+    fn test_directive() {
+        let path = Path::new("/tmp/sim/circuit.wav");
+        assert_eq!(directive(path, &wave("out", 44_100, 1.0)), ".wave \"/tmp/sim/circuit.wav\" 16 44100 V(out)");
+        assert_eq!(directive(path, &wave("V(a,b)", 8_000, 1.0)), ".wave \"/tmp/sim/circuit.wav\" 16 8000 V(a,b)");
+    }
+
+    #[test]
+    fn test_has_signal() {
+        let results = results_with("V(out)");
+        assert!(has_signal(&results, "OUT"));
+        assert!(has_signal(&results, "v(out)"));
+        assert!(!has_signal(&results, "in"));
+    }
+
+    #[test]
+    fn test_trim_keeps_the_requested_duration() {
+        let wav = wav_file(1_000, &[1, 2, 3, 4, 5, 6, 7, 8, 9, 10]);
+        let trimmed = trim(&wav, 0.004).unwrap();
+        assert_eq!(trimmed, wav_file(1_000, &[1, 2, 3, 4]));
+
+        // Shorter files are returned whole
+        assert_eq!(trim(&wav, 1.0).unwrap(), wav);
+    }
+
+    #[test]
+    fn test_trim_skips_other_chunks_and_short_data() {
+        let mut wav = wav_file(1_000, &[1, 2, 3]);
+        wav.splice(12..12, b"LIST\x03\x00\x00\x00abc\x00".iter().copied());
+        // A data chunk cut off mid-sample, claiming more than it holds
+        wav.push(0x7f);
+        let position = wav.windows(4).position(|window| window == b"data").unwrap();
+        wav[position + 4..position + 8].copy_from_slice(&100u32.to_le_bytes());
+        assert_eq!(trim(&wav, 1.0).unwrap(), wav_file(1_000, &[1, 2, 3]));
+    }
+
+    #[test]
+    fn test_trim_rejects_other_files() {
+        assert!(trim(b"not a wav file", 1.0).is_err());
+        assert!(trim(b"RIFF\x04\x00\x00\x00WAVE", 1.0).unwrap_err().contains("without a format"));
+    }
+
+    #[test]
+    fn test_collect() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("circuit.wav");
+        let results = results_with("V(out)");
+
+        let capture = WaveCapture::new(wave("out", 1_000, 0.002));
+        assert!(capture.collect(&path, &results).unwrap_err().to_string().contains("wrote no WAV file"));
+        std::fs::write(&path, wav_file(1_000, &[1, 2, 3])).unwrap();
+        capture.collect(&path, &results).unwrap();
+        assert_eq!(capture.take(), Some(wav_file(1_000, &[1, 2])));
+
+        let missing = WaveCapture::new(wave("in", 1_000, 0.002));
+        assert!(missing.collect(&path, &results).unwrap_err().is::<WaveNodeNotFound>());
+    }
+}
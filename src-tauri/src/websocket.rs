@@ -5,55 +5,680 @@
 //! WebSocket server for handling connections from the web app
 
 use std::sync::Arc;
-use std::sync::atomic::Ordering;
-use futures_util::{SinkExt, StreamExt};
+use std::time::Duration;
+use base64::prelude::{Engine, BASE64_STANDARD};
+use futures_util::{Sink, SinkExt, StreamExt};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast::{self, error::RecvError};
 use tokio::sync::mpsc;
-use tokio_tungstenite::{accept_async, tungstenite::Message};
+use tokio_tungstenite::tungstenite::protocol::{frame::coding::CloseCode, CloseFrame, WebSocketConfig};
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::handshake::{client, server};
+use tokio_tungstenite::tungstenite::http::{header, HeaderValue, StatusCode};
+use tokio_tungstenite::{accept_hdr_async_with_config, tungstenite, tungstenite::Message};
 
+use crate::catalog;
+use crate::derived;
+use crate::dedup::{Listeners, Seen, Stored};
+use crate::detection;
+use crate::export;
+use crate::netlist;
 use crate::protocol::*;
+use crate::simulation;
 use crate::simulator;
-use crate::AppState;
+use crate::self_test;
+use crate::shared_results::SharedMessage;
+use crate::state::{AppState, StatusEvent};
+use crate::status;
+use crate::templates::{self, TemplateError};
+use crate::trace_store;
+use crate::validation;
+use crate::wire;
+
+/// How long a process already holding the port gets to answer our ping
+const PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Messages queued for a connection; beyond this progress updates are dropped and results wait
+const OUTBOUND_CAPACITY: usize = 32;
+
+/// How long a client may leave a message unread before the connection is given up on
+const SEND_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long a new connection has to send its handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Largest message accepted from a client
+const MAX_MESSAGE_BYTES: usize = 64 * 1024 * 1024;
+
+/// How long a closing client gets to acknowledge the close frame before the socket is dropped
+const CLOSE_GRACE: Duration = Duration::from_millis(500);
+
+/// Path of the plain HTTP health check served on the WebSocket port
+const HEALTH_PATH: &str = "/health";
+
+/// Most bytes of a health check request that are read; its headers are only searched for `Origin`
+const MAX_HEALTH_REQUEST_BYTES: usize = 8 * 1024;
 
 /// Start the WebSocket server
-pub async fn start_server(state: Arc<AppState>) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let addr = format!("127.0.0.1:{}", WS_PORT);
+pub async fn start_server(state: Arc<AppState>, port: u16) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let addr = format!("127.0.0.1:{}", port);
     let listener = TcpListener::bind(&addr).await?;
     log::info!("WebSocket server listening on {}", addr);
 
-    while let Ok((stream, peer_addr)) = listener.accept().await {
-        log::info!("New connection from: {}", peer_addr);
-        let state = state.clone();
-        tokio::spawn(async move {
-            if let Err(e) = handle_connection(stream, state).await {
-                log::error!("Connection error: {}", e);
+    serve(listener, state).await;
+    Ok(())
+}
+
+/// Accept connections until the listener fails or the agent shuts down
+pub async fn serve(listener: TcpListener, state: Arc<AppState>) {
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let Ok((stream, peer_addr)) = accepted else {
+                    break;
+                };
+                log::info!("New connection from: {}", peer_addr);
+                let state = state.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = handle_connection(stream, state, ConnectionLimits::default()).await {
+                        log::error!("Connection error: {}", e);
+                    }
+                });
+            }
+            _ = state.wait_for_shutdown() => {
+                log::info!("WebSocket server stopped accepting connections");
+                break;
             }
+        }
+    }
+}
+
+/// Take a simulation start from `origin`'s rate limit, or return the ms until it may start one
+/// Retries of a request id already seen don't count as new starts
+async fn check_rate_limit(state: &AppState, origin: &str, request_id: &str) -> Result<(), u64> {
+    if state.recent_requests.read().await.contains(request_id) {
+        return Ok(());
+    }
+    let (per_minute, limit_localhost) = {
+        let settings = state.settings.read().await;
+        (settings.rate_limit_per_minute, settings.rate_limit_localhost)
+    };
+    let checked = state.rate_limiter.write().await.check(origin, per_minute, limit_localhost);
+    if let Err(retry_after_ms) = checked {
+        log::warn!("Rate limited simulation {} from {}; next start in {} ms", request_id, origin, retry_after_ms);
+        state.metrics.write().await.record_origin_rate_limited(origin);
+    }
+    checked
+}
+
+fn rate_limited_error(retry_after_ms: u64) -> String {
+    format!(
+        "Too many simulations started from this page; try again in {} s",
+        retry_after_ms.div_ceil(1000)
+    )
+}
+
+fn rate_limited_response(request: &SimulationRequest, retry_after_ms: u64) -> SimulationResponse {
+    SimulationResponse {
+        id: uuid::Uuid::new_v4().to_string(),
+        msg_type: "simulation_result".to_string(),
+        request_id: request.id.clone(),
+        timestamp: now_ms(),
+        success: false,
+        f32_results: false,
+        results: None,
+        error: Some(rate_limited_error(retry_after_ms)),
+        error_code: Some("RATE_LIMITED".to_string()),
+        execution_time: 0,
+        simulator: request.simulator.clone(),
+        monte_carlo: None,
+        duplicate: false,
+        retried_with: None,
+        translations: Vec::new(),
+        debug_info: None,
+        results_compressed: None,
+        compression: None,
+        retry_after_ms: Some(retry_after_ms),
+        warnings: Vec::new(),
+        simulator_version: None,
+        simulator_binary_sha256: None,
+        replay_of: request.replay_of.clone(),
+        fallback_simulator_used: None,
+        engine_errors: Vec::new(),
+        artifact_id: None,
+        raw_file_size: None,
+        trace_catalog: None,
+        operating_point: None,
+        wave_data: None,
+        libraries_used: Vec::new(),
+        busy_info: None,
+        timings: SimulationTimings::default(),
+    }
+}
+
+/// Response to a `rerun_simulation` whose netlist isn't stored
+fn replay_failed_response(rerun: &RerunSimulationRequest, error: String) -> SimulationResponse {
+    SimulationResponse {
+        id: uuid::Uuid::new_v4().to_string(),
+        msg_type: "simulation_result".to_string(),
+        request_id: rerun.id.clone(),
+        timestamp: now_ms(),
+        success: false,
+        f32_results: false,
+        results: None,
+        error: Some(error),
+        error_code: Some("REPLAY_NOT_FOUND".to_string()),
+        execution_time: 0,
+        simulator: String::new(),
+        monte_carlo: None,
+        duplicate: false,
+        retried_with: None,
+        translations: Vec::new(),
+        debug_info: None,
+        results_compressed: None,
+        compression: None,
+        retry_after_ms: None,
+        warnings: Vec::new(),
+        simulator_version: None,
+        simulator_binary_sha256: None,
+        replay_of: Some(rerun.history_id.clone()),
+        fallback_simulator_used: None,
+        engine_errors: Vec::new(),
+        artifact_id: None,
+        raw_file_size: None,
+        trace_catalog: None,
+        operating_point: None,
+        wave_data: None,
+        libraries_used: Vec::new(),
+        busy_info: None,
+        timings: SimulationTimings::default(),
+    }
+}
+
+/// The result sent for a `simulate_template` message whose template is gone or whose values don't fit it
+fn template_failed_response(request_id: &str, error: TemplateError) -> SimulationResponse {
+    SimulationResponse {
+        id: uuid::Uuid::new_v4().to_string(),
+        msg_type: "simulation_result".to_string(),
+        request_id: request_id.to_string(),
+        timestamp: now_ms(),
+        success: false,
+        f32_results: false,
+        results: None,
+        error: Some(error.message),
+        error_code: Some(error.code.to_string()),
+        execution_time: 0,
+        simulator: String::new(),
+        monte_carlo: None,
+        duplicate: false,
+        retried_with: None,
+        translations: Vec::new(),
+        debug_info: None,
+        results_compressed: None,
+        compression: None,
+        retry_after_ms: None,
+        warnings: Vec::new(),
+        simulator_version: None,
+        simulator_binary_sha256: None,
+        replay_of: None,
+        fallback_simulator_used: None,
+        engine_errors: Vec::new(),
+        artifact_id: None,
+        raw_file_size: None,
+        trace_catalog: None,
+        operating_point: None,
+        wave_data: None,
+        libraries_used: Vec::new(),
+        busy_info: None,
+        timings: SimulationTimings::default(),
+    }
+}
+
+/// Start a simulate request in the background, or join or replay the run of an earlier one with the same id
+/// Returns a message to send right away; otherwise the result arrives through `sim_tx`
+async fn start_simulation(
+    mut request: SimulationRequest,
+    state: &Arc<AppState>,
+    origin: &str,
+    sim_tx: &mpsc::Sender<String>,
+) -> Result<Option<String>, serde_json::Error> {
+    if let Err(retry_after_ms) = check_rate_limit(state, origin, &request.id).await {
+        return serde_json::to_string(&rate_limited_response(&request, retry_after_ms)).map(Some);
+    }
+
+    request.origin = Some(origin.to_string());
+
+    // A client retrying after a dropped connection re-sends the same id
+    let seen = state.recent_requests.write().await.begin(&request.id, sim_tx);
+    match seen {
+        Seen::New(listeners) => {
+            // Spawn simulation in a separate task so we can process cancel messages
+            let state = state.clone();
+            let origin = origin.to_string();
+            tokio::spawn(async move {
+                simulation::execute_shared(&request, &origin, &state, listeners).await;
+            });
+            Ok(None)
+        }
+        Seen::Attached => {
+            log::info!("Simulation {} is already running; sending its result here too", request.id);
+            Ok(None)
+        }
+        Seen::Finished(response) => {
+            log::info!("Replaying the result of simulation {}", request.id);
+            let replay = SimulationResponse {
+                duplicate: true,
+                ..(*response).clone()
+            };
+            let response = serde_json::to_string(&replay)?;
+            state.metrics.write().await.record_bytes_served(response.len());
+            Ok(Some(response))
+        }
+    }
+}
+
+fn rate_limited_batch_response(request: &BatchSimulationRequest, retry_after_ms: u64) -> BatchSimulationResponse {
+    BatchSimulationResponse {
+        id: uuid::Uuid::new_v4().to_string(),
+        msg_type: "batch_simulation_result".to_string(),
+        request_id: request.id.clone(),
+        timestamp: now_ms(),
+        success: false,
+        corners: vec![],
+        error: Some(rate_limited_error(retry_after_ms)),
+        error_code: Some("RATE_LIMITED".to_string()),
+        execution_time: 0,
+        simulator: request.simulator.clone(),
+        retry_after_ms: Some(retry_after_ms),
+        busy_info: None,
+    }
+}
+
+/// Queue a finished simulation's response for the client, counting it as served results
+async fn send_result(state: &AppState, sim_tx: &mpsc::Sender<String>, response: String) {
+    let len = response.len();
+    if sim_tx.send(response).await.is_ok() {
+        state.metrics.write().await.record_bytes_served(len);
+    }
+}
+
+/// Explain why the server could not start, for the UI and agent status
+/// If the port is taken, checks whether the listener is another KeliCAD agent
+pub async fn describe_start_error(error: &(dyn std::error::Error + Send + Sync + 'static), port: u16) -> String {
+    let port_in_use = error
+        .downcast_ref::<std::io::Error>()
+        .is_some_and(|e| e.kind() == std::io::ErrorKind::AddrInUse);
+    if !port_in_use {
+        return format!("WebSocket server failed to start: {}", error);
+    }
+
+    if probe_agent(&format!("127.0.0.1:{}", port)).await {
+        format!("Another KeliCAD agent is already running on port {}", port)
+    } else {
+        format!("Port {} is in use by another application", port)
+    }
+}
+
+/// A WebSocket upgrade request to `addr` carrying `origin` as its `Origin` header, as a page
+/// on that origin would send it
+pub(crate) fn browser_request(addr: &str, origin: &str) -> Option<client::Request> {
+    let mut request = format!("ws://{}", addr).into_client_request().ok()?;
+    request.headers_mut().insert(header::ORIGIN, HeaderValue::from_str(origin).ok()?);
+    Some(request)
+}
+
+/// True if the listener at `addr` speaks our protocol and answers a ping with a pong
+async fn probe_agent(addr: &str) -> bool {
+    let probe = async {
+        let request = browser_request(addr, ALLOWED_ORIGINS[0])?;
+        let (mut ws, _) = tokio_tungstenite::connect_async(request).await.ok()?;
+        let ping = serde_json::json!({
+            "id": uuid::Uuid::new_v4().to_string(),
+            "type": "ping",
+            "timestamp": now_ms(),
         });
+        ws.send(Message::Text(ping.to_string())).await.ok()?;
+
+        let mut is_agent = None;
+        while let Some(Ok(message)) = ws.next().await {
+            if let Message::Text(text) = message {
+                is_agent = serde_json::from_str::<GenericMessage>(&text)
+                    .ok()
+                    .map(|reply| reply.msg_type == "pong");
+                break;
+            }
+        }
+        let _ = ws.close(None).await;
+        is_agent
+    };
+
+    matches!(tokio::time::timeout(PROBE_TIMEOUT, probe).await, Ok(Some(true)))
+}
+
+/// Sending half of a connection
+struct Outgoing<S> {
+    sink: S,
+    /// Agreed at handshake; JSON until then
+    encoding: Encoding,
+    /// How long a frame may wait for the client to read before delivery is abandoned
+    timeout: Duration,
+}
+
+impl<S> Outgoing<S>
+where
+    S: Sink<Message> + Unpin,
+    S::Error: std::fmt::Display,
+{
+    /// Send a JSON message in the connection's encoding
+    async fn send(&mut self, json: String) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let frame = wire::encode(json, self.encoding)?;
+        self.send_frame(frame).await
+    }
+
+    async fn send_frame(&mut self, frame: Message) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        match tokio::time::timeout(self.timeout, self.sink.send(frame)).await {
+            Ok(sent) => sent.map_err(|e| e.to_string().into()),
+            Err(_) => Err(format!("client stopped reading for {} s", self.timeout.as_secs()).into()),
+        }
+    }
+}
+
+/// A message for a connection's writer
+enum Outbound {
+    /// JSON text, sent in the connection's encoding
+    Message(String),
+    /// The handshake reply, sent as JSON text so any client can read it; the encoding it agreed
+    /// is used from then on
+    Handshake(String, Encoding),
+}
+
+/// Send a connection's messages one at a time, each stamped with the next `seq`, until `outbox`
+/// closes or a send fails
+/// The only code writing to the socket while the connection is open: replies queued by the
+/// connection's loop and results and progress from spawned work in `sim_rx` are numbered in the
+/// order they go out. Returns the sink so the close frame can be sent.
+async fn write_outbound<S>(
+    mut write: Outgoing<S>,
+    mut outbox: mpsc::Receiver<Outbound>,
+    mut sim_rx: mpsc::Receiver<String>,
+) -> Outgoing<S>
+where
+    S: Sink<Message> + Unpin,
+    S::Error: std::fmt::Display,
+{
+    let mut seq = 0;
+    loop {
+        let outbound = tokio::select! {
+            outbound = outbox.recv() => match outbound {
+                Some(outbound) => outbound,
+                None => break,
+            },
+            Some(json) = sim_rx.recv() => Outbound::Message(json),
+        };
+        seq += 1;
+        let sent = match outbound {
+            Outbound::Message(json) => write.send(wire::stamp(json, seq)).await,
+            Outbound::Handshake(json, encoding) => {
+                let sent = write.send_frame(Message::Text(wire::stamp(json, seq))).await;
+                write.encoding = encoding;
+                sent
+            }
+        };
+        if let Err(e) = sent {
+            log::error!("Failed to send message {}: {}", seq, e);
+            break;
+        }
+    }
+    write
+}
+
+/// Why the agent ends a connection, told to the client in the close frame
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Disconnect {
+    ShuttingDown,
+    OriginRejected,
+    HandshakeTimeout,
+    MessageTooLarge,
+}
+
+impl Disconnect {
+    fn close_frame(self) -> CloseFrame<'static> {
+        let (code, reason) = match self {
+            Disconnect::ShuttingDown => (4000, "Agent shutting down"),
+            Disconnect::OriginRejected => (4001, "Origin not allowed"),
+            Disconnect::HandshakeTimeout => (4002, "No handshake received"),
+            Disconnect::MessageTooLarge => (4003, "Message too large"),
+        };
+        CloseFrame {
+            code: CloseCode::from(code),
+            reason: reason.into(),
+        }
+    }
+}
+
+/// Limits a connection is held to
+#[derive(Debug, Clone, Copy)]
+struct ConnectionLimits {
+    handshake_timeout: Duration,
+    max_message_bytes: usize,
+}
+
+impl Default for ConnectionLimits {
+    fn default() -> Self {
+        Self {
+            handshake_timeout: HANDSHAKE_TIMEOUT,
+            max_message_bytes: MAX_MESSAGE_BYTES,
+        }
+    }
+}
+
+/// Send the close frame for `cause`, then wait briefly for the client to acknowledge it so the
+/// frame isn't lost to a reset connection
+async fn close<S, R>(write: &mut Outgoing<S>, read: &mut R, cause: Disconnect)
+where
+    S: Sink<Message> + Unpin,
+    S::Error: std::fmt::Display,
+    R: futures_util::Stream + Unpin,
+{
+    let frame = cause.close_frame();
+    log::info!("Closing connection with code {}: {}", u16::from(frame.code), frame.reason);
+    if let Err(e) = write.send_frame(Message::Close(Some(frame))).await {
+        log::warn!("Failed to send close frame: {}", e);
+        return;
+    }
+    let _ = tokio::time::timeout(CLOSE_GRACE, async { while read.next().await.is_some() {} }).await;
+}
+
+/// Whether `head`, the start of a connection's first request, asks for the health check
+/// None until the request line is complete
+fn is_health_request(head: &[u8]) -> Option<bool> {
+    let end = head.windows(2).position(|pair| pair == b"\r\n")?;
+    let line = String::from_utf8_lossy(&head[..end]);
+    let mut parts = line.split(' ');
+    let (method, target) = (parts.next()?, parts.next()?);
+    let path = target.split('?').next().unwrap_or(target);
+    Some(matches!(method, "GET" | "OPTIONS") && path == HEALTH_PATH)
+}
+
+/// Whether the connection opened with a health check, judged from its request line without
+/// consuming it, so a WebSocket upgrade reaches the handshake untouched
+async fn wants_health(stream: &TcpStream, timeout: Duration) -> bool {
+    let mut head = [0u8; 256];
+    let deadline = tokio::time::Instant::now() + timeout;
+    loop {
+        let Ok(Ok(n)) = tokio::time::timeout_at(deadline, stream.peek(&mut head)).await else {
+            return false;
+        };
+        if let Some(health) = is_health_request(&head[..n]) {
+            return health;
+        }
+        // Closed, or a line too long for a health check; the handshake deals with it
+        if n == 0 || n == head.len() {
+            return false;
+        }
+        // Peeking again returns at once, so give the rest of a split request line time to arrive
+        tokio::time::sleep(Duration::from_millis(5)).await;
+    }
+}
+
+/// Answer a `GET /health` (or its CORS preflight) with the agent's version and whether it is
+/// busy, then close the connection
+/// Pages on allowed origins may read the response; others get it without CORS headers
+async fn serve_health(mut stream: TcpStream, state: &AppState) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut request = Vec::new();
+    let mut buf = [0u8; 1024];
+    while request.len() < MAX_HEALTH_REQUEST_BYTES && !request.windows(4).any(|window| window == b"\r\n\r\n") {
+        let n = tokio::time::timeout(HANDSHAKE_TIMEOUT, stream.read(&mut buf)).await??;
+        if n == 0 {
+            break;
+        }
+        request.extend_from_slice(&buf[..n]);
     }
+    let request = String::from_utf8_lossy(&request);
+    let preflight = request.starts_with("OPTIONS ");
+    let origin = request
+        .lines()
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("origin"))
+        .map(|(_, value)| value.trim())
+        .filter(|origin| is_origin_allowed(origin));
 
+    let mut headers = String::from("Cache-Control: no-store\r\nConnection: close\r\n");
+    if let Some(origin) = origin {
+        headers.push_str(&format!("Access-Control-Allow-Origin: {}\r\nVary: Origin\r\n", origin));
+    }
+    let response = if preflight {
+        format!(
+            "HTTP/1.1 204 No Content\r\n{}Access-Control-Allow-Methods: GET\r\nAccess-Control-Allow-Private-Network: true\r\nContent-Length: 0\r\n\r\n",
+            headers
+        )
+    } else {
+        let body = serde_json::to_string(&HealthResponse {
+            status: "ok".to_string(),
+            agent_version: AGENT_VERSION.to_string(),
+            busy: state.is_busy(),
+            active_simulations: state.active_simulations.read().await.len(),
+        })?;
+        format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n{}\r\n{}",
+            body.len(),
+            headers,
+            body
+        )
+    };
+    log::debug!("Served health check{}", origin.map(|origin| format!(" for {}", origin)).unwrap_or_default());
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
     Ok(())
 }
 
-/// Handle a single WebSocket connection
+/// Check the HTTP headers of a WebSocket upgrade, returning its `Origin`
+/// Browsers always send `Origin` and pages can't forge it, so it must be an allowed one; other
+/// programs send none and are let in only with `allow_non_browser_clients`. `Host` must name this
+/// machine, so a page can't reach the agent through a DNS name pointed at 127.0.0.1
+fn check_upgrade(request: &server::Request, allow_non_browser_clients: bool) -> Result<Option<String>, String> {
+    let host = request
+        .headers()
+        .get(header::HOST)
+        .and_then(|host| host.to_str().ok())
+        .ok_or("The upgrade request has no Host header")?;
+    let hostname = match host.rsplit_once(':') {
+        Some((hostname, port)) if port.chars().all(|c| c.is_ascii_digit()) => hostname,
+        _ => host,
+    };
+    if !matches!(hostname, "localhost" | "127.0.0.1" | "[::1]") {
+        return Err(format!("Host {} is not this machine", host));
+    }
+
+    match request.headers().get(header::ORIGIN) {
+        Some(origin) => {
+            let origin = origin.to_str().map_err(|_| "The Origin header is not text".to_string())?;
+            if is_origin_allowed(origin) {
+                Ok(Some(origin.to_string()))
+            } else {
+                Err(format!("Origin {} is not allowed", origin))
+            }
+        }
+        None if allow_non_browser_clients => Ok(None),
+        None => Err("Connections without an Origin header are not allowed".to_string()),
+    }
+}
+
+/// The next shared message for a subscribed connection; never ready for one that isn't
+async fn next_shared(feed: &mut Option<broadcast::Receiver<SharedMessage>>) -> Result<SharedMessage, RecvError> {
+    match feed {
+        Some(feed) => feed.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Handle a single WebSocket connection, or a health check made on the same port
 async fn handle_connection(
     stream: TcpStream,
     state: Arc<AppState>,
+    limits: ConnectionLimits,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let ws_stream = accept_async(stream).await?;
-    let (mut write, mut read) = ws_stream.split();
+    if wants_health(&stream, limits.handshake_timeout).await {
+        return serve_health(stream, &state).await;
+    }
+    // tungstenite 0.21 doesn't negotiate permessage-deflate; clients that want smaller
+    // results set `compress` on the simulate request instead
+    let config = WebSocketConfig {
+        max_message_size: Some(limits.max_message_bytes),
+        max_frame_size: Some(limits.max_message_bytes),
+        ..WebSocketConfig::default()
+    };
+    let allow_non_browser_clients = state.settings.read().await.allow_non_browser_clients;
+    // The upgrade's Origin header, which the JSON handshake's origin has to match
+    let mut upgrade_origin = None;
+    // The error response is tungstenite's, so its size isn't ours to choose
+    #[allow(clippy::result_large_err)]
+    let check = |request: &server::Request, response: server::Response| {
+        match check_upgrade(request, allow_non_browser_clients) {
+            Ok(origin) => {
+                upgrade_origin = origin;
+                Ok(response)
+            }
+            Err(reason) => {
+                log::warn!("Rejected WebSocket upgrade: {}", reason);
+                let mut rejection = server::ErrorResponse::new(Some(reason));
+                *rejection.status_mut() = StatusCode::FORBIDDEN;
+                Err(rejection)
+            }
+        }
+    };
+    let ws_stream = accept_hdr_async_with_config(stream, check, Some(config)).await?;
+    let (write, mut read) = ws_stream.split();
+    let write = Outgoing {
+        sink: write,
+        encoding: Encoding::Json,
+        timeout: SEND_TIMEOUT,
+    };
 
     // Increment connection count
     {
         let mut count = state.ws_connections.write().await;
         *count += 1;
     }
+    state.notify_status(StatusEvent::Changed);
 
-    // Track if handshake was successful
+    // Track if handshake was successful, and the page it came from
     let mut handshake_complete = false;
+    let mut origin = String::new();
+    let handshake_deadline = tokio::time::sleep(limits.handshake_timeout);
+    tokio::pin!(handshake_deadline);
+    // Set when the agent ends the connection, to tell the client why
+    let mut disconnect = None;
 
     // Channel for simulation results
-    let (sim_tx, mut sim_rx) = mpsc::channel::<String>(1);
-
+    let (sim_tx, sim_rx) = mpsc::channel::<String>(OUTBOUND_CAPACITY);
+    // Everything the connection sends goes through one writer, which numbers the messages
+    let (outbox, outbox_rx) = mpsc::channel::<Outbound>(OUTBOUND_CAPACITY);
+    let writer = tokio::spawn(write_outbound(write, outbox_rx, sim_rx));
+    let mut status_events = state.subscribe_status();
+    // Set when the handshake subscribed the connection to every simulation's messages
+    let mut result_feed: Option<broadcast::Receiver<SharedMessage>> = None;
     loop {
         tokio::select! {
             // Handle incoming WebSocket messages
@@ -62,27 +687,58 @@ async fn handle_connection(
                     Some(Ok(m)) => m,
                     Some(Err(e)) => {
                         log::error!("WebSocket read error: {}", e);
+                        if matches!(e, tungstenite::Error::Capacity(_)) {
+                            disconnect = Some(Disconnect::MessageTooLarge);
+                        }
                         break;
                     }
                     None => break,
                 };
 
-                if let Message::Text(text) = msg {
-                    // Parse the message type first
-                    let generic: GenericMessage = match serde_json::from_str(&text) {
-                        Ok(m) => m,
-                        Err(e) => {
-                            log::error!("Failed to parse message: {}", e);
+                // Binary frames are MessagePack; they are handled as the JSON they transcode to
+                let text = wire::decode(msg).unwrap_or_else(|e| {
+                    log::error!("Failed to decode message: {}", e);
+                    None
+                });
+                if let Some(text) = text {
+                    // Check the message against its type's schema first, so a malformed one gets an
+                    // error naming the field instead of closing the connection
+                    let checked = match validation::parse_message(&text) {
+                        Ok((message, msg_type)) => {
+                            let checked = validation::check(&msg_type, &message, &*state.settings.read().await);
+                            let request_id = message.get("id").and_then(|id| id.as_str()).map(str::to_string);
+                            checked.map(|_| msg_type.clone()).map_err(|invalid| invalid.response(request_id, Some(msg_type)))
+                        }
+                        Err(invalid) => Err(invalid.response(None, None)),
+                    };
+                    let msg_type = match checked {
+                        Ok(msg_type) => msg_type,
+                        Err(rejection) => {
+                            log::warn!("Rejected message: {}", rejection.error);
+                            if outbox.send(Outbound::Message(serde_json::to_string(&rejection)?)).await.is_err() {
+                                break;
+                            }
                             continue;
                         }
                     };
 
-                    let response = match generic.msg_type.as_str() {
+                    let response = match msg_type.as_str() {
                         "handshake" => {
                             let request: HandshakeRequest = serde_json::from_str(&text)?;
-                            let response = handle_handshake(&request, &state).await;
+                            let response = handle_handshake(&request, &state, upgrade_origin.as_deref()).await;
                             handshake_complete = response.success;
-                            Some(serde_json::to_string(&response)?)
+                            origin = request.origin;
+                            result_feed = response.subscribed_results.then(|| state.result_feed.subscribe());
+                            // Sent as JSON so any client can read which encoding was agreed
+                            let reply = Outbound::Handshake(serde_json::to_string(&response)?, response.encoding);
+                            if outbox.send(reply).await.is_err() {
+                                break;
+                            }
+                            if !response.success {
+                                disconnect = Some(Disconnect::OriginRejected);
+                                break;
+                            }
+                            continue;
                         }
                         "simulate" => {
                             if !handshake_complete {
@@ -90,36 +746,119 @@ async fn handle_connection(
                                 continue;
                             }
                             let request: SimulationRequest = serde_json::from_str(&text)?;
-
-                            // Send progress update
-                            let progress = SimulationProgress {
-                                id: uuid::Uuid::new_v4().to_string(),
-                                msg_type: "simulation_progress".to_string(),
-                                request_id: request.id.clone(),
-                                timestamp: now_ms(),
-                                stage: "preparing".to_string(),
-                                message: "Preparing simulation...".to_string(),
+                            start_simulation(request, &state, &origin, &sim_tx).await?
+                        }
+                        "rerun_simulation" => {
+                            if !handshake_complete {
+                                log::warn!("Rerun request before handshake");
+                                continue;
+                            }
+                            let rerun: RerunSimulationRequest = serde_json::from_str(&text)?;
+                            let replay = state.history.read().await.replay_request(&rerun.history_id);
+                            match replay {
+                                // The replay runs under the rerun message's id so its result can be matched
+                                Ok(request) => {
+                                    log::info!("Replaying simulation {} as {}", rerun.history_id, rerun.id);
+                                    let request = SimulationRequest { id: rerun.id, ..request };
+                                    start_simulation(request, &state, &origin, &sim_tx).await?
+                                }
+                                Err(error) => Some(serde_json::to_string(&replay_failed_response(&rerun, error))?),
+                            }
+                        }
+                        "simulate_template" => {
+                            if !handshake_complete {
+                                log::warn!("Template simulation request before handshake");
+                                continue;
+                            }
+                            let message: serde_json::Value = serde_json::from_str(&text)?;
+                            let request: SimulateTemplateRequest = serde_json::from_value(message.clone())?;
+                            let filled = state.templates.write().await.fill(&origin, &request.template, &request.values);
+                            match filled {
+                                Ok(netlist) => {
+                                    let request = templates::simulate_request(&message, netlist)?;
+                                    start_simulation(request, &state, &origin, &sim_tx).await?
+                                }
+                                Err(error) => Some(serde_json::to_string(&template_failed_response(&request.id, error))?),
+                            }
+                        }
+                        "register_template" => {
+                            if !handshake_complete {
+                                log::warn!("Template registration before handshake");
+                                continue;
+                            }
+                            let request: RegisterTemplateRequest = serde_json::from_str(&text)?;
+                            let registered = state.templates.write().await.register(&origin, &request.name, &request.netlist);
+                            Some(serde_json::to_string(&template_response(&request.id, registered.map(|info| vec![info])))?)
+                        }
+                        "delete_template" => {
+                            if !handshake_complete {
+                                log::warn!("Template deletion before handshake");
+                                continue;
+                            }
+                            let request: DeleteTemplateRequest = serde_json::from_str(&text)?;
+                            let deleted = state.templates.write().await.delete(&origin, &request.name);
+                            let result = if deleted {
+                                Ok(Vec::new())
+                            } else {
+                                Err(TemplateError {
+                                    code: "TEMPLATE_NOT_FOUND",
+                                    message: format!("No template named {} is registered", request.name),
+                                })
                             };
-                            write.send(Message::Text(serde_json::to_string(&progress)?)).await?;
+                            Some(serde_json::to_string(&template_response(&request.id, result))?)
+                        }
+                        "list_templates" => {
+                            if !handshake_complete {
+                                log::warn!("Template list request before handshake");
+                                continue;
+                            }
+                            let request: ListTemplatesRequest = serde_json::from_str(&text)?;
+                            let listed = state.templates.write().await.list(&origin);
+                            Some(serde_json::to_string(&template_response(&request.id, Ok(listed)))?)
+                        }
+                        "batch_simulate" => {
+                            if !handshake_complete {
+                                log::warn!("Batch simulation request before handshake");
+                                continue;
+                            }
+                            let mut request: BatchSimulationRequest = serde_json::from_str(&text)?;
+                            request.origin = Some(origin.clone());
+                            if let Err(retry_after_ms) = check_rate_limit(&state, &origin, &request.id).await {
+                                let response = rate_limited_batch_response(&request, retry_after_ms);
+                                if outbox.send(Outbound::Message(serde_json::to_string(&response)?)).await.is_err() {
+                                    break;
+                                }
+                                continue;
+                            }
 
-                            // Spawn simulation in a separate task so we can process cancel messages
+                            // Spawn the batch so cancel messages are still processed; per-corner
+                            // progress updates and the final response both arrive via sim_rx
                             let state_clone = state.clone();
                             let sim_tx_clone = sim_tx.clone();
+                            let origin = origin.clone();
                             tokio::spawn(async move {
-                                let response = handle_simulate(&request, &state_clone).await;
-                                let _ = sim_tx_clone.send(serde_json::to_string(&response).unwrap_or_default()).await;
+                                state_clone.metrics.write().await.record_origin_started(&origin);
+                                let (progress_tx, relay) = simulation::relay_progress(Arc::new(Listeners::new(&sim_tx_clone)), None);
+                                let response = simulation::execute_batch(&request, &state_clone, &progress_tx).await;
+                                drop(progress_tx);
+                                simulation::finish_relay(&state_clone, &request.id, relay).await;
+                                let json = serde_json::to_string(&response).unwrap_or_default();
+                                state_clone.metrics.write().await.record_origin_finished(&origin, response.success, json.len());
+                                send_result(&state_clone, &sim_tx_clone, json).await;
                             });
-                            None // Don't send response immediately, it will come via sim_rx
+                            None
                         }
                         "ping" => {
                             let _request: PingMessage = serde_json::from_str(&text)?;
-                            let is_sim = *state.is_simulating.read().await;
-                            let response = PongResponse {
-                                id: uuid::Uuid::new_v4().to_string(),
-                                msg_type: "pong".to_string(),
-                                timestamp: now_ms(),
-                                status: if is_sim { "busy" } else { "ready" }.to_string(),
-                            };
+                            Some(serde_json::to_string(&handle_ping(&state).await)?)
+                        }
+                        "confirm" => {
+                            if !handshake_complete {
+                                log::warn!("Confirm request before handshake");
+                                continue;
+                            }
+                            let request: ConfirmRequest = serde_json::from_str(&text)?;
+                            let response = handle_confirm(&request, &state).await;
                             Some(serde_json::to_string(&response)?)
                         }
                         "cancel" => {
@@ -127,51 +866,210 @@ async fn handle_connection(
                             let response = handle_cancel(&request, &state).await;
                             Some(serde_json::to_string(&response)?)
                         }
+                        "analyze_netlist" => {
+                            if !handshake_complete {
+                                log::warn!("Netlist analysis request before handshake");
+                                continue;
+                            }
+                            let request: AnalyzeNetlistRequest = serde_json::from_str(&text)?;
+                            Some(serde_json::to_string(&handle_analyze_netlist(&request))?)
+                        }
                         "list_libraries" => {
                             let request: ListLibrariesRequest = serde_json::from_str(&text)?;
-                            let response = handle_list_libraries(&request).await;
+                            let response = handle_list_libraries(&request, &state).await;
+                            Some(serde_json::to_string(&response)?)
+                        }
+                        "export" => {
+                            if !handshake_complete {
+                                log::warn!("Export request before handshake");
+                                continue;
+                            }
+                            let request: ExportRequest = serde_json::from_str(&text)?;
+                            let response = serde_json::to_string(&handle_export(&request, &state).await)?;
+                            state.metrics.write().await.record_bytes_served(response.len());
+                            Some(response)
+                        }
+                        "get_metrics" => {
+                            if !handshake_complete {
+                                log::warn!("Metrics request before handshake");
+                                continue;
+                            }
+                            let request: MetricsRequest = serde_json::from_str(&text)?;
+                            let response = MetricsResponse {
+                                id: uuid::Uuid::new_v4().to_string(),
+                                msg_type: "metrics".to_string(),
+                                request_id: request.id,
+                                timestamp: now_ms(),
+                                metrics: state.metrics.read().await.snapshot(),
+                            };
                             Some(serde_json::to_string(&response)?)
                         }
+                        "post_process" => {
+                            if !handshake_complete {
+                                log::warn!("Post-process request before handshake");
+                                continue;
+                            }
+                            let request: PostProcessRequest = serde_json::from_str(&text)?;
+                            let response = serde_json::to_string(&handle_post_process(&request, &state).await)?;
+                            state.metrics.write().await.record_bytes_served(response.len());
+                            Some(response)
+                        }
+                        "get_status" => {
+                            if !handshake_complete {
+                                log::warn!("Status request before handshake");
+                                continue;
+                            }
+                            let request: StatusRequest = serde_json::from_str(&text)?;
+                            Some(serde_json::to_string(&status::status_response(&state, request.id).await)?)
+                        }
+                        "get_result" => {
+                            if !handshake_complete {
+                                log::warn!("Result request before handshake");
+                                continue;
+                            }
+                            let request: GetResultRequest = serde_json::from_str(&text)?;
+                            let response = serde_json::to_string(&handle_get_result(&request, &state, &sim_tx).await)?;
+                            state.metrics.write().await.record_bytes_served(response.len());
+                            Some(response)
+                        }
+                        "get_artifact" => {
+                            if !handshake_complete {
+                                log::warn!("Artifact request before handshake");
+                                continue;
+                            }
+                            let request: GetArtifactRequest = serde_json::from_str(&text)?;
+                            let response = serde_json::to_string(&handle_get_artifact(&request, &state).await)?;
+                            state.metrics.write().await.record_bytes_served(response.len());
+                            Some(response)
+                        }
+                        "get_trace_data" => {
+                            if !handshake_complete {
+                                log::warn!("Trace data request before handshake");
+                                continue;
+                            }
+                            let request: GetTraceDataRequest = serde_json::from_str(&text)?;
+                            let response = serde_json::to_string(&handle_get_trace_data(&request, &state).await)?;
+                            state.metrics.write().await.record_bytes_served(response.len());
+                            Some(response)
+                        }
+                        "redetect_simulators" => {
+                            if !handshake_complete {
+                                log::warn!("Redetect request before handshake");
+                                continue;
+                            }
+                            let request: RedetectSimulatorsRequest = serde_json::from_str(&text)?;
+                            detection::redetect(&state).await;
+                            Some(serde_json::to_string(&capabilities_message(&state, Some(request.id)).await)?)
+                        }
+                        "self_test" => {
+                            if !handshake_complete {
+                                log::warn!("Self test request before handshake");
+                                continue;
+                            }
+                            let request: SelfTestRequest = serde_json::from_str(&text)?;
+                            // Spawned so cancel and status messages are still processed meanwhile
+                            let state_clone = state.clone();
+                            let sim_tx_clone = sim_tx.clone();
+                            tokio::spawn(async move {
+                                let response = self_test::run(&state_clone, request.id).await;
+                                let json = serde_json::to_string(&response).unwrap_or_default();
+                                send_result(&state_clone, &sim_tx_clone, json).await;
+                            });
+                            None
+                        }
                         _ => {
-                            log::warn!("Unknown message type: {}", generic.msg_type);
+                            log::warn!("Unknown message type: {}", msg_type);
                             continue;
                         }
                     };
 
                     if let Some(response) = response {
-                        if let Err(e) = write.send(Message::Text(response)).await {
-                            log::error!("Failed to send response: {}", e);
+                        if outbox.send(Outbound::Message(response)).await.is_err() {
                             break;
                         }
                     }
                 }
             }
 
-            // Handle simulation results from spawned tasks
-            Some(response) = sim_rx.recv() => {
-                if let Err(e) = write.send(Message::Text(response)).await {
-                    log::error!("Failed to send response: {}", e);
-                    break;
+            // The writer stops when a send fails, which ends the connection
+            _ = outbox.closed() => break,
+
+            // Tell the client when a simulator appears or disappears; a lagged receiver may have missed it
+            event = status_events.recv() => {
+                let capabilities_changed = matches!(event, Ok(StatusEvent::CapabilitiesChanged) | Err(RecvError::Lagged(_)));
+                if handshake_complete && capabilities_changed {
+                    let message = serde_json::to_string(&capabilities_message(&state, None).await)?;
+                    if outbox.send(Outbound::Message(message)).await.is_err() {
+                        break;
+                    }
+                }
+            }
+
+            // Another connection's simulation, for a connection subscribed to every result
+            shared = next_shared(&mut result_feed) => match shared {
+                Ok(message) => {
+                    let allowed = state.settings.read().await.allow_result_subscriptions;
+                    if allowed && !message.reaches(&sim_tx) && outbox.send(Outbound::Message(message.json.to_string())).await.is_err() {
+                        break;
+                    }
                 }
+                Err(RecvError::Lagged(missed)) => log::warn!("Subscribed connection from {} missed {} shared messages", origin, missed),
+                Err(RecvError::Closed) => result_feed = None,
+            },
+
+            _ = &mut handshake_deadline, if !handshake_complete => {
+                log::warn!("No handshake within {} s", limits.handshake_timeout.as_secs());
+                disconnect = Some(Disconnect::HandshakeTimeout);
+                break;
+            }
+
+            // Tell the client why the connection is going away, then close it properly
+            _ = state.wait_for_shutdown() => {
+                let notice = AgentShutdown {
+                    id: uuid::Uuid::new_v4().to_string(),
+                    msg_type: "agent_shutdown".to_string(),
+                    timestamp: now_ms(),
+                    reason: "The agent is quitting".to_string(),
+                };
+                let _ = outbox.send(Outbound::Message(serde_json::to_string(&notice).unwrap_or_default())).await;
+                disconnect = Some(Disconnect::ShuttingDown);
+                break;
             }
         }
     }
 
+    // The writer sends what is still queued, then hands the socket back for the close frame
+    drop(outbox);
+    if let (Ok(mut write), Some(cause)) = (writer.await, disconnect) {
+        close(&mut write, &mut read, cause).await;
+    }
+
     // Decrement connection count
     {
         let mut count = state.ws_connections.write().await;
         *count = count.saturating_sub(1);
     }
+    state.notify_status(StatusEvent::Changed);
 
     log::info!("Connection closed");
     Ok(())
 }
 
 /// Handle handshake request
-async fn handle_handshake(request: &HandshakeRequest, state: &AppState) -> HandshakeResponse {
+/// `upgrade_origin` is the `Origin` header the connection was opened with, if it had one
+async fn handle_handshake(request: &HandshakeRequest, state: &AppState, upgrade_origin: Option<&str>) -> HandshakeResponse {
+    let max_simulation_time = state.settings.read().await.max_simulation_seconds;
+
     // Validate origin
-    if !is_origin_allowed(&request.origin) {
-        log::warn!("Rejected connection from origin: {}", request.origin);
+    let rejection = if !is_origin_allowed(&request.origin) {
+        Some("Invalid origin")
+    } else if upgrade_origin.is_some_and(|header| header != request.origin) {
+        Some("Origin does not match the connection's Origin header")
+    } else {
+        None
+    };
+    if let Some(error) = rejection {
+        log::warn!("Rejected connection from origin: {} ({})", request.origin, error);
         return HandshakeResponse {
             id: uuid::Uuid::new_v4().to_string(),
             msg_type: "handshake_response".to_string(),
@@ -184,19 +1082,30 @@ async fn handle_handshake(request: &HandshakeRequest, state: &AppState) -> Hands
                 ltspice_available: false,
                 ngspice_available: false,
                 supported_analyses: vec![],
-                max_simulation_time: 120,
+                analyses: vec![],
+                supported_input_formats: vec![],
+                max_simulation_time,
+                max_batch_size: MAX_BATCH_SIZE,
+                engine_options: vec![],
+                accepts_compressed_netlist: true,
             },
-            error: Some("Invalid origin".to_string()),
+            onboarding: None,
+            encoding: Encoding::Json,
+            subscribed_results: false,
+            error: Some(error.to_string()),
         };
     }
 
     let ltspice_path = state.ltspice_path.read().await.clone();
-    let ltspice_available = ltspice_path.is_some();
     let ngspice_path = state.ngspice_path.read().await.clone();
-    let ngspice_available = ngspice_path.is_some();
+    let capabilities = capabilities(ltspice_path.as_deref(), ngspice_path.is_some(), max_simulation_time);
 
     log::info!("Handshake successful from: {} (LTspice: {}, ngspice: {})",
-               request.origin, ltspice_available, ngspice_available);
+               request.origin, capabilities.ltspice_available, capabilities.ngspice_available);
+    let subscribed_results = request.subscribe_results && state.settings.read().await.allow_result_subscriptions;
+    if request.subscribe_results && !subscribed_results {
+        log::info!("Result subscription from {} refused: allow_result_subscriptions is off", request.origin);
+    }
 
     HandshakeResponse {
         id: uuid::Uuid::new_v4().to_string(),
@@ -206,271 +1115,320 @@ async fn handle_handshake(request: &HandshakeRequest, state: &AppState) -> Hands
         agent_version: AGENT_VERSION.to_string(),
         ltspice_path,
         ngspice_path,
-        capabilities: AgentCapabilities {
-            ltspice_available,
-            ngspice_available,
-            supported_analyses: vec![
-                "transient".to_string(),
-                "ac".to_string(),
-                "dc".to_string(),
-            ],
-            max_simulation_time: 120,
-        },
+        capabilities,
+        onboarding: Some(state.onboarding.read().await.clone()),
+        encoding: request.encoding,
+        subscribed_results,
         error: None,
     }
 }
 
-/// Handle simulation request
-async fn handle_simulate(request: &SimulationRequest, state: &AppState) -> SimulationResponse {
-    let start_time = std::time::Instant::now();
-    let simulator_type = request.simulator.as_str();
+fn capabilities(ltspice_path: Option<&str>, ngspice_available: bool, max_simulation_time: u64) -> AgentCapabilities {
+    let ltspice_available = ltspice_path.is_some();
+    let analyses = simulator::capabilities_for(ltspice_available, ngspice_available);
+    // Older clients only read the flat list of analyses some detected engine can run
+    let supported_analyses = analyses
+        .iter()
+        .filter(|analysis| !analysis.simulators.is_empty())
+        .map(|analysis| analysis.name.clone())
+        .collect();
+    // Schematics are converted to netlists by LTspice itself
+    let mut supported_input_formats = vec!["netlist".to_string()];
+    if ltspice_available {
+        supported_input_formats.push("asc".to_string());
+    }
 
-    // Check if already simulating
-    {
-        let is_sim = *state.is_simulating.read().await;
-        if is_sim {
-            return SimulationResponse {
-                id: uuid::Uuid::new_v4().to_string(),
-                msg_type: "simulation_result".to_string(),
-                request_id: request.id.clone(),
-                timestamp: now_ms(),
-                success: false,
-                results: None,
-                error: Some("Another simulation is already running".to_string()),
-                execution_time: 0,
-                simulator: simulator_type.to_string(),
-            };
-        }
+    AgentCapabilities {
+        ltspice_available,
+        ngspice_available,
+        supported_analyses,
+        analyses,
+        supported_input_formats,
+        max_simulation_time,
+        max_batch_size: MAX_BATCH_SIZE,
+        engine_options: ltspice_path.map(simulator::ltspice_engine_options).unwrap_or_default(),
+        accepts_compressed_netlist: true,
     }
+}
 
-    // Get simulator path based on requested type
-    let (simulator_path, simulator_name) = match simulator_type {
-        "ngspice" => {
-            let ngspice_path = state.ngspice_path.read().await.clone();
-            match ngspice_path {
-                Some(p) => (p, "ngspice"),
-                None => {
-                    return SimulationResponse {
-                        id: uuid::Uuid::new_v4().to_string(),
-                        msg_type: "simulation_result".to_string(),
-                        request_id: request.id.clone(),
-                        timestamp: now_ms(),
-                        success: false,
-                        results: None,
-                        error: Some("ngspice not found on this system. Install ngspice via Homebrew (brew install ngspice) or from ngspice.sourceforge.io".to_string()),
-                        execution_time: 0,
-                        simulator: "ngspice".to_string(),
-                    };
-                }
-            }
-        }
-        _ => {
-            // Default to LTspice
-            let ltspice_path = state.ltspice_path.read().await.clone();
-            match ltspice_path {
-                Some(p) => (p, "ltspice"),
-                None => {
-                    return SimulationResponse {
-                        id: uuid::Uuid::new_v4().to_string(),
-                        msg_type: "simulation_result".to_string(),
-                        request_id: request.id.clone(),
-                        timestamp: now_ms(),
-                        success: false,
-                        results: None,
-                        error: Some("LTspice not found on this system".to_string()),
-                        execution_time: 0,
-                        simulator: "ltspice".to_string(),
-                    };
-                }
-            }
-        }
-    };
+/// Current simulator availability, unsolicited or in reply to `redetect_simulators`
+async fn capabilities_message(state: &AppState, request_id: Option<String>) -> CapabilitiesChanged {
+    let ltspice_path = state.ltspice_path.read().await.clone();
+    let ngspice_path = state.ngspice_path.read().await.clone();
+    CapabilitiesChanged {
+        id: uuid::Uuid::new_v4().to_string(),
+        msg_type: "capabilities_changed".to_string(),
+        request_id,
+        timestamp: now_ms(),
+        capabilities: capabilities(
+            ltspice_path.as_deref(),
+            ngspice_path.is_some(),
+            state.settings.read().await.max_simulation_seconds,
+        ),
+        ltspice_path,
+        ngspice_path,
+        onboarding: state.onboarding.read().await.clone(),
+        checked_at: *state.simulators_checked_at.read().await,
+    }
+}
 
-    log::info!("Running simulation with {} at: {}", simulator_name, simulator_path);
+/// Handle cancel request
+async fn handle_cancel(request: &CancelRequest, state: &AppState) -> CancelResponse {
+    let outcome = simulation::cancel(&request.request_id, state).await;
 
-    // Mark as simulating and set current simulation ID
-    {
-        let mut is_sim = state.is_simulating.write().await;
-        *is_sim = true;
-        let mut current_id = state.current_simulation_id.write().await;
-        *current_id = Some(request.id.clone());
-        state.cancel_requested.store(false, Ordering::SeqCst);
-        state.current_process_id.store(0, Ordering::SeqCst);
+    CancelResponse {
+        id: uuid::Uuid::new_v4().to_string(),
+        msg_type: "cancel_response".to_string(),
+        request_id: request.request_id.clone(),
+        timestamp: now_ms(),
+        success: outcome.success(),
+        reason: outcome.reason().map(str::to_string),
     }
+}
 
-    // Run simulation with the appropriate simulator
-    let result = match simulator_name {
-        "ngspice" => {
-            simulator::run_ngspice_simulation(
-                &simulator_path,
-                &request.netlist,
-                &request.waveform_quality,
-                Some(state.current_process_id.clone()),
-            )
-            .await
-        }
-        _ => {
-            simulator::run_ltspice_simulation(
-                &simulator_path,
-                &request.netlist,
-                &request.waveform_quality,
-                Some(state.current_process_id.clone()),
-            )
-            .await
-        }
-    };
+/// Handle ping: busy state plus the progress of the longest-running simulation
+async fn handle_ping(state: &AppState) -> PongResponse {
+    let active = state.active_simulation_statuses().await;
+    let waiting = state.pending_confirmation_count().await;
+    let current = active.first();
 
-    // Check if cancelled
-    let was_cancelled = state.cancel_requested.load(Ordering::SeqCst);
+    PongResponse {
+        id: uuid::Uuid::new_v4().to_string(),
+        msg_type: "pong".to_string(),
+        timestamp: now_ms(),
+        status: if state.is_busy() { "busy" } else { "ready" }.to_string(),
+        current_request_id: current.map(|sim| sim.request_id.clone()),
+        progress_percent: current.and_then(|sim| sim.progress_percent),
+        elapsed_ms: current.map(|sim| sim.elapsed_ms),
+        queue_length: (current.is_some() || waiting > 0).then(|| (active.len().saturating_sub(1) + waiting) as u32),
+    }
+}
 
-    // Mark as not simulating and clear current simulation ID
-    {
-        let mut is_sim = state.is_simulating.write().await;
-        *is_sim = false;
-        let mut current_id = state.current_simulation_id.write().await;
-        *current_id = None;
-        state.cancel_requested.store(false, Ordering::SeqCst);
-        state.current_process_id.store(0, Ordering::SeqCst);
+/// Handle confirm request: start a simulation held by a long-run warning
+async fn handle_confirm(request: &ConfirmRequest, state: &AppState) -> ConfirmResponse {
+    let success = state.resolve_confirmation(&request.request_id, true).await;
+    if !success {
+        log::warn!("Confirm request for {} but it is not waiting for confirmation", request.request_id);
     }
 
-    // If cancelled, return cancelled error
-    if was_cancelled {
-        return SimulationResponse {
-            id: uuid::Uuid::new_v4().to_string(),
-            msg_type: "simulation_result".to_string(),
-            request_id: request.id.clone(),
-            timestamp: now_ms(),
-            success: false,
-            results: None,
-            error: Some("Simulation cancelled".to_string()),
-            execution_time: start_time.elapsed().as_millis() as u64,
-            simulator: simulator_name.to_string(),
-        };
+    ConfirmResponse {
+        id: uuid::Uuid::new_v4().to_string(),
+        msg_type: "confirm_response".to_string(),
+        request_id: request.request_id.clone(),
+        timestamp: now_ms(),
+        success,
     }
+}
 
-    let execution_time = start_time.elapsed().as_millis() as u64;
+/// Handle export request: CSV text of the last results, if small enough for one message
+async fn handle_export(request: &ExportRequest, state: &AppState) -> ExportResponse {
+    let results = state.last_results.write().await.get();
+    let (csv, error, error_code) = match results {
+        None => (None, Some("No simulation results to export".to_string()), Some("NO_RESULTS")),
+        Some(results) if export::results_size_bytes(&results) > MAX_WS_EXPORT_BYTES => (
+            None,
+            Some("Results are too large to export over the connection; export them from the agent window".to_string()),
+            Some("EXPORT_TOO_LARGE"),
+        ),
+        Some(results) => (Some(export::to_csv_string(&results)), None, None),
+    };
 
-    match result {
-        Ok(results) => {
-            log::info!(
-                "Simulation completed with {}: {} traces, {} points",
-                simulator_name,
-                results.traces.len(),
-                results.time.len()
-            );
+    ExportResponse {
+        id: uuid::Uuid::new_v4().to_string(),
+        msg_type: "export_response".to_string(),
+        request_id: request.id.clone(),
+        timestamp: now_ms(),
+        success: csv.is_some(),
+        csv,
+        error,
+        error_code: error_code.map(str::to_string),
+    }
+}
 
-            // Update simulation stats
-            {
-                let mut count = state.simulation_count.write().await;
-                *count += 1;
-                let mut last_time = state.last_simulation_time.write().await;
-                *last_time = Some(now_ms());
-            }
+/// Handle get_result request: the stored outcome, or attach this connection to the running simulation
+async fn handle_get_result(request: &GetResultRequest, state: &AppState, sim_tx: &mpsc::Sender<String>) -> GetResultResponse {
+    let seen = state.recent_requests.write().await.lookup(&request.request_id, sim_tx);
+    let (status, result, error) = match seen {
+        Some(Seen::Finished(response)) => ("complete", Some((*response).clone()), None),
+        Some(Seen::Attached) | Some(Seen::New(_)) => ("running", None, None),
+        None => (
+            "not_found",
+            None,
+            Some(format!("No stored result for simulation {}", request.request_id)),
+        ),
+    };
 
-            SimulationResponse {
-                id: uuid::Uuid::new_v4().to_string(),
-                msg_type: "simulation_result".to_string(),
-                request_id: request.id.clone(),
-                timestamp: now_ms(),
-                success: true,
-                results: Some(results),
-                error: None,
-                execution_time,
-                simulator: simulator_name.to_string(),
-            }
-        }
-        Err(e) => {
-            log::error!("Simulation failed with {}: {}", simulator_name, e);
-            SimulationResponse {
-                id: uuid::Uuid::new_v4().to_string(),
-                msg_type: "simulation_result".to_string(),
-                request_id: request.id.clone(),
-                timestamp: now_ms(),
-                success: false,
-                results: None,
-                error: Some(e.to_string()),
-                execution_time,
-                simulator: simulator_name.to_string(),
-            }
-        }
+    GetResultResponse {
+        id: uuid::Uuid::new_v4().to_string(),
+        msg_type: "get_result_response".to_string(),
+        request_id: request.request_id.clone(),
+        timestamp: now_ms(),
+        status: status.to_string(),
+        result,
+        error,
     }
 }
 
-/// Handle cancel request
-async fn handle_cancel(request: &CancelRequest, state: &AppState) -> CancelResponse {
-    let current_id = state.current_simulation_id.read().await.clone();
-
-    // Check if we're cancelling the right simulation
-    let success = if let Some(current) = current_id {
-        if current == request.request_id {
-            // Set cancel flag
-            state.cancel_requested.store(true, Ordering::SeqCst);
-            log::info!("Cancel requested for simulation: {}", request.request_id);
-
-            // Try to kill the LTspice process
-            let pid = state.current_process_id.load(Ordering::SeqCst);
-            if pid != 0 {
-                log::info!("Attempting to kill LTspice process with PID: {}", pid);
-                kill_process(pid);
+/// Handle get_artifact request: a raw file kept by a simulation that set `keepRaw`
+async fn handle_get_artifact(request: &GetArtifactRequest, state: &AppState) -> ArtifactResponse {
+    let artifact = state.artifacts.write().await.get(&request.artifact_id);
+    let content = match &artifact {
+        None => Err((format!("No kept raw file with id {}", request.artifact_id), "ARTIFACT_NOT_FOUND")),
+        Some(artifact) if artifact.size > MAX_WS_ARTIFACT_BYTES => Err((
+            "The raw file is too large to fetch over the connection; save it from the agent window".to_string(),
+            "ARTIFACT_TOO_LARGE",
+        )),
+        Some(artifact) => {
+            let path = artifact.path.clone();
+            let read = tokio::task::spawn_blocking(move || std::fs::read(path).map_err(|e| e.to_string())).await;
+            match read.unwrap_or_else(|e| Err(e.to_string())) {
+                Ok(bytes) => Ok(BASE64_STANDARD.encode(bytes)),
+                Err(e) => Err((format!("Failed to read the raw file: {}", e), "ARTIFACT_NOT_FOUND")),
             }
-
-            true
-        } else {
-            log::warn!(
-                "Cancel request for {} but current simulation is {}",
-                request.request_id,
-                current
-            );
-            false
         }
-    } else {
-        log::warn!("Cancel request but no simulation running");
-        false
+    };
+    let (content_base64, error, error_code) = match content {
+        Ok(content) => (Some(content), None, None),
+        Err((error, error_code)) => (None, Some(error), Some(error_code.to_string())),
     };
 
-    CancelResponse {
+    ArtifactResponse {
         id: uuid::Uuid::new_v4().to_string(),
-        msg_type: "cancel_response".to_string(),
-        request_id: request.request_id.clone(),
+        msg_type: "artifact".to_string(),
+        request_id: request.id.clone(),
         timestamp: now_ms(),
-        success,
+        success: content_base64.is_some(),
+        artifact_id: request.artifact_id.clone(),
+        file_name: artifact.as_ref().map(|artifact| artifact.file_name()),
+        size: artifact.as_ref().map(|artifact| artifact.size),
+        content_base64,
+        error,
+        error_code,
     }
 }
 
-/// Kill a process by PID
-fn kill_process(pid: u32) {
-    #[cfg(unix)]
-    {
-        use std::process::Command;
-        // Send SIGTERM first, then SIGKILL
-        let _ = Command::new("kill")
-            .arg("-15")  // SIGTERM
-            .arg(pid.to_string())
-            .output();
+/// Handle get_trace_data request: traces of a simulation that set `deferTraceData`
+async fn handle_get_trace_data(request: &GetTraceDataRequest, state: &AppState) -> TraceDataResponse {
+    let results = state.trace_store.write().await.get(&request.simulation_id);
+    let selected = match results {
+        Some(results) => trace_store::select(&results, &request.traces, request.max_points),
+        None => Err((
+            format!("No deferred trace data for simulation {}; it may have expired", request.simulation_id),
+            "TRACE_DATA_NOT_FOUND",
+        )),
+    };
+    let (time, traces, error) = match selected {
+        Ok((time, traces)) => (time, traces, None),
+        Err(error) => (Vec::new(), Vec::new(), Some(error)),
+    };
 
-        // Give it a moment, then force kill
-        std::thread::sleep(std::time::Duration::from_millis(100));
-        let _ = Command::new("kill")
-            .arg("-9")  // SIGKILL
-            .arg(pid.to_string())
-            .output();
+    TraceDataResponse {
+        id: uuid::Uuid::new_v4().to_string(),
+        msg_type: "trace_data".to_string(),
+        request_id: request.id.clone(),
+        timestamp: now_ms(),
+        success: error.is_none(),
+        simulation_id: request.simulation_id.clone(),
+        time,
+        traces,
+        error_code: error.as_ref().map(|(_, code)| code.to_string()),
+        error: error.map(|(message, _)| message),
+    }
+}
 
-        log::info!("Sent kill signals to process {}", pid);
+/// Reply to a template message: the templates it concerns, or why it failed
+fn template_response(request_id: &str, result: Result<Vec<TemplateInfo>, TemplateError>) -> TemplateResponse {
+    let (templates, error) = match result {
+        Ok(templates) => (templates, None),
+        Err(error) => (Vec::new(), Some(error)),
+    };
+    TemplateResponse {
+        id: uuid::Uuid::new_v4().to_string(),
+        msg_type: "template_response".to_string(),
+        request_id: request_id.to_string(),
+        timestamp: now_ms(),
+        success: error.is_none(),
+        templates,
+        error_code: error.as_ref().map(|error| error.code.to_string()),
+        error: error.map(|error| error.message),
     }
+}
 
-    #[cfg(windows)]
-    {
-        use std::process::Command;
-        let _ = Command::new("taskkill")
-            .args(["/F", "/PID", &pid.to_string()])
-            .output();
+/// Results `post_process` works on: a stored simulation's, or the most recent
+async fn post_process_source(request: &PostProcessRequest, state: &AppState) -> Result<Arc<SimulationResults>, (String, &'static str)> {
+    let Some(simulation_id) = &request.simulation_id else {
+        let results = state.last_results.write().await.get();
+        return results.ok_or_else(|| ("No simulation results to post-process".to_string(), "NO_RESULTS"));
+    };
 
-        log::info!("Sent taskkill to process {}", pid);
+    // A deferred response holds only the catalog; its data is in the trace store
+    if let Some(results) = state.trace_store.write().await.get(simulation_id) {
+        return Ok(results);
+    }
+    let stored = state.recent_requests.read().await.stored(simulation_id);
+    let response = match stored {
+        Some(Stored::Finished(response)) => response,
+        Some(Stored::Running) => {
+            return Err((format!("Simulation {} is still running", simulation_id), "RESULT_NOT_READY"));
+        }
+        Some(Stored::Dropped) => {
+            return Err((
+                format!("The results of simulation {} were too large to keep or were evicted to stay within the result budget", simulation_id),
+                "RESULT_NOT_FOUND",
+            ));
+        }
+        None => return Err((format!("No stored result for simulation {}", simulation_id), "RESULT_NOT_FOUND")),
+    };
+    match response.decompressed_results() {
+        Ok(Some(results)) => Ok(Arc::new(results)),
+        Ok(None) => Err((format!("Simulation {} produced no results", simulation_id), "NO_RESULTS")),
+        Err(e) => Err((
+            format!("Could not read the stored results of simulation {}: {}", simulation_id, e),
+            "RESULT_NOT_FOUND",
+        )),
+    }
+}
+
+/// Handle post_process request: new traces computed from existing ones, without resimulating
+async fn handle_post_process(request: &PostProcessRequest, state: &AppState) -> PostProcessResponse {
+    let outcome = post_process_source(request, state).await.and_then(|results| {
+        derived::derive_traces(&results, &request.traces).map(|traces| (results.analysis_type.clone(), traces))
+    });
+    let (analysis_type, traces, error) = match outcome {
+        Ok((analysis_type, traces)) => (Some(analysis_type), traces, None),
+        Err(error) => (None, Vec::new(), Some(error)),
+    };
+
+    PostProcessResponse {
+        id: uuid::Uuid::new_v4().to_string(),
+        msg_type: "post_process_response".to_string(),
+        request_id: request.id.clone(),
+        timestamp: now_ms(),
+        success: error.is_none(),
+        simulation_id: request.simulation_id.clone(),
+        analysis_type,
+        traces,
+        error_code: error.as_ref().map(|(_, code)| code.to_string()),
+        error: error.map(|(message, _)| message),
+    }
+}
+
+/// Handle analyze_netlist request: report what the netlist contains without running it
+fn handle_analyze_netlist(request: &AnalyzeNetlistRequest) -> AnalyzeNetlistResponse {
+    let summary = netlist::parse(&request.netlist);
+    AnalyzeNetlistResponse {
+        id: uuid::Uuid::new_v4().to_string(),
+        msg_type: "analyze_netlist_response".to_string(),
+        request_id: request.id.clone(),
+        timestamp: now_ms(),
+        analyses: summary.analyses,
+        nodes: summary.nodes,
+        components: summary.components,
     }
 }
 
 /// Handle list libraries request
-async fn handle_list_libraries(request: &ListLibrariesRequest) -> ListLibrariesResponse {
+async fn handle_list_libraries(request: &ListLibrariesRequest, state: &AppState) -> ListLibrariesResponse {
     let simulator_type = request.simulator.as_str();
     log::info!("Listing available libraries for {}", simulator_type);
 
@@ -490,6 +1448,15 @@ async fn handle_list_libraries(request: &ListLibrariesRequest) -> ListLibrariesR
 
     log::info!("Found {} libraries for {}", libraries.len(), simulator_type);
 
+    // Only LTspice runs substitute bundled libraries
+    let bundled = match simulator_type {
+        "ngspice" => Vec::new(),
+        _ => {
+            let settings = state.settings.read().await;
+            catalog::statuses(&state.bundled_libraries, &settings.disabled_bundled_libraries)
+        }
+    };
+
     ListLibrariesResponse {
         id: uuid::Uuid::new_v4().to_string(),
         msg_type: "list_libraries_response".to_string(),
@@ -497,6 +1464,854 @@ async fn handle_list_libraries(request: &ListLibrariesRequest) -> ListLibrariesR
         success: true,
         libraries,
         lib_path: lib_path.map(|p| p.to_string_lossy().to_string()),
+        bundled,
         error: None,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_probe_detects_running_agent() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let state = Arc::new(AppState::default());
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(handle_connection(stream, state.clone(), ConnectionLimits::default()));
+            }
+        });
+
+        assert!(probe_agent(&addr).await);
+    }
+
+    #[test]
+    fn test_is_health_request() {
+        assert_eq!(is_health_request(b"GET /health HTTP/1.1\r\nHost: localhost\r\n"), Some(true));
+        assert_eq!(is_health_request(b"GET /health?t=1 HTTP/1.1\r\n"), Some(true));
+        assert_eq!(is_health_request(b"OPTIONS /health HTTP/1.1\r\n"), Some(true));
+        assert_eq!(is_health_request(b"GET / HTTP/1.1\r\nUpgrade: websocket\r\n"), Some(false));
+        assert_eq!(is_health_request(b"POST /health HTTP/1.1\r\n"), Some(false));
+        assert_eq!(is_health_request(b"GET /healthz HTTP/1.1\r\n"), Some(false));
+        assert_eq!(is_health_request(b"GET /hea"), None);
+    }
+
+    #[tokio::test]
+    async fn test_health_check_and_websocket_share_the_port() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let state = Arc::new(AppState::default());
+        let _slot = state.begin_simulation("sim-1", None).await.unwrap();
+        tokio::spawn(serve(listener, state.clone()));
+
+        async fn fetch(addr: std::net::SocketAddr, parts: &[&str]) -> String {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            for part in parts {
+                stream.write_all(part.as_bytes()).await.unwrap();
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await.unwrap();
+            response
+        }
+
+        let response = fetch(addr, &["GET /health HTTP/1.1\r\nHost: localhost\r\nOrigin: https://kelicad.com\r\n\r\n"]).await;
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("HTTP/1.1 200 OK"), "{}", head);
+        assert!(head.contains("Content-Type: application/json"));
+        assert!(head.contains("Access-Control-Allow-Origin: https://kelicad.com"));
+        let health: serde_json::Value = serde_json::from_str(body).unwrap();
+        assert_eq!(health["status"], "ok");
+        assert_eq!(health["agentVersion"], AGENT_VERSION);
+        assert_eq!(health["busy"], true);
+        assert_eq!(health["activeSimulations"], 1);
+
+        // A request line split across packets, from a page that isn't allowed to read the answer
+        let response = fetch(addr, &["GET /hea", "lth HTTP/1.1\r\nOrigin: https://evil.example\r\n\r\n"]).await;
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(!response.contains("Access-Control-Allow-Origin"));
+
+        let response = fetch(addr, &["OPTIONS /health HTTP/1.1\r\nOrigin: https://kelicad.com\r\n\r\n"]).await;
+        assert!(response.starts_with("HTTP/1.1 204 No Content"));
+        assert!(response.contains("Access-Control-Allow-Private-Network: true"));
+
+        // Health checks aren't WebSocket connections, and upgrades work as before
+        assert_eq!(*state.ws_connections.read().await, 0);
+        let (mut ws, _) = tokio_tungstenite::connect_async(browser_request(&addr.to_string(), ALLOWED_ORIGINS[0]).unwrap()).await.unwrap();
+        let handshake = serde_json::json!({
+            "id": "h", "type": "handshake", "origin": "https://kelicad.com", "version": "1.0.0", "timestamp": 0
+        });
+        ws.send(Message::Text(handshake.to_string())).await.unwrap();
+        let Some(Ok(Message::Text(text))) = ws.next().await else {
+            panic!("expected a handshake response");
+        };
+        let response: serde_json::Value = serde_json::from_str(&text).unwrap();
+        assert_eq!(response["type"], "handshake_response");
+        assert_eq!(response["success"], true);
+    }
+
+    #[tokio::test]
+    async fn test_probe_rejects_other_listener() {
+        // Accepts connections but never completes a WebSocket handshake
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                drop(stream);
+            }
+        });
+
+        assert!(!probe_agent(&addr).await);
+    }
+
+    #[tokio::test]
+    async fn test_handshake_reports_max_simulation_time() {
+        let state = AppState::default();
+        state.settings.write().await.max_simulation_seconds = 900;
+        let request = HandshakeRequest {
+            id: "hs-1".to_string(),
+            msg_type: "handshake".to_string(),
+            origin: ALLOWED_ORIGINS[0].to_string(),
+            version: PROTOCOL_VERSION.to_string(),
+            encoding: Encoding::Json,
+            subscribe_results: false,
+            timestamp: 0,
+        };
+
+        let response = handle_handshake(&request, &state, Some(ALLOWED_ORIGINS[0])).await;
+        assert!(response.success);
+        assert_eq!(response.capabilities.max_simulation_time, 900);
+    }
+
+    #[tokio::test]
+    async fn test_send_gives_up_on_a_client_that_stops_reading() {
+        use tokio::io::AsyncReadExt;
+        use tokio_tungstenite::{tungstenite::protocol::Role, WebSocketStream};
+
+        let (server, mut client) = tokio::io::duplex(1024);
+        let mut write = Outgoing {
+            sink: WebSocketStream::from_raw_socket(server, Role::Server, None).await,
+            encoding: Encoding::Json,
+            timeout: Duration::from_millis(500),
+        };
+        let large = format!("\"{}\"", "x".repeat(64 * 1024));
+
+        // A slow reader that keeps reading gets everything
+        let reader = tokio::spawn(async move {
+            let mut buffer = [0; 1024];
+            let mut received = 0;
+            while received < 64 * 1024 {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+                received += client.read(&mut buffer).await.unwrap();
+            }
+            client
+        });
+        write.send(large.clone()).await.unwrap();
+        let _client = reader.await.unwrap();
+
+        // Once it stops, delivery is abandoned after the timeout
+        let error = write.send(large).await.unwrap_err();
+        assert_eq!(error.to_string(), "client stopped reading for 0 s");
+    }
+
+    #[tokio::test]
+    async fn test_simulation_starts_are_rate_limited_per_origin() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let state = Arc::new(AppState::default());
+        state.settings.write().await.rate_limit_per_minute = 1;
+        tokio::spawn({
+            let state = state.clone();
+            async move {
+                let (stream, _) = listener.accept().await.unwrap();
+                let _ = handle_connection(stream, state, ConnectionLimits::default()).await;
+            }
+        });
+        let (mut ws, _) = tokio_tungstenite::connect_async(browser_request(&addr.to_string(), ALLOWED_ORIGINS[0]).unwrap()).await.unwrap();
+        let handshake = serde_json::json!({
+            "id": "hs-1",
+            "type": "handshake",
+            "origin": "https://kelicad.com",
+            "version": PROTOCOL_VERSION,
+            "timestamp": 0,
+        });
+        ws.send(Message::Text(handshake.to_string())).await.unwrap();
+
+        let simulate = |id: &str| serde_json::json!({"id": id, "type": "simulate", "netlist": ".op\n.end", "timestamp": 0});
+        async fn result_for<S>(ws: &mut S, id: &str) -> serde_json::Value
+        where
+            S: futures_util::Stream<Item = Result<Message, tokio_tungstenite::tungstenite::Error>> + Unpin,
+        {
+            loop {
+                let Some(Ok(Message::Text(text))) = ws.next().await else {
+                    panic!("connection closed");
+                };
+                let message: serde_json::Value = serde_json::from_str(&text).unwrap();
+                if message["type"] == "simulation_result" && message["requestId"] == id {
+                    return message;
+                }
+            }
+        }
+        ws.send(Message::Text(simulate("a").to_string())).await.unwrap();
+        result_for(&mut ws, "a").await;
+
+        ws.send(Message::Text(simulate("b").to_string())).await.unwrap();
+        let limited = result_for(&mut ws, "b").await;
+        assert_eq!(limited["errorCode"], "RATE_LIMITED");
+        let retry_after_ms = limited["retryAfterMs"].as_u64().unwrap();
+        assert!(retry_after_ms > 0 && retry_after_ms <= 60_000);
+
+        // Re-sending a request already run is a retry, not a new start
+        ws.send(Message::Text(simulate("a").to_string())).await.unwrap();
+        let replay = result_for(&mut ws, "a").await;
+        assert_ne!(replay["errorCode"], "RATE_LIMITED");
+
+        let origins = state.metrics.read().await.snapshot().origins;
+        assert_eq!(origins.len(), 1);
+        assert_eq!(origins[0].origin, "https://kelicad.com");
+        assert_eq!(origins[0].simulations_started, 1);
+        assert_eq!(origins[0].rate_limited, 1);
+    }
+
+    #[tokio::test]
+    async fn test_msgpack_negotiated_at_handshake() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let _ = handle_connection(stream, Arc::new(AppState::default()), ConnectionLimits::default()).await;
+        });
+        let (mut ws, _) = tokio_tungstenite::connect_async(browser_request(&addr.to_string(), ALLOWED_ORIGINS[0]).unwrap()).await.unwrap();
+
+        let handshake = serde_json::json!({
+            "id": "hs-1",
+            "type": "handshake",
+            "origin": ALLOWED_ORIGINS[0],
+            "version": PROTOCOL_VERSION,
+            "encoding": "msgpack",
+            "timestamp": 0,
+        });
+        ws.send(Message::Text(handshake.to_string())).await.unwrap();
+        let Some(Ok(Message::Text(reply))) = ws.next().await else {
+            panic!("expected a JSON handshake response");
+        };
+        let reply: serde_json::Value = serde_json::from_str(&reply).unwrap();
+        assert_eq!(reply["encoding"], "msgpack");
+
+        // Everything after the handshake is MessagePack, both ways
+        let ping = serde_json::json!({"id": "p-1", "type": "ping", "timestamp": 0});
+        ws.send(Message::Binary(rmp_serde::to_vec(&ping).unwrap())).await.unwrap();
+        let Some(Ok(Message::Binary(reply))) = ws.next().await else {
+            panic!("expected a binary pong");
+        };
+        let reply: serde_json::Value = rmp_serde::from_slice(&reply).unwrap();
+        assert_eq!(reply["type"], "pong");
+    }
+
+    #[tokio::test]
+    async fn test_get_result_after_reconnect() {
+        let state = AppState::default();
+        let request = |request_id: &str| GetResultRequest {
+            id: "get-1".to_string(),
+            msg_type: "get_result".to_string(),
+            request_id: request_id.to_string(),
+            timestamp: 0,
+        };
+
+        // The original connection is gone by the time the simulation finishes
+        let (first_tx, first_rx) = mpsc::channel(1);
+        let Seen::New(_) = state.recent_requests.write().await.begin("sim-1", &first_tx) else {
+            panic!("expected a new run");
+        };
+        drop(first_rx);
+
+        let (second_tx, _second_rx) = mpsc::channel(1);
+        let response = handle_get_result(&request("sim-1"), &state, &second_tx).await;
+        assert_eq!(response.status, "running");
+
+        state.recent_requests.write().await.finish(&SimulationResponse {
+            id: "resp-1".to_string(),
+            msg_type: "simulation_result".to_string(),
+            request_id: "sim-1".to_string(),
+            timestamp: 0,
+            success: true,
+            f32_results: false,
+            results: None,
+            error: None,
+            error_code: None,
+            execution_time: 300_000,
+            simulator: "ngspice".to_string(),
+            monte_carlo: None,
+            duplicate: false,
+            retried_with: None,
+            translations: Vec::new(),
+            debug_info: None,
+            results_compressed: None,
+            compression: None,
+            retry_after_ms: None,
+            warnings: Vec::new(),
+            simulator_version: None,
+            simulator_binary_sha256: None,
+            replay_of: None,
+            fallback_simulator_used: None,
+            engine_errors: Vec::new(),
+            artifact_id: None,
+            raw_file_size: None,
+            trace_catalog: None,
+            operating_point: None,
+            wave_data: None,
+            libraries_used: Vec::new(),
+            busy_info: None,
+            timings: SimulationTimings::default(),
+        });
+        let response = handle_get_result(&request("sim-1"), &state, &second_tx).await;
+        assert_eq!(response.status, "complete");
+        assert_eq!(response.result.unwrap().execution_time, 300_000);
+
+        let response = handle_get_result(&request("sim-2"), &state, &second_tx).await;
+        assert_eq!(response.status, "not_found");
+        assert!(response.error.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_post_process() {
+        let state = AppState::default();
+        let request = |simulation_id: Option<&str>, expression: &str| PostProcessRequest {
+            id: "pp-1".to_string(),
+            msg_type: "post_process".to_string(),
+            timestamp: 0,
+            simulation_id: simulation_id.map(str::to_string),
+            traces: vec![DerivedTrace { name: Some("V(R1)".to_string()), expression: expression.to_string() }],
+        };
+
+        let response = handle_post_process(&request(None, "V(a)-V(b)"), &state).await;
+        assert!(!response.success);
+        assert_eq!(response.error_code.as_deref(), Some("NO_RESULTS"));
+
+        let results = SimulationResults {
+            time: vec![0.0, 1.0],
+            traces: vec![
+                Trace { name: "V(a)".to_string(), data: vec![3.0, 2.0], unit: "V".to_string(), phase: None, raw_name: None },
+                Trace { name: "V(b)".to_string(), data: vec![1.0, 1.0], unit: "V".to_string(), phase: None, raw_name: None },
+            ],
+            analysis_type: "transient".to_string(),
+            x_axis_label: None,
+            x_axis_unit: None,
+            scalar_results: None,
+            device_operating_points: Default::default(),
+            truncated: None,
+            plot_hints: None,
+            raw_header: None,
+        };
+        state.store_results(&results).await;
+        let response = handle_post_process(&request(None, "V(a)-V(b)"), &state).await;
+        assert!(response.success);
+        assert_eq!(response.analysis_type.as_deref(), Some("transient"));
+        assert_eq!(response.traces.len(), 1);
+        assert_eq!(response.traces[0].name, "V(R1)");
+        assert_eq!(response.traces[0].data, vec![2.0, 1.0]);
+
+        let response = handle_post_process(&request(None, "V(a)-V(c)"), &state).await;
+        assert_eq!(response.error_code.as_deref(), Some("UNKNOWN_TRACE"));
+        assert_eq!(response.error.as_deref(), Some("Trace V(c) is not in the results"));
+
+        // A stored result is looked up by id, even one sent compressed
+        let (tx, _rx) = mpsc::channel(1);
+        let _ = state.recent_requests.write().await.begin("sim-1", &tx);
+        let response = handle_post_process(&request(Some("sim-1"), "-V(a)"), &state).await;
+        assert_eq!(response.error_code.as_deref(), Some("RESULT_NOT_READY"));
+
+        let mut finished = SimulationResponse {
+            id: "resp-1".to_string(),
+            msg_type: "simulation_result".to_string(),
+            request_id: "sim-1".to_string(),
+            timestamp: 0,
+            success: true,
+            f32_results: false,
+            results: Some(SimulationResults { time: vec![0.0], traces: vec![results.traces[0].clone()], ..results.clone() }),
+            error: None,
+            error_code: None,
+            execution_time: 10,
+            simulator: "ngspice".to_string(),
+            monte_carlo: None,
+            duplicate: false,
+            retried_with: None,
+            translations: Vec::new(),
+            debug_info: None,
+            results_compressed: None,
+            compression: None,
+            retry_after_ms: None,
+            warnings: Vec::new(),
+            simulator_version: None,
+            simulator_binary_sha256: None,
+            replay_of: None,
+            fallback_simulator_used: None,
+            engine_errors: Vec::new(),
+            artifact_id: None,
+            raw_file_size: None,
+            trace_catalog: None,
+            operating_point: None,
+            wave_data: None,
+            libraries_used: Vec::new(),
+            busy_info: None,
+            timings: SimulationTimings::default(),
+        };
+        finished.results.as_mut().unwrap().traces[0].data = vec![4.0];
+        finished.compress_results().unwrap();
+        state.recent_requests.write().await.finish(&finished);
+        let response = handle_post_process(&request(Some("sim-1"), "-V(a)"), &state).await;
+        assert!(response.success);
+        assert_eq!(response.simulation_id.as_deref(), Some("sim-1"));
+        assert_eq!(response.traces[0].data, vec![-4.0]);
+
+        let response = handle_post_process(&request(Some("sim-2"), "-V(a)"), &state).await;
+        assert_eq!(response.error_code.as_deref(), Some("RESULT_NOT_FOUND"));
+    }
+
+    #[test]
+    fn test_analyze_netlist() {
+        let request = AnalyzeNetlistRequest {
+            id: "an-1".to_string(),
+            msg_type: "analyze_netlist".to_string(),
+            netlist: "* RC\nV1 in 0 AC 1\nR1 in out 1k\nC1 out 0 1u\n.ac dec 10 1 100k\n.end".to_string(),
+            timestamp: 0,
+        };
+
+        let json = serde_json::to_value(handle_analyze_netlist(&request)).unwrap();
+        assert_eq!(json["type"], "analyze_netlist_response");
+        assert_eq!(json["requestId"], "an-1");
+        assert_eq!(json["analyses"][0]["type"], "ac");
+        assert_eq!(json["analyses"][0]["points"], 10);
+        assert_eq!(json["nodes"], serde_json::json!(["in", "0", "out"]));
+        assert_eq!(json["components"], serde_json::json!(["V1", "R1", "C1"]));
+    }
+
+    #[test]
+    fn test_noise_requires_an_engine() {
+        assert!(!capabilities(None, false, 300).supported_analyses.contains(&"noise".to_string()));
+        assert!(capabilities(None, true, 300).supported_analyses.contains(&"noise".to_string()));
+        assert!(capabilities(Some("/opt/ltspice/LTspice.exe"), false, 300).supported_analyses.contains(&"noise".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_ping_reports_current_simulation() {
+        let state = AppState::default();
+        let idle = handle_ping(&state).await;
+        assert_eq!(idle.status, "ready");
+        assert!(idle.current_request_id.is_none());
+        assert!(idle.queue_length.is_none());
+
+        let slot = state.begin_simulation("sim-1", None).await.unwrap();
+        slot.simulation.set_progress(25.0);
+        let _first = state.await_confirmation("sim-2").await;
+        let _second = state.await_confirmation("sim-3").await;
+
+        let pong = handle_ping(&state).await;
+        assert_eq!(pong.current_request_id.as_deref(), Some("sim-1"));
+        assert_eq!(pong.progress_percent, Some(25.0));
+        assert!(pong.elapsed_ms.is_some());
+        assert_eq!(pong.queue_length, Some(2));
+    }
+
+    #[test]
+    fn test_asc_input_requires_ltspice() {
+        assert_eq!(capabilities(None, true, 300).supported_input_formats, ["netlist"]);
+        assert_eq!(capabilities(Some("/opt/ltspice/LTspice.exe"), false, 300).supported_input_formats, ["netlist", "asc"]);
+    }
+
+    #[test]
+    fn test_engine_options_follow_ltspice_build() {
+        assert!(capabilities(None, true, 300).engine_options.is_empty());
+        assert_eq!(
+            capabilities(Some("/opt/ltspice/LTspice.exe"), false, 300).engine_options,
+            ["alternateSolver", "asciiOutput"]
+        );
+        // Native macOS builds have no alternate solver
+        let mac = capabilities(Some("/Applications/LTspice.app/Contents/MacOS/LTspice"), false, 300);
+        assert_eq!(mac.engine_options, ["asciiOutput"]);
+    }
+
+    #[tokio::test]
+    async fn test_describe_start_error_other_failure() {
+        let error: Box<dyn std::error::Error + Send + Sync> =
+            Box::new(std::io::Error::from(std::io::ErrorKind::PermissionDenied));
+        let message = describe_start_error(&*error, WS_PORT).await;
+        assert!(message.starts_with("WebSocket server failed to start"));
+    }
+
+    /// A client connected to a fresh agent connection held to `limits`
+    async fn connect_with(
+        limits: ConnectionLimits,
+    ) -> tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<TcpStream>> {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let _ = handle_connection(stream, Arc::new(AppState::default()), limits).await;
+        });
+        let (ws, _) = tokio_tungstenite::connect_async(browser_request(&addr.to_string(), ALLOWED_ORIGINS[0]).unwrap()).await.unwrap();
+        ws
+    }
+
+    /// Read until the close frame and return its code and reason
+    async fn close_frame<S>(ws: &mut S) -> (u16, String)
+    where
+        S: futures_util::Stream<Item = Result<Message, tungstenite::Error>> + Unpin,
+    {
+        while let Some(message) = ws.next().await {
+            if let Message::Close(Some(frame)) = message.unwrap() {
+                return (frame.code.into(), frame.reason.into_owned());
+            }
+        }
+        panic!("connection ended without a close frame");
+    }
+
+    #[tokio::test]
+    async fn test_messages_are_numbered_in_order() {
+        let mut ws = connect_with(ConnectionLimits::default()).await;
+        let handshake = serde_json::json!({
+            "id": "hs-1",
+            "type": "handshake",
+            "origin": ALLOWED_ORIGINS[0],
+            "version": PROTOCOL_VERSION,
+            "timestamp": 0,
+        });
+        ws.send(Message::Text(handshake.to_string())).await.unwrap();
+
+        // Replies from the connection's loop interleave with results and progress from spawned simulations
+        for i in 0..10 {
+            let simulate = serde_json::json!({"id": format!("s-{}", i), "type": "simulate", "netlist": ".op\n.end", "timestamp": 0});
+            ws.send(Message::Text(simulate.to_string())).await.unwrap();
+            let ping = serde_json::json!({"id": format!("p-{}", i), "type": "ping", "timestamp": 0});
+            ws.send(Message::Text(ping.to_string())).await.unwrap();
+        }
+        let mut seqs = Vec::new();
+        let (mut pongs, mut results) = (0, 0);
+        while pongs < 10 || results < 10 {
+            let Some(Ok(Message::Text(text))) = ws.next().await else {
+                panic!("connection closed");
+            };
+            assert!(text.starts_with("{\"seq\":"), "{}", text);
+            let message: serde_json::Value = serde_json::from_str(&text).unwrap();
+            seqs.push(message["seq"].as_u64().unwrap());
+            match message["type"].as_str().unwrap() {
+                "pong" => pongs += 1,
+                "simulation_result" => results += 1,
+                _ => {}
+            }
+        }
+        assert_eq!(seqs, (1..=seqs.len() as u64).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn test_subscribed_clients_get_every_result() {
+        let state = Arc::new(AppState::default());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server_state = state.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(handle_connection(stream, server_state.clone(), ConnectionLimits::default()));
+            }
+        });
+        let connect = |subscribe: bool| async move {
+            let (mut ws, _) = tokio_tungstenite::connect_async(browser_request(&addr.to_string(), ALLOWED_ORIGINS[0]).unwrap()).await.unwrap();
+            let handshake = serde_json::json!({
+                "id": "hs-1",
+                "type": "handshake",
+                "origin": ALLOWED_ORIGINS[0],
+                "version": PROTOCOL_VERSION,
+                "subscribeResults": subscribe,
+                "timestamp": 0,
+            });
+            ws.send(Message::Text(handshake.to_string())).await.unwrap();
+            let reply = next_json(&mut ws).await;
+            assert_eq!(reply["subscribedResults"], subscribe);
+            ws
+        };
+        let mut requester = connect(false).await;
+        let mut subscriber = connect(true).await;
+        let mut other = connect(false).await;
+
+        let simulate = serde_json::json!({"id": "sim-shared", "type": "simulate", "netlist": ".op\n.end", "timestamp": 0});
+        requester.send(Message::Text(simulate.to_string())).await.unwrap();
+
+        let own = next_result(&mut requester).await;
+        assert_eq!(own["requestId"], "sim-shared");
+        assert!(own.get("sourceOrigin").is_none());
+        let copy = next_result(&mut subscriber).await;
+        assert_eq!(copy["requestId"], "sim-shared");
+        assert_eq!(copy["sourceOrigin"], ALLOWED_ORIGINS[0]);
+
+        // The unsubscribed connection's next message is the reply to its own ping
+        let ping = serde_json::json!({"id": "p-1", "type": "ping", "timestamp": 0});
+        other.send(Message::Text(ping.to_string())).await.unwrap();
+        assert_eq!(next_json(&mut other).await["type"], "pong");
+
+        // A subscriber that starts a run gets its messages once, not a copy as well
+        let simulate = serde_json::json!({"id": "sim-own", "type": "simulate", "netlist": ".op\n.end", "timestamp": 0});
+        subscriber.send(Message::Text(simulate.to_string())).await.unwrap();
+        let own = next_result(&mut subscriber).await;
+        assert_eq!(own["requestId"], "sim-own");
+        assert!(own.get("sourceOrigin").is_none());
+        subscriber.send(Message::Text(ping.to_string())).await.unwrap();
+        loop {
+            let message = next_json(&mut subscriber).await;
+            assert_ne!(message["type"], "simulation_result", "{}", message);
+            if message["type"] == "pong" {
+                break;
+            }
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_simulate_applies_trace_aliases() {
+        use std::os::unix::fs::PermissionsExt;
+
+        // Fake ngspice writing a one-point raw file to the path its deck's `write` names
+        let dir = tempfile::tempdir().unwrap();
+        let ngspice = dir.path().join("ngspice");
+        let script = r#"#!/bin/sh
+raw=$(sed -n 's/^write \([^ ]*\) .*$/\1/p' "$2" | tr -d "'")
+printf 'Title: fake\nPlotname: Transient Analysis\nFlags: real\nNo. Variables: 2\nNo. Points: 1\nVariables:\n\t0\ttime\ttime\n\t1\tv(out)\tvoltage\nValues:\n 0\t0.0\n\t1.0\n' > "$raw"
+"#;
+        std::fs::write(&ngspice, script).unwrap();
+        std::fs::set_permissions(&ngspice, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let state = Arc::new(AppState::default());
+        *state.ngspice_path.write().await = Some(ngspice.to_string_lossy().to_string());
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            handle_connection(stream, state, ConnectionLimits::default()).await
+        });
+        let (mut ws, _) = tokio_tungstenite::connect_async(browser_request(&addr.to_string(), ALLOWED_ORIGINS[0]).unwrap()).await.unwrap();
+        let handshake = serde_json::json!({
+            "id": "hs-1",
+            "type": "handshake",
+            "origin": ALLOWED_ORIGINS[0],
+            "version": PROTOCOL_VERSION,
+            "timestamp": 0,
+        });
+        ws.send(Message::Text(handshake.to_string())).await.unwrap();
+        next_json(&mut ws).await;
+
+        let simulate = serde_json::json!({
+            "id": "sim-aliased",
+            "type": "simulate",
+            "netlist": "V1 out 0 1\nR1 out 0 1k\n.tran 1m\n.end",
+            "simulator": "ngspice",
+            "traceAliases": {"V(OUT)": "Output"},
+            "timestamp": 0,
+        });
+        ws.send(Message::Text(simulate.to_string())).await.unwrap();
+        let result = next_result(&mut ws).await;
+        assert_eq!(result["success"], true, "{}", result);
+        assert_eq!(result["results"]["traces"][0]["name"], "Output");
+        assert_eq!(result["results"]["traces"][0]["rawName"], "v(out)");
+    }
+
+    #[tokio::test]
+    async fn test_result_subscriptions_can_be_turned_off() {
+        let state = AppState::default();
+        state.settings.write().await.allow_result_subscriptions = false;
+        let request = HandshakeRequest {
+            id: "hs-1".to_string(),
+            msg_type: "handshake".to_string(),
+            origin: ALLOWED_ORIGINS[0].to_string(),
+            version: PROTOCOL_VERSION.to_string(),
+            encoding: Encoding::Json,
+            subscribe_results: true,
+            timestamp: 0,
+        };
+        let response = handle_handshake(&request, &state, Some(ALLOWED_ORIGINS[0])).await;
+        assert!(response.success);
+        assert!(!response.subscribed_results);
+
+        state.settings.write().await.allow_result_subscriptions = true;
+        assert!(handle_handshake(&request, &state, Some(ALLOWED_ORIGINS[0])).await.subscribed_results);
+    }
+
+    /// The next `simulation_result` message, skipping progress
+    async fn next_result<S>(ws: &mut S) -> serde_json::Value
+    where
+        S: futures_util::Stream<Item = Result<Message, tungstenite::Error>> + Unpin,
+    {
+        loop {
+            let message = next_json(ws).await;
+            if message["type"] == "simulation_result" {
+                return message;
+            }
+        }
+    }
+
+    /// The next text message, parsed
+    async fn next_json<S>(ws: &mut S) -> serde_json::Value
+    where
+        S: futures_util::Stream<Item = Result<Message, tungstenite::Error>> + Unpin,
+    {
+        let message = tokio::time::timeout(Duration::from_secs(10), ws.next()).await.expect("no message within 10 s");
+        let Some(Ok(Message::Text(text))) = message else {
+            panic!("connection closed");
+        };
+        serde_json::from_str(&text).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_rejected_origin_gets_close_code() {
+        let mut ws = connect_with(ConnectionLimits::default()).await;
+        let handshake = serde_json::json!({
+            "id": "hs-1",
+            "type": "handshake",
+            "origin": "https://evil.example.com",
+            "version": PROTOCOL_VERSION,
+            "timestamp": 0,
+        });
+        ws.send(Message::Text(handshake.to_string())).await.unwrap();
+
+        let Some(Ok(Message::Text(reply))) = ws.next().await else {
+            panic!("expected the handshake response");
+        };
+        assert!(reply.contains("Invalid origin"));
+        assert_eq!(close_frame(&mut ws).await, (4001, "Origin not allowed".to_string()));
+    }
+
+    #[test]
+    fn test_check_upgrade() {
+        let upgrade = |host: &str, origin: Option<&str>| {
+            let mut request = server::Request::builder().uri("/").header(header::HOST, host);
+            if let Some(origin) = origin {
+                request = request.header(header::ORIGIN, origin);
+            }
+            request.body(()).unwrap()
+        };
+
+        let browser = upgrade("127.0.0.1:9347", Some("https://kelicad.com"));
+        assert_eq!(check_upgrade(&browser, false), Ok(Some("https://kelicad.com".to_string())));
+        assert!(check_upgrade(&upgrade("localhost:9347", Some("http://localhost:3000")), false).is_ok());
+        assert!(check_upgrade(&upgrade("[::1]:9347", Some("https://kelicad.com")), false).is_ok());
+
+        let forged = upgrade("127.0.0.1:9347", Some("https://evil.example.com"));
+        assert_eq!(check_upgrade(&forged, true), Err("Origin https://evil.example.com is not allowed".to_string()));
+        let rebound = upgrade("attacker.example.com:9347", Some("https://kelicad.com"));
+        assert!(check_upgrade(&rebound, true).unwrap_err().contains("not this machine"));
+
+        // Tools that send no Origin at all, only when the setting allows them
+        let tool = upgrade("127.0.0.1:9347", None);
+        assert!(check_upgrade(&tool, false).unwrap_err().contains("without an Origin header"));
+        assert_eq!(check_upgrade(&tool, true), Ok(None));
+    }
+
+    #[tokio::test]
+    async fn test_upgrade_checks_origin_header() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let state = Arc::new(AppState::default());
+        tokio::spawn(serve(listener, state.clone()));
+
+        let rejected = |result: Result<_, tungstenite::Error>| match result {
+            Err(tungstenite::Error::Http(response)) => response.status() == StatusCode::FORBIDDEN,
+            _ => false,
+        };
+        let forged = tokio_tungstenite::connect_async(browser_request(&addr, "https://evil.example.com").unwrap()).await;
+        assert!(rejected(forged.map(|_| ())));
+        let tool = tokio_tungstenite::connect_async(format!("ws://{}", addr)).await;
+        assert!(rejected(tool.map(|_| ())));
+
+        async fn handshake<S>(ws: &mut S, origin: &str) -> serde_json::Value
+        where
+            S: futures_util::Sink<Message> + futures_util::Stream<Item = Result<Message, tungstenite::Error>> + Unpin,
+            <S as futures_util::Sink<Message>>::Error: std::fmt::Debug,
+        {
+            let request = serde_json::json!({
+                "id": "hs-1", "type": "handshake", "origin": origin, "version": PROTOCOL_VERSION, "timestamp": 0
+            });
+            ws.send(Message::Text(request.to_string())).await.unwrap();
+            let Some(Ok(Message::Text(reply))) = ws.next().await else {
+                panic!("expected the handshake response");
+            };
+            serde_json::from_str(&reply).unwrap()
+        }
+
+        // The handshake has to claim the origin the browser sent
+        let (mut ws, _) = tokio_tungstenite::connect_async(browser_request(&addr, ALLOWED_ORIGINS[0]).unwrap()).await.unwrap();
+        let reply = handshake(&mut ws, ALLOWED_ORIGINS[2]).await;
+        assert_eq!(reply["success"], false);
+        assert_eq!(reply["error"], "Origin does not match the connection's Origin header");
+
+        // With the setting on, a tool without an Origin header connects and hands over its origin itself
+        state.settings.write().await.allow_non_browser_clients = true;
+        let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{}", addr)).await.unwrap();
+        assert_eq!(handshake(&mut ws, ALLOWED_ORIGINS[2]).await["success"], true);
+    }
+
+    #[tokio::test]
+    async fn test_missing_handshake_gets_close_code() {
+        let mut ws = connect_with(ConnectionLimits {
+            handshake_timeout: Duration::from_millis(100),
+            ..ConnectionLimits::default()
+        })
+        .await;
+        assert_eq!(close_frame(&mut ws).await, (4002, "No handshake received".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_oversized_message_gets_close_code() {
+        let mut ws = connect_with(ConnectionLimits {
+            max_message_bytes: 1024,
+            ..ConnectionLimits::default()
+        })
+        .await;
+        ws.send(Message::Text("x".repeat(2048))).await.unwrap();
+        assert_eq!(close_frame(&mut ws).await, (4003, "Message too large".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_invalid_message_gets_error_and_connection_stays_open() {
+        let mut ws = connect_with(ConnectionLimits::default()).await;
+        let handshake = serde_json::json!({
+            "id": "hs-1",
+            "type": "handshake",
+            "origin": ALLOWED_ORIGINS[0],
+            "version": PROTOCOL_VERSION,
+            "timestamp": 0,
+        });
+        ws.send(Message::Text(handshake.to_string())).await.unwrap();
+        let Some(Ok(Message::Text(_))) = ws.next().await else {
+            panic!("expected the handshake response");
+        };
+
+        async fn reply<S>(ws: &mut S, message: &str) -> serde_json::Value
+        where
+            S: futures_util::Stream<Item = Result<Message, tungstenite::Error>>
+                + Sink<Message, Error = tungstenite::Error>
+                + Unpin,
+        {
+            ws.send(Message::Text(message.to_string())).await.unwrap();
+            let Some(Ok(Message::Text(text))) = ws.next().await else {
+                panic!("connection closed");
+            };
+            serde_json::from_str(&text).unwrap()
+        }
+
+        let simulate = serde_json::json!({"id": "sim-1", "type": "simulate", "netlist": ".op", "timeout": "60", "timestamp": 0});
+        let error = reply(&mut ws, &simulate.to_string()).await;
+        assert_eq!(error["type"], "error");
+        assert_eq!(error["errorCode"], "INVALID_MESSAGE");
+        assert_eq!(error["requestId"], "sim-1");
+        assert_eq!(error["messageType"], "simulate");
+        assert_eq!(error["field"], "timeout");
+        assert_eq!(error["expected"], "a non-negative integer");
+
+        let error = reply(&mut ws, "{\"id\": ").await;
+        assert_eq!(error["errorCode"], "INVALID_MESSAGE");
+        assert!(error.get("requestId").is_none());
+
+        let pong = reply(&mut ws, r#"{"id": "p-1", "type": "ping", "timestamp": 0}"#).await;
+        assert_eq!(pong["type"], "pong");
+    }
+}
@@ -0,0 +1,102 @@
+// Copyright (c) 2024-2025 Wanyeki Technologies LLC. All rights reserved.
+// This source code is licensed under the proprietary license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Main window placement and close-to-tray behavior
+
+use std::sync::Arc;
+use tauri::{AppHandle, Manager, PhysicalPosition, PhysicalSize, WebviewWindow};
+use tauri_plugin_notification::NotificationExt;
+
+use crate::settings::{self, WindowGeometry};
+use crate::state::AppState;
+
+/// Label of the window declared in tauri.conf.json
+pub const MAIN_WINDOW: &str = "main";
+
+/// Move the main window to where it was last closed, if that is still on a connected display
+/// Otherwise it keeps the centered default
+pub fn restore_geometry(app: &AppHandle, geometry: Option<WindowGeometry>) {
+    let (Some(window), Some(geometry)) = (app.get_webview_window(MAIN_WINDOW), geometry) else {
+        return;
+    };
+    let monitors: Vec<WindowGeometry> = window
+        .available_monitors()
+        .unwrap_or_default()
+        .iter()
+        .map(|monitor| WindowGeometry {
+            x: monitor.position().x,
+            y: monitor.position().y,
+            width: monitor.size().width,
+            height: monitor.size().height,
+        })
+        .collect();
+    if !geometry.is_reachable_on(&monitors) {
+        log::info!("Saved window position {:?} is off screen; centering the window", geometry);
+        return;
+    }
+    let _ = window.set_size(PhysicalSize::new(geometry.width, geometry.height));
+    let _ = window.set_position(PhysicalPosition::new(geometry.x, geometry.y));
+}
+
+/// Where the window is now, or None if it is hidden or minimized
+fn current_geometry(window: &WebviewWindow) -> Option<WindowGeometry> {
+    if !window.is_visible().unwrap_or(false) || window.is_minimized().unwrap_or(false) {
+        return None;
+    }
+    let position = window.outer_position().ok()?;
+    let size = window.inner_size().ok()?;
+    Some(WindowGeometry { x: position.x, y: position.y, width: size.width, height: size.height })
+}
+
+/// Save the main window's position and size, if it is showing
+pub async fn save_geometry(app: &AppHandle) {
+    let Some(geometry) = app.get_webview_window(MAIN_WINDOW).as_ref().and_then(current_geometry) else {
+        return;
+    };
+    let state = app.state::<Arc<AppState>>().inner().clone();
+    let updated = {
+        let mut settings = state.settings.write().await;
+        if settings.window_geometry == Some(geometry) {
+            return;
+        }
+        settings.window_geometry = Some(geometry);
+        settings.clone()
+    };
+    if let Err(e) = settings::save_settings(&updated) {
+        log::warn!("Failed to save window position: {}", e);
+    }
+}
+
+/// Hide the main window instead of closing it, so it can be shown again from the tray
+/// The first time, a notification explains that the agent is still running
+pub fn hide_to_tray(window: &WebviewWindow) {
+    let app = window.app_handle().clone();
+    let window = window.clone();
+    tauri::async_runtime::spawn(async move {
+        save_geometry(&app).await;
+        let _ = window.hide();
+
+        let state = app.state::<Arc<AppState>>().inner().clone();
+        let updated = {
+            let mut settings = state.settings.write().await;
+            if settings.close_to_tray_notified {
+                return;
+            }
+            settings.close_to_tray_notified = true;
+            settings.clone()
+        };
+        let shown = app
+            .notification()
+            .builder()
+            .title("KeliCAD Agent is still running")
+            .body("Simulations keep working in the background. Use the tray icon to open the window or quit.")
+            .show();
+        if let Err(e) = shown {
+            log::warn!("Failed to show notification: {}", e);
+        }
+        if let Err(e) = settings::save_settings(&updated) {
+            log::warn!("Failed to save settings: {}", e);
+        }
+    });
+}
@@ -0,0 +1,518 @@
+// Copyright (c) 2024-2025 Wanyeki Technologies LLC. All rights reserved.
+// This source code is licensed under the proprietary license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! WebSocket framing for the negotiated message encoding
+//!
+//! Messages are built as JSON throughout the agent. A connection that negotiated MessagePack
+//! has them transcoded into binary frames on the way out; binary frames from the client are
+//! transcoded back to JSON, so handlers only ever see JSON text.
+//!
+//! Every message the agent sends starts with a `seq` number, counting up from 1 on each
+//! connection in the order messages are written. A gap or a step back means a message was lost or
+//! reordered between the agent and the client.
+//!
+//! A message with `"f32Results": true` has every float after that entry written as a 32-bit
+//! MessagePack float, halving the size of its samples. Floats outside the f32 range stay 64-bit.
+
+use std::cell::Cell;
+
+use serde::ser::{Serialize, SerializeMap, SerializeSeq, Serializer};
+use tokio_tungstenite::tungstenite::Message;
+
+use crate::protocol::Encoding;
+
+type WireError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Key whose `true` value switches the rest of a message to 32-bit floats
+const F32_KEY: &str = "f32Results";
+
+/// Frame a JSON message for a client using `encoding`
+pub fn encode(json: String, encoding: Encoding) -> Result<Message, WireError> {
+    match encoding {
+        Encoding::Json => Ok(Message::Text(json)),
+        Encoding::Msgpack => {
+            let mut bytes = Vec::with_capacity(json.len() / 2);
+            let mut deserializer = serde_json::Deserializer::from_str(&json);
+            let mut serializer = rmp_serde::Serializer::new(&mut bytes);
+            let narrowing = NarrowState::default();
+            serde_transcode::transcode(&mut deserializer, Narrowing::value(&mut serializer, &narrowing))?;
+            Ok(Message::Binary(bytes))
+        }
+    }
+}
+
+/// `json`, a message object, with `"seq": seq` as its first entry
+pub fn stamp(json: String, seq: u64) -> String {
+    let Some(rest) = json.strip_prefix('{') else {
+        return json;
+    };
+    let separator = if rest.trim_start().starts_with('}') { "" } else { "," };
+    format!("{{\"seq\":{}{}{}", seq, separator, rest)
+}
+
+/// JSON text of a client frame: text as is, binary as MessagePack
+/// Returns None for control frames, which carry no message
+pub fn decode(message: Message) -> Result<Option<String>, WireError> {
+    match message {
+        Message::Text(text) => Ok(Some(text)),
+        Message::Binary(bytes) => {
+            let mut json = Vec::with_capacity(bytes.len() * 2);
+            let mut deserializer = rmp_serde::Deserializer::new(bytes.as_slice());
+            serde_transcode::transcode(&mut deserializer, &mut serde_json::Serializer::new(&mut json))?;
+            Ok(Some(String::from_utf8(json)?))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Whether a message being transcoded has switched to 32-bit floats
+#[derive(Default)]
+struct NarrowState {
+    narrow: Cell<bool>,
+    /// The map key just written was `F32_KEY`
+    at_f32_key: Cell<bool>,
+}
+
+/// Serializer passing everything through to `inner`, except that floats are written as f32 once
+/// `state` says so
+/// Only what transcoding from JSON produces is intercepted: maps, sequences and their contents.
+struct Narrowing<'a, S> {
+    inner: S,
+    state: &'a NarrowState,
+    /// Serializing a map key rather than a value
+    key: bool,
+}
+
+impl<'a, S> Narrowing<'a, S> {
+    fn value(inner: S, state: &'a NarrowState) -> Self {
+        Self { inner, state, key: false }
+    }
+}
+
+/// A value to serialize through `Narrowing`
+struct Narrowed<'a, T: ?Sized> {
+    value: &'a T,
+    state: &'a NarrowState,
+    key: bool,
+}
+
+impl<T: ?Sized + Serialize> Serialize for Narrowed<'_, T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.value.serialize(Narrowing { inner: serializer, state: self.state, key: self.key })
+    }
+}
+
+impl<'a, S: Serializer> Serializer for Narrowing<'a, S> {
+    type Ok = S::Ok;
+    type Error = S::Error;
+    type SerializeSeq = Narrowing<'a, S::SerializeSeq>;
+    type SerializeTuple = S::SerializeTuple;
+    type SerializeTupleStruct = S::SerializeTupleStruct;
+    type SerializeTupleVariant = S::SerializeTupleVariant;
+    type SerializeMap = Narrowing<'a, S::SerializeMap>;
+    type SerializeStruct = S::SerializeStruct;
+    type SerializeStructVariant = S::SerializeStructVariant;
+
+    fn serialize_f64(self, v: f64) -> Result<S::Ok, S::Error> {
+        let narrowed = v as f32;
+        let in_range = narrowed.is_finite() == v.is_finite() && (narrowed != 0.0 || v == 0.0);
+        if self.state.narrow.get() && in_range {
+            self.inner.serialize_f32(narrowed)
+        } else {
+            self.inner.serialize_f64(v)
+        }
+    }
+
+    fn serialize_bool(self, v: bool) -> Result<S::Ok, S::Error> {
+        if v && !self.key && self.state.at_f32_key.get() {
+            self.state.narrow.set(true);
+        }
+        self.inner.serialize_bool(v)
+    }
+
+    fn serialize_str(self, v: &str) -> Result<S::Ok, S::Error> {
+        if self.key {
+            self.state.at_f32_key.set(v == F32_KEY);
+        }
+        self.inner.serialize_str(v)
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<Self::SerializeSeq, S::Error> {
+        Ok(Narrowing { inner: self.inner.serialize_seq(len)?, state: self.state, key: false })
+    }
+
+    fn serialize_map(self, len: Option<usize>) -> Result<Self::SerializeMap, S::Error> {
+        Ok(Narrowing { inner: self.inner.serialize_map(len)?, state: self.state, key: false })
+    }
+
+    fn serialize_some<T: ?Sized + Serialize>(self, value: &T) -> Result<S::Ok, S::Error> {
+        self.inner.serialize_some(&Narrowed { value, state: self.state, key: self.key })
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<S::Ok, S::Error> {
+        self.inner.serialize_i8(v)
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<S::Ok, S::Error> {
+        self.inner.serialize_i16(v)
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<S::Ok, S::Error> {
+        self.inner.serialize_i32(v)
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<S::Ok, S::Error> {
+        self.inner.serialize_i64(v)
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<S::Ok, S::Error> {
+        self.inner.serialize_u8(v)
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<S::Ok, S::Error> {
+        self.inner.serialize_u16(v)
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<S::Ok, S::Error> {
+        self.inner.serialize_u32(v)
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<S::Ok, S::Error> {
+        self.inner.serialize_u64(v)
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<S::Ok, S::Error> {
+        self.inner.serialize_f32(v)
+    }
+
+    fn serialize_char(self, v: char) -> Result<S::Ok, S::Error> {
+        self.inner.serialize_char(v)
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<S::Ok, S::Error> {
+        self.inner.serialize_bytes(v)
+    }
+
+    fn serialize_none(self) -> Result<S::Ok, S::Error> {
+        self.inner.serialize_none()
+    }
+
+    fn serialize_unit(self) -> Result<S::Ok, S::Error> {
+        self.inner.serialize_unit()
+    }
+
+    fn serialize_unit_struct(self, name: &'static str) -> Result<S::Ok, S::Error> {
+        self.inner.serialize_unit_struct(name)
+    }
+
+    fn serialize_unit_variant(self, name: &'static str, index: u32, variant: &'static str) -> Result<S::Ok, S::Error> {
+        self.inner.serialize_unit_variant(name, index, variant)
+    }
+
+    fn serialize_newtype_struct<T: ?Sized + Serialize>(self, name: &'static str, value: &T) -> Result<S::Ok, S::Error> {
+        self.inner.serialize_newtype_struct(name, value)
+    }
+
+    fn serialize_newtype_variant<T: ?Sized + Serialize>(
+        self,
+        name: &'static str,
+        index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<S::Ok, S::Error> {
+        self.inner.serialize_newtype_variant(name, index, variant, value)
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<S::SerializeTuple, S::Error> {
+        self.inner.serialize_tuple(len)
+    }
+
+    fn serialize_tuple_struct(self, name: &'static str, len: usize) -> Result<S::SerializeTupleStruct, S::Error> {
+        self.inner.serialize_tuple_struct(name, len)
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        name: &'static str,
+        index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<S::SerializeTupleVariant, S::Error> {
+        self.inner.serialize_tuple_variant(name, index, variant, len)
+    }
+
+    fn serialize_struct(self, name: &'static str, len: usize) -> Result<S::SerializeStruct, S::Error> {
+        self.inner.serialize_struct(name, len)
+    }
+
+    fn serialize_struct_variant(
+        self,
+        name: &'static str,
+        index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<S::SerializeStructVariant, S::Error> {
+        self.inner.serialize_struct_variant(name, index, variant, len)
+    }
+
+    fn is_human_readable(&self) -> bool {
+        self.inner.is_human_readable()
+    }
+}
+
+impl<S: SerializeSeq> SerializeSeq for Narrowing<'_, S> {
+    type Ok = S::Ok;
+    type Error = S::Error;
+
+    fn serialize_element<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), S::Error> {
+        self.inner.serialize_element(&Narrowed { value, state: self.state, key: false })
+    }
+
+    fn end(self) -> Result<S::Ok, S::Error> {
+        self.inner.end()
+    }
+}
+
+impl<S: SerializeMap> SerializeMap for Narrowing<'_, S> {
+    type Ok = S::Ok;
+    type Error = S::Error;
+
+    fn serialize_key<T: ?Sized + Serialize>(&mut self, key: &T) -> Result<(), S::Error> {
+        self.inner.serialize_key(&Narrowed { value: key, state: self.state, key: true })
+    }
+
+    fn serialize_value<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), S::Error> {
+        let result = self.inner.serialize_value(&Narrowed { value, state: self.state, key: false });
+        self.state.at_f32_key.set(false);
+        result
+    }
+
+    fn end(self) -> Result<S::Ok, S::Error> {
+        self.inner.end()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+    use crate::protocol::*;
+    use serde::Serialize;
+
+    fn round_trip(json: &str, encoding: Encoding) -> String {
+        decode(encode(json.to_string(), encoding).unwrap()).unwrap().unwrap()
+    }
+
+    #[test]
+    fn test_encodings_frame_differently() {
+        let json = r#"{"id":"p-1","type":"ping","timestamp":0}"#;
+        assert!(matches!(encode(json.to_string(), Encoding::Json).unwrap(), Message::Text(_)));
+
+        let Message::Binary(bytes) = encode(json.to_string(), Encoding::Msgpack).unwrap() else {
+            panic!("expected a binary frame");
+        };
+        assert!(bytes.len() < json.len());
+        let value: serde_json::Value = rmp_serde::from_slice(&bytes).unwrap();
+        assert_eq!(value["type"], "ping");
+
+        assert_eq!(decode(Message::Ping(vec![1])).unwrap(), None);
+        assert!(decode(Message::Binary(vec![0xc1])).is_err());
+    }
+
+    #[test]
+    fn test_stamp() {
+        let stamped = stamp(r#"{"id":"p-1","type":"pong"}"#.to_string(), 7);
+        assert_eq!(stamped, r#"{"seq":7,"id":"p-1","type":"pong"}"#);
+        assert_eq!(stamp("{}".to_string(), 1), r#"{"seq":1}"#);
+        assert_eq!(stamp("[]".to_string(), 1), "[]");
+
+        let value: serde_json::Value = rmp_serde::from_slice(&match encode(stamped, Encoding::Msgpack).unwrap() {
+            Message::Binary(bytes) => bytes,
+            _ => panic!("expected a binary frame"),
+        })
+        .unwrap();
+        assert_eq!(value["seq"], 7);
+    }
+
+    #[test]
+    fn test_outgoing_messages_round_trip() {
+        fn json<T: Serialize>(message: &T) -> String {
+            serde_json::to_string(message).unwrap()
+        }
+
+        let results = SimulationResults {
+            time: vec![0.0, 1e-9, 2.5e-9, 1.0 / 3.0],
+            traces: vec![Trace {
+                name: "V(out)".to_string(),
+                data: vec![-0.0, 1e-300, f64::MAX, 0.1 + 0.2],
+                unit: "V".to_string(),
+                phase: Some(vec![-90.0, 45.5, 0.0, 180.0]),
+                raw_name: None,
+            }],
+            analysis_type: "ac".to_string(),
+            x_axis_label: Some("Frequency".to_string()),
+            x_axis_unit: None,
+            scalar_results: Some([("Transfer_function".to_string(), 0.5)].into_iter().collect()),
+            device_operating_points: BTreeMap::new(),
+            truncated: None,
+            plot_hints: None,
+            raw_header: None,
+        };
+        let response = SimulationResponse {
+            id: "resp-1".to_string(),
+            msg_type: "simulation_result".to_string(),
+            request_id: "sim-1".to_string(),
+            timestamp: u64::MAX,
+            success: true,
+            f32_results: false,
+            results: Some(results),
+            error: None,
+            error_code: None,
+            execution_time: 1234,
+            simulator: "ngspice".to_string(),
+            monte_carlo: None,
+            duplicate: true,
+            retried_with: Some("uic".to_string()),
+            translations: vec!["Removed LTspice directive: .backanno".to_string()],
+            debug_info: None,
+            results_compressed: None,
+            compression: None,
+            retry_after_ms: None,
+            warnings: Vec::new(),
+            simulator_version: None,
+            simulator_binary_sha256: None,
+            replay_of: None,
+            fallback_simulator_used: None,
+            engine_errors: Vec::new(),
+            artifact_id: None,
+            raw_file_size: None,
+            trace_catalog: None,
+            operating_point: None,
+            wave_data: None,
+            libraries_used: Vec::new(),
+            busy_info: None,
+            timings: SimulationTimings::default(),
+        };
+        let progress = SimulationProgress {
+            id: "prog-1".to_string(),
+            msg_type: "simulation_progress".to_string(),
+            request_id: "sim-1".to_string(),
+            timestamp: 0,
+            stage: ProgressStage::Running { percent: Some(42.5) },
+            message: "Simulating (CPU 97%, 120 MB)".to_string(),
+            corner: Some("ff".to_string()),
+            completed: Some(1),
+            total: Some(3),
+            resources: None,
+        };
+
+        let mut messages = vec![json(&response), json(&progress)];
+        messages.extend(
+            [
+                r#"{"id":"h","type":"handshake_response","timestamp":0,"success":true,"encoding":"msgpack","capabilities":{"ltspiceAvailable":false,"analyses":[]}}"#,
+                r#"{"id":"p","type":"pong","timestamp":0,"currentRequestId":"sim-1","progressPercent":12}"#,
+                r#"{"id":"l","type":"simulation_log","requestId":"sim-1","timestamp":0,"lines":["Note: µA range","\ttab"]}"#,
+                r#"{"id":"b","type":"batch_simulation_result","requestId":"b-1","timestamp":0,"success":false,"corners":[],"error":null}"#,
+                r#"{"id":"c","type":"cancel_response","requestId":"sim-1","timestamp":0,"success":true}"#,
+                r#"{"id":"c","type":"confirm_response","requestId":"sim-1","timestamp":0,"success":false}"#,
+                r#"{"id":"a","type":"analyze_netlist_response","requestId":"n-1","timestamp":0,"analyses":[{"type":"tran","step":"1u"}]}"#,
+                r#"{"id":"l","type":"list_libraries_response","timestamp":0,"libraries":["standard.dio"]}"#,
+                r#"{"id":"e","type":"export_response","timestamp":0,"success":true,"csv":"time,V(out)\n0,1\n"}"#,
+                r#"{"id":"m","type":"metrics","timestamp":0,"bytesServed":18446744073709551615}"#,
+                r#"{"id":"g","type":"get_result_response","requestId":"sim-1","timestamp":0,"found":false}"#,
+                r#"{"id":"c","type":"capabilities_changed","timestamp":0,"capabilities":{}}"#,
+                r#"{"id":"s","type":"agent_shutdown","timestamp":0,"reason":"The agent is quitting"}"#,
+            ]
+            .map(str::to_string),
+        );
+
+        for message in messages {
+            assert_eq!(round_trip(&message, Encoding::Json), message);
+            assert_eq!(round_trip(&message, Encoding::Msgpack), message);
+        }
+    }
+
+    #[test]
+    fn test_f32_results_narrow_later_floats() {
+        let samples: Vec<f64> = (0..1000).map(|i| (i as f64 * 0.01).sin()).collect();
+        let message = |f32_results: bool| {
+            serde_json::json!({
+                "id": "r",
+                "type": "simulation_result",
+                "executionTime": 1.5,
+                "f32Results": f32_results,
+                "results": {"time": samples, "tiny": 1e-300, "huge": f64::MAX, "zero": 0.0},
+            })
+            .to_string()
+        };
+        let binary = |json: String| match encode(json, Encoding::Msgpack).unwrap() {
+            Message::Binary(bytes) => bytes,
+            _ => panic!("expected a binary frame"),
+        };
+
+        let full = binary(message(false));
+        let narrow = binary(message(true));
+        // 9 bytes per f64 sample against 5 per f32
+        assert!(narrow.len() + 3900 < full.len(), "{} vs {} bytes", narrow.len(), full.len());
+
+        let value: serde_json::Value = rmp_serde::from_slice(&narrow).unwrap();
+        // Floats before the flag keep full precision
+        assert_eq!(value["executionTime"], 1.5);
+        let time = value["results"]["time"].as_array().unwrap();
+        assert_eq!(time[7].as_f64().unwrap(), samples[7] as f32 as f64);
+        assert_ne!(time[7].as_f64().unwrap(), samples[7]);
+        // Floats f32 can't hold stay 64-bit
+        assert_eq!(value["results"]["tiny"], 1e-300);
+        assert_eq!(value["results"]["huge"], f64::MAX);
+        assert_eq!(value["results"]["zero"], 0.0);
+
+        // A string value of the key is not the flag
+        let spoofed = r#"{"requestId":"f32Results","note":true,"value":0.1}"#;
+        assert_eq!(round_trip(spoofed, Encoding::Msgpack), spoofed);
+    }
+
+    #[test]
+    fn test_incoming_requests_decode_from_msgpack() {
+        let simulate = serde_json::json!({
+            "id": "sim-1",
+            "type": "simulate",
+            "netlist": "V1 in 0 1\n.op\n.end",
+            "timeout": 60000,
+            "compress": true,
+            "timestamp": 0,
+        });
+        let json = decode(Message::Binary(rmp_serde::to_vec(&simulate).unwrap())).unwrap().unwrap();
+        let request: SimulationRequest = serde_json::from_str(&json).unwrap();
+        assert_eq!(request.netlist, "V1 in 0 1\n.op\n.end");
+        assert_eq!(request.timeout, Some(60000));
+        assert!(request.compress);
+
+        let handshake = serde_json::json!({
+            "id": "hs-1",
+            "type": "handshake",
+            "origin": "http://localhost:3000",
+            "version": "1.0.0",
+            "encoding": "msgpack",
+            "timestamp": 0,
+        });
+        let json = decode(Message::Binary(rmp_serde::to_vec(&handshake).unwrap())).unwrap().unwrap();
+        let generic: GenericMessage = serde_json::from_str(&json).unwrap();
+        assert_eq!(generic.msg_type, "handshake");
+        let request: HandshakeRequest = serde_json::from_str(&json).unwrap();
+        assert_eq!(request.encoding, Encoding::Msgpack);
+
+        for message in [
+            r#"{"id":"p-1","type":"ping","timestamp":0}"#,
+            r#"{"id":"c-1","type":"cancel","requestId":"sim-1","timestamp":0}"#,
+            r#"{"id":"c-2","type":"confirm","requestId":"sim-1","proceed":true,"timestamp":0}"#,
+            r#"{"id":"b-1","type":"batch_simulate","netlist":".op","corners":[{"name":"tt","params":{"R":1.5}}],"timestamp":0}"#,
+        ] {
+            let value: serde_json::Value = serde_json::from_str(message).unwrap();
+            let decoded = decode(Message::Binary(rmp_serde::to_vec(&value).unwrap())).unwrap().unwrap();
+            let generic: GenericMessage = serde_json::from_str(&decoded).unwrap();
+            assert_eq!(generic.msg_type, value["type"]);
+            assert_eq!(serde_json::from_str::<serde_json::Value>(&decoded).unwrap(), value);
+        }
+    }
+}